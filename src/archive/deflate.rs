@@ -0,0 +1,587 @@
+//! DEFLATE (RFC 1951) compression with the fixed Huffman codes, decompression of any DEFLATE
+//! stream, and the gzip (RFC 1952) framing around them
+
+use std::io::{self, ErrorKind};
+
+use crate::digest::Crc32;
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried for each match
+const MAX_CHAIN: usize = 64;
+const END_OF_BLOCK: u16 = 256;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in, in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Compresses data written in chunks into a raw DEFLATE stream
+///
+/// Each chunk becomes a block coded with the fixed Huffman codes, with matches found in the chunk
+/// and the 32 KiB before it.
+#[derive(Debug, Default)]
+pub(crate) struct Deflater {
+    bits: BitWriter,
+    /// The end of the data compressed so far, which later chunks can refer back to
+    history: Vec<u8>,
+}
+
+impl Deflater {
+    /// Compresses `data`, returning the bytes of the stream completed so far
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return Vec::new();
+        }
+        let start = self.history.len();
+        let mut buf = std::mem::take(&mut self.history);
+        buf.extend_from_slice(data);
+        // Not the final block, with the fixed codes
+        self.bits.write(0b010, 3);
+        let mut chains = Chains::default();
+        for position in 0..start {
+            chains.insert(&buf, position);
+        }
+        let mut position = start;
+        while position < buf.len() {
+            if let Some((len, distance)) = chains.longest_match(&buf, position) {
+                self.write_match(len, distance);
+                for skipped in position..position + len {
+                    chains.insert(&buf, skipped);
+                }
+                position += len;
+            } else {
+                self.write_literal(u16::from(buf[position]));
+                chains.insert(&buf, position);
+                position += 1;
+            }
+        }
+        self.write_literal(END_OF_BLOCK);
+        self.history = buf[buf.len().saturating_sub(WINDOW)..].to_vec();
+        self.bits.take_bytes()
+    }
+
+    /// Ends the stream, returning its remaining bytes
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        // An empty final block
+        self.bits.write(0b011, 3);
+        self.write_literal(END_OF_BLOCK);
+        self.bits.flush();
+        self.bits.take_bytes()
+    }
+
+    fn write_literal(&mut self, symbol: u16) {
+        let (code, len) = match symbol {
+            0..=143 => (0x30 + symbol, 8),
+            144..=255 => (0x190 + symbol - 144, 9),
+            256..=279 => (symbol - 256, 7),
+            _ => (0xc0 + symbol - 280, 8),
+        };
+        self.bits.write_code(code, len);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn write_match(&mut self, len: usize, distance: usize) {
+        let index = LEN_BASE.partition_point(|&base| usize::from(base) <= len) - 1;
+        self.write_literal(257 + index as u16);
+        self.bits.write(
+            (len - usize::from(LEN_BASE[index])) as u32,
+            LEN_EXTRA[index],
+        );
+        let index = DIST_BASE.partition_point(|&base| usize::from(base) <= distance) - 1;
+        self.bits.write_code(index as u16, 5);
+        self.bits.write(
+            (distance - usize::from(DIST_BASE[index])) as u32,
+            DIST_EXTRA[index],
+        );
+    }
+}
+
+/// The earlier positions in a chunk with each hash of their next three bytes
+#[derive(Debug, Default)]
+struct Chains(std::collections::HashMap<[u8; 3], Vec<usize>>);
+
+impl Chains {
+    fn insert(&mut self, buf: &[u8], position: usize) {
+        if let Some(key) = buf.get(position..position + MIN_MATCH) {
+            self.0
+                .entry([key[0], key[1], key[2]])
+                .or_default()
+                .push(position);
+        }
+    }
+
+    /// The longest match for the bytes at `position`, as its length and distance back
+    fn longest_match(&self, buf: &[u8], position: usize) -> Option<(usize, usize)> {
+        let key = buf.get(position..position + MIN_MATCH)?;
+        let candidates = self.0.get(&[key[0], key[1], key[2]])?;
+        let max = MAX_MATCH.min(buf.len() - position);
+        candidates
+            .iter()
+            .rev()
+            .take(MAX_CHAIN)
+            .take_while(|&&candidate| position - candidate <= WINDOW)
+            .map(|&candidate| {
+                let len = buf[candidate..]
+                    .iter()
+                    .zip(&buf[position..position + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (len, position - candidate)
+            })
+            .filter(|&(len, _)| len >= MIN_MATCH)
+            .max_by_key(|&(len, distance)| (len, std::cmp::Reverse(distance)))
+    }
+}
+
+/// Packs bits into bytes, least significant bit first
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    buffered: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u8) {
+        self.buffer |= u64::from(value) << self.buffered;
+        self.buffered += bits;
+        while self.buffered >= 8 {
+            self.bytes.push(self.buffer.to_le_bytes()[0]);
+            self.buffer >>= 8;
+            self.buffered -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which is packed starting from its most significant bit
+    fn write_code(&mut self, code: u16, bits: u8) {
+        let reversed = code.reverse_bits() >> (16 - bits);
+        self.write(u32::from(reversed), bits);
+    }
+
+    /// Pads the last partial byte with zeros
+    fn flush(&mut self) {
+        if self.buffered > 0 {
+            self.write(0, 8 - self.buffered);
+        }
+    }
+
+    fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+/// Compresses data written in chunks into a gzip member
+#[derive(Debug, Default)]
+pub(crate) struct GzipEncoder {
+    deflater: Deflater,
+    crc: Crc32,
+    len: u32,
+    started: bool,
+}
+
+impl GzipEncoder {
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = self.header();
+        self.crc.update(data);
+        #[allow(clippy::cast_possible_truncation)]
+        let len = data.len() as u32;
+        // The length is stored modulo 2^32
+        self.len = self.len.wrapping_add(len);
+        out.extend(self.deflater.compress(data));
+        out
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        let mut out = self.header();
+        out.extend(self.deflater.finish());
+        out.extend(self.crc.finish().to_le_bytes());
+        out.extend(self.len.to_le_bytes());
+        out
+    }
+
+    fn header(&mut self) -> Vec<u8> {
+        if std::mem::replace(&mut self.started, true) {
+            return Vec::new();
+        }
+        // No flags or modification time, and an unknown OS
+        vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]
+    }
+}
+
+/// Decompresses the gzip members in `data`, failing once the output would be longer than `limit`
+pub(crate) fn gunzip(mut data: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let header = gzip_header_len(data)?;
+        let (member, consumed) = inflate(&data[header..], limit - out.len() as u64)?;
+        let trailer = data
+            .get(header + consumed..header + consumed + 8)
+            .ok_or_else(|| invalid("the gzip stream is truncated"))?;
+        let mut crc = Crc32::default();
+        crc.update(&member);
+        #[allow(clippy::cast_possible_truncation)]
+        let len = member.len() as u32;
+        if trailer[..4] != crc.finish().to_le_bytes() || trailer[4..] != len.to_le_bytes() {
+            return Err(invalid("the gzip checksum doesn't match"));
+        }
+        out.extend(member);
+        data = &data[header + consumed + 8..];
+    }
+    Ok(out)
+}
+
+fn gzip_header_len(data: &[u8]) -> io::Result<usize> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+    let truncated = || invalid("the gzip header is truncated");
+    if data.get(..3) != Some(&[0x1f, 0x8b, 8]) {
+        return Err(invalid("not a gzip stream"));
+    }
+    let flags = *data.get(3).ok_or_else(truncated)?;
+    let mut len = 10;
+    if flags & FEXTRA != 0 {
+        let extra = data.get(len..len + 2).ok_or_else(truncated)?;
+        len += 2 + usize::from(u16::from_le_bytes([extra[0], extra[1]]));
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(len..)
+                .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+                .ok_or_else(truncated)?;
+            len += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+    if len > data.len() {
+        return Err(truncated());
+    }
+    Ok(len)
+}
+
+/// Decompresses the raw DEFLATE stream at the start of `data`, returning the output and how many
+/// bytes of `data` the stream took up
+///
+/// # Errors
+///
+/// With [`ErrorKind::InvalidData`] if the stream is malformed, or the output would be longer than
+/// `limit`
+pub(crate) fn inflate(data: &[u8], limit: u64) -> io::Result<(Vec<u8>, usize)> {
+    let mut bits = BitReader { data, position: 0 };
+    let mut out = Vec::new();
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    loop {
+        let last = bits.read(1)? == 1;
+        match bits.read(2)? {
+            0 => {
+                bits.align();
+                let header = bits.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid("a stored DEFLATE block's length is corrupt"));
+                }
+                let stored = bits.bytes(usize::from(len))?;
+                if out.len() + stored.len() > limit {
+                    return Err(too_long());
+                }
+                out.extend_from_slice(stored);
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                inflate_block(&mut bits, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, &literals, &distances, limit)?;
+            }
+            _ => return Err(invalid("a DEFLATE block has a reserved type")),
+        }
+        if last {
+            return Ok((out, bits.position.div_ceil(8)));
+        }
+    }
+}
+
+fn inflate_block(
+    bits: &mut BitReader<'_>,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> io::Result<()> {
+    loop {
+        let symbol = literals.decode(bits)?;
+        match symbol {
+            0..=255 => {
+                if out.len() >= limit {
+                    return Err(too_long());
+                }
+                #[allow(clippy::cast_possible_truncation)]
+                out.push(symbol as u8);
+            }
+            END_OF_BLOCK => return Ok(()),
+            _ => {
+                let index = usize::from(symbol - 257);
+                let (Some(base), Some(&extra)) = (LEN_BASE.get(index), LEN_EXTRA.get(index)) else {
+                    return Err(invalid("a DEFLATE length code is invalid"));
+                };
+                let len = usize::from(*base) + bits.read(extra)? as usize;
+                let index = usize::from(distances.decode(bits)?);
+                let (Some(base), Some(&extra)) = (DIST_BASE.get(index), DIST_EXTRA.get(index))
+                else {
+                    return Err(invalid("a DEFLATE distance code is invalid"));
+                };
+                let distance = usize::from(*base) + bits.read(extra)? as usize;
+                if distance > out.len() {
+                    return Err(invalid("a DEFLATE match refers to before the stream"));
+                }
+                if out.len() + len > limit {
+                    return Err(too_long());
+                }
+                let start = out.len() - distance;
+                // Matches can overlap the bytes they produce, so they're copied a byte at a time
+                for offset in 0..len {
+                    out.push(out[start + offset]);
+                }
+            }
+        }
+    }
+}
+
+fn dynamic_codes(bits: &mut BitReader<'_>) -> io::Result<(Huffman, Huffman)> {
+    let literal_count = bits.read(5)? as usize + 257;
+    let distance_count = bits.read(5)? as usize + 1;
+    let code_length_count = bits.read(4)? as usize + 4;
+    let mut code_lengths = [0; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        #[allow(clippy::cast_possible_truncation)]
+        let len = bits.read(3)? as u8;
+        code_lengths[index] = len;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (len, repeat) = match code_lengths.decode(bits)? {
+            #[allow(clippy::cast_possible_truncation)]
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| invalid("a DEFLATE code length repeats nothing"))?;
+                (previous, 3 + bits.read(2)?)
+            }
+            17 => (0, 3 + bits.read(3)?),
+            _ => (0, 11 + bits.read(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths.len() != literal_count + distance_count {
+        return Err(invalid("DEFLATE code lengths overrun the codes"));
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals)?, Huffman::new(distances)?))
+}
+
+/// A canonical Huffman code, decoded a bit at a time
+#[derive(Debug)]
+struct Huffman {
+    /// How many codes there are of each length
+    counts: [u16; 16],
+    /// The symbols, ordered by their codes
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            *counts
+                .get_mut(usize::from(len))
+                .ok_or_else(|| invalid("a DEFLATE code is too long"))? += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                let offset = &mut offsets[usize::from(len)];
+                #[allow(clippy::cast_possible_truncation)]
+                {
+                    symbols[usize::from(*offset)] = symbol as u16;
+                }
+                *offset += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut BitReader<'_>) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= i32::try_from(bits.read(1)?).unwrap_or_default();
+            let count = i32::from(count);
+            if code - first < count {
+                let position = usize::try_from(index + code - first).unwrap_or(usize::MAX);
+                return self
+                    .symbols
+                    .get(position)
+                    .copied()
+                    .ok_or_else(|| invalid("a DEFLATE code is invalid"));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("a DEFLATE code is invalid"))
+    }
+}
+
+/// Reads bits from bytes, least significant bit first
+#[derive(Debug)]
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// The position in bits
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u8) -> io::Result<u32> {
+        let mut value = 0;
+        for bit in 0..bits {
+            let byte = self
+                .data
+                .get(self.position / 8)
+                .ok_or_else(|| invalid("the DEFLATE stream is truncated"))?;
+            value |= u32::from(byte >> (self.position % 8) & 1) << bit;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    /// The next `len` bytes, once the reader is aligned to a byte
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        let start = self.position / 8;
+        let bytes = self
+            .data
+            .get(start..start + len)
+            .ok_or_else(|| invalid("the DEFLATE stream is truncated"))?;
+        self.position += len * 8;
+        Ok(bytes)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn too_long() -> io::Error {
+    invalid("the decompressed data is longer than the limit")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> io::Result<()> {
+        let text = "tower_fs compresses tower_fs archives. ".repeat(2000);
+        let mut binary = Vec::new();
+        let mut state = 7u32;
+        for _ in 0..50_000 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            binary.push(state.to_be_bytes()[0]);
+        }
+        for data in [text.as_bytes(), &binary, b""] {
+            let mut deflater = Deflater::default();
+            let mut compressed = Vec::new();
+            for chunk in data.chunks(10_000) {
+                compressed.extend(deflater.compress(chunk));
+            }
+            compressed.extend(deflater.finish());
+            compressed.extend(b"trailing");
+            let (inflated, consumed) = inflate(&compressed, u64::MAX)?;
+            assert_eq!(inflated, data);
+            assert_eq!(&compressed[consumed..], b"trailing");
+        }
+        let mut deflater = Deflater::default();
+        let mut compressed = deflater.compress(text.as_bytes());
+        compressed.extend(deflater.finish());
+        assert!(compressed.len() < text.len() / 20, "{}", compressed.len());
+        assert_eq!(
+            inflate(&compressed, 1000).map_err(|err| err.kind()),
+            Err(ErrorKind::InvalidData)
+        );
+
+        let mut gzip = GzipEncoder::default();
+        let mut compressed = gzip.compress(&binary);
+        compressed.extend(gzip.finish());
+        assert_eq!(gunzip(&compressed, u64::MAX)?, binary);
+        compressed[20] ^= 1;
+        assert!(gunzip(&compressed, u64::MAX).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inflate_dynamic() -> io::Result<()> {
+        // 200 random bytes skewed towards a few letters, compressed by zlib, which chose a block
+        // with dynamic codes
+        let compressed = [
+            0x1d, 0x8e, 0x31, 0x0a, 0x45, 0x41, 0x08, 0x03, 0xfb, 0x3d, 0x85, 0x57, 0xb3, 0x18,
+            0x58, 0x1b, 0x85, 0x67, 0xee, 0xcf, 0xcf, 0x7e, 0xd0, 0xc6, 0xc4, 0x49, 0x98, 0x41,
+            0x95, 0x3d, 0x14, 0x91, 0x0c, 0x6c, 0x4e, 0x4c, 0xaa, 0x03, 0xda, 0x27, 0x2d, 0x7d,
+            0xe1, 0xda, 0x76, 0x89, 0x20, 0x6b, 0x8f, 0xf6, 0x93, 0x77, 0x92, 0x23, 0x20, 0xed,
+            0xd4, 0xed, 0x90, 0x32, 0x34, 0x1a, 0x8f, 0x05, 0x3f, 0x53, 0xbd, 0xac, 0x6f, 0xc7,
+            0x29, 0xdb, 0x74, 0x98, 0x62, 0x7f, 0x74, 0xa2, 0x0f, 0x1d, 0x90, 0xae, 0x9c, 0x54,
+            0x75, 0xd1, 0xfa, 0xf1, 0x3c, 0xda, 0x83, 0xd6, 0x3c, 0xb1, 0xdc, 0x07, 0x83, 0xc5,
+            0xf9, 0xe3, 0x5f, 0xaf, 0x7a, 0x65, 0x3f, 0x13, 0x7a, 0xd7, 0x16, 0xd2, 0x99, 0xfc,
+            0x00,
+        ];
+        let (inflated, consumed) = inflate(&compressed, u64::MAX)?;
+        assert_eq!((inflated.len(), consumed), (200, compressed.len()));
+        let mut crc = Crc32::default();
+        crc.update(&inflated);
+        assert_eq!(crc.finish(), 0xb352_e163);
+
+        // A stored block, then a block with the fixed codes
+        let mut stream = vec![0b000, 5, 0, !5, !0];
+        stream.extend(b"hello");
+        let mut deflater = Deflater::default();
+        let mut fixed = deflater.compress(b" world");
+        fixed.extend(deflater.finish());
+        stream.extend(fixed);
+        assert_eq!(inflate(&stream, u64::MAX)?.0, b"hello world");
+        Ok(())
+    }
+}
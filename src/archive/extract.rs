@@ -0,0 +1,606 @@
+//! Unpacking tar and zip archives into a directory, for
+//! [`Request::Extract`](crate::Request::Extract)
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use super::{deflate, zip};
+use crate::backend::{
+    normalize,
+    tar::{c_string, padding, Header, Overrides, BLOCK, BLOCK_SIZE},
+};
+
+/// PAX headers and GNU long names are read into memory, so they're limited to this size
+const MAX_EXTENDED_HEADER: u64 = 1024 * 1024;
+
+/// Limits on what an archive can unpack, against archives crafted to exhaust the disk (and, for
+/// compressed archives, memory)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExtractOptions {
+    /// The most entries (files, directories and links) the archive may hold
+    pub max_entries: usize,
+    /// The most bytes the files in the archive may add up to, once decompressed
+    pub max_size: u64,
+    /// Replace files and symlinks which are already in the destination, rather than failing with
+    /// [`ErrorKind::AlreadyExists`]
+    pub overwrite: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            max_size: 1024 * 1024 * 1024,
+            overwrite: false,
+        }
+    }
+}
+
+/// Unpacks the local archive at `archive` into `dst_dir`, creating it if it doesn't exist
+///
+/// The format is detected from the archive's first bytes: zip archives start with a zip header,
+/// gzip compressed tar archives with the gzip magic number, and anything else is read as tar.
+/// Compressed tar archives are decompressed in memory, up to the size limit (with room for the tar
+/// headers).
+pub(crate) fn extract(archive: &Path, dst_dir: &Path, options: &ExtractOptions) -> io::Result<()> {
+    std::fs::create_dir_all(dst_dir)?;
+    let mut extractor = Extractor {
+        root: std::fs::canonicalize(dst_dir)?,
+        options,
+        entries: 0,
+        size: 0,
+        files: HashSet::new(),
+    };
+    let mut file = File::open(archive)?;
+    let mut magic = [0; 4];
+    let read = read_full(&mut file, &mut magic)?;
+    file.rewind()?;
+    match &magic[..read] {
+        [b'P', b'K', 3, 4] | [b'P', b'K', 5, 6] => extractor.zip(&mut file),
+        [0x1f, 0x8b, ..] => {
+            let mut compressed = Vec::new();
+            file.read_to_end(&mut compressed)?;
+            let headers = (options.max_entries as u64 + 1).saturating_mul(3 * BLOCK_SIZE);
+            let limit = options.max_size.saturating_add(headers);
+            extractor.tar(&mut deflate::gunzip(&compressed, limit)?.as_slice())
+        }
+        _ => extractor.tar(&mut BufReader::new(file)),
+    }
+}
+
+#[derive(Debug)]
+struct Extractor<'a> {
+    /// The canonical path of the destination
+    root: PathBuf,
+    options: &'a ExtractOptions,
+    entries: usize,
+    size: u64,
+    /// The files unpacked so far, which are the only files hard links may refer to
+    files: HashSet<PathBuf>,
+}
+
+impl Extractor<'_> {
+    fn tar(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        let mut overrides = Overrides::default();
+        loop {
+            let mut block = [0; BLOCK];
+            if read_full(reader, &mut block)? < BLOCK || block.iter().all(|byte| *byte == 0) {
+                // The end-of-archive marker, or a truncated archive
+                return Ok(());
+            }
+            let header = Header::parse(&block)?;
+            let size = overrides.size.take().unwrap_or(header.size);
+            let mut data = reader.take(size);
+            match header.typeflag {
+                b'x' | b'L' | b'K' => {
+                    if size > MAX_EXTENDED_HEADER {
+                        return Err(invalid("a tar extended header is too large"));
+                    }
+                    let mut extended = Vec::new();
+                    data.read_to_end(&mut extended)?;
+                    match header.typeflag {
+                        b'x' => overrides.parse_pax(&extended),
+                        b'L' => overrides.path = Some(c_string(&extended)),
+                        _ => overrides.link = Some(c_string(&extended)),
+                    }
+                }
+                typeflag => {
+                    let name = overrides.path.take().unwrap_or(header.name);
+                    let link = overrides.link.take().unwrap_or(header.link);
+                    match typeflag {
+                        b'0' | b'\0' | b'7' => {
+                            let path = self.entry(&name, size)?;
+                            self.file(&path, &mut data, Some(header.mode), header.mtime)?;
+                        }
+                        b'5' => {
+                            let path = self.entry(&name, 0)?;
+                            self.dir(&path)?;
+                        }
+                        b'2' => {
+                            let path = self.entry(&name, 0)?;
+                            self.symlink(&path, Path::new(&link))?;
+                        }
+                        b'1' => {
+                            let path = self.entry(&name, 0)?;
+                            self.hard_link(&path, Path::new(&link))?;
+                        }
+                        // Global PAX headers, devices, fifos and vendor extensions aren't unpacked
+                        _ => {}
+                    }
+                }
+            }
+            // Skip whatever of the entry's data wasn't read, then its padding
+            io::copy(&mut data, &mut io::sink())?;
+            let padding = padding(size).len() as u64;
+            if io::copy(&mut reader.take(padding), &mut io::sink())? < padding {
+                return Ok(());
+            }
+        }
+    }
+
+    fn zip(&mut self, file: &mut File) -> io::Result<()> {
+        let len = file.seek(SeekFrom::End(0))?;
+        // The end of central directory record is 22 bytes, followed by a comment of up to 64 KiB
+        let tail_len = len.min(22 + 0xffff);
+        let mut tail = vec![0; usize::try_from(tail_len).map_err(io::Error::other)?];
+        file.seek(SeekFrom::Start(len - tail_len))?;
+        file.read_exact(&mut tail)?;
+        let end = (0..tail.len().saturating_sub(21))
+            .rev()
+            .find(|&offset| u32_at(&tail, offset) == zip::END_OF_CENTRAL_DIRECTORY)
+            .ok_or_else(|| invalid("the zip archive has no central directory"))?;
+        let end = &tail[end..];
+        let count = usize::from(u16_at(end, 10));
+        if count > self.options.max_entries {
+            return Err(too_many_entries());
+        }
+        let directory_len = u32_at(end, 12);
+        let directory_offset = u32_at(end, 16);
+        if directory_len == u32::MAX || directory_offset == u32::MAX {
+            return Err(zip64());
+        }
+        if u64::from(directory_offset) + u64::from(directory_len) > len {
+            return Err(invalid("the zip central directory is corrupt"));
+        }
+        let mut directory = vec![0; directory_len as usize];
+        file.seek(SeekFrom::Start(directory_offset.into()))?;
+        file.read_exact(&mut directory)?;
+
+        let mut offset = 0;
+        for _ in 0..count {
+            let header = directory
+                .get(offset..offset + 46)
+                .filter(|header| u32_at(header, 0) == zip::CENTRAL_HEADER)
+                .ok_or_else(|| invalid("the zip central directory is corrupt"))?;
+            let made_by_unix = header[5] == 3;
+            let method = u16_at(header, 10);
+            let crc = u32_at(header, 16);
+            let compressed_len = u32_at(header, 20);
+            let len = u32_at(header, 24);
+            let name_len = usize::from(u16_at(header, 28));
+            let trailer_len = usize::from(u16_at(header, 30)) + usize::from(u16_at(header, 32));
+            let mode = u32_at(header, 38) >> 16;
+            let local_offset = u32_at(header, 42);
+            if [compressed_len, len, local_offset].contains(&u32::MAX) {
+                return Err(zip64());
+            }
+            let name = directory
+                .get(offset + 46..offset + 46 + name_len)
+                .ok_or_else(|| invalid("the zip central directory is corrupt"))?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            offset += 46 + name_len + trailer_len;
+
+            let mut local = [0; 30];
+            file.seek(SeekFrom::Start(local_offset.into()))?;
+            file.read_exact(&mut local)?;
+            if u32_at(&local, 0) != zip::LOCAL_HEADER {
+                return Err(invalid("a zip local header is corrupt"));
+            }
+            let data_offset = u64::from(local_offset)
+                + 30
+                + u64::from(u16_at(&local, 26))
+                + u64::from(u16_at(&local, 28));
+            let path = self.entry(&name, len.into())?;
+            let file_type = if made_by_unix { mode & 0o170_000 } else { 0 };
+            if name.ends_with('/') || file_type == 0o040_000 {
+                self.dir(&path)?;
+                continue;
+            }
+            file.seek(SeekFrom::Start(data_offset))?;
+            let mut compressed = Vec::new();
+            file.take(compressed_len.into())
+                .read_to_end(&mut compressed)?;
+            let data = match method {
+                zip::STORED => compressed,
+                zip::DEFLATED => deflate::inflate(&compressed, len.into())?.0,
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::Unsupported,
+                        format!("zip compression method {method} isn't supported"),
+                    ))
+                }
+            };
+            let mut check = crate::digest::Crc32::default();
+            check.update(&data);
+            if data.len() as u64 != u64::from(len) || check.finish() != crc {
+                return Err(invalid("a zip entry's checksum doesn't match"));
+            }
+            if file_type == 0o120_000 {
+                let target = String::from_utf8(data)
+                    .map_err(|_| invalid("a zip symlink target isn't utf-8"))?;
+                self.symlink(&path, Path::new(&target))?;
+            } else {
+                let mode = (made_by_unix && mode != 0).then_some(mode);
+                self.file(&path, &mut data.as_slice(), mode, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts an entry holding `size` bytes against the limits, and checks its name stays in the
+    /// destination, returning the path it's unpacked to
+    fn entry(&mut self, name: &str, size: u64) -> io::Result<PathBuf> {
+        self.entries += 1;
+        if self.entries > self.options.max_entries {
+            return Err(too_many_entries());
+        }
+        self.size = self.size.saturating_add(size);
+        if self.size > self.options.max_size {
+            return Err(invalid("the archive is larger than the size limit"));
+        }
+        let path = normalize(Path::new(name)).map_err(|_| escapes(name))?;
+        if path.as_os_str().is_empty() {
+            return Ok(self.root.clone());
+        }
+        let mut dir = self.root.clone();
+        let mut components = path.components().peekable();
+        while let Some(component) = components.next() {
+            dir.push(component);
+            match std::fs::symlink_metadata(&dir) {
+                Ok(metadata) if metadata.is_symlink() && components.peek().is_some() => {
+                    return Err(invalid(&format!(
+                        "{name} would be unpacked through a symlink"
+                    )));
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound && components.peek().is_some() => {
+                    std::fs::create_dir(&dir)?;
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Clears the way for a new file or symlink at `path`
+    fn replace(&self, path: &Path) -> io::Result<()> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Err(ErrorKind::IsADirectory.into()),
+            Ok(_) if self.options.overwrite => std::fs::remove_file(path),
+            Ok(_) => Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            )),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn dir(&self, path: &Path) -> io::Result<()> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            _ => {
+                self.replace(path)?;
+                std::fs::create_dir(path)
+            }
+        }
+    }
+
+    fn file(
+        &mut self,
+        path: &Path,
+        data: &mut impl Read,
+        mode: Option<u32>,
+        mtime: u64,
+    ) -> io::Result<()> {
+        self.replace(path)?;
+        let mut file = File::options().write(true).create_new(true).open(path)?;
+        io::copy(data, &mut file)?;
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            // Without setuid, setgid or sticky bits
+            file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        if mtime > 0 {
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        self.files.insert(path.to_owned());
+        Ok(())
+    }
+
+    /// Creates a symlink to `target`, which has to be relative and can only lead out of the
+    /// directory holding the link with leading `..` components, and not out of the destination
+    ///
+    /// Targets are checked without following the links they pass through.  As every link they
+    /// could pass through has been checked in the same way, and nothing is unpacked through a
+    /// link, following them can't lead out of the destination either.
+    fn symlink(&self, path: &Path, target: &Path) -> io::Result<()> {
+        let parent = path.parent().unwrap_or(&self.root);
+        let depth = parent
+            .strip_prefix(&self.root)
+            .map_or(0, |parent| parent.components().count());
+        let mut ups = 0;
+        let mut descended = false;
+        for component in target.components() {
+            match component {
+                Component::ParentDir if !descended => ups += 1,
+                Component::Normal(_) => descended = true,
+                Component::CurDir => {}
+                _ => return Err(escapes(&target.display().to_string())),
+            }
+        }
+        if ups > depth {
+            return Err(escapes(&target.display().to_string()));
+        }
+        self.replace(path)?;
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, path);
+        #[cfg(not(unix))]
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "symlinks can only be unpacked on unix",
+        ))
+    }
+
+    /// Links `path` to `target`, which has to be a file unpacked earlier from the same archive
+    fn hard_link(&self, path: &Path, target: &Path) -> io::Result<()> {
+        let name = target.display().to_string();
+        let target = self
+            .root
+            .join(normalize(target).map_err(|_| escapes(&name))?);
+        if !self.files.contains(&target) {
+            return Err(invalid(&format!(
+                "{name} is linked to before it's unpacked"
+            )));
+        }
+        self.replace(path)?;
+        std::fs::hard_link(target, path)
+    }
+}
+
+/// Reads into `buf` until it's full or the reader ends, returning how much was read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(len) => read += len,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn escapes(name: &str) -> io::Error {
+    invalid(&format!("{name} leads out of the destination"))
+}
+
+fn too_many_entries() -> io::Error {
+    invalid("the archive has more entries than the limit")
+}
+
+fn zip64() -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, "zip64 archives aren't supported")
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{
+        archive::{ArchiveFilter, ArchiveFormat, Compression, Encoder},
+        backend::tar::{Entry, Kind},
+        test_kit::TestDir,
+        FileSystem, Request,
+    };
+
+    /// An archive of `entries`, which are paths with their contents, or the targets of symlinks
+    /// for paths starting with `@`
+    fn archive(format: ArchiveFormat, entries: &[(&str, &str)]) -> io::Result<Vec<u8>> {
+        let mut encoder = Encoder::new(format, Compression::Stored);
+        let mut archive = Vec::new();
+        for (path, contents) in entries {
+            if let Some(path) = path.strip_prefix('@') {
+                let entry = Entry::new(Kind::Symlink(contents.into()), 0);
+                archive.extend(encoder.entry(Path::new(path), &entry)?);
+            } else {
+                let entry = Entry::new(Kind::File, contents.len() as u64);
+                archive.extend(encoder.entry(Path::new(path), &entry)?);
+                archive.extend(encoder.data(contents.as_bytes())?);
+                archive.extend(encoder.end_file()?);
+            }
+        }
+        archive.extend(encoder.finish()?);
+        Ok(archive)
+    }
+
+    /// A ustar header for `name`, which [`archive`] would have normalized
+    fn raw_tar(name: &str, typeflag: u8, link: &str, data: &[u8]) -> Vec<u8> {
+        let mut block = [0; BLOCK];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        block[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        block[136..147].copy_from_slice(b"00000000000");
+        block[156] = typeflag;
+        block[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        block[148..156].fill(b' ');
+        let checksum: u64 = block.iter().copied().map(u64::from).sum();
+        block[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        let mut archive = block.to_vec();
+        archive.extend(data);
+        archive.extend(padding(data.len() as u64));
+        archive.extend([0; 2 * BLOCK]);
+        archive
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() -> io::Result<()> {
+        let dir = TestDir::new("extract")?;
+        std::fs::create_dir_all(dir.join("src/sub"))?;
+        std::fs::write(dir.join("src/sub/file.txt"), "nested ".repeat(1000))?;
+        std::fs::write(dir.join("src/top.txt"), "top")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("sub/file.txt", dir.join("src/link"))?;
+        let mut fs = FileSystem::new();
+        for (format, compression) in [
+            (ArchiveFormat::Tar, Compression::Stored),
+            (ArchiveFormat::Tar, Compression::Deflate),
+            (ArchiveFormat::Zip, Compression::Stored),
+            (ArchiveFormat::Zip, Compression::Deflate),
+        ] {
+            let packed = dir.join("packed");
+            let unpacked = dir.join("unpacked");
+            fs.call(Request::Archive {
+                src_dir: dir.join("src").into(),
+                dst: packed.as_path().into(),
+                format,
+                compression,
+                filter: ArchiveFilter::new(),
+            })
+            .await?
+            .into_done()?;
+            let extract = || Request::Extract {
+                archive: packed.as_path().into(),
+                dst_dir: unpacked.as_path().into(),
+                options: ExtractOptions::default(),
+            };
+            fs.call(extract()).await?.into_done()?;
+            assert_eq!(
+                std::fs::read(unpacked.join("sub/file.txt"))?,
+                "nested ".repeat(1000).as_bytes()
+            );
+            assert_eq!(std::fs::read(unpacked.join("top.txt"))?, b"top");
+            #[cfg(unix)]
+            assert_eq!(
+                std::fs::read_link(unpacked.join("link"))?,
+                Path::new("sub/file.txt")
+            );
+
+            let err = fs.call(extract()).await.err();
+            assert_eq!(
+                err.map(|err| err.kind()),
+                Some(ErrorKind::AlreadyExists),
+                "{format:?} {compression:?}"
+            );
+            let overwrite = Request::Extract {
+                archive: packed.as_path().into(),
+                dst_dir: unpacked.as_path().into(),
+                options: ExtractOptions {
+                    overwrite: true,
+                    ..ExtractOptions::default()
+                },
+            };
+            fs.call(overwrite).await?.into_done()?;
+
+            for options in [
+                ExtractOptions {
+                    max_entries: 2,
+                    ..ExtractOptions::default()
+                },
+                ExtractOptions {
+                    max_size: 1000,
+                    ..ExtractOptions::default()
+                },
+            ] {
+                let limited = Request::Extract {
+                    archive: packed.as_path().into(),
+                    dst_dir: dir.join("limited").into(),
+                    options,
+                };
+                let err = fs.call(limited).await.err();
+                assert_eq!(err.map(|err| err.kind()), Some(ErrorKind::InvalidData));
+                std::fs::remove_dir_all(dir.join("limited"))?;
+            }
+            std::fs::remove_dir_all(unpacked)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_escapes() -> io::Result<()> {
+        let dir = TestDir::new("escapes")?;
+        let packed = dir.join("packed");
+        let unpacked = dir.join("unpacked");
+        let mut archives = vec![
+            raw_tar("../outside.txt", b'0', "", b"escaped"),
+            raw_tar("link", b'1', "../outside.txt", b""),
+        ];
+        for format in [ArchiveFormat::Tar, ArchiveFormat::Zip] {
+            archives.extend([
+                archive(format, &[("@link", "/etc")])?,
+                archive(format, &[("@link", "../..")])?,
+                archive(format, &[("@dir/link", "../../outside")])?,
+                archive(format, &[("@link", "sub/../../outside")])?,
+                // The link stays in the destination, but nothing is unpacked through links
+                archive(format, &[("@link", "."), ("link/file.txt", "through")])?,
+            ]);
+        }
+        for (number, archive) in archives.into_iter().enumerate() {
+            std::fs::write(&packed, archive)?;
+            let err = extract(&packed, &unpacked, &ExtractOptions::default()).err();
+            assert_eq!(
+                err.map(|err| err.kind()),
+                Some(ErrorKind::InvalidData),
+                "archive {number}"
+            );
+            std::fs::remove_dir_all(&unpacked)?;
+        }
+        assert!(!dir.join("outside.txt").exists());
+
+        // Absolute names are unpacked relative to the destination, and links may lead up as far
+        // as the destination
+        let mut allowed = raw_tar("/abs/file.txt", b'0', "", b"absolute");
+        allowed.truncate(allowed.len() - 2 * BLOCK);
+        allowed.extend(archive(
+            ArchiveFormat::Tar,
+            &[
+                ("dir/file.txt", "file"),
+                ("@dir/sub/link", "../../dir/./file.txt"),
+            ],
+        )?);
+        std::fs::write(&packed, allowed)?;
+        extract(&packed, &unpacked, &ExtractOptions::default())?;
+        assert_eq!(std::fs::read(unpacked.join("abs/file.txt"))?, b"absolute");
+        assert_eq!(std::fs::read(unpacked.join("dir/sub/link"))?, b"file");
+        Ok(())
+    }
+}
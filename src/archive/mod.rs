@@ -0,0 +1,485 @@
+//! Packing directories into tar and zip archives, for [`Request::Archive`](crate::Request::Archive),
+//! and unpacking them, for [`Request::Extract`](crate::Request::Extract)
+//!
+//! Archives are built by an encoder which turns entries into the bytes of the archive without doing
+//! any IO itself, so the same encoder writes archives to local files and can stream them from
+//! other services.  Compression is DEFLATE, as gzip around a whole tar archive (`.tar.gz`) or on
+//! each file of a zip archive.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    backend::tar::{self, Entry, Kind},
+    glob::glob_match,
+    FileType, Metadata,
+};
+
+pub(crate) mod deflate;
+mod extract;
+mod zip;
+
+use deflate::GzipEncoder;
+pub(crate) use extract::extract;
+pub use extract::ExtractOptions;
+
+/// The size of the chunks files are read and compressed in
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ArchiveFormat {
+    /// A POSIX tar archive, with PAX headers for long names
+    #[default]
+    Tar,
+    /// A zip archive, which can't be larger than 4 GiB or hold more than 65535 entries
+    Zip,
+}
+
+/// How the contents of an archive are compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// The contents are stored as they are
+    #[default]
+    Stored,
+    /// The whole of a tar archive is compressed with gzip, or each file in a zip archive is
+    /// compressed with DEFLATE
+    Deflate,
+}
+
+/// Which paths below an archived directory are packed
+///
+/// Patterns are matched against paths relative to the directory, with `/` separators, where `*`
+/// matches any run of characters other than `/` and `**` any run of characters.  Excluded
+/// directories aren't descended into, while directories which aren't included still are, so files
+/// below them can be.  Without any include patterns, everything which isn't excluded is included.
+///
+/// ```
+/// use tower_fs::archive::ArchiveFilter;
+///
+/// let filter = ArchiveFilter::new().include("src/**").exclude("**.tmp");
+/// assert!(filter.matches("src/lib.rs"));
+/// assert!(!filter.matches("src/scratch.tmp"));
+/// assert!(!filter.matches("README.md"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ArchiveFilter {
+    pub(crate) include: Vec<String>,
+    pub(crate) exclude: Vec<String>,
+}
+
+impl ArchiveFilter {
+    /// A filter which packs everything
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Packs only paths matching `pattern`, or one of the other include patterns
+    #[must_use]
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Leaves out paths matching `pattern`, along with everything below them
+    #[must_use]
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Whether `path`, relative to the archived directory, is packed
+    #[must_use]
+    pub fn matches(&self, path: impl AsRef<Path>) -> bool {
+        let path = slashed(path.as_ref());
+        !self.excludes(&path)
+            && (self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|pattern| glob_match(pattern.as_bytes(), path.as_bytes())))
+    }
+
+    /// Whether `path`, relative to the archived directory, is left out along with everything below
+    /// it
+    pub(crate) fn skips(&self, path: &Path) -> bool {
+        self.excludes(&slashed(path))
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), path.as_bytes()))
+    }
+}
+
+/// Turns entries into the bytes of an archive
+///
+/// Each entry starts with [`Encoder::entry`].  A file's contents are then passed to
+/// [`Encoder::data`], and the file is ended with [`Encoder::end_file`].  Tar headers hold the size
+/// of a file before its contents, so in a tar archive the contents have to match the size in the
+/// file's [`Entry`].
+#[derive(Debug)]
+pub(crate) struct Encoder {
+    inner: Inner,
+}
+
+#[derive(Debug)]
+enum Inner {
+    Tar {
+        gzip: Option<GzipEncoder>,
+        /// The bytes of the current file which are yet to be written
+        remaining: u64,
+        size: u64,
+    },
+    Zip(zip::Writer),
+}
+
+impl Encoder {
+    pub(crate) fn new(format: ArchiveFormat, compression: Compression) -> Self {
+        let inner = match format {
+            ArchiveFormat::Tar => Inner::Tar {
+                gzip: (compression == Compression::Deflate).then(GzipEncoder::default),
+                remaining: 0,
+                size: 0,
+            },
+            ArchiveFormat::Zip => Inner::Zip(zip::Writer::new(compression)),
+        };
+        Self { inner }
+    }
+
+    /// Starts the entry for `path`, relative to the archived directory
+    pub(crate) fn entry(&mut self, path: &Path, entry: &Entry) -> io::Result<Vec<u8>> {
+        match &mut self.inner {
+            Inner::Tar {
+                gzip,
+                remaining,
+                size,
+            } => {
+                let headers = tar::headers(path, entry)?;
+                (*remaining, *size) = match entry.kind {
+                    Kind::File => (entry.size, entry.size),
+                    Kind::Directory | Kind::Symlink(_) => (0, 0),
+                };
+                Ok(compress(gzip, &headers))
+            }
+            Inner::Zip(writer) => writer.entry(path, entry),
+        }
+    }
+
+    /// Adds `data` to the contents of the current file
+    pub(crate) fn data(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match &mut self.inner {
+            Inner::Tar {
+                gzip, remaining, ..
+            } => {
+                *remaining = remaining
+                    .checked_sub(data.len() as u64)
+                    .ok_or_else(changed)?;
+                Ok(compress(gzip, data))
+            }
+            Inner::Zip(writer) => Ok(writer.data(data)),
+        }
+    }
+
+    /// Ends the current file
+    pub(crate) fn end_file(&mut self) -> io::Result<Vec<u8>> {
+        match &mut self.inner {
+            Inner::Tar {
+                gzip,
+                remaining,
+                size,
+            } => {
+                if *remaining != 0 {
+                    return Err(changed());
+                }
+                Ok(compress(gzip, &tar::padding(*size)))
+            }
+            Inner::Zip(writer) => writer.end_file(),
+        }
+    }
+
+    /// Ends the archive
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self.inner {
+            Inner::Tar { gzip: None, .. } => Ok(vec![0; 2 * tar::BLOCK]),
+            Inner::Tar {
+                gzip: Some(mut gzip),
+                ..
+            } => {
+                let mut out = gzip.compress(&[0; 2 * tar::BLOCK]);
+                out.extend(gzip.finish());
+                Ok(out)
+            }
+            Inner::Zip(writer) => writer.finish(),
+        }
+    }
+}
+
+fn compress(gzip: &mut Option<GzipEncoder>, data: &[u8]) -> Vec<u8> {
+    match gzip {
+        Some(gzip) => gzip.compress(data),
+        None => data.to_vec(),
+    }
+}
+
+fn changed() -> io::Error {
+    io::Error::other("a file changed size while it was being archived")
+}
+
+/// `path` with `/` separators
+fn slashed(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The entry packed for a file, directory or link with `metadata`, or `None` for devices, fifos
+/// and sockets, which have no contents to pack.  Links are packed pointing to `target`
+pub(crate) fn entry(metadata: &Metadata, target: Option<PathBuf>) -> Option<Entry> {
+    let kind = match (metadata.file_type(), target) {
+        (FileType::Dir, _) => Kind::Directory,
+        (FileType::Symlink, Some(target)) => Kind::Symlink(target),
+        (FileType::File, _) => Kind::File,
+        _ => return None,
+    };
+    let mut entry = Entry::new(kind, 0);
+    if entry.kind == Kind::File {
+        entry.size = metadata.len();
+    }
+    if let Some(mode) = metadata.permissions() {
+        entry.mode = mode;
+    }
+    if let Some(elapsed) = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    {
+        entry.mtime = elapsed.as_secs();
+    }
+    Some(entry)
+}
+
+/// Packs the local directory `src_dir` into `out`, which is the file at `dst`
+///
+/// `dst` is left out if it's below `src_dir`, rather than packing the archive into itself.
+pub(crate) fn pack(
+    src_dir: &Path,
+    dst: &Path,
+    out: File,
+    format: ArchiveFormat,
+    compression: Compression,
+    filter: &ArchiveFilter,
+) -> io::Result<()> {
+    let dst = std::fs::canonicalize(dst).ok();
+    let mut out = BufWriter::new(out);
+    pack_into(
+        src_dir,
+        dst.as_deref(),
+        &mut out,
+        format,
+        compression,
+        filter,
+    )?;
+    out.into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .sync_all()
+}
+
+/// Packs the local directory `src_dir` into `out`, leaving out `skip`, which must be canonical
+///
+/// Directories are walked in order of their names, each directory's entries before those of its
+/// subdirectories, and `out` is flushed once the archive is finished.
+pub(crate) fn pack_into(
+    src_dir: &Path,
+    skip: Option<&Path>,
+    out: &mut impl Write,
+    format: ArchiveFormat,
+    compression: Compression,
+    filter: &ArchiveFilter,
+) -> io::Result<()> {
+    let src_dir = std::fs::canonicalize(src_dir)?;
+    let mut encoder = Encoder::new(format, compression);
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let mut children = std::fs::read_dir(src_dir.join(&dir))?
+            .map(|entry| entry.map(|entry| dir.join(entry.file_name())))
+            .collect::<io::Result<Vec<_>>>()?;
+        children.sort();
+        let mut subdirs = Vec::new();
+        for path in children {
+            let full_path = src_dir.join(&path);
+            if filter.skips(&path) || Some(full_path.as_path()) == skip {
+                continue;
+            }
+            let metadata = Metadata::from(std::fs::symlink_metadata(&full_path)?);
+            if metadata.is_dir() {
+                subdirs.push(path.clone());
+            }
+            if !filter.matches(&path) {
+                continue;
+            }
+            let target = if metadata.is_symlink() {
+                Some(std::fs::read_link(&full_path)?)
+            } else {
+                None
+            };
+            let Some(entry) = entry(&metadata, target) else {
+                continue;
+            };
+            out.write_all(&encoder.entry(&path, &entry)?)?;
+            if entry.kind == Kind::File {
+                let mut file = File::open(&full_path)?;
+                let mut buf = vec![0; CHUNK];
+                loop {
+                    let read = match file.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(read) => read,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(err) => return Err(err),
+                    };
+                    out.write_all(&encoder.data(&buf[..read])?)?;
+                }
+                out.write_all(&encoder.end_file()?)?;
+            }
+        }
+        // Pushed in reverse, so they're popped in order
+        dirs.extend(subdirs.into_iter().rev());
+    }
+    out.write_all(&encoder.finish()?)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{backend::tar::Tar, test_kit::TestDir, FileSystem, Request, Response};
+
+    fn source(name: &str) -> io::Result<TestDir> {
+        let dir = TestDir::new(name)?;
+        std::fs::create_dir_all(dir.join("src/nested"))?;
+        std::fs::create_dir_all(dir.join("target"))?;
+        std::fs::write(dir.join("src/lib.rs"), "pub mod nested;\n".repeat(500))?;
+        std::fs::write(dir.join("src/nested/mod.rs"), "")?;
+        std::fs::write(dir.join("src/scratch.tmp"), "scratch")?;
+        std::fs::write(dir.join("target/build.o"), "object")?;
+        std::fs::write(dir.join("README.md"), "readme")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("src/lib.rs", dir.join("lib.rs"))?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = ArchiveFilter::new().exclude("target").exclude("**.tmp");
+        assert!(filter.matches("src/lib.rs"));
+        assert!(!filter.matches("target"));
+        assert!(!filter.matches("src/scratch.tmp"));
+        assert!(ArchiveFilter::new().matches("anything"));
+    }
+
+    #[tokio::test]
+    async fn test_archive_tar() -> io::Result<()> {
+        let dir = source("archive_tar")?;
+        let mut fs = FileSystem::new();
+        for (name, compression) in [
+            ("plain.tar", Compression::Stored),
+            ("compressed.tar.gz", Compression::Deflate),
+        ] {
+            // The archive is written into the directory being packed, and leaves itself out
+            let dst = dir.join(name);
+            fs.call(Request::Archive {
+                src_dir: dir.path().into(),
+                dst: dst.as_path().into(),
+                format: ArchiveFormat::Tar,
+                compression,
+                filter: ArchiveFilter::new().exclude("target").exclude("**.tmp"),
+            })
+            .await?
+            .into_done()?;
+
+            let mut archive = std::fs::read(&dst)?;
+            if compression == Compression::Deflate {
+                archive = deflate::gunzip(&archive, u64::MAX)?;
+                assert!(std::fs::metadata(&dst)?.len() < archive.len() as u64);
+            }
+            let unpacked = dir.join(format!("{name}.unpacked"));
+            std::fs::write(&unpacked, archive)?;
+            let mut tar = Tar::open(&unpacked).await?;
+            let read = |path: &str| Request::ReadBytes(Path::new(path).into());
+            let bytes = tar.call(read("src/lib.rs")).await?.into_bytes()?;
+            assert_eq!(bytes, "pub mod nested;\n".repeat(500).as_bytes());
+            assert!(tar
+                .call(read("src/nested/mod.rs"))
+                .await?
+                .into_bytes()?
+                .is_empty());
+            for missing in ["src/scratch.tmp", "target/build.o", name] {
+                let exists = tar.call(Request::Exists(Path::new(missing).into())).await?;
+                assert!(!exists.into_exists()?, "{missing}");
+            }
+            #[cfg(unix)]
+            {
+                let points_to = tar.call(Request::FollowLink(Path::new("lib.rs").into()));
+                assert_eq!(points_to.await?.into_points_to()?, Path::new("src/lib.rs"));
+            }
+            std::fs::remove_file(unpacked)?;
+            std::fs::remove_file(dst)?;
+        }
+        assert!(matches!(
+            fs.call(Request::Archive {
+                src_dir: dir.join("missing").into(),
+                dst: dir.join("missing.tar").into(),
+                format: ArchiveFormat::Tar,
+                compression: Compression::Stored,
+                filter: ArchiveFilter::new(),
+            })
+            .await,
+            Err(err) if err.kind() == ErrorKind::NotFound
+        ));
+        assert!(!dir.join("missing.tar").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_archive_zip() -> io::Result<()> {
+        let dir = source("archive_zip")?;
+        let dst = dir.join("archive.zip");
+        for compression in [Compression::Stored, Compression::Deflate] {
+            let response = FileSystem::new()
+                .call(Request::Archive {
+                    src_dir: dir.path().into(),
+                    dst: dst.as_path().into(),
+                    format: ArchiveFormat::Zip,
+                    compression,
+                    filter: ArchiveFilter::new().include("src/**"),
+                })
+                .await?;
+            assert!(matches!(response, Response::Done));
+            let files = zip::tests::read(&std::fs::read(&dst)?)?;
+            let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(
+                names,
+                [
+                    "src/lib.rs",
+                    "src/nested/",
+                    "src/scratch.tmp",
+                    "src/nested/mod.rs"
+                ]
+            );
+            assert_eq!(files[0].1, "pub mod nested;\n".repeat(500).as_bytes());
+            assert_eq!(files[2].1, b"scratch");
+        }
+        Ok(())
+    }
+}
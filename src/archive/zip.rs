@@ -0,0 +1,337 @@
+//! Writing zip archives, with each entry's sizes and checksum in a data descriptor after its
+//! contents so the archive can be written in one pass
+
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use super::{deflate::Deflater, slashed, Compression};
+use crate::{
+    backend::tar::{Entry, Kind},
+    date::DateTime,
+    digest::Crc32,
+};
+
+pub(super) const LOCAL_HEADER: u32 = 0x0403_4b50;
+pub(super) const CENTRAL_HEADER: u32 = 0x0201_4b50;
+pub(super) const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+/// Version 2.0, which added directories and DEFLATE
+const VERSION: u16 = 20;
+/// The sizes and checksum are in a data descriptor
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+/// Names are UTF-8
+const FLAG_UTF8: u16 = 1 << 11;
+pub(super) const STORED: u16 = 0;
+pub(super) const DEFLATED: u16 = 8;
+/// The high byte of "version made by" for unix, whose external attributes hold a mode
+const UNIX: u16 = 3 << 8;
+const DOS_DIRECTORY: u32 = 0x10;
+
+#[derive(Debug)]
+pub(super) struct Writer {
+    compression: Compression,
+    /// The number of bytes written so far
+    offset: u64,
+    central_directory: Vec<u8>,
+    entries: u16,
+    current: Option<Current>,
+}
+
+/// The file being written
+#[derive(Debug)]
+struct Current {
+    header: Header,
+    deflater: Option<Deflater>,
+    crc: Crc32,
+    len: u64,
+    compressed_len: u64,
+}
+
+/// The fields shared by an entry's local and central headers
+#[derive(Debug, Clone)]
+struct Header {
+    name: String,
+    method: u16,
+    time: u16,
+    date: u16,
+    mode: u32,
+    is_dir: bool,
+    offset: u32,
+}
+
+impl Writer {
+    pub(super) fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            offset: 0,
+            central_directory: Vec::new(),
+            entries: 0,
+            current: None,
+        }
+    }
+
+    pub(super) fn entry(&mut self, path: &Path, entry: &Entry) -> io::Result<Vec<u8>> {
+        let mut name = slashed(path);
+        let (file_type, contents) = match &entry.kind {
+            Kind::File => (0o100_000, None),
+            Kind::Directory => {
+                name.push('/');
+                (0o040_000, Some(Vec::new()))
+            }
+            Kind::Symlink(target) => (0o120_000, Some(slashed_target(target)?.into_bytes())),
+        };
+        let method = match (&entry.kind, self.compression) {
+            (Kind::File, Compression::Deflate) => DEFLATED,
+            _ => STORED,
+        };
+        let (time, date) = dos_date_time(entry.mtime);
+        let header = Header {
+            name,
+            method,
+            time,
+            date,
+            mode: file_type | entry.mode,
+            is_dir: entry.kind == Kind::Directory,
+            offset: u32::try_from(self.offset).map_err(|_| too_large())?,
+        };
+        let mut out = Vec::with_capacity(30 + header.name.len());
+        put_u32(&mut out, LOCAL_HEADER);
+        put_u16(&mut out, VERSION);
+        put_u16(&mut out, FLAG_DATA_DESCRIPTOR | FLAG_UTF8);
+        put_u16(&mut out, header.method);
+        put_u16(&mut out, header.time);
+        put_u16(&mut out, header.date);
+        // The checksum and sizes, which are in the data descriptor instead
+        out.extend([0; 12]);
+        put_u16(&mut out, name_len(&header.name)?);
+        // No extra field
+        put_u16(&mut out, 0);
+        out.extend(header.name.as_bytes());
+        self.offset += out.len() as u64;
+        self.current = Some(Current {
+            deflater: (method == DEFLATED).then(Deflater::default),
+            header,
+            crc: Crc32::default(),
+            len: 0,
+            compressed_len: 0,
+        });
+        if let Some(contents) = contents {
+            out.extend(self.data(&contents));
+            out.extend(self.end_file()?);
+        }
+        Ok(out)
+    }
+
+    pub(super) fn data(&mut self, data: &[u8]) -> Vec<u8> {
+        let Some(current) = &mut self.current else {
+            return Vec::new();
+        };
+        current.crc.update(data);
+        current.len += data.len() as u64;
+        let out = match &mut current.deflater {
+            Some(deflater) => deflater.compress(data),
+            None => data.to_vec(),
+        };
+        current.compressed_len += out.len() as u64;
+        self.offset += out.len() as u64;
+        out
+    }
+
+    pub(super) fn end_file(&mut self) -> io::Result<Vec<u8>> {
+        let Some(mut current) = self.current.take() else {
+            return Ok(Vec::new());
+        };
+        let mut out = current
+            .deflater
+            .take()
+            .map(Deflater::finish)
+            .unwrap_or_default();
+        current.compressed_len += out.len() as u64;
+        let crc = current.crc.finish();
+        let len = u32::try_from(current.len).map_err(|_| too_large())?;
+        let compressed_len = u32::try_from(current.compressed_len).map_err(|_| too_large())?;
+        put_u32(&mut out, DATA_DESCRIPTOR);
+        put_u32(&mut out, crc);
+        put_u32(&mut out, compressed_len);
+        put_u32(&mut out, len);
+        self.offset += out.len() as u64;
+
+        let header = current.header;
+        let central = &mut self.central_directory;
+        put_u32(central, CENTRAL_HEADER);
+        put_u16(central, UNIX | VERSION);
+        put_u16(central, VERSION);
+        put_u16(central, FLAG_DATA_DESCRIPTOR | FLAG_UTF8);
+        put_u16(central, header.method);
+        put_u16(central, header.time);
+        put_u16(central, header.date);
+        put_u32(central, crc);
+        put_u32(central, compressed_len);
+        put_u32(central, len);
+        put_u16(central, name_len(&header.name)?);
+        // No extra field or comment, on the first disk, with no internal attributes
+        central.extend([0; 8]);
+        put_u32(
+            central,
+            header.mode << 16 | if header.is_dir { DOS_DIRECTORY } else { 0 },
+        );
+        put_u32(central, header.offset);
+        central.extend(header.name.as_bytes());
+        self.entries = self.entries.checked_add(1).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::Unsupported,
+                "zip archives can't hold more than 65535 entries",
+            )
+        })?;
+        Ok(out)
+    }
+
+    pub(super) fn finish(mut self) -> io::Result<Vec<u8>> {
+        let mut out = self.end_file()?;
+        let offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+        let len = u32::try_from(self.central_directory.len()).map_err(|_| too_large())?;
+        out.append(&mut self.central_directory);
+        put_u32(&mut out, END_OF_CENTRAL_DIRECTORY);
+        // This disk, and the disk the central directory starts on
+        out.extend([0; 4]);
+        put_u16(&mut out, self.entries);
+        put_u16(&mut out, self.entries);
+        put_u32(&mut out, len);
+        put_u32(&mut out, offset);
+        // No comment
+        put_u16(&mut out, 0);
+        Ok(out)
+    }
+}
+
+fn slashed_target(target: &Path) -> io::Result<String> {
+    target
+        .to_str()
+        .map(|target| target.replace('\\', "/"))
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "symlink targets must be utf-8"))
+}
+
+fn name_len(name: &str) -> io::Result<u16> {
+    u16::try_from(name.len()).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            "zip entry names must fit in 64 KiB",
+        )
+    })
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "zip archives larger than 4 GiB aren't supported",
+    )
+}
+
+/// The MS-DOS time and date of `mtime`, clamped to the range they can hold (1980 to 2107)
+fn dos_date_time(mtime: u64) -> (u16, u16) {
+    let date = DateTime::from(UNIX_EPOCH + Duration::from_secs(mtime));
+    if date.year < 1980 {
+        // Midnight on the 1st of January 1980
+        return (0, 1 << 5 | 1);
+    }
+    if date.year > 2107 {
+        // 23:59:58 on the 31st of December 2107
+        return (0xbf7d, 0xff9f);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let time = (date.hour << 11 | date.minute << 5 | (date.second / 2)) as u16;
+    #[allow(clippy::cast_possible_truncation)]
+    let date = ((date.year - 1980) << 9 | date.month << 5 | date.day) as u16;
+    (time, date)
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend(value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::archive::deflate::inflate;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    /// The names and contents of the entries in a zip archive, found through its central
+    /// directory
+    pub(in crate::archive) fn read(archive: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), END_OF_CENTRAL_DIRECTORY);
+        let mut offset = u32_at(archive, end + 16) as usize;
+        let mut entries = Vec::new();
+        for _ in 0..u16_at(archive, end + 10) {
+            assert_eq!(u32_at(archive, offset), CENTRAL_HEADER);
+            let method = u16_at(archive, offset + 10);
+            let crc = u32_at(archive, offset + 16);
+            let compressed_len = u32_at(archive, offset + 20) as usize;
+            let name_len = usize::from(u16_at(archive, offset + 28));
+            let local = u32_at(archive, offset + 42) as usize;
+            let name = &archive[offset + 46..offset + 46 + name_len];
+            offset += 46 + name_len;
+
+            assert_eq!(u32_at(archive, local), LOCAL_HEADER);
+            let start = local + 30 + name_len;
+            let data = &archive[start..start + compressed_len];
+            let data = match method {
+                STORED => data.to_vec(),
+                DEFLATED => inflate(data, u64::MAX)?.0,
+                method => panic!("unexpected compression method {method}"),
+            };
+            let mut check = Crc32::default();
+            check.update(&data);
+            assert_eq!(check.finish(), crc);
+            entries.push((String::from_utf8_lossy(name).into_owned(), data));
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_writer() -> io::Result<()> {
+        let mut writer = Writer::new(Compression::Deflate);
+        let mut archive = writer.entry(Path::new("dir"), &Entry::new(Kind::Directory, 0))?;
+        let link = Entry::new(Kind::Symlink("../target".into()), 0);
+        archive.extend(writer.entry(Path::new("dir/link"), &link)?);
+        archive.extend(writer.entry(Path::new("dir/file"), &Entry::new(Kind::File, 10))?);
+        archive.extend(writer.data(b"hello "));
+        archive.extend(writer.data(b"hello"));
+        archive.extend(writer.finish()?);
+        assert_eq!(
+            read(&archive)?,
+            [
+                ("dir/".to_owned(), Vec::new()),
+                ("dir/link".to_owned(), b"../target".to_vec()),
+                ("dir/file".to_owned(), b"hello hello".to_vec()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dos_date_time() {
+        assert_eq!(dos_date_time(0), (0, 0x21));
+        // 13:45:30 on the 29th of February 2024
+        assert_eq!(dos_date_time(1_709_214_330), (0x6daf, 0x585d));
+    }
+}
@@ -0,0 +1,1006 @@
+use std::{
+    fmt::{self, Write},
+    io::{self, ErrorKind},
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt};
+use http::{
+    header::{HeaderName, CONTENT_LENGTH, RANGE},
+    HeaderMap, Method, StatusCode, Uri,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tower_service::Service;
+
+use super::{
+    key, rest,
+    rest::{
+        client_error, file_metadata, header_value, range_header, trim_to_range, xml_elements,
+        xml_values, BoxError,
+    },
+};
+use crate::{
+    date::DateTime,
+    digest::{base64, base64_decode, hmac_sha256},
+    CopyOptions, FileType, Metadata, Request, Response,
+};
+
+const API_VERSION: &str = "2021-08-06";
+const FOLDER_METADATA: &str = "x-ms-meta-hdi_isfolder";
+const COPY_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+const ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+const ENCODE_PATH: &AsciiSet = &ENCODE.remove(b'/');
+
+/// A backend which stores files as block blobs in an Azure Storage container
+///
+/// Like [`S3`](super::s3::S3), requests are sent through `C`, any HTTP client implementing
+/// `Service<http::Request<Bytes>, Response = http::Response<Bytes>>`, and are authorized with the
+/// account's shared key.
+///
+/// Writes larger than [`Config::single_upload_limit`] are staged as blocks and committed with a
+/// block list.  Without a hierarchical namespace directories are synthetic: [`Request::CreateDir`]
+/// writes an empty blob marked with `hdi_isfolder` metadata, and any blob name prefix counts as a
+/// directory.  When [`Config::dfs_endpoint`] is set, directory creation, removal and renames instead
+/// go through the Data Lake API, so they are real directories and renames are atomic.
+///
+/// [`Request::GetMetadata`] reads a blob's properties, giving it's size and last modified time.
+/// [`Request::ReadDir`] lists the blobs under a directory's prefix with a `/` delimiter, so each
+/// deeper prefix, like each folder blob, is listed as a directory.
+/// [`Request::Open`], as well as links and permission changes, fail with
+/// [`ErrorKind::Unsupported`].
+#[derive(Debug, Clone)]
+pub struct Azure<C> {
+    client: C,
+    config: Arc<Config>,
+}
+
+impl<C> Azure<C> {
+    pub fn new(client: C, config: Config) -> Self {
+        Self {
+            client,
+            config: Arc::new(config),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub account: String,
+    pub key: AccountKey,
+    pub container: String,
+    /// Scheme and authority of the blob service, e.g. `https://<account>.blob.core.windows.net`
+    pub blob_endpoint: Uri,
+    /// Scheme and authority of the Data Lake service, for accounts with a hierarchical namespace
+    pub dfs_endpoint: Option<Uri>,
+    /// Writes larger than this many bytes are staged as multiple blocks
+    pub single_upload_limit: usize,
+    /// Size of each staged block
+    pub block_size: usize,
+}
+
+impl Config {
+    /// Creates a configuration using the public Azure endpoints for `account`, which stages writes
+    /// over 16 MiB as 8 MiB blocks
+    ///
+    /// # Errors
+    ///
+    /// If `account` isn't a valid host name component
+    pub fn new(
+        account: impl Into<String>,
+        key: AccountKey,
+        container: impl Into<String>,
+    ) -> io::Result<Self> {
+        let account = account.into();
+        let blob_endpoint = format!("https://{account}.blob.core.windows.net")
+            .parse()
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        Ok(Self {
+            account,
+            key,
+            container: container.into(),
+            blob_endpoint,
+            dfs_endpoint: None,
+            single_upload_limit: 16 * 1024 * 1024,
+            block_size: 8 * 1024 * 1024,
+        })
+    }
+
+    /// Uses the public Data Lake endpoint for directory operations, for accounts with a
+    /// hierarchical namespace
+    ///
+    /// # Errors
+    ///
+    /// If the account name isn't a valid host name component
+    pub fn with_hierarchical_namespace(mut self) -> io::Result<Self> {
+        self.dfs_endpoint = Some(
+            format!("https://{}.dfs.core.windows.net", self.account)
+                .parse()
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?,
+        );
+        Ok(self)
+    }
+
+    /// The encoded path of `key` in the container
+    fn path(&self, key: &str) -> String {
+        utf8_percent_encode(&format!("/{}/{key}", self.container), ENCODE_PATH).to_string()
+    }
+
+    /// Builds a request against `endpoint`, authorized with the account's shared key
+    #[allow(clippy::too_many_arguments)]
+    fn sign(
+        &self,
+        endpoint: &Uri,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        mut headers: HeaderMap,
+        body: Bytes,
+        now: SystemTime,
+    ) -> io::Result<http::Request<Bytes>> {
+        headers.insert("x-ms-date", header_value(&DateTime::from(now).http())?);
+        headers.insert("x-ms-version", header_value(API_VERSION)?);
+        if !body.is_empty() || matches!(method, Method::PUT | Method::POST) {
+            headers.insert(CONTENT_LENGTH, body.len().into());
+        }
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        let content_length = match header("content-length") {
+            "0" => "",
+            len => len,
+        };
+        let mut string_to_sign = format!(
+            "{method}\n{}\n{}\n{content_length}\n{}\n{}\n\n{}\n{}\n{}\n{}\n{}\n",
+            header("content-encoding"),
+            header("content-language"),
+            header("content-md5"),
+            header("content-type"),
+            header("if-modified-since"),
+            header("if-match"),
+            header("if-none-match"),
+            header("if-unmodified-since"),
+            header("range"),
+        );
+        let mut ms_headers: Vec<_> = headers
+            .keys()
+            .map(HeaderName::as_str)
+            .filter(|name| name.starts_with("x-ms-"))
+            .collect();
+        ms_headers.sort_unstable();
+        for name in ms_headers {
+            let _ = writeln!(string_to_sign, "{name}:{}", header(name).trim());
+        }
+        let _ = write!(string_to_sign, "/{}{path}", self.account);
+        let mut params: Vec<_> = query
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), *value))
+            .collect();
+        params.sort_unstable();
+        for (name, value) in params {
+            let _ = write!(string_to_sign, "\n{name}:{value}");
+        }
+
+        let signature = base64(&hmac_sha256(&self.key.0, string_to_sign.as_bytes()));
+        headers.insert(
+            http::header::AUTHORIZATION,
+            header_value(&format!("SharedKey {}:{signature}", self.account))?,
+        );
+
+        let query = query
+            .iter()
+            .map(|(name, value)| format!("{name}={}", utf8_percent_encode(value, ENCODE)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let authority = endpoint
+            .authority()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "endpoint has no authority"))?;
+        let scheme = endpoint.scheme_str().unwrap_or("https");
+        let uri = if query.is_empty() {
+            format!("{scheme}://{authority}{path}")
+        } else {
+            format!("{scheme}://{authority}{path}?{query}")
+        };
+        let mut request = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+        *request.headers_mut() = headers;
+        Ok(request)
+    }
+}
+
+/// The (decoded) shared key of a storage account
+#[derive(Clone)]
+pub struct AccountKey(Vec<u8>);
+
+impl AccountKey {
+    /// Decodes a key in the base64 form the Azure portal displays it in
+    ///
+    /// # Errors
+    ///
+    /// If `key` isn't valid base64
+    pub fn from_base64(key: &str) -> io::Result<Self> {
+        base64_decode(key)
+            .map(Self)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "account key isn't base64"))
+    }
+}
+
+impl fmt::Debug for AccountKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccountKey(<redacted>)")
+    }
+}
+
+impl<C> Service<Request> for Azure<C>
+where
+    C: Service<http::Request<Bytes>, Response = http::Response<Bytes>> + Clone + Send + 'static,
+    C::Error: Into<BoxError>,
+    C::Future: Send,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.client.poll_ready(cx).map_err(client_error)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Keep the client which was driven to readiness for this request
+        let client = self.client.clone();
+        let mut container = Container {
+            client: std::mem::replace(&mut self.client, client),
+            config: self.config.clone(),
+        };
+        async move { container.handle(req).await }.boxed()
+    }
+}
+
+struct Container<C> {
+    client: C,
+    config: Arc<Config>,
+}
+
+/// What a HEAD request found at a blob name
+enum Blob {
+    File(Metadata),
+    Folder,
+}
+
+impl<C> Container<C>
+where
+    C: Service<http::Request<Bytes>, Response = http::Response<Bytes>>,
+    C::Error: Into<BoxError>,
+{
+    async fn handle(&mut self, req: Request) -> io::Result<Response> {
+        match req {
+            Request::Compact | Request::Flush => Ok(Response::Done),
+            Request::Copy { from, to, options } if options == CopyOptions::default() => self
+                .copy(&key(&from)?, &key(&to)?)
+                .await
+                .map(Response::Copied),
+            Request::CreateDir { path, recursive } => self
+                .create_dir(&key(&path)?, recursive)
+                .await
+                .map(Response::done),
+            Request::Exists(path) => self.exists(&key(&path)?).await.map(Response::Exists),
+            Request::GetMetadata { path, .. } => {
+                self.metadata(&key(&path)?).await.map(Response::Metadata)
+            }
+            Request::ReadBytes(path) => self.read(&key(&path)?, None).await.map(Response::Bytes),
+            Request::ReadDir(path) => self.read_dir(&key(&path)?).await.map(Response::Directory),
+            Request::ReadRange { path, range } => self
+                .read(&key(&path)?, Some(&range))
+                .await
+                .map(Response::Bytes),
+            Request::RemoveDir { path, recursive } => self
+                .remove_dir(&key(&path)?, recursive)
+                .await
+                .map(Response::done),
+            Request::RemoveFile(path) => self.remove_file(&key(&path)?).await.map(Response::done),
+            Request::Rename { from, to } => self
+                .rename(&key(&from)?, &key(&to)?)
+                .await
+                .map(Response::done),
+            Request::WriteBytes { path, bytes } => self
+                .write(&key(&path)?, bytes.into())
+                .await
+                .map(Response::done),
+            Request::FollowLink(_)
+            | Request::HardLink { .. }
+            | Request::Open { .. }
+            | Request::SetPermissions { .. }
+            | Request::AppendBytes { .. }
+            | Request::CopyDir { .. }
+            | Request::MetadataBatch { .. }
+            | Request::Copy { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(unix)]
+            Request::Symlink { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(windows)]
+            Request::SymlinkDir { .. }
+            | Request::SymlinkFile { .. }
+            | Request::ReadJunction(_)
+            | Request::OpenStream { .. }
+            | Request::GetFileAttributes(_)
+            | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "archive")]
+            Request::Archive { .. } | Request::Extract { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "dedup")]
+            Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "posix-acl")]
+            Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+            #[cfg(unix)]
+            Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+        }
+    }
+
+    async fn send(
+        &mut self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> io::Result<http::Response<Bytes>> {
+        let request = self.config.sign(
+            &self.config.blob_endpoint,
+            method,
+            &self.config.path(key),
+            query,
+            headers,
+            body,
+            SystemTime::now(),
+        )?;
+        rest::send(&mut self.client, request).await
+    }
+
+    /// Sends a Data Lake request, if the account has a hierarchical namespace
+    async fn send_dfs(
+        &mut self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: HeaderMap,
+    ) -> Option<io::Result<http::Response<Bytes>>> {
+        let endpoint = self.config.dfs_endpoint.as_ref()?;
+        let request = self.config.sign(
+            endpoint,
+            method,
+            &self.config.path(key),
+            query,
+            headers,
+            Bytes::new(),
+            SystemTime::now(),
+        );
+        Some(match request {
+            Ok(request) => rest::send(&mut self.client, request).await,
+            Err(err) => Err(err),
+        })
+    }
+
+    async fn head(&mut self, key: &str) -> io::Result<Option<Blob>> {
+        if key.is_empty() {
+            return Ok(Some(Blob::Folder));
+        }
+        let response = self
+            .send(Method::HEAD, key, &[], HeaderMap::new(), Bytes::new())
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let headers = response.headers().clone();
+        check(response)?;
+        if headers
+            .get(FOLDER_METADATA)
+            .is_some_and(|folder| folder.as_bytes().eq_ignore_ascii_case(b"true"))
+        {
+            return Ok(Some(Blob::Folder));
+        }
+        Ok(Some(Blob::File(file_metadata(&headers))))
+    }
+
+    /// Lists the blob names starting with `prefix`, stopping early once `limit` have been found
+    async fn list(&mut self, prefix: &str, limit: Option<usize>) -> io::Result<Vec<String>> {
+        let pages = self.list_pages(prefix, false, limit).await?;
+        Ok(pages
+            .iter()
+            .flat_map(|xml| xml_values(xml, "Name"))
+            .collect())
+    }
+
+    /// Sends List Blobs for the names starting with `prefix`, following markers until the listing
+    /// is complete or `limit` names have been found, and returns each page.  With `delimited`,
+    /// names with a `/` after the prefix are rolled up into `BlobPrefix`es, and each blob's
+    /// metadata is included so folders can be told apart.
+    async fn list_pages(
+        &mut self,
+        prefix: &str,
+        delimited: bool,
+        limit: Option<usize>,
+    ) -> io::Result<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut names = 0;
+        let mut marker: Option<String> = None;
+        let max_results = limit.map(|limit| limit.to_string());
+        loop {
+            let mut query = vec![
+                ("restype", "container"),
+                ("comp", "list"),
+                ("prefix", prefix),
+            ];
+            if delimited {
+                query.extend([("delimiter", "/"), ("include", "metadata")]);
+            }
+            if let Some(max_results) = &max_results {
+                query.push(("maxresults", max_results));
+            }
+            if let Some(marker) = &marker {
+                query.push(("marker", marker.as_str()));
+            }
+            let request = self.config.sign(
+                &self.config.blob_endpoint,
+                Method::GET,
+                &utf8_percent_encode(&format!("/{}", self.config.container), ENCODE_PATH)
+                    .to_string(),
+                &query,
+                HeaderMap::new(),
+                Bytes::new(),
+                SystemTime::now(),
+            )?;
+            let body = check(rest::send(&mut self.client, request).await?)?;
+            let xml = String::from_utf8_lossy(&body).into_owned();
+            names += xml_elements(&xml, "Name").len();
+            marker = xml_values(&xml, "NextMarker")
+                .pop()
+                .filter(|marker| !marker.is_empty());
+            pages.push(xml);
+            if marker.is_none() || limit.is_some_and(|limit| names >= limit) {
+                return Ok(pages);
+            }
+        }
+    }
+
+    /// Lists the blobs and blob prefixes directly under the directory `key`
+    async fn read_dir(&mut self, key: &str) -> io::Result<Vec<(PathBuf, Metadata)>> {
+        let prefix = if key.is_empty() {
+            String::new()
+        } else {
+            format!("{key}/")
+        };
+        let pages = self.list_pages(&prefix, true, None).await?;
+        let mut entries = Vec::new();
+        for xml in &pages {
+            for blob in xml_elements(xml, "Blob") {
+                let Some(name) = xml_values(blob, "Name")
+                    .pop()
+                    .and_then(|child| child.strip_prefix(&prefix).map(str::to_owned))
+                else {
+                    continue;
+                };
+                let folder = xml_values(blob, "hdi_isfolder")
+                    .iter()
+                    .any(|folder| folder.eq_ignore_ascii_case("true"));
+                let metadata = if folder {
+                    Metadata::new(FileType::Dir, 0)
+                } else {
+                    let len = xml_values(blob, "Content-Length")
+                        .pop()
+                        .and_then(|len| len.parse().ok())
+                        .unwrap_or_default();
+                    let metadata = Metadata::new(FileType::File, len);
+                    let modified = xml_values(blob, "Last-Modified")
+                        .pop()
+                        .and_then(|modified| DateTime::parse_http(&modified));
+                    match modified {
+                        Some(modified) => metadata.with_modified(modified.into()),
+                        None => metadata,
+                    }
+                };
+                entries.push((PathBuf::from(name), metadata));
+            }
+            for blob_prefix in xml_elements(xml, "BlobPrefix") {
+                let dirs = xml_values(blob_prefix, "Name");
+                let names = dirs.iter().filter_map(|dir| dir.strip_prefix(&prefix));
+                entries.extend(names.map(|name| {
+                    let name = PathBuf::from(name.trim_end_matches('/'));
+                    (name, Metadata::new(FileType::Dir, 0))
+                }));
+            }
+        }
+        // Folders with children are listed both as a blob and a prefix
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.dedup_by(|(a, _), (b, _)| a == b);
+        if entries.is_empty() {
+            match self.head(key).await? {
+                Some(Blob::Folder) => {}
+                Some(Blob::File(_)) => return Err(ErrorKind::NotADirectory.into()),
+                None => return Err(ErrorKind::NotFound.into()),
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn is_dir(&mut self, key: &str) -> io::Result<bool> {
+        if matches!(self.head(key).await?, Some(Blob::Folder)) {
+            return Ok(true);
+        }
+        Ok(!self.list(&format!("{key}/"), Some(1)).await?.is_empty())
+    }
+
+    /// Reads the properties of the blob at `key`, or describes a directory if it's a folder or the
+    /// prefix of any blob
+    async fn metadata(&mut self, key: &str) -> io::Result<Metadata> {
+        match self.head(key).await? {
+            Some(Blob::File(metadata)) => Ok(metadata),
+            Some(Blob::Folder) => Ok(Metadata::new(FileType::Dir, 0)),
+            None if !self.list(&format!("{key}/"), Some(1)).await?.is_empty() => {
+                Ok(Metadata::new(FileType::Dir, 0))
+            }
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn exists(&mut self, key: &str) -> io::Result<bool> {
+        if self.head(key).await?.is_some() {
+            return Ok(true);
+        }
+        self.is_dir(key).await
+    }
+
+    async fn read(&mut self, key: &str, range: Option<&Range<u64>>) -> io::Result<Vec<u8>> {
+        match self.head(key).await? {
+            Some(Blob::Folder) => return Err(ErrorKind::IsADirectory.into()),
+            None if self.is_dir(key).await? => return Err(ErrorKind::IsADirectory.into()),
+            _ => {}
+        }
+        let mut headers = HeaderMap::new();
+        if let Some(range) = range {
+            headers.insert(RANGE, range_header(range)?);
+        }
+        let response = self
+            .send(Method::GET, key, &[], headers, Bytes::new())
+            .await?;
+        match response.status() {
+            // The range starts past the end of the blob
+            StatusCode::RANGE_NOT_SATISFIABLE if range.is_some() => Ok(Vec::new()),
+            status => check(response).map(|body| match range {
+                Some(range) => trim_to_range(status, body, range).into(),
+                None => body.into(),
+            }),
+        }
+    }
+
+    async fn write(&mut self, key: &str, bytes: Bytes) -> io::Result<()> {
+        if self.is_dir(key).await? {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        if bytes.len() <= self.config.single_upload_limit {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-ms-blob-type", header_value("BlockBlob")?);
+            return check(self.send(Method::PUT, key, &[], headers, bytes).await?).map(drop);
+        }
+
+        let block_size = self.config.block_size.max(1);
+        let mut block_list = String::from(r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#);
+        for (index, start) in (0..bytes.len()).step_by(block_size).enumerate() {
+            // Every block id in a blob must have the same length
+            let block_id = base64(format!("{index:08}").as_bytes());
+            let block = bytes.slice(start..bytes.len().min(start + block_size));
+            check(
+                self.send(
+                    Method::PUT,
+                    key,
+                    &[("comp", "block"), ("blockid", &block_id)],
+                    HeaderMap::new(),
+                    block,
+                )
+                .await?,
+            )?;
+            let _ = write!(block_list, "<Latest>{block_id}</Latest>");
+        }
+        block_list.push_str("</BlockList>");
+        check(
+            self.send(
+                Method::PUT,
+                key,
+                &[("comp", "blocklist")],
+                HeaderMap::new(),
+                block_list.into(),
+            )
+            .await?,
+        )
+        .map(drop)
+    }
+
+    async fn copy(&mut self, from: &str, to: &str) -> io::Result<u64> {
+        let size = match self.head(from).await? {
+            Some(Blob::File(metadata)) => metadata.len(),
+            Some(Blob::Folder) => return Err(ErrorKind::IsADirectory.into()),
+            None => return Err(ErrorKind::NotFound.into()),
+        };
+        let authority =
+            self.config.blob_endpoint.authority().ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "endpoint has no authority")
+            })?;
+        let source = format!(
+            "{}://{authority}{}",
+            self.config.blob_endpoint.scheme_str().unwrap_or("https"),
+            self.config.path(from)
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-copy-source", header_value(&source)?);
+        let response = self
+            .send(Method::PUT, to, &[], headers, Bytes::new())
+            .await?;
+        let mut status = copy_status(&response);
+        check(response)?;
+
+        // Copies within an account usually complete synchronously, but aren't guaranteed to
+        while status.as_deref() == Some("pending") {
+            tokio::time::sleep(COPY_POLL_INTERVAL).await;
+            let response = self
+                .send(Method::HEAD, to, &[], HeaderMap::new(), Bytes::new())
+                .await?;
+            status = copy_status(&response);
+            check(response)?;
+        }
+        match status.as_deref() {
+            Some("success") | None => Ok(size),
+            Some(status) => Err(io::Error::other(format!("blob copy {status}"))),
+        }
+    }
+
+    async fn delete(&mut self, key: &str) -> io::Result<()> {
+        check(
+            self.send(Method::DELETE, key, &[], HeaderMap::new(), Bytes::new())
+                .await?,
+        )
+        .map(drop)
+    }
+
+    async fn remove_file(&mut self, key: &str) -> io::Result<()> {
+        match self.head(key).await? {
+            Some(Blob::File(_)) => self.delete(key).await,
+            Some(Blob::Folder) => Err(ErrorKind::IsADirectory.into()),
+            None if self.is_dir(key).await? => Err(ErrorKind::IsADirectory.into()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn create_dir(&mut self, key: &str, recursive: bool) -> io::Result<()> {
+        if self.exists(key).await? {
+            return if recursive && self.is_dir(key).await? {
+                Ok(())
+            } else {
+                Err(ErrorKind::AlreadyExists.into())
+            };
+        }
+        if self.config.dfs_endpoint.is_some() {
+            let parent = key.rsplit_once('/').map_or("", |(parent, _)| parent);
+            if !recursive && !self.is_dir(parent).await? {
+                return Err(ErrorKind::NotFound.into());
+            }
+            if let Some(response) = self
+                .send_dfs(
+                    Method::PUT,
+                    key,
+                    &[("resource", "directory")],
+                    HeaderMap::new(),
+                )
+                .await
+            {
+                return check(response?).map(drop);
+            }
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-blob-type", header_value("BlockBlob")?);
+        headers.insert(FOLDER_METADATA, header_value("true")?);
+        check(
+            self.send(Method::PUT, key, &[], headers, Bytes::new())
+                .await?,
+        )
+        .map(drop)
+    }
+
+    async fn remove_dir(&mut self, key: &str, recursive: bool) -> io::Result<()> {
+        if key.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "can't remove the root of the container",
+            ));
+        }
+        let marker = self.head(key).await?;
+        if matches!(marker, Some(Blob::File(_))) {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+
+        let recursive_param = if recursive { "true" } else { "false" };
+        let mut continuation: Option<String> = None;
+        if self.config.dfs_endpoint.is_some() {
+            // Recursive deletes of large directories are split across several requests
+            loop {
+                let mut query = vec![("recursive", recursive_param)];
+                if let Some(continuation) = &continuation {
+                    query.push(("continuation", continuation.as_str()));
+                }
+                let Some(response) = self
+                    .send_dfs(Method::DELETE, key, &query, HeaderMap::new())
+                    .await
+                else {
+                    break;
+                };
+                let response = response?;
+                continuation = response
+                    .headers()
+                    .get("x-ms-continuation")
+                    .and_then(|continuation| continuation.to_str().ok())
+                    .map(str::to_owned);
+                check(response)?;
+                if continuation.is_none() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let children = self.list(&format!("{key}/"), None).await?;
+        if children.is_empty() && marker.is_none() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if !recursive && !children.is_empty() {
+            return Err(ErrorKind::DirectoryNotEmpty.into());
+        }
+        for child in children {
+            self.delete(&child).await?;
+        }
+        if marker.is_some() {
+            self.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        if self.config.dfs_endpoint.is_some() {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-ms-rename-source", header_value(&self.config.path(from))?);
+            if let Some(response) = self.send_dfs(Method::PUT, to, &[], headers).await {
+                return check(response?).map(drop);
+            }
+        }
+
+        let marker = self.head(from).await?;
+        if let Some(Blob::File(_)) = marker {
+            self.copy(from, to).await?;
+            return self.delete(from).await;
+        }
+        let children = self.list(&format!("{from}/"), None).await?;
+        if children.is_empty() && marker.is_none() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if marker.is_some() {
+            self.create_dir(to, true).await?;
+        }
+        for child in children {
+            let dest = format!("{to}{}", &child[from.len()..]);
+            if let Some(Blob::Folder) = self.head(&child).await? {
+                self.create_dir(&dest, true).await?;
+            } else {
+                self.copy(&child, &dest).await?;
+            }
+            self.delete(&child).await?;
+        }
+        if marker.is_some() {
+            self.delete(from).await?;
+        }
+        Ok(())
+    }
+}
+
+fn copy_status(response: &http::Response<Bytes>) -> Option<String> {
+    response
+        .headers()
+        .get("x-ms-copy-status")
+        .and_then(|status| status.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Returns the body of a successful response, or converts the Azure error into an [`io::Error`]
+fn check(response: http::Response<Bytes>) -> io::Result<Bytes> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response.into_body());
+    }
+    let code = response
+        .headers()
+        .get("x-ms-error-code")
+        .and_then(|code| code.to_str().ok())
+        .map(|code| percent_decode_str(code).decode_utf8_lossy().into_owned())
+        .or_else(|| xml_values(&String::from_utf8_lossy(response.body()), "Code").pop());
+    let kind = match (status, code.as_deref()) {
+        (StatusCode::NOT_FOUND, _) => ErrorKind::NotFound,
+        (StatusCode::FORBIDDEN, _) => ErrorKind::PermissionDenied,
+        (_, Some("DirectoryNotEmpty")) => ErrorKind::DirectoryNotEmpty,
+        (_, Some("BlobAlreadyExists" | "PathAlreadyExists")) => ErrorKind::AlreadyExists,
+        _ => ErrorKind::Other,
+    };
+    let code = code.map_or_else(String::new, |code| format!(" ({code})"));
+    Err(io::Error::new(
+        kind,
+        format!("Azure request failed with status {status}{code}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, path::Path, time::UNIX_EPOCH};
+
+    use futures::future::{ready, Ready};
+    use http::{header::LAST_MODIFIED, HeaderValue};
+
+    use super::*;
+
+    const OBJECT: &str = "/container/dir/a.txt";
+    const LISTING: &str = "<EnumerationResults><Prefix>dir/</Prefix><Blobs><Blob>\
+        <Name>dir/a.txt</Name><Properties><Last-Modified>Sun, 06 Nov 1994 08:49:37 GMT\
+        </Last-Modified><Content-Length>10</Content-Length></Properties></Blob></Blobs>\
+        <NextMarker /></EnumerationResults>";
+    /// The root of the container, listed with a delimiter, which also holds an empty folder
+    const ROOT_LISTING: &str = "<EnumerationResults><Prefix></Prefix><Blobs>\
+        <Blob><Name>dir</Name><Metadata><hdi_isfolder>true</hdi_isfolder></Metadata></Blob>\
+        <BlobPrefix><Name>dir/</Name></BlobPrefix>\
+        <Blob><Name>empty</Name><Metadata><hdi_isfolder>true</hdi_isfolder></Metadata></Blob>\
+        </Blobs><NextMarker /></EnumerationResults>";
+
+    /// Holds `dir/a.txt`, 10 bytes last modified at 784111777 seconds past the epoch, answering
+    /// `HEAD`s of it and listings of `dir/` and the root
+    #[derive(Clone)]
+    struct Server;
+
+    impl Service<http::Request<Bytes>> for Server {
+        type Response = http::Response<Bytes>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<Bytes>) -> Self::Future {
+            let mut response = http::Response::new(Bytes::new());
+            let listing = req
+                .uri()
+                .query()
+                .is_some_and(|query| query.contains("prefix="));
+            if listing {
+                let query = req.uri().query().unwrap_or_default();
+                let prefix = |prefix| query.split('&').any(|pair| pair == prefix);
+                *response.body_mut() = Bytes::from(if prefix("prefix=dir%2F") {
+                    LISTING
+                } else if prefix("prefix=") {
+                    ROOT_LISTING
+                } else {
+                    "<Blobs></Blobs>"
+                });
+            } else if req.method() == Method::HEAD && req.uri().path() == OBJECT {
+                let headers = response.headers_mut();
+                headers.insert(CONTENT_LENGTH, 10.into());
+                headers.insert(
+                    LAST_MODIFIED,
+                    HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+                );
+            } else {
+                *response.status_mut() = StatusCode::NOT_FOUND;
+            }
+            ready(Ok(response))
+        }
+    }
+
+    #[test]
+    fn test_sign() -> io::Result<()> {
+        let config = Config::new("myaccount", AccountKey(b"secret".to_vec()), "mycontainer")?;
+        let request = config.sign(
+            &config.blob_endpoint,
+            Method::GET,
+            "/mycontainer",
+            &[("restype", "container"), ("comp", "list")],
+            HeaderMap::new(),
+            Bytes::new(),
+            UNIX_EPOCH + Duration::from_secs(784_111_777),
+        )?;
+
+        assert_eq!(
+            request.uri(),
+            "https://myaccount.blob.core.windows.net/mycontainer?restype=container&comp=list"
+        );
+        let string_to_sign = "GET\n\n\n\n\n\n\n\n\n\n\n\n\
+            x-ms-date:Sun, 06 Nov 1994 08:49:37 GMT\nx-ms-version:2021-08-06\n\
+            /myaccount/mycontainer\ncomp:list\nrestype:container";
+        assert_eq!(
+            request.headers()[http::header::AUTHORIZATION],
+            format!(
+                "SharedKey myaccount:{}",
+                base64(&hmac_sha256(b"secret", string_to_sign.as_bytes()))
+            )
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_metadata() -> io::Result<()> {
+        let config = Config::new("account", AccountKey(b"secret".to_vec()), "container")?;
+        let mut backend = Azure::new(Server, config);
+        let stat = |path: &str| Request::GetMetadata {
+            path: Path::new(path).into(),
+            follow_symlinks: true,
+        };
+        let metadata = backend.call(stat("dir/a.txt")).await?.into_metadata()?;
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 10);
+        assert_eq!(
+            metadata.modified().ok(),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        for dir in ["dir", ""] {
+            assert!(backend.call(stat(dir)).await?.into_metadata()?.is_dir());
+        }
+        assert_eq!(
+            backend
+                .call(stat("missing.txt"))
+                .await
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::NotFound)
+        );
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_read_dir() -> io::Result<()> {
+        let config = Config::new("account", AccountKey(b"secret".to_vec()), "container")?;
+        let mut backend = Azure::new(Server, config);
+        let read_dir = |path: &str| Request::ReadDir(Path::new(path).into());
+        let Response::Directory(root) = backend.call(read_dir("")).await? else {
+            unreachable!()
+        };
+        let names: Vec<_> = root.iter().map(|(name, _)| name.as_path()).collect();
+        assert_eq!(names, [Path::new("dir"), Path::new("empty")]);
+        assert!(root.iter().all(|(_, metadata)| metadata.is_dir()));
+        let Response::Directory(dir) = backend.call(read_dir("dir")).await? else {
+            unreachable!()
+        };
+        assert_eq!(dir.len(), 1);
+        assert_eq!(dir[0].0, Path::new("a.txt"));
+        assert_eq!(dir[0].1.len(), 10);
+        assert_eq!(
+            dir[0].1.modified().ok(),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        for (path, kind) in [
+            ("dir/a.txt", ErrorKind::NotADirectory),
+            ("missing", ErrorKind::NotFound),
+        ] {
+            assert!(backend
+                .call(read_dir(path))
+                .await
+                .is_err_and(|err| err.kind() == kind));
+        }
+        Ok(())
+    }
+}
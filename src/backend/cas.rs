@@ -0,0 +1,588 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    io::{self, ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, lock::Mutex, FutureExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tower_service::Service;
+
+use super::{key, normalize};
+use crate::{
+    digest::{hex, sha256},
+    CopyOptions, FileHandle, FileType, Metadata, Mode, Request, Response,
+};
+
+const BLOBS: &str = "blobs";
+const INDEX: &str = "index";
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// A backend which stores the contents of each file under it's SHA-256 digest
+///
+/// The store is a directory holding a `blobs` directory, with one file per distinct content named
+/// after its hex digest, and an `index` file mapping each path to a digest.  Files with identical
+/// contents share a blob, so [`Request::Copy`] and [`Request::Rename`] (including of whole
+/// directories) only change the index.  Blobs aren't deleted when the last file referring to them
+/// is removed or overwritten; a [`Request::Compact`] garbage collects every unreferenced blob.
+///
+/// [`Request::Open`] with [`Mode::Read`] opens a file's blob, which is never changed once written,
+/// and [`Request::GetMetadata`] answers with the size the index holds, without touching the blob.
+/// Opening files to write them, as well as links and permission changes, fail with
+/// [`ErrorKind::Unsupported`].
+#[derive(Debug, Clone)]
+pub struct Cas {
+    store: Arc<Mutex<Store>>,
+}
+
+impl Cas {
+    /// Opens the store in the directory at `path`, creating it if there isn't one
+    ///
+    /// # Errors
+    ///
+    /// - If the store's directories can't be created
+    /// - If the index can't be read or is malformed
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Store::open(path.as_ref().to_owned())
+            .await
+            .map(|store| Self {
+                store: Arc::new(Mutex::new(store)),
+            })
+    }
+
+    /// Returns the SHA-256 digest of the file at `path`, without reading it
+    ///
+    /// # Errors
+    ///
+    /// If there's no file at `path`
+    pub async fn digest<P: AsRef<Path>>(&self, path: P) -> io::Result<[u8; 32]> {
+        let path = normalize(path.as_ref())?;
+        let store = self.store.lock().await;
+        store.file(&path).map(|(digest, _)| digest)
+    }
+}
+
+impl Service<Request> for Cas {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    /// Requests are serialized on the index when called, so the [`Cas`] backend is always ready
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let store = self.store.clone();
+        async move { store.lock().await.handle(req).await }.boxed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    File { digest: [u8; 32], size: u64 },
+    Directory,
+}
+
+#[derive(Debug)]
+struct Store {
+    root: PathBuf,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Store {
+    async fn open(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(root.join(BLOBS)).await?;
+        let index = match fs::read_to_string(root.join(INDEX)).await {
+            Ok(index) => index,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let entries = index.lines().map(parse_entry).collect::<io::Result<_>>()?;
+        Ok(Self { root, entries })
+    }
+
+    async fn handle(&mut self, req: Request) -> io::Result<Response> {
+        match req {
+            Request::Compact => self.collect_garbage().await.map(Response::done),
+            Request::Flush => Ok(Response::Done),
+            Request::Copy { from, to, options } if options == CopyOptions::default() => self
+                .copy(&normalize(&from)?, &normalize(&to)?)
+                .await
+                .map(Response::Copied),
+            Request::CreateDir { path, recursive } => self
+                .create_dir(&normalize(&path)?, recursive)
+                .await
+                .map(Response::done),
+            Request::Exists(path) => Ok(Response::Exists(self.exists(&normalize(&path)?))),
+            Request::GetMetadata { path, .. } => {
+                let path = normalize(&path)?;
+                let metadata = match self.file(&path) {
+                    Ok((_, size)) => Metadata::new(FileType::File, size),
+                    Err(err) if err.kind() == ErrorKind::IsADirectory => {
+                        Metadata::new(FileType::Dir, 0)
+                    }
+                    Err(err) => return Err(err),
+                };
+                Ok(Response::Metadata(metadata))
+            }
+            Request::Open {
+                mode: Mode::Read,
+                path,
+            } => {
+                let (digest, _) = self.file(&normalize(&path)?)?;
+                let blob = fs::File::open(self.blob_path(&digest)).await?;
+                Ok(Response::File(FileHandle::read_only(blob)))
+            }
+            Request::ReadBytes(path) => self
+                .read(&normalize(&path)?, 0..u64::MAX)
+                .await
+                .map(Response::Bytes),
+            Request::ReadRange { path, range } => self
+                .read(&normalize(&path)?, range)
+                .await
+                .map(Response::Bytes),
+            Request::RemoveDir { path, recursive } => self
+                .remove_dir(&normalize(&path)?, recursive)
+                .await
+                .map(Response::done),
+            Request::RemoveFile(path) => self
+                .remove_file(&normalize(&path)?)
+                .await
+                .map(Response::done),
+            Request::Rename { from, to } => self
+                .rename(&normalize(&from)?, &normalize(&to)?)
+                .await
+                .map(Response::done),
+            Request::WriteBytes { path, bytes } => self
+                .write(&normalize(&path)?, &bytes)
+                .await
+                .map(Response::done),
+            Request::FollowLink(_)
+            | Request::HardLink { .. }
+            | Request::Open { .. }
+            | Request::SetPermissions { .. }
+            | Request::AppendBytes { .. }
+            | Request::CopyDir { .. }
+            | Request::MetadataBatch { .. }
+            | Request::ReadDir(_)
+            | Request::Copy { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(unix)]
+            Request::Symlink { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(windows)]
+            Request::SymlinkDir { .. }
+            | Request::SymlinkFile { .. }
+            | Request::ReadJunction(_)
+            | Request::OpenStream { .. }
+            | Request::GetFileAttributes(_)
+            | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "archive")]
+            Request::Archive { .. } | Request::Extract { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "dedup")]
+            Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "posix-acl")]
+            Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+            #[cfg(unix)]
+            Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+        }
+    }
+
+    fn blob_path(&self, digest: &[u8; 32]) -> PathBuf {
+        self.root.join(BLOBS).join(hex(digest))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty()
+            || self.entries.iter().any(|(entry_path, entry)| {
+                if entry_path == path {
+                    *entry == Entry::Directory
+                } else {
+                    entry_path.starts_with(path)
+                }
+            })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.contains_key(path) || self.is_dir(path)
+    }
+
+    fn has_children(&self, path: &Path) -> bool {
+        self.entries
+            .keys()
+            .any(|entry_path| entry_path != path && entry_path.starts_with(path))
+    }
+
+    fn ensure_parent_dir(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(ErrorKind::NotFound.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<([u8; 32], u64)> {
+        match self.entries.get(path) {
+            Some(Entry::File { digest, size }) => Ok((*digest, *size)),
+            _ if self.is_dir(path) => Err(ErrorKind::IsADirectory.into()),
+            _ => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn read(&mut self, path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let (digest, size) = self.file(path)?;
+        let mut blob = fs::File::open(self.blob_path(&digest)).await?;
+        let start = range.start.min(size);
+        blob.seek(SeekFrom::Start(start)).await?;
+        let mut bytes = Vec::new();
+        blob.take(range.end.min(size).saturating_sub(start))
+            .read_to_end(&mut bytes)
+            .await?;
+        Ok(bytes)
+    }
+
+    async fn write(&mut self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        if self.is_dir(path) {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        self.ensure_parent_dir(path)?;
+        let digest = sha256(bytes);
+        let blob_path = self.blob_path(&digest);
+        if !fs::try_exists(&blob_path).await? {
+            // Written under a temporary name first, so a blob is never seen half written
+            let mut temp_name = blob_path.file_name().unwrap_or_default().to_owned();
+            temp_name.push(TEMP_SUFFIX);
+            let temp_path = blob_path.with_file_name(temp_name);
+            fs::write(&temp_path, bytes).await?;
+            fs::rename(&temp_path, &blob_path).await?;
+        }
+        let entry = Entry::File {
+            digest,
+            size: bytes.len() as u64,
+        };
+        self.update(|entries| {
+            entries.insert(path.to_owned(), entry);
+        })
+        .await
+    }
+
+    async fn copy(&mut self, from: &Path, to: &Path) -> io::Result<u64> {
+        let (digest, size) = self.file(from)?;
+        if self.is_dir(to) {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        self.ensure_parent_dir(to)?;
+        self.update(|entries| {
+            entries.insert(to.to_owned(), Entry::File { digest, size });
+        })
+        .await?;
+        Ok(size)
+    }
+
+    async fn create_dir(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        if self.exists(path) {
+            return if recursive && self.is_dir(path) {
+                Ok(())
+            } else {
+                Err(ErrorKind::AlreadyExists.into())
+            };
+        }
+        if recursive {
+            if let Some(file) = path.ancestors().find(|ancestor| {
+                self.entries
+                    .get(*ancestor)
+                    .is_some_and(|entry| *entry != Entry::Directory)
+            }) {
+                return Err(io::Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("{} is a file", file.display()),
+                ));
+            }
+        } else {
+            self.ensure_parent_dir(path)?;
+        }
+        self.update(|entries| {
+            entries.insert(path.to_owned(), Entry::Directory);
+        })
+        .await
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        self.file(path)?;
+        self.update(|entries| {
+            entries.remove(path);
+        })
+        .await
+    }
+
+    async fn remove_dir(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        if path.as_os_str().is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "can't remove the root of the store",
+            ));
+        }
+        if !self.is_dir(path) {
+            return Err(if self.entries.contains_key(path) {
+                ErrorKind::NotADirectory
+            } else {
+                ErrorKind::NotFound
+            }
+            .into());
+        }
+        if !recursive && self.has_children(path) {
+            return Err(ErrorKind::DirectoryNotEmpty.into());
+        }
+        self.update(|entries| entries.retain(|entry_path, _| !entry_path.starts_with(path)))
+            .await
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        if !self.exists(from) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        if from.as_os_str().is_empty() || to.starts_with(from) && to != from {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "can't move a directory into itself",
+            ));
+        }
+        if self.is_dir(to) {
+            return Err(if self.is_dir(from) {
+                ErrorKind::AlreadyExists
+            } else {
+                ErrorKind::IsADirectory
+            }
+            .into());
+        }
+        if self.is_dir(from) && self.entries.contains_key(to) {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+        self.ensure_parent_dir(to)?;
+        self.update(|entries| {
+            let moved: Vec<_> = entries
+                .keys()
+                .filter(|path| path.starts_with(from))
+                .cloned()
+                .collect();
+            for path in moved {
+                if let Some(entry) = entries.remove(&path) {
+                    let relative = path.strip_prefix(from).unwrap_or(Path::new(""));
+                    entries.insert(to.join(relative), entry);
+                }
+            }
+        })
+        .await
+    }
+
+    /// Applies `change` to the index and persists it, restoring the previous index if it can't be
+    /// persisted
+    async fn update(
+        &mut self,
+        change: impl FnOnce(&mut BTreeMap<PathBuf, Entry>),
+    ) -> io::Result<()> {
+        let previous = self.entries.clone();
+        change(&mut self.entries);
+        let saved = self.save().await;
+        if saved.is_err() {
+            self.entries = previous;
+        }
+        saved
+    }
+
+    /// Rewrites the index under a temporary name, then moves it into place
+    async fn save(&self) -> io::Result<()> {
+        let mut index = String::new();
+        for (path, entry) in &self.entries {
+            let path = key(path)?;
+            if path.contains('\n') {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "paths can't contain newlines",
+                ));
+            }
+            match entry {
+                Entry::File { digest, size } => {
+                    let _ = writeln!(index, "f\t{}\t{size}\t{path}", hex(digest));
+                }
+                Entry::Directory => {
+                    let _ = writeln!(index, "d\t{path}");
+                }
+            }
+        }
+        let temp_path = self.root.join(format!("{INDEX}{TEMP_SUFFIX}"));
+        fs::write(&temp_path, index).await?;
+        fs::rename(&temp_path, self.root.join(INDEX)).await
+    }
+
+    /// Deletes every blob (and leftover temporary file) the index doesn't refer to
+    async fn collect_garbage(&mut self) -> io::Result<()> {
+        let live: HashSet<_> = self
+            .entries
+            .values()
+            .filter_map(|entry| match entry {
+                Entry::File { digest, .. } => Some(hex(digest)),
+                Entry::Directory => None,
+            })
+            .collect();
+        let mut blobs = fs::read_dir(self.root.join(BLOBS)).await?;
+        while let Some(blob) = blobs.next_entry().await? {
+            if !blob
+                .file_name()
+                .to_str()
+                .is_some_and(|name| live.contains(name))
+            {
+                fs::remove_file(blob.path()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_entry(line: &str) -> io::Result<(PathBuf, Entry)> {
+    let malformed = || io::Error::new(ErrorKind::InvalidData, "malformed index entry");
+    match line.split_once('\t') {
+        Some(("d", path)) => Ok((PathBuf::from(path), Entry::Directory)),
+        Some(("f", rest)) => {
+            let mut fields = rest.splitn(3, '\t');
+            let (Some(digest), Some(size), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(malformed());
+            };
+            let size = size.parse().map_err(|_| malformed())?;
+            Ok((
+                PathBuf::from(path),
+                Entry::File {
+                    digest: parse_digest(digest).ok_or_else(malformed)?,
+                    size,
+                },
+            ))
+        }
+        _ => Err(malformed()),
+    }
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    let mut digest = [0; 32];
+    if hex.len() != 64 {
+        return None;
+    }
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+
+    async fn blobs(root: &Path) -> io::Result<usize> {
+        let mut count = 0;
+        let mut blobs = fs::read_dir(root.join(BLOBS)).await?;
+        while blobs.next_entry().await?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    #[tokio::test]
+    async fn test_dedup_and_garbage_collection() -> io::Result<()> {
+        let root = TestDir::new("cas")?;
+
+        let mut cas = Cas::open(&root).await?;
+        cas.call(Request::CreateDir {
+            path: Path::new("dir").into(),
+            recursive: false,
+        })
+        .await?;
+        for path in ["dir/a.txt", "dir/b.txt"] {
+            cas.call(Request::WriteBytes {
+                path: Path::new(path).into(),
+                bytes: b"shared".to_vec(),
+            })
+            .await?;
+        }
+        assert_eq!(blobs(&root).await?, 1);
+        assert_eq!(cas.digest("dir/a.txt").await?, sha256(b"shared"));
+
+        cas.call(Request::Rename {
+            from: Path::new("dir").into(),
+            to: Path::new("moved").into(),
+        })
+        .await?;
+        cas.call(Request::WriteBytes {
+            path: Path::new("moved/a.txt").into(),
+            bytes: b"changed".to_vec(),
+        })
+        .await?;
+        cas.call(Request::RemoveFile(Path::new("moved/b.txt").into()))
+            .await?;
+        assert_eq!(blobs(&root).await?, 2);
+        cas.call(Request::Compact).await?;
+        assert_eq!(blobs(&root).await?, 1);
+
+        let mut reopened = Cas::open(&root).await?;
+        assert!(matches!(
+            reopened.call(Request::ReadBytes(Path::new("moved/a.txt").into())).await?,
+            Response::Bytes(bytes) if bytes == b"changed"
+        ));
+        assert!(matches!(
+            reopened
+                .call(Request::Exists(Path::new("dir").into()))
+                .await?,
+            Response::Exists(false)
+        ));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_open() -> io::Result<()> {
+        let root = TestDir::new("cas_open")?;
+        let mut cas = Cas::open(&root).await?;
+        let write = |bytes: &[u8]| Request::WriteBytes {
+            path: Path::new("a.txt").into(),
+            bytes: bytes.to_vec(),
+        };
+        cas.call(write(b"opened")).await?.into_done()?;
+        let open = |mode| Request::Open {
+            mode,
+            path: Path::new("a.txt").into(),
+        };
+        let mut file = cas.call(open(Mode::Read)).await?.into_file()?;
+        let stat = |path: &str| Request::GetMetadata {
+            path: Path::new(path).into(),
+            follow_symlinks: true,
+        };
+        let metadata = cas.call(stat("a.txt")).await?.into_metadata()?;
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 6);
+        assert!(cas.call(stat("")).await?.into_metadata()?.is_dir());
+
+        // Overwriting the file writes a new blob, leaving the open one as it was
+        cas.call(write(b"overwritten")).await?.into_done()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "opened");
+        assert!(file.write_all(b"x").await.is_err());
+
+        let write = cas.call(open(Mode::CreateOrAppend)).await;
+        assert_eq!(
+            write.map(drop).map_err(|err| err.kind()),
+            Err(ErrorKind::Unsupported)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    io::{self, Cursor, ErrorKind},
+    ops::Range,
+    task::Poll,
+};
+
+use futures::future::{ready, Ready};
+use tower_service::Service;
+
+use super::key;
+use crate::{FileHandle, FileType, Metadata, Mode, Request, Response, SharedService};
+
+/// A read-only backend which serves files embedded in the binary
+///
+/// The files are a table of `/` separated paths (relative to the root of the backend, like
+/// `css/site.css`) and their contents, typically built with [`include_bytes!`] or from the output of
+/// an asset embedding crate:
+///
+/// ```
+/// # use tower_fs::backend::embedded::Embedded;
+/// static ASSETS: Embedded = Embedded::new(&[("index.html", b"<h1>Hello</h1>")]);
+/// ```
+///
+/// Directories exist implicitly for every prefix of a path in the table, and every entry is read
+/// only.  [`Request::Open`] opens the contents in memory with [`Mode::Read`], and requests which
+/// would change the files, including opening them in any other mode, fail with
+/// [`ErrorKind::Unsupported`].
+#[derive(Debug, Clone, Copy)]
+pub struct Embedded {
+    files: &'static [(&'static str, &'static [u8])],
+}
+
+impl Embedded {
+    #[must_use]
+    pub const fn new(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        Self { files }
+    }
+
+    fn file(&self, key: &str) -> io::Result<&'static [u8]> {
+        match self.files.iter().find(|(path, _)| *path == key) {
+            Some((_, contents)) => Ok(contents),
+            None if self.is_dir(key) => Err(ErrorKind::IsADirectory.into()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn is_dir(&self, key: &str) -> bool {
+        key.is_empty()
+            || self.files.iter().any(|(path, _)| {
+                path.strip_prefix(key)
+                    .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    fn read(&self, key: &str, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let contents = self.file(key)?;
+        let len = contents.len() as u64;
+        let start = usize::try_from(range.start.min(len)).map_err(io::Error::other)?;
+        let end = usize::try_from(range.end.clamp(range.start.min(len), len))
+            .map_err(io::Error::other)?;
+        Ok(contents[start..end].to_vec())
+    }
+
+    fn handle(&self, req: Request) -> io::Result<Response> {
+        match req {
+            Request::Compact | Request::Flush => Ok(Response::Done),
+            Request::Exists(path) => {
+                let key = key(&path)?;
+                Ok(Response::Exists(
+                    self.is_dir(&key) || self.files.iter().any(|(path, _)| *path == key),
+                ))
+            }
+            Request::FollowLink(path) => self.file(&key(&path)?).and(Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "not a symbolic link",
+            ))),
+            Request::GetMetadata { path, .. } => {
+                let key = key(&path)?;
+                let metadata = if self.is_dir(&key) {
+                    Metadata::new(FileType::Dir, 0)
+                } else {
+                    Metadata::new(FileType::File, self.file(&key)?.len() as u64)
+                };
+                Ok(Response::Metadata(metadata.with_readonly(true)))
+            }
+            Request::Open {
+                mode: Mode::Read,
+                path,
+            } => Ok(Response::File(FileHandle::read_only(Cursor::new(
+                self.file(&key(&path)?)?,
+            )))),
+            Request::ReadBytes(path) => self.read(&key(&path)?, 0..u64::MAX).map(Response::Bytes),
+            Request::ReadRange { path, range } => {
+                self.read(&key(&path)?, range).map(Response::Bytes)
+            }
+            Request::Copy { .. }
+            | Request::CreateDir { .. }
+            | Request::HardLink { .. }
+            | Request::Open { .. }
+            | Request::RemoveDir { .. }
+            | Request::RemoveFile(_)
+            | Request::Rename { .. }
+            | Request::SetPermissions { .. }
+            | Request::WriteBytes { .. }
+            | Request::AppendBytes { .. }
+            | Request::CopyDir { .. }
+            | Request::MetadataBatch { .. }
+            | Request::ReadDir(_) => Err(ErrorKind::Unsupported.into()),
+            #[cfg(unix)]
+            Request::Symlink { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(windows)]
+            Request::SymlinkDir { .. }
+            | Request::SymlinkFile { .. }
+            | Request::ReadJunction(_)
+            | Request::OpenStream { .. }
+            | Request::GetFileAttributes(_)
+            | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "archive")]
+            Request::Archive { .. } | Request::Extract { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "dedup")]
+            Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "posix-acl")]
+            Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+            #[cfg(unix)]
+            Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+        }
+    }
+}
+
+impl Service<Request> for Embedded {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = Ready<Result<Response, Self::Error>>;
+
+    /// The files are already in memory, so the [`Embedded`] backend is always ready
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        ready(self.handle(req))
+    }
+}
+
+impl Service<Request> for &Embedded {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = Ready<Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        ready(self.handle(req))
+    }
+}
+
+impl SharedService for Embedded {
+    type Future = Ready<Result<Response, io::Error>>;
+
+    fn call_shared(&self, req: Request) -> Self::Future {
+        ready(self.handle(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use futures::FutureExt;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::FileSystemExt;
+
+    #[test]
+    fn test_embedded() -> io::Result<()> {
+        let embedded = Embedded::new(&[("css/site.css", b"body {}"), ("index.html", b"")]);
+        assert!(matches!(
+            embedded.handle(Request::Exists(Path::new("/css").into()))?,
+            Response::Exists(true)
+        ));
+        assert!(matches!(
+            embedded.handle(Request::Exists(Path::new("cs").into()))?,
+            Response::Exists(false)
+        ));
+        assert!(matches!(
+            embedded.handle(Request::ReadRange { path: Path::new("css/site.css").into(), range: 5..50 })?,
+            Response::Bytes(bytes) if bytes == b"{}"
+        ));
+        assert_eq!(
+            embedded
+                .handle(Request::ReadBytes(Path::new("css").into()))
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::IsADirectory)
+        );
+        let stat = Request::GetMetadata {
+            path: Path::new("css/site.css").into(),
+            follow_symlinks: true,
+        };
+        assert!(matches!(
+            embedded.handle(stat)?,
+            Response::Metadata(metadata) if metadata.is_file() && metadata.len() == 7 && metadata.readonly()
+        ));
+
+        let open = |mode| Request::Open {
+            mode,
+            path: Path::new("css/site.css").into(),
+        };
+        let mut file = embedded.handle(open(Mode::Read))?.into_file()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .now_or_never()
+            .ok_or(ErrorKind::WouldBlock)??;
+        assert_eq!(contents, "body {}");
+        assert_eq!(
+            embedded
+                .handle(open(Mode::CreateOrOverwrite))
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::Unsupported)
+        );
+
+        // Batches are answered with a request for each path
+        let mut service = embedded;
+        let batch = service
+            .metadata_batch(["index.html", "missing"])
+            .now_or_never()
+            .ok_or(ErrorKind::WouldBlock)??;
+        assert!(batch[0].as_ref().is_ok_and(Metadata::is_file));
+        assert!(batch[1]
+            .as_ref()
+            .is_err_and(|err| err.kind() == ErrorKind::NotFound));
+        Ok(())
+    }
+}
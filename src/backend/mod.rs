@@ -0,0 +1,272 @@
+#[cfg(any(
+    feature = "azure",
+    feature = "cas",
+    feature = "embedded",
+    feature = "origin",
+    feature = "s3",
+    feature = "tar",
+    feature = "tempdir",
+    feature = "wasi",
+    windows
+))]
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+#[cfg(any(
+    feature = "azure",
+    feature = "cas",
+    feature = "embedded",
+    feature = "origin",
+    feature = "s3",
+    feature = "tar",
+    feature = "tempdir",
+    feature = "wasi"
+))]
+use std::{io::ErrorKind, path::Component};
+
+#[cfg(feature = "azure")]
+pub mod azure;
+#[cfg(feature = "cas")]
+pub mod cas;
+#[cfg(feature = "embedded")]
+pub mod embedded;
+#[cfg(feature = "origin")]
+pub mod origin;
+// The XML helpers are only needed by the object store backends
+#[cfg(any(feature = "azure", feature = "origin", feature = "s3"))]
+#[allow(dead_code)]
+mod rest;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+#[cfg(feature = "tar")]
+pub mod tar;
+#[cfg(feature = "tempdir")]
+pub mod tempdir;
+#[cfg(feature = "wasi")]
+pub mod wasi;
+
+#[cfg(any(feature = "tempdir", feature = "wasi", windows))]
+use crate::Request;
+
+/// Converts a request path into a path relative to the root of a backend, resolving `.` and `..`
+/// lexically
+#[cfg(any(
+    feature = "azure",
+    feature = "cas",
+    feature = "embedded",
+    feature = "origin",
+    feature = "s3",
+    feature = "tar",
+    feature = "tempdir",
+    feature = "wasi"
+))]
+pub(crate) fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => normalized.push(component),
+            Component::ParentDir if normalized.pop() => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "path escapes the root of the backend",
+                ))
+            }
+            Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Ok(normalized)
+}
+
+/// Resolves the paths of `req` relative to `root`, for backends which store their files in a
+/// directory of the local file system
+///
+/// Symbolic link targets are left as they are, so they're resolved relative to the link like on
+/// the real file system.
+#[cfg(any(feature = "tempdir", feature = "wasi"))]
+fn rebase(root: &Path, req: Request) -> io::Result<Request> {
+    map_paths(req, |path| normalize(path).map(|path| root.join(path)))
+}
+
+/// Replaces each path of `req` with `resolve`'s result for it, leaving link targets alone
+#[cfg(any(feature = "tempdir", feature = "wasi", windows))]
+// One arm per request
+#[allow(clippy::too_many_lines)]
+pub(crate) fn map_paths(
+    req: Request,
+    resolve: impl Fn(&Path) -> io::Result<PathBuf>,
+) -> io::Result<Request> {
+    Ok(match req {
+        #[cfg(feature = "archive")]
+        Request::Archive {
+            src_dir,
+            dst,
+            format,
+            compression,
+            filter,
+        } => Request::Archive {
+            src_dir: resolve(&src_dir)?.into(),
+            dst: resolve(&dst)?.into(),
+            format,
+            compression,
+            filter,
+        },
+        #[cfg(feature = "archive")]
+        Request::Extract {
+            archive,
+            dst_dir,
+            options,
+        } => Request::Extract {
+            archive: resolve(&archive)?.into(),
+            dst_dir: resolve(&dst_dir)?.into(),
+            options,
+        },
+        #[cfg(feature = "dedup")]
+        Request::FindDuplicates { dir, options } => Request::FindDuplicates {
+            dir: resolve(&dir)?.into(),
+            options,
+        },
+        #[cfg(feature = "posix-acl")]
+        Request::GetPosixAcl(path) => Request::GetPosixAcl(resolve(&path)?.into()),
+        #[cfg(feature = "posix-acl")]
+        Request::SetPosixAcl { path, acl } => Request::SetPosixAcl {
+            path: resolve(&path)?.into(),
+            acl,
+        },
+        Request::Compact => Request::Compact,
+        Request::Flush => Request::Flush,
+        Request::Copy { from, to, options } => Request::Copy {
+            from: resolve(&from)?.into(),
+            to: resolve(&to)?.into(),
+            options,
+        },
+        Request::CopyDir { from, to, options } => Request::CopyDir {
+            from: resolve(&from)?.into(),
+            to: resolve(&to)?.into(),
+            options,
+        },
+        Request::CreateDir { path, recursive } => Request::CreateDir {
+            path: resolve(&path)?.into(),
+            recursive,
+        },
+        Request::Exists(path) => Request::Exists(resolve(&path)?.into()),
+        Request::FollowLink(path) => Request::FollowLink(resolve(&path)?.into()),
+        Request::GetMetadata {
+            path,
+            follow_symlinks,
+        } => Request::GetMetadata {
+            path: resolve(&path)?.into(),
+            follow_symlinks,
+        },
+        Request::MetadataBatch {
+            paths,
+            follow_symlinks,
+        } => Request::MetadataBatch {
+            paths: paths
+                .iter()
+                .map(|path| resolve(path).map(Into::into))
+                .collect::<io::Result<_>>()?,
+            follow_symlinks,
+        },
+        Request::HardLink { src, dst } => Request::HardLink {
+            src: resolve(&src)?.into(),
+            dst: resolve(&dst)?.into(),
+        },
+        Request::Open { mode, path } => Request::Open {
+            mode,
+            path: resolve(&path)?.into(),
+        },
+        Request::ReadDir(path) => Request::ReadDir(resolve(&path)?.into()),
+        Request::ReadBytes(path) => Request::ReadBytes(resolve(&path)?.into()),
+        Request::ReadRange { path, range } => Request::ReadRange {
+            path: resolve(&path)?.into(),
+            range,
+        },
+        Request::RemoveDir { path, recursive } => Request::RemoveDir {
+            path: resolve(&path)?.into(),
+            recursive,
+        },
+        Request::RemoveFile(path) => Request::RemoveFile(resolve(&path)?.into()),
+        Request::Rename { from, to } => Request::Rename {
+            from: resolve(&from)?.into(),
+            to: resolve(&to)?.into(),
+        },
+        Request::SetPermissions { path, perm } => Request::SetPermissions {
+            path: resolve(&path)?.into(),
+            perm,
+        },
+        #[cfg(unix)]
+        Request::GetAttributes(path) => Request::GetAttributes(resolve(&path)?.into()),
+        #[cfg(unix)]
+        Request::SetAttributes { path, set, clear } => Request::SetAttributes {
+            path: resolve(&path)?.into(),
+            set,
+            clear,
+        },
+        // Link targets are resolved relative to the link, like on the real file system
+        #[cfg(unix)]
+        Request::Symlink { src, dst } => Request::Symlink {
+            src,
+            dst: resolve(&dst)?.into(),
+        },
+        #[cfg(windows)]
+        Request::SymlinkDir { src, dst } => Request::SymlinkDir {
+            src,
+            dst: resolve(&dst)?.into(),
+        },
+        #[cfg(windows)]
+        Request::SymlinkFile { src, dst } => Request::SymlinkFile {
+            src,
+            dst: resolve(&dst)?.into(),
+        },
+        #[cfg(windows)]
+        Request::ReadJunction(path) => Request::ReadJunction(resolve(&path)?.into()),
+        #[cfg(windows)]
+        Request::OpenStream { mode, path, stream } => Request::OpenStream {
+            mode,
+            path: resolve(&path)?.into(),
+            stream,
+        },
+        #[cfg(windows)]
+        Request::GetFileAttributes(path) => Request::GetFileAttributes(resolve(&path)?.into()),
+        #[cfg(windows)]
+        Request::SetFileAttributes { path, set, clear } => Request::SetFileAttributes {
+            path: resolve(&path)?.into(),
+            set,
+            clear,
+        },
+        Request::WriteBytes { path, bytes } => Request::WriteBytes {
+            path: resolve(&path)?.into(),
+            bytes,
+        },
+        Request::AppendBytes { path, bytes } => Request::AppendBytes {
+            path: resolve(&path)?.into(),
+            bytes,
+        },
+    })
+}
+
+/// Converts a request path into a `/` separated key, as used by archive and object store backends
+#[cfg(any(
+    feature = "azure",
+    feature = "cas",
+    feature = "embedded",
+    feature = "origin",
+    feature = "s3",
+    feature = "tar"
+))]
+fn key(path: &Path) -> io::Result<String> {
+    Ok(normalize(path)?
+        .components()
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "keys must be valid utf-8"))
+        })
+        .collect::<io::Result<Vec<_>>>()?
+        .join("/"))
+}
@@ -0,0 +1,285 @@
+use std::{
+    io::{self, ErrorKind},
+    ops::Range,
+    task::Poll,
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt};
+use http::{header::RANGE, Method, StatusCode, Uri};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tower_service::Service;
+
+use super::{
+    key, rest,
+    rest::{client_error, file_metadata, range_header, trim_to_range, BoxError},
+};
+use crate::{Request, Response};
+
+/// Everything except unreserved characters and the `/` between path segments gets percent-encoded
+const ENCODE_PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// A read-only backend which fetches files from an HTTP server
+///
+/// Each request path is resolved against `base` (so `a/b.txt` with a base of
+/// `https://example.com/assets` is fetched from `https://example.com/assets/a/b.txt`) and sent
+/// through `C`, any HTTP client implementing
+/// `Service<http::Request<Bytes>, Response = http::Response<Bytes>>`.
+///
+/// [`Request::ReadBytes`] and [`Request::ReadRange`] are sent as `GET`s (the latter with a `Range`
+/// header), and [`Request::Exists`] and [`Request::GetMetadata`] as `HEAD`s, describing a read only
+/// file from it's `Content-Length` and `Last-Modified` headers.  Every other request fails with
+/// [`ErrorKind::Unsupported`].
+#[derive(Debug, Clone)]
+pub struct Origin<C> {
+    client: C,
+    base: Uri,
+}
+
+impl<C> Origin<C> {
+    pub fn new(client: C, base: Uri) -> Self {
+        Self { client, base }
+    }
+}
+
+impl<C> Service<Request> for Origin<C>
+where
+    C: Service<http::Request<Bytes>, Response = http::Response<Bytes>> + Clone + Send + 'static,
+    C::Error: Into<BoxError>,
+    C::Future: Send,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.client.poll_ready(cx).map_err(client_error)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Keep the client which was driven to readiness for this request
+        let client = self.client.clone();
+        let mut client = std::mem::replace(&mut self.client, client);
+        let base = self.base.clone();
+        async move {
+            match req {
+                Request::Compact | Request::Flush => Ok(Response::Done),
+                Request::Exists(path) => {
+                    let request = request(Method::HEAD, &base, &key(&path)?, None)?;
+                    let response = rest::send(&mut client, request).await?;
+                    match response.status() {
+                        StatusCode::NOT_FOUND | StatusCode::GONE => Ok(Response::Exists(false)),
+                        _ => check(response).map(|_| Response::Exists(true)),
+                    }
+                }
+                Request::GetMetadata { path, .. } => {
+                    let request = request(Method::HEAD, &base, &key(&path)?, None)?;
+                    let response = rest::send(&mut client, request).await?;
+                    let metadata = file_metadata(response.headers()).with_readonly(true);
+                    check(response).map(|_| Response::Metadata(metadata))
+                }
+                Request::ReadBytes(path) => {
+                    let request = request(Method::GET, &base, &key(&path)?, None)?;
+                    check(rest::send(&mut client, request).await?)
+                        .map(|body| Response::Bytes(body.into()))
+                }
+                Request::ReadRange { path, range } => {
+                    let request = request(Method::GET, &base, &key(&path)?, Some(&range))?;
+                    let response = rest::send(&mut client, request).await?;
+                    match response.status() {
+                        // The range starts past the end of the file
+                        StatusCode::RANGE_NOT_SATISFIABLE => Ok(Response::Bytes(Vec::new())),
+                        status => check(response).map(|body| {
+                            Response::Bytes(trim_to_range(status, body, &range).into())
+                        }),
+                    }
+                }
+                Request::Copy { .. }
+                | Request::CreateDir { .. }
+                | Request::FollowLink(_)
+                | Request::HardLink { .. }
+                | Request::Open { .. }
+                | Request::RemoveDir { .. }
+                | Request::RemoveFile(_)
+                | Request::Rename { .. }
+                | Request::SetPermissions { .. }
+                | Request::WriteBytes { .. }
+                | Request::AppendBytes { .. }
+                | Request::CopyDir { .. }
+                | Request::MetadataBatch { .. }
+                | Request::ReadDir(_) => Err(ErrorKind::Unsupported.into()),
+                #[cfg(unix)]
+                Request::Symlink { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(windows)]
+                Request::SymlinkDir { .. }
+                | Request::SymlinkFile { .. }
+                | Request::ReadJunction(_)
+                | Request::OpenStream { .. }
+                | Request::GetFileAttributes(_)
+                | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(feature = "archive")]
+                Request::Archive { .. } | Request::Extract { .. } => {
+                    Err(ErrorKind::Unsupported.into())
+                }
+                #[cfg(feature = "dedup")]
+                Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(feature = "posix-acl")]
+                Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                    Err(ErrorKind::Unsupported.into())
+                }
+                #[cfg(unix)]
+                Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                    Err(ErrorKind::Unsupported.into())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+fn request(
+    method: Method,
+    base: &Uri,
+    key: &str,
+    range: Option<&Range<u64>>,
+) -> io::Result<http::Request<Bytes>> {
+    let base = base.to_string();
+    let uri = format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        utf8_percent_encode(key, ENCODE_PATH)
+    );
+    let mut request = http::Request::builder().method(method).uri(uri);
+    if let Some(range) = range {
+        request = request.header(RANGE, range_header(range)?);
+    }
+    request
+        .body(Bytes::new())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+}
+
+/// Returns the body of a successful response, or converts the status into an [`io::Error`]
+fn check(response: http::Response<Bytes>) -> io::Result<Bytes> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response.into_body());
+    }
+    let kind = match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => ErrorKind::NotFound,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    Err(io::Error::new(
+        kind,
+        format!("origin request failed with status {status}"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        path::Path,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use futures::future::{ready, Ready};
+    use http::{
+        header::{CONTENT_LENGTH, LAST_MODIFIED},
+        HeaderValue,
+    };
+
+    use super::*;
+
+    /// Serves `b"0123456789"` at `/files/digits.txt`, last modified at 784111777 seconds past the
+    /// epoch, ignoring `Range` headers
+    #[derive(Clone)]
+    struct Server;
+
+    impl Service<http::Request<Bytes>> for Server {
+        type Response = http::Response<Bytes>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<Bytes>) -> Self::Future {
+            let mut response = http::Response::new(Bytes::new());
+            if req.uri().path() == "/files/digits.txt" {
+                let headers = response.headers_mut();
+                headers.insert(CONTENT_LENGTH, 10.into());
+                headers.insert(
+                    LAST_MODIFIED,
+                    HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+                );
+                if req.method() == Method::GET {
+                    *response.body_mut() = Bytes::from_static(b"0123456789");
+                }
+            } else {
+                *response.status_mut() = StatusCode::NOT_FOUND;
+            }
+            ready(Ok(response))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_origin() -> io::Result<()> {
+        let mut origin = Origin::new(Server, Uri::from_static("http://example.com/files/"));
+        assert!(matches!(
+            origin
+                .call(Request::Exists(Path::new("digits.txt").into()))
+                .await?,
+            Response::Exists(true)
+        ));
+        assert!(matches!(
+            origin
+                .call(Request::Exists(Path::new("missing.txt").into()))
+                .await?,
+            Response::Exists(false)
+        ));
+        assert!(matches!(
+            origin
+                .call(Request::ReadRange {
+                    path: Path::new("/digits.txt").into(),
+                    range: 7..20,
+                })
+                .await?,
+            Response::Bytes(bytes) if bytes == b"789"
+        ));
+        let stat = |path: &str| Request::GetMetadata {
+            path: Path::new(path).into(),
+            follow_symlinks: true,
+        };
+        let metadata = origin.call(stat("digits.txt")).await?.into_metadata()?;
+        assert!(metadata.is_file() && metadata.readonly());
+        assert_eq!(metadata.len(), 10);
+        assert_eq!(
+            metadata.modified().ok(),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            origin
+                .call(stat("missing.txt"))
+                .await
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::NotFound)
+        );
+        assert_eq!(
+            origin
+                .call(Request::RemoveFile(Path::new("digits.txt").into()))
+                .await
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::Unsupported)
+        );
+        Ok(())
+    }
+}
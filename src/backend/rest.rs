@@ -0,0 +1,149 @@
+//! Helpers shared by the backends which are clients of an HTTP API
+
+use std::{
+    future::poll_fn,
+    io::{self, ErrorKind},
+    ops::Range,
+};
+
+use bytes::Bytes;
+use http::{
+    header::{CONTENT_LENGTH, LAST_MODIFIED},
+    HeaderMap, HeaderValue, StatusCode,
+};
+use tower_service::Service;
+
+use crate::{date::DateTime, FileType, Metadata};
+
+pub(super) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Drives `client` to readiness, then sends `request` through it
+pub(super) async fn send<C>(
+    client: &mut C,
+    request: http::Request<Bytes>,
+) -> io::Result<http::Response<Bytes>>
+where
+    C: Service<http::Request<Bytes>, Response = http::Response<Bytes>>,
+    C::Error: Into<BoxError>,
+{
+    poll_fn(|cx| client.poll_ready(cx))
+        .await
+        .map_err(client_error)?;
+    client.call(request).await.map_err(client_error)
+}
+
+pub(super) fn client_error<E: Into<BoxError>>(err: E) -> io::Error {
+    io::Error::other(err.into())
+}
+
+pub(super) fn header_value(value: &str) -> io::Result<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+}
+
+/// Formats `range` as the value of a `Range` header.  Empty ranges still ask for a byte, so that
+/// missing files are reported as such
+pub(super) fn range_header(range: &Range<u64>) -> io::Result<HeaderValue> {
+    let last = range.end.max(range.start + 1) - 1;
+    header_value(&format!("bytes={}-{last}", range.start))
+}
+
+/// Cuts `range` out of the body of a successful response to a ranged `GET`, whether or not the
+/// server honored the `Range` header
+pub(super) fn trim_to_range(status: StatusCode, body: Bytes, range: &Range<u64>) -> Bytes {
+    let body = if status == StatusCode::PARTIAL_CONTENT {
+        body
+    } else {
+        let start = usize::try_from(range.start).map_or(body.len(), |start| start.min(body.len()));
+        body.slice(start..)
+    };
+    let len = usize::try_from(range.end.saturating_sub(range.start)).unwrap_or(usize::MAX);
+    body.slice(..len.min(body.len()))
+}
+
+/// The metadata of a file from the headers of a successful `HEAD` of it: it's `Content-Length`,
+/// and it's `Last-Modified` time if the server sent one
+pub(super) fn file_metadata(headers: &HeaderMap) -> Metadata {
+    let len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok())
+        .unwrap_or_default();
+    let metadata = Metadata::new(FileType::File, len);
+    match headers
+        .get(LAST_MODIFIED)
+        .and_then(|modified| DateTime::parse_http(modified.to_str().ok()?))
+    {
+        Some(modified) => metadata.with_modified(modified.into()),
+        None => metadata,
+    }
+}
+
+/// Extracts the (unescaped) text of every `<tag>` element in an XML response
+pub(super) fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    xml_elements(xml, tag)
+        .into_iter()
+        .map(|value| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+/// Extracts the contents of every `<tag>` element in an XML response, still escaped, for picking
+/// out the values of nested elements
+pub(super) fn xml_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let close = format!("</{tag}>");
+    xml.split(&format!("<{tag}>"))
+        .skip(1)
+        .filter_map(|rest| rest.find(&close).map(|end| &rest[..end]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xml_values() {
+        let xml = "<ListBucketResult><Contents><Key>a&amp;b</Key></Contents>\
+                   <Contents><Key>c</Key></Contents></ListBucketResult>";
+        assert_eq!(xml_values(xml, "Key"), ["a&b", "c"]);
+        assert_eq!(
+            xml_elements(xml, "Contents"),
+            ["<Key>a&amp;b</Key>", "<Key>c</Key>"]
+        );
+    }
+
+    #[test]
+    fn test_file_metadata() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("10"));
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        let metadata = file_metadata(&headers);
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 10);
+        assert_eq!(
+            metadata.modified().ok(),
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(784_111_777))
+        );
+        assert!(file_metadata(&HeaderMap::new()).modified().is_err());
+    }
+
+    #[test]
+    fn test_trim_to_range() {
+        let body = Bytes::from_static(b"0123456789");
+        assert_eq!(trim_to_range(StatusCode::OK, body.clone(), &(2..5)), "234");
+        assert_eq!(trim_to_range(StatusCode::OK, body.clone(), &(8..20)), "89");
+        assert_eq!(trim_to_range(StatusCode::OK, body.clone(), &(20..30)), "");
+        assert_eq!(
+            trim_to_range(StatusCode::PARTIAL_CONTENT, body.slice(3..4), &(3..3)),
+            ""
+        );
+    }
+}
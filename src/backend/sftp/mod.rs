@@ -0,0 +1,954 @@
+use std::{
+    fs::Permissions,
+    future::poll_fn,
+    io::{self, ErrorKind, SeekFrom},
+    mem,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tower_service::Service;
+
+use crate::{CopyOptions, FileHandle, FileType, Metadata, Mode, Request, Response};
+use protocol::{
+    receive, status_error, Attrs, Reader, Writer, FXF_APPEND, FXF_CREAT, FXF_EXCL, FXF_READ,
+    FXF_TRUNC, FXF_WRITE, FXP_ATTRS, FXP_CLOSE, FXP_DATA, FXP_EXTENDED, FXP_FSTAT, FXP_HANDLE,
+    FXP_INIT, FXP_LSTAT, FXP_MKDIR, FXP_NAME, FXP_OPEN, FXP_OPENDIR, FXP_READ, FXP_READDIR,
+    FXP_READLINK, FXP_REMOVE, FXP_RENAME, FXP_RMDIR, FXP_SETSTAT, FXP_STAT, FXP_STATUS,
+    FXP_SYMLINK, FXP_VERSION, FXP_WRITE, FX_OK, HARDLINK, POSIX_RENAME, S_IFDIR, S_IFLNK, S_IFMT,
+    S_IFREG, VERSION,
+};
+
+// Some of the protocol is only needed to serve requests
+#[allow(dead_code)]
+pub(crate) mod protocol;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A backend which executes requests on a remote server using the SSH file transfer protocol
+///
+/// The backend speaks SFTP (version 3) over any byte stream, and leaves establishing the SSH
+/// connection and authenticating to the application: `M` is a connector which, when called,
+/// returns a stream connected to the `sftp` subsystem of an authenticated SSH session (or to an
+/// `sftp-server` process).
+///
+/// Idle sessions are kept in a pool for reuse.  If a session's connection fails while a request
+/// is in flight, the request is retried on a fresh connection up to [`Config::retries`] times; since
+/// the server may have already applied the request, only enable retries when that's acceptable.
+///
+/// Request paths are sent to the server unmodified, so relative paths are resolved against the
+/// remote user's starting directory.  [`Request::GetMetadata`] and [`Request::ReadDir`] answer
+/// with what the server's attributes hold: the type, size, permissions, owner and times.  Files
+/// opened with [`Request::Open`] take a session of their own, which goes back to the pool once
+/// the file is shut down; they're never retried, so a failed connection fails the file's
+/// operations until it's opened again.
+#[derive(Debug)]
+pub struct Sftp<M, S> {
+    connector: M,
+    idle: Arc<Mutex<Vec<Session<S>>>>,
+    config: Config,
+}
+
+impl<M: Clone, S> Clone for Sftp<M, S> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            idle: self.idle.clone(),
+            config: self.config,
+        }
+    }
+}
+
+impl<M, S> Sftp<M, S>
+where
+    M: Service<(), Response = S>,
+{
+    pub fn new(connector: M, config: Config) -> Self {
+        Self {
+            connector,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            config,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// How many idle sessions to keep open for reuse
+    pub max_idle: usize,
+    /// How many times to retry a request on a new connection after it's connection failed
+    pub retries: usize,
+    /// How many bytes to request or send in each read or write packet
+    pub chunk_size: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_idle: 4,
+            retries: 1,
+            chunk_size: 32 * 1024,
+        }
+    }
+}
+
+impl<M, S> Service<Request> for Sftp<M, S>
+where
+    M: Service<(), Response = S> + Clone + Send + 'static,
+    M::Error: Into<BoxError>,
+    M::Future: Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    /// Connections are only made once a request needs one, so the backend is always ready
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let mut connector = self.connector.clone();
+        let idle = self.idle.clone();
+        let config = self.config;
+        async move {
+            let mut attempt = 0;
+            loop {
+                let pooled = idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+                let mut session = match pooled {
+                    Some(session) => session,
+                    None => Session::connect(&mut connector).await?,
+                };
+                let result = match &req {
+                    Request::Open { mode, path } => {
+                        match session.open(&remote(path)?, open_flags(*mode)).await {
+                            Ok(handle) => {
+                                let file = RemoteFile::new(session, handle, config, idle);
+                                return Ok(Response::File(FileHandle::new(file)));
+                            }
+                            Err(err) => Err(err),
+                        }
+                    }
+                    req => session.handle(req, config.chunk_size).await,
+                };
+                if !session.broken {
+                    release(&idle, session, config.max_idle);
+                    return result;
+                }
+                if attempt >= config.retries {
+                    return result;
+                }
+                attempt += 1;
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Returns a session to the pool, unless it's already full
+fn release<S>(idle: &Mutex<Vec<Session<S>>>, session: Session<S>, max_idle: usize) {
+    let mut idle = idle.lock().unwrap_or_else(PoisonError::into_inner);
+    if idle.len() < max_idle {
+        idle.push(session);
+    }
+}
+
+#[derive(Debug)]
+struct Session<S> {
+    stream: S,
+    next_id: u32,
+    extensions: Vec<String>,
+    /// Set once the connection fails, so the session isn't reused
+    broken: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    async fn connect<M>(connector: &mut M) -> io::Result<Self>
+    where
+        M: Service<(), Response = S>,
+        M::Error: Into<BoxError>,
+    {
+        poll_fn(|cx| connector.poll_ready(cx))
+            .await
+            .map_err(|err| io::Error::other(err.into()))?;
+        let mut stream = connector
+            .call(())
+            .await
+            .map_err(|err| io::Error::other(err.into()))?;
+
+        Writer::default()
+            .u32(VERSION)
+            .send(&mut stream, FXP_INIT)
+            .await?;
+        let (ty, body) = receive(&mut stream).await?;
+        if ty != FXP_VERSION {
+            return Err(unexpected_reply());
+        }
+        let mut reader = Reader(&body);
+        if reader.u32()? < VERSION {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "server doesn't support SFTP version 3",
+            ));
+        }
+        let mut extensions = Vec::new();
+        while !reader.is_empty() {
+            extensions.push(reader.string()?);
+            reader.bytes()?;
+        }
+        Ok(Self {
+            stream,
+            next_id: 0,
+            extensions,
+            broken: false,
+        })
+    }
+
+    // One arm per request
+    #[allow(clippy::too_many_lines)]
+    async fn handle(&mut self, req: &Request, chunk_size: u32) -> io::Result<Response> {
+        match req {
+            Request::Compact | Request::Flush => Ok(Response::Done),
+            Request::Copy { from, to, options } if *options == CopyOptions::default() => {
+                let bytes = self.read(&remote(from)?, 0..u64::MAX, chunk_size).await?;
+                self.write(&remote(to)?, &bytes, chunk_size).await?;
+                Ok(Response::Copied(bytes.len() as u64))
+            }
+            Request::CreateDir { path, recursive } => self
+                .create_dir(&remote(path)?, *recursive)
+                .await
+                .map(Response::done),
+            Request::Exists(path) => self
+                .stat(&remote(path)?, true)
+                .await
+                .map(|attrs| Response::Exists(attrs.is_some())),
+            Request::FollowLink(path) => {
+                let path = remote(path)?;
+                let reply = self.request(FXP_READLINK, |w| w.string(path)).await?;
+                single_name(reply).map(|target| Response::PointsTo(PathBuf::from(target)))
+            }
+            Request::GetMetadata {
+                path,
+                follow_symlinks,
+            } => self
+                .metadata(path, *follow_symlinks)
+                .await
+                .map(Response::Metadata),
+            Request::MetadataBatch {
+                paths,
+                follow_symlinks,
+            } => {
+                let mut batch = Vec::with_capacity(paths.len());
+                for path in paths {
+                    match self.metadata(path, *follow_symlinks).await {
+                        // Fail the whole batch so it's retried on a new connection
+                        Err(err) if self.broken => return Err(err),
+                        metadata => batch.push(metadata),
+                    }
+                }
+                Ok(Response::MetadataBatch(batch))
+            }
+            Request::HardLink { src, dst } => {
+                let (src, dst) = (remote(src)?, remote(dst)?);
+                self.extended(HARDLINK, |w| w.string(src).string(dst))
+                    .await
+                    .map(Response::done)
+            }
+            Request::ReadDir(path) => {
+                let mut entries: Vec<_> = self
+                    .list(&remote(path)?)
+                    .await?
+                    .into_iter()
+                    .filter(|(name, _)| name != "." && name != "..")
+                    .map(|(name, attrs)| (PathBuf::from(name), metadata(&attrs)))
+                    .collect();
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Ok(Response::Directory(entries))
+            }
+            Request::ReadBytes(path) => self
+                .read(&remote(path)?, 0..u64::MAX, chunk_size)
+                .await
+                .map(Response::Bytes),
+            Request::ReadRange { path, range } => self
+                .read(&remote(path)?, range.clone(), chunk_size)
+                .await
+                .map(Response::Bytes),
+            Request::RemoveDir {
+                path,
+                recursive: false,
+            } => {
+                let path = remote(path)?;
+                let reply = self.request(FXP_RMDIR, |w| w.string(path)).await?;
+                expect_ok(reply).map(Response::done)
+            }
+            Request::RemoveDir {
+                path,
+                recursive: true,
+            } => self.remove_tree(&remote(path)?).await.map(Response::done),
+            Request::RemoveFile(path) => self.remove(&remote(path)?).await.map(Response::done),
+            Request::Rename { from, to } => {
+                let (from, to) = (remote(from)?, remote(to)?);
+                // Plain renames fail if the destination exists, unlike std::fs::rename
+                if self.extensions.iter().any(|ext| ext == POSIX_RENAME) {
+                    self.extended(POSIX_RENAME, |w| w.string(from).string(to))
+                        .await
+                } else {
+                    let reply = self
+                        .request(FXP_RENAME, |w| w.string(from).string(to))
+                        .await?;
+                    expect_ok(reply)
+                }
+                .map(Response::done)
+            }
+            Request::SetPermissions { path, perm } => {
+                let path = remote(path)?;
+                let attrs = Attrs {
+                    permissions: Some(mode(perm)),
+                    ..Attrs::default()
+                };
+                let reply = self
+                    .request(FXP_SETSTAT, |w| w.string(path).attrs(&attrs))
+                    .await?;
+                expect_ok(reply).map(Response::done)
+            }
+            #[cfg(unix)]
+            Request::Symlink { src, dst } => self
+                .symlink(&remote(src)?, &remote(dst)?)
+                .await
+                .map(Response::done),
+            #[cfg(windows)]
+            Request::SymlinkDir { src, dst } | Request::SymlinkFile { src, dst } => self
+                .symlink(&remote(src)?, &remote(dst)?)
+                .await
+                .map(Response::done),
+            #[cfg(windows)]
+            Request::ReadJunction(_)
+            | Request::OpenStream { .. }
+            | Request::GetFileAttributes(_)
+            | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+            Request::WriteBytes { path, bytes } => self
+                .write(&remote(path)?, bytes, chunk_size)
+                .await
+                .map(Response::done),
+            Request::AppendBytes { path, bytes } => self
+                .append(&remote(path)?, bytes, chunk_size)
+                .await
+                .map(Response::done),
+            // Files need a session to themselves, so they're opened by `Sftp::call`
+            Request::Copy { .. } | Request::Open { .. } | Request::CopyDir { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+            #[cfg(feature = "archive")]
+            Request::Archive { .. } | Request::Extract { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "dedup")]
+            Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "posix-acl")]
+            Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+            #[cfg(unix)]
+            Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+        }
+    }
+
+    /// Sends a request and waits for it's reply, returning the reply's type and body (after the
+    /// request id)
+    async fn request(
+        &mut self,
+        ty: u8,
+        body: impl FnOnce(Writer) -> Writer,
+    ) -> io::Result<(u8, Vec<u8>)> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let sent = body(Writer::default().u32(id))
+            .send(&mut self.stream, ty)
+            .await;
+        let received = match sent {
+            Ok(()) => receive(&mut self.stream).await,
+            Err(err) => Err(err),
+        };
+        let (reply, mut body) = received.inspect_err(|_| self.broken = true)?;
+        if Reader(&body).u32().ok() != Some(id) {
+            self.broken = true;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "reply to the wrong request",
+            ));
+        }
+        body.drain(..4);
+        Ok((reply, body))
+    }
+
+    async fn extended(
+        &mut self,
+        extension: &str,
+        body: impl FnOnce(Writer) -> Writer,
+    ) -> io::Result<()> {
+        if !self.extensions.iter().any(|ext| ext == extension) {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let reply = self
+            .request(FXP_EXTENDED, |w| body(w.string(extension)))
+            .await?;
+        expect_ok(reply)
+    }
+
+    async fn stat(&mut self, path: &str, follow_symlinks: bool) -> io::Result<Option<Attrs>> {
+        let ty = if follow_symlinks { FXP_STAT } else { FXP_LSTAT };
+        let (ty, body) = self.request(ty, |w| w.string(path)).await?;
+        match ty {
+            FXP_ATTRS => Reader(&body).attrs().map(Some),
+            _ => match expect_ok((ty, body)) {
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+                Ok(()) => Err(unexpected_reply()),
+            },
+        }
+    }
+
+    async fn metadata(&mut self, path: &Path, follow_symlinks: bool) -> io::Result<Metadata> {
+        match self.stat(&remote(path)?, follow_symlinks).await? {
+            Some(attrs) => Ok(metadata(&attrs)),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// The attributes of an open file
+    async fn fstat(&mut self, handle: &[u8]) -> io::Result<Attrs> {
+        match self.request(FXP_FSTAT, |w| w.string(handle)).await? {
+            (FXP_ATTRS, body) => Reader(&body).attrs(),
+            reply => expect_ok(reply).and(Err(unexpected_reply())),
+        }
+    }
+
+    async fn open(&mut self, path: &str, flags: u32) -> io::Result<Vec<u8>> {
+        let reply = self
+            .request(FXP_OPEN, |w| {
+                w.string(path).u32(flags).attrs(&Attrs::default())
+            })
+            .await?;
+        expect_handle(reply)
+    }
+
+    async fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let reply = self.request(FXP_CLOSE, |w| w.string(handle)).await?;
+        expect_ok(reply)
+    }
+
+    async fn read(
+        &mut self,
+        path: &str,
+        range: Range<u64>,
+        chunk_size: u32,
+    ) -> io::Result<Vec<u8>> {
+        let handle = self.open(path, FXF_READ).await?;
+        let mut bytes = Vec::new();
+        let result = loop {
+            let offset = range.start + bytes.len() as u64;
+            let len = range.end.saturating_sub(offset).min(u64::from(chunk_size));
+            if len == 0 {
+                break Ok(());
+            }
+            let len = u32::try_from(len).unwrap_or(chunk_size);
+            match self.read_chunk(&handle, offset, len).await {
+                Ok(data) if !data.is_empty() => bytes.extend(data),
+                Ok(_) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        let closed = self.close(&handle).await;
+        result.and(closed).map(|()| bytes)
+    }
+
+    /// Reads up to `len` bytes of an open file at `offset`, which are empty at the end of the
+    /// file
+    async fn read_chunk(&mut self, handle: &[u8], offset: u64, len: u32) -> io::Result<Vec<u8>> {
+        let reply = self
+            .request(FXP_READ, |w| w.string(handle).u64(offset).u32(len))
+            .await?;
+        match reply {
+            (FXP_DATA, body) => Reader(&body).bytes(),
+            reply => match expect_ok(reply) {
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(Vec::new()),
+                Err(err) => Err(err),
+                Ok(()) => Err(unexpected_reply()),
+            },
+        }
+    }
+
+    async fn write_chunk(&mut self, handle: &[u8], offset: u64, chunk: &[u8]) -> io::Result<()> {
+        let reply = self
+            .request(FXP_WRITE, |w| w.string(handle).u64(offset).string(chunk))
+            .await?;
+        expect_ok(reply)
+    }
+
+    async fn write(&mut self, path: &str, bytes: &[u8], chunk_size: u32) -> io::Result<()> {
+        self.write_with(path, FXF_WRITE | FXF_CREAT | FXF_TRUNC, bytes, chunk_size)
+            .await
+    }
+
+    /// Writes `bytes` to the end of the file, as servers place each write of a file opened for
+    /// appending there whatever its offset
+    async fn append(&mut self, path: &str, bytes: &[u8], chunk_size: u32) -> io::Result<()> {
+        self.write_with(path, FXF_WRITE | FXF_CREAT | FXF_APPEND, bytes, chunk_size)
+            .await
+    }
+
+    async fn write_with(
+        &mut self,
+        path: &str,
+        flags: u32,
+        bytes: &[u8],
+        chunk_size: u32,
+    ) -> io::Result<()> {
+        let handle = self.open(path, flags).await?;
+        let mut result = Ok(());
+        for (index, chunk) in bytes.chunks(chunk_size.max(1) as usize).enumerate() {
+            let offset = index as u64 * u64::from(chunk_size);
+            result = self.write_chunk(&handle, offset, chunk).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        let closed = self.close(&handle).await;
+        result.and(closed)
+    }
+
+    async fn create_dir(&mut self, path: &str, recursive: bool) -> io::Result<()> {
+        let mut missing = Vec::new();
+        for ancestor in Path::new(path).ancestors() {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            let ancestor = remote(ancestor)?;
+            match self.stat(&ancestor, true).await? {
+                Some(attrs) if attrs.is_dir() && ancestor == path && recursive => return Ok(()),
+                Some(attrs) if attrs.is_dir() && ancestor != path => break,
+                Some(_) => return Err(ErrorKind::AlreadyExists.into()),
+                None => missing.push(ancestor),
+            }
+            if !recursive {
+                break;
+            }
+        }
+        for dir in missing.iter().rev() {
+            let reply = self
+                .request(FXP_MKDIR, |w| w.string(dir).attrs(&Attrs::default()))
+                .await?;
+            expect_ok(reply)?;
+        }
+        Ok(())
+    }
+
+    async fn remove(&mut self, path: &str) -> io::Result<()> {
+        let reply = self.request(FXP_REMOVE, |w| w.string(path)).await?;
+        expect_ok(reply)
+    }
+
+    async fn list(&mut self, path: &str) -> io::Result<Vec<(String, Attrs)>> {
+        let reply = self.request(FXP_OPENDIR, |w| w.string(path)).await?;
+        let handle = expect_handle(reply)?;
+        let mut entries = Vec::new();
+        let result = loop {
+            match self.request(FXP_READDIR, |w| w.string(&handle)).await {
+                Ok((FXP_NAME, body)) => {
+                    if let Err(err) = read_names(&body, &mut entries) {
+                        break Err(err);
+                    }
+                }
+                Ok(reply) => match expect_ok(reply) {
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+                    Err(err) => break Err(err),
+                    Ok(()) => break Err(unexpected_reply()),
+                },
+                Err(err) => break Err(err),
+            }
+        };
+        let closed = self.close(&handle).await;
+        result.and(closed).map(|()| entries)
+    }
+
+    /// Removes a directory and everything in it, depth first
+    async fn remove_tree(&mut self, root: &str) -> io::Result<()> {
+        let mut stack = vec![(root.to_owned(), false)];
+        while let Some((dir, emptied)) = stack.pop() {
+            if emptied {
+                let reply = self.request(FXP_RMDIR, |w| w.string(&dir)).await?;
+                expect_ok(reply)?;
+                continue;
+            }
+            stack.push((dir.clone(), true));
+            for (name, attrs) in self.list(&dir).await? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let child = format!("{}/{name}", dir.trim_end_matches('/'));
+                if attrs.is_dir() {
+                    stack.push((child, false));
+                } else {
+                    self.remove(&child).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn symlink(&mut self, target: &str, link: &str) -> io::Result<()> {
+        // OpenSSH (and every server compatible with it) takes the target first, the reverse of
+        // the order in the draft
+        let reply = self
+            .request(FXP_SYMLINK, |w| w.string(target).string(link))
+            .await?;
+        expect_ok(reply)
+    }
+}
+
+/// An operation a [`RemoteFile`] is waiting on, which gives back it's session when it's done
+type Pending<S> = BoxFuture<'static, (Session<S>, io::Result<Done>)>;
+
+enum State<S> {
+    Idle(Session<S>),
+    Busy(Pending<S>),
+    Closed,
+}
+
+/// What a [`RemoteFile`]'s operation did
+enum Done {
+    Read(Vec<u8>),
+    Wrote(usize),
+    Size(u64),
+    Closed,
+}
+
+/// A file opened with [`Request::Open`], which has a session to itself until it's shut down
+///
+/// Each read or write is a single packet of up to [`Config::chunk_size`] bytes at the file's
+/// position, which is tracked here as SFTP has no notion of one.
+struct RemoteFile<S> {
+    /// Only ever locked through `get_mut`, to make the pending operation `Sync`
+    state: Mutex<State<S>>,
+    handle: Arc<[u8]>,
+    position: u64,
+    seek: Option<SeekFrom>,
+    idle: Arc<Mutex<Vec<Session<S>>>>,
+    config: Config,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> RemoteFile<S> {
+    fn new(
+        session: Session<S>,
+        handle: Vec<u8>,
+        config: Config,
+        idle: Arc<Mutex<Vec<Session<S>>>>,
+    ) -> Self {
+        Self {
+            state: Mutex::new(State::Idle(session)),
+            handle: handle.into(),
+            position: 0,
+            seek: None,
+            idle,
+            config,
+        }
+    }
+
+    /// Waits for the pending operation, if there is one, and returns what it did
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Done>>> {
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let State::Busy(pending) = state else {
+            return Poll::Ready(None);
+        };
+        let (session, result) = ready!(pending.poll_unpin(cx));
+        match result {
+            Ok(Done::Closed) => {
+                *state = State::Closed;
+                release(&self.idle, session, self.config.max_idle);
+            }
+            Ok(Done::Wrote(len)) => {
+                *state = State::Idle(session);
+                self.position += len as u64;
+            }
+            _ => *state = State::Idle(session),
+        }
+        Poll::Ready(Some(result))
+    }
+
+    /// Starts an operation with the session, the file's handle and it's position
+    fn start(
+        &mut self,
+        operation: impl FnOnce(Session<S>, Arc<[u8]>, u64) -> Pending<S>,
+    ) -> io::Result<()> {
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        match mem::replace(state, State::Closed) {
+            State::Idle(session) => {
+                *state = State::Busy(operation(session, self.handle.clone(), self.position));
+                Ok(())
+            }
+            State::Busy(_) => unreachable!("operations are only started once the last is done"),
+            State::Closed => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "the file has been shut down",
+            )),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncRead for RemoteFile<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let len = buf.remaining().min(this.config.chunk_size as usize);
+        if len == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            // An operation left pending by another call finishes first
+            if let Some(done) = ready!(this.poll_pending(cx)) {
+                if let Done::Read(data) = done? {
+                    let len = data.len().min(buf.remaining());
+                    buf.put_slice(&data[..len]);
+                    this.position += len as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            this.start(|mut session, handle, offset| {
+                async move {
+                    let len = u32::try_from(len).unwrap_or(u32::MAX);
+                    let read = session.read_chunk(&handle, offset, len).await;
+                    (session, read.map(Done::Read))
+                }
+                .boxed()
+            })?;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncWrite for RemoteFile<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let len = buf.len().min(this.config.chunk_size as usize);
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if let Some(done) = ready!(this.poll_pending(cx)) {
+                if let Done::Wrote(len) = done? {
+                    return Poll::Ready(Ok(len));
+                }
+            }
+            let chunk = buf[..len].to_vec();
+            this.start(|mut session, handle, offset| {
+                async move {
+                    let written = session.write_chunk(&handle, offset, &chunk).await;
+                    (session, written.map(|()| Done::Wrote(chunk.len())))
+                }
+                .boxed()
+            })?;
+        }
+    }
+
+    /// Each write is acknowledged by the server before it's done, so flushing only waits for
+    /// the pending one
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let done = ready!(self.get_mut().poll_pending(cx));
+        Poll::Ready(done.transpose().map(drop))
+    }
+
+    /// Closes the file on the server, and returns it's session to the pool
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(done) = ready!(this.poll_pending(cx)) {
+                if let Done::Closed = done? {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            let state = this.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+            if let State::Closed = state {
+                return Poll::Ready(Ok(()));
+            }
+            this.start(|mut session, handle, _| {
+                async move {
+                    let closed = session.close(&handle).await;
+                    (session, closed.map(|()| Done::Closed))
+                }
+                .boxed()
+            })?;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncSeek for RemoteFile<S> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        self.get_mut().seek = Some(position);
+        Ok(())
+    }
+
+    /// Seeking from the end asks the server for the file's size
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        loop {
+            let size = match ready!(this.poll_pending(cx)).transpose() {
+                Ok(Some(Done::Size(size))) => Some(size),
+                Ok(_) => None,
+                Err(err) => {
+                    this.seek = None;
+                    return Poll::Ready(Err(err));
+                }
+            };
+            let (base, offset) = match this.seek {
+                None => return Poll::Ready(Ok(this.position)),
+                Some(SeekFrom::Start(position)) => (position, 0),
+                Some(SeekFrom::Current(offset)) => (this.position, offset),
+                Some(SeekFrom::End(offset)) => {
+                    let Some(size) = size else {
+                        this.start(|mut session, handle, _| {
+                            async move {
+                                let attrs = session.fstat(&handle).await;
+                                let size = attrs.map(|attrs| attrs.size.unwrap_or(0));
+                                (session, size.map(Done::Size))
+                            }
+                            .boxed()
+                        })?;
+                        continue;
+                    };
+                    (size, offset)
+                }
+            };
+            this.seek = None;
+            this.position = base.checked_add_signed(offset).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "seeked to a negative or overflowing position",
+                )
+            })?;
+            return Poll::Ready(Ok(this.position));
+        }
+    }
+}
+
+fn read_names(body: &[u8], entries: &mut Vec<(String, Attrs)>) -> io::Result<()> {
+    let mut reader = Reader(body);
+    for _ in 0..reader.u32()? {
+        let name = reader.string()?;
+        let _long_name = reader.bytes()?;
+        entries.push((name, reader.attrs()?));
+    }
+    Ok(())
+}
+
+/// Converts the attributes a server sent, any of which it may have left out
+fn metadata(attrs: &Attrs) -> Metadata {
+    let file_type = match attrs.permissions.map(|permissions| permissions & S_IFMT) {
+        Some(S_IFREG) | None => FileType::File,
+        Some(S_IFDIR) => FileType::Dir,
+        Some(S_IFLNK) => FileType::Symlink,
+        Some(_) => FileType::Other,
+    };
+    let mut metadata = Metadata::new(file_type, attrs.size.unwrap_or(0));
+    if let Some(permissions) = attrs.permissions {
+        metadata = metadata.with_permissions(permissions);
+    }
+    if let Some((uid, gid)) = attrs.uid_gid {
+        metadata = metadata.with_owner(uid, gid);
+    }
+    if let Some((atime, mtime)) = attrs.atime_mtime {
+        let time = |secs: u32| SystemTime::UNIX_EPOCH + Duration::from_secs(secs.into());
+        metadata = metadata
+            .with_accessed(time(atime))
+            .with_modified(time(mtime));
+    }
+    metadata
+}
+
+fn open_flags(mode: Mode) -> u32 {
+    match mode {
+        Mode::Read => FXF_READ,
+        Mode::AppendExisting => FXF_WRITE | FXF_APPEND,
+        Mode::CreateOrOverwrite => FXF_WRITE | FXF_CREAT | FXF_TRUNC,
+        Mode::CreateOrAppend => FXF_WRITE | FXF_CREAT | FXF_APPEND,
+        Mode::CreateNew => FXF_WRITE | FXF_CREAT | FXF_EXCL,
+    }
+}
+
+/// Converts a request path into the `/` separated form SFTP servers expect
+fn remote(path: &Path) -> io::Result<String> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "SFTP paths must be utf-8"))?;
+    Ok(if cfg!(windows) {
+        path.replace('\\', "/")
+    } else {
+        path.to_owned()
+    })
+}
+
+#[cfg(unix)]
+fn mode(perm: &Permissions) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    perm.mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(perm: &Permissions) -> u32 {
+    if perm.readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+fn unexpected_reply() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "unexpected SFTP reply")
+}
+
+fn expect_ok((ty, body): (u8, Vec<u8>)) -> io::Result<()> {
+    if ty != FXP_STATUS {
+        return Err(unexpected_reply());
+    }
+    let mut reader = Reader(&body);
+    let code = reader.u32()?;
+    if code == FX_OK {
+        return Ok(());
+    }
+    // Some servers omit the message and language tag
+    let message = reader.string().unwrap_or_default();
+    Err(status_error(code, &message))
+}
+
+fn expect_handle((ty, body): (u8, Vec<u8>)) -> io::Result<Vec<u8>> {
+    match ty {
+        FXP_HANDLE => Reader(&body).bytes(),
+        _ => expect_ok((ty, body)).and(Err(unexpected_reply())),
+    }
+}
+
+fn single_name((ty, body): (u8, Vec<u8>)) -> io::Result<String> {
+    match ty {
+        FXP_NAME => {
+            let mut entries = Vec::new();
+            read_names(&body, &mut entries)?;
+            entries
+                .pop()
+                .map(|(name, _)| name)
+                .ok_or_else(unexpected_reply)
+        }
+        _ => expect_ok((ty, body)).and(Err(unexpected_reply())),
+    }
+}
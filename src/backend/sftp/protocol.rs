@@ -0,0 +1,295 @@
+//! Packet encoding for version 3 of the SSH file transfer protocol (draft-ietf-secsh-filexfer-02)
+
+use std::io::{self, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub(crate) const VERSION: u32 = 3;
+/// Larger packets are rejected rather than buffered
+pub(crate) const MAX_PACKET_LEN: u32 = 256 * 1024;
+
+pub(crate) const FXP_INIT: u8 = 1;
+pub(crate) const FXP_VERSION: u8 = 2;
+pub(crate) const FXP_OPEN: u8 = 3;
+pub(crate) const FXP_CLOSE: u8 = 4;
+pub(crate) const FXP_READ: u8 = 5;
+pub(crate) const FXP_WRITE: u8 = 6;
+pub(crate) const FXP_LSTAT: u8 = 7;
+pub(crate) const FXP_FSTAT: u8 = 8;
+pub(crate) const FXP_SETSTAT: u8 = 9;
+pub(crate) const FXP_FSETSTAT: u8 = 10;
+pub(crate) const FXP_OPENDIR: u8 = 11;
+pub(crate) const FXP_READDIR: u8 = 12;
+pub(crate) const FXP_REMOVE: u8 = 13;
+pub(crate) const FXP_MKDIR: u8 = 14;
+pub(crate) const FXP_RMDIR: u8 = 15;
+pub(crate) const FXP_REALPATH: u8 = 16;
+pub(crate) const FXP_STAT: u8 = 17;
+pub(crate) const FXP_RENAME: u8 = 18;
+pub(crate) const FXP_READLINK: u8 = 19;
+pub(crate) const FXP_SYMLINK: u8 = 20;
+pub(crate) const FXP_STATUS: u8 = 101;
+pub(crate) const FXP_HANDLE: u8 = 102;
+pub(crate) const FXP_DATA: u8 = 103;
+pub(crate) const FXP_NAME: u8 = 104;
+pub(crate) const FXP_ATTRS: u8 = 105;
+pub(crate) const FXP_EXTENDED: u8 = 200;
+pub(crate) const FXP_EXTENDED_REPLY: u8 = 201;
+
+pub(crate) const FX_OK: u32 = 0;
+pub(crate) const FX_EOF: u32 = 1;
+pub(crate) const FX_NO_SUCH_FILE: u32 = 2;
+pub(crate) const FX_PERMISSION_DENIED: u32 = 3;
+pub(crate) const FX_FAILURE: u32 = 4;
+pub(crate) const FX_BAD_MESSAGE: u32 = 5;
+pub(crate) const FX_NO_CONNECTION: u32 = 6;
+pub(crate) const FX_CONNECTION_LOST: u32 = 7;
+pub(crate) const FX_OP_UNSUPPORTED: u32 = 8;
+
+/// `OpenSSH` extensions, which the backend uses when the server advertises them
+pub(crate) const POSIX_RENAME: &str = "posix-rename@openssh.com";
+pub(crate) const HARDLINK: &str = "hardlink@openssh.com";
+
+pub(crate) const FXF_READ: u32 = 0x01;
+pub(crate) const FXF_WRITE: u32 = 0x02;
+pub(crate) const FXF_APPEND: u32 = 0x04;
+pub(crate) const FXF_CREAT: u32 = 0x08;
+pub(crate) const FXF_TRUNC: u32 = 0x10;
+pub(crate) const FXF_EXCL: u32 = 0x20;
+
+const ATTR_SIZE: u32 = 0x01;
+const ATTR_UIDGID: u32 = 0x02;
+const ATTR_PERMISSIONS: u32 = 0x04;
+const ATTR_ACMODTIME: u32 = 0x08;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+/// File type bits of [`Attrs::permissions`], as in `st_mode`
+pub(crate) const S_IFMT: u32 = 0o170_000;
+pub(crate) const S_IFDIR: u32 = 0o040_000;
+pub(crate) const S_IFREG: u32 = 0o100_000;
+pub(crate) const S_IFLNK: u32 = 0o120_000;
+
+/// Converts a `SSH_FXP_STATUS` code into the closest [`ErrorKind`]
+pub(crate) fn status_error(code: u32, message: &str) -> io::Error {
+    let kind = match code {
+        FX_EOF => ErrorKind::UnexpectedEof,
+        FX_NO_SUCH_FILE => ErrorKind::NotFound,
+        FX_PERMISSION_DENIED => ErrorKind::PermissionDenied,
+        FX_BAD_MESSAGE => ErrorKind::InvalidData,
+        FX_NO_CONNECTION | FX_CONNECTION_LOST => ErrorKind::ConnectionAborted,
+        FX_OP_UNSUPPORTED => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    };
+    if message.is_empty() {
+        kind.into()
+    } else {
+        io::Error::new(kind, message.to_owned())
+    }
+}
+
+/// Converts an [`io::Error`] into the closest `SSH_FXP_STATUS` code
+pub(crate) fn error_status(err: &io::Error) -> u32 {
+    match err.kind() {
+        ErrorKind::UnexpectedEof => FX_EOF,
+        ErrorKind::NotFound => FX_NO_SUCH_FILE,
+        ErrorKind::PermissionDenied => FX_PERMISSION_DENIED,
+        ErrorKind::InvalidData => FX_BAD_MESSAGE,
+        ErrorKind::Unsupported => FX_OP_UNSUPPORTED,
+        _ => FX_FAILURE,
+    }
+}
+
+/// File attributes, where absent fields weren't sent (or shouldn't be changed)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Attrs {
+    pub(crate) size: Option<u64>,
+    pub(crate) uid_gid: Option<(u32, u32)>,
+    pub(crate) permissions: Option<u32>,
+    pub(crate) atime_mtime: Option<(u32, u32)>,
+}
+
+impl Attrs {
+    pub(crate) fn is_dir(&self) -> bool {
+        self.permissions
+            .is_some_and(|permissions| permissions & S_IFMT == S_IFDIR)
+    }
+}
+
+/// Builds the body of a packet
+#[derive(Debug, Default)]
+pub(crate) struct Writer(Vec<u8>);
+
+impl Writer {
+    pub(crate) fn u8(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    pub(crate) fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(crate) fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(crate) fn string(self, value: impl AsRef<[u8]>) -> Self {
+        let value = value.as_ref();
+        let mut writer = self.u32(u32::try_from(value.len()).unwrap_or(u32::MAX));
+        writer.0.extend_from_slice(value);
+        writer
+    }
+
+    pub(crate) fn attrs(self, attrs: &Attrs) -> Self {
+        let flags = [
+            (attrs.size.is_some(), ATTR_SIZE),
+            (attrs.uid_gid.is_some(), ATTR_UIDGID),
+            (attrs.permissions.is_some(), ATTR_PERMISSIONS),
+            (attrs.atime_mtime.is_some(), ATTR_ACMODTIME),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        let mut writer = self.u32(flags);
+        if let Some(size) = attrs.size {
+            writer = writer.u64(size);
+        }
+        if let Some((uid, gid)) = attrs.uid_gid {
+            writer = writer.u32(uid).u32(gid);
+        }
+        if let Some(permissions) = attrs.permissions {
+            writer = writer.u32(permissions);
+        }
+        if let Some((atime, mtime)) = attrs.atime_mtime {
+            writer = writer.u32(atime).u32(mtime);
+        }
+        writer
+    }
+
+    /// Writes the body to `stream` as a packet of type `ty`
+    pub(crate) async fn send<S: AsyncWrite + Unpin>(
+        self,
+        stream: &mut S,
+        ty: u8,
+    ) -> io::Result<()> {
+        let len = u32::try_from(self.0.len() + 1)
+            .ok()
+            .filter(|len| *len <= MAX_PACKET_LEN)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "packet too large"))?;
+        let mut packet = Vec::with_capacity(self.0.len() + 5);
+        packet.extend_from_slice(&len.to_be_bytes());
+        packet.push(ty);
+        packet.extend_from_slice(&self.0);
+        stream.write_all(&packet).await?;
+        stream.flush().await
+    }
+}
+
+/// Reads a packet from `stream`, returning it's type and body
+pub(crate) async fn receive<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(u8, Vec<u8>)> {
+    let len = stream.read_u32().await?;
+    if len == 0 || len > MAX_PACKET_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid packet length",
+        ));
+    }
+    let ty = stream.read_u8().await?;
+    let mut body = vec![0; len as usize - 1];
+    stream.read_exact(&mut body).await?;
+    Ok((ty, body))
+}
+
+/// Parses the body of a packet
+#[derive(Debug)]
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated packet"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from(self.u32()?) << 32 | u64::from(self.u32()?))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        self.take(len).map(<[u8]>::to_vec)
+    }
+
+    pub(crate) fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    pub(crate) fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+        if flags & ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((self.u32()?, self.u32()?));
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            attrs.atime_mtime = Some((self.u32()?, self.u32()?));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attrs)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() -> io::Result<()> {
+        let attrs = Attrs {
+            size: Some(1 << 40),
+            uid_gid: None,
+            permissions: Some(S_IFDIR | 0o755),
+            atime_mtime: Some((1, 2)),
+        };
+        let mut buffer = Vec::new();
+        Writer::default()
+            .u32(7)
+            .string("/tmp")
+            .attrs(&attrs)
+            .send(&mut buffer, FXP_MKDIR)
+            .await?;
+
+        let (ty, body) = receive(&mut buffer.as_slice()).await?;
+        assert_eq!(ty, FXP_MKDIR);
+        let mut reader = Reader(&body);
+        assert_eq!(reader.u32()?, 7);
+        assert_eq!(reader.string()?, "/tmp");
+        assert_eq!(reader.attrs()?, attrs);
+        assert!(attrs.is_dir() && reader.is_empty());
+        Ok(())
+    }
+}
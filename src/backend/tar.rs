@@ -0,0 +1,1024 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, lock::Mutex, FutureExt};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+};
+use tower_service::Service;
+
+use super::{key, normalize};
+use crate::{CopyOptions, FileHandle, FileType, Metadata, Mode, Request, Response};
+
+pub(crate) const BLOCK: usize = 512;
+pub(crate) const BLOCK_SIZE: u64 = BLOCK as u64;
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// A backend which stores its files in a single tar archive
+///
+/// Existing entries are indexed when the archive is opened, and writes append new entries to the
+/// end of the archive instead of rewriting it.  An overwritten file is shadowed by its newer entry,
+/// and removals are recorded as OCI-style whiteout entries (`.wh.<name>`), so the space they held is
+/// only reclaimed by a [`Request::Compact`].
+///
+/// [`Request::Open`] with [`Mode::Read`] reads a file's entry in place, through a handle of it's
+/// own on the archive.  Opening files to write them, as well as hard links, permission changes and
+/// directory renames, fail with [`ErrorKind::Unsupported`].
+#[derive(Debug, Clone)]
+pub struct Tar {
+    archive: Arc<Mutex<Archive>>,
+}
+
+impl Tar {
+    /// Opens the archive at `path` (creating an empty archive if there isn't one) and indexes its
+    /// entries
+    ///
+    /// # Errors
+    ///
+    /// - If the archive can't be opened or created
+    /// - If the archive contains a malformed header
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Archive::open(path.as_ref().to_owned())
+            .await
+            .map(|archive| Self {
+                archive: Arc::new(Mutex::new(archive)),
+            })
+    }
+}
+
+impl Service<Request> for Tar {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    /// Requests are serialized on the archive when called, so the [`Tar`] backend is always ready
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let archive = self.archive.clone();
+        async move { archive.lock().await.handle(req).await }.boxed()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Kind {
+    File,
+    Directory,
+    Symlink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub(crate) kind: Kind,
+    /// Offset of the entry's data (or, for entries without data, of the end of it's header)
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    pub(crate) mode: u32,
+    pub(crate) mtime: u64,
+}
+
+impl Entry {
+    pub(crate) fn new(kind: Kind, size: u64) -> Self {
+        let mode = match kind {
+            Kind::File => 0o644,
+            Kind::Directory => 0o755,
+            Kind::Symlink(_) => 0o777,
+        };
+        Self {
+            kind,
+            offset: 0,
+            size,
+            mode,
+            mtime: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Archive {
+    path: PathBuf,
+    file: fs::File,
+    entries: HashMap<PathBuf, Entry>,
+    /// Offset of the end-of-archive marker, where new entries get written
+    end: u64,
+}
+
+impl Archive {
+    async fn open(path: PathBuf) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        let (entries, end) = index(&mut file).await?;
+        let mut archive = Self {
+            path,
+            file,
+            entries,
+            end,
+        };
+        if archive.end == 0 {
+            archive.finish().await?;
+        }
+        Ok(archive)
+    }
+
+    async fn handle(&mut self, req: Request) -> io::Result<Response> {
+        match req {
+            Request::Compact => self.compact().await.map(Response::done),
+            Request::Flush => Ok(Response::Done),
+            Request::Copy { from, to, options } if options == CopyOptions::default() => {
+                let bytes = self.read(&from).await?;
+                self.write_file(&to, &bytes).await?;
+                Ok(Response::Copied(bytes.len() as u64))
+            }
+            Request::CreateDir { path, recursive } => self
+                .create_dir(&normalize(&path)?, recursive)
+                .await
+                .map(Response::done),
+            Request::Exists(path) => Ok(Response::Exists(self.exists(&normalize(&path)?))),
+            Request::FollowLink(path) => match self.entries.get(&normalize(&path)?) {
+                Some(Entry {
+                    kind: Kind::Symlink(target),
+                    ..
+                }) => Ok(Response::PointsTo(target.clone())),
+                Some(_) => Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "not a symbolic link",
+                )),
+                None => Err(ErrorKind::NotFound.into()),
+            },
+            Request::GetMetadata {
+                path,
+                follow_symlinks,
+            } => self
+                .metadata(&path, follow_symlinks)
+                .map(Response::Metadata),
+            Request::Open {
+                mode: Mode::Read,
+                path,
+            } => self.open_entry(&path).await.map(Response::File),
+            Request::ReadBytes(path) => self.read(&path).await.map(Response::Bytes),
+            Request::ReadRange { path, range } => {
+                self.read_range(&path, range).await.map(Response::Bytes)
+            }
+            Request::RemoveDir { path, recursive } => self
+                .remove_dir(&normalize(&path)?, recursive)
+                .await
+                .map(Response::done),
+            Request::RemoveFile(path) => self
+                .remove_file(&normalize(&path)?)
+                .await
+                .map(Response::done),
+            Request::Rename { from, to } => self
+                .rename(&normalize(&from)?, &normalize(&to)?)
+                .await
+                .map(Response::done),
+            #[cfg(unix)]
+            Request::Symlink { src, dst } => self
+                .create(&normalize(&dst)?, Kind::Symlink(src.to_path_buf()), &[])
+                .await
+                .map(Response::done),
+            Request::WriteBytes { path, bytes } => {
+                self.write_file(&path, &bytes).await.map(Response::done)
+            }
+            Request::HardLink { .. }
+            | Request::Open { .. }
+            | Request::SetPermissions { .. }
+            | Request::AppendBytes { .. }
+            | Request::CopyDir { .. }
+            | Request::MetadataBatch { .. }
+            | Request::ReadDir(_)
+            | Request::Copy { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(windows)]
+            Request::SymlinkDir { .. }
+            | Request::SymlinkFile { .. }
+            | Request::ReadJunction(_)
+            | Request::OpenStream { .. }
+            | Request::GetFileAttributes(_)
+            | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "archive")]
+            Request::Archive { .. } | Request::Extract { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "dedup")]
+            Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+            #[cfg(feature = "posix-acl")]
+            Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+            #[cfg(unix)]
+            Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                Err(ErrorKind::Unsupported.into())
+            }
+        }
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty()
+            || self.entries.iter().any(|(entry_path, entry)| {
+                if entry_path == path {
+                    entry.kind == Kind::Directory
+                } else {
+                    entry_path.starts_with(path)
+                }
+            })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.contains_key(path) || self.is_dir(path)
+    }
+
+    fn has_children(&self, path: &Path) -> bool {
+        self.entries
+            .keys()
+            .any(|entry_path| entry_path != path && entry_path.starts_with(path))
+    }
+
+    fn ensure_parent_dir(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !self.is_dir(parent) => Err(ErrorKind::NotFound.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Follows symbolic links in the final component of `path`
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let mut path = normalize(path)?;
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            match self.entries.get(&path) {
+                Some(Entry {
+                    kind: Kind::Symlink(target),
+                    ..
+                }) => path = normalize(&path.parent().unwrap_or(Path::new("")).join(target))?,
+                _ => return Ok(path),
+            }
+        }
+        Err(io::Error::other("too many levels of symbolic links"))
+    }
+
+    /// Describes the entry at `path` from it's header, or an implicit directory if it only has
+    /// children
+    fn metadata(&self, path: &Path, follow_symlinks: bool) -> io::Result<Metadata> {
+        let path = if follow_symlinks {
+            self.resolve(path)?
+        } else {
+            normalize(path)?
+        };
+        match self.entries.get(&path) {
+            Some(entry) => {
+                let file_type = match entry.kind {
+                    Kind::File => FileType::File,
+                    Kind::Directory => FileType::Dir,
+                    Kind::Symlink(_) => FileType::Symlink,
+                };
+                let metadata = Metadata::new(file_type, entry.size).with_permissions(entry.mode);
+                Ok(
+                    match UNIX_EPOCH.checked_add(Duration::from_secs(entry.mtime)) {
+                        Some(modified) => metadata.with_modified(modified),
+                        None => metadata,
+                    },
+                )
+            }
+            None if self.is_dir(&path) => {
+                Ok(Metadata::new(FileType::Dir, 0).with_permissions(0o755))
+            }
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn read(&mut self, path: &Path) -> io::Result<Vec<u8>> {
+        self.read_range(path, 0..u64::MAX).await
+    }
+
+    async fn read_range(&mut self, path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let path = self.resolve(path)?;
+        match self.entries.get(&path) {
+            Some(Entry {
+                kind: Kind::File,
+                offset,
+                size,
+                ..
+            }) => {
+                let start = range.start.min(*size);
+                let len = range.end.min(*size).saturating_sub(start);
+                let mut bytes = vec![0; usize::try_from(len).map_err(io::Error::other)?];
+                self.file.seek(SeekFrom::Start(offset + start)).await?;
+                self.file.read_exact(&mut bytes).await?;
+                Ok(bytes)
+            }
+            _ if self.is_dir(&path) => Err(ErrorKind::IsADirectory.into()),
+            _ => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Opens the file at `path` for reading, through a new handle on the archive so it's unaffected
+    /// by later requests, even a [`Request::Compact`] replacing the archive
+    async fn open_entry(&self, path: &Path) -> io::Result<FileHandle> {
+        let path = self.resolve(path)?;
+        match self.entries.get(&path) {
+            Some(Entry {
+                kind: Kind::File,
+                offset,
+                size,
+                ..
+            }) => {
+                let mut file = fs::File::open(&self.path).await?;
+                file.seek(SeekFrom::Start(*offset)).await?;
+                Ok(FileHandle::read_only(Section {
+                    file,
+                    start: *offset,
+                    len: *size,
+                    position: 0,
+                }))
+            }
+            _ if self.is_dir(&path) => Err(ErrorKind::IsADirectory.into()),
+            _ => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn write_file(&mut self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let path = self.resolve(path)?;
+        if self.is_dir(&path) {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        self.ensure_parent_dir(&path)?;
+        self.create(&path, Kind::File, bytes).await
+    }
+
+    async fn create_dir(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        if self.exists(path) {
+            return if recursive && self.is_dir(path) {
+                Ok(())
+            } else {
+                Err(ErrorKind::AlreadyExists.into())
+            };
+        }
+        if recursive {
+            let missing: Vec<_> = path
+                .ancestors()
+                .skip(1)
+                .take_while(|ancestor| !self.exists(ancestor))
+                .map(Path::to_owned)
+                .collect();
+            for ancestor in missing.iter().rev() {
+                self.create(ancestor, Kind::Directory, &[]).await?;
+            }
+        } else {
+            self.ensure_parent_dir(path)?;
+        }
+        self.create(path, Kind::Directory, &[]).await
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        match self.entries.get(path) {
+            Some(Entry {
+                kind: Kind::Directory,
+                ..
+            }) => Err(ErrorKind::IsADirectory.into()),
+            Some(_) => self.whiteout(path).await,
+            None if self.is_dir(path) => Err(ErrorKind::IsADirectory.into()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    async fn remove_dir(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        if path.as_os_str().is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "can't remove the root of the archive",
+            ));
+        }
+        if !self.is_dir(path) {
+            return Err(if self.entries.contains_key(path) {
+                ErrorKind::NotADirectory
+            } else {
+                ErrorKind::NotFound
+            }
+            .into());
+        }
+        if !recursive && self.has_children(path) {
+            return Err(ErrorKind::DirectoryNotEmpty.into());
+        }
+        self.whiteout(path).await
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let entry = match self.entries.get(from) {
+            Some(entry) if entry.kind != Kind::Directory => entry.clone(),
+            Some(_) => return Err(ErrorKind::Unsupported.into()),
+            None if self.is_dir(from) => return Err(ErrorKind::Unsupported.into()),
+            None => return Err(ErrorKind::NotFound.into()),
+        };
+        if self.is_dir(to) {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        self.ensure_parent_dir(to)?;
+        let bytes = match entry.kind {
+            Kind::File => self.read(from).await?,
+            _ => Vec::new(),
+        };
+        self.create(to, entry.kind, &bytes).await?;
+        self.whiteout(from).await
+    }
+
+    async fn whiteout(&mut self, path: &Path) -> io::Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::from(ErrorKind::InvalidInput))?;
+        let mut whiteout = std::ffi::OsString::from(WHITEOUT_PREFIX);
+        whiteout.push(name);
+        let whiteout_path = path.with_file_name(whiteout);
+        self.append(&whiteout_path, Entry::new(Kind::File, 0), &[])
+            .await?;
+        remove_tree(&mut self.entries, path);
+        Ok(())
+    }
+
+    async fn create(&mut self, path: &Path, kind: Kind, data: &[u8]) -> io::Result<()> {
+        let entry = Entry::new(kind, data.len() as u64);
+        let entry = self.append(path, entry, data).await?;
+        self.entries.insert(path.to_owned(), entry);
+        Ok(())
+    }
+
+    /// Writes a new entry over the end-of-archive marker, then writes a new marker after it
+    async fn append(&mut self, path: &Path, mut entry: Entry, data: &[u8]) -> io::Result<Entry> {
+        self.file.seek(SeekFrom::Start(self.end)).await?;
+        entry.offset = self.end + write_headers(&mut self.file, path, &entry).await?;
+        self.file.write_all(data).await?;
+        self.file.write_all(&padding(entry.size)).await?;
+        self.end = entry.offset + entry.size + padding(entry.size).len() as u64;
+        self.finish().await?;
+        Ok(entry)
+    }
+
+    /// Writes the end-of-archive marker (two zeroed blocks)
+    async fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.end)).await?;
+        self.file.write_all(&[0; 2 * BLOCK]).await?;
+        self.file.flush().await
+    }
+
+    /// Rewrites the archive with only the live entries, dropping shadowed entries and whiteouts
+    async fn compact(&mut self) -> io::Result<()> {
+        let mut live: Vec<_> = self.entries.iter().collect();
+        live.sort_by_key(|(_, entry)| entry.offset);
+
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_owned();
+        temp_name.push(".compact");
+        let temp_path = self.path.with_file_name(temp_name);
+        let mut out = fs::File::create(&temp_path).await?;
+        for (path, entry) in live {
+            write_headers(&mut out, path, entry).await?;
+            self.file.seek(SeekFrom::Start(entry.offset)).await?;
+            tokio::io::copy(&mut (&mut self.file).take(entry.size), &mut out).await?;
+            out.write_all(&padding(entry.size)).await?;
+        }
+        out.write_all(&[0; 2 * BLOCK]).await?;
+        out.sync_all().await?;
+        drop(out);
+
+        fs::rename(&temp_path, &self.path).await?;
+        *self = Self::open(self.path.clone()).await?;
+        Ok(())
+    }
+}
+
+/// The data of one entry of an archive, read and seeked as if it were a file of it's own
+struct Section {
+    file: fs::File,
+    /// Offset of the entry's data in the archive
+    start: u64,
+    len: u64,
+    /// Position in the entry, which the archive's own position is kept `start` ahead of
+    position: u64,
+}
+
+impl AsyncRead for Section {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let left = usize::try_from(this.len.saturating_sub(this.position)).unwrap_or(usize::MAX);
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(left.min(buf.remaining())));
+        ready!(Pin::new(&mut this.file).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.position += read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for Section {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => this.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before the start"))?;
+        let offset = this
+            .start
+            .checked_add(position)
+            .ok_or(ErrorKind::InvalidInput)?;
+        Pin::new(&mut this.file).start_seek(SeekFrom::Start(offset))?;
+        this.position = position;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.file).poll_complete(cx))?;
+        Poll::Ready(Ok(this.position))
+    }
+}
+
+fn remove_tree(entries: &mut HashMap<PathBuf, Entry>, path: &Path) {
+    entries.retain(|entry_path, _| !entry_path.starts_with(path));
+}
+
+pub(crate) fn padding(size: u64) -> Vec<u8> {
+    vec![0; usize::try_from((BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE).unwrap_or_default()]
+}
+
+/// Replays the entries of an archive, returning the live entries and the offset of the end marker
+async fn index(file: &mut fs::File) -> io::Result<(HashMap<PathBuf, Entry>, u64)> {
+    let mut entries = HashMap::new();
+    let mut offset = 0;
+    let mut overrides = Overrides::default();
+    loop {
+        let mut block = [0; BLOCK];
+        file.seek(SeekFrom::Start(offset)).await?;
+        match file.read_exact(&mut block).await {
+            Ok(_) if block.iter().any(|byte| *byte != 0) => {}
+            // An empty file, a truncated archive or the end-of-archive marker
+            Ok(_) => break,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        let header = Header::parse(&block)?;
+        let size = overrides.size.take().unwrap_or(header.size);
+        let data_offset = offset + BLOCK_SIZE;
+        offset = data_offset + size + padding(size).len() as u64;
+
+        match header.typeflag {
+            b'x' => overrides.parse_pax(&read_data(file, data_offset, size).await?),
+            b'L' => overrides.path = Some(read_string(file, data_offset, size).await?),
+            b'K' => overrides.link = Some(read_string(file, data_offset, size).await?),
+            typeflag => {
+                let name = overrides.path.take().unwrap_or(header.name);
+                let link = overrides.link.take().unwrap_or(header.link);
+                let Ok(path) = normalize(Path::new(&name)) else {
+                    continue;
+                };
+                let kind = match typeflag {
+                    b'0' | b'\0' | b'7' => Kind::File,
+                    b'5' => Kind::Directory,
+                    b'2' => Kind::Symlink(PathBuf::from(link)),
+                    b'1' => match normalize(Path::new(&link))
+                        .ok()
+                        .and_then(|target| entries.get(&target).cloned())
+                    {
+                        Some(entry) => {
+                            entries.insert(path, entry);
+                            continue;
+                        }
+                        None => continue,
+                    },
+                    // Global PAX headers, devices, fifos and vendor extensions have no effect on
+                    // the files the backend serves
+                    _ => continue,
+                };
+                let entry = Entry {
+                    kind,
+                    offset: data_offset,
+                    size,
+                    mode: header.mode,
+                    mtime: header.mtime,
+                };
+                apply(&mut entries, path, entry);
+            }
+        }
+    }
+    Ok((entries, offset))
+}
+
+/// Adds an entry to the index, handling whiteouts
+fn apply(entries: &mut HashMap<PathBuf, Entry>, path: PathBuf, entry: Entry) {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        entries.insert(path, entry);
+        return;
+    };
+    if name == OPAQUE_WHITEOUT {
+        if let Some(parent) = path.parent() {
+            entries.retain(|entry_path, _| entry_path == parent || !entry_path.starts_with(parent));
+        }
+    } else if let Some(removed) = name.strip_prefix(WHITEOUT_PREFIX) {
+        let removed = path.with_file_name(removed);
+        remove_tree(entries, &removed);
+    } else {
+        entries.insert(path, entry);
+    }
+}
+
+async fn read_data(file: &mut fs::File, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; usize::try_from(size).map_err(io::Error::other)?];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+async fn read_string(file: &mut fs::File, offset: u64, size: u64) -> io::Result<String> {
+    read_data(file, offset, size)
+        .await
+        .map(|data| c_string(&data))
+}
+
+/// Values from PAX extended headers and GNU long name entries which apply to the next entry
+#[derive(Debug, Default)]
+pub(crate) struct Overrides {
+    pub(crate) path: Option<String>,
+    pub(crate) link: Option<String>,
+    pub(crate) size: Option<u64>,
+}
+
+impl Overrides {
+    pub(crate) fn parse_pax(&mut self, data: &[u8]) {
+        // records have the form "<length> <key>=<value>\n", where length includes itself
+        let mut rest = data;
+        while let Some(space) = rest.iter().position(|byte| *byte == b' ') {
+            let Some(len) = std::str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .filter(|len| (space + 1..=rest.len()).contains(len))
+            else {
+                return;
+            };
+            let record = String::from_utf8_lossy(&rest[space + 1..len]);
+            if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+                match key {
+                    "path" => self.path = Some(value.to_owned()),
+                    "linkpath" => self.link = Some(value.to_owned()),
+                    "size" => self.size = value.parse().ok(),
+                    _ => {}
+                }
+            }
+            rest = &rest[len..];
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Header {
+    pub(crate) name: String,
+    pub(crate) link: String,
+    pub(crate) size: u64,
+    pub(crate) mode: u32,
+    pub(crate) mtime: u64,
+    pub(crate) typeflag: u8,
+}
+
+impl Header {
+    pub(crate) fn parse(block: &[u8; BLOCK]) -> io::Result<Self> {
+        let stored = parse_numeric(&block[148..156])?;
+        let computed: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| u64::from(if (148..156).contains(&i) { b' ' } else { *byte }))
+            .sum();
+        if stored != computed {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "tar header checksum mismatch",
+            ));
+        }
+
+        let mut name = c_string(&block[0..100]);
+        if &block[257..262] == b"ustar" {
+            let prefix = c_string(&block[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{prefix}/{name}");
+            }
+        }
+        Ok(Self {
+            name,
+            link: c_string(&block[157..257]),
+            size: parse_numeric(&block[124..136])?,
+            mode: u32::try_from(parse_numeric(&block[100..108])? & 0o7777).unwrap_or(0o644),
+            mtime: parse_numeric(&block[136..148])?,
+            typeflag: block[156],
+        })
+    }
+
+    fn encode(name: &str, link: &str, entry: &Entry, typeflag: u8) -> [u8; BLOCK] {
+        let mut block = [0; BLOCK];
+        copy_truncated(&mut block[0..100], name.as_bytes());
+        write_octal(&mut block[100..108], u64::from(entry.mode));
+        write_octal(&mut block[108..116], 0);
+        write_octal(&mut block[116..124], 0);
+        write_numeric(&mut block[124..136], entry.size);
+        write_numeric(&mut block[136..148], entry.mtime);
+        block[156] = typeflag;
+        copy_truncated(&mut block[157..257], link.as_bytes());
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        block[148..156].fill(b' ');
+        let checksum: u64 = block.iter().copied().map(u64::from).sum();
+        block[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        block
+    }
+}
+
+/// Writes the header for an entry (preceded by a PAX header if its names don't fit in a ustar
+/// header), returning the number of bytes written
+async fn write_headers<W: AsyncWrite + Unpin>(
+    out: &mut W,
+    path: &Path,
+    entry: &Entry,
+) -> io::Result<u64> {
+    let headers = headers(path, entry)?;
+    out.write_all(&headers).await?;
+    Ok(headers.len() as u64)
+}
+
+/// The header for an entry, preceded by a PAX header if its names don't fit in a ustar header
+pub(crate) fn headers(path: &Path, entry: &Entry) -> io::Result<Vec<u8>> {
+    let name = entry_name(path, &entry.kind)?;
+    let (typeflag, link) = match &entry.kind {
+        Kind::File => (b'0', String::new()),
+        Kind::Directory => (b'5', String::new()),
+        Kind::Symlink(target) => (b'2', target_name(target)?),
+    };
+
+    let mut records = Vec::new();
+    if name.len() > 100 {
+        records.extend(pax_record("path", &name));
+    }
+    if link.len() > 100 {
+        records.extend(pax_record("linkpath", &link));
+    }
+    let mut headers = Vec::new();
+    if !records.is_empty() {
+        let pax = Entry {
+            kind: Kind::File,
+            size: records.len() as u64,
+            ..entry.clone()
+        };
+        headers.extend(Header::encode("././@PaxHeader", "", &pax, b'x'));
+        headers.extend(records);
+        headers.extend(padding(pax.size));
+    }
+    headers.extend(Header::encode(&name, &link, entry, typeflag));
+    Ok(headers)
+}
+
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let body = format!(" {key}={value}\n");
+    let mut len = body.len();
+    while (len.to_string().len() + body.len()) != len {
+        len = len.to_string().len() + body.len();
+    }
+    format!("{len}{body}").into_bytes()
+}
+
+fn entry_name(path: &Path, kind: &Kind) -> io::Result<String> {
+    let mut name = key(path)?;
+    if *kind == Kind::Directory {
+        name.push('/');
+    }
+    Ok(name)
+}
+
+fn target_name(target: &Path) -> io::Result<String> {
+    target
+        .to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "symlink targets must be utf-8"))
+}
+
+pub(crate) fn c_string(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn copy_truncated(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Parses a numeric header field, which is either NUL/space terminated octal or (a GNU extension
+/// for large values) big-endian base-256 flagged by the high bit of the first byte
+fn parse_numeric(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |acc, byte| {
+                (acc << 8) | u64::from(*byte)
+            }));
+    }
+    let digits = c_string(field);
+    let digits = digits.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    field[..width].copy_from_slice(format!("{value:0width$o}").as_bytes());
+    field[width] = 0;
+}
+
+fn write_numeric(field: &mut [u8], value: u64) {
+    if value < 1 << (3 * (field.len() - 1)) {
+        write_octal(field, value);
+    } else {
+        field.fill(0);
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&value.to_be_bytes());
+        field[0] |= 0x80;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kit::TestDir;
+
+    async fn call(tar: &mut Tar, req: Request) -> io::Result<Response> {
+        tar.call(req).await
+    }
+
+    #[tokio::test]
+    async fn test_append_remove_compact() -> io::Result<()> {
+        let dir = TestDir::new("tar")?;
+        let path = dir.join("archive.tar");
+        let long_name = PathBuf::from("dir").join("a".repeat(120));
+
+        let mut tar = Tar::open(&path).await?;
+        for (file, contents) in [
+            ("dir/a.txt", "first"),
+            ("dir/a.txt", "second"),
+            ("dir/b.txt", "removed"),
+        ] {
+            call(
+                &mut tar,
+                Request::CreateDir {
+                    path: Path::new("dir").into(),
+                    recursive: true,
+                },
+            )
+            .await?;
+            call(
+                &mut tar,
+                Request::WriteBytes {
+                    path: Path::new(file).into(),
+                    bytes: contents.into(),
+                },
+            )
+            .await?;
+        }
+        call(
+            &mut tar,
+            Request::WriteBytes {
+                path: long_name.as_path().into(),
+                bytes: b"long".to_vec(),
+            },
+        )
+        .await?;
+        call(
+            &mut tar,
+            Request::RemoveFile(Path::new("/dir/b.txt").into()),
+        )
+        .await?;
+        let size_before = std::fs::metadata(&path)?.len();
+
+        let mut reopened = Tar::open(&path).await?;
+        assert!(matches!(
+            call(&mut reopened, Request::ReadBytes(Path::new("dir/a.txt").into())).await?,
+            Response::Bytes(bytes) if bytes == b"second"
+        ));
+        assert!(matches!(
+            call(
+                &mut reopened,
+                Request::ReadRange {
+                    path: Path::new("dir/a.txt").into(),
+                    range: 3..10,
+                },
+            )
+            .await?,
+            Response::Bytes(bytes) if bytes == b"ond"
+        ));
+        assert!(matches!(
+            call(
+                &mut reopened,
+                Request::Exists(Path::new("dir/b.txt").into())
+            )
+            .await?,
+            Response::Exists(false)
+        ));
+
+        let stat = Request::GetMetadata {
+            path: Path::new("dir/a.txt").into(),
+            follow_symlinks: true,
+        };
+        let metadata = call(&mut reopened, stat).await?.into_metadata()?;
+        assert!(metadata.is_file());
+        assert_eq!((metadata.len(), metadata.permissions()), (6, Some(0o644)));
+        assert!(metadata.modified().is_ok());
+
+        call(&mut reopened, Request::Compact).await?;
+        assert!(std::fs::metadata(&path)?.len() < size_before);
+        assert!(matches!(
+            call(&mut reopened, Request::ReadBytes(long_name.into())).await?,
+            Response::Bytes(bytes) if bytes == b"long"
+        ));
+        assert!(matches!(
+            call(&mut reopened, Request::ReadBytes(Path::new("dir/a.txt").into())).await?,
+            Response::Bytes(bytes) if bytes == b"second"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open() -> io::Result<()> {
+        let dir = TestDir::new("tar_open")?;
+        let path = dir.join("archive.tar");
+        let mut tar = Tar::open(&path).await?;
+        for (file, contents) in [
+            ("a.txt", "before"),
+            ("b.txt", "0123456789"),
+            ("c.txt", "after"),
+        ] {
+            let write = Request::WriteBytes {
+                path: Path::new(file).into(),
+                bytes: contents.into(),
+            };
+            call(&mut tar, write).await?.into_done()?;
+        }
+        let open = |mode, file: &str| Request::Open {
+            mode,
+            path: Path::new(file).into(),
+        };
+        let mut file = call(&mut tar, open(Mode::Read, "b.txt"))
+            .await?
+            .into_file()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "0123456789");
+        assert_eq!(file.seek(SeekFrom::End(-3)).await?, 7);
+        contents.clear();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "789");
+        file.seek(SeekFrom::Start(2)).await?;
+        let mut two = [0; 2];
+        file.read_exact(&mut two).await?;
+        assert_eq!(&two, b"23");
+        assert!(file.seek(SeekFrom::Current(-5)).await.is_err());
+
+        // The handle keeps reading the entry it opened after the archive is compacted
+        file.seek(SeekFrom::Start(0)).await?;
+        call(&mut tar, Request::RemoveFile(Path::new("a.txt").into())).await?;
+        call(&mut tar, Request::Compact).await?;
+        contents.clear();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "0123456789");
+        assert!(file.write_all(b"x").await.is_err());
+
+        let write = call(&mut tar, open(Mode::CreateOrOverwrite, "b.txt")).await;
+        assert_eq!(
+            write.map(drop).map_err(|err| err.kind()),
+            Err(ErrorKind::Unsupported)
+        );
+        let missing = call(&mut tar, open(Mode::Read, "missing.txt")).await;
+        assert_eq!(
+            missing.map(drop).map_err(|err| err.kind()),
+            Err(ErrorKind::NotFound)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::future::{ready, BoxFuture, FutureExt};
+use tokio::fs;
+use tower_service::Service;
+
+use super::rebase;
+use crate::{FileSystem, Request, Response};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A backend which stores it's files in a new temporary directory, deleted once the backend (and
+/// every clone of it) is dropped
+///
+/// Request paths are resolved relative to the directory, and may not escape it with `..`, though
+/// symbolic links created inside it are followed by the operating system as usual.  Requests are
+/// otherwise handled exactly as [`FileSystem`] handles them, by [`FileSystem::new`] unless another
+/// is given to [`TempDir::file_system`].
+#[derive(Debug, Clone)]
+pub struct TempDir {
+    root: Arc<Root>,
+    fs: FileSystem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The start of the directory's name, followed by a unique suffix
+    pub prefix: String,
+    /// Where to create the directory
+    pub parent: PathBuf,
+    /// Keep the directory (and print it's path) if it's dropped while the thread is panicking, to
+    /// allow inspecting what a failed test left behind
+    pub persist_on_panic: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            prefix: String::from("tower_fs_"),
+            parent: std::env::temp_dir(),
+            persist_on_panic: false,
+        }
+    }
+}
+
+impl TempDir {
+    /// Creates a new, empty directory with a unique name
+    ///
+    /// # Errors
+    ///
+    /// If the directory can't be created
+    pub async fn new(config: Config) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        loop {
+            let path = config.parent.join(format!(
+                "{}{}_{timestamp:08x}_{}",
+                config.prefix,
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match fs::create_dir(&path).await {
+                Ok(()) => {
+                    return Ok(Self {
+                        root: Arc::new(Root {
+                            path,
+                            persist_on_panic: config.persist_on_panic,
+                        }),
+                        fs: FileSystem::new(),
+                    })
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Performs requests with `fs`, such as one with a runtime dedicated to file system work
+    #[must_use]
+    pub fn file_system(mut self, fs: FileSystem) -> Self {
+        self.fs = fs;
+        self
+    }
+
+    /// The temporary directory
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.root.path
+    }
+}
+
+impl Service<Request> for TempDir {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    /// The directory is created up front, so the [`TempDir`] backend is always ready
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        match rebase(&self.root.path, req) {
+            Ok(req) => {
+                // Keep the directory alive until the request completes
+                let root = self.root.clone();
+                let response = self.fs.call(req);
+                async move {
+                    let response = response.await;
+                    drop(root);
+                    response
+                }
+                .boxed()
+            }
+            Err(err) => ready(Err(err)).boxed(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Root {
+    path: PathBuf,
+    persist_on_panic: bool,
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        if self.persist_on_panic && std::thread::panicking() {
+            eprintln!(
+                "keeping temporary directory {} after a panic",
+                self.path.display()
+            );
+            return;
+        }
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_removed_on_drop() -> io::Result<()> {
+        let mut temp = TempDir::new(Config::default()).await?;
+        let path = temp.path().to_owned();
+        temp.call(Request::WriteBytes {
+            path: Path::new("/file.txt").into(),
+            bytes: b"scratch".to_vec(),
+        })
+        .await?;
+        assert!(path.join("file.txt").exists());
+        assert_eq!(
+            temp.call(Request::ReadBytes(Path::new("../escape").into()))
+                .await
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::InvalidInput)
+        );
+
+        drop(temp);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! A backend for WASI runtimes, such as wasmtime or wasm based edge runtimes
+//!
+//! WASI modules can only reach the directories the host preopened for them, and can't start the
+//! threads of tokio's blocking pool, which [`FileSystem`](crate::FileSystem) performs requests on.
+//! [`Preopened`] performs each request on the calling thread instead, inside one preopened
+//! directory, so it works on a current thread runtime:
+//!
+//! ```no_run
+//! // Run with `wasmtime run --dir ./site::/site server.wasm`
+//! use tower_fs::{backend::wasi::Preopened, FileSystemExt};
+//!
+//! fn main() -> std::io::Result<()> {
+//!     let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+//!     runtime.block_on(async {
+//!         let mut fs = Preopened::new("/site");
+//!         let index = fs.read("index.html").await?;
+//!         println!("{}", String::from_utf8_lossy(&index));
+//!         Ok(())
+//!     })
+//! }
+//! ```
+//!
+//! Nothing here is specific to WASI, so the backend also runs (and is tested) on other targets.
+//! Symbolic links can't be created through it there either, as [`Request`] only has symlink
+//! variants on unix and windows.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::future::{ready, Ready};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tower_service::Service;
+
+use super::rebase;
+use crate::{local::call_std, FileHandle, Request, Response};
+
+/// A backend which performs requests inside a preopened directory, on the calling thread
+///
+/// Request paths are resolved relative to the directory, and may not escape it with `..`.  The
+/// files it opens read and write on the calling thread too.
+#[derive(Debug, Clone)]
+pub struct Preopened {
+    root: Arc<Path>,
+}
+
+impl Preopened {
+    /// A backend for the directory the host preopened at `root`, such as `/site` for
+    /// `wasmtime run --dir ./site::/site`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into().into(),
+        }
+    }
+
+    /// The preopened directory
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Service<Request> for Preopened {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = Ready<io::Result<Response>>;
+
+    /// Requests are performed when called, so the [`Preopened`] backend is always ready
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        ready(rebase(&self.root, req).and_then(|req| {
+            match req {
+                Request::Open { mode, path } => mode
+                    .into_open_options()
+                    .open(path)
+                    .map(|file| Response::File(FileHandle::new(Inline { file, seek: None }))),
+                req => call_std(req),
+            }
+        }))
+    }
+}
+
+/// A file which is read, written and seeked on the calling thread
+#[derive(Debug)]
+struct Inline {
+    file: std::fs::File,
+    /// The result of the seek started by [`AsyncSeek::start_seek`]
+    seek: Option<io::Result<u64>>,
+}
+
+impl AsyncRead for Inline {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = self.get_mut().file.read(buf.initialize_unfilled())?;
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Inline {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().file.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().file.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().file.flush())
+    }
+}
+
+impl AsyncSeek for Inline {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.seek = Some(this.file.seek(position));
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        Poll::Ready(
+            this.seek
+                .take()
+                .unwrap_or_else(|| this.file.stream_position()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystemExt, Mode};
+
+    async fn open(fs: &mut Preopened, mode: Mode) -> io::Result<FileHandle> {
+        let Response::File(file) = fs
+            .call(Request::Open {
+                mode,
+                path: Path::new("notes.txt").into(),
+            })
+            .await?
+        else {
+            unreachable!("Open always responds with a file")
+        };
+        Ok(file)
+    }
+
+    #[tokio::test]
+    async fn test_preopened() -> io::Result<()> {
+        let dir = TestDir::new("wasi")?;
+        let mut fs = Preopened::new(&dir);
+
+        fs.write("/notes.txt", b"hello".to_vec()).await?;
+        assert_eq!(std::fs::read(dir.join("notes.txt"))?, b"hello");
+        open(&mut fs, Mode::AppendExisting)
+            .await?
+            .write_all(b", world")
+            .await?;
+        let mut file = open(&mut fs, Mode::Read).await?;
+        assert_eq!(file.seek(SeekFrom::Start(7)).await?, 7);
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "world");
+        assert_eq!(
+            fs.read("../escape").await.map_err(|err| err.kind()),
+            Err(ErrorKind::InvalidInput)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Type erased services, for storing different stacks behind one type
+
+use std::{
+    fmt, io,
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tower_service::Service;
+
+use crate::{Request, Response};
+
+/// A stack of any middleware over any backend, as a `Service<Request>`
+pub type BoxFsService = BoxCloneService<Request, Response, io::Error>;
+
+/// A clonable service whose type has been erased, so stacks built from different middleware can be
+/// stored in the same struct field
+///
+/// The service and it's futures must be `Send`, and the service `Sync`, so a `BoxCloneService` can
+/// live in shared application state.
+pub struct BoxCloneService<T, U, E>(Box<dyn CloneService<T, U, E>>);
+
+impl<T, U, E> BoxCloneService<T, U, E> {
+    /// Erases the type of `service`
+    pub fn new<S>(service: S) -> Self
+    where
+        S: Service<T, Response = U, Error = E> + Clone + Send + Sync + 'static,
+        S::Future: Send + 'static,
+    {
+        Self(Box::new(Boxed(service)))
+    }
+}
+
+impl<T, U, E> Service<T> for BoxCloneService<T, U, E> {
+    type Response = U;
+    type Error = E;
+    type Future = BoxFuture<'static, Result<U, E>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: T) -> Self::Future {
+        self.0.call(req)
+    }
+}
+
+impl<T, U, E> Clone for BoxCloneService<T, U, E> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+impl<T, U, E> fmt::Debug for BoxCloneService<T, U, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxCloneService").finish_non_exhaustive()
+    }
+}
+
+/// A service with boxed futures, which can clone itself into a box
+trait CloneService<T, U, E>:
+    Service<T, Response = U, Error = E, Future = BoxFuture<'static, Result<U, E>>> + Send + Sync
+{
+    fn clone_box(&self) -> Box<dyn CloneService<T, U, E>>;
+}
+
+/// Boxes the futures of the service it wraps
+#[derive(Clone)]
+struct Boxed<S>(S);
+
+impl<S, T> Service<T> for Boxed<S>
+where
+    S: Service<T>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: T) -> Self::Future {
+        self.0.call(req).boxed()
+    }
+}
+
+impl<S, T, U, E> CloneService<T, U, E> for Boxed<S>
+where
+    S: Service<T, Response = U, Error = E> + Clone + Send + Sync + 'static,
+    S::Future: Send + 'static,
+{
+    fn clone_box(&self) -> Box<dyn CloneService<T, U, E>> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt};
+
+    /// Holds whichever stack the application was configured with
+    #[derive(Debug, Clone)]
+    struct State {
+        fs: BoxFsService,
+    }
+
+    #[tokio::test]
+    async fn test_box_fs_service() -> io::Result<()> {
+        let dir = TestDir::new("boxed")?;
+        let path = dir.join("boxed.txt");
+        let state = State {
+            fs: BoxFsService::new(FileSystem::new()),
+        };
+        let mut fs = state.fs.clone();
+        fs.write(&path, b"boxed".to_vec()).await?;
+        assert_eq!(state.fs.clone().read(&path).await?, b"boxed");
+        fs.remove_file(&path).await
+    }
+}
@@ -0,0 +1,525 @@
+//! Copying local files with [`CopyOptions`], keeping what they ask for of the originals
+
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    digest::{hex, Sha256},
+    BufferPool,
+};
+
+/// How many bytes a resumable copy writes between checkpoints
+const CHECKPOINT: u64 = 64 * 1024 * 1024;
+
+/// What a [`Request::Copy`](crate::Request::Copy) or [`Request::CopyDir`](crate::Request::CopyDir)
+/// keeps of the originals besides their contents, and which copies it leaves alone
+///
+/// By default only contents are copied, though [`FileSystem`](crate::FileSystem) gives files the
+/// permissions of the originals as [`std::fs::copy`] does, while [`CopyOptions::archive`] keeps
+/// what `cp -a` does.  Backends other than `FileSystem` fail with [`io::ErrorKind::Unsupported`]
+/// unless every option is off.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CopyOptions {
+    /// Gives copies the permissions of the originals, directories included
+    pub permissions: bool,
+    /// Gives copies the access and modification times of the originals.  Symlinks keep the times
+    /// they're created with
+    pub timestamps: bool,
+    /// Gives copies the owner and group of the originals, on unix.  Only privileged processes can
+    /// give files away, so failures for lack of privileges are ignored, as they are by `cp -a`
+    pub ownership: bool,
+    /// Copies the extended attributes of the originals with `getfattr` and `setfattr` from the attr
+    /// package, which only Linux supports.  Attributes outside the `user.` namespace which can't be
+    /// set, for lack of privileges or support, are left out
+    pub xattrs: bool,
+    /// Leaves files alone whose copies already have the same length and modification time, and
+    /// symlinks whose copies already point to the same target, as `rsync` does.  Only copies made
+    /// with [`timestamps`](Self::timestamps) keep the modification time, and skipped files count as
+    /// no bytes copied
+    pub skip_identical: bool,
+    /// Records how far each file has been copied in a hidden file next to the copy, such as
+    /// `.video.mkv.copy-progress`, so a copy which is interrupted carries on from there when it's
+    /// retried, rather than starting over.  The part already copied is hashed and checked against
+    /// the record before it's kept, and files which have changed since are copied again from the
+    /// start.  Progress is recorded every 64 MiB and when a copy fails, and the record is removed
+    /// once the copy is finished.  Files are copied a buffer at a time rather than with
+    /// [`std::fs::copy`], and only the bytes copied by the retry are counted
+    pub resumable: bool,
+    /// Reads back each file once it's copied, and fails with [`io::ErrorKind::InvalidData`] if
+    /// its SHA-256 doesn't match the original's.  The copy is read back through the page cache, so
+    /// this catches what went wrong on the way to the file system rather than on the disk.  Files
+    /// skipped as identical aren't read
+    pub verify: bool,
+    /// Removes copies which fail [`verify`](Self::verify), rather than leaving them for inspection
+    pub remove_unverified: bool,
+}
+
+impl CopyOptions {
+    /// Keeps the permissions, timestamps, ownership and extended attributes of the originals, as
+    /// `cp -a` does
+    #[must_use]
+    pub const fn archive() -> Self {
+        Self {
+            permissions: true,
+            timestamps: true,
+            ownership: true,
+            xattrs: true,
+            skip_identical: false,
+            resumable: false,
+            verify: false,
+            remove_unverified: false,
+        }
+    }
+}
+
+/// Copies the file `from` to `to` with `options`, returning the number of bytes copied
+pub(crate) fn copy_file(from: &Path, to: &Path, options: CopyOptions) -> io::Result<u64> {
+    let metadata = fs::metadata(from)?;
+    if options.skip_identical && identical(&metadata, to) {
+        return Ok(0);
+    }
+    let copied = if options.resumable {
+        copy_resumable(from, &metadata, to)?
+    } else {
+        fs::copy(from, to)?
+    };
+    if options.verify {
+        verify(from, to, options)?;
+    }
+    preserve(from, &metadata, to, options)?;
+    Ok(copied)
+}
+
+/// Fails if the contents of `to` don't hash the same as those of `from`, removing `to` if
+/// `options` ask for it
+fn verify(from: &Path, to: &Path, options: CopyOptions) -> io::Result<()> {
+    if sha256_file(from)? == sha256_file(to)? {
+        return Ok(());
+    }
+    if options.remove_unverified {
+        fs::remove_file(to)?;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "the copy at {} doesn't match {}",
+            to.display(),
+            from.display()
+        ),
+    ))
+}
+
+fn sha256_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buffer = BufferPool::shared().get();
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => return Ok(hasher.finish()),
+            Ok(read) => hasher.update(&buffer[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Copies `from`, whose metadata is `metadata`, to `to` from where the last attempt's checkpoint
+/// left off, returning the number of bytes copied by this attempt
+fn copy_resumable(from: &Path, metadata: &fs::Metadata, to: &Path) -> io::Result<u64> {
+    let record = checkpoint_path(to)?;
+    let mut source = fs::File::open(from)?;
+    let mut dest = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(to)?;
+    let (mut offset, mut hasher) = match Checkpoint::read(&record) {
+        Some(checkpoint) if checkpoint.matches(metadata) => {
+            match verify_prefix(&mut dest, &checkpoint)? {
+                Some(hasher) => (checkpoint.offset, hasher),
+                None => (0, Sha256::default()),
+            }
+        }
+        _ => (0, Sha256::default()),
+    };
+    dest.set_len(offset)?;
+    source.seek(SeekFrom::Start(offset))?;
+    dest.seek(SeekFrom::Start(offset))?;
+    let started = offset;
+    let mut checkpointed = offset;
+    let mut buffer = BufferPool::shared().get();
+    let copied = loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err),
+        };
+        if let Err(err) = dest.write_all(&buffer[..read]) {
+            break Err(err);
+        }
+        hasher.update(&buffer[..read]);
+        offset += read as u64;
+        if offset - checkpointed >= CHECKPOINT {
+            if let Err(err) = checkpoint(&dest, &record, metadata, offset, &hasher) {
+                break Err(err);
+            }
+            checkpointed = offset;
+        }
+    };
+    if let Err(err) = copied {
+        // What was written before the failure is kept for the retry, if it can be
+        let _ = dest
+            .flush()
+            .and_then(|()| checkpoint(&dest, &record, metadata, offset, &hasher));
+        return Err(err);
+    }
+    // As `fs::copy` does
+    dest.set_permissions(metadata.permissions())?;
+    drop(dest);
+    match fs::remove_file(&record) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    Ok(offset - started)
+}
+
+/// Hashes the first `checkpoint.offset` bytes of `dest`, returning the hasher if they match the
+/// checkpoint
+fn verify_prefix(dest: &mut fs::File, checkpoint: &Checkpoint) -> io::Result<Option<Sha256>> {
+    if dest.metadata()?.len() < checkpoint.offset {
+        return Ok(None);
+    }
+    let mut hasher = Sha256::default();
+    let mut buffer = BufferPool::shared().get();
+    let mut remaining = checkpoint.offset;
+    while remaining > 0 {
+        let len = buffer
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        dest.read_exact(&mut buffer[..len])?;
+        hasher.update(&buffer[..len]);
+        remaining -= len as u64;
+    }
+    let verified = hex(&hasher.clone().finish()) == checkpoint.sha256;
+    Ok(verified.then_some(hasher))
+}
+
+/// Makes what's been written to `dest` durable, then records it
+fn checkpoint(
+    dest: &fs::File,
+    record: &Path,
+    metadata: &fs::Metadata,
+    offset: u64,
+    hasher: &Sha256,
+) -> io::Result<()> {
+    dest.sync_data()?;
+    let checkpoint = Checkpoint {
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        offset,
+        sha256: hex(&hasher.clone().finish()),
+    };
+    checkpoint.write(record)
+}
+
+/// The hidden file next to `to` recording the progress of a resumable copy
+fn checkpoint_path(to: &Path) -> io::Result<PathBuf> {
+    let name = to
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "copies need a file name"))?;
+    let mut record = std::ffi::OsString::from(".");
+    record.push(name);
+    record.push(".copy-progress");
+    Ok(to.with_file_name(record))
+}
+
+/// How far a resumable copy had got, and what it was copying
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Checkpoint {
+    /// The length of the original
+    len: u64,
+    /// The modification time of the original, if it has one
+    modified: Option<SystemTime>,
+    /// How many bytes had been copied
+    offset: u64,
+    /// The hex SHA-256 of the bytes which had been copied
+    sha256: String,
+}
+
+impl Checkpoint {
+    /// Whether the checkpoint was recorded for a file with `metadata`
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        self.len == metadata.len()
+            && self.modified == metadata.modified().ok()
+            && self.offset <= self.len
+    }
+
+    /// Reads the checkpoint recorded at `path`, or `None` if there isn't one it can read
+    fn read(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(path).ok()?;
+        let mut fields = text.lines().map(|line| line.split_once(' '));
+        let mut field = |name| match fields.next()? {
+            Some((key, value)) if key == name => Some(value.to_owned()),
+            _ => None,
+        };
+        let len = field("len")?.parse().ok()?;
+        let modified = match field("modified")?.as_str() {
+            "unknown" => None,
+            modified => {
+                let (secs, nanos) = modified.split_once('.')?;
+                let since_epoch = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+                Some(UNIX_EPOCH.checked_add(since_epoch)?)
+            }
+        };
+        Some(Self {
+            len,
+            modified,
+            offset: field("offset")?.parse().ok()?,
+            sha256: field("sha256")?,
+        })
+    }
+
+    /// Records the checkpoint at `path`, as lines of names and values
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let modified = match self.modified.map(|time| time.duration_since(UNIX_EPOCH)) {
+            Some(Ok(since_epoch)) => {
+                format!("{}.{}", since_epoch.as_secs(), since_epoch.subsec_nanos())
+            }
+            // Earlier times can't be recorded, so the checkpoint is never used
+            Some(Err(_)) => "before-epoch".to_owned(),
+            None => "unknown".to_owned(),
+        };
+        let text = format!(
+            "len {}\nmodified {modified}\noffset {}\nsha256 {}\n",
+            self.len, self.offset, self.sha256
+        );
+        fs::write(path, text)
+    }
+}
+
+/// Whether the file at `to` has the length and modification time of `metadata`
+fn identical(metadata: &fs::Metadata, to: &Path) -> bool {
+    fs::metadata(to).is_ok_and(|existing| {
+        existing.is_file()
+            && existing.len() == metadata.len()
+            && matches!(
+                (existing.modified(), metadata.modified()),
+                (Ok(existing), Ok(original)) if existing == original
+            )
+    })
+}
+
+/// Gives `to` what `options` keep of `from`, whose metadata (not following symlinks) is `metadata`
+///
+/// Timestamps are set before permissions, as the copy is opened to set them, which the permissions
+/// may not allow.
+pub(crate) fn preserve(
+    from: &Path,
+    metadata: &fs::Metadata,
+    to: &Path,
+    options: CopyOptions,
+) -> io::Result<()> {
+    if options.xattrs {
+        copy_xattrs(from, to)?;
+    }
+    #[cfg(unix)]
+    if options.ownership {
+        use std::os::unix::fs::{chown, lchown, MetadataExt};
+
+        let (uid, gid) = (Some(metadata.uid()), Some(metadata.gid()));
+        let owned = if metadata.is_symlink() {
+            lchown(to, uid, gid)
+        } else {
+            chown(to, uid, gid)
+        };
+        match owned {
+            Err(err) if err.kind() != io::ErrorKind::PermissionDenied => return Err(err),
+            _ => {}
+        }
+    }
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    if options.timestamps {
+        let mut times = fs::FileTimes::new();
+        if let Ok(accessed) = metadata.accessed() {
+            times = times.set_accessed(accessed);
+        }
+        if let Ok(modified) = metadata.modified() {
+            times = times.set_modified(modified);
+        }
+        open_for_times(to)?.set_times(times)?;
+    }
+    if options.permissions {
+        fs::set_permissions(to, metadata.permissions())?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    crate::linux::copy_xattrs(from, to)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_xattrs(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only Linux's extended attributes can be copied",
+    ))
+}
+
+/// Opens the file or directory at `path` to set it's times
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+/// Opens the file or directory at `path` to set it's times, which Windows needs write access for,
+/// and opening directories needs `FILE_FLAG_BACKUP_SEMANTICS`
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> io::Result<fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(0x0200_0000)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::test_kit::TestDir;
+
+    #[test]
+    fn test_copy_file() -> io::Result<()> {
+        let dir = TestDir::new("copy")?;
+        let (from, to) = (dir.join("from.txt"), dir.join("to.txt"));
+        fs::write(&from, "contents")?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::open(&from)?.set_times(fs::FileTimes::new().set_modified(modified))?;
+        let mut readonly = fs::metadata(&from)?.permissions();
+        readonly.set_readonly(true);
+        fs::set_permissions(&from, readonly)?;
+
+        let options = CopyOptions {
+            permissions: true,
+            timestamps: true,
+            ownership: true,
+            skip_identical: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(copy_file(&from, &to, options)?, 8);
+        let copied = fs::metadata(&to)?;
+        assert_eq!(copied.modified()?, modified);
+        assert!(copied.permissions().readonly());
+        // The copy is now identical, so it's skipped
+        assert_eq!(copy_file(&from, &to, options)?, 0);
+
+        // Copies which differ are replaced
+        let mut writable = copied.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        writable.set_readonly(false);
+        fs::set_permissions(&to, writable)?;
+        fs::write(&to, "changed")?;
+        assert_eq!(copy_file(&from, &to, options)?, 8);
+        assert_eq!(fs::read(&to)?, b"contents");
+
+        #[cfg(target_os = "linux")]
+        match copy_file(&from, &dir.join("xattrs.txt"), CopyOptions::archive()) {
+            // The attr package may not be installed
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {}
+            copied => assert_eq!(copied?, 8),
+        }
+
+        let mut writable = fs::metadata(&from)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        writable.set_readonly(false);
+        fs::set_permissions(&from, writable.clone())?;
+        fs::set_permissions(&to, writable)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_resumable_copy() -> io::Result<()> {
+        let dir = TestDir::new("copy_resumable")?;
+        let (from, to) = (dir.join("large.bin"), dir.join("copy.bin"));
+        let contents = (0..=u8::MAX).cycle().take(200_000).collect::<Vec<_>>();
+        fs::write(&from, &contents)?;
+        let record = checkpoint_path(&to)?;
+        assert_eq!(record, dir.join(".copy.bin.copy-progress"));
+        let metadata = fs::metadata(&from)?;
+        let interrupted = |prefix: &[u8]| -> io::Result<()> {
+            // What was written after the checkpoint is dropped
+            fs::write(&to, [prefix, b"unrecorded"].concat())?;
+            Checkpoint {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+                offset: prefix.len() as u64,
+                sha256: hex(&crate::digest::sha256(prefix)),
+            }
+            .write(&record)
+        };
+        let options = CopyOptions {
+            resumable: true,
+            ..CopyOptions::default()
+        };
+
+        interrupted(&contents[..120_000])?;
+        assert_eq!(copy_file(&from, &to, options)?, 80_000);
+        assert_eq!(fs::read(&to)?, contents);
+        assert!(!record.exists());
+
+        // Copies whose prefix no longer matches start over
+        let mut corrupted = contents[..120_000].to_vec();
+        interrupted(&corrupted)?;
+        corrupted[5] ^= 1;
+        fs::write(&to, &corrupted)?;
+        assert_eq!(copy_file(&from, &to, options)?, 200_000);
+        assert_eq!(fs::read(&to)?, contents);
+
+        // As do copies of files which have changed since
+        interrupted(&contents[..120_000])?;
+        fs::write(&from, &contents[..150_000])?;
+        assert_eq!(copy_file(&from, &to, options)?, 150_000);
+        assert_eq!(fs::read(&to)?, &contents[..150_000]);
+        assert!(!record.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> io::Result<()> {
+        let dir = TestDir::new("copy_verify")?;
+        let (from, to) = (dir.join("from.txt"), dir.join("to.txt"));
+        fs::write(&from, "contents")?;
+        let options = CopyOptions {
+            verify: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(copy_file(&from, &to, options)?, 8);
+
+        // Copies which don't match are kept unless they're to be removed
+        fs::write(&to, "corrupted")?;
+        assert!(
+            verify(&from, &to, options).is_err_and(|err| err.kind() == io::ErrorKind::InvalidData)
+        );
+        assert!(to.exists());
+        let removing = CopyOptions {
+            remove_unverified: true,
+            ..options
+        };
+        assert!(verify(&from, &to, removing).is_err());
+        assert!(!to.exists());
+        Ok(())
+    }
+}
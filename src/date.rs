@@ -0,0 +1,189 @@
+//! Conversion of [`SystemTime`]s into calendar dates, for the date formats used by network protocols
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A UTC date and time, with second precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub(crate) year: u64,
+    /// 1 based
+    pub(crate) month: u64,
+    pub(crate) day: u64,
+    pub(crate) hour: u64,
+    pub(crate) minute: u64,
+    pub(crate) second: u64,
+    /// Days since sunday
+    pub(crate) weekday: u64,
+}
+
+impl From<SystemTime> for DateTime {
+    /// Times before the epoch are clamped to it
+    fn from(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let (days, secs) = (secs / 86_400, secs % 86_400);
+
+        // Converts days since the epoch to a civil date (http://howardhinnant.github.io/date_algorithms.html)
+        let shifted = days + 719_468;
+        let era = shifted / 146_097;
+        let day_of_era = shifted % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+
+        Self {
+            year: year_of_era + era * 400 + u64::from(month <= 2),
+            month,
+            day: day_of_year - (153 * month_index + 2) / 5 + 1,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+            // the epoch was a thursday
+            weekday: (days + 4) % 7,
+        }
+    }
+}
+
+impl From<DateTime> for SystemTime {
+    fn from(date: DateTime) -> Self {
+        // The inverse of the conversion above
+        let year = date.year - u64::from(date.month <= 2);
+        let (era, year_of_era) = (year / 400, year % 400);
+        let month_index = if date.month > 2 {
+            date.month - 3
+        } else {
+            date.month + 9
+        };
+        let day_of_year = (153 * month_index + 2) / 5 + date.day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+        UNIX_EPOCH
+            + Duration::from_secs(days * 86_400 + date.hour * 3600 + date.minute * 60 + date.second)
+    }
+}
+
+impl DateTime {
+    /// Parses an HTTP date in the preferred format (`Sun, 06 Nov 1994 08:49:37 GMT`), returning
+    /// `None` for the obsolete formats and dates before the epoch
+    pub(crate) fn parse_http(value: &str) -> Option<Self> {
+        let mut fields = value.split_ascii_whitespace();
+        let (_weekday, day, month, year, time, zone) = (
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+            fields.next()?,
+        );
+        if zone != "GMT" || fields.next().is_some() {
+            return None;
+        }
+        let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+        let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+        let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+        let (year, day) = (year.parse::<u64>().ok()?, day.parse::<u64>().ok()?);
+        Self::new([year, month, day], [hour, minute, second])
+    }
+
+    /// Parses an ISO 8601 timestamp in UTC (`1994-11-06T08:49:37.000Z`), as in S3's XML
+    /// responses, ignoring fractions of a second
+    pub(crate) fn parse_iso(value: &str) -> Option<Self> {
+        let (date, time) = value.strip_suffix('Z')?.split_once('T')?;
+        let mut date = date.splitn(3, '-').map(str::parse::<u64>);
+        let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+        let time = time.split_once('.').map_or(time, |(time, _fraction)| time);
+        let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+        let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+        Self::new([year, month, day], [hour, minute, second])
+    }
+
+    /// Checks the fields of a parsed date, returning `None` for dates before the epoch
+    fn new([year, month, day]: [u64; 3], [hour, minute, second]: [u64; 3]) -> Option<Self> {
+        if year < 1970
+            || !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return None;
+        }
+        let parsed = Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            weekday: 0,
+        };
+        Some(Self::from(SystemTime::from(parsed)))
+    }
+
+    /// Formats the date as `ls -l` does for old files (`Nov  6  1994`), as FTP directory listings
+    /// expect
+    pub(crate) fn ls(&self) -> String {
+        #[allow(clippy::cast_possible_truncation)]
+        let month = MONTHS[(self.month as usize + 11) % 12];
+        format!("{month} {:>2} {:>5}", self.day, self.year)
+    }
+
+    /// Formats the date as an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`)
+    pub(crate) fn http(&self) -> String {
+        #[allow(clippy::cast_possible_truncation)]
+        let (weekday, month) = (
+            WEEKDAYS[self.weekday as usize % 7],
+            MONTHS[(self.month as usize + 11) % 12],
+        );
+        format!(
+            "{weekday}, {:02} {month} {:04} {:02}:{:02}:{:02} GMT",
+            self.day, self.year, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(
+            DateTime::from(UNIX_EPOCH + Duration::from_secs(784_111_777)).http(),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            DateTime::parse_http("Sun, 06 Nov 1994 08:49:37 GMT").map(SystemTime::from),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            DateTime::from(UNIX_EPOCH + Duration::from_secs(784_111_777)).ls(),
+            "Nov  6  1994"
+        );
+        assert_eq!(DateTime::parse_http("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn test_iso_date() {
+        for value in ["1994-11-06T08:49:37.000Z", "1994-11-06T08:49:37Z"] {
+            assert_eq!(
+                DateTime::parse_iso(value).map(SystemTime::from),
+                Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+            );
+        }
+        assert_eq!(DateTime::parse_iso("1994-11-06T08:49:37+01:00"), None);
+        assert_eq!(DateTime::parse_iso("1994-13-06T08:49:37Z"), None);
+    }
+}
@@ -0,0 +1,247 @@
+//! Finding files with the same contents, for [`Request::FindDuplicates`](crate::Request::FindDuplicates)
+//!
+//! Files are grouped by their length first, so only files which share a length with another file
+//! are read, and then by the SHA-256 digest of their contents.  Duplicates can then be hard linked
+//! together, so the tree holds one copy of each distinct content.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, Metadata},
+    io::{self, ErrorKind, Read},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{digest::Sha256, BufferPool};
+
+/// How duplicates are found, and what's done with them
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DedupOptions {
+    /// Files shorter than this are left out, which by default is only empty files
+    pub min_size: u64,
+    /// Replaces every file of a set with a hard link to the first, once they've all been found
+    pub hard_link: bool,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 1,
+            hard_link: false,
+        }
+    }
+}
+
+/// Files with the same contents
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DuplicateSet {
+    /// The length of each file
+    pub size: u64,
+    /// The paths of the files relative to the searched directory, in order
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateSet {
+    /// The space taken by all but one of the files, which linking them together frees unless some
+    /// of them are already links to each other
+    #[must_use]
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64).saturating_sub(1)
+    }
+}
+
+/// A file found by the walk
+#[derive(Debug)]
+struct Candidate {
+    path: PathBuf,
+    /// The device and inode of the file, which it shares with its hard links
+    id: Option<(u64, u64)>,
+    modified: Option<SystemTime>,
+}
+
+/// Finds the regular files below the local directory `dir` with the same contents
+///
+/// Symlinks aren't followed.  Paths which are already hard links to one file count as a single
+/// file, so a set is only returned if it holds at least two distinct files, but all their paths are
+/// listed.  Sets are ordered by their first path.
+pub(crate) fn find_duplicates(dir: &Path, options: &DedupOptions) -> io::Result<Vec<DuplicateSet>> {
+    let mut by_size = BTreeMap::<u64, Vec<Candidate>>::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(parent) = dirs.pop() {
+        for entry in std::fs::read_dir(dir.join(&parent))? {
+            let entry = entry?;
+            let path = parent.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() {
+                let metadata = entry.metadata()?;
+                if metadata.len() >= options.min_size {
+                    by_size.entry(metadata.len()).or_default().push(Candidate {
+                        path,
+                        id: file_id(&metadata),
+                        modified: metadata.modified().ok(),
+                    });
+                }
+            }
+        }
+    }
+
+    let mut sets = Vec::new();
+    for (size, candidates) in by_size {
+        if candidates.len() < 2 {
+            continue;
+        }
+        // Hard links to one file are only read once
+        let mut digests = HashMap::new();
+        let mut by_digest = BTreeMap::<[u8; 32], Vec<Candidate>>::new();
+        for candidate in candidates {
+            let digest = if let Some(digest) = candidate.id.and_then(|id| digests.get(&id)) {
+                *digest
+            } else {
+                let digest = hash(&dir.join(&candidate.path))?;
+                if let Some(id) = candidate.id {
+                    digests.insert(id, digest);
+                }
+                digest
+            };
+            by_digest.entry(digest).or_default().push(candidate);
+        }
+        for mut files in by_digest.into_values() {
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            let distinct = files
+                .iter()
+                .any(|file| file.id.is_none() || file.id != files[0].id);
+            if files.len() < 2 || !distinct {
+                continue;
+            }
+            if options.hard_link {
+                link_together(dir, &files)?;
+            }
+            sets.push(DuplicateSet {
+                size,
+                paths: files.into_iter().map(|file| file.path).collect(),
+            });
+        }
+    }
+    sets.sort_by(|a, b| a.paths.cmp(&b.paths));
+    Ok(sets)
+}
+
+fn hash(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut sha256 = Sha256::default();
+    let mut buf = BufferPool::shared().get();
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(sha256.finish()),
+            Ok(read) => sha256.update(&buf[..read]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Replaces each of `files` after the first with a hard link to it
+///
+/// Each link is made beside the file it replaces and renamed over it, so the file is never
+/// missing.  Files which are already links to the first, or which were modified since they were
+/// found, are left alone.
+fn link_together(dir: &Path, files: &[Candidate]) -> io::Result<()> {
+    let original = &files[0];
+    let original_path = dir.join(&original.path);
+    if !unchanged(&original_path, original)? {
+        return Ok(());
+    }
+    for file in &files[1..] {
+        let path = dir.join(&file.path);
+        if (file.id.is_some() && file.id == original.id) || !unchanged(&path, file)? {
+            continue;
+        }
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(".dedup");
+        let temp = path.with_file_name(temp_name);
+        std::fs::hard_link(&original_path, &temp)?;
+        if let Err(err) = std::fs::rename(&temp, &path) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` is still the one which was found and hashed
+fn unchanged(path: &Path, candidate: &Candidate) -> io::Result<bool> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    Ok(metadata.is_file()
+        && file_id(&metadata) == candidate.id
+        && metadata.modified().ok() == candidate.modified)
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Only unix exposes which files are hard links to each other through [`Metadata`]
+#[cfg(not(unix))]
+fn file_id(_: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, Request};
+
+    #[tokio::test]
+    async fn test_find_duplicates() -> io::Result<()> {
+        let dir = TestDir::new("dedup")?;
+        std::fs::create_dir_all(dir.join("a/b"))?;
+        std::fs::write(dir.join("one.txt"), "same")?;
+        std::fs::write(dir.join("a/two.txt"), "same")?;
+        std::fs::write(dir.join("a/b/three.txt"), "same")?;
+        // The same length, with different contents
+        std::fs::write(dir.join("a/other.txt"), "diff")?;
+        std::fs::write(dir.join("empty"), "")?;
+        std::fs::write(dir.join("a/empty"), "")?;
+        let mut fs = FileSystem::new();
+        let mut find = |hard_link| {
+            fs.call(Request::FindDuplicates {
+                dir: dir.path().into(),
+                options: DedupOptions {
+                    hard_link,
+                    ..DedupOptions::default()
+                },
+            })
+        };
+
+        let expected = DuplicateSet {
+            size: 4,
+            paths: ["a/b/three.txt", "a/two.txt", "one.txt"]
+                .map(PathBuf::from)
+                .to_vec(),
+        };
+        assert_eq!(
+            find(false).await?.into_duplicates()?,
+            std::slice::from_ref(&expected)
+        );
+        assert_eq!(expected.wasted(), 8);
+        assert_eq!(find(true).await?.into_duplicates()?, [expected]);
+        assert_eq!(std::fs::read(dir.join("one.txt"))?, b"same");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            assert_eq!(std::fs::metadata(dir.join("one.txt"))?.nlink(), 3);
+            // Once they're linked together they're no longer duplicates
+            assert!(find(false).await?.into_duplicates()?.is_empty());
+        }
+        Ok(())
+    }
+}
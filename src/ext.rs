@@ -0,0 +1,422 @@
+//! Convenience methods for calling a `Service<Request>` outside of a tower stack
+
+use std::{
+    future::{poll_fn, Future},
+    io::{self, IoSlice},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tower_service::Service;
+
+use crate::{
+    BufferPool, CopyOptions, FileHandle, Metadata, Mode, ProgressReporter, Request, Response,
+    WrongVariant,
+};
+
+/// Async helpers for every `Service<Request>`, which build the [`Request`], wait for the service
+/// to be ready, and unpack the [`Response`]
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tower_fs::{FileSystem, FileSystemExt};
+///
+/// let mut fs = FileSystem::new();
+/// fs.write("greeting.txt", b"hello".to_vec()).await?;
+/// assert!(fs.exists("greeting.txt").await?);
+/// assert_eq!(fs.read("greeting.txt").await?, b"hello");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A response of the wrong variant becomes the service's error, through it's
+/// `From<`[`WrongVariant`]`>` implementation.
+pub trait FileSystemExt: Service<Request, Response = Response>
+where
+    Self::Error: From<WrongVariant>,
+{
+    /// Reads the entire contents of the file at `path`, with a [`Request::ReadBytes`]
+    fn read<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> impl Future<Output = Result<Vec<u8>, Self::Error>> {
+        let req = Request::ReadBytes(Arc::from(path.as_ref()));
+        async move { Ok(ready_call(self, req).await?.into_bytes()?) }
+    }
+
+    /// Replaces the contents of the file at `path` with `bytes`, creating it if it doesn't exist,
+    /// with a [`Request::WriteBytes`]
+    fn write<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        bytes: Vec<u8>,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        let req = Request::WriteBytes {
+            path: Arc::from(path.as_ref()),
+            bytes,
+        };
+        async move { Ok(ready_call(self, req).await?.into_done()?) }
+    }
+
+    /// Writes `bytes` to the end of the file at `path`, creating it if it doesn't exist, with a
+    /// [`Request::AppendBytes`]
+    fn append<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        bytes: Vec<u8>,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        let req = Request::AppendBytes {
+            path: Arc::from(path.as_ref()),
+            bytes,
+        };
+        async move { Ok(ready_call(self, req).await?.into_done()?) }
+    }
+
+    /// Opens the file at `path` with `mode`, with a [`Request::Open`]
+    fn open<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        mode: Mode,
+    ) -> impl Future<Output = Result<FileHandle, Self::Error>> {
+        let req = Request::Open {
+            mode,
+            path: Arc::from(path.as_ref()),
+        };
+        async move { Ok(ready_call(self, req).await?.into_file()?) }
+    }
+
+    /// Lists the entries of the directory at `path`, with their names and metadata, with a
+    /// [`Request::ReadDir`]
+    fn read_dir<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> impl Future<Output = Result<Vec<(PathBuf, Metadata)>, Self::Error>> {
+        let req = Request::ReadDir(Arc::from(path.as_ref()));
+        async move { Ok(ready_call(self, req).await?.into_directory()?) }
+    }
+
+    /// Gets the metadata of `path`, following symlinks, with a [`Request::GetMetadata`]
+    fn metadata<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> impl Future<Output = Result<Metadata, Self::Error>> {
+        let req = Request::GetMetadata {
+            path: Arc::from(path.as_ref()),
+            follow_symlinks: true,
+        };
+        async move { Ok(ready_call(self, req).await?.into_metadata()?) }
+    }
+
+    /// Gets the metadata of each of `paths`, following symlinks, with one
+    /// [`Request::MetadataBatch`], returning a result for each path in the same order
+    ///
+    /// Services which don't support batches are sent a [`Request::GetMetadata`] for each path
+    /// instead.
+    fn metadata_batch<I, P>(
+        &mut self,
+        paths: I,
+    ) -> impl Future<Output = io::Result<Vec<io::Result<Metadata>>>>
+    where
+        Self: Service<Request, Error = io::Error>,
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let paths: Vec<Arc<Path>> = paths
+            .into_iter()
+            .map(|path| Arc::from(path.as_ref()))
+            .collect();
+        async move {
+            let req = Request::MetadataBatch {
+                paths: paths.clone(),
+                follow_symlinks: true,
+            };
+            match ready_call(self, req).await {
+                Err(err) if err.kind() == io::ErrorKind::Unsupported => {}
+                response => return Ok(response?.into_metadata_batch()?),
+            }
+            let mut results = Vec::with_capacity(paths.len());
+            for path in paths {
+                let req = Request::GetMetadata {
+                    path,
+                    follow_symlinks: true,
+                };
+                results.push(
+                    ready_call(self, req)
+                        .await
+                        .and_then(|response| Ok(response.into_metadata()?)),
+                );
+            }
+            Ok(results)
+        }
+    }
+
+    /// Checks whether `path` exists, with a [`Request::Exists`]
+    fn exists<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> impl Future<Output = Result<bool, Self::Error>> {
+        let req = Request::Exists(Arc::from(path.as_ref()));
+        async move { Ok(ready_call(self, req).await?.into_exists()?) }
+    }
+
+    /// Removes the file at `path`, with a [`Request::RemoveFile`]
+    fn remove_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        let req = Request::RemoveFile(Arc::from(path.as_ref()));
+        async move { Ok(ready_call(self, req).await?.into_done()?) }
+    }
+
+    /// Creates the directory at `path` and any missing parents, with a [`Request::CreateDir`]
+    fn create_dir_all<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        let req = Request::CreateDir {
+            path: Arc::from(path.as_ref()),
+            recursive: true,
+        };
+        async move { Ok(ready_call(self, req).await?.into_done()?) }
+    }
+
+    /// Copies the file at `from` to `to`, returning the number of bytes copied, with a
+    /// [`Request::Copy`]
+    fn copy<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> impl Future<Output = Result<u64, Self::Error>> {
+        self.copy_with(from, to, CopyOptions::default())
+    }
+
+    /// Copies the file at `from` to `to` with `options`, returning the number of bytes copied,
+    /// with a [`Request::Copy`]
+    fn copy_with<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+        options: CopyOptions,
+    ) -> impl Future<Output = Result<u64, Self::Error>> {
+        let req = Request::Copy {
+            from: Arc::from(from.as_ref()),
+            to: Arc::from(to.as_ref()),
+            options,
+        };
+        async move { Ok(ready_call(self, req).await?.into_copied()?) }
+    }
+
+    /// Copies the directory at `from` and everything in it to `to`, returning the number of bytes
+    /// copied, with a [`Request::CopyDir`]
+    fn copy_dir<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> impl Future<Output = Result<u64, Self::Error>> {
+        self.copy_dir_with(from, to, CopyOptions::default())
+    }
+
+    /// Copies the directory at `from` and everything in it to `to` with `options`, returning the
+    /// number of bytes copied, with a [`Request::CopyDir`]
+    fn copy_dir_with<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+        options: CopyOptions,
+    ) -> impl Future<Output = Result<u64, Self::Error>> {
+        let req = Request::CopyDir {
+            from: Arc::from(from.as_ref()),
+            to: Arc::from(to.as_ref()),
+            options,
+        };
+        async move { Ok(ready_call(self, req).await?.into_copied()?) }
+    }
+
+    /// Copies the file at `from` to `to` through files opened with [`Request::Open`], reporting
+    /// the bytes copied to `progress` as they're written, and returning the number of bytes copied
+    ///
+    /// The backend can't copy the file itself, as it can for [`copy`](Self::copy), so this is
+    /// slower, but shows how far a large copy has got.  The total is taken from the metadata of
+    /// `from`, and left unknown if the backend doesn't provide it.
+    fn copy_with_progress<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+        progress: &ProgressReporter,
+    ) -> impl Future<Output = Result<u64, Self::Error>>
+    where
+        Self::Error: From<io::Error>,
+    {
+        let (from, to): (Arc<Path>, Arc<Path>) = (Arc::from(from.as_ref()), Arc::from(to.as_ref()));
+        let progress = progress.clone();
+        async move {
+            if let Ok(metadata) = self.metadata(&from).await {
+                progress.add_total_bytes(metadata.len());
+            }
+            let mut reader = self.open(&from, Mode::Read).await?;
+            let mut writer = self.open(&to, Mode::CreateOrOverwrite).await?;
+            let copied =
+                copy_vectored(&mut reader, &mut writer, |len| progress.add_bytes(len)).await?;
+            writer.flush().await?;
+            progress.add_entry();
+            Ok(copied)
+        }
+    }
+
+    /// Moves `from` to `to`, with a [`Request::Rename`]
+    fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        let req = Request::Rename {
+            from: Arc::from(from.as_ref()),
+            to: Arc::from(to.as_ref()),
+        };
+        async move { Ok(ready_call(self, req).await?.into_done()?) }
+    }
+}
+
+impl<S> FileSystemExt for S
+where
+    S: Service<Request, Response = Response> + ?Sized,
+    S::Error: From<WrongVariant>,
+{
+}
+
+pub(crate) async fn ready_call<S>(service: &mut S, req: Request) -> Result<Response, S::Error>
+where
+    S: Service<Request, Response = Response> + ?Sized,
+{
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    service.call(req).await
+}
+
+/// Copies `reader` into `writer`, filling a pair of pooled buffers before writing both with one
+/// vectored write, and passing the length of each pair to `copied`
+pub(crate) async fn copy_vectored<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mut copied: impl FnMut(u64),
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let pool = BufferPool::shared();
+    let mut buffers = [pool.get(), pool.get()];
+    let mut total = 0;
+    loop {
+        let mut filled = [0; 2];
+        let mut eof = false;
+        for (buffer, filled) in buffers.iter_mut().zip(&mut filled) {
+            while !eof && *filled < buffer.len() {
+                match reader.read(&mut buffer[*filled..]).await? {
+                    0 => eof = true,
+                    read => *filled += read,
+                }
+            }
+        }
+        let len = filled[0] + filled[1];
+        write_all_vectored(writer, [&buffers[0][..filled[0]], &buffers[1][..filled[1]]]).await?;
+        total += len as u64;
+        if len > 0 {
+            copied(len as u64);
+        }
+        if eof {
+            return Ok(total);
+        }
+    }
+}
+
+async fn write_all_vectored<W>(writer: &mut W, mut parts: [&[u8]; 2]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    while parts.iter().any(|part| !part.is_empty()) {
+        let mut written = writer.write_vectored(&parts.map(IoSlice::new)).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        for part in &mut parts {
+            let advanced = written.min(part.len());
+            *part = &part[advanced..];
+            written -= advanced;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    #[tokio::test]
+    async fn test_copy_vectored() -> io::Result<()> {
+        // Long enough to take a few pairs of buffers, and end part way through one
+        let contents = (0..=u8::MAX).cycle().take(300_000).collect::<Vec<_>>();
+        let (mut reader, mut writer) = (contents.as_slice(), Vec::new());
+        let mut chunks = Vec::new();
+        let copied = copy_vectored(&mut reader, &mut writer, |len| chunks.push(len)).await?;
+        assert_eq!(copied, 300_000);
+        assert_eq!(writer, contents);
+        let pair = 2 * BufferPool::shared().buffer_size() as u64;
+        assert_eq!(chunks, [pair, pair, 300_000 - 2 * pair]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_system_ext() -> io::Result<()> {
+        let dir = TestDir::new("ext")?;
+        let mut fs = FileSystem::new();
+        fs.create_dir_all(dir.join("nested")).await?;
+        let path = dir.join("nested/hello.txt");
+        fs.write(&path, b"hello".to_vec()).await?;
+        assert!(fs.exists(&path).await?);
+        assert_eq!(fs.metadata(&path).await?.len(), 5);
+        let batch = fs.metadata_batch([&path, &dir.join("missing")]).await?;
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].as_ref().map(Metadata::len).ok(), Some(5));
+        assert!(batch[1]
+            .as_ref()
+            .is_err_and(|err| err.kind() == io::ErrorKind::NotFound));
+        assert_eq!(fs.copy(&path, dir.join("copy.txt")).await?, 5);
+        let kept = CopyOptions {
+            timestamps: true,
+            ..CopyOptions::default()
+        };
+        assert_eq!(fs.copy_with(&path, dir.join("kept.txt"), kept).await?, 5);
+        assert_eq!(
+            std::fs::metadata(dir.join("kept.txt"))?.modified()?,
+            std::fs::metadata(&path)?.modified()?
+        );
+        fs.rename(dir.join("copy.txt"), dir.join("moved.txt"))
+            .await?;
+        assert_eq!(fs.read(dir.join("moved.txt")).await?, b"hello");
+        fs.append(dir.join("moved.txt"), b" again".to_vec()).await?;
+        fs.append(dir.join("appended.txt"), b"new".to_vec()).await?;
+        assert_eq!(fs.read(dir.join("moved.txt")).await?, b"hello again");
+        assert_eq!(fs.read(dir.join("appended.txt")).await?, b"new");
+        assert_eq!(
+            fs.copy_dir(dir.join("nested"), dir.join("copied")).await?,
+            5
+        );
+        assert_eq!(fs.read(dir.join("copied/hello.txt")).await?, b"hello");
+
+        let mut contents = String::new();
+        let mut file = fs.open(&path, Mode::Read).await?;
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "hello");
+
+        fs.remove_file(&path).await?;
+        assert!(!fs.exists(&path).await?);
+        Ok(())
+    }
+}
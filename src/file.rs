@@ -0,0 +1,280 @@
+//! Open files returned by backends, whether local or not
+
+use std::{
+    fmt,
+    io::{self, ErrorKind, IoSlice, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncSeek, AsyncWrite, AsyncWriteExt, ReadBuf},
+};
+
+use crate::Metadata;
+
+/// A file opened with [`Request::Open`](crate::Request::Open), which can be read, written and
+/// seeked whatever backend it came from
+///
+/// Files from the local file system are held unboxed, and can be taken back out with
+/// [`FileHandle::try_into_tokio_file`].  Other backends, such as archives, object stores or remote
+/// servers, can return any streamable type with [`FileHandle::new`], or [`FileHandle::read_only`]
+/// if it can't be written.
+pub struct FileHandle {
+    inner: Inner,
+    /// Values dropped with the file, such as the permit counting it toward the limit of the
+    /// [`FileSystem`](crate::FileSystem) it's from
+    held: Option<Box<dyn Send + Sync>>,
+}
+
+enum Inner {
+    Local(fs::File),
+    Boxed(Box<dyn Io>),
+}
+
+/// The operations a [`FileHandle`] forwards
+trait Io: AsyncRead + AsyncWrite + AsyncSeek + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + AsyncSeek + Send + Sync + Unpin> Io for T {}
+
+impl FileHandle {
+    /// Wraps a file of any backend
+    pub fn new<T>(io: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + AsyncSeek + Send + Sync + Unpin + 'static,
+    {
+        Self {
+            inner: Inner::Boxed(Box::new(io)),
+            held: None,
+        }
+    }
+
+    /// Wraps a file which can't be written, which fails writes with
+    /// [`ErrorKind::PermissionDenied`]
+    pub fn read_only<T>(io: T) -> Self
+    where
+        T: AsyncRead + AsyncSeek + Send + Sync + Unpin + 'static,
+    {
+        Self::new(ReadOnly(io))
+    }
+
+    /// Holds `value` until the handle is dropped
+    pub(crate) fn hold(mut self, value: impl Send + Sync + 'static) -> Self {
+        self.held = Some(Box::new((self.held.take(), value)));
+        self
+    }
+
+    /// The local file this handle wraps, or the handle itself if it's from another backend
+    ///
+    /// # Errors
+    ///
+    /// If the handle isn't a local file
+    pub fn try_into_tokio_file(self) -> Result<fs::File, Self> {
+        match self.inner {
+            Inner::Local(file) => Ok(file),
+            inner @ Inner::Boxed(_) => Err(Self {
+                inner,
+                held: self.held,
+            }),
+        }
+    }
+
+    /// A second handle to the local file this handle wraps, sharing it's position, or `None` for
+    /// other backends
+    #[cfg(all(feature = "middleware", unix))]
+    pub(crate) async fn try_clone_local(&self) -> io::Result<Option<std::fs::File>> {
+        match &self.inner {
+            Inner::Local(file) => Ok(Some(file.try_clone().await?.into_std().await)),
+            Inner::Boxed(_) => Ok(None),
+        }
+    }
+
+    /// Flushes the file, and for local files waits for the data and metadata to reach the disk
+    ///
+    /// # Errors
+    ///
+    /// If flushing or syncing fails
+    pub async fn sync_all(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        match &self.inner {
+            Inner::Local(file) => file.sync_all().await,
+            Inner::Boxed(_) => Ok(()),
+        }
+    }
+
+    /// The metadata of a local file
+    ///
+    /// # Errors
+    ///
+    /// - [`ErrorKind::Unsupported`] if the handle isn't a local file
+    /// - If the metadata can't be read
+    pub async fn metadata(&self) -> io::Result<Metadata> {
+        match &self.inner {
+            Inner::Local(file) => file.metadata().await.map(Metadata::from),
+            Inner::Boxed(_) => Err(ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+impl From<fs::File> for FileHandle {
+    fn from(file: fs::File) -> Self {
+        Self {
+            inner: Inner::Local(file),
+            held: None,
+        }
+    }
+}
+
+impl fmt::Debug for FileHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.inner {
+            Inner::Local(file) => f.debug_tuple("FileHandle").field(file).finish(),
+            Inner::Boxed(_) => f.debug_tuple("FileHandle").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl AsyncRead for FileHandle {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).poll_read(cx, buf),
+            Inner::Boxed(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for FileHandle {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).poll_write(cx, buf),
+            Inner::Boxed(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).poll_write_vectored(cx, bufs),
+            Inner::Boxed(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.inner {
+            Inner::Local(file) => file.is_write_vectored(),
+            Inner::Boxed(io) => io.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).poll_flush(cx),
+            Inner::Boxed(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).poll_shutdown(cx),
+            Inner::Boxed(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncSeek for FileHandle {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).start_seek(position),
+            Inner::Boxed(io) => Pin::new(io).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match &mut self.get_mut().inner {
+            Inner::Local(file) => Pin::new(file).poll_complete(cx),
+            Inner::Boxed(io) => Pin::new(io).poll_complete(cx),
+        }
+    }
+}
+
+/// Adapts a reader into an [`Io`] which refuses writes
+struct ReadOnly<T>(T);
+
+impl<T: AsyncRead + Unpin> AsyncRead for ReadOnly<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl<T: Unpin> AsyncWrite for ReadOnly<T> {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(ErrorKind::PermissionDenied.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncSeek + Unpin> AsyncSeek for ReadOnly<T> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.get_mut().0).start_seek(position)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.get_mut().0).poll_complete(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_handle() -> io::Result<()> {
+        let mut handle = FileHandle::new(Cursor::new(Vec::new()));
+        handle.write_all(b"hello world").await?;
+        handle.seek(SeekFrom::Start(6)).await?;
+        let mut contents = String::new();
+        handle.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "world");
+        handle.sync_all().await?;
+        assert!(handle
+            .metadata()
+            .await
+            .is_err_and(|err| err.kind() == ErrorKind::Unsupported));
+        assert!(handle.try_into_tokio_file().is_err());
+
+        let mut read_only = FileHandle::read_only(Cursor::new(b"archived".to_vec()));
+        let written = read_only.write_all(b"changed").await;
+        assert!(written.is_err_and(|err| err.kind() == ErrorKind::PermissionDenied));
+        let mut contents = String::new();
+        read_only.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "archived");
+        Ok(())
+    }
+}
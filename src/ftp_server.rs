@@ -0,0 +1,894 @@
+//! Serving a service stack over FTP
+//!
+//! [`serve`] answers the commands of one FTP control connection by translating them into requests
+//! against a service, for exchanging files with systems which only speak FTP.  Opening sockets is
+//! left to the application: it accepts control connections, and provides a listener which opens a
+//! port for each passive mode data connection.  Since there's no TLS, run it on a trusted network
+//! or behind a proxy which terminates FTPS.  [`serve_sessions`] builds a service for each login
+//! instead, so users can be given their own root or quota.
+
+use std::{
+    future::{poll_fn, Future},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tower_service::Service;
+
+use crate::{date::DateTime, FileHandle, Metadata, Mode, Request, Response};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Longer command lines are cut short
+const MAX_LINE_LEN: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The user name and password clients must log in with, or `None` to accept any login
+    pub login: Option<(String, String)>,
+}
+
+/// Answers the FTP commands read from `control` with `service` until the client quits or closes
+/// the connection
+///
+/// Data connections are only made in passive mode (`PASV` or `EPSV`): `listener` is called for
+/// each, and returns the address for the client to connect to along with a future which accepts
+/// that connection.  `PORT` and `EPRT` are refused.
+///
+/// Paths are resolved against the client's working directory, starting at `/`, and passed to
+/// `service` as absolute paths, so serve a directory by wrapping the service in
+/// [`Root`](crate::middleware::root::Root).  Files are transferred through [`Request::Open`] where
+/// the service supports it, and otherwise with [`Request::ReadBytes`] and [`Request::WriteBytes`].
+/// Transfers are always binary, whatever `TYPE` the client asks for.
+///
+/// Besides logging in and transfers (`RETR`, `STOR` and `APPE`), `DELE`, `MKD`, `RMD`, `RNFR`/`RNTO`,
+/// `SIZE` and `MDTM` are supported, as are `LIST` and `NLST`, which list directories with
+/// [`Request::ReadDir`].
+///
+/// # Errors
+///
+/// If reading from or writing to `control` fails.  Errors from `service`, `listener` and data
+/// connections are sent to the client instead.
+pub async fn serve<T, S, L, A, D>(
+    control: T,
+    service: S,
+    listener: L,
+    config: Config,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Service<Request, Response = Response, Error = io::Error>,
+    L: Service<(), Response = (SocketAddr, A)>,
+    L::Error: Into<BoxError>,
+    A: Future<Output = io::Result<D>>,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    run(control, Single(service), listener, config).await
+}
+
+/// The login a service is built for by [`serve_sessions`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    pub user: String,
+}
+
+/// Answers FTP commands as [`serve`] does, but with a service built by `make` for each login, such
+/// as a [`MakeFileSystem`](crate::MakeFileSystem) giving each user their own root
+///
+/// The service is built once the client's password is accepted, and replaced if the client logs in
+/// again.  If `make` fails, the login is refused.
+///
+/// # Errors
+///
+/// If reading from or writing to `control` fails.  Errors from `make`, `listener`, the services it
+/// builds and data connections are sent to the client instead.
+pub async fn serve_sessions<T, M, L, A, D>(
+    control: T,
+    make: M,
+    listener: L,
+    config: Config,
+) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    M: Service<Login>,
+    M::Response: Service<Request, Response = Response, Error = io::Error>,
+    M::Error: Into<BoxError>,
+    L: Service<(), Response = (SocketAddr, A)>,
+    L::Error: Into<BoxError>,
+    A: Future<Output = io::Result<D>>,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let logins = PerLogin {
+        make,
+        service: None,
+    };
+    run(control, logins, listener, config).await
+}
+
+async fn run<T, X, L, A, D>(control: T, logins: X, listener: L, config: Config) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    X: Logins,
+    L: Service<(), Response = (SocketAddr, A)>,
+    L::Error: Into<BoxError>,
+    A: Future<Output = io::Result<D>>,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut session = Session {
+        control: BufReader::new(control),
+        logins,
+        listener,
+        config,
+        user: None,
+        logged_in: false,
+        cwd: String::from("/"),
+        rename_from: None,
+        passive: None,
+    };
+    session.reply(220, "Service ready").await?;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = (&mut session.control)
+            .take(MAX_LINE_LEN)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if read == 0 {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        if !session
+            .command(&command.to_ascii_uppercase(), argument)
+            .await?
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Provides the service used after each login
+trait Logins {
+    type Service: Service<Request, Response = Response, Error = io::Error>;
+
+    /// Prepares the service for `login`
+    async fn login(&mut self, login: Login) -> io::Result<()>;
+
+    /// The service of the current login, if there is one
+    fn service(&mut self) -> Option<&mut Self::Service>;
+}
+
+/// Uses the same service for every login
+struct Single<S>(S);
+
+impl<S> Logins for Single<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    type Service = S;
+
+    async fn login(&mut self, _: Login) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn service(&mut self) -> Option<&mut S> {
+        Some(&mut self.0)
+    }
+}
+
+/// Builds a service for each login
+struct PerLogin<M, S> {
+    make: M,
+    service: Option<S>,
+}
+
+impl<M> Logins for PerLogin<M, M::Response>
+where
+    M: Service<Login>,
+    M::Response: Service<Request, Response = Response, Error = io::Error>,
+    M::Error: Into<BoxError>,
+{
+    type Service = M::Response;
+
+    async fn login(&mut self, login: Login) -> io::Result<()> {
+        self.service = None;
+        poll_fn(|cx| self.make.poll_ready(cx))
+            .await
+            .map_err(|err| io::Error::other(err.into()))?;
+        let service = self
+            .make
+            .call(login)
+            .await
+            .map_err(|err| io::Error::other(err.into()))?;
+        self.service = Some(service);
+        Ok(())
+    }
+
+    fn service(&mut self) -> Option<&mut M::Response> {
+        self.service.as_mut()
+    }
+}
+
+struct Session<T, X, L, A> {
+    control: BufReader<T>,
+    logins: X,
+    listener: L,
+    config: Config,
+    user: Option<String>,
+    logged_in: bool,
+    cwd: String,
+    /// Set by `RNFR`, for the `RNTO` which must follow it
+    rename_from: Option<PathBuf>,
+    /// Accepts the data connection of the last `PASV`
+    passive: Option<Pin<Box<A>>>,
+}
+
+/// A file to send in response to `RETR`
+enum Source {
+    File(FileHandle),
+    Bytes(Vec<u8>),
+}
+
+/// Where to write a file received with `STOR` or `APPE`
+enum Sink {
+    File(FileHandle),
+    /// Written with [`Request::WriteBytes`] once the transfer completes
+    Buffer(PathBuf, Vec<u8>),
+}
+
+impl<T, X, L, A, D> Session<T, X, L, A>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    X: Logins,
+    L: Service<(), Response = (SocketAddr, A)>,
+    L::Error: Into<BoxError>,
+    A: Future<Output = io::Result<D>>,
+    D: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Handles a command, returning whether to keep reading commands
+    async fn command(&mut self, command: &str, argument: &str) -> io::Result<bool> {
+        let rename_from = self.rename_from.take();
+        match (command, self.logged_in) {
+            ("USER", _) => {
+                self.user = Some(argument.to_owned());
+                self.logged_in = false;
+                self.reply(331, "Password required").await?;
+            }
+            ("PASS", _) => {
+                let Some(user) = &self.user else {
+                    self.reply(503, "Login with USER first").await?;
+                    return Ok(true);
+                };
+                let accepted = match &self.config.login {
+                    Some((expected_user, password)) => {
+                        user == expected_user && argument == password
+                    }
+                    None => true,
+                };
+                if !accepted {
+                    self.logged_in = false;
+                    self.reply(530, "Login incorrect").await?;
+                    return Ok(true);
+                }
+                let login = Login { user: user.clone() };
+                match self.logins.login(login).await {
+                    Ok(()) => {
+                        self.logged_in = true;
+                        self.reply(230, "Logged in").await?;
+                    }
+                    Err(err) => {
+                        self.logged_in = false;
+                        self.reply(530, &format!("Login failed: {err}")).await?;
+                    }
+                }
+            }
+            ("QUIT", _) => {
+                self.reply(221, "Goodbye").await?;
+                return Ok(false);
+            }
+            ("NOOP", _) => self.reply(200, "OK").await?,
+            ("SYST", _) => self.reply(215, "UNIX Type: L8").await?,
+            ("FEAT", _) => {
+                self.send("211-Features:\r\n EPSV\r\n MDTM\r\n SIZE\r\n UTF8\r\n211 End\r\n")
+                    .await?;
+            }
+            (_, false) => self.reply(530, "Login with USER and PASS").await?,
+            ("OPTS", true) if argument.eq_ignore_ascii_case("UTF8 ON") => {
+                self.reply(200, "Always in UTF8 mode").await?;
+            }
+            ("TYPE", true) => match argument.to_ascii_uppercase().as_str() {
+                "A" | "A N" | "I" | "L 8" => self.reply(200, "Type set").await?,
+                _ => self.reply(504, "Type not supported").await?,
+            },
+            ("MODE", true) if argument.eq_ignore_ascii_case("S") => {
+                self.reply(200, "Mode set").await?;
+            }
+            ("STRU", true) if argument.eq_ignore_ascii_case("F") => {
+                self.reply(200, "Structure set").await?;
+            }
+            ("MODE" | "STRU", true) => {
+                self.reply(504, "Only streams of files are supported")
+                    .await?;
+            }
+            ("PWD" | "XPWD", true) => {
+                let message = format!("{} is the current directory", quote(&self.cwd));
+                self.reply(257, &message).await?;
+            }
+            ("CWD" | "XCWD", true) => self.change_dir(argument).await?,
+            ("CDUP" | "XCUP", true) => self.change_dir("..").await?,
+            ("PASV", true) => self.passive(false).await?,
+            ("EPSV", true) => self.passive(true).await?,
+            ("PORT" | "EPRT", true) => self.reply(502, "Use passive mode").await?,
+            ("RETR", true) => self.retrieve(argument).await?,
+            ("STOR", true) => self.store(argument, Mode::CreateOrOverwrite).await?,
+            ("APPE", true) => self.store(argument, Mode::CreateOrAppend).await?,
+            ("LIST" | "NLST", true) => self.list(argument, command == "NLST").await?,
+            _ => self.manage(command, argument, rename_from).await?,
+        }
+        Ok(true)
+    }
+
+    /// Handles the commands which manage files, besides transfers
+    async fn manage(
+        &mut self,
+        command: &str,
+        argument: &str,
+        rename_from: Option<PathBuf>,
+    ) -> io::Result<()> {
+        match command {
+            "SIZE" | "MDTM" => {
+                let req = Request::GetMetadata {
+                    path: self.resolve(argument).into(),
+                    follow_symlinks: true,
+                };
+                match self.call(req).await {
+                    Ok(Response::Metadata(metadata)) if command == "SIZE" => {
+                        self.reply(213, &metadata.len().to_string()).await?;
+                    }
+                    Ok(Response::Metadata(metadata)) => match metadata.modified() {
+                        Ok(modified) => {
+                            let date = DateTime::from(modified);
+                            let message = format!(
+                                "{:04}{:02}{:02}{:02}{:02}{:02}",
+                                date.year,
+                                date.month,
+                                date.day,
+                                date.hour,
+                                date.minute,
+                                date.second
+                            );
+                            self.reply(213, &message).await?;
+                        }
+                        Err(err) => self.error(&err).await?,
+                    },
+                    Ok(_) => self.error(&unexpected_response()).await?,
+                    Err(err) => self.error(&err).await?,
+                }
+            }
+            "DELE" => {
+                let req = Request::RemoveFile(self.resolve(argument).into());
+                self.simple(req, 250, "File removed").await?;
+            }
+            "MKD" | "XMKD" => {
+                let path = self.resolve(argument);
+                let message = format!("{} created", quote(&path.to_string_lossy()));
+                let req = Request::CreateDir {
+                    path: path.into(),
+                    recursive: false,
+                };
+                self.simple(req, 257, &message).await?;
+            }
+            "RMD" | "XRMD" => {
+                let req = Request::RemoveDir {
+                    path: self.resolve(argument).into(),
+                    recursive: false,
+                };
+                self.simple(req, 250, "Directory removed").await?;
+            }
+            "RNFR" => {
+                let path = self.resolve(argument);
+                match self.call(Request::Exists(path.as_path().into())).await {
+                    Ok(Response::Exists(true)) => {
+                        self.rename_from = Some(path);
+                        self.reply(350, "Ready for RNTO").await?;
+                    }
+                    Ok(Response::Exists(false)) => self.reply(550, "File not found").await?,
+                    Ok(_) => self.error(&unexpected_response()).await?,
+                    Err(err) => self.error(&err).await?,
+                }
+            }
+            "RNTO" => match rename_from {
+                Some(from) => {
+                    let req = Request::Rename {
+                        from: from.into(),
+                        to: self.resolve(argument).into(),
+                    };
+                    self.simple(req, 250, "File renamed").await?;
+                }
+                None => self.reply(503, "RNFR required first").await?,
+            },
+            _ => self.reply(502, "Command not implemented").await?,
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, text: &str) -> io::Result<()> {
+        let control = self.control.get_mut();
+        control.write_all(text.as_bytes()).await?;
+        control.flush().await
+    }
+
+    async fn reply(&mut self, code: u16, message: &str) -> io::Result<()> {
+        // Line breaks would be read as the end of the reply
+        let message = message.replace(['\r', '\n'], " ");
+        self.send(&format!("{code} {message}\r\n")).await
+    }
+
+    /// Replies with the error from a request
+    async fn error(&mut self, err: &io::Error) -> io::Result<()> {
+        let code = match err.kind() {
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => 452,
+            ErrorKind::InvalidInput | ErrorKind::InvalidFilename => 553,
+            ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyExists
+            | ErrorKind::NotADirectory
+            | ErrorKind::IsADirectory
+            | ErrorKind::DirectoryNotEmpty
+            | ErrorKind::Unsupported => 550,
+            _ => 451,
+        };
+        self.reply(code, &err.to_string()).await
+    }
+
+    async fn call(&mut self, req: Request) -> io::Result<Response> {
+        let Some(service) = self.logins.service() else {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "not logged in"));
+        };
+        poll_fn(|cx| service.poll_ready(cx)).await?;
+        service.call(req).await
+    }
+
+    /// Sends a request, replying with `code` and `message` if it succeeds
+    async fn simple(&mut self, req: Request, code: u16, message: &str) -> io::Result<()> {
+        match self.call(req).await {
+            Ok(_) => self.reply(code, message).await,
+            Err(err) => self.error(&err).await,
+        }
+    }
+
+    /// Resolves `path` against the working directory
+    fn resolve(&self, path: &str) -> PathBuf {
+        let mut components: Vec<&str> = Vec::new();
+        let relative_to = if path.starts_with('/') { "" } else { &self.cwd };
+        for component in relative_to.split('/').chain(path.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    components.pop();
+                }
+                component => components.push(component),
+            }
+        }
+        PathBuf::from(format!("/{}", components.join("/")))
+    }
+
+    async fn change_dir(&mut self, path: &str) -> io::Result<()> {
+        let path = self.resolve(path);
+        let req = Request::GetMetadata {
+            path: path.as_path().into(),
+            follow_symlinks: true,
+        };
+        let is_dir = match self.call(req).await {
+            Ok(Response::Metadata(metadata)) => Ok(metadata.is_dir()),
+            Ok(_) => Err(unexpected_response()),
+            // Directories are usually implicit on backends without metadata
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                match self.call(Request::Exists(path.as_path().into())).await {
+                    Ok(Response::Exists(exists)) => Ok(exists || path.parent().is_none()),
+                    Ok(_) => Err(unexpected_response()),
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        match is_dir {
+            Ok(true) => {
+                self.cwd = path.to_string_lossy().into_owned();
+                self.reply(250, "Directory changed").await
+            }
+            Ok(false) => self.reply(550, "Not a directory").await,
+            Err(err) => self.error(&err).await,
+        }
+    }
+
+    async fn passive(&mut self, extended: bool) -> io::Result<()> {
+        let listening = match poll_fn(|cx| self.listener.poll_ready(cx)).await {
+            Ok(()) => self.listener.call(()).await,
+            Err(err) => Err(err),
+        };
+        let (addr, accept) = match listening {
+            Ok(listening) => listening,
+            Err(err) => {
+                let err: BoxError = err.into();
+                return self
+                    .reply(425, &format!("Can't open data connection: {err}"))
+                    .await;
+            }
+        };
+        self.passive = Some(Box::pin(accept));
+        match addr {
+            _ if extended => {
+                let message = format!("Entering Extended Passive Mode (|||{}|)", addr.port());
+                self.reply(229, &message).await
+            }
+            SocketAddr::V4(addr) => {
+                let [a, b, c, d] = addr.ip().octets();
+                let [high, low] = addr.port().to_be_bytes();
+                let message = format!("Entering Passive Mode ({a},{b},{c},{d},{high},{low})");
+                self.reply(227, &message).await
+            }
+            SocketAddr::V6(_) => {
+                self.passive = None;
+                self.reply(425, "Use EPSV for IPv6").await
+            }
+        }
+    }
+
+    /// Accepts the data connection for a transfer, after telling the client to connect
+    async fn accept_data(&mut self) -> io::Result<Option<D>> {
+        let Some(accept) = self.passive.take() else {
+            self.reply(425, "Use PASV first").await?;
+            return Ok(None);
+        };
+        self.reply(150, "Opening data connection").await?;
+        match accept.await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) => {
+                self.reply(425, &format!("Can't open data connection: {err}"))
+                    .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Replies with the outcome of a transfer
+    async fn transferred(&mut self, result: io::Result<()>) -> io::Result<()> {
+        match result {
+            Ok(()) => self.reply(226, "Transfer complete").await,
+            Err(err) => self.reply(426, &format!("Transfer aborted: {err}")).await,
+        }
+    }
+
+    async fn retrieve(&mut self, path: &str) -> io::Result<()> {
+        let path = self.resolve(path);
+        let open = Request::Open {
+            mode: Mode::Read,
+            path: path.as_path().into(),
+        };
+        let source = match self.call(open).await {
+            Ok(Response::File(file)) => Ok(Source::File(file)),
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                match self.call(Request::ReadBytes(path.into())).await {
+                    Ok(Response::Bytes(bytes)) => Ok(Source::Bytes(bytes)),
+                    Ok(_) => Err(unexpected_response()),
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => return self.error(&err).await,
+        };
+        let Some(mut data) = self.accept_data().await? else {
+            return Ok(());
+        };
+        let result = async {
+            match source {
+                Source::File(mut file) => tokio::io::copy(&mut file, &mut data).await.map(drop)?,
+                Source::Bytes(bytes) => data.write_all(&bytes).await?,
+            }
+            data.shutdown().await
+        }
+        .await;
+        self.transferred(result).await
+    }
+
+    async fn store(&mut self, path: &str, mode: Mode) -> io::Result<()> {
+        let path = self.resolve(path);
+        let open = Request::Open {
+            mode,
+            path: path.as_path().into(),
+        };
+        let sink = match self.call(open).await {
+            Ok(Response::File(file)) => Sink::File(file),
+            Ok(_) => return self.error(&unexpected_response()).await,
+            Err(err) if err.kind() == ErrorKind::Unsupported && mode == Mode::CreateOrOverwrite => {
+                Sink::Buffer(path, Vec::new())
+            }
+            Err(err) => return self.error(&err).await,
+        };
+        let Some(mut data) = self.accept_data().await? else {
+            return Ok(());
+        };
+        let result = match sink {
+            Sink::File(mut file) => {
+                async {
+                    tokio::io::copy(&mut data, &mut file).await?;
+                    file.flush().await
+                }
+                .await
+            }
+            Sink::Buffer(path, mut bytes) => match data.read_to_end(&mut bytes).await {
+                Ok(_) => self
+                    .call(Request::WriteBytes {
+                        path: path.into(),
+                        bytes,
+                    })
+                    .await
+                    .map(drop),
+                Err(err) => Err(err),
+            },
+        };
+        self.transferred(result).await
+    }
+
+    async fn list(&mut self, argument: &str, names_only: bool) -> io::Result<()> {
+        // Options like `-la` are commonly sent, but there's nothing to configure
+        let path = argument
+            .split(' ')
+            .find(|part| !part.is_empty() && !part.starts_with('-'))
+            .unwrap_or(".");
+        let path = self.resolve(path);
+        let req = Request::GetMetadata {
+            path: path.as_path().into(),
+            follow_symlinks: true,
+        };
+        let entries = match self.call(req).await {
+            Ok(Response::Metadata(metadata)) if metadata.is_dir() => {
+                match self.call(Request::ReadDir(path.as_path().into())).await {
+                    Ok(Response::Directory(entries)) => entries,
+                    Ok(_) => return self.error(&unexpected_response()).await,
+                    Err(err) => return self.error(&err).await,
+                }
+            }
+            Ok(Response::Metadata(metadata)) => {
+                let name = path.file_name().map(PathBuf::from).unwrap_or_default();
+                vec![(name, metadata)]
+            }
+            Ok(_) => return self.error(&unexpected_response()).await,
+            Err(err) => return self.error(&err).await,
+        };
+        let listing: String = entries
+            .iter()
+            .map(|(name, metadata)| listing_line(&name.to_string_lossy(), metadata, names_only))
+            .collect();
+        let Some(mut data) = self.accept_data().await? else {
+            return Ok(());
+        };
+        let result = async {
+            data.write_all(listing.as_bytes()).await?;
+            data.shutdown().await
+        }
+        .await;
+        self.transferred(result).await
+    }
+}
+
+/// Quotes a path for a `257` reply, doubling any quotes inside it
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('"', "\"\""))
+}
+
+/// Formats a line of a `LIST` reply as `ls -l` does, or of an `NLST` reply with just the `name`
+fn listing_line(name: &str, metadata: &Metadata, names_only: bool) -> String {
+    if names_only {
+        return format!("{name}\r\n");
+    }
+    let modified = metadata
+        .modified()
+        .map(|modified| DateTime::from(modified).ls())
+        .unwrap_or_default();
+    format!(
+        "{} 1 owner group {:>12} {modified} {name}\r\n",
+        permissions(metadata),
+        metadata.len()
+    )
+}
+
+/// Formats the type and permissions of a file as `ls -l` does
+fn permissions(metadata: &Metadata) -> String {
+    let mode = metadata.mode();
+    let mut formatted = String::from(if metadata.is_dir() {
+        "d"
+    } else if metadata.is_symlink() {
+        "l"
+    } else {
+        "-"
+    });
+    for shift in [6, 3, 0] {
+        for (bit, flag) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            formatted.push(if mode >> shift & bit == 0 { '-' } else { flag });
+        }
+    }
+    formatted
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "unexpected response from inner service",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        task::Poll,
+    };
+
+    use futures::future::{ready, Ready};
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, MakeFileSystem};
+
+    /// Opens a data connection to the test, as though the client had connected to the port
+    #[derive(Clone, Default)]
+    struct Listener(Arc<Mutex<Option<DuplexStream>>>);
+
+    impl Service<()> for Listener {
+        type Response = (SocketAddr, Ready<io::Result<DuplexStream>>);
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            if let Ok(mut slot) = self.0.lock() {
+                *slot = Some(client);
+            }
+            let addr = SocketAddr::from(([127, 0, 0, 1], 2121));
+            ready(Ok((addr, ready(Ok(server)))))
+        }
+    }
+
+    struct Client {
+        reader: BufReader<ReadHalf<DuplexStream>>,
+        writer: WriteHalf<DuplexStream>,
+        listener: Listener,
+    }
+
+    impl Client {
+        async fn reply(&mut self) -> io::Result<String> {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).await?;
+            Ok(line.trim_end().to_owned())
+        }
+
+        async fn command(&mut self, command: &str) -> io::Result<String> {
+            self.writer
+                .write_all(format!("{command}\r\n").as_bytes())
+                .await?;
+            self.reply().await
+        }
+
+        /// Sends a transfer command, writing `upload` to the data connection and returning what
+        /// the server sent over it
+        async fn transfer(&mut self, command: &str, upload: &[u8]) -> io::Result<Vec<u8>> {
+            assert!(self.command("PASV").await?.starts_with("227 "));
+            assert!(self.command(command).await?.starts_with("150 "));
+            let data = self.listener.0.lock().ok().and_then(|mut slot| slot.take());
+            let Some(mut data) = data else {
+                unreachable!("PASV opens a data connection")
+            };
+            data.write_all(upload).await?;
+            data.shutdown().await?;
+            let mut downloaded = Vec::new();
+            data.read_to_end(&mut downloaded).await?;
+            assert!(self.reply().await?.starts_with("226 "));
+            Ok(downloaded)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session() -> io::Result<()> {
+        let dir = TestDir::new("ftp_server")?;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let listener = Listener::default();
+        let config = Config {
+            login: Some((String::from("partner"), String::from("secret"))),
+        };
+        tokio::spawn(serve(server, FileSystem::new(), listener.clone(), config));
+        let (reader, writer) = tokio::io::split(client);
+        let mut client = Client {
+            reader: BufReader::new(reader),
+            writer,
+            listener,
+        };
+
+        assert!(client.reply().await?.starts_with("220 "));
+        assert!(client.command("PWD").await?.starts_with("530 "));
+        client.command("USER partner").await?;
+        assert!(client.command("PASS secret").await?.starts_with("230 "));
+        let cwd = format!("CWD {}", dir.display());
+        assert!(client.command(&cwd).await?.starts_with("250 "));
+
+        client.transfer("STOR upload.txt", b"pushed file").await?;
+        assert_eq!(std::fs::read(dir.join("upload.txt"))?, b"pushed file");
+        assert_eq!(client.command("SIZE upload.txt").await?, "213 11");
+        assert!(client.command("RNFR upload.txt").await?.starts_with("350 "));
+        assert!(client
+            .command("RNTO renamed.txt")
+            .await?
+            .starts_with("250 "));
+        let downloaded = client.transfer("RETR renamed.txt", b"").await?;
+        assert_eq!(downloaded, b"pushed file");
+        let listed = client.transfer("NLST renamed.txt", b"").await?;
+        assert_eq!(listed, b"renamed.txt\r\n");
+        std::fs::create_dir(dir.join("nested"))?;
+        let listed = client.transfer("NLST", b"").await?;
+        assert_eq!(listed, b"nested\r\nrenamed.txt\r\n");
+        let listed = String::from_utf8(client.transfer("LIST -la", b"").await?).unwrap_or_default();
+        let lines: Vec<_> = listed.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("drwx") && lines[0].ends_with(" nested"));
+        assert!(lines[1].starts_with("-rw") && lines[1].ends_with(" renamed.txt"));
+        assert!(lines[1].contains(" 11 "));
+        assert!(client
+            .command("DELE renamed.txt")
+            .await?
+            .starts_with("250 "));
+        assert!(client
+            .command("RETR renamed.txt")
+            .await?
+            .starts_with("550 "));
+        assert!(client.command("QUIT").await?.starts_with("221 "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_sessions() -> io::Result<()> {
+        let dir = TestDir::new("ftp_login")?;
+        let path = dir.join("hello.txt");
+        std::fs::write(&path, "hello")?;
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let listener = Listener::default();
+        let make = MakeFileSystem::new(|login: Login| {
+            ready(if login.user == "guest" {
+                Err(io::Error::from(ErrorKind::PermissionDenied))
+            } else {
+                Ok(FileSystem::new())
+            })
+        });
+        let config = Config { login: None };
+        tokio::spawn(serve_sessions(server, make, listener.clone(), config));
+        let (reader, writer) = tokio::io::split(client);
+        let mut client = Client {
+            reader: BufReader::new(reader),
+            writer,
+            listener,
+        };
+
+        assert!(client.reply().await?.starts_with("220 "));
+        client.command("USER guest").await?;
+        assert!(client.command("PASS guest").await?.starts_with("530 "));
+        let size = format!("SIZE {}", path.display());
+        assert!(client.command(&size).await?.starts_with("530 "));
+        client.command("USER partner").await?;
+        assert!(client.command("PASS secret").await?.starts_with("230 "));
+        assert_eq!(client.command(&size).await?, "213 5");
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! Message encoding for the FUSE kernel protocol, which uses the kernel's byte order and structure
+//! layouts, with names terminated by nul bytes
+
+use std::{
+    ffi::OsStr,
+    io::{self, ErrorKind},
+    os::unix::ffi::OsStrExt,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The protocol version spoken, whose structure layouts are used
+pub(super) const MAJOR: u32 = 7;
+pub(super) const MINOR: u32 = 31;
+
+/// The size of `fuse_in_header`
+pub(super) const IN_HEADER_LEN: usize = 40;
+/// The size of `fuse_out_header`
+const OUT_HEADER_LEN: usize = 16;
+/// The most bytes written by a single request
+pub(super) const MAX_WRITE: u32 = 128 * 1024;
+/// The size of buffer needed to read any request, since the device only returns whole requests
+pub(super) const BUFFER_LEN: usize = MAX_WRITE as usize + 4096;
+
+/// The node id of the root directory
+pub(super) const ROOT_ID: u64 = 1;
+
+pub(super) const FUSE_LOOKUP: u32 = 1;
+pub(super) const FUSE_FORGET: u32 = 2;
+pub(super) const FUSE_GETATTR: u32 = 3;
+pub(super) const FUSE_SETATTR: u32 = 4;
+pub(super) const FUSE_READLINK: u32 = 5;
+pub(super) const FUSE_SYMLINK: u32 = 6;
+pub(super) const FUSE_MKDIR: u32 = 9;
+pub(super) const FUSE_UNLINK: u32 = 10;
+pub(super) const FUSE_RMDIR: u32 = 11;
+pub(super) const FUSE_RENAME: u32 = 12;
+pub(super) const FUSE_LINK: u32 = 13;
+pub(super) const FUSE_OPEN: u32 = 14;
+pub(super) const FUSE_READ: u32 = 15;
+pub(super) const FUSE_WRITE: u32 = 16;
+pub(super) const FUSE_STATFS: u32 = 17;
+pub(super) const FUSE_RELEASE: u32 = 18;
+pub(super) const FUSE_FSYNC: u32 = 20;
+pub(super) const FUSE_FLUSH: u32 = 25;
+pub(super) const FUSE_INIT: u32 = 26;
+pub(super) const FUSE_OPENDIR: u32 = 27;
+pub(super) const FUSE_READDIR: u32 = 28;
+pub(super) const FUSE_RELEASEDIR: u32 = 29;
+pub(super) const FUSE_FSYNCDIR: u32 = 30;
+pub(super) const FUSE_ACCESS: u32 = 34;
+pub(super) const FUSE_CREATE: u32 = 35;
+pub(super) const FUSE_INTERRUPT: u32 = 36;
+pub(super) const FUSE_DESTROY: u32 = 38;
+pub(super) const FUSE_BATCH_FORGET: u32 = 42;
+pub(super) const FUSE_RENAME2: u32 = 45;
+
+/// `FUSE_INIT` flags: truncating files as they're opened, and writes larger than a page
+pub(super) const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+pub(super) const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+/// `fuse_setattr_in` validity flags
+pub(super) const FATTR_MODE: u32 = 1 << 0;
+pub(super) const FATTR_SIZE: u32 = 1 << 3;
+
+/// Linux open flags
+pub(super) const O_ACCMODE: u32 = 0o3;
+pub(super) const O_RDONLY: u32 = 0o0;
+pub(super) const O_WRONLY: u32 = 0o1;
+pub(super) const O_EXCL: u32 = 0o200;
+pub(super) const O_TRUNC: u32 = 0o1000;
+pub(super) const O_APPEND: u32 = 0o2000;
+
+/// `errno`s sent for requests which are never supported
+pub(super) const ENOSYS: i32 = 38;
+pub(super) const EOPNOTSUPP: i32 = 95;
+
+/// The parts of `fuse_in_header` which are used
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Header {
+    pub(super) opcode: u32,
+    pub(super) unique: u64,
+    pub(super) node: u64,
+}
+
+/// Builds the body of a reply
+#[derive(Debug, Default)]
+pub(super) struct Writer(pub(super) Vec<u8>);
+
+impl Writer {
+    pub(super) fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub(super) fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub(super) fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub(super) fn bytes(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    /// Writes `count` zeroed words, for unused fields and padding
+    pub(super) fn zeros(mut self, count: usize) -> Self {
+        for _ in 0..count {
+            self = self.u32(0);
+        }
+        self
+    }
+
+    /// Writes the body to `device` as the reply to the request `unique`, with an `errno` of
+    /// `error`, which is zero for success
+    pub(super) async fn send<D: AsyncWrite + Unpin>(
+        self,
+        device: &mut D,
+        unique: u64,
+        error: i32,
+    ) -> io::Result<()> {
+        let len = u32::try_from(OUT_HEADER_LEN + self.0.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "reply too large"))?;
+        // Replies must be written whole, with a single write
+        let mut reply = Vec::with_capacity(OUT_HEADER_LEN + self.0.len());
+        reply.extend_from_slice(&len.to_ne_bytes());
+        reply.extend_from_slice(&(-error).to_ne_bytes());
+        reply.extend_from_slice(&unique.to_ne_bytes());
+        reply.extend_from_slice(&self.0);
+        device.write_all(&reply).await?;
+        device.flush().await
+    }
+}
+
+/// Reads a request from `device` into `buffer`, returning it's header and the length of the
+/// request, or `None` once the file system is unmounted
+pub(super) async fn receive<D: AsyncRead + Unpin>(
+    device: &mut D,
+    buffer: &mut [u8],
+) -> io::Result<Option<(Header, usize)>> {
+    let mut read = 0;
+    loop {
+        match device.read(&mut buffer[read..]).await {
+            Ok(0) => return Ok(None),
+            Ok(len) => read += len,
+            // The request was interrupted before it was read
+            Err(err) if err.raw_os_error() == Some(2) => {}
+            // The file system was unmounted
+            Err(err) if err.raw_os_error() == Some(19) => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        if read < IN_HEADER_LEN {
+            continue;
+        }
+        let mut reader = Reader(&buffer[..IN_HEADER_LEN]);
+        let len = reader.u32()? as usize;
+        if !(IN_HEADER_LEN..=buffer.len()).contains(&len) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid request length",
+            ));
+        }
+        // The device returns whole requests, but other streams might not
+        if read >= len {
+            let (opcode, unique, node) = (reader.u32()?, reader.u64()?, reader.u64()?);
+            let header = Header {
+                opcode,
+                unique,
+                node,
+            };
+            return Ok(Some((header, len)));
+        }
+    }
+}
+
+/// Parses the body of a request
+#[derive(Debug)]
+pub(super) struct Reader<'a>(pub(super) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated request"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(super) fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(super) fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_ne_bytes(bytes))
+    }
+
+    /// Skips `len` bytes of fields which aren't used
+    pub(super) fn skip(&mut self, len: usize) -> io::Result<()> {
+        self.take(len).map(drop)
+    }
+
+    /// Reads a name terminated by a nul byte
+    pub(super) fn name(&mut self) -> io::Result<&'a OsStr> {
+        let len = self
+            .0
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "unterminated name"))?;
+        let name = self.take(len)?;
+        self.skip(1)?;
+        Ok(OsStr::from_bytes(name))
+    }
+
+    /// The rest of the body, such as the data to write
+    pub(super) fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+/// Converts an [`io::Error`] into the Linux `errno` sent in replies
+pub(super) fn errno(err: &io::Error) -> i32 {
+    if let Some(code) = err.raw_os_error() {
+        return code;
+    }
+    match err.kind() {
+        ErrorKind::NotFound => 2,
+        ErrorKind::PermissionDenied => 13,
+        ErrorKind::AlreadyExists => 17,
+        ErrorKind::CrossesDevices => 18,
+        ErrorKind::NotADirectory => 20,
+        ErrorKind::IsADirectory => 21,
+        ErrorKind::InvalidInput | ErrorKind::InvalidFilename => 22,
+        ErrorKind::FileTooLarge => 27,
+        ErrorKind::StorageFull => 28,
+        ErrorKind::ReadOnlyFilesystem => 30,
+        ErrorKind::DirectoryNotEmpty => 39,
+        ErrorKind::Unsupported => EOPNOTSUPP,
+        ErrorKind::QuotaExceeded => 122,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_receive() -> io::Result<()> {
+        let mut request = Writer::default()
+            .u32(0)
+            .u32(FUSE_LOOKUP)
+            .u64(3)
+            .u64(ROOT_ID)
+            .zeros(4)
+            .bytes(b"name\0")
+            .0;
+        let len = u32::try_from(request.len()).unwrap_or(u32::MAX);
+        request[..4].copy_from_slice(&len.to_ne_bytes());
+
+        let mut buffer = vec![0; BUFFER_LEN];
+        let received = receive(&mut request.as_slice(), &mut buffer).await?;
+        let Some((header, len)) = received else {
+            unreachable!("a request was sent")
+        };
+        assert_eq!(
+            (header.opcode, header.unique, header.node),
+            (FUSE_LOOKUP, 3, 1)
+        );
+        let mut reader = Reader(&buffer[IN_HEADER_LEN..len]);
+        assert_eq!(reader.name()?, "name");
+        assert!(reader.rest().is_empty());
+        assert!(receive(&mut [].as_slice(), &mut buffer).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send() -> io::Result<()> {
+        let mut reply = Vec::new();
+        Writer::default().u64(7).send(&mut reply, 9, 2).await?;
+        let mut reader = Reader(&reply);
+        assert_eq!(
+            (reader.u32()?, reader.u32()?),
+            (24, (-2_i32).cast_unsigned())
+        );
+        assert_eq!((reader.u64()?, reader.u64()?), (9, 7));
+        Ok(())
+    }
+}
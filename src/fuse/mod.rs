@@ -0,0 +1,891 @@
+//! Mounting a service stack as a local file system with FUSE
+//!
+//! [`serve`] answers the requests the kernel makes of a FUSE file system by translating them into
+//! requests against a service, so any backend and middleware stack can be used with normal shell
+//! tools.  Mounting is left to the application, which opens `/dev/fuse`, mounts it with `mount(2)`
+//! (passing the device's file descriptor in the `fd` option) or `fusermount3`, and passes the
+//! device to [`serve`].
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    future::poll_fn,
+    io::{self, ErrorKind, SeekFrom},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tower_service::Service;
+
+use crate::{FileHandle, Metadata, Mode, Request, Response};
+use abi::{
+    errno, receive, Header, Reader, Writer, BUFFER_LEN, ENOSYS, EOPNOTSUPP, FATTR_MODE, FATTR_SIZE,
+    FUSE_ACCESS, FUSE_ATOMIC_O_TRUNC, FUSE_BATCH_FORGET, FUSE_BIG_WRITES, FUSE_CREATE,
+    FUSE_DESTROY, FUSE_FLUSH, FUSE_FORGET, FUSE_FSYNC, FUSE_FSYNCDIR, FUSE_GETATTR, FUSE_INIT,
+    FUSE_INTERRUPT, FUSE_LINK, FUSE_LOOKUP, FUSE_MKDIR, FUSE_OPEN, FUSE_OPENDIR, FUSE_READ,
+    FUSE_READDIR, FUSE_READLINK, FUSE_RELEASE, FUSE_RELEASEDIR, FUSE_RENAME, FUSE_RENAME2,
+    FUSE_RMDIR, FUSE_SETATTR, FUSE_STATFS, FUSE_SYMLINK, FUSE_UNLINK, FUSE_WRITE, IN_HEADER_LEN,
+    MAJOR, MAX_WRITE, MINOR, O_ACCMODE, O_APPEND, O_EXCL, O_RDONLY, O_TRUNC, O_WRONLY, ROOT_ID,
+};
+
+mod abi;
+
+/// File type bits of a mode, as in `st_mode`
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+
+/// How long, in seconds, the kernel may cache names and attributes, which is kept short since the
+/// service stack might be shared
+const VALID_SECS: u64 = 1;
+
+/// Answers the requests read from the FUSE `device` with `service` until the file system is
+/// unmounted
+///
+/// The root of the mount is `/`, and paths are passed to `service` as absolute paths, so mount a
+/// directory by wrapping the service in [`Root`](crate::middleware::root::Root).  Requests are
+/// answered in order, so interrupting them has no effect.
+///
+/// Files are read and written through [`Request::Open`] where the service supports it, and
+/// otherwise with [`Request::ReadRange`], or buffered in memory and written with
+/// [`Request::WriteBytes`] when they're closed.  Like [`Mode`], files can only be opened for
+/// reading, or for writing when truncating or appending.  Attributes come from
+/// [`Request::GetMetadata`], falling back to reading the whole file to find it's size.
+///
+/// Directories are listed with [`Request::ReadDir`] when they're opened.  On services which can't
+/// list them, opening a directory still works, so the files in it can be used by name, but reading
+/// it fails.  Setting attributes only changes permissions or truncates files to nothing, ignoring
+/// other attributes.  Extended attributes, locks and special files aren't supported.
+///
+/// # Errors
+///
+/// If reading from or writing to `device` fails, or a request is malformed.  Errors from `service`
+/// are sent to the kernel instead.
+pub async fn serve<D, S>(mut device: D, service: S) -> io::Result<()>
+where
+    D: AsyncRead + AsyncWrite + Unpin,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut server = Server {
+        service,
+        nodes: HashMap::from([(
+            ROOT_ID,
+            Node {
+                path: PathBuf::from("/"),
+                lookups: 1,
+            },
+        )]),
+        ids: HashMap::from([(PathBuf::from("/"), ROOT_ID)]),
+        next_node: ROOT_ID + 1,
+        files: HashMap::new(),
+        next_file: 0,
+    };
+    let mut buffer = vec![0; BUFFER_LEN];
+    while let Some((header, len)) = receive(&mut device, &mut buffer).await? {
+        let mut reader = Reader(&buffer[IN_HEADER_LEN..len]);
+        match header.opcode {
+            // The kernel doesn't expect replies to these
+            FUSE_FORGET | FUSE_BATCH_FORGET => {
+                server.forget(header, &mut reader)?;
+                continue;
+            }
+            FUSE_INTERRUPT => continue,
+            _ => {}
+        }
+        let (error, reply) = match server.handle(header, &mut reader).await {
+            Ok(reply) => (0, reply),
+            Err(err) => (errno(&err), Writer::default()),
+        };
+        reply.send(&mut device, header.unique, error).await?;
+        if header.opcode == FUSE_DESTROY {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Server<S> {
+    service: S,
+    nodes: HashMap<u64, Node>,
+    /// The ids of the nodes in `nodes`, by their path
+    ids: HashMap<PathBuf, u64>,
+    next_node: u64,
+    files: HashMap<u64, Handle>,
+    next_file: u64,
+}
+
+/// A file the kernel has looked up
+#[derive(Debug)]
+struct Node {
+    path: PathBuf,
+    /// How many times the node has been looked up and not forgotten
+    lookups: u64,
+}
+
+#[derive(Debug)]
+enum Handle {
+    /// A file opened by the service
+    File(FileHandle),
+    /// A file read with [`Request::ReadRange`], for services which can't open files
+    Read(PathBuf),
+    /// A file written with [`Request::WriteBytes`] when it's flushed, for services which can't open
+    /// files
+    Write {
+        path: PathBuf,
+        bytes: Vec<u8>,
+        dirty: bool,
+    },
+    /// A directory's entries as they were when it was opened, including `.` and `..`, or `None`
+    /// for services which can't list directories
+    Directory(Option<Vec<Dirent>>),
+}
+
+/// An entry of a directory, as sent in `fuse_dirent`
+#[derive(Debug)]
+struct Dirent {
+    ino: u64,
+    /// The file type bits of the entry's mode, shifted down as in `d_type`
+    kind: u32,
+    name: PathBuf,
+}
+
+/// The attributes of a file, as sent in `fuse_attr`
+#[derive(Debug)]
+struct Attr {
+    ino: u64,
+    size: u64,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    /// Access, modification and change times
+    times: [(u64, u32); 3],
+}
+
+impl<S> Server<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    /// Handles a request, returning the body of the reply
+    // One arm per request
+    #[allow(clippy::too_many_lines)]
+    async fn handle(&mut self, header: Header, reader: &mut Reader<'_>) -> io::Result<Writer> {
+        let reply = Writer::default();
+        match header.opcode {
+            FUSE_INIT => {
+                let (major, _minor) = (reader.u32()?, reader.u32()?);
+                let (max_readahead, flags) = (reader.u32()?, reader.u32()?);
+                if major < MAJOR {
+                    return Err(io::Error::from_raw_os_error(71));
+                }
+                let flags = flags & (FUSE_ATOMIC_O_TRUNC | FUSE_BIG_WRITES);
+                let reply = reply.u32(MAJOR).u32(MINOR).u32(max_readahead).u32(flags);
+                // Background requests and congestion threshold, the largest write and the time
+                // granularity, then unused fields
+                let reply = reply.u16(16).u16(12).u32(MAX_WRITE).u32(1);
+                Ok(reply.zeros(9))
+            }
+            FUSE_LOOKUP => {
+                let path = self.child(header.node, reader.name()?)?;
+                self.entry(path, reply).await
+            }
+            FUSE_GETATTR => {
+                let path = self.path(header.node)?;
+                Ok(attr_out(reply, &self.attr(&path).await?))
+            }
+            FUSE_SETATTR => self.set_attr(header.node, reader, reply).await,
+            FUSE_READLINK => {
+                let path = self.path(header.node)?;
+                match self.call(Request::FollowLink(path.into())).await? {
+                    Response::PointsTo(target) => Ok(reply.bytes(target.as_os_str().as_bytes())),
+                    _ => Err(unexpected_response()),
+                }
+            }
+            FUSE_OPEN => {
+                let flags = reader.u32()?;
+                let path = self.path(header.node)?;
+                let mode = match (flags & O_ACCMODE, flags & (O_TRUNC | O_APPEND)) {
+                    (O_RDONLY, _) => Mode::Read,
+                    (O_WRONLY, O_TRUNC) => Mode::CreateOrOverwrite,
+                    (O_WRONLY, O_APPEND) => Mode::AppendExisting,
+                    _ => {
+                        return Err(io::Error::new(
+                            ErrorKind::Unsupported,
+                            "files can only be opened for reading, truncating or appending",
+                        ))
+                    }
+                };
+                let fh = self.open(path, mode).await?;
+                Ok(reply.u64(fh).zeros(2))
+            }
+            FUSE_CREATE => {
+                let flags = reader.u32()?;
+                // The mode, umask and open flags, since new files get the service's defaults
+                reader.skip(12)?;
+                let path = self.child(header.node, reader.name()?)?;
+                let mode = if flags & O_EXCL == 0 {
+                    Mode::CreateOrOverwrite
+                } else {
+                    Mode::CreateNew
+                };
+                let fh = self.open(path.clone(), mode).await?;
+                // Buffered files don't exist until they're flushed
+                self.flush(fh).await?;
+                Ok(self.entry(path, reply).await?.u64(fh).zeros(2))
+            }
+            FUSE_READ => {
+                let (fh, offset, size) = (reader.u64()?, reader.u64()?, reader.u32()?);
+                Ok(reply.bytes(&self.read(fh, offset, size.min(MAX_WRITE)).await?))
+            }
+            FUSE_WRITE => {
+                let (fh, offset) = (reader.u64()?, reader.u64()?);
+                // The size, flags and lock owner, since the data follows
+                reader.skip(24)?;
+                let data = reader.rest();
+                self.write(fh, offset, data).await?;
+                let written = u32::try_from(data.len()).unwrap_or(u32::MAX);
+                Ok(reply.u32(written).zeros(1))
+            }
+            FUSE_FLUSH => {
+                self.flush(reader.u64()?).await?;
+                Ok(reply)
+            }
+            FUSE_FSYNC => {
+                let fh = reader.u64()?;
+                self.flush(fh).await?;
+                if let Some(Handle::File(file)) = self.files.get_mut(&fh) {
+                    file.sync_all().await?;
+                }
+                Ok(reply)
+            }
+            FUSE_RELEASE => {
+                let fh = reader.u64()?;
+                let flushed = self.flush(fh).await;
+                self.files.remove(&fh);
+                flushed.map(|()| reply)
+            }
+            FUSE_OPENDIR => {
+                let fh = self.open_dir(header.node).await?;
+                Ok(reply.u64(fh).zeros(2))
+            }
+            FUSE_READDIR => {
+                let (fh, offset, size) = (reader.u64()?, reader.u64()?, reader.u32()?);
+                let Handle::Directory(entries) = self.file(fh)? else {
+                    return Err(io::Error::from_raw_os_error(9));
+                };
+                let entries = entries
+                    .as_deref()
+                    .ok_or_else(|| io::Error::from_raw_os_error(EOPNOTSUPP))?;
+                Ok(dirents(reply, entries, offset, size))
+            }
+            FUSE_RELEASEDIR => {
+                self.files.remove(&reader.u64()?);
+                Ok(reply)
+            }
+            // Whether access is allowed is left to the service when the file is used
+            FUSE_FSYNCDIR | FUSE_ACCESS | FUSE_DESTROY => Ok(reply),
+            FUSE_STATFS => {
+                // The counts of blocks and files, which aren't known, then the block size, name
+                // length and fragment size
+                let reply = reply.zeros(10).u32(4096).u32(255).u32(4096);
+                Ok(reply.zeros(7))
+            }
+            _ => self.modify(header, reader, reply).await,
+        }
+    }
+
+    /// Handles the requests which change directories
+    async fn modify(
+        &mut self,
+        header: Header,
+        reader: &mut Reader<'_>,
+        reply: Writer,
+    ) -> io::Result<Writer> {
+        match header.opcode {
+            FUSE_MKDIR => {
+                // The mode and umask, since new directories get the service's defaults
+                reader.skip(8)?;
+                let path = self.child(header.node, reader.name()?)?;
+                let req = Request::CreateDir {
+                    path: path.as_path().into(),
+                    recursive: false,
+                };
+                self.call(req).await?;
+                self.entry(path, reply).await
+            }
+            FUSE_SYMLINK => {
+                let dst = self.child(header.node, reader.name()?)?;
+                let src = PathBuf::from(reader.name()?);
+                let req = Request::Symlink {
+                    src: src.into(),
+                    dst: dst.as_path().into(),
+                };
+                self.call(req).await?;
+                self.entry(dst, reply).await
+            }
+            FUSE_LINK => {
+                let src = self.path(reader.u64()?)?;
+                let dst = self.child(header.node, reader.name()?)?;
+                let req = Request::HardLink {
+                    src: src.into(),
+                    dst: dst.as_path().into(),
+                };
+                self.call(req).await?;
+                self.entry(dst, reply).await
+            }
+            FUSE_UNLINK => {
+                let path = self.child(header.node, reader.name()?)?;
+                self.call(Request::RemoveFile(path.into())).await?;
+                Ok(reply)
+            }
+            FUSE_RMDIR => {
+                let path = self.child(header.node, reader.name()?)?;
+                let req = Request::RemoveDir {
+                    path: path.into(),
+                    recursive: false,
+                };
+                self.call(req).await?;
+                Ok(reply)
+            }
+            FUSE_RENAME | FUSE_RENAME2 => {
+                let dir = reader.u64()?;
+                if header.opcode == FUSE_RENAME2 && reader.u64()? != 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "renaming with flags isn't supported",
+                    ));
+                }
+                let from = self.child(header.node, reader.name()?)?;
+                let to = self.child(dir, reader.name()?)?;
+                let req = Request::Rename {
+                    from: from.as_path().into(),
+                    to: to.as_path().into(),
+                };
+                self.call(req).await?;
+                self.moved(&from, &to);
+                Ok(reply)
+            }
+            // Including extended attributes, locks and special files
+            _ => Err(io::Error::from_raw_os_error(ENOSYS)),
+        }
+    }
+
+    async fn call(&mut self, req: Request) -> io::Result<Response> {
+        poll_fn(|cx| self.service.poll_ready(cx)).await?;
+        self.service.call(req).await
+    }
+
+    fn path(&self, node: u64) -> io::Result<PathBuf> {
+        self.nodes
+            .get(&node)
+            .map(|node| node.path.clone())
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "unknown node"))
+    }
+
+    /// The path of `name` in the directory `node`
+    fn child(&self, node: u64, name: &OsStr) -> io::Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.as_bytes().contains(&b'/') {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid file name"));
+        }
+        Ok(self.path(node)?.join(name))
+    }
+
+    /// Looks up `path`, replying with it's node and attributes
+    async fn entry(&mut self, path: PathBuf, reply: Writer) -> io::Result<Writer> {
+        let attr = self.attr(&path).await?;
+        let id = if let Some(id) = self.ids.get(&path) {
+            *id
+        } else {
+            let id = self.next_node;
+            self.next_node += 1;
+            self.ids.insert(path.clone(), id);
+            self.nodes.insert(id, Node { path, lookups: 0 });
+            id
+        };
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.lookups += 1;
+        }
+        // The generation, then how long the name and attributes are valid for
+        let reply = reply
+            .u64(id)
+            .u64(0)
+            .u64(VALID_SECS)
+            .u64(VALID_SECS)
+            .zeros(2);
+        Ok(fuse_attr(reply, &attr))
+    }
+
+    fn forget(&mut self, header: Header, reader: &mut Reader<'_>) -> io::Result<()> {
+        let forgotten = if header.opcode == FUSE_FORGET {
+            vec![(header.node, reader.u64()?)]
+        } else {
+            let count = reader.u32()?;
+            reader.skip(4)?;
+            (0..count)
+                .map(|_| Ok((reader.u64()?, reader.u64()?)))
+                .collect::<io::Result<_>>()?
+        };
+        for (id, lookups) in forgotten {
+            let Some(node) = self.nodes.get_mut(&id) else {
+                continue;
+            };
+            node.lookups = node.lookups.saturating_sub(lookups);
+            if node.lookups == 0 && id != ROOT_ID {
+                if let Some(node) = self.nodes.remove(&id) {
+                    self.ids.remove(&node.path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates the paths of the nodes within `from`, which was renamed to `to`
+    fn moved(&mut self, from: &Path, to: &Path) {
+        for (id, node) in &mut self.nodes {
+            if let Ok(rest) = node.path.strip_prefix(from) {
+                self.ids.remove(&node.path);
+                node.path = to.join(rest);
+                self.ids.insert(node.path.clone(), *id);
+            }
+        }
+    }
+
+    /// Describes the file at `path`, without following symbolic links
+    async fn attr(&mut self, path: &Path) -> io::Result<Attr> {
+        let req = Request::GetMetadata {
+            path: path.into(),
+            follow_symlinks: false,
+        };
+        match self.call(req).await {
+            Ok(Response::Metadata(metadata)) => {
+                let ino = self.ids.get(path).copied().unwrap_or_default();
+                Ok(metadata_attr(&metadata, ino))
+            }
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => self.probe(path).await,
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Describes the file at `path` on services without metadata, by reading it
+    async fn probe(&mut self, path: &Path) -> io::Result<Attr> {
+        let (mode, size) = match self.call(Request::ReadBytes(path.into())).await {
+            Ok(Response::Bytes(bytes)) => (S_IFREG | 0o644, bytes.len() as u64),
+            Ok(_) => return Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::IsADirectory => (S_IFDIR | 0o755, 0),
+            // Directories are usually implicit on services without metadata
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                match self.call(Request::Exists(path.into())).await? {
+                    Response::Exists(exists) if exists || path.parent().is_none() => {
+                        (S_IFDIR | 0o755, 0)
+                    }
+                    Response::Exists(_) => return Err(err),
+                    _ => return Err(unexpected_response()),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Attr {
+            ino: self.ids.get(path).copied().unwrap_or_default(),
+            size,
+            mode,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            times: [(0, 0); 3],
+        })
+    }
+
+    async fn set_attr(
+        &mut self,
+        node: u64,
+        reader: &mut Reader<'_>,
+        reply: Writer,
+    ) -> io::Result<Writer> {
+        let valid = reader.u32()?;
+        reader.skip(4)?;
+        let (fh, size) = (reader.u64()?, reader.u64()?);
+        // The lock owner and times
+        reader.skip(44)?;
+        let mode = reader.u32()?;
+        let path = self.path(node)?;
+        if valid & FATTR_MODE != 0 {
+            let perm = std::os::unix::fs::PermissionsExt::from_mode(mode & 0o7777);
+            self.call(Request::SetPermissions {
+                path: path.as_path().into(),
+                perm,
+            })
+            .await?;
+        }
+        if valid & FATTR_SIZE != 0 {
+            if size != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "files can only be truncated to nothing",
+                ));
+            }
+            if let Some(Handle::Write { bytes, dirty, .. }) = self.files.get_mut(&fh) {
+                bytes.clear();
+                *dirty = true;
+            } else {
+                let req = Request::WriteBytes {
+                    path: path.as_path().into(),
+                    bytes: Vec::new(),
+                };
+                self.call(req).await?;
+            }
+        }
+        Ok(attr_out(reply, &self.attr(&path).await?))
+    }
+
+    /// Opens the file at `path`, returning it's handle
+    async fn open(&mut self, path: PathBuf, mode: Mode) -> io::Result<u64> {
+        let open = Request::Open {
+            mode,
+            path: path.as_path().into(),
+        };
+        let handle = match self.call(open).await {
+            Ok(Response::File(file)) => Handle::File(file),
+            Ok(_) => return Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => match mode {
+                Mode::Read => Handle::Read(path),
+                Mode::CreateNew => match self.call(Request::Exists(path.as_path().into())).await? {
+                    Response::Exists(true) => return Err(ErrorKind::AlreadyExists.into()),
+                    Response::Exists(false) => Handle::Write {
+                        path,
+                        bytes: Vec::new(),
+                        dirty: true,
+                    },
+                    _ => return Err(unexpected_response()),
+                },
+                Mode::CreateOrOverwrite => Handle::Write {
+                    path,
+                    bytes: Vec::new(),
+                    dirty: true,
+                },
+                Mode::AppendExisting | Mode::CreateOrAppend => return Err(err),
+            },
+            Err(err) => return Err(err),
+        };
+        Ok(self.insert(handle))
+    }
+
+    /// Opens the directory `node`, listing its entries, and returns its handle
+    async fn open_dir(&mut self, node: u64) -> io::Result<u64> {
+        let path = self.path(node)?;
+        let entries = match self.call(Request::ReadDir(path.as_path().into())).await {
+            Ok(Response::Directory(entries)) => entries,
+            Ok(_) => return Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                return Ok(self.insert(Handle::Directory(None)))
+            }
+            Err(err) => return Err(err),
+        };
+        let parent = path
+            .parent()
+            .and_then(|parent| self.ids.get(parent))
+            .copied()
+            .unwrap_or(ROOT_ID);
+        let dots = [(".", node), ("..", parent)].map(|(name, ino)| Dirent {
+            ino,
+            kind: S_IFDIR >> 12,
+            name: name.into(),
+        });
+        let entries = entries.into_iter().map(|(name, metadata)| {
+            let ino = self.ids.get(&path.join(&name)).copied().unwrap_or_default();
+            Dirent {
+                ino: metadata.ino().unwrap_or(ino),
+                kind: metadata.mode() >> 12,
+                name,
+            }
+        });
+        let entries = dots.into_iter().chain(entries).collect();
+        Ok(self.insert(Handle::Directory(Some(entries))))
+    }
+
+    fn insert(&mut self, handle: Handle) -> u64 {
+        let fh = self.next_file;
+        self.next_file += 1;
+        self.files.insert(fh, handle);
+        fh
+    }
+
+    fn file(&mut self, fh: u64) -> io::Result<&mut Handle> {
+        self.files
+            .get_mut(&fh)
+            .ok_or_else(|| io::Error::from_raw_os_error(9))
+    }
+
+    async fn read(&mut self, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        match self.file(fh)? {
+            Handle::File(file) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = Vec::new();
+                file.take(u64::from(size)).read_to_end(&mut data).await?;
+                Ok(data)
+            }
+            Handle::Read(path) => {
+                let path = path.clone();
+                let range = offset..offset.saturating_add(u64::from(size));
+                match self
+                    .call(Request::ReadRange {
+                        path: path.into(),
+                        range,
+                    })
+                    .await?
+                {
+                    Response::Bytes(bytes) => Ok(bytes),
+                    _ => Err(unexpected_response()),
+                }
+            }
+            Handle::Write { .. } | Handle::Directory(_) => Err(io::Error::from_raw_os_error(9)),
+        }
+    }
+
+    async fn write(&mut self, fh: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        match self.file(fh)? {
+            // Files opened for appending ignore the offset, as on Linux
+            Handle::File(file) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(data).await
+            }
+            Handle::Write { bytes, dirty, .. } => {
+                let start = usize::try_from(offset).map_err(io::Error::other)?;
+                let end = start
+                    .checked_add(data.len())
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "offset too large"))?;
+                if bytes.len() < end {
+                    bytes.resize(end, 0);
+                }
+                bytes[start..end].copy_from_slice(data);
+                *dirty = true;
+                Ok(())
+            }
+            Handle::Read(_) | Handle::Directory(_) => Err(io::Error::from_raw_os_error(9)),
+        }
+    }
+
+    /// Writes out what's been written to a file, which happens each time a descriptor for it is
+    /// closed
+    async fn flush(&mut self, fh: u64) -> io::Result<()> {
+        let req = match self.file(fh)? {
+            Handle::File(file) => return file.flush().await,
+            Handle::Write { path, bytes, dirty } if *dirty => {
+                *dirty = false;
+                Request::WriteBytes {
+                    path: path.as_path().into(),
+                    bytes: bytes.clone(),
+                }
+            }
+            Handle::Write { .. } | Handle::Read(_) | Handle::Directory(_) => return Ok(()),
+        };
+        self.call(req).await.map(drop)
+    }
+}
+
+/// The attributes of a file, using `ino` for backends without inode numbers
+#[allow(clippy::cast_possible_truncation)]
+fn metadata_attr(metadata: &Metadata, ino: u64) -> Attr {
+    let modified = timestamp(metadata.modified());
+    Attr {
+        ino: metadata.ino().unwrap_or(ino),
+        size: metadata.len(),
+        mode: metadata.mode(),
+        nlink: metadata.nlink().unwrap_or(1) as u32,
+        uid: metadata.uid().unwrap_or(0),
+        gid: metadata.gid().unwrap_or(0),
+        times: [
+            timestamp(metadata.accessed()),
+            modified,
+            metadata
+                .changed()
+                .map_or(modified, |changed| timestamp(Ok(changed))),
+        ],
+    }
+}
+
+fn timestamp(time: io::Result<SystemTime>) -> (u64, u32) {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |elapsed| {
+            (elapsed.as_secs(), elapsed.subsec_nanos())
+        })
+}
+
+fn fuse_attr(reply: Writer, attr: &Attr) -> Writer {
+    let mut reply = reply
+        .u64(attr.ino)
+        .u64(attr.size)
+        .u64(attr.size.div_ceil(512));
+    for (secs, _) in attr.times {
+        reply = reply.u64(secs);
+    }
+    for (_, nanos) in attr.times {
+        reply = reply.u32(nanos);
+    }
+    let reply = reply
+        .u32(attr.mode)
+        .u32(attr.nlink)
+        .u32(attr.uid)
+        .u32(attr.gid);
+    // The device, block size and flags
+    reply.u32(0).u32(4096).u32(0)
+}
+
+/// Writes the `entries` from `offset` on, as many as fit in `size` bytes, each with the offset of
+/// the entry after it for the kernel to continue from
+fn dirents(mut reply: Writer, entries: &[Dirent], offset: u64, size: u32) -> Writer {
+    let mut len = 0;
+    let skip = usize::try_from(offset).unwrap_or(usize::MAX);
+    for (entry, next) in entries.iter().zip(1..).skip(skip) {
+        let name = entry.name.as_os_str().as_bytes();
+        // Each entry is padded to a multiple of 8 bytes
+        let entry_len = (24 + name.len()).next_multiple_of(8);
+        len += entry_len;
+        if len > size as usize {
+            break;
+        }
+        reply = reply
+            .u64(entry.ino)
+            .u64(next)
+            .u32(u32::try_from(name.len()).unwrap_or(u32::MAX))
+            .u32(entry.kind)
+            .bytes(name)
+            .bytes(&[0; 7][..entry_len - 24 - name.len()]);
+    }
+    reply
+}
+
+fn attr_out(reply: Writer, attr: &Attr) -> Writer {
+    fuse_attr(reply.u64(VALID_SECS).zeros(2), attr)
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "unexpected response from inner service",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    /// Sends a request about `node`, returning the `errno` and body of the reply
+    async fn request(
+        device: &mut DuplexStream,
+        opcode: u32,
+        node: u64,
+        body: Writer,
+    ) -> io::Result<(i32, Vec<u8>)> {
+        let len = u32::try_from(IN_HEADER_LEN + body.0.len()).unwrap_or(u32::MAX);
+        let request = Writer::default().u32(len).u32(opcode).u64(5).u64(node);
+        device.write_all(&request.zeros(4).bytes(&body.0).0).await?;
+        let mut header = [0; 16];
+        device.read_exact(&mut header).await?;
+        let mut reader = Reader(&header);
+        let (len, error, unique) = (reader.u32()?, reader.u32()?, reader.u64()?);
+        assert_eq!(unique, 5);
+        let mut body = vec![0; len as usize - header.len()];
+        device.read_exact(&mut body).await?;
+        Ok((-error.cast_signed(), body))
+    }
+
+    /// The names of the `fuse_dirent`s in the body of a reply to `FUSE_READDIR`
+    fn names(mut body: &[u8]) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        while !body.is_empty() {
+            let mut reader = Reader(body);
+            reader.skip(16)?;
+            let len = reader.u32()? as usize;
+            names.push(String::from_utf8_lossy(&body[24..24 + len]).into_owned());
+            body = &body[(24 + len).next_multiple_of(8)..];
+        }
+        Ok(names)
+    }
+
+    #[tokio::test]
+    async fn test_session() -> io::Result<()> {
+        let dir = TestDir::new("fuse")?;
+        let (mut device, server) = tokio::io::duplex(BUFFER_LEN);
+        tokio::spawn(serve(server, FileSystem::new()));
+
+        let init = Writer::default().u32(MAJOR).u32(MINOR).u32(0).u32(0);
+        let (error, body) = request(&mut device, FUSE_INIT, 0, init).await?;
+        assert_eq!((error, body.len()), (0, 64));
+
+        // Look up the directory one name at a time
+        let mut node = ROOT_ID;
+        for name in dir.iter().skip(1) {
+            let lookup = Writer::default().bytes(name.as_bytes()).bytes(&[0]);
+            let (error, body) = request(&mut device, FUSE_LOOKUP, node, lookup).await?;
+            assert_eq!(error, 0);
+            node = Reader(&body).u64()?;
+        }
+
+        // Create a file and write to it
+        let create = Writer::default()
+            .u32(O_WRONLY)
+            .zeros(3)
+            .bytes(b"file.txt\0");
+        let (error, body) = request(&mut device, FUSE_CREATE, node, create).await?;
+        assert_eq!((error, body.len()), (0, 128 + 16));
+        let file = Reader(&body).u64()?;
+        let fh = Reader(&body[128..]).u64()?;
+        let write = Writer::default().u64(fh).u64(0).zeros(6).bytes(b"hello");
+        let (error, body) = request(&mut device, FUSE_WRITE, file, write).await?;
+        assert_eq!((error, Reader(&body).u32()?), (0, 5));
+        let release = Writer::default().u64(fh).zeros(4);
+        assert_eq!(
+            request(&mut device, FUSE_RELEASE, file, release).await?.0,
+            0
+        );
+
+        // Then read it back
+        let getattr = Writer::default().zeros(4);
+        let (error, body) = request(&mut device, FUSE_GETATTR, file, getattr).await?;
+        // The size follows the validity and inode
+        assert_eq!((error, Reader(&body[24..]).u64()?), (0, 5));
+        let open = Writer::default().u32(O_RDONLY).zeros(1);
+        let (error, body) = request(&mut device, FUSE_OPEN, file, open).await?;
+        assert_eq!(error, 0);
+        let read = Writer::default().u64(Reader(&body).u64()?).u64(1).u32(100);
+        let (error, body) = request(&mut device, FUSE_READ, file, read.zeros(5)).await?;
+        assert_eq!((error, body.as_slice()), (0, &b"ello"[..]));
+
+        // List the directory, a page at a time
+        let opendir = Writer::default().zeros(2);
+        let (error, body) = request(&mut device, FUSE_OPENDIR, node, opendir).await?;
+        assert_eq!(error, 0);
+        let dh = Reader(&body).u64()?;
+        let readdir = |offset, size| Writer::default().u64(dh).u64(offset).u32(size).zeros(3);
+        let (error, body) = request(&mut device, FUSE_READDIR, node, readdir(0, 4096)).await?;
+        assert_eq!(error, 0);
+        assert_eq!(names(&body)?, [".", "..", "file.txt"]);
+        let (_, body) = request(&mut device, FUSE_READDIR, node, readdir(1, 32)).await?;
+        assert_eq!(names(&body)?, [".."]);
+        let (_, body) = request(&mut device, FUSE_READDIR, node, readdir(2, 4096)).await?;
+        assert_eq!(names(&body)?, ["file.txt"]);
+        let (error, body) = request(&mut device, FUSE_READDIR, node, readdir(3, 4096)).await?;
+        assert_eq!((error, body.len()), (0, 0));
+        let releasedir = Writer::default().u64(dh).zeros(2);
+        let (error, _) = request(&mut device, FUSE_RELEASEDIR, node, releasedir).await?;
+        assert_eq!(error, 0);
+        let (error, _) = request(&mut device, FUSE_READDIR, node, readdir(0, 4096)).await?;
+        assert_eq!(error, 9);
+
+        // Remove it, after which it can't be found
+        let name = || Writer::default().bytes(b"file.txt\0");
+        assert_eq!(request(&mut device, FUSE_UNLINK, node, name()).await?.0, 0);
+        assert_eq!(request(&mut device, FUSE_LOOKUP, node, name()).await?.0, 2);
+        let (error, _) = request(&mut device, 8, node, name()).await?;
+        assert_eq!(error, ENOSYS);
+        Ok(())
+    }
+}
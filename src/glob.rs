@@ -0,0 +1,35 @@
+//! Matching paths against glob patterns
+
+/// Whether `text` matches `pattern`, where `*` matches any run of bytes other than `/`, and `**`
+/// any run of bytes
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|start| glob_match(rest, &text[start..])),
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&start| start == 0 || text[start - 1] != b'/')
+            .any(|start| glob_match(rest, &text[start..])),
+        [byte, rest @ ..] => text.first() == Some(byte) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, text, matches) in [
+            ("assets/**", "assets/js/app.js", true),
+            ("assets/*", "assets/js/app.js", false),
+            ("*.*.js", "app.3f2a.js", true),
+            ("*.js", "js/app.js", false),
+            ("**.js", "js/app.js", true),
+            ("favicon.ico", "favicon.ico", true),
+            ("favicon.ico", "favicon.icon", false),
+        ] {
+            let result = glob_match(pattern.as_bytes(), text.as_bytes());
+            assert_eq!(result, matches, "{pattern} {text}");
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    path::PathBuf,
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use http::StatusCode;
+use tower_service::Service;
+
+use super::{
+    error::{into_problem, write_error_status},
+    path_policy::PathPolicy,
+    serve::{call, method_not_allowed, status_response, ResponseBody},
+};
+use crate::{Request, Response};
+
+/// Creates directories under a directory of an inner `Service<Request>` in response to `WebDAV`
+/// style HTTP `MKCOL` requests
+///
+/// The request path is validated with the [`PathPolicy`] given to [`AcceptCreateDir::path_policy`]
+/// and joined onto `base`, and the directory is created with [`Request::CreateDir`].  As with
+/// `WebDAV`, missing parent directories aren't created.  Responses are:
+/// - `201 Created` once the directory is created
+/// - `405 Method Not Allowed` if something already exists at the path, for other methods, or for
+///   inner services which can't create directories
+/// - `409 Conflict` if the parent directory is missing
+/// - `404 Not Found` for invalid paths
+///
+/// Error responses have no body, unless problem details are enabled by
+/// [`AcceptCreateDir::problem_details`].
+#[derive(Debug, Clone)]
+pub struct AcceptCreateDir<S> {
+    base: PathBuf,
+    inner: S,
+    path_policy: PathPolicy,
+    problem_details: bool,
+}
+
+impl<S> AcceptCreateDir<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            path_policy: PathPolicy::default(),
+            problem_details: false,
+        }
+    }
+
+    /// Validates request paths with `path_policy`, answering those it rejects with a
+    /// `404 Not Found`
+    #[must_use]
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        self.path_policy = path_policy;
+        self
+    }
+
+    /// Gives error responses an `application/problem+json` body, as described by RFC 9457, with
+    /// the status' reason phrase as it's title
+    #[must_use]
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        self.problem_details = problem_details;
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for AcceptCreateDir<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let problem_details = self.problem_details;
+        let (parts, _) = req.into_parts();
+        let path = self
+            .path_policy
+            .build(parts.uri.path())
+            .map(|path| self.base.join(path));
+        async move {
+            if parts.method.as_str() != "MKCOL" {
+                return Ok(method_not_allowed("MKCOL"));
+            }
+            let Ok(path) = path else {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            };
+            let req = Request::CreateDir {
+                path: path.into(),
+                recursive: false,
+            };
+            Ok(match call(&mut inner, req).await {
+                Ok(_) => status_response(StatusCode::CREATED),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => method_not_allowed("MKCOL"),
+                Err(err) => status_response(write_error_status(&err)),
+            })
+        }
+        .map(move |response| match response {
+            Ok(response) if problem_details => Ok(into_problem(response)),
+            response => response,
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn create_dir(service: &mut AcceptCreateDir<FileSystem>, uri: &str) -> StatusCode {
+        let Ok(request) = http::Request::builder().method("MKCOL").uri(uri).body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response.status(),
+            Err(never) => match never {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_create_dir() -> io::Result<()> {
+        let dir = TestDir::new("accept_create_dir")?;
+        let mut service = AcceptCreateDir::new(&dir, FileSystem::new());
+
+        for (uri, status) in [
+            ("/new", StatusCode::CREATED),
+            ("/new", StatusCode::METHOD_NOT_ALLOWED),
+            ("/missing/new", StatusCode::CONFLICT),
+        ] {
+            assert_eq!(create_dir(&mut service, uri).await, status);
+        }
+        assert!(dir.join("new").is_dir());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() -> io::Result<()> {
+        let dir = TestDir::new("create_dir_policy")?;
+        let mut service = AcceptCreateDir::new(&dir, FileSystem::new())
+            .path_policy(PathPolicy::new().dotfiles(false));
+
+        assert_eq!(
+            create_dir(&mut service, "/.git").await,
+            StatusCode::NOT_FOUND
+        );
+        assert!(!dir.join(".git").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_problem_details() -> io::Result<()> {
+        let dir = TestDir::new("create_dir_problem")?;
+        let mut service = AcceptCreateDir::new(&dir, FileSystem::new()).problem_details(true);
+
+        let Ok(request) = http::Request::builder()
+            .method("MKCOL")
+            .uri("/missing/new")
+            .body(())
+        else {
+            unreachable!("the test request is valid")
+        };
+        let Ok(response) = service.call(request).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(create_dir(&mut service, "/new").await, StatusCode::CREATED);
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    path::PathBuf,
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use http::{HeaderMap, Method, StatusCode};
+use tower_service::Service;
+
+use super::{
+    error::{into_problem, write_error_status},
+    path_policy::PathPolicy,
+    serve::{call, check_write_preconditions, method_not_allowed, status_response, ResponseBody},
+    strong_etags::StrongETags,
+};
+use crate::{Request, Response};
+
+/// Removes files and directories under a directory of an inner `Service<Request>` in response to
+/// HTTP `DELETE` requests
+///
+/// The request path is validated with the [`PathPolicy`] given to [`AcceptDelete::path_policy`]
+/// and joined onto `base`.  Files (and symbolic links) are removed with [`Request::RemoveFile`],
+/// and directories with [`Request::RemoveDir`], only removing their contents if
+/// [`AcceptDelete::recursive`] is enabled.
+/// Responses are:
+/// - `204 No Content` once the entry is removed
+/// - `404 Not Found` if there's nothing to remove, or the path is invalid
+/// - `409 Conflict` for directories which aren't empty
+/// - `412 Precondition Failed` if an `If-Match` or `If-Unmodified-Since` header shows the entry
+///   has changed since the client saw it
+/// - `403 Forbidden` for `base` itself
+/// - `405 Method Not Allowed` for other methods, or inner services which can't remove entries
+///
+/// Error responses have no body, unless problem details are enabled by
+/// [`AcceptDelete::problem_details`].
+#[derive(Debug, Clone)]
+pub struct AcceptDelete<S> {
+    base: PathBuf,
+    inner: S,
+    recursive: bool,
+    strong_etags: Option<StrongETags>,
+    path_policy: PathPolicy,
+    problem_details: bool,
+}
+
+impl<S> AcceptDelete<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            recursive: false,
+            strong_etags: None,
+            path_policy: PathPolicy::default(),
+            problem_details: false,
+        }
+    }
+
+    /// Allows removing directories along with their contents
+    #[must_use]
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Matches `If-Match` headers against the strong `ETag`s from `strong_etags`, as
+    /// [`AcceptUpload::strong_etags`](super::AcceptUpload::strong_etags) does
+    #[must_use]
+    pub fn strong_etags(mut self, strong_etags: StrongETags) -> Self {
+        self.strong_etags = Some(strong_etags);
+        self
+    }
+
+    /// Validates request paths with `path_policy`, answering those it rejects with a
+    /// `404 Not Found`
+    #[must_use]
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        self.path_policy = path_policy;
+        self
+    }
+
+    /// Gives error responses an `application/problem+json` body, as
+    /// [`AcceptCreateDir::problem_details`](super::AcceptCreateDir::problem_details) does
+    #[must_use]
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        self.problem_details = problem_details;
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for AcceptDelete<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let (recursive, problem_details) = (self.recursive, self.problem_details);
+        let strong_etags = self.strong_etags.clone();
+        let (parts, _) = req.into_parts();
+        let path = self.path_policy.build(parts.uri.path());
+        let base = self.base.clone();
+        async move {
+            if parts.method != Method::DELETE {
+                return Ok(method_not_allowed("DELETE"));
+            }
+            let status = match path {
+                Ok(path) if path.as_os_str().is_empty() => StatusCode::FORBIDDEN,
+                Ok(path) => {
+                    let path = base.join(path);
+                    let strong_etags = strong_etags.as_ref();
+                    remove_if_unchanged(&mut inner, path, recursive, &parts.headers, strong_etags)
+                        .await
+                }
+                Err(_) => StatusCode::NOT_FOUND,
+            };
+            Ok(status_response(status))
+        }
+        .map(move |response| match response {
+            Ok(response) if problem_details => Ok(into_problem(response)),
+            response => response,
+        })
+        .boxed()
+    }
+}
+
+/// Removes the entry at `path` if the request's preconditions hold
+async fn remove_if_unchanged<S>(
+    inner: &mut S,
+    path: PathBuf,
+    recursive: bool,
+    headers: &HeaderMap,
+    strong_etags: Option<&StrongETags>,
+) -> StatusCode
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    if let Err(status) = check_write_preconditions(inner, path.clone(), headers, strong_etags).await
+    {
+        return status;
+    }
+    match remove(inner, path, recursive).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) if err.kind() == ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        Err(err) => write_error_status(&err),
+    }
+}
+
+/// Removes the file or directory at `path`, using it's metadata to choose the request
+pub(super) async fn remove<S>(inner: &mut S, path: PathBuf, recursive: bool) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let metadata = call(
+        inner,
+        Request::GetMetadata {
+            path: path.as_path().into(),
+            follow_symlinks: false,
+        },
+    )
+    .await;
+    let is_dir = match metadata {
+        Ok(Response::Metadata(metadata)) => metadata.is_dir(),
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "unexpected response from inner service",
+            ))
+        }
+        // Without metadata, try removing a file first
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            match call(inner, Request::RemoveFile(path.as_path().into())).await {
+                Err(err) if err.kind() == ErrorKind::IsADirectory => true,
+                result => return result.map(drop),
+            }
+        }
+        Err(err) => return Err(err),
+    };
+    let req = if is_dir {
+        Request::RemoveDir {
+            path: path.into(),
+            recursive,
+        }
+    } else {
+        Request::RemoveFile(path.into())
+    };
+    call(inner, req).await.map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn delete(service: &mut AcceptDelete<FileSystem>, uri: &str) -> StatusCode {
+        let Ok(request) = http::Request::delete(uri).body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response.status(),
+            Err(never) => match never {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_delete() -> io::Result<()> {
+        let dir = TestDir::new("accept_delete")?;
+        std::fs::create_dir_all(dir.join("full"))?;
+        std::fs::write(dir.join("file.txt"), "")?;
+        std::fs::write(dir.join("full/file.txt"), "")?;
+        let mut service = AcceptDelete::new(&dir, FileSystem::new());
+
+        for (uri, status) in [
+            ("/file.txt", StatusCode::NO_CONTENT),
+            ("/file.txt", StatusCode::NOT_FOUND),
+            ("/full", StatusCode::CONFLICT),
+            ("/", StatusCode::FORBIDDEN),
+        ] {
+            assert_eq!(delete(&mut service, uri).await, status);
+        }
+        let mut service = service.recursive(true);
+        assert_eq!(delete(&mut service, "/full").await, StatusCode::NO_CONTENT);
+        assert!(!dir.join("full").exists());
+
+        std::fs::write(dir.join("file.txt"), "")?;
+        for (name, value, status) in [
+            (
+                http::header::IF_UNMODIFIED_SINCE,
+                "Sun, 06 Nov 1994 08:49:37 GMT",
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                http::header::IF_MATCH,
+                r#""v1""#,
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (http::header::IF_MATCH, "*", StatusCode::NO_CONTENT),
+            (http::header::IF_MATCH, "*", StatusCode::PRECONDITION_FAILED),
+        ] {
+            let Ok(request) = http::Request::delete("/file.txt")
+                .header(name, value)
+                .body(())
+            else {
+                unreachable!("the test requests are valid")
+            };
+            let response = match service.call(request).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            assert_eq!(response.status(), status);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() -> io::Result<()> {
+        let dir = TestDir::new("delete_policy")?;
+        std::fs::write(dir.join(".keep"), "")?;
+        let mut service = AcceptDelete::new(&dir, FileSystem::new())
+            .path_policy(PathPolicy::new().dotfiles(false));
+
+        assert_eq!(delete(&mut service, "/.keep").await, StatusCode::NOT_FOUND);
+        assert!(dir.join(".keep").exists());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_problem_details() -> io::Result<()> {
+        use http_body::Body;
+
+        let dir = TestDir::new("delete_problem")?;
+        let mut service = AcceptDelete::new(&dir, FileSystem::new()).problem_details(true);
+
+        let Ok(request) = http::Request::delete("/missing.txt").body(()) else {
+            unreachable!("the test request is valid")
+        };
+        let Ok(response) = service.call(request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            r#"{"type":"about:blank","title":"Not Found","status":404}"#
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+use std::{convert::Infallible, io, path::PathBuf, task::Poll};
+
+use futures::{future::BoxFuture, FutureExt};
+use http::{header::CONTENT_LENGTH, Method, StatusCode};
+use http_body::Body;
+use tower_service::Service;
+
+use super::{
+    error::{into_problem, write_error_status},
+    path_policy::PathPolicy,
+    serve::{
+        call, check_write_preconditions, expectation_failed, method_not_allowed, status_response,
+        ResponseBody,
+    },
+    strong_etags::StrongETags,
+    write_body::{upload_error_status, write_body_to_file, WriteOptions},
+};
+use crate::{Mode, Request, Response};
+
+/// Accepts file uploads over HTTP, writing them under a directory of an inner `Service<Request>`
+///
+/// The request path is validated with the [`PathPolicy`] given to [`AcceptUpload::path_policy`] and
+/// joined onto `base`, and the body is streamed into the file opened with [`Request::Open`], so
+/// uploads pass through the same middleware as every other request.  Backends which can't open
+/// files for writing get the whole body with [`Request::WriteBytes`] instead.
+///
+/// `POST` only creates new files, as does `PUT` unless [`AcceptUpload::overwrite`] is enabled.
+/// Responses are:
+/// - `201 Created` for a new file, or `204 No Content` when a file was overwritten
+/// - `409 Conflict` if the file already exists (and can't be overwritten), or it's parent directory
+///   is missing
+/// - `412 Precondition Failed` if an `If-Match` or `If-Unmodified-Since` header shows the file has
+///   changed since the client saw it
+/// - `413 Payload Too Large` if the body is longer than [`AcceptUpload::max_len`], in which case
+///   the partially written file is removed
+/// - `405 Method Not Allowed` for other methods, or inner services which can't write files
+///
+/// Every check is made before the body is read, so a client sending `Expect: 100-continue` isn't
+/// told to send the body of an upload which will be rejected, by servers (such as hyper) which
+/// send `100 Continue` once the body is first read.  Other expectations get a
+/// `417 Expectation Failed`.
+///
+/// An overwritten file is truncated before the new body is written, so it's lost if the upload
+/// then fails.
+///
+/// Error responses have no body, unless problem details are enabled by
+/// [`AcceptUpload::problem_details`].
+#[derive(Debug, Clone)]
+pub struct AcceptUpload<S> {
+    base: PathBuf,
+    inner: S,
+    max_len: Option<u64>,
+    overwrite: bool,
+    path_policy: PathPolicy,
+    strong_etags: Option<StrongETags>,
+    problem_details: bool,
+}
+
+impl<S> AcceptUpload<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            max_len: None,
+            overwrite: false,
+            path_policy: PathPolicy::default(),
+            strong_etags: None,
+            problem_details: false,
+        }
+    }
+
+    /// Rejects bodies longer than `max_len` bytes
+    #[must_use]
+    pub fn max_len(mut self, max_len: u64) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Allows `PUT` requests to replace existing files
+    #[must_use]
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Validates request paths with `path_policy`, such as to keep uploaded names to a safe set of
+    /// characters
+    #[must_use]
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        self.path_policy = path_policy;
+        self
+    }
+
+    /// Matches `If-Match` headers against the strong `ETag`s from `strong_etags`, which should be
+    /// a clone of the one given to [`ServeDir::strong_etags`](super::ServeDir::strong_etags) so
+    /// the tags clients were sent are cached.  Without it files only have weak tags, which never
+    /// satisfy `If-Match`, so only `If-Match: *` can pass
+    #[must_use]
+    pub fn strong_etags(mut self, strong_etags: StrongETags) -> Self {
+        self.strong_etags = Some(strong_etags);
+        self
+    }
+
+    /// Gives error responses an `application/problem+json` body, as
+    /// [`AcceptCreateDir::problem_details`](super::AcceptCreateDir::problem_details) does
+    #[must_use]
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        self.problem_details = problem_details;
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for AcceptUpload<S>
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Send,
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let (max_len, overwrite) = (self.max_len.unwrap_or(u64::MAX), self.overwrite);
+        let (strong_etags, problem_details) = (self.strong_etags.clone(), self.problem_details);
+        let (parts, body) = req.into_parts();
+        let path = self
+            .path_policy
+            .build(parts.uri.path())
+            .map(|path| self.base.join(path));
+        async move {
+            let mode = match parts.method {
+                Method::PUT if overwrite => Mode::CreateOrOverwrite,
+                Method::PUT | Method::POST => Mode::CreateNew,
+                _ => return Ok(method_not_allowed("PUT, POST")),
+            };
+            if expectation_failed(&parts.headers) {
+                return Ok(status_response(StatusCode::EXPECTATION_FAILED));
+            }
+            let Ok(path) = path else {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            };
+            let declared_len = parts
+                .headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+                .unwrap_or_else(|| body.size_hint().lower());
+            if declared_len > max_len {
+                return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE));
+            }
+            let checked = check_write_preconditions(
+                &mut inner,
+                path.clone(),
+                &parts.headers,
+                strong_etags.as_ref(),
+            );
+            if let Err(status) = checked.await {
+                return Ok(status_response(status));
+            }
+            let status = match upload(&mut inner, path, mode, body, max_len).await {
+                Ok(status) | Err(status) => status,
+            };
+            Ok(status_response(status))
+        }
+        .map(move |response| match response {
+            Ok(response) if problem_details => Ok(into_problem(response)),
+            response => response,
+        })
+        .boxed()
+    }
+}
+
+async fn upload<S, B>(
+    inner: &mut S,
+    path: PathBuf,
+    mode: Mode,
+    body: B,
+    max_len: u64,
+) -> Result<StatusCode, StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    B: Body,
+{
+    let existed = mode == Mode::CreateOrOverwrite && exists(inner, path.clone()).await?;
+    let success = if existed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let options = WriteOptions::new().mode(mode).max_len(max_len);
+    match write_body_to_file(body, inner, path, options).await {
+        Ok(_) => Ok(success),
+        Err(err) => Err(upload_error_status(&err)),
+    }
+}
+
+async fn exists<S>(inner: &mut S, path: PathBuf) -> Result<bool, StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    match call(inner, Request::Exists(path.into())).await {
+        Ok(Response::Exists(exists)) => Ok(exists),
+        Ok(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) => Err(write_error_status(&err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body::Full;
+
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn upload(
+        service: &mut AcceptUpload<FileSystem>,
+        method: Method,
+        uri: &str,
+        body: &'static str,
+    ) -> StatusCode {
+        let Ok(request) = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Full::new(Bytes::from(body)))
+        else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response.status(),
+            Err(never) => match never {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_upload() -> io::Result<()> {
+        let dir = TestDir::new("accept_upload")?;
+        let mut service = AcceptUpload::new(&dir, FileSystem::new()).max_len(8);
+
+        for (method, uri, body, status) in [
+            (Method::POST, "/new.txt", "first", StatusCode::CREATED),
+            (Method::PUT, "/new.txt", "second", StatusCode::CONFLICT),
+            (
+                Method::PUT,
+                "/big.txt",
+                "too long by far",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (Method::PUT, "/missing/new.txt", "", StatusCode::CONFLICT),
+            (Method::GET, "/new.txt", "", StatusCode::METHOD_NOT_ALLOWED),
+        ] {
+            assert_eq!(upload(&mut service, method, uri, body).await, status);
+        }
+        assert_eq!(std::fs::read_to_string(dir.join("new.txt"))?, "first");
+        assert!(!dir.join("big.txt").exists());
+
+        let mut service = service.overwrite(true);
+        let status = upload(&mut service, Method::PUT, "/new.txt", "second").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read_to_string(dir.join("new.txt"))?, "second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_if_match() -> io::Result<()> {
+        let dir = TestDir::new("if_match")?;
+        std::fs::write(dir.join("file.txt"), "first")?;
+        let strong_etags = crate::http::StrongETags::new(1024);
+        let mut serve =
+            crate::http::ServeDir::new(&dir, FileSystem::new()).strong_etags(strong_etags.clone());
+        let Ok(request) = http::Request::get("/file.txt").body(Full::new(Bytes::new())) else {
+            unreachable!("the test requests are valid")
+        };
+        let response = match serve.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+        let Some(etag) = response.headers().get(http::header::ETAG).cloned() else {
+            panic!("no ETag sent")
+        };
+
+        let put = |etag| {
+            let Ok(request) = http::Request::put("/file.txt")
+                .header(http::header::IF_MATCH, etag)
+                .body(Full::new(Bytes::from("second")))
+            else {
+                unreachable!("the test requests are valid")
+            };
+            request
+        };
+        // Weak tags never satisfy `If-Match`
+        let mut service = AcceptUpload::new(&dir, FileSystem::new()).overwrite(true);
+        let Ok(response) = service.call(put(etag.clone())).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let mut service = service.strong_etags(strong_etags);
+        let Ok(response) = service.call(put(etag.clone())).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read_to_string(dir.join("file.txt"))?, "second");
+        // The file has changed since the tag was sent
+        let Ok(response) = service.call(put(etag)).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        Ok(())
+    }
+
+    /// A body which records whether it was read, standing in for one the client hasn't sent yet
+    struct Unsent(Arc<AtomicBool>);
+
+    impl Body for Unsent {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> Poll<Option<Result<Bytes, Infallible>>> {
+            self.0.store(true, Ordering::Relaxed);
+            Poll::Ready(None)
+        }
+
+        fn poll_trailers(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> Poll<Result<Option<http::HeaderMap>, Infallible>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() -> io::Result<()> {
+        let dir = TestDir::new("expect_continue")?;
+        std::fs::write(dir.join("taken.txt"), "")?;
+        let mut service = AcceptUpload::new(&dir, FileSystem::new()).max_len(8);
+
+        for (uri, expect, len, status, read) in [
+            (
+                "/big.txt",
+                "100-continue",
+                "9",
+                StatusCode::PAYLOAD_TOO_LARGE,
+                false,
+            ),
+            (
+                "/taken.txt",
+                "100-continue",
+                "1",
+                StatusCode::CONFLICT,
+                false,
+            ),
+            (
+                "/new.txt",
+                "something-else",
+                "1",
+                StatusCode::EXPECTATION_FAILED,
+                false,
+            ),
+            ("/new.txt", "100-Continue", "0", StatusCode::CREATED, true),
+        ] {
+            let polled = Arc::new(AtomicBool::new(false));
+            let Ok(request) = http::Request::put(uri)
+                .header(http::header::EXPECT, expect)
+                .header(CONTENT_LENGTH, len)
+                .body(Unsent(polled.clone()))
+            else {
+                unreachable!("the test requests are valid")
+            };
+            let response = match service.call(request).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            assert_eq!(response.status(), status, "{uri}");
+            assert_eq!(polled.load(Ordering::Relaxed), read, "{uri}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() -> io::Result<()> {
+        let dir = TestDir::new("upload_policy")?;
+        let mut service = AcceptUpload::new(&dir, FileSystem::new())
+            .path_policy(PathPolicy::new().dotfiles(false));
+
+        let status = upload(&mut service, Method::PUT, "/.htaccess", "deny").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!dir.join(".htaccess").exists());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_problem_details() -> io::Result<()> {
+        let dir = TestDir::new("upload_problem")?;
+        let mut service = AcceptUpload::new(&dir, FileSystem::new())
+            .max_len(8)
+            .problem_details(true);
+
+        let Ok(request) =
+            http::Request::put("/big.txt").body(Full::new(Bytes::from("too long by far")))
+        else {
+            unreachable!("the test request is valid")
+        };
+        let Ok(response) = service.call(request).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            r#"{"type":"about:blank","title":"Payload Too Large","status":413}"#
+        );
+        let status = upload(&mut service, Method::PUT, "/new.txt", "new").await;
+        assert_eq!(status, StatusCode::CREATED);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! `multipart/byteranges` bodies, which answer requests for several ranges of a file at once
+
+use std::{
+    fmt::Write,
+    io::{self, ErrorKind, SeekFrom},
+    ops::RangeInclusive,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http::HeaderValue;
+use http_body::{Body, SizeHint};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use super::serve::random_token;
+
+const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// A `multipart/byteranges` body, sending several ranges of a seekable reader as the parts of a
+/// `206 Partial Content` response
+///
+/// Each part has a `Content-Range` header, and the `Content-Type` given to
+/// [`MultiRangeBody::content_type`] if any.  The response must be sent with the `Content-Type`
+/// returned by [`MultiRangeBody::multipart_content_type`], which names the boundary between parts,
+/// and can be sent with a `Content-Length` of [`MultiRangeBody::content_length`].
+///
+/// The ranges are sent in the order given, and must lie within the reader, as those returned by
+/// [`try_parse_range`](super::try_parse_range) do.
+#[derive(Debug)]
+pub struct MultiRangeBody<T> {
+    reader: T,
+    len: u64,
+    ranges: Vec<RangeInclusive<u64>>,
+    content_type: Option<String>,
+    boundary: String,
+    capacity: usize,
+    buffer: Vec<u8>,
+    /// The index of the next range to send
+    next: usize,
+    state: State,
+    sent: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// Sending the header of the next part, or the closing delimiter
+    Next,
+    /// Waiting for the reader to seek to a part, before sending `remaining` bytes
+    Seeking {
+        remaining: u64,
+    },
+    Reading {
+        remaining: u64,
+    },
+    Done,
+}
+
+impl<T> MultiRangeBody<T> {
+    /// A body sending `ranges` of `reader`, which is `len` bytes long
+    pub fn new(reader: T, len: u64, ranges: Vec<RangeInclusive<u64>>) -> Self {
+        Self {
+            reader,
+            len,
+            ranges,
+            content_type: None,
+            boundary: random_token(),
+            capacity: DEFAULT_CAPACITY,
+            buffer: Vec::new(),
+            next: 0,
+            state: State::Next,
+            sent: 0,
+        }
+    }
+
+    /// Sends `content_type` as the `Content-Type` of each part, unless it isn't visible ascii
+    #[must_use]
+    pub fn content_type(mut self, content_type: &HeaderValue) -> Self {
+        self.content_type = content_type.to_str().ok().map(str::to_owned);
+        self
+    }
+
+    /// Reads at most `capacity` bytes at a time
+    #[must_use]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// The `Content-Type` of the response, naming the boundary between parts
+    #[must_use]
+    pub fn multipart_content_type(&self) -> HeaderValue {
+        // The boundary is hex, so the value is always valid
+        HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", self.boundary))
+            .unwrap_or_else(|_| HeaderValue::from_static("multipart/byteranges"))
+    }
+
+    /// The length of the whole body
+    #[must_use]
+    pub fn content_length(&self) -> u64 {
+        let parts = self.ranges.iter().enumerate().map(|(index, range)| {
+            self.part_header(index, range).len() as u64
+                + (range.end() - range.start()).saturating_add(1)
+        });
+        parts.sum::<u64>() + self.closing_delimiter().len() as u64
+    }
+
+    fn part_header(&self, index: usize, range: &RangeInclusive<u64>) -> String {
+        // Delimiters after the first are preceded by a line break
+        let mut header = if index == 0 {
+            format!("--{}\r\n", self.boundary)
+        } else {
+            format!("\r\n--{}\r\n", self.boundary)
+        };
+        if let Some(content_type) = &self.content_type {
+            let _ = write!(header, "Content-Type: {content_type}\r\n");
+        }
+        let _ = write!(
+            header,
+            "Content-Range: bytes {}-{}/{}\r\n\r\n",
+            range.start(),
+            range.end(),
+            self.len
+        );
+        header
+    }
+
+    fn closing_delimiter(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    fn emit(&mut self, bytes: Bytes) -> Poll<Option<io::Result<Bytes>>> {
+        self.sent += bytes.len() as u64;
+        Poll::Ready(Some(Ok(bytes)))
+    }
+}
+
+impl<T> Body for MultiRangeBody<T>
+where
+    T: AsyncRead + AsyncSeek + Unpin,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            match this.state {
+                State::Next => {
+                    let Some(range) = this.ranges.get(this.next).cloned() else {
+                        this.state = State::Done;
+                        let closing = this.closing_delimiter();
+                        return this.emit(closing.into());
+                    };
+                    let header = this.part_header(this.next, &range);
+                    this.next += 1;
+                    Pin::new(&mut this.reader).start_seek(SeekFrom::Start(*range.start()))?;
+                    this.state = State::Seeking {
+                        remaining: (range.end() - range.start()).saturating_add(1),
+                    };
+                    return this.emit(header.into());
+                }
+                State::Seeking { remaining } => {
+                    ready!(Pin::new(&mut this.reader).poll_complete(cx))?;
+                    this.state = State::Reading { remaining };
+                }
+                State::Reading { remaining: 0 } => this.state = State::Next,
+                State::Reading { remaining } => {
+                    let len = usize::try_from(remaining)
+                        .map_or(this.capacity, |remaining| remaining.min(this.capacity));
+                    this.buffer.resize(len, 0);
+                    let mut buffer = ReadBuf::new(&mut this.buffer);
+                    ready!(Pin::new(&mut this.reader).poll_read(cx, &mut buffer))?;
+                    let read = buffer.filled().len();
+                    if read == 0 {
+                        this.state = State::Done;
+                        let err =
+                            io::Error::new(ErrorKind::UnexpectedEof, "range past end of file");
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    this.state = State::Reading {
+                        remaining: remaining - read as u64,
+                    };
+                    let chunk = Bytes::copy_from_slice(&this.buffer[..read]);
+                    return this.emit(chunk);
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.content_length().saturating_sub(self.sent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_multi_range_body() -> io::Result<()> {
+        let reader = Cursor::new(b"0123456789".to_vec());
+        let body = MultiRangeBody::new(reader, 10, vec![0..=1, 5..=9])
+            .content_type(&HeaderValue::from_static("text/plain"))
+            .capacity(3);
+        let boundary = body.boundary.clone();
+        assert_eq!(
+            body.multipart_content_type(),
+            format!("multipart/byteranges; boundary={boundary}").as_str()
+        );
+        let len = body.content_length();
+
+        let bytes = body.collect().await?.to_bytes();
+        let expected = format!(
+            "--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-9/10\r\n\r\n\
+             56789\r\n--{boundary}--\r\n"
+        );
+        assert_eq!(bytes, expected);
+        assert_eq!(len, bytes.len() as u64);
+
+        let reader = Cursor::new(b"short".to_vec());
+        let body = MultiRangeBody::new(reader, 10, vec![2..=9]);
+        assert!(body.collect().await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Choosing the `Cache-Control` of responses, by their path and `Content-Type`
+
+use std::time::{Duration, SystemTime};
+
+use http::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, EXPIRES},
+    HeaderMap, HeaderValue,
+};
+
+use crate::{date::DateTime, glob::glob_match};
+
+/// Assigns `Cache-Control` headers to files, such as caching fingerprinted assets forever while
+/// making browsers revalidate HTML
+///
+/// Rules are checked in the order they're added, and the first which matches gives the header.
+/// Path patterns are matched against the request path without it's leading `/`, where `*` matches
+/// any characters within a name and `**` any characters at all, so `assets/**` matches everything
+/// under `assets`.  If no rule matches the value given to [`CacheControl::fallback`] is used, if
+/// any.
+#[derive(Debug, Clone, Default)]
+pub struct CacheControl {
+    rules: Vec<(Matcher, HeaderValue)>,
+    fallback: Option<HeaderValue>,
+    expires: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Path(String),
+    /// A type and subtype, or a type followed by a `/`
+    ContentType(String),
+}
+
+impl CacheControl {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `value` for paths matching `pattern`
+    #[must_use]
+    pub fn path(mut self, pattern: &str, value: HeaderValue) -> Self {
+        let pattern = pattern.trim_start_matches('/').to_owned();
+        self.rules.push((Matcher::Path(pattern), value));
+        self
+    }
+
+    /// Sends `value` for files of `content_type`, which can be a type and subtype
+    /// (`text/html`), or a type followed by a `/` (`image/`) to match all of it's subtypes
+    #[must_use]
+    pub fn content_type(mut self, content_type: &str, value: HeaderValue) -> Self {
+        let content_type = content_type.trim().to_ascii_lowercase();
+        self.rules.push((Matcher::ContentType(content_type), value));
+        self
+    }
+
+    /// Sends `value` for files no rule matches
+    #[must_use]
+    pub fn fallback(mut self, value: HeaderValue) -> Self {
+        self.fallback = Some(value);
+        self
+    }
+
+    /// Also sends an `Expires` header for values with a `max-age`, for HTTP/1.0 caches
+    #[must_use]
+    pub fn expires(mut self, expires: bool) -> Self {
+        self.expires = expires;
+        self
+    }
+
+    /// The `Cache-Control` for the request `path` of a file of `content_type`
+    #[must_use]
+    pub fn value_for(
+        &self,
+        path: &str,
+        content_type: Option<&HeaderValue>,
+    ) -> Option<&HeaderValue> {
+        let path = path.trim_start_matches('/');
+        let essence = content_type
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(|content_type| {
+                let essence = content_type.split(';').next().unwrap_or_default();
+                essence.trim().to_ascii_lowercase()
+            });
+        let matches = |matcher: &Matcher| match matcher {
+            Matcher::Path(pattern) => glob_match(pattern.as_bytes(), path.as_bytes()),
+            Matcher::ContentType(pattern) => essence.as_ref().is_some_and(|essence| {
+                if pattern.ends_with('/') {
+                    essence.starts_with(pattern.as_str())
+                } else {
+                    essence == pattern
+                }
+            }),
+        };
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matches(matcher))
+            .map(|(_, value)| value)
+            .or(self.fallback.as_ref())
+    }
+
+    /// Inserts the `Cache-Control` (and `Expires`) for the request `path` into `headers`, using
+    /// the `Content-Type` already in them
+    pub(super) fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        let Some(value) = self.value_for(path, headers.get(CONTENT_TYPE)).cloned() else {
+            return;
+        };
+        if self.expires {
+            if let Some(max_age) = max_age(&value) {
+                let expires = DateTime::from(SystemTime::now() + max_age).http();
+                if let Ok(expires) = HeaderValue::try_from(expires) {
+                    headers.insert(EXPIRES, expires);
+                }
+            }
+        }
+        headers.insert(CACHE_CONTROL, value);
+    }
+}
+
+/// The `max-age` directive of a `Cache-Control` value
+fn max_age(value: &HeaderValue) -> Option<Duration> {
+    value.to_str().ok()?.split(',').find_map(|directive| {
+        let (name, secs) = directive.trim().split_once('=')?;
+        if !name.eq_ignore_ascii_case("max-age") {
+            return None;
+        }
+        secs.trim_matches('"').parse().ok().map(Duration::from_secs)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_control() {
+        let immutable = HeaderValue::from_static("public, max-age=31536000, immutable");
+        let no_cache = HeaderValue::from_static("no-cache");
+        let images = HeaderValue::from_static("max-age=3600");
+        let cache_control = CacheControl::new()
+            .path("/assets/**", immutable.clone())
+            .content_type("text/html", no_cache.clone())
+            .content_type("image/", images.clone())
+            .expires(true);
+        let html = HeaderValue::from_static("text/html; charset=utf-8");
+        let png = HeaderValue::from_static("image/png");
+
+        assert_eq!(
+            cache_control.value_for("/assets/index.html", Some(&html)),
+            Some(&immutable)
+        );
+        assert_eq!(
+            cache_control.value_for("/index.html", Some(&html)),
+            Some(&no_cache)
+        );
+        assert_eq!(cache_control.value_for("/a.png", Some(&png)), Some(&images));
+        assert_eq!(cache_control.value_for("/a.txt", None), None);
+        assert_eq!(
+            cache_control
+                .clone()
+                .fallback(no_cache.clone())
+                .value_for("/a.txt", None),
+            Some(&no_cache)
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, png);
+        cache_control.apply("/a.png", &mut headers);
+        assert_eq!(headers.get(CACHE_CONTROL), Some(&images));
+        assert!(headers.contains_key(EXPIRES));
+        assert_eq!(
+            max_age(&HeaderValue::from_static("private, max-age=\"90\"")),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(max_age(&no_cache), None);
+    }
+}
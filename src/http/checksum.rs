@@ -0,0 +1,159 @@
+//! Bodies which send a digest of their contents as a trailer, computed while streaming
+
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use http_body::{Body, SizeHint};
+use pin_project_lite::pin_project;
+
+use crate::digest::{base64, Crc32c, Sha256};
+
+/// A digest which can be sent in a trailer by a [`ChecksumBody`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// The name of the trailer the digest is sent in, which can be announced to clients in the
+    /// `Trailer` header
+    #[must_use]
+    pub fn trailer_name(self) -> HeaderName {
+        match self {
+            Self::Crc32c => HeaderName::from_static("x-checksum-crc32c"),
+            Self::Sha256 => HeaderName::from_static("x-checksum-sha256"),
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Crc32c => Hasher::Crc32c(Crc32c::default()),
+            Self::Sha256 => Hasher::Sha256(Sha256::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Hasher {
+    Crc32c(Crc32c),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Crc32c(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The base64 encoded digest, as sent by S3 and other object stores
+    fn finish(self) -> HeaderValue {
+        let digest = match self {
+            Self::Crc32c(hasher) => base64(&hasher.finish().to_be_bytes()),
+            Self::Sha256(hasher) => base64(&hasher.finish()),
+        };
+        // Base64 is always a valid header value
+        HeaderValue::try_from(digest).unwrap_or_else(|_| HeaderValue::from_static(""))
+    }
+}
+
+pin_project! {
+    /// A body which hashes the data of `inner` as it's sent, then sends the base64 encoded digest
+    /// in a trailer named by [`ChecksumAlgorithm::trailer_name`]
+    ///
+    /// Any trailers of `inner` are sent as well.  Trailers are only delivered over protocols which
+    /// support them, such as HTTP/2 or chunked HTTP/1.1 responses to clients sending `TE: trailers`.
+    #[derive(Debug)]
+    pub struct ChecksumBody<B> {
+        #[pin]
+        inner: B,
+        algorithm: ChecksumAlgorithm,
+        hasher: Option<Hasher>,
+    }
+}
+
+impl<B> ChecksumBody<B> {
+    pub fn new(inner: B, algorithm: ChecksumAlgorithm) -> Self {
+        Self {
+            inner,
+            algorithm,
+            hasher: Some(algorithm.hasher()),
+        }
+    }
+}
+
+impl<B> Body for ChecksumBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let data = ready!(this.inner.poll_data(cx));
+        if let (Some(Ok(data)), Some(hasher)) = (&data, this.hasher) {
+            hasher.update(data);
+        }
+        Poll::Ready(data)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.project();
+        let trailers = ready!(this.inner.poll_trailers(cx))?;
+        let Some(hasher) = this.hasher.take() else {
+            return Poll::Ready(Ok(trailers));
+        };
+        let mut trailers = trailers.unwrap_or_default();
+        trailers.insert(this.algorithm.trailer_name(), hasher.finish());
+        Poll::Ready(Ok(Some(trailers)))
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use crate::http::AsyncReadBody;
+
+    #[tokio::test]
+    async fn test_checksum_body() -> io::Result<()> {
+        for (algorithm, digest) in [
+            (ChecksumAlgorithm::Crc32c, "4waSgw=="),
+            (
+                ChecksumAlgorithm::Sha256,
+                "FeKw08M4keuw8e9gnsQZQgwg4yDOlMZfvIwzEkSOsiU=",
+            ),
+        ] {
+            let reader = Cursor::new(b"123456789".to_vec());
+            let body = AsyncReadBody::with_capacity(reader, 4).with_checksum(algorithm);
+            let collected = body.collect().await?;
+            let trailers = collected.trailers().cloned().unwrap_or_default();
+            assert_eq!(collected.to_bytes(), "123456789");
+            assert_eq!(
+                trailers
+                    .get(algorithm.trailer_name())
+                    .and_then(|value| value.to_str().ok()),
+                Some(digest)
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! How much of a file is read at a time when it's sent as a response body
+
+use std::{io, ops::RangeInclusive};
+
+use tokio::io::{AsyncRead, AsyncSeek, Take};
+
+use super::AsyncReadBody;
+use crate::BufferPool;
+
+/// How much of a file is read at a time, chosen by how much of it's sent
+///
+/// Up to [`ChunkSize::one_shot`] bytes are read into a single buffer of their length, so small
+/// files are sent as one chunk.  Anything longer is read in [`ChunkSize::chunk`] sized chunks, or
+/// by default into buffers from the [shared pool](BufferPool::shared).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSize {
+    one_shot: u64,
+    chunk: Option<usize>,
+}
+
+impl Default for ChunkSize {
+    fn default() -> Self {
+        Self {
+            one_shot: 256 * 1024,
+            chunk: None,
+        }
+    }
+}
+
+impl ChunkSize {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads bodies up to `max_len` bytes long in one go, which defaults to 256 KiB
+    #[must_use]
+    pub fn one_shot(mut self, max_len: u64) -> Self {
+        self.one_shot = max_len;
+        self
+    }
+
+    /// Reads longer bodies `chunk` bytes at a time, rather than into pooled buffers
+    #[must_use]
+    pub fn chunk(mut self, chunk: usize) -> Self {
+        self.chunk = Some(chunk.max(1));
+        self
+    }
+
+    /// The capacity read at a time for a body of `len` bytes, or `None` for pooled buffers
+    #[must_use]
+    pub fn for_len(&self, len: u64) -> Option<usize> {
+        if len <= self.one_shot {
+            Some(usize::try_from(len).unwrap_or(usize::MAX).max(1))
+        } else {
+            self.chunk
+        }
+    }
+
+    /// A body sending the `range` of `file`
+    pub(super) async fn body<T>(
+        &self,
+        file: T,
+        range: RangeInclusive<u64>,
+    ) -> io::Result<AsyncReadBody<Take<T>>>
+    where
+        T: AsyncRead + AsyncSeek + Unpin,
+    {
+        let len = (range.end() - range.start()).saturating_add(1);
+        match self.for_len(len) {
+            Some(capacity) => AsyncReadBody::with_range(file, capacity, range).await,
+            None => {
+                AsyncReadBody::with_pooled_range(file, BufferPool::shared().clone(), range).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_size() {
+        let default = ChunkSize::new();
+        assert_eq!(default.for_len(0), Some(1));
+        assert_eq!(default.for_len(1000), Some(1000));
+        assert_eq!(default.for_len(256 * 1024 + 1), None);
+        let chunked = default.one_shot(10).chunk(4);
+        assert_eq!(chunked.for_len(10), Some(10));
+        assert_eq!(chunked.for_len(11), Some(4));
+        assert_eq!(ChunkSize::new().chunk(0).for_len(u64::MAX), Some(1));
+    }
+}
@@ -0,0 +1,433 @@
+//! Conditional requests, which let clients revalidate their cached copies of files
+
+use std::{fmt, ops::RangeInclusive, time::SystemTime};
+
+use http::{
+    header::{
+        ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE,
+        LAST_MODIFIED, RANGE,
+    },
+    HeaderMap, HeaderValue, StatusCode,
+};
+
+use super::{
+    serve::{status_response, ResponseBody},
+    try_parse_range,
+};
+use crate::date::DateTime;
+
+/// An entity tag, identifying a version of a file
+///
+/// Strong tags promise the content is byte for byte identical whenever the tag is, while weak tags
+/// only promise it's equivalent, so they can't be used to combine ranges of different responses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ETag {
+    weak: bool,
+    tag: String,
+}
+
+impl ETag {
+    /// A strong tag, or `None` if `tag` contains characters which can't appear in a tag
+    pub fn strong<T: Into<String>>(tag: T) -> Option<Self> {
+        Self::new(false, tag.into())
+    }
+
+    /// A weak tag, or `None` if `tag` contains characters which can't appear in a tag
+    pub fn weak<T: Into<String>>(tag: T) -> Option<Self> {
+        Self::new(true, tag.into())
+    }
+
+    fn new(weak: bool, tag: String) -> Option<Self> {
+        tag.bytes()
+            .all(|byte| byte == 0x21 || (0x23..=0x7e).contains(&byte) || byte >= 0x80)
+            .then_some(Self { weak, tag })
+    }
+
+    /// A weak tag derived from a file's length and modification time, which can be generated
+    /// without reading the file
+    ///
+    /// The tag changes whenever the file is modified, unless it's length is unchanged and it's
+    /// modification time is restored.
+    #[must_use]
+    pub fn from_metadata(len: u64, modified: SystemTime) -> Self {
+        let since_epoch = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            weak: true,
+            tag: format!(
+                "{:x}.{:x}-{len:x}",
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos()
+            ),
+        }
+    }
+
+    /// Parses a single tag, as sent in the `ETag` header
+    #[must_use]
+    pub fn parse(value: &str) -> Option<Self> {
+        match parse_list(value.trim()) {
+            Some(mut tags) if tags.len() == 1 => tags.pop(),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// The tag, without quotes or the weak indicator
+    #[must_use]
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Whether both tags are strong and identical, as required when combining ranges
+    #[must_use]
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Whether the tags are identical, ignoring whether they're weak, as used to revalidate caches
+    #[must_use]
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        self.tag == other.tag
+    }
+
+    /// The tag as a header value
+    #[must_use]
+    pub fn to_header_value(&self) -> HeaderValue {
+        // Tags are checked to be visible ascii or obs-text when created, which are valid in headers
+        HeaderValue::from_maybe_shared(self.to_string())
+            .unwrap_or_else(|_| HeaderValue::from_static("\"\""))
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Parses a comma separated list of tags, as sent in `If-None-Match` and `If-Match`, returning
+/// `None` if the list is malformed
+pub(super) fn parse_list(value: &str) -> Option<Vec<ETag>> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return Some(tags);
+        }
+        let (weak, quoted) = match rest.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, rest),
+        };
+        let (tag, remainder) = quoted.strip_prefix('"')?.split_once('"')?;
+        tags.push(ETag::new(weak, tag.to_owned())?);
+        rest = remainder;
+        if !rest.is_empty() && !rest.starts_with([' ', '\t', ',']) {
+            return None;
+        }
+    }
+}
+
+/// Whether the `If-None-Match` header or `If-Modified-Since` header of a `GET` or `HEAD` request
+/// shows the client's cached copy of a file is current, so a `304 Not Modified` can be sent
+///
+/// `If-None-Match` takes precedence when both are sent, and uses weak comparison.  An unparsable
+/// header is ignored, as is `If-Modified-Since` when the modification time isn't known.  HTTP
+/// dates only have second precision, so `modified` is truncated to match.
+#[must_use]
+pub fn is_not_modified(
+    headers: &HeaderMap,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> bool {
+    if let Some(value) = headers.get(IF_NONE_MATCH) {
+        let Ok(value) = value.to_str() else {
+            return false;
+        };
+        if value.trim() == "*" {
+            return true;
+        }
+        return etag.is_some_and(|etag| {
+            parse_list(value).is_some_and(|tags| tags.iter().any(|tag| tag.weak_eq(etag)))
+        });
+    }
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|value| DateTime::parse_http(value.to_str().ok()?))
+        .map(SystemTime::from);
+    match (since, modified) {
+        (Some(since), Some(modified)) => SystemTime::from(DateTime::from(modified)) <= since,
+        _ => false,
+    }
+}
+
+/// Evaluates [`is_not_modified`], returning a `304 Not Modified` response carrying the validators
+/// if the client's copy is current
+///
+/// Only a file's metadata is needed, so servers can check this before opening the file.
+#[must_use]
+pub fn not_modified(
+    headers: &HeaderMap,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> Option<http::Response<ResponseBody>> {
+    if !is_not_modified(headers, etag, modified) {
+        return None;
+    }
+    let mut response = status_response(StatusCode::NOT_MODIFIED);
+    insert_validators(response.headers_mut(), etag, modified);
+    Some(response)
+}
+
+/// Whether the `If-Match` header or `If-Unmodified-Since` header of a request shows the file has
+/// changed since the client last saw it, so a `412 Precondition Failed` must be sent instead of
+/// changing it
+///
+/// `etag` and `modified` describe the current file, and are `None` if it doesn't exist.
+/// `If-Match` takes precedence when both are sent, and uses strong comparison, so weak tags (such
+/// as [`ETag::from_metadata`]'s) never match, though `*` matches any existing file.  An unparsable
+/// `If-Match` fails.  `If-Unmodified-Since` is ignored when it's unparsable or the modification
+/// time isn't known, and `modified` is truncated to the second to compare with it.
+#[must_use]
+pub fn precondition_failed(
+    headers: &HeaderMap,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> bool {
+    if let Some(value) = headers.get(IF_MATCH) {
+        let Ok(value) = value.to_str() else {
+            return true;
+        };
+        if value.trim() == "*" {
+            return etag.is_none();
+        }
+        return !etag.is_some_and(|etag| {
+            parse_list(value).is_some_and(|tags| tags.iter().any(|tag| tag.strong_eq(etag)))
+        });
+    }
+    let since = headers
+        .get(IF_UNMODIFIED_SINCE)
+        .and_then(|value| DateTime::parse_http(value.to_str().ok()?))
+        .map(SystemTime::from);
+    match (since, modified) {
+        (Some(since), Some(modified)) => SystemTime::from(DateTime::from(modified)) > since,
+        _ => false,
+    }
+}
+
+/// How to answer a request which may have a `Range` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeSelection {
+    /// Send the whole file with `200 OK`
+    Full,
+    /// Send these ranges with `206 Partial Content`, each of which can be read with
+    /// [`AsyncReadBody::with_range`](super::AsyncReadBody::with_range)
+    Partial(Vec<RangeInclusive<u64>>),
+    /// Respond with `416 Range Not Satisfiable`
+    Unsatisfiable,
+}
+
+impl RangeSelection {
+    /// The status of the response
+    #[must_use]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Full => StatusCode::OK,
+            Self::Partial(_) => StatusCode::PARTIAL_CONTENT,
+            Self::Unsatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+        }
+    }
+}
+
+/// Decides whether to respond to a request for a file of `len` bytes with the ranges in it's
+/// `Range` header, or the whole file
+///
+/// The whole file is sent when no range is requested, and when an `If-Range` header shows the
+/// client's partial copy is of a different version of the file, so it can't be resumed.  An
+/// `If-Range` tag only matches a strong `etag`, and an `If-Range` date only matches `modified`
+/// exactly, to the second.  Without either validator `If-Range` can't be evaluated, so the whole
+/// file is sent.
+#[must_use]
+pub fn select_ranges(
+    headers: &HeaderMap,
+    len: u64,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> RangeSelection {
+    let Some(range) = headers.get(RANGE) else {
+        return RangeSelection::Full;
+    };
+    if !if_range_matches(headers, etag, modified) {
+        return RangeSelection::Full;
+    }
+    match range
+        .to_str()
+        .ok()
+        .and_then(|range| try_parse_range(range, len).ok())
+    {
+        Some(ranges) => RangeSelection::Partial(ranges),
+        None => RangeSelection::Unsatisfiable,
+    }
+}
+
+/// Whether the `If-Range` header, if any, matches the current version of the file
+fn if_range_matches(
+    headers: &HeaderMap,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> bool {
+    let Some(value) = headers.get(IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    if let Some(tag) = ETag::parse(value) {
+        return etag.is_some_and(|etag| tag.strong_eq(etag));
+    }
+    DateTime::parse_http(value).is_some_and(|date| modified.map(DateTime::from) == Some(date))
+}
+
+/// Adds the `ETag` and `Last-Modified` headers describing a file
+pub(super) fn insert_validators(
+    headers: &mut HeaderMap,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) {
+    if let Some(etag) = etag {
+        headers.insert(ETAG, etag.to_header_value());
+    }
+    if let Some(modified) = modified {
+        if let Ok(value) = HeaderValue::from_str(&DateTime::from(modified).http()) {
+            headers.insert(LAST_MODIFIED, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let tags = parse_list(r#""a", W/"b",W/"c""#);
+        let expected = [ETag::strong("a"), ETag::weak("b"), ETag::weak("c")];
+        assert_eq!(tags, expected.into_iter().collect());
+        assert_eq!(parse_list(r#""a"b"#), None);
+        assert_eq!(parse_list(r#""a"#), None);
+        assert_eq!(ETag::parse(r#" W/"x" "#), ETag::weak("x"));
+        assert_eq!(ETag::strong("a\"b"), None);
+
+        let etag = ETag::from_metadata(16, SystemTime::UNIX_EPOCH + Duration::new(255, 1));
+        assert_eq!(etag.to_string(), r#"W/"ff.1-10""#);
+        assert_eq!(ETag::parse(&etag.to_string()), Some(etag));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let etag = ETag::from_metadata(3, modified);
+        let check = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+            is_not_modified(&headers, Some(&etag), Some(modified))
+        };
+
+        assert!(check(IF_NONE_MATCH, &format!(r#""other", {etag}"#)));
+        assert!(check(IF_NONE_MATCH, &format!("\"{}\"", etag.tag())));
+        assert!(check(IF_NONE_MATCH, "*"));
+        assert!(!check(IF_NONE_MATCH, r#""other""#));
+        assert!(check(IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!check(IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:36 GMT"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static(r#""other""#));
+        headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        assert!(not_modified(&headers, Some(&etag), Some(modified)).is_none());
+        headers.remove(IF_NONE_MATCH);
+        let response = not_modified(&headers, Some(&etag), Some(modified));
+        assert_eq!(
+            response.and_then(|response| response.headers().get(ETAG).cloned()),
+            Some(etag.to_header_value())
+        );
+    }
+
+    #[test]
+    fn test_precondition_failed() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let strong = ETag::strong("v1");
+        let check = |name, value: &'static str, etag: Option<&ETag>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            precondition_failed(&headers, etag, etag.map(|_| modified))
+        };
+
+        assert!(!check(IF_MATCH, r#""v0", "v1""#, strong.as_ref()));
+        assert!(check(IF_MATCH, r#""v2""#, strong.as_ref()));
+        assert!(check(IF_MATCH, r#"W/"v1""#, ETag::weak("v1").as_ref()));
+        assert!(!check(IF_MATCH, "*", strong.as_ref()));
+        assert!(check(IF_MATCH, "*", None));
+        assert!(check(IF_MATCH, r#""v1"#, strong.as_ref()));
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(!check(IF_UNMODIFIED_SINCE, date, strong.as_ref()));
+        assert!(!check(IF_UNMODIFIED_SINCE, date, None));
+        let date = "Sun, 06 Nov 1994 08:49:36 GMT";
+        assert!(check(IF_UNMODIFIED_SINCE, date, strong.as_ref()));
+        assert!(!precondition_failed(&HeaderMap::new(), None, None));
+    }
+
+    #[test]
+    fn test_select_ranges() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        let strong = ETag::strong("v1");
+        let select = |if_range: Option<&'static str>, etag: Option<&ETag>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, HeaderValue::from_static("bytes=2-"));
+            if let Some(if_range) = if_range {
+                headers.insert(IF_RANGE, HeaderValue::from_static(if_range));
+            }
+            select_ranges(&headers, 4, etag, Some(modified))
+        };
+
+        let partial = RangeSelection::Partial(vec![2..=3]);
+        assert_eq!(select(None, None), partial);
+        assert_eq!(select(Some(r#""v1""#), strong.as_ref()), partial);
+        assert_eq!(
+            select(Some(r#""v2""#), strong.as_ref()),
+            RangeSelection::Full
+        );
+        let weak = ETag::weak("v1");
+        assert_eq!(
+            select(Some(r#"W/"v1""#), weak.as_ref()),
+            RangeSelection::Full
+        );
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(select(Some(date), None), partial);
+        let date = "Sun, 06 Nov 1994 08:49:36 GMT";
+        assert_eq!(select(Some(date), None), RangeSelection::Full);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_static("bytes=4-"));
+        let selection = select_ranges(&headers, 4, None, None);
+        assert_eq!(selection.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            select_ranges(&HeaderMap::new(), 4, None, None),
+            RangeSelection::Full
+        );
+    }
+}
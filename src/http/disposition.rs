@@ -0,0 +1,135 @@
+//! `Content-Disposition` headers, telling browsers whether to display or download a file
+
+use std::{fmt::Write, path::Path};
+
+use http::HeaderValue;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// The characters which must be percent encoded in an extended parameter value (RFC 5987's
+/// `attr-char`s are left alone)
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// Whether a file should be displayed by the browser, or downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Inline,
+    Attachment,
+}
+
+impl Disposition {
+    /// The disposition for files of `content_type`
+    ///
+    /// Plain text, PDFs, and images, audio and video are displayed, since browsers show them
+    /// without running any scripts they contain.  Everything else is downloaded, including HTML and
+    /// SVG, which could run scripts with the site's origin.
+    #[must_use]
+    pub fn for_content_type(content_type: &HeaderValue) -> Self {
+        let Ok(content_type) = content_type.to_str() else {
+            return Self::Attachment;
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let inline = match essence.split_once('/') {
+            Some(("image", subtype)) => subtype != "svg+xml",
+            Some(("audio" | "video", _)) => true,
+            _ => essence == "text/plain" || essence == "application/pdf",
+        };
+        if inline {
+            Self::Inline
+        } else {
+            Self::Attachment
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Inline => "inline",
+            Self::Attachment => "attachment",
+        }
+    }
+}
+
+/// Builds a `Content-Disposition` header with the file name of `path`
+///
+/// Names which aren't plain ascii are sent in an RFC 5987 `filename*` parameter, along with an
+/// ascii `filename` for older clients, with other characters replaced by `_`.  Paths without a
+/// file name give a header without one.
+#[must_use]
+pub fn content_disposition(disposition: Disposition, path: &Path) -> HeaderValue {
+    let Some(name) = path.file_name() else {
+        return HeaderValue::from_static(disposition.as_str());
+    };
+    let name = name.to_string_lossy();
+    let fallback = name
+        .chars()
+        .map(|char| match char {
+            ' ' => ' ',
+            '"' | '\\' => '_',
+            char if char.is_ascii_graphic() => char,
+            _ => '_',
+        })
+        .collect::<String>();
+    let mut value = format!("{}; filename=\"{fallback}\"", disposition.as_str());
+    if fallback != name {
+        let encoded = utf8_percent_encode(&name, ATTR_CHAR);
+        let _ = write!(value, "; filename*=UTF-8''{encoded}");
+    }
+    // Both parameters are visible ascii
+    HeaderValue::try_from(value).unwrap_or_else(|_| HeaderValue::from_static(disposition.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_disposition() {
+        let header = |path: &str| content_disposition(Disposition::Attachment, Path::new(path));
+        assert_eq!(
+            header("/files/report 2024.pdf"),
+            "attachment; filename=\"report 2024.pdf\""
+        );
+        assert_eq!(
+            header("résumé \"final\".txt"),
+            "attachment; filename=\"r_sum_ _final_.txt\"; \
+             filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.txt"
+        );
+        assert_eq!(header("/"), "attachment");
+        assert_eq!(
+            content_disposition(Disposition::Inline, Path::new("a.png")),
+            "inline; filename=\"a.png\""
+        );
+    }
+
+    #[test]
+    fn test_for_content_type() {
+        for (content_type, disposition) in [
+            ("image/png", Disposition::Inline),
+            ("text/plain; charset=utf-8", Disposition::Inline),
+            ("Application/PDF", Disposition::Inline),
+            ("video/mp4", Disposition::Inline),
+            ("image/svg+xml", Disposition::Attachment),
+            ("text/html; charset=utf-8", Disposition::Attachment),
+            ("application/octet-stream", Disposition::Attachment),
+        ] {
+            let content_type = HeaderValue::from_static(content_type);
+            assert_eq!(Disposition::for_content_type(&content_type), disposition);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Mapping the errors of an inner `Service<Request>` to HTTP responses
+
+use std::io::{self, ErrorKind};
+
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderValue, StatusCode,
+};
+use http_body::Body;
+
+use super::{manage_api::json_string, serve::full_body, ResponseBody};
+
+/// The status to respond with when the inner service fails to read a file
+///
+/// Missing files, and paths through or to the wrong kind of entry, are a `404 Not Found`, and
+/// denied permissions a `403 Forbidden`.  Everything else is a `500 Internal Server Error`.
+#[must_use]
+pub fn error_status(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::IsADirectory => {
+            StatusCode::NOT_FOUND
+        }
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// The status to respond with when the inner service fails to change a file
+///
+/// Changes which conflict with the existing files, such as creating a file which exists or one in
+/// a missing directory, are a `409 Conflict`.  Backends which can't be written to give a
+/// `405 Method Not Allowed`, files which are too large a `413 Payload Too Large`, and full storage
+/// or exceeded quotas a `507 Insufficient Storage`.  Anything else is mapped as by
+/// [`error_status`].
+#[must_use]
+pub fn write_error_status(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        ErrorKind::AlreadyExists
+        | ErrorKind::NotFound
+        | ErrorKind::NotADirectory
+        | ErrorKind::IsADirectory
+        | ErrorKind::DirectoryNotEmpty => StatusCode::CONFLICT,
+        ErrorKind::Unsupported | ErrorKind::ReadOnlyFilesystem => StatusCode::METHOD_NOT_ALLOWED,
+        ErrorKind::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+        _ => error_status(err),
+    }
+}
+
+/// A `application/problem+json` response with `status`, as described by RFC 9457
+///
+/// The problem's `title` is the status' reason phrase, and `detail` is included if given.  The
+/// messages of errors from the inner service can reveal paths on the server, so they're best
+/// left out of responses to untrusted clients.
+#[must_use]
+pub fn problem_response(status: StatusCode, detail: Option<&str>) -> http::Response<ResponseBody> {
+    let title = status.canonical_reason().unwrap_or("Unknown Error");
+    let mut json = format!(
+        r#"{{"type":"about:blank","title":{},"status":{}"#,
+        json_string(title),
+        status.as_u16()
+    );
+    if let Some(detail) = detail {
+        json.push_str(r#","detail":"#);
+        json.push_str(&json_string(detail));
+    }
+    json.push('}');
+
+    let mut response = http::Response::new(full_body(json));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/problem+json"),
+    );
+    response
+}
+
+/// The detail to give the problem made of an error response by [`into_problem`]
+#[derive(Debug, Clone)]
+pub(super) struct ProblemDetail(pub(super) String);
+
+/// Gives an error `response` the body of a [`problem_response`] with it's status, as the services
+/// of this module do once problem details are enabled
+///
+/// The problem's `detail` is taken from the response's [`ProblemDetail`] extension, if it has
+/// one.  Otherwise responses which already have a body, such as error pages, are left alone.
+/// Other headers, such as `Allow`, are kept.
+pub(super) fn into_problem(
+    mut response: http::Response<ResponseBody>,
+) -> http::Response<ResponseBody> {
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+    let detail = response.extensions_mut().remove::<ProblemDetail>();
+    if detail.is_none() && !response.body().is_end_stream() {
+        return response;
+    }
+    let detail = detail.as_ref().map(|ProblemDetail(detail)| detail.as_str());
+    let (parts, body) = problem_response(status, detail).into_parts();
+    let headers = response.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    headers.extend(parts.headers);
+    *response.body_mut() = body;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::ALLOW;
+
+    use super::*;
+    use crate::http::serve::{method_not_allowed, status_response};
+
+    #[tokio::test]
+    async fn test_problem_response() -> io::Result<()> {
+        let err = io::Error::from(ErrorKind::QuotaExceeded);
+        assert_eq!(error_status(&err), StatusCode::INTERNAL_SERVER_ERROR);
+        let status = write_error_status(&err);
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+
+        let response = problem_response(status, Some("quota \"home\" exceeded"));
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            r#"{"type":"about:blank","title":"Insufficient Storage","status":507,"detail":"quota \"home\" exceeded"}"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_into_problem() -> io::Result<()> {
+        let response = into_problem(method_not_allowed("MKCOL"));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "MKCOL");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            r#"{"type":"about:blank","title":"Method Not Allowed","status":405}"#
+        );
+
+        let mut response = status_response(StatusCode::CONFLICT);
+        response
+            .extensions_mut()
+            .insert(ProblemDetail("parent directory is missing".to_owned()));
+        assert_eq!(
+            into_problem(response)
+                .into_body()
+                .collect()
+                .await?
+                .to_bytes(),
+            r#"{"type":"about:blank","title":"Conflict","status":409,"detail":"parent directory is missing"}"#
+        );
+
+        let response = into_problem(status_response(StatusCode::NO_CONTENT));
+        assert!(!response.headers().contains_key(CONTENT_TYPE));
+        let mut page = http::Response::new(full_body("<h1>Not Found</h1>"));
+        *page.status_mut() = StatusCode::NOT_FOUND;
+        let page = into_problem(page);
+        assert!(!page.headers().contains_key(CONTENT_TYPE));
+        assert_eq!(
+            page.into_body().collect().await?.to_bytes(),
+            "<h1>Not Found</h1>"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    io::{self, ErrorKind},
+    path::PathBuf,
+    task::Poll,
+    time::UNIX_EPOCH,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use http::{
+    header::{ALLOW, CONTENT_TYPE},
+    HeaderValue, Method, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use tower_service::Service;
+
+use super::{
+    accept_delete::remove,
+    error::{error_status, into_problem, write_error_status, ProblemDetail},
+    path_policy::PathPolicy,
+    serve::{call, full_body, status_response, ResponseBody},
+};
+use crate::{Metadata, Request, Response};
+
+/// A JSON API for managing the files under a directory of an inner `Service<Request>`, for admin
+/// dashboards and scripts
+///
+/// The request path (and the path renamed to) is validated with the [`PathPolicy`] given to
+/// [`ManageApi::path_policy`] and joined onto `base`, and the operation is chosen by the method
+/// and the `op` query parameter:
+/// - `GET ?op=stat` (the default) responds with the type, length, read only flag and modification
+///   time (in seconds since the unix epoch, or `null`) of the path, like
+///   `{"type":"file","len":5,"readonly":false,"modified":1700000000}`
+/// - `GET ?op=exists` responds with `{"exists":true}` or `{"exists":false}`
+/// - `GET ?op=list` responds with the entries of a directory, sorted by name, each with it's name
+///   and the fields of `stat`, like `{"entries":[{"name":"a.txt","type":"file",...}]}`
+/// - `GET ?op=du` responds with the disk usage of a file, or the total length of the files below
+///   a directory, like `{"bytes":5}`
+/// - `POST ?op=mkdir` creates a directory, along with it's parents if `recursive=true` is given,
+///   and responds with `201 Created`
+/// - `POST ?op=rename&to=/new/path` renames the path within `base`, responding with
+///   `204 No Content`
+/// - `DELETE` removes a file, or a directory if it's empty or `recursive=true` is given,
+///   responding with `204 No Content`
+///
+/// Failures respond with a status like the other services in this module and a body like
+/// `{"error":"entity not found"}`, or `400 Bad Request` for unknown operations and missing
+/// parameters.  Reads from inner services without metadata, or which can't list directories,
+/// respond with `501 Not Implemented`.  Once [`ManageApi::problem_details`] is enabled the body
+/// is an `application/problem+json` one instead, with the message as it's `detail`.
+#[derive(Debug, Clone)]
+pub struct ManageApi<S> {
+    base: PathBuf,
+    inner: S,
+    path_policy: PathPolicy,
+    problem_details: bool,
+}
+
+impl<S> ManageApi<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            path_policy: PathPolicy::default(),
+            problem_details: false,
+        }
+    }
+
+    /// Validates request paths, and the paths renamed to, with `path_policy`, answering those it
+    /// rejects with a `404 Not Found` or `400 Bad Request` respectively
+    #[must_use]
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        self.path_policy = path_policy;
+        self
+    }
+
+    /// Answers failures with `application/problem+json` bodies, as described by RFC 9457, rather
+    /// than `{"error":...}` ones
+    ///
+    /// The messages of errors from the inner service are kept as the problem's `detail`.
+    #[must_use]
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        self.problem_details = problem_details;
+        self
+    }
+
+    /// An `OpenAPI` 3 document describing the API, as served from `server_url`
+    #[cfg(feature = "openapi")]
+    #[must_use]
+    pub fn openapi(server_url: &str) -> String {
+        include_str!("manage_api.json")
+            .replace("\"{server_url}\"", &json_string(server_url))
+            .replace("{version}", env!("CARGO_PKG_VERSION"))
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for ManageApi<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let (parts, _) = req.into_parts();
+        let (path_policy, problem_details) = (self.path_policy, self.problem_details);
+        let path = path_policy
+            .build(parts.uri.path())
+            .map(|path| self.base.join(path));
+        let query = parts.uri.query().map(parse_query).unwrap_or_default();
+        let base = self.base.clone();
+        async move {
+            let Ok(path) = path else {
+                return Ok(error_response(StatusCode::NOT_FOUND, "invalid path"));
+            };
+            let param = |name: &str| {
+                query
+                    .iter()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.as_str())
+            };
+            let recursive = param("recursive") == Some("true");
+            Ok(match (&parts.method, param("op")) {
+                (&Method::GET, None | Some("stat")) => stat(&mut inner, path).await,
+                (&Method::GET, Some("exists")) => {
+                    match call(&mut inner, Request::Exists(path.into())).await {
+                        Ok(Response::Exists(exists)) => {
+                            json_response(&format!(r#"{{"exists":{exists}}}"#))
+                        }
+                        Ok(_) => unexpected_response(),
+                        Err(err) => read_failure(&err),
+                    }
+                }
+                (&Method::GET, Some("du")) => usage(&mut inner, path).await,
+                (&Method::GET, Some("list")) => list(&mut inner, path).await,
+                (&Method::POST, Some("mkdir")) => {
+                    let req = Request::CreateDir {
+                        path: path.into(),
+                        recursive,
+                    };
+                    changed(call(&mut inner, req).await, StatusCode::CREATED)
+                }
+                (&Method::POST, Some("rename")) => {
+                    match param("to").map(|to| path_policy.build(to)) {
+                        Some(Ok(to)) => {
+                            let req = Request::Rename {
+                                from: path.into(),
+                                to: base.join(to).into(),
+                            };
+                            changed(call(&mut inner, req).await, StatusCode::NO_CONTENT)
+                        }
+                        Some(Err(_)) => {
+                            error_response(StatusCode::BAD_REQUEST, "invalid path to rename to")
+                        }
+                        None => {
+                            error_response(StatusCode::BAD_REQUEST, "missing path to rename to")
+                        }
+                    }
+                }
+                (&Method::DELETE, None) if path == base => {
+                    error_response(StatusCode::FORBIDDEN, "the base directory can't be removed")
+                }
+                (&Method::DELETE, None) => changed(
+                    remove(&mut inner, path, recursive)
+                        .await
+                        .map(|()| Response::Done),
+                    StatusCode::NO_CONTENT,
+                ),
+                (&Method::GET | &Method::POST | &Method::DELETE, _) => {
+                    error_response(StatusCode::BAD_REQUEST, "unknown operation")
+                }
+                _ => {
+                    let mut response =
+                        error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+                    response
+                        .headers_mut()
+                        .insert(ALLOW, HeaderValue::from_static("GET, POST, DELETE"));
+                    response
+                }
+            })
+        }
+        .map(move |response| match response {
+            Ok(response) if problem_details => Ok(into_problem(response)),
+            response => response,
+        })
+        .boxed()
+    }
+}
+
+async fn metadata<S>(inner: &mut S, path: PathBuf) -> io::Result<Metadata>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let req = Request::GetMetadata {
+        path: path.into(),
+        follow_symlinks: false,
+    };
+    match call(inner, req).await? {
+        Response::Metadata(metadata) => Ok(metadata),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "unexpected response from inner service",
+        )),
+    }
+}
+
+async fn stat<S>(inner: &mut S, path: PathBuf) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    match metadata(inner, path).await {
+        Ok(metadata) => json_response(&format!("{{{}}}", stat_fields(&metadata))),
+        Err(err) => read_failure(&err),
+    }
+}
+
+async fn list<S>(inner: &mut S, path: PathBuf) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let entries = match call(inner, Request::ReadDir(path.into())).await {
+        Ok(Response::Directory(entries)) => entries,
+        Ok(_) => return unexpected_response(),
+        Err(err) => return read_failure(&err),
+    };
+    let entries = entries
+        .iter()
+        .map(|(name, metadata)| {
+            let name = json_string(&name.to_string_lossy());
+            format!(r#"{{"name":{name},{}}}"#, stat_fields(metadata))
+        })
+        .collect::<Vec<_>>();
+    json_response(&format!(r#"{{"entries":[{}]}}"#, entries.join(",")))
+}
+
+/// The fields `stat` responds with, without the braces around them
+fn stat_fields(metadata: &Metadata) -> String {
+    let ty = if metadata.is_dir() {
+        "dir"
+    } else if metadata.is_symlink() {
+        "symlink"
+    } else {
+        "file"
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(
+            || "null".to_owned(),
+            |elapsed| elapsed.as_secs().to_string(),
+        );
+    format!(
+        r#""type":"{ty}","len":{},"readonly":{},"modified":{modified}"#,
+        metadata.len(),
+        metadata.readonly(),
+    )
+}
+
+async fn usage<S>(inner: &mut S, path: PathBuf) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let bytes = match metadata(inner, path.clone()).await {
+        Ok(metadata) if metadata.is_dir() => dir_usage(inner, path).await,
+        Ok(metadata) => Ok(metadata.len()),
+        Err(err) => Err(err),
+    };
+    match bytes {
+        Ok(bytes) => json_response(&format!(r#"{{"bytes":{bytes}}}"#)),
+        Err(err) => read_failure(&err),
+    }
+}
+
+/// The total length of the files below the directory at `path`, without following symlinks
+async fn dir_usage<S>(inner: &mut S, path: PathBuf) -> io::Result<u64>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut bytes = 0;
+    let mut dirs = vec![path];
+    while let Some(dir) = dirs.pop() {
+        let listing = call(inner, Request::ReadDir(dir.as_path().into())).await?;
+        for (name, metadata) in listing.into_directory()? {
+            if metadata.is_dir() {
+                dirs.push(dir.join(name));
+            } else if metadata.is_file() {
+                bytes += metadata.len();
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Responds to a request which failed to read the files
+fn read_failure(err: &io::Error) -> http::Response<ResponseBody> {
+    let status = if err.kind() == ErrorKind::Unsupported {
+        StatusCode::NOT_IMPLEMENTED
+    } else {
+        error_status(err)
+    };
+    error_response(status, &err.to_string())
+}
+
+/// Responds to a request which changed the files, with `status` on success
+fn changed(result: io::Result<Response>, status: StatusCode) -> http::Response<ResponseBody> {
+    match result {
+        Ok(_) => status_response(status),
+        Err(err) => error_response(write_error_status(&err), &err.to_string()),
+    }
+}
+
+/// Splits a query string into it's percent decoded keys and values
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |part: &str| {
+                percent_decode_str(&part.replace('+', " "))
+                    .decode_utf8_lossy()
+                    .into_owned()
+            };
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn json_response(json: &str) -> http::Response<ResponseBody> {
+    let mut response = http::Response::new(full_body(json.to_owned()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error_response(status: StatusCode, message: &str) -> http::Response<ResponseBody> {
+    let mut response = json_response(&format!(r#"{{"error":{}}}"#, json_string(message)));
+    *response.status_mut() = status;
+    response
+        .extensions_mut()
+        .insert(ProblemDetail(message.to_owned()));
+    response
+}
+
+fn unexpected_response() -> http::Response<ResponseBody> {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "unexpected response from inner service",
+    )
+}
+
+/// Quotes `value` as a JSON string
+pub(super) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body::Body;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn send(
+        service: &mut ManageApi<FileSystem>,
+        method: Method,
+        uri: &str,
+    ) -> (StatusCode, String) {
+        let Ok(request) = http::Request::builder().method(method).uri(uri).body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        let response = match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+        let status = response.status();
+        let mut body = response.into_body();
+        let mut json = Vec::new();
+        while let Some(Ok(chunk)) = body.data().await {
+            json.extend_from_slice(&chunk);
+        }
+        (status, String::from_utf8_lossy(&json).into_owned())
+    }
+
+    #[tokio::test]
+    async fn test_manage_api() -> io::Result<()> {
+        let dir = TestDir::new("manage_api")?;
+        std::fs::write(dir.join("file.txt"), "hello")?;
+        let mut service = ManageApi::new(&dir, FileSystem::new());
+
+        let (status, json) = send(&mut service, Method::GET, "/file.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(json.starts_with(r#"{"type":"file","len":5,"readonly":false,"modified":"#));
+        for (method, uri, status, json) in [
+            (
+                Method::GET,
+                "/file.txt?op=exists",
+                StatusCode::OK,
+                r#"{"exists":true}"#,
+            ),
+            (
+                Method::GET,
+                "/file.txt?op=du",
+                StatusCode::OK,
+                r#"{"bytes":5}"#,
+            ),
+            (
+                Method::POST,
+                "/a/b?op=mkdir",
+                StatusCode::CONFLICT,
+                "{\"error\":",
+            ),
+            (
+                Method::POST,
+                "/a/b?op=mkdir&recursive=true",
+                StatusCode::CREATED,
+                "",
+            ),
+            (
+                Method::POST,
+                "/file.txt?op=rename&to=a%2Fmoved.txt",
+                StatusCode::NO_CONTENT,
+                "",
+            ),
+            (
+                Method::GET,
+                "/file.txt?op=exists",
+                StatusCode::OK,
+                r#"{"exists":false}"#,
+            ),
+            (
+                Method::GET,
+                "/a?op=list",
+                StatusCode::OK,
+                r#"{"entries":[{"name":"b","type":"dir","len":"#,
+            ),
+            (Method::GET, "/a?op=du", StatusCode::OK, r#"{"bytes":5}"#),
+            (
+                Method::GET,
+                "/a/moved.txt?op=list",
+                StatusCode::NOT_FOUND,
+                "{\"error\":",
+            ),
+            (
+                Method::GET,
+                "/a?op=chmod",
+                StatusCode::BAD_REQUEST,
+                "{\"error\":",
+            ),
+            (Method::DELETE, "/a", StatusCode::CONFLICT, "{\"error\":"),
+            (
+                Method::DELETE,
+                "/a?recursive=true",
+                StatusCode::NO_CONTENT,
+                "",
+            ),
+            (Method::DELETE, "/", StatusCode::FORBIDDEN, "{\"error\":"),
+            (
+                Method::PUT,
+                "/a",
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{\"error\":",
+            ),
+        ] {
+            let response = send(&mut service, method, uri).await;
+            assert_eq!(response.0, status, "{uri}");
+            assert!(response.1.starts_with(json), "{uri}: {}", response.1);
+        }
+        assert!(!dir.join("a").exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "openapi")]
+    #[test]
+    fn test_openapi() {
+        let document = ManageApi::<FileSystem>::openapi("https://example.com/files");
+        assert!(document.contains(r#"[{ "url": "https://example.com/files" }]"#));
+        assert!(!document.contains("{version}"));
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() -> io::Result<()> {
+        let dir = TestDir::new("manage_policy")?;
+        std::fs::write(dir.join("file.txt"), "hello")?;
+        let mut service =
+            ManageApi::new(&dir, FileSystem::new()).path_policy(PathPolicy::new().dotfiles(false));
+
+        let (status, _) = send(&mut service, Method::POST, "/.git?op=mkdir").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let uri = "/file.txt?op=rename&to=.hidden";
+        let (status, _) = send(&mut service, Method::POST, uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(dir.join("file.txt").exists());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_problem_details() -> io::Result<()> {
+        let dir = TestDir::new("manage_problem")?;
+        let mut service = ManageApi::new(&dir, FileSystem::new()).problem_details(true);
+
+        let (status, json) = send(&mut service, Method::GET, "/?op=chmod").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json,
+            r#"{"type":"about:blank","title":"Bad Request","status":400,"detail":"unknown operation"}"#
+        );
+        let Ok(request) = http::Request::put("/").body(()) else {
+            unreachable!("the test request is valid")
+        };
+        let Ok(response) = service.call(request).await;
+        assert_eq!(response.headers()[ALLOW], "GET, POST, DELETE");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let (status, json) = send(&mut service, Method::GET, "/?op=exists").await;
+        assert_eq!(
+            (status, json.as_str()),
+            (StatusCode::OK, r#"{"exists":true}"#)
+        );
+        Ok(())
+    }
+}
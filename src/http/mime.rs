@@ -0,0 +1,274 @@
+//! Choosing the `Content-Type` of files, from their extension or their first bytes
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use http::HeaderValue;
+use tower_service::Service;
+
+use super::serve::call;
+use crate::{Request, Response};
+
+/// How many bytes are read from the start of a file to sniff it's type
+pub const SNIFF_LEN: usize = 512;
+
+/// Extensions of common web files, and their types
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("7z", "application/x-7z-compressed"),
+    ("aac", "audio/aac"),
+    ("apng", "image/apng"),
+    ("avif", "image/avif"),
+    ("bin", "application/octet-stream"),
+    ("bmp", "image/bmp"),
+    ("br", "application/x-brotli"),
+    ("bz2", "application/x-bzip2"),
+    ("css", "text/css; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("epub", "application/epub+zip"),
+    ("flac", "audio/flac"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/x-icon"),
+    ("ics", "text/calendar; charset=utf-8"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("m4a", "audio/mp4"),
+    ("map", "application/json"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("rtf", "application/rtf"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("webm", "video/webm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xhtml", "application/xhtml+xml"),
+    ("xls", "application/vnd.ms-excel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("zip", "application/zip"),
+    ("zst", "application/zstd"),
+];
+
+/// Signatures at the start of binary files, and their types
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"\0asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+    (b"fLaC", "audio/flac"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"\0\0\x01\0", "image/x-icon"),
+];
+
+/// Resolves the `Content-Type` of files
+///
+/// Types are looked up by the file's extension, ignoring case, first in the overrides given to
+/// [`MimeTypes::extension`] and then in a built in table of common web formats.  Files with
+/// unknown extensions have no type, unless [`MimeTypes::sniff`] is enabled, in which case the
+/// first [`SNIFF_LEN`] bytes are checked by [`sniff_content_type`].
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    overrides: HashMap<String, HeaderValue>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `content_type` for files with the `extension` (without the leading `.`), instead of
+    /// the built in type
+    #[must_use]
+    pub fn extension(mut self, extension: &str, content_type: HeaderValue) -> Self {
+        self.overrides
+            .insert(extension.to_ascii_lowercase(), content_type);
+        self
+    }
+
+    /// Reads the start of files with unknown extensions to guess their type
+    #[must_use]
+    pub fn sniff(mut self, sniff: bool) -> Self {
+        self.sniff = sniff;
+        self
+    }
+
+    /// The type of the file at `path`, based on it's extension
+    #[must_use]
+    pub fn guess(&self, path: &Path) -> Option<HeaderValue> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        if let Some(content_type) = self.overrides.get(&extension) {
+            return Some(content_type.clone());
+        }
+        let index = EXTENSIONS
+            .binary_search_by_key(&extension.as_str(), |(extension, _)| extension)
+            .ok()?;
+        Some(HeaderValue::from_static(EXTENSIONS[index].1))
+    }
+
+    /// The type of the file at `path`, sniffing it's contents with [`Request::ReadRange`] if
+    /// enabled and the extension is unknown
+    pub(super) async fn resolve<S>(&self, inner: &mut S, path: PathBuf) -> Option<HeaderValue>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        if let Some(content_type) = self.guess(&path) {
+            return Some(content_type);
+        }
+        if !self.sniff {
+            return None;
+        }
+        let range = 0..SNIFF_LEN as u64;
+        match call(
+            inner,
+            Request::ReadRange {
+                path: path.into(),
+                range,
+            },
+        )
+        .await
+        {
+            Ok(Response::Bytes(bytes)) => Some(sniff_content_type(&bytes)),
+            _ => None,
+        }
+    }
+}
+
+/// Guesses the type of a file from it's first bytes
+///
+/// Common binary formats are recognized by their signatures, HTML and XML by their opening tags,
+/// and anything else which is UTF-8 without control characters is plain text.  Other files are
+/// `application/octet-stream`.
+#[must_use]
+pub fn sniff_content_type(prefix: &[u8]) -> HeaderValue {
+    if let Some((_, content_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| prefix.starts_with(signature))
+    {
+        return HeaderValue::from_static(content_type);
+    }
+    if prefix.starts_with(b"RIFF") && prefix.get(8..12) == Some(b"WEBP") {
+        return HeaderValue::from_static("image/webp");
+    }
+
+    // A character may have been cut off by the end of the prefix
+    let text = match std::str::from_utf8(prefix) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&prefix[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return HeaderValue::from_static("application/octet-stream"),
+    };
+    let trimmed = text.trim_start_matches(['\u{feff}', ' ', '\t', '\n', '\r']);
+    let start = trimmed.get(..14).unwrap_or(trimmed).to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        return HeaderValue::from_static("text/html; charset=utf-8");
+    }
+    if start.starts_with("<?xml") {
+        return HeaderValue::from_static("application/xml");
+    }
+    if text
+        .chars()
+        .any(|char| char.is_control() && !matches!(char, '\t' | '\n' | '\r' | '\x0c'))
+    {
+        return HeaderValue::from_static("application/octet-stream");
+    }
+    HeaderValue::from_static("text/plain; charset=utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guess() {
+        assert!(EXTENSIONS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let types = MimeTypes::new().extension("TXT", HeaderValue::from_static("text/x-custom"));
+        let guess = |path: &str| types.guess(Path::new(path));
+        assert_eq!(
+            guess("/site/index.HTML"),
+            Some(HeaderValue::from_static("text/html; charset=utf-8"))
+        );
+        assert_eq!(
+            guess("notes.txt"),
+            Some(HeaderValue::from_static("text/x-custom"))
+        );
+        assert_eq!(
+            guess("archive.tar.gz"),
+            Some(HeaderValue::from_static("application/gzip"))
+        );
+        assert_eq!(guess("Makefile"), None);
+        assert_eq!(guess("data.unknown"), None);
+    }
+
+    #[test]
+    fn test_sniff_content_type() {
+        for (prefix, content_type) in [
+            (&b"\x89PNG\r\n\x1a\n\0\0"[..], "image/png"),
+            (b"RIFF\0\0\0\0WEBPVP8 ", "image/webp"),
+            (b"\n  <!DOCTYPE html><html>", "text/html; charset=utf-8"),
+            (b"<?xml version=\"1.0\"?>", "application/xml"),
+            (b"plain text\n", "text/plain; charset=utf-8"),
+            (b"caf\xc3", "text/plain; charset=utf-8"),
+            (b"\0\x01\x02binary", "application/octet-stream"),
+            (b"\xff\xfe", "application/octet-stream"),
+        ] {
+            assert_eq!(sniff_content_type(prefix), content_type);
+        }
+    }
+}
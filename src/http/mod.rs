@@ -0,0 +1,385 @@
+//! Serving and changing the files of a `Service<Request>` over HTTP
+//!
+//! The services here, such as [`ServeDir`] and [`AcceptUpload`], are always ready.  Readiness of
+//! their inner service is awaited within each request instead, so it's failures become error
+//! responses rather than errors of the HTTP service.
+
+use std::{
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+    task::ready,
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use http_body::Body;
+use http_range_header::RangeUnsatisfiableError;
+use percent_encoding::percent_decode;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf, Take};
+use tokio_util::io::ReaderStream;
+
+use crate::{BufferPool, PooledBuffer};
+
+pub use accept_create_dir::AcceptCreateDir;
+pub use accept_delete::AcceptDelete;
+pub use accept_upload::AcceptUpload;
+pub use byteranges::MultiRangeBody;
+pub use cache_control::CacheControl;
+pub use checksum::{ChecksumAlgorithm, ChecksumBody};
+pub use chunk_size::ChunkSize;
+pub use conditional::{
+    is_not_modified, not_modified, precondition_failed, select_ranges, ETag, RangeSelection,
+};
+pub use disposition::{content_disposition, Disposition};
+pub use error::{error_status, problem_response, write_error_status};
+pub use manage_api::ManageApi;
+pub use mime::{sniff_content_type, MimeTypes, SNIFF_LEN};
+#[cfg(feature = "multipart")]
+pub use multipart::{save_multipart, SavedFile};
+pub use path_policy::PathPolicy;
+pub use precompressed::Encoding;
+pub use progress::{Progress, ProgressBody};
+pub use serve::{head_response, range_response, ResponseBody};
+#[cfg(feature = "archive")]
+pub use serve_archive::ServeArchive;
+pub use serve_dir::ServeDir;
+pub use serve_file::ServeFile;
+pub use strong_etags::{CacheStats, CachedETag, StrongETags};
+pub use tus::TusUploads;
+pub use write_body::{write_body_to_file, WriteOptions};
+
+mod accept_create_dir;
+mod accept_delete;
+mod accept_upload;
+mod byteranges;
+mod cache_control;
+mod checksum;
+mod chunk_size;
+mod conditional;
+mod disposition;
+mod error;
+mod manage_api;
+mod mime;
+#[cfg(feature = "multipart")]
+mod multipart;
+mod path_policy;
+mod precompressed;
+mod progress;
+mod serve;
+#[cfg(feature = "archive")]
+mod serve_archive;
+mod serve_dir;
+mod serve_file;
+mod strong_etags;
+mod tus;
+mod write_body;
+
+pin_project! {
+    #[derive(Debug)]
+    pub struct AsyncReadBody<T> {
+        #[pin]
+        reader: Reader<T>
+    }
+}
+
+pin_project! {
+    #[project = ReaderProj]
+    #[derive(Debug)]
+    enum Reader<T> {
+        Stream { #[pin] stream: ReaderStream<T> },
+        Pooled { #[pin] read: T, pool: BufferPool },
+    }
+}
+
+/// The part of a pooled buffer filled by a read, which is sent as a chunk of the body without
+/// being copied, and returns to the pool once the chunk is dropped
+struct Filled(PooledBuffer, usize);
+
+impl AsRef<[u8]> for Filled {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..self.1]
+    }
+}
+
+impl<T> AsyncReadBody<T>
+where
+    T: AsyncRead,
+{
+    /// Create a new [`AsyncReadBody`] wrapping the given reader, with a
+    /// specific read buffer capacity
+    pub fn with_capacity(read: T, capacity: usize) -> Self {
+        Self {
+            reader: Reader::Stream {
+                stream: ReaderStream::with_capacity(read, capacity),
+            },
+        }
+    }
+
+    /// Create a new [`AsyncReadBody`] wrapping the given reader, reading each chunk into a buffer
+    /// taken from `pool`
+    pub fn with_pool(read: T, pool: BufferPool) -> Self {
+        Self {
+            reader: Reader::Pooled { read, pool },
+        }
+    }
+
+    /// Sends a digest of the body in a trailer, computed as it's read
+    pub fn with_checksum(self, algorithm: ChecksumAlgorithm) -> ChecksumBody<Self> {
+        ChecksumBody::new(self, algorithm)
+    }
+}
+
+impl<T> AsyncReadBody<T>
+where
+    T: AsyncRead + AsyncSeek + Unpin,
+{
+    /// Create a new [`AsyncReadBody`] wrapping the given reader with a
+    /// specific buffer capacity and range.
+    ///
+    /// # Errors
+    ///
+    /// If the reader fails to seek to the start of the range
+    pub async fn with_range(
+        mut read: T,
+        capacity: usize,
+        range: RangeInclusive<u64>,
+    ) -> std::io::Result<AsyncReadBody<Take<T>>> {
+        read.seek(std::io::SeekFrom::Start(*range.start())).await?;
+        let max_read_bytes = if range.is_empty() {
+            0
+        } else {
+            (range.end() - range.start()).saturating_add(1)
+        };
+        Ok(AsyncReadBody::with_capacity(
+            read.take(max_read_bytes),
+            capacity,
+        ))
+    }
+
+    /// Create a new [`AsyncReadBody`] wrapping the given reader with a range, reading each chunk
+    /// into a buffer taken from `pool`
+    ///
+    /// # Errors
+    ///
+    /// If the reader fails to seek to the start of the range
+    pub async fn with_pooled_range(
+        mut read: T,
+        pool: BufferPool,
+        range: RangeInclusive<u64>,
+    ) -> std::io::Result<AsyncReadBody<Take<T>>> {
+        read.seek(std::io::SeekFrom::Start(*range.start())).await?;
+        let max_read_bytes = if range.is_empty() {
+            0
+        } else {
+            (range.end() - range.start()).saturating_add(1)
+        };
+        Ok(AsyncReadBody::with_pool(read.take(max_read_bytes), pool))
+    }
+}
+
+impl<T> Body for AsyncReadBody<T>
+where
+    T: AsyncRead,
+{
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_data(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+        match self.project().reader.project() {
+            ReaderProj::Stream { stream } => stream.poll_next(cx),
+            ReaderProj::Pooled { read, pool } => {
+                let mut buffer = pool.get();
+                let mut buf = ReadBuf::new(&mut buffer);
+                ready!(read.poll_read(cx, &mut buf))?;
+                let len = buf.filled().len();
+                std::task::Poll::Ready(
+                    (len > 0).then(|| Ok(Bytes::from_owner(Filled(buffer, len)))),
+                )
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        std::task::Poll::Ready(Ok(None))
+    }
+}
+
+/// Tries to parse a given range header
+///
+/// # Errors
+///
+/// if the range cannot be parsed or extends past the given file length
+pub fn try_parse_range(
+    header_value: &str,
+    file_size: u64,
+) -> Result<Vec<RangeInclusive<u64>>, RangeUnsatisfiableError> {
+    http_range_header::parse_range_header(header_value)
+        .and_then(|first_pass| first_pass.validate(file_size))
+}
+
+/// Builds a path from a given request string
+///
+/// # Errors
+///
+/// - If the path (after percent decoding) isn't valid utf-8
+/// - If a subcomponent of the path isn't a [`std::path::Component::Normal`]
+/// - If the paht contains [`std::path::Component::Prefix`], [`std::path::Component::RootDir`], or [`std::path::Component::ParentDir`] elements.
+/// - If a component holds a `:`, which names an alternate data stream, on windows
+pub fn build_and_validate_path(requested_path: &str) -> Result<PathBuf, PathError> {
+    // taken from https://github.com/tower-rs/tower-http/blob/d895678bd70ae894f2001d30a3499995eab874ce/tower-http/src/services/fs/serve_dir/mod.rs#L486
+    let str_decoded =
+        percent_decode(requested_path.trim_start_matches('/').as_ref()).decode_utf8()?;
+    validate_path(Path::new(&*str_decoded))
+}
+
+/// Builds a path from a given request string, allowing names which aren't utf-8 where the
+/// platform does
+///
+/// On unix, where file names are arbitrary bytes, the percent decoded bytes are used as they are.
+/// Elsewhere this is the same as [`build_and_validate_path`].
+///
+/// # Errors
+///
+/// - If the path (after percent decoding) isn't valid utf-8, on platforms other than unix
+/// - If a subcomponent of the path isn't a [`std::path::Component::Normal`]
+/// - If the path contains [`std::path::Component::Prefix`], [`std::path::Component::RootDir`], or
+///   [`std::path::Component::ParentDir`] elements.
+pub fn build_and_validate_os_path(requested_path: &str) -> Result<PathBuf, PathError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let decoded =
+            percent_decode(requested_path.trim_start_matches('/').as_ref()).collect::<Vec<u8>>();
+        validate_path(Path::new(std::ffi::OsStr::from_bytes(&decoded)))
+    }
+    #[cfg(not(unix))]
+    {
+        build_and_validate_path(requested_path)
+    }
+}
+
+fn validate_path(path_decoded: &Path) -> Result<PathBuf, PathError> {
+    let mut path_to_file = PathBuf::with_capacity(path_decoded.as_os_str().len());
+    for component in path_decoded.components() {
+        match component {
+            // `name:stream` opens an alternate data stream of `name`, which is never served
+            #[cfg(windows)]
+            Component::Normal(comp) if comp.to_string_lossy().contains(':') => {
+                return Err(PathError::CharacterNotAllowed);
+            }
+            Component::Normal(comp) => {
+                if Path::new(&comp)
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+                {
+                    path_to_file.push(comp);
+                } else {
+                    return Err(PathError::SubComponentNotNormal);
+                }
+            }
+            Component::CurDir => {}
+            Component::Prefix(_) | Component::RootDir | Component::ParentDir => {
+                return Err(PathError::ComponentNotAllowed);
+            }
+        }
+    }
+    Ok(path_to_file)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PathError {
+    #[error("A sub-component of the path was not normal")]
+    SubComponentNotNormal,
+    #[error("A component of the path was not of the allowed types")]
+    ComponentNotAllowed,
+    #[error("A component of the path started with a `.`")]
+    DotfileNotAllowed,
+    #[error("The path had too many components")]
+    TooManyComponents,
+    #[error("A component of the path was too long")]
+    ComponentTooLong,
+    #[error("A component of the path contained a character which isn't allowed")]
+    CharacterNotAllowed,
+    #[error("The path contained a percent encoded separator")]
+    EncodedSeparator,
+    #[error("Path not valid utf-8")]
+    Utf8(
+        #[from]
+        #[source]
+        std::str::Utf8Error,
+    ),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pooled_body() -> std::io::Result<()> {
+        let pool = BufferPool::new(4, 2);
+        let reader = std::io::Cursor::new(b"hello world".to_vec());
+        let mut body = AsyncReadBody::with_pooled_range(reader, pool.clone(), 0..=8).await?;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = body.data().await {
+            chunks.push(chunk?);
+        }
+        assert_eq!(chunks, ["hell", "o wo", "r"]);
+        // Chunks hold on to their buffers until they're dropped, unlike the read finding the end
+        assert_eq!(pool.idle(), 1);
+        drop(chunks);
+        assert_eq!(pool.idle(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_and_validate_path() {
+        assert_eq!(
+            build_and_validate_path("/docs/./a%20b.txt"),
+            Ok(PathBuf::from("docs/a b.txt"))
+        );
+        assert_eq!(
+            build_and_validate_path("/docs/../secret"),
+            Err(PathError::ComponentNotAllowed)
+        );
+        assert!(matches!(
+            build_and_validate_path("/caf%E9"),
+            Err(PathError::Utf8(_))
+        ));
+        #[cfg(windows)]
+        assert_eq!(
+            build_and_validate_path("/setup.exe:Zone.Identifier"),
+            Err(PathError::CharacterNotAllowed)
+        );
+    }
+
+    #[test]
+    fn test_build_and_validate_os_path() {
+        assert_eq!(
+            build_and_validate_os_path("/docs/a%20b.txt"),
+            Ok(PathBuf::from("docs/a b.txt"))
+        );
+        assert_eq!(
+            build_and_validate_os_path("/%2E%2E/secret"),
+            Err(PathError::ComponentNotAllowed)
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            assert_eq!(
+                build_and_validate_os_path("/caf%E9")
+                    .as_deref()
+                    .map(|path| path.as_os_str().as_bytes()),
+                Ok(&b"caf\xe9"[..])
+            );
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Saving the files uploaded in `multipart/form-data` request bodies
+
+use std::{
+    future::poll_fn,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{HeaderMap, HeaderValue};
+use http_body::Body;
+use tower_service::Service;
+
+use super::{
+    mime::{sniff_content_type, MimeTypes, SNIFF_LEN},
+    write_body::{write_body_to_file, WriteOptions},
+};
+use crate::{Request, Response};
+
+/// The longest header section a part can have
+const MAX_HEADERS_LEN: usize = 16 * 1024;
+
+/// The longest file name a saved file can have, in bytes
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// A file saved by [`save_multipart`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedFile {
+    /// The name of the form field the file was sent in
+    pub field: String,
+    /// The path the file was saved to
+    pub path: PathBuf,
+    /// The number of bytes saved
+    pub len: u64,
+    /// The type of the file, from the extension of it's name or otherwise it's first bytes
+    pub content_type: HeaderValue,
+}
+
+/// Saves each file in a `multipart/form-data` body into `dir`, through `inner`
+///
+/// `content_type` is the `Content-Type` of the request, which names the boundary between parts.
+/// Each part with a file name is streamed to a file with [`write_body_to_file`], using `options`
+/// for each file.  Names are reduced to their final component, without control characters,
+/// characters Windows doesn't allow, or leading and trailing dots and spaces, so they can't escape
+/// `dir`.  Other fields, and files with empty names, are skipped.
+///
+/// The client's `Content-Type` for each part isn't trusted; the type is instead guessed from the
+/// name, and the first [`SNIFF_LEN`] bytes for unknown extensions.
+///
+/// # Errors
+///
+/// - [`ErrorKind::InvalidInput`] if `content_type` isn't `multipart/form-data` with a boundary
+/// - [`ErrorKind::InvalidData`] if the body is malformed or fails
+/// - [`ErrorKind::UnexpectedEof`] if the body ends before the closing delimiter
+/// - Any error from [`write_body_to_file`], such as an existing file when creating new files
+///
+/// Files saved before an error are kept.
+pub async fn save_multipart<B, S>(
+    content_type: &HeaderValue,
+    body: B,
+    inner: &mut S,
+    dir: &Path,
+    options: WriteOptions,
+) -> io::Result<Vec<SavedFile>>
+where
+    B: Body,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let boundary = boundary(content_type).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            "not a multipart/form-data content type",
+        )
+    })?;
+    let mut parser = Parser::new(body, &boundary);
+    let mut saved = Vec::new();
+    while let Some(headers) = parser.next_part().await? {
+        let disposition = headers
+            .get(http::header::CONTENT_DISPOSITION)
+            .and_then(|value| parse_disposition(value.as_bytes()));
+        let Some((field, file_name)) = disposition
+            .and_then(|(field, file_name)| Some((field, sanitize_file_name(&file_name?)?)))
+        else {
+            let mut part = PartBody::new(&mut parser);
+            while let Some(data) = part.data().await {
+                data?;
+            }
+            continue;
+        };
+
+        let path = dir.join(&file_name);
+        let mut part = PartBody::new(&mut parser);
+        let written = write_body_to_file(&mut part, inner, path.clone(), options).await;
+        let prefix = part.prefix;
+        // The parser's error explains a failed body better than the error it was wrapped in
+        let len = match (written, parser.error.take()) {
+            (Ok(len), _) => len,
+            (Err(_), Some(err)) | (Err(err), None) => return Err(err),
+        };
+        let content_type = MimeTypes::new()
+            .guess(&path)
+            .unwrap_or_else(|| sniff_content_type(&prefix));
+        saved.push(SavedFile {
+            field,
+            path,
+            len,
+            content_type,
+        });
+    }
+    Ok(saved)
+}
+
+/// The boundary parameter of a `multipart/form-data` content type
+fn boundary(content_type: &HeaderValue) -> Option<String> {
+    let mut params = content_type.to_str().ok()?.split(';');
+    let essence = params.next()?.trim();
+    if !essence.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        (!value.is_empty() && value.len() <= 70).then(|| value.to_owned())
+    })
+}
+
+/// The field name and file name of a part's `Content-Disposition`
+fn parse_disposition(value: &[u8]) -> Option<(String, Option<String>)> {
+    let value = String::from_utf8_lossy(value);
+    let mut params = value.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+    let (mut field, mut file_name) = (None, None);
+    for param in params {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .map_or_else(|| value.to_owned(), |value| value.replace("\\\"", "\""));
+        match name.trim().to_ascii_lowercase().as_str() {
+            "name" => field = Some(value),
+            "filename" => file_name = Some(value),
+            _ => {}
+        }
+    }
+    Some((field?, file_name))
+}
+
+/// Reduces an uploaded file's name to a single, safe component, or `None` if nothing is left
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name = name
+        .chars()
+        .map(|char| match char {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            char if char.is_control() => '_',
+            char => char,
+        })
+        .collect::<String>();
+    let mut name = name.trim_matches(['.', ' ']).to_owned();
+    while name.len() > MAX_FILE_NAME_LEN {
+        name.pop();
+    }
+    (!name.is_empty()).then_some(name)
+}
+
+/// Splits a body into parts, with the delimiter before each part
+struct Parser<B> {
+    body: Pin<Box<B>>,
+    buffer: BytesMut,
+    /// A line break, followed by `--` and the boundary
+    delimiter: Vec<u8>,
+    /// Why a [`PartBody`] failed
+    error: Option<io::Error>,
+}
+
+impl<B> Parser<B>
+where
+    B: Body,
+{
+    fn new(body: B, boundary: &str) -> Self {
+        Self {
+            body: Box::pin(body),
+            // The first delimiter needn't follow a line break
+            buffer: BytesMut::from(&b"\r\n"[..]),
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            error: None,
+        }
+    }
+
+    /// Reads more of the body into the buffer
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(self.body.as_mut().poll_data(cx)) {
+            Some(Ok(data)) => {
+                self.buffer.put(data);
+                Poll::Ready(Ok(()))
+            }
+            Some(Err(_)) => Poll::Ready(Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "failed to receive the body",
+            ))),
+            None => Poll::Ready(Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "body ended before the closing delimiter",
+            ))),
+        }
+    }
+
+    async fn fill(&mut self) -> io::Result<()> {
+        poll_fn(|cx| self.poll_fill(cx)).await
+    }
+
+    /// Removes the bytes before `pattern` from the buffer and returns them, along with `pattern`
+    async fn read_until(&mut self, pattern: &[u8], limit: usize) -> io::Result<Bytes> {
+        loop {
+            if let Some(index) = find(&self.buffer, pattern) {
+                let line = self.buffer.split_to(index).freeze();
+                self.buffer.advance(pattern.len());
+                return Ok(line);
+            }
+            if self.buffer.len() > limit {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "part header too long",
+                ));
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Skips to the next delimiter and reads the headers of the part after it, or returns `None`
+    /// after the closing delimiter
+    async fn next_part(&mut self) -> io::Result<Option<HeaderMap>> {
+        loop {
+            if let Some(index) = find(&self.buffer, &self.delimiter) {
+                self.buffer.advance(index + self.delimiter.len());
+                break;
+            }
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                self.buffer.advance(self.buffer.len() - keep);
+            }
+            self.fill().await?;
+        }
+        while self.buffer.len() < 2 {
+            self.fill().await?;
+        }
+        if self.buffer.starts_with(b"--") {
+            return Ok(None);
+        }
+        // Transport padding may follow the delimiter
+        let padding = self.read_until(b"\r\n", MAX_HEADERS_LEN).await?;
+        if !padding.iter().all(|byte| matches!(byte, b' ' | b'\t')) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "malformed delimiter",
+            ));
+        }
+
+        let mut headers = HeaderMap::new();
+        let mut remaining = MAX_HEADERS_LEN;
+        loop {
+            let line = self.read_until(b"\r\n", remaining).await?;
+            if line.is_empty() {
+                return Ok(Some(headers));
+            }
+            remaining = remaining.saturating_sub(line.len() + 2);
+            let Some(colon) = line.iter().position(|byte| *byte == b':') else {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "malformed part header",
+                ));
+            };
+            let name = http::HeaderName::from_bytes(&line[..colon]);
+            let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii());
+            if let (Ok(name), Ok(value)) = (name, value) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+/// The contents of the current part, ending at the next delimiter
+struct PartBody<'a, B> {
+    parser: &'a mut Parser<B>,
+    /// The first bytes of the part, for sniffing it's type
+    prefix: Vec<u8>,
+}
+
+impl<'a, B> PartBody<'a, B> {
+    fn new(parser: &'a mut Parser<B>) -> Self {
+        Self {
+            parser,
+            prefix: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, data: Bytes) -> Poll<Option<io::Result<Bytes>>> {
+        let take = SNIFF_LEN.saturating_sub(self.prefix.len()).min(data.len());
+        self.prefix.extend_from_slice(&data[..take]);
+        Poll::Ready(Some(Ok(data)))
+    }
+}
+
+impl<B> Body for PartBody<'_, B>
+where
+    B: Body,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = &mut *self;
+        loop {
+            let parser = &mut *this.parser;
+            if let Some(index) = find(&parser.buffer, &parser.delimiter) {
+                if index == 0 {
+                    return Poll::Ready(None);
+                }
+                let data = parser.buffer.split_to(index).freeze();
+                return this.emit(data);
+            }
+            // The end of the buffer could be the start of a delimiter
+            let safe = parser
+                .buffer
+                .len()
+                .saturating_sub(parser.delimiter.len() - 1);
+            if safe > 0 {
+                let data = parser.buffer.split_to(safe).freeze();
+                return this.emit(data);
+            }
+            if let Err(err) = ready!(parser.poll_fill(cx)) {
+                let wrapped = io::Error::new(err.kind(), err.to_string());
+                parser.error = Some(err);
+                return Poll::Ready(Some(Err(wrapped)));
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body::Full;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, Mode};
+
+    #[test]
+    fn test_sanitize_file_name() {
+        for (name, sanitized) in [
+            ("photo.png", Some("photo.png")),
+            ("../../etc/passwd", Some("passwd")),
+            ("C:\\Users\\me\\report.pdf", Some("report.pdf")),
+            ("..", None),
+            (" .hidden ", Some("hidden")),
+            ("a<b>:c?.txt", Some("a_b__c_.txt")),
+            ("", None),
+        ] {
+            assert_eq!(sanitize_file_name(name).as_deref(), sanitized, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_multipart() -> io::Result<()> {
+        let dir = TestDir::new("multipart")?;
+        let content_type = HeaderValue::from_static("multipart/form-data; boundary=\"XyZ\"");
+        let body = "preamble\r\n\
+             --XyZ\r\n\
+             Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+             not a file\r\n\
+             --XyZ  \r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"../notes\"\r\n\
+             Content-Type: image/png\r\n\r\n\
+             line one\r\nline two\r\n\
+             --XyZ\r\n\
+             Content-Disposition: form-data; name=\"upload\"; filename=\"a.html\"\r\n\r\n\
+             <p>hi</p>\r\n\
+             --XyZ--\r\nepilogue";
+        let mut inner = FileSystem::new();
+        let options = WriteOptions::new().mode(Mode::CreateOrOverwrite);
+        let body = Full::new(Bytes::from(body));
+        let saved = save_multipart(&content_type, body, &mut inner, &dir, options).await?;
+
+        assert_eq!(
+            saved,
+            [
+                SavedFile {
+                    field: "upload".to_owned(),
+                    path: dir.join("notes"),
+                    len: 18,
+                    content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+                },
+                SavedFile {
+                    field: "upload".to_owned(),
+                    path: dir.join("a.html"),
+                    len: 9,
+                    content_type: HeaderValue::from_static("text/html; charset=utf-8"),
+                }
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("notes"))?,
+            "line one\r\nline two"
+        );
+
+        let truncated = Full::new(Bytes::from(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"f\"; filename=\"t\"\r\n\r\nab",
+        ));
+        let result = save_multipart(&content_type, truncated, &mut inner, &dir, options).await;
+        assert!(result.is_err_and(|err| err.kind() == ErrorKind::UnexpectedEof));
+        let plain = HeaderValue::from_static("text/plain");
+        let result = save_multipart(&plain, Full::new(Bytes::new()), &mut inner, &dir, options);
+        assert!(result
+            .await
+            .is_err_and(|err| err.kind() == ErrorKind::InvalidInput));
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Configurable validation of request paths
+
+use std::path::{Component, PathBuf};
+
+use super::{build_and_validate_os_path, build_and_validate_path, PathError};
+
+/// Rules for which request paths are turned into file system paths
+///
+/// Paths are always percent decoded and checked as by [`build_and_validate_path`], so they can't
+/// escape the directory they're joined onto.  The default policy adds nothing to that, and each
+/// builder method tightens or loosens it:
+/// - [`PathPolicy::dotfiles`] rejects names starting with `.`
+/// - [`PathPolicy::max_components`] and [`PathPolicy::max_component_len`] limit the depth of the
+///   path, and the length of each name in bytes
+/// - [`PathPolicy::allowed_chars`] rejects names with other characters
+/// - [`PathPolicy::encoded_separators`] rejects `%2F`, and `%5C` which is a separator on Windows,
+///   so a single encoded segment can't become several names
+/// - [`PathPolicy::non_utf8`] accepts names which aren't utf-8 on unix, as
+///   [`build_and_validate_os_path`] does
+#[derive(Debug, Clone, Copy)]
+pub struct PathPolicy {
+    dotfiles: bool,
+    max_components: Option<usize>,
+    max_component_len: Option<usize>,
+    allowed_chars: Option<fn(char) -> bool>,
+    encoded_separators: bool,
+    non_utf8: bool,
+}
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        Self {
+            dotfiles: true,
+            max_components: None,
+            max_component_len: None,
+            allowed_chars: None,
+            encoded_separators: true,
+            non_utf8: false,
+        }
+    }
+}
+
+impl PathPolicy {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows names starting with `.`, such as `.env` or `.git`
+    #[must_use]
+    pub fn dotfiles(mut self, allow: bool) -> Self {
+        self.dotfiles = allow;
+        self
+    }
+
+    /// Rejects paths with more than `max` names
+    #[must_use]
+    pub fn max_components(mut self, max: usize) -> Self {
+        self.max_components = Some(max);
+        self
+    }
+
+    /// Rejects paths with a name longer than `max` bytes
+    #[must_use]
+    pub fn max_component_len(mut self, max: usize) -> Self {
+        self.max_component_len = Some(max);
+        self
+    }
+
+    /// Rejects paths with a name containing a character `allowed` returns `false` for, or which
+    /// isn't utf-8
+    #[must_use]
+    pub fn allowed_chars(mut self, allowed: fn(char) -> bool) -> Self {
+        self.allowed_chars = Some(allowed);
+        self
+    }
+
+    /// Allows percent encoded separators, which are decoded into separators between names
+    #[must_use]
+    pub fn encoded_separators(mut self, allow: bool) -> Self {
+        self.encoded_separators = allow;
+        self
+    }
+
+    /// Allows names which aren't utf-8 on unix
+    #[must_use]
+    pub fn non_utf8(mut self, allow: bool) -> Self {
+        self.non_utf8 = allow;
+        self
+    }
+
+    /// Builds a path from a given request string
+    ///
+    /// # Errors
+    ///
+    /// If the path is rejected by [`build_and_validate_path`] or this policy
+    pub fn build(&self, requested_path: &str) -> Result<PathBuf, PathError> {
+        if !self.encoded_separators {
+            let lowercase = requested_path.to_ascii_lowercase();
+            if lowercase.contains("%2f") || lowercase.contains("%5c") {
+                return Err(PathError::EncodedSeparator);
+            }
+        }
+        let path = if self.non_utf8 {
+            build_and_validate_os_path(requested_path)?
+        } else {
+            build_and_validate_path(requested_path)?
+        };
+
+        let mut count = 0;
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            count += 1;
+            if self.max_components.is_some_and(|max| count > max) {
+                return Err(PathError::TooManyComponents);
+            }
+            if self.max_component_len.is_some_and(|max| name.len() > max) {
+                return Err(PathError::ComponentTooLong);
+            }
+            if !self.dotfiles && name.as_encoded_bytes().starts_with(b".") {
+                return Err(PathError::DotfileNotAllowed);
+            }
+            if let Some(allowed) = self.allowed_chars {
+                if !name.to_str().is_some_and(|name| name.chars().all(allowed)) {
+                    return Err(PathError::CharacterNotAllowed);
+                }
+            }
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_policy() {
+        let default = PathPolicy::new();
+        assert_eq!(
+            default.build("/.config/a%2Fb"),
+            Ok(PathBuf::from(".config/a/b"))
+        );
+        assert_eq!(
+            default.build("/../etc/passwd"),
+            Err(PathError::ComponentNotAllowed)
+        );
+
+        let strict = PathPolicy::new()
+            .dotfiles(false)
+            .max_components(3)
+            .max_component_len(8)
+            .allowed_chars(|char| char.is_ascii_alphanumeric() || matches!(char, '.' | '-' | '_'))
+            .encoded_separators(false);
+        assert_eq!(strict.build("/a/b/c.txt"), Ok(PathBuf::from("a/b/c.txt")));
+        for (path, err) in [
+            ("/a/.git/config", PathError::DotfileNotAllowed),
+            ("/a/b/c/d", PathError::TooManyComponents),
+            ("/a/long-name.txt", PathError::ComponentTooLong),
+            ("/a%20b", PathError::CharacterNotAllowed),
+            ("/a%2fb", PathError::EncodedSeparator),
+            ("/a%5Cb", PathError::EncodedSeparator),
+        ] {
+            assert_eq!(strict.build(path), Err(err), "{path}");
+        }
+    }
+}
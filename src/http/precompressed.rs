@@ -0,0 +1,183 @@
+//! Serving precompressed copies of files, chosen by the `Accept-Encoding` header
+
+use std::{cmp::Reverse, io, path::PathBuf};
+
+use http::{
+    header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY},
+    request::Parts,
+    HeaderMap, HeaderValue, StatusCode,
+};
+use tower_service::Service;
+
+use super::{
+    chunk_size::ChunkSize,
+    serve::{serve_file, ResponseBody},
+    strong_etags::StrongETags,
+};
+use crate::{Request, Response};
+
+/// A content coding which files can be precompressed with, in the order they're preferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// The name of the coding, as used in `Accept-Encoding` and `Content-Encoding`
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// The extension appended to the path of a file to find it's precompressed copy
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+        }
+    }
+}
+
+/// The `available` encodings which the `Accept-Encoding` header accepts, most preferred first
+///
+/// Encodings the client weighs equally are preferred in the order given.
+pub(super) fn negotiate(headers: &HeaderMap, available: &[Encoding]) -> Vec<Encoding> {
+    let mut weights = Vec::new();
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let coding = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            if coding.is_empty() {
+                continue;
+            }
+            let weight = params
+                .find_map(|param| {
+                    let param = param.trim();
+                    param
+                        .strip_prefix("q=")
+                        .or_else(|| param.strip_prefix("Q="))
+                })
+                .map_or(Some(1000), parse_weight);
+            weights.push((coding, weight.unwrap_or(0)));
+        }
+    }
+    let weight = |name: &str| {
+        let find = |name: &str| weights.iter().find(|(coding, _)| coding == name);
+        find(name)
+            .or_else(|| find("*"))
+            .map_or(0, |(_, weight)| *weight)
+    };
+
+    let mut accepted = available
+        .iter()
+        .map(|encoding| (*encoding, weight(encoding.name())))
+        .filter(|(_, weight)| *weight > 0)
+        .collect::<Vec<_>>();
+    accepted.sort_by_key(|(_, weight)| Reverse(*weight));
+    accepted.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// Parses a `q` parameter in thousandths
+fn parse_weight(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = format!("{fraction:0<3}").parse::<u16>().ok()?;
+    match whole {
+        "0" => Some(fraction),
+        "1" if fraction == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Serves the first copy of the file at `path` precompressed with one of the `available`
+/// encodings the request accepts, falling back to the file itself if none exist
+pub(super) async fn serve_precompressed<S>(
+    inner: &mut S,
+    path: PathBuf,
+    parts: &Parts,
+    available: &[Encoding],
+    strong_etags: Option<&StrongETags>,
+    chunk_size: ChunkSize,
+) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut response = None;
+    for encoding in negotiate(&parts.headers, available) {
+        let mut encoded = path.clone().into_os_string();
+        encoded.push(".");
+        encoded.push(encoding.extension());
+        let mut encoded = serve_file(
+            inner,
+            PathBuf::from(encoded).into(),
+            parts,
+            strong_etags,
+            chunk_size,
+        )
+        .await;
+        if encoded.status() != StatusCode::NOT_FOUND {
+            encoded
+                .headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            response = Some(encoded);
+            break;
+        }
+    }
+    let mut response = match response {
+        Some(response) => response,
+        None => serve_file(inner, path.into(), parts, strong_etags, chunk_size).await,
+    };
+    if !available.is_empty() {
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let all = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+        let accepted = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept));
+            negotiate(&headers, &all)
+        };
+        assert_eq!(
+            accepted("gzip, deflate, br"),
+            [Encoding::Brotli, Encoding::Gzip]
+        );
+        assert_eq!(
+            accepted("br;q=0.5, gzip;q=0.8, zstd;q=0"),
+            [Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(
+            accepted("*;q=0.1, br;q=0"),
+            [Encoding::Zstd, Encoding::Gzip]
+        );
+        assert_eq!(accepted("identity"), []);
+        assert_eq!(accepted("gzip;q=2"), []);
+        assert_eq!(negotiate(&HeaderMap::new(), &all), []);
+    }
+}
@@ -0,0 +1,118 @@
+//! Bodies which report how much of themselves has been sent
+
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Buf;
+use http::HeaderMap;
+use http_body::{Body, SizeHint};
+use pin_project_lite::pin_project;
+
+/// How much of a [`ProgressBody`] has been sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of bytes sent so far
+    pub sent: u64,
+    /// The length of the whole body, if it was known when it was wrapped
+    pub total: Option<u64>,
+}
+
+pin_project! {
+    /// A body which calls `on_progress` with the bytes sent so far each time `inner` produces data
+    ///
+    /// Progress can be published to other tasks by a callback which sends it into a channel, such
+    /// as `tokio::sync::watch::Sender::send_replace`.
+    #[derive(Debug)]
+    pub struct ProgressBody<B, F> {
+        #[pin]
+        inner: B,
+        on_progress: F,
+        progress: Progress,
+    }
+}
+
+impl<B, F> ProgressBody<B, F>
+where
+    B: Body,
+    F: FnMut(Progress),
+{
+    pub fn new(inner: B, on_progress: F) -> Self {
+        let progress = Progress {
+            sent: 0,
+            total: inner.size_hint().exact(),
+        };
+        Self {
+            inner,
+            on_progress,
+            progress,
+        }
+    }
+}
+
+impl<B, F> ProgressBody<B, F> {
+    /// How much of the body has been sent so far
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+}
+
+impl<B, F> Body for ProgressBody<B, F>
+where
+    B: Body,
+    F: FnMut(Progress),
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let data = ready!(this.inner.poll_data(cx));
+        if let Some(Ok(data)) = &data {
+            this.progress.sent += data.remaining() as u64;
+            (this.on_progress)(*this.progress);
+        }
+        Poll::Ready(data)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use super::*;
+    use crate::http::AsyncReadBody;
+
+    #[tokio::test]
+    async fn test_progress_body() -> io::Result<()> {
+        let mut reports = Vec::new();
+        let reader = Cursor::new(b"0123456789".to_vec());
+        let body = ProgressBody::new(AsyncReadBody::with_capacity(reader, 4), |progress| {
+            reports.push(progress.sent);
+        });
+        assert_eq!(body.progress().total, None);
+        let bytes = body.collect().await?.to_bytes();
+        assert_eq!(bytes, "0123456789");
+        assert_eq!(reports, [4, 8, 10]);
+        Ok(())
+    }
+}
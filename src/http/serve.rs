@@ -0,0 +1,488 @@
+//! Building HTTP responses from the responses of an inner `Service<Request>`
+
+use std::{
+    collections::hash_map::RandomState,
+    fs::Metadata,
+    future::poll_fn,
+    hash::BuildHasher,
+    io::{self, ErrorKind},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use http::{
+    header::{
+        ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, EXPECT, IF_MATCH,
+        IF_UNMODIFIED_SINCE,
+    },
+    request::Parts,
+    HeaderMap, HeaderValue, Method, StatusCode,
+};
+use http_body::{combinators::UnsyncBoxBody, Body, Empty, Full};
+use tokio::io::{AsyncRead, AsyncSeek};
+use tower_service::Service;
+
+use super::{
+    conditional::{
+        insert_validators, not_modified, precondition_failed, select_ranges, ETag, RangeSelection,
+    },
+    error::error_status,
+    strong_etags::StrongETags,
+    try_parse_range, ChunkSize, MultiRangeBody,
+};
+use crate::{Mode, Request, Response};
+
+/// The body of the responses sent by this module's services
+pub type ResponseBody = UnsyncBoxBody<Bytes, io::Error>;
+
+const READ_CAPACITY: usize = 64 * 1024;
+
+pub(super) fn empty_body() -> ResponseBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+pub(super) fn full_body(bytes: impl Into<Bytes>) -> ResponseBody {
+    Full::new(bytes.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// A response with no body
+pub(super) fn status_response(status: StatusCode) -> http::Response<ResponseBody> {
+    let mut response = http::Response::new(empty_body());
+    *response.status_mut() = status;
+    response
+}
+
+/// A `405 Method Not Allowed` response, listing the `allow`ed methods
+pub(super) fn method_not_allowed(allow: &'static str) -> http::Response<ResponseBody> {
+    let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+    response
+        .headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+/// Whether the `Expect` header of a request asks for anything but `100-continue`, so the request
+/// must be answered with `417 Expectation Failed`
+///
+/// `100-continue` needs no handling beyond checking a request before reading it's body, since
+/// servers such as hyper send `100 Continue` when the body is first read.
+pub(super) fn expectation_failed(headers: &HeaderMap) -> bool {
+    headers.get_all(EXPECT).iter().any(|value| {
+        !value
+            .to_str()
+            .is_ok_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    })
+}
+
+/// Generates 32 hex digits, from the time and a counter hashed with randomly keyed hashers
+pub(super) fn random_token() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos());
+    let (high, low) = (
+        RandomState::new().hash_one((now, count, std::process::id())),
+        RandomState::new().hash_one((count, now)),
+    );
+    format!("{high:016x}{low:016x}")
+}
+
+pub(super) async fn call<S>(inner: &mut S, req: Request) -> io::Result<Response>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    poll_fn(|cx| inner.poll_ready(cx)).await?;
+    inner.call(req).await
+}
+
+/// Responds to a `GET` or `HEAD` request for the file at `path`, honoring a single `Range` and the
+/// `If-Range`, `If-Match`, `If-None-Match`, `If-Modified-Since` and `If-Unmodified-Since` headers
+///
+/// The file is streamed if the inner service supports [`Request::GetMetadata`] and
+/// [`Request::Open`], as `chunk_size` chooses, and otherwise read into memory.  It's `ETag` is
+/// strong if `strong_etags` is given, and otherwise derived from it's metadata.
+pub(super) async fn serve_file<S>(
+    inner: &mut S,
+    path: Arc<Path>,
+    parts: &Parts,
+    strong_etags: Option<&StrongETags>,
+    chunk_size: ChunkSize,
+) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let metadata = call(
+        inner,
+        Request::GetMetadata {
+            path: path.clone(),
+            follow_symlinks: true,
+        },
+    )
+    .await;
+    let (len, modified) = match metadata {
+        Ok(Response::Metadata(metadata)) if metadata.is_dir() => {
+            return status_response(StatusCode::NOT_FOUND)
+        }
+        Ok(Response::Metadata(metadata)) => (metadata.len(), metadata.modified().ok()),
+        Ok(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            return serve_bytes(inner, path, parts).await
+        }
+        Err(err) => return status_response(error_status(&err)),
+    };
+    let etag = match (strong_etags, modified) {
+        (Some(strong_etags), Some(modified)) => {
+            Some(strong_etags.etag(inner, &path, len, modified).await)
+        }
+        (None, Some(modified)) => Some(ETag::from_metadata(len, modified)),
+        (_, None) => None,
+    };
+    if let Some((etag, modified)) = etag.as_ref().zip(modified) {
+        if let Some(response) = check_preconditions(parts, etag, modified) {
+            return response;
+        }
+    }
+    let range = match requested_range(parts, len, etag.as_ref(), modified) {
+        Ok(range) => range,
+        Err(response) => return response,
+    };
+    let mut response = headers(len, range.as_ref(), etag.as_ref(), modified);
+    if parts.method == Method::HEAD || len == 0 {
+        return response;
+    }
+
+    let (start, end) = range.map_or((0, len - 1), RangeInclusive::into_inner);
+    let body = match call(
+        inner,
+        Request::Open {
+            mode: Mode::Read,
+            path: path.clone(),
+        },
+    )
+    .await
+    {
+        Ok(Response::File(file)) => match chunk_size.body(file, start..=end).await {
+            Ok(body) => body.boxed_unsync(),
+            Err(err) => return status_response(error_status(&err)),
+        },
+        Ok(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            let range = start..end.saturating_add(1);
+            match call(inner, Request::ReadRange { path, range }).await {
+                Ok(Response::Bytes(bytes)) => full_body(bytes),
+                Ok(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+                Err(err) => return status_response(error_status(&err)),
+            }
+        }
+        Err(err) => return status_response(error_status(&err)),
+    };
+    *response.body_mut() = body;
+    response
+}
+
+/// Builds a response sending `file` (described by `metadata`), or the ranges of it requested by
+/// `range_header`
+///
+/// The response is a `200 OK` with the whole file when there's no `range_header`, a
+/// `206 Partial Content` for satisfiable ranges (as a [`MultiRangeBody`] if there's more than
+/// one), or a `416 Range Not Satisfiable`.  `Accept-Ranges`, `Content-Length` and `Content-Range`
+/// are set, as are `ETag` and `Last-Modified`.  Conditional headers such as `If-Range` aren't
+/// evaluated, so [`select_ranges`](super::select_ranges) should be used first where they matter.
+///
+/// # Errors
+///
+/// If the file can't seek to the start of a single range
+pub async fn range_response<T>(
+    file: T,
+    metadata: &Metadata,
+    range_header: Option<&HeaderValue>,
+) -> io::Result<http::Response<ResponseBody>>
+where
+    T: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = modified.map(|modified| ETag::from_metadata(len, modified));
+    let ranges = match range_header {
+        Some(header) => match header
+            .to_str()
+            .ok()
+            .and_then(|header| try_parse_range(header, len).ok())
+        {
+            Some(ranges) => ranges,
+            None => return Ok(range_not_satisfiable(len)),
+        },
+        None => Vec::new(),
+    };
+
+    if ranges.len() > 1 {
+        let body = MultiRangeBody::new(file, len, ranges).capacity(READ_CAPACITY);
+        let mut response = status_response(StatusCode::PARTIAL_CONTENT);
+        let headers = response.headers_mut();
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(CONTENT_TYPE, body.multipart_content_type());
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.content_length()));
+        insert_validators(headers, etag.as_ref(), modified);
+        *response.body_mut() = body.boxed_unsync();
+        return Ok(response);
+    }
+    let range = ranges.into_iter().next();
+    let mut response = headers(len, range.as_ref(), etag.as_ref(), modified);
+    if len > 0 {
+        let range = range.unwrap_or(0..=len - 1);
+        let body = ChunkSize::default().body(file, range).await?;
+        *response.body_mut() = body.boxed_unsync();
+    }
+    Ok(response)
+}
+
+/// Builds the response to a `HEAD` request for a file described by `metadata`, with the headers a
+/// `GET` would have but no body
+///
+/// `Accept-Ranges`, `Content-Length`, `ETag` and `Last-Modified` are derived from `metadata`, and
+/// `Content-Type` is set to `content_type` if given, so the file needn't be opened.
+#[must_use]
+pub fn head_response(
+    metadata: &Metadata,
+    content_type: Option<HeaderValue>,
+) -> http::Response<ResponseBody> {
+    let (len, modified) = (metadata.len(), metadata.modified().ok());
+    let etag = modified.map(|modified| ETag::from_metadata(len, modified));
+    let mut response = headers(len, None, etag.as_ref(), modified);
+    if let Some(content_type) = content_type {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+/// Responds with a file read entirely into memory, for inner services without metadata
+async fn serve_bytes<S>(
+    inner: &mut S,
+    path: Arc<Path>,
+    parts: &Parts,
+) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let bytes = match call(inner, Request::ReadBytes(path)).await {
+        Ok(Response::Bytes(bytes)) => Bytes::from(bytes),
+        Ok(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) => return status_response(error_status(&err)),
+    };
+    let len = bytes.len() as u64;
+    let range = match requested_range(parts, len, None, None) {
+        Ok(range) => range,
+        Err(response) => return response,
+    };
+    let mut response = headers(len, range.as_ref(), None, None);
+    if parts.method == Method::GET {
+        let bytes = match range.map(RangeInclusive::into_inner) {
+            // Validated ranges lie within the body, so the bounds fit in a usize
+            Some((start, end)) => {
+                let start = usize::try_from(start).unwrap_or(0);
+                let end = usize::try_from(end).unwrap_or(0);
+                bytes.slice(start..=end)
+            }
+            None => bytes,
+        };
+        *response.body_mut() = full_body(bytes);
+    }
+    response
+}
+
+/// Evaluates the conditional headers, returning a `412 Precondition Failed` or
+/// `304 Not Modified` response if the request shouldn't be served
+fn check_preconditions(
+    parts: &Parts,
+    etag: &ETag,
+    modified: SystemTime,
+) -> Option<http::Response<ResponseBody>> {
+    if precondition_failed(&parts.headers, Some(etag), Some(modified)) {
+        return Some(status_response(StatusCode::PRECONDITION_FAILED));
+    }
+    not_modified(&parts.headers, Some(etag), Some(modified))
+}
+
+/// Evaluates the `If-Match` and `If-Unmodified-Since` headers of a request to change the file at
+/// `path` against it's metadata, failing with `412 Precondition Failed` if it has changed
+///
+/// The file's tag is the strong one from `strong_etags` if it's given.  Otherwise it's the weak
+/// tag derived from the metadata, which never satisfies `If-Match` (as only strong tags can), so
+/// only `If-Match: *` and `If-Unmodified-Since` let such requests through.
+///
+/// The metadata is only requested when one of the headers is sent.  The file can still change
+/// between this check and the request which changes it.
+pub(super) async fn check_write_preconditions<S>(
+    inner: &mut S,
+    path: PathBuf,
+    headers: &HeaderMap,
+    strong_etags: Option<&StrongETags>,
+) -> Result<(), StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    if !headers.contains_key(IF_MATCH) && !headers.contains_key(IF_UNMODIFIED_SINCE) {
+        return Ok(());
+    }
+    let path: Arc<Path> = path.into();
+    let metadata = Request::GetMetadata {
+        path: path.clone(),
+        follow_symlinks: true,
+    };
+    let (etag, modified) = match call(inner, metadata).await {
+        Ok(Response::Metadata(metadata)) => {
+            let modified = metadata.modified().ok();
+            let etag = match (strong_etags, modified) {
+                (Some(strong_etags), Some(modified)) if metadata.is_file() => Some(
+                    strong_etags
+                        .etag(inner, &path, metadata.len(), modified)
+                        .await,
+                ),
+                (_, modified) => {
+                    modified.map(|modified| ETag::from_metadata(metadata.len(), modified))
+                }
+            };
+            // An existing file matches `If-Match: *` even without a tag
+            let etag = etag.or_else(|| ETag::weak(""));
+            (etag, modified)
+        }
+        Ok(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) if err.kind() == ErrorKind::NotFound => (None, None),
+        Err(err) => return Err(error_status(&err)),
+    };
+    if precondition_failed(headers, etag.as_ref(), modified) {
+        return Err(StatusCode::PRECONDITION_FAILED);
+    }
+    Ok(())
+}
+
+/// Decides which range to respond with (or `None` to respond with the whole file), or returns a
+/// `416 Range Not Satisfiable` response
+///
+/// Requests for multiple ranges are answered with the whole file.
+#[allow(clippy::result_large_err)]
+fn requested_range(
+    parts: &Parts,
+    len: u64,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> Result<Option<RangeInclusive<u64>>, http::Response<ResponseBody>> {
+    match select_ranges(&parts.headers, len, etag, modified) {
+        RangeSelection::Partial(mut ranges) if ranges.len() == 1 => Ok(ranges.pop()),
+        RangeSelection::Full | RangeSelection::Partial(_) => Ok(None),
+        RangeSelection::Unsatisfiable => Err(range_not_satisfiable(len)),
+    }
+}
+
+fn range_not_satisfiable(len: u64) -> http::Response<ResponseBody> {
+    let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+    if let Ok(value) = HeaderValue::from_str(&format!("bytes */{len}")) {
+        response.headers_mut().insert(CONTENT_RANGE, value);
+    }
+    response
+}
+
+fn headers(
+    len: u64,
+    range: Option<&RangeInclusive<u64>>,
+    etag: Option<&ETag>,
+    modified: Option<SystemTime>,
+) -> http::Response<ResponseBody> {
+    let mut response = status_response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    insert_validators(headers, etag, modified);
+    match range {
+        Some(range) => {
+            headers.insert(
+                CONTENT_LENGTH,
+                HeaderValue::from(range.end() - range.start() + 1),
+            );
+            if let Ok(value) =
+                HeaderValue::from_str(&format!("bytes {}-{}/{len}", range.start(), range.end()))
+            {
+                headers.insert(CONTENT_RANGE, value);
+            }
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        }
+        None => {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{ETAG, LAST_MODIFIED};
+
+    use super::*;
+    use crate::test_kit::TestDir;
+
+    #[tokio::test]
+    async fn test_range_response() -> io::Result<()> {
+        let dir = TestDir::new("range_response")?;
+        let path = dir.join("digits.txt");
+        std::fs::write(&path, "0123456789")?;
+        let metadata = std::fs::metadata(&path)?;
+        let respond = |range: Option<&'static str>| {
+            let (path, metadata) = (path.clone(), metadata.clone());
+            async move {
+                let file = tokio::fs::File::open(path).await?;
+                let range = range.map(HeaderValue::from_static);
+                range_response(file, &metadata, range.as_ref()).await
+            }
+        };
+
+        let response = respond(None).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "0123456789"
+        );
+
+        let response = respond(Some("bytes=-3")).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "789");
+
+        let response = respond(Some("bytes=0-0,9-9")).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap_or_default();
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let len = response.headers()[CONTENT_LENGTH].clone();
+        let body = response.into_body().collect().await?.to_bytes();
+        assert_eq!(len, body.len().to_string().as_str());
+
+        let response = respond(Some("bytes=10-")).await?;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = head_response(&metadata, Some(HeaderValue::from_static("text/plain")));
+        assert_eq!(response.status(), StatusCode::OK);
+        for name in [
+            ACCEPT_RANGES,
+            CONTENT_LENGTH,
+            CONTENT_TYPE,
+            ETAG,
+            LAST_MODIFIED,
+        ] {
+            assert!(response.headers().contains_key(name));
+        }
+        assert!(response.body().is_end_stream());
+        Ok(())
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::Poll,
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt};
+use http::{
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    HeaderValue, Method, StatusCode,
+};
+use http_body::Body;
+use tokio::{io::AsyncReadExt, sync::mpsc};
+use tokio_util::io::StreamReader;
+use tower_service::Service;
+
+use super::{
+    content_disposition,
+    error::error_status,
+    path_policy::PathPolicy,
+    serve::{call, method_not_allowed, status_response, ResponseBody},
+    AsyncReadBody, Disposition,
+};
+use crate::{
+    archive::{self, ArchiveFilter, ArchiveFormat, Compression, Encoder},
+    backend::tar::Kind,
+    Mode, Request, Response,
+};
+
+/// The size of the chunks the archive is sent in
+const CHUNK: usize = 64 * 1024;
+/// The number of chunks which can be packed ahead of the client
+const CHUNKS_AHEAD: usize = 4;
+
+/// Sends the directories under a directory of an inner `Service<Request>` as tar or zip archives,
+/// packed as they're sent, for "download folder" links
+///
+/// The request path is validated with the [`PathPolicy`] given to [`ServeArchive::path_policy`]
+/// and joined onto `base`.  The directory is walked with [`Request::ReadDir`] and it's files read
+/// with [`Request::Open`], or [`Request::ReadBytes`] for backends which can't open files, so the
+/// inner service can be any stack of middleware over a backend which lists directories.  Entries
+/// are packed in the same order as [`Request::Archive`] packs them.  The archive is written
+/// straight into the body of the response, without a temporary file, so it's sent without a
+/// `Content-Length`.
+///
+/// `GET` and `HEAD` requests are answered, with a `Content-Disposition` naming the archive after
+/// the directory; other methods get a `405 Method Not Allowed`.  Invalid paths, missing
+/// directories and files get a `404 Not Found`.  A failure once the archive has started, such as
+/// a file being removed or changing size while it's packed, ends the body with an error, so the
+/// download fails rather than being cut short.
+#[derive(Debug, Clone)]
+pub struct ServeArchive<S> {
+    base: PathBuf,
+    inner: S,
+    options: Arc<Options>,
+}
+
+/// The configuration shared by every request
+#[derive(Debug, Clone, Default)]
+struct Options {
+    format: ArchiveFormat,
+    compression: Compression,
+    filter: ArchiveFilter,
+    path_policy: PathPolicy,
+}
+
+impl<S> ServeArchive<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            options: Arc::default(),
+        }
+    }
+
+    /// Sends archives in `format`, which is tar by default
+    #[must_use]
+    pub fn format(mut self, format: ArchiveFormat) -> Self {
+        Arc::make_mut(&mut self.options).format = format;
+        self
+    }
+
+    /// Compresses archives with `compression`, which stores their contents as they are by default
+    #[must_use]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        Arc::make_mut(&mut self.options).compression = compression;
+        self
+    }
+
+    /// Leaves out the paths `filter` rejects, relative to the requested directory
+    #[must_use]
+    pub fn filter(mut self, filter: ArchiveFilter) -> Self {
+        Arc::make_mut(&mut self.options).filter = filter;
+        self
+    }
+
+    /// Validates request paths with `path_policy`, answering those it rejects with a
+    /// `404 Not Found`
+    #[must_use]
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        Arc::make_mut(&mut self.options).path_policy = path_policy;
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for ServeArchive<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let (mut inner, options) = (self.inner.clone(), self.options.clone());
+        let (parts, _) = req.into_parts();
+        let path = options
+            .path_policy
+            .build(parts.uri.path())
+            .map(|path| self.base.join(path));
+        async move {
+            if parts.method != Method::GET && parts.method != Method::HEAD {
+                return Ok(method_not_allowed("GET, HEAD"));
+            }
+            let Ok(path) = path else {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            };
+            let metadata = Request::GetMetadata {
+                path: path.as_path().into(),
+                follow_symlinks: true,
+            };
+            match call(&mut inner, metadata).await {
+                Ok(Response::Metadata(metadata)) if metadata.is_dir() => {}
+                Ok(_) => return Ok(status_response(StatusCode::NOT_FOUND)),
+                Err(err) => return Ok(status_response(error_status(&err))),
+            }
+            let mut response = options.headers(&path);
+            if parts.method == Method::GET {
+                *response.body_mut() = options.body(inner, path);
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+impl Options {
+    /// The extension and `Content-Type` of archives
+    fn kind(&self) -> (&'static str, &'static str) {
+        match (self.format, self.compression) {
+            (ArchiveFormat::Tar, Compression::Stored) => ("tar", "application/x-tar"),
+            (ArchiveFormat::Tar, Compression::Deflate) => ("tar.gz", "application/gzip"),
+            (ArchiveFormat::Zip, _) => ("zip", "application/zip"),
+        }
+    }
+
+    fn headers(&self, dir: &Path) -> http::Response<ResponseBody> {
+        let (extension, content_type) = self.kind();
+        let mut file_name = dir
+            .file_name()
+            .map_or_else(|| "archive".into(), ToOwned::to_owned);
+        file_name.push(".");
+        file_name.push(extension);
+
+        let mut response = status_response(StatusCode::OK);
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(
+            CONTENT_DISPOSITION,
+            content_disposition(Disposition::Attachment, Path::new(&file_name)),
+        );
+        response
+    }
+
+    /// Packs `dir` in a task of it's own, sending the archive through a channel read by the body
+    fn body<S>(&self, mut inner: S, dir: PathBuf) -> ResponseBody
+    where
+        S: Service<Request, Response = Response, Error = io::Error> + Send + 'static,
+        S::Future: Send,
+    {
+        let (sender, mut receiver) = mpsc::channel(CHUNKS_AHEAD);
+        let mut out = Chunks {
+            sender,
+            buf: Vec::with_capacity(CHUNK),
+        };
+        let options = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = options.pack(&mut inner, &dir, &mut out).await {
+                // Nothing is listening any more if the client went away
+                let _ = out.sender.send(Err(err)).await;
+            }
+        });
+        let chunks = futures::stream::poll_fn(move |cx| receiver.poll_recv(cx));
+        AsyncReadBody::with_capacity(StreamReader::new(chunks), CHUNK).boxed_unsync()
+    }
+
+    /// Packs the directory `dir` of `inner` into `out`, walking it depth first in order of the
+    /// entries' names, each directory's entries before those of its subdirectories
+    async fn pack<S>(&self, inner: &mut S, dir: &Path, out: &mut Chunks) -> io::Result<()>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let mut encoder = Encoder::new(self.format, self.compression);
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(subdir) = dirs.pop() {
+            let listing = Request::ReadDir(dir.join(&subdir).into());
+            let mut children = call(inner, listing).await?.into_directory()?;
+            children.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let mut subdirs = Vec::new();
+            for (name, metadata) in children {
+                let path = subdir.join(name);
+                if self.filter.skips(&path) {
+                    continue;
+                }
+                if metadata.is_dir() {
+                    subdirs.push(path.clone());
+                }
+                if !self.filter.matches(&path) {
+                    continue;
+                }
+                let full_path: Arc<Path> = dir.join(&path).into();
+                let target = if metadata.is_symlink() {
+                    let follow = Request::FollowLink(full_path.clone());
+                    Some(call(inner, follow).await?.into_points_to()?)
+                } else {
+                    None
+                };
+                let Some(entry) = archive::entry(&metadata, target) else {
+                    continue;
+                };
+                out.write(&encoder.entry(&path, &entry)?).await?;
+                if entry.kind == Kind::File {
+                    send_contents(inner, full_path, &mut encoder, out).await?;
+                    out.write(&encoder.end_file()?).await?;
+                }
+            }
+            // Pushed in reverse, so they're popped in order
+            dirs.extend(subdirs.into_iter().rev());
+        }
+        out.write(&encoder.finish()?).await?;
+        out.flush().await
+    }
+}
+
+/// Adds the contents of the file at `path` to the archive, reading it a chunk at a time if the
+/// inner service can open it, and whole otherwise
+async fn send_contents<S>(
+    inner: &mut S,
+    path: Arc<Path>,
+    encoder: &mut Encoder,
+    out: &mut Chunks,
+) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let open = Request::Open {
+        mode: Mode::Read,
+        path: path.clone(),
+    };
+    let mut file = match call(inner, open).await {
+        Ok(response) => response.into_file()?,
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            let bytes = call(inner, Request::ReadBytes(path)).await?.into_bytes()?;
+            return out.write(&encoder.data(&bytes)?).await;
+        }
+        Err(err) => return Err(err),
+    };
+    let mut buf = vec![0; CHUNK];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        out.write(&encoder.data(&buf[..read])?).await?;
+    }
+}
+
+/// Gathers what's written to it into chunks of a body, failing with [`ErrorKind::BrokenPipe`]
+/// once the body is dropped
+struct Chunks {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Chunks {
+    async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= CHUNK {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK));
+        self.sender
+            .send(Ok(chunk.into()))
+            .await
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::{deflate::gunzip, ExtractOptions},
+        test_kit::TestDir,
+        FileSystem,
+    };
+
+    async fn get<S>(service: &mut S, method: Method, uri: &str) -> http::Response<ResponseBody>
+    where
+        S: Service<http::Request<()>, Response = http::Response<ResponseBody>, Error = Infallible>,
+    {
+        let Ok(request) = http::Request::builder().method(method).uri(uri).body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+
+    async fn read_body(mut body: ResponseBody) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend(chunk?);
+        }
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn test_serve_archive() -> io::Result<()> {
+        let dir = TestDir::new("serve_archive")?;
+        std::fs::create_dir_all(dir.join("photos/2024"))?;
+        // Larger than a chunk, so it's sent in several
+        let large = (0..200_000u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        std::fs::write(dir.join("photos/2024/large.raw"), &large)?;
+        std::fs::write(dir.join("photos/notes.txt"), "notes")?;
+        std::fs::write(dir.join("photos/thumbs.db"), "thumbs")?;
+        let mut service = ServeArchive::new(&dir, FileSystem::new())
+            .compression(Compression::Deflate)
+            .filter(ArchiveFilter::new().exclude("**.db"));
+
+        let response = get(&mut service, Method::HEAD, "/photos").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/gzip");
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            r#"attachment; filename="photos.tar.gz""#
+        );
+        assert!(response.body().is_end_stream());
+
+        let response = get(&mut service, Method::GET, "/photos").await;
+        let archive = gunzip(&read_body(response.into_body()).await?, u64::MAX)?;
+        let tar = dir.join("photos.tar");
+        std::fs::write(&tar, archive)?;
+        let unpacked = dir.join("unpacked");
+        crate::archive::extract(&tar, &unpacked, &ExtractOptions::default())?;
+        assert_eq!(std::fs::read(unpacked.join("2024/large.raw"))?, large);
+        assert_eq!(std::fs::read(unpacked.join("notes.txt"))?, b"notes");
+        assert!(!unpacked.join("thumbs.db").exists());
+
+        let mut service = service.format(ArchiveFormat::Zip);
+        let response = get(&mut service, Method::GET, "/photos/2024").await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/zip");
+        let zip = dir.join("2024.zip");
+        std::fs::write(&zip, read_body(response.into_body()).await?)?;
+        let unpacked = dir.join("unpacked_zip");
+        crate::archive::extract(&zip, &unpacked, &ExtractOptions::default())?;
+        assert_eq!(std::fs::read(unpacked.join("large.raw"))?, large);
+
+        for (method, uri, status) in [
+            (Method::GET, "/photos/notes.txt", StatusCode::NOT_FOUND),
+            (Method::GET, "/missing", StatusCode::NOT_FOUND),
+            (Method::GET, "/../secret", StatusCode::NOT_FOUND),
+            (Method::PUT, "/photos", StatusCode::METHOD_NOT_ALLOWED),
+        ] {
+            assert_eq!(
+                get(&mut service, method, uri).await.status(),
+                status,
+                "{uri}"
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "middleware"))]
+    #[tokio::test]
+    async fn test_inner_service() -> io::Result<()> {
+        use tower_layer::Layer;
+
+        use crate::backend::tar::Tar;
+
+        let dir = TestDir::new("archive_inner")?;
+        std::fs::create_dir_all(dir.join("root/shared"))?;
+        std::fs::create_dir_all(dir.join("outside"))?;
+        std::fs::write(dir.join("root/shared/notes.txt"), "notes")?;
+        std::os::unix::fs::symlink("../../outside", dir.join("root/shared/escape"))?;
+        std::os::unix::fs::symlink("../outside", dir.join("root/escape"))?;
+        let root = crate::middleware::root::RootLayer::new(dir.join("root"))?;
+        // Archives are packed through the inner service, so the root keeps them inside it
+        let mut service = ServeArchive::new("/", root.layer(FileSystem::new()));
+
+        let response = get(&mut service, Method::GET, "/escape").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get(&mut service, Method::GET, "/shared").await;
+        let tar = dir.join("shared.tar");
+        std::fs::write(&tar, read_body(response.into_body()).await?)?;
+        let mut tar = Tar::open(&tar).await?;
+        let notes = tar.call(Request::ReadBytes(Path::new("notes.txt").into()));
+        assert_eq!(notes.await?.into_bytes()?, b"notes");
+        // The link is stored as it is, rather than followed
+        let escape = tar.call(Request::FollowLink(Path::new("escape").into()));
+        assert_eq!(escape.await?.into_points_to()?, Path::new("../../outside"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() -> io::Result<()> {
+        let dir = TestDir::new("archive_policy")?;
+        std::fs::create_dir_all(dir.join(".git"))?;
+        std::fs::write(dir.join(".git/config"), "")?;
+        let mut service = ServeArchive::new(&dir, FileSystem::new())
+            .path_policy(PathPolicy::new().dotfiles(false));
+
+        let response = get(&mut service, Method::GET, "/.git").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
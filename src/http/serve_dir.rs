@@ -0,0 +1,646 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    task::Poll,
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, FutureExt};
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    request::Parts,
+    HeaderValue, Method, StatusCode,
+};
+use tower_service::Service;
+
+use super::{
+    cache_control::CacheControl,
+    chunk_size::ChunkSize,
+    error::into_problem,
+    mime::MimeTypes,
+    path_policy::PathPolicy,
+    precompressed::{serve_precompressed, Encoding},
+    serve::{call, full_body, method_not_allowed, status_response, ResponseBody},
+    strong_etags::StrongETags,
+    PathError,
+};
+use crate::{Request, Response};
+
+/// Serves the files under a directory of an inner `Service<Request>` over HTTP
+///
+/// The request path is validated with the [`PathPolicy`] given to [`ServeDir::path_policy`] and
+/// joined onto `base`, and the file is streamed back with [`Request::GetMetadata`] and
+/// [`Request::Open`], so the inner service can be any stack of middleware over a backend.
+/// Backends which can't open files are read with [`Request::ReadRange`] or [`Request::ReadBytes`]
+/// instead.
+///
+/// `GET` and `HEAD` requests are answered, including single `Range` requests and the `If-Match`,
+/// `If-None-Match`, `If-Modified-Since` and `If-Unmodified-Since` headers, with a weak
+/// [`ETag`](super::ETag) derived from each file's length and modification time, or a strong one
+/// as enabled by [`ServeDir::strong_etags`]; other methods get a `405 Method Not Allowed`.  `HEAD`
+/// requests get the same headers as `GET`, from the file's metadata, without the file being opened
+/// unless it's digest is needed.  Invalid paths, missing files and directories get a
+/// `404 Not Found`.
+///
+/// Files are sent with the `Content-Type` resolved by [`ServeDir::mime_types`], which by default
+/// only knows common extensions.  Precompressed copies of files can be sent instead to clients
+/// which accept them, as enabled by [`ServeDir::precompressed`], and directories can be answered
+/// with an index file, as enabled by [`ServeDir::index_files`].  Files can be sent with a
+/// `Cache-Control` header, chosen by [`ServeDir::cache_control`].  Error responses have no body,
+/// unless a page is configured for their status with [`ServeDir::error_page`], or problem details
+/// are enabled by [`ServeDir::problem_details`].
+#[derive(Debug, Clone)]
+pub struct ServeDir<S> {
+    base: PathBuf,
+    inner: S,
+    options: Arc<Options>,
+}
+
+/// The configuration shared by every request
+#[derive(Debug, Clone, Default)]
+struct Options {
+    mime_types: MimeTypes,
+    precompressed: Vec<Encoding>,
+    index_files: Vec<String>,
+    error_pages: HashMap<StatusCode, ErrorPage>,
+    path_policy: PathPolicy,
+    cache_control: CacheControl,
+    strong_etags: Option<StrongETags>,
+    chunk_size: ChunkSize,
+    problem_details: bool,
+}
+
+#[derive(Debug, Clone)]
+struct ErrorPage {
+    path: PathBuf,
+    /// The contents of the page, once it's been read
+    body: OnceLock<Bytes>,
+}
+
+impl<S> ServeDir<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            options: Arc::default(),
+        }
+    }
+
+    /// Resolves the `Content-Type` of files with `mime_types`
+    #[must_use]
+    pub fn mime_types(mut self, mime_types: MimeTypes) -> Self {
+        Arc::make_mut(&mut self.options).mime_types = mime_types;
+        self
+    }
+
+    /// Sends the copy of each file compressed with `encoding`, found by appending it's
+    /// [extension](Encoding::extension) to the path, to clients which accept the encoding
+    ///
+    /// Encodings are chosen by the client's preferences given in `Accept-Encoding`, and otherwise
+    /// in the order of [`Encoding`]'s variants.  If no accepted copy exists the file itself is
+    /// sent.  Responses have a `Vary: accept-encoding` header once any encoding is enabled.
+    #[must_use]
+    pub fn precompressed(mut self, encoding: Encoding) -> Self {
+        let precompressed = &mut Arc::make_mut(&mut self.options).precompressed;
+        precompressed.push(encoding);
+        precompressed.sort_unstable();
+        precompressed.dedup();
+        self
+    }
+
+    /// Answers requests for directories with the first of `index_files` (such as `index.html`)
+    /// found in them
+    ///
+    /// Requests for a directory without a trailing slash are redirected to the path with one, with
+    /// a `301 Moved Permanently`, so relative links in the index resolve within the directory.
+    /// Directories without an index file get a `404 Not Found`.
+    #[must_use]
+    pub fn index_files<I>(mut self, index_files: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Arc::make_mut(&mut self.options).index_files =
+            index_files.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sends the `Cache-Control` header chosen by `cache_control` with files, and with
+    /// `304 Not Modified` responses for them
+    ///
+    /// `Content-Type` rules only apply to successful responses, which are the only ones sent with
+    /// the type.
+    #[must_use]
+    pub fn cache_control(mut self, cache_control: CacheControl) -> Self {
+        Arc::make_mut(&mut self.options).cache_control = cache_control;
+        self
+    }
+
+    /// Sends strong `ETag`s, hashed from the contents of files by `strong_etags`, rather than weak
+    /// ones derived from their metadata
+    ///
+    /// Strong tags let `If-Match` and `If-Range` match, and are required by some caches, at the
+    /// cost of reading each version of a file once more, including for `HEAD` requests.
+    #[must_use]
+    pub fn strong_etags(mut self, strong_etags: StrongETags) -> Self {
+        Arc::make_mut(&mut self.options).strong_etags = Some(strong_etags);
+        self
+    }
+
+    /// Reads files as `chunk_size` chooses for their length, which by default sends files up to
+    /// 256 KiB in one chunk and reads longer ones into pooled buffers
+    #[must_use]
+    pub fn chunk_size(mut self, chunk_size: ChunkSize) -> Self {
+        Arc::make_mut(&mut self.options).chunk_size = chunk_size;
+        self
+    }
+
+    /// Validates request paths with `path_policy`, answering those it rejects with a
+    /// `404 Not Found`
+    #[must_use]
+    pub fn path_policy(mut self, path_policy: PathPolicy) -> Self {
+        Arc::make_mut(&mut self.options).path_policy = path_policy;
+        self
+    }
+
+    /// Sends the file at `path` as the body of responses with `status`, such as a branded
+    /// `404 Not Found` page
+    ///
+    /// `path` is read with [`Request::ReadBytes`] the first time it's needed, and then kept in
+    /// memory, so later changes to the file aren't seen.  If it can't be read the response is sent
+    /// without a body, and reading is tried again for the next response.
+    #[must_use]
+    pub fn error_page<P: Into<PathBuf>>(mut self, status: StatusCode, path: P) -> Self {
+        let page = ErrorPage {
+            path: path.into(),
+            body: OnceLock::new(),
+        };
+        Arc::make_mut(&mut self.options)
+            .error_pages
+            .insert(status, page);
+        self
+    }
+
+    /// Gives error responses without an [error page](ServeDir::error_page) an
+    /// `application/problem+json` body, as described by RFC 9457, with the status' reason phrase
+    /// as it's title
+    #[must_use]
+    pub fn problem_details(mut self, problem_details: bool) -> Self {
+        Arc::make_mut(&mut self.options).problem_details = problem_details;
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for ServeDir<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let (mut inner, options) = (self.inner.clone(), self.options.clone());
+        let (parts, _) = req.into_parts();
+        let path = options
+            .path_policy
+            .build(parts.uri.path())
+            .map(|path| self.base.join(path));
+        async move {
+            let mut response = options.respond(&mut inner, path, &parts).await;
+            options
+                .insert_error_page(&mut inner, &mut response, &parts)
+                .await;
+            if options.problem_details && parts.method != Method::HEAD {
+                response = into_problem(response);
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+impl Options {
+    async fn respond<S>(
+        &self,
+        inner: &mut S,
+        path: Result<PathBuf, PathError>,
+        parts: &Parts,
+    ) -> http::Response<ResponseBody>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        if parts.method != Method::GET && parts.method != Method::HEAD {
+            return method_not_allowed("GET, HEAD");
+        }
+        let Ok(path) = path else {
+            return status_response(StatusCode::NOT_FOUND);
+        };
+        if self.index_files.is_empty() {
+            return self.serve(inner, path, parts).await;
+        }
+        if parts.uri.path().ends_with('/') {
+            return self.serve_index(inner, &path, parts).await;
+        }
+
+        let response = self.serve(inner, path.clone(), parts).await;
+        if response.status() != StatusCode::NOT_FOUND {
+            return response;
+        }
+        let metadata = Request::GetMetadata {
+            path: path.into(),
+            follow_symlinks: true,
+        };
+        match call(inner, metadata).await {
+            Ok(Response::Metadata(metadata)) if metadata.is_dir() => redirect_to_dir(parts),
+            _ => response,
+        }
+    }
+
+    /// Serves the file at `path`, or a precompressed copy of it
+    async fn serve<S>(
+        &self,
+        inner: &mut S,
+        path: PathBuf,
+        parts: &Parts,
+    ) -> http::Response<ResponseBody>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let mut response = serve_precompressed(
+            inner,
+            path.clone(),
+            parts,
+            &self.precompressed,
+            self.strong_etags.as_ref(),
+            self.chunk_size,
+        )
+        .await;
+        if response.status().is_success() {
+            if let Some(content_type) = self.mime_types.resolve(inner, path).await {
+                response.headers_mut().insert(CONTENT_TYPE, content_type);
+            }
+        }
+        if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+            self.cache_control
+                .apply(parts.uri.path(), response.headers_mut());
+        }
+        response
+    }
+
+    /// Serves the first index file found in the directory at `path`
+    async fn serve_index<S>(
+        &self,
+        inner: &mut S,
+        path: &Path,
+        parts: &Parts,
+    ) -> http::Response<ResponseBody>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        for index_file in &self.index_files {
+            let response = self.serve(inner, path.join(index_file), parts).await;
+            if response.status() != StatusCode::NOT_FOUND {
+                return response;
+            }
+        }
+        status_response(StatusCode::NOT_FOUND)
+    }
+
+    /// Gives `response` the body of the error page for it's status, if there is one
+    async fn insert_error_page<S>(
+        &self,
+        inner: &mut S,
+        response: &mut http::Response<ResponseBody>,
+        parts: &Parts,
+    ) where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let Some(page) = self.error_pages.get(&response.status()) else {
+            return;
+        };
+        let body = match page.body.get() {
+            Some(body) => body.clone(),
+            None => match call(inner, Request::ReadBytes(page.path.as_path().into())).await {
+                Ok(Response::Bytes(bytes)) => page.body.get_or_init(|| bytes.into()).clone(),
+                _ => return,
+            },
+        };
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        match self.mime_types.guess(&page.path) {
+            Some(content_type) => headers.insert(CONTENT_TYPE, content_type),
+            None => headers.remove(CONTENT_TYPE),
+        };
+        if parts.method != Method::HEAD {
+            *response.body_mut() = full_body(body);
+        }
+    }
+}
+
+/// Redirects a request for a directory to the path with a trailing slash, keeping the query
+fn redirect_to_dir(parts: &Parts) -> http::Response<ResponseBody> {
+    let location = match parts.uri.query() {
+        Some(query) => format!("{}/?{query}", parts.uri.path()),
+        None => format!("{}/", parts.uri.path()),
+    };
+    let Ok(location) = HeaderValue::try_from(location) else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+    let mut response = status_response(StatusCode::MOVED_PERMANENTLY);
+    response.headers_mut().insert(LOCATION, location);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::{
+        ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_RANGE, ETAG, RANGE, VARY,
+    };
+    use http_body::Body;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn get(
+        service: &mut ServeDir<FileSystem>,
+        uri: &str,
+        range: Option<&str>,
+    ) -> http::Response<ResponseBody> {
+        let mut request = http::Request::builder().uri(uri);
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+        let Ok(request) = request.body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_dir() -> io::Result<()> {
+        let dir = TestDir::new("serve_dir")?;
+        std::fs::write(dir.join("hello.txt"), "hello world")?;
+        let mut service = ServeDir::new(&dir, FileSystem::new());
+
+        let response = get(&mut service, "/hello.txt", Some("bytes=6-")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 6-10/11");
+        let body = response.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "world");
+
+        let response = get(&mut service, "/hello.txt", None).await;
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = response.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "hello world");
+
+        for (uri, range, status) in [
+            ("/missing.txt", None, StatusCode::NOT_FOUND),
+            ("/../hello.txt", None, StatusCode::NOT_FOUND),
+            ("/", None, StatusCode::NOT_FOUND),
+            (
+                "/hello.txt",
+                Some("bytes=20-"),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            ),
+        ] {
+            assert_eq!(get(&mut service, uri, range).await.status(), status);
+        }
+        Ok(())
+    }
+
+    /// Forwards requests to the [`FileSystem`], recording their debug representations
+    #[derive(Debug, Clone, Default)]
+    struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Service<Request> for Recorder {
+        type Response = Response;
+        type Error = io::Error;
+        type Future = BoxFuture<'static, io::Result<Response>>;
+
+        fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request) -> Self::Future {
+            if let Ok(mut requests) = self.0.lock() {
+                requests.push(format!("{req:?}"));
+            }
+            FileSystem::new().call(req).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head() -> io::Result<()> {
+        let dir = TestDir::new("serve_head")?;
+        std::fs::write(dir.join("hello.txt"), "hello world")?;
+        let recorder = Recorder::default();
+        let mut service = ServeDir::new(&dir, recorder.clone());
+
+        let Ok(request) = http::Request::head("/hello.txt").body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        let response = match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[CONTENT_LENGTH], "11");
+        assert_eq!(headers[CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(headers[ACCEPT_RANGES], "bytes");
+        assert!(headers.contains_key(ETAG));
+        assert!(response.into_body().collect().await?.to_bytes().is_empty());
+
+        let requests = recorder.0.lock().map(|requests| requests.clone());
+        assert!(requests.is_ok_and(|requests| requests
+            .iter()
+            .all(|request| request.starts_with("GetMetadata"))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_files() -> io::Result<()> {
+        let dir = TestDir::new("index_files")?;
+        std::fs::create_dir_all(dir.join("docs"))?;
+        std::fs::create_dir_all(dir.join("empty"))?;
+        std::fs::write(dir.join("docs/index.htm"), "docs")?;
+        let mut service =
+            ServeDir::new(&dir, FileSystem::new()).index_files(["index.html", "index.htm"]);
+
+        let response = get(&mut service, "/docs/", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "docs");
+
+        let response = get(&mut service, "/docs?lang=en", None).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()[LOCATION], "/docs/?lang=en");
+        for uri in ["/empty/", "/missing", "/docs/index.htm/"] {
+            let response = get(&mut service, uri, None).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        let response = get(&mut service, "/docs/index.htm", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_page() -> io::Result<()> {
+        let dir = TestDir::new("error_page")?;
+        let site = dir.join("site");
+        std::fs::create_dir_all(&site)?;
+        std::fs::write(dir.join("404.html"), "not here")?;
+        let mut service = ServeDir::new(&site, FileSystem::new())
+            .error_page(StatusCode::NOT_FOUND, dir.join("404.html"));
+
+        let response = get(&mut service, "/missing.txt", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "not here");
+
+        // The page is cached once read
+        std::fs::remove_file(dir.join("404.html"))?;
+        let response = get(&mut service, "/../escape", None).await;
+        assert_eq!(response.headers()[CONTENT_LENGTH], "8");
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "not here");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_problem_details() -> io::Result<()> {
+        let dir = TestDir::new("serve_problem")?;
+        let site = dir.join("site");
+        std::fs::create_dir_all(&site)?;
+        std::fs::write(dir.join("404.html"), "not here")?;
+        std::fs::write(site.join("hello.txt"), "hello world")?;
+        let mut service = ServeDir::new(&site, FileSystem::new()).problem_details(true);
+
+        let response = get(&mut service, "/missing.txt", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            r#"{"type":"about:blank","title":"Not Found","status":404}"#
+        );
+        let response = get(&mut service, "/hello.txt", Some("bytes=20-")).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */11");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let response = get(&mut service, "/hello.txt", None).await;
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "hello world"
+        );
+
+        // Error pages take precedence
+        let mut service = service.error_page(StatusCode::NOT_FOUND, dir.join("404.html"));
+        let response = get(&mut service, "/missing.txt", None).await;
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "not here");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_precompressed() -> io::Result<()> {
+        let dir = TestDir::new("precompressed")?;
+        std::fs::write(dir.join("app.js"), "identity")?;
+        std::fs::write(dir.join("app.js.gz"), "gzipped")?;
+        let mut service = ServeDir::new(&dir, FileSystem::new())
+            .precompressed(Encoding::Gzip)
+            .precompressed(Encoding::Brotli);
+
+        for (accept, encoding, body) in [
+            (Some("br, gzip"), Some("gzip"), "gzipped"),
+            (Some("gzip;q=0"), None, "identity"),
+            (None, None, "identity"),
+        ] {
+            let mut request = http::Request::builder().uri("/app.js");
+            if let Some(accept) = accept {
+                request = request.header(ACCEPT_ENCODING, accept);
+            }
+            let Ok(request) = request.body(()) else {
+                unreachable!("the test requests are valid")
+            };
+            let response = match service.call(request).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            assert_eq!(
+                response
+                    .headers()
+                    .get(CONTENT_ENCODING)
+                    .map(HeaderValue::as_bytes),
+                encoding.map(str::as_bytes)
+            );
+            assert_eq!(response.headers()[VARY], "accept-encoding");
+            assert_eq!(
+                response.headers()[CONTENT_TYPE],
+                "text/javascript; charset=utf-8"
+            );
+            assert_eq!(response.into_body().collect().await?.to_bytes(), body);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_control() -> io::Result<()> {
+        let dir = TestDir::new("cache_control")?;
+        std::fs::create_dir_all(dir.join("assets"))?;
+        std::fs::write(dir.join("assets/app.js"), "app")?;
+        std::fs::write(dir.join("index.html"), "index")?;
+        let cache_control = CacheControl::new()
+            .path(
+                "assets/**",
+                HeaderValue::from_static("max-age=31536000, immutable"),
+            )
+            .content_type("text/html", HeaderValue::from_static("no-cache"));
+        let mut service = ServeDir::new(&dir, FileSystem::new()).cache_control(cache_control);
+
+        for (uri, expected) in [
+            ("/assets/app.js", Some("max-age=31536000, immutable")),
+            ("/index.html", Some("no-cache")),
+            ("/missing.html", None),
+        ] {
+            let response = get(&mut service, uri, None).await;
+            assert_eq!(
+                response
+                    .headers()
+                    .get(http::header::CACHE_CONTROL)
+                    .map(HeaderValue::as_bytes),
+                expected.map(str::as_bytes)
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_policy() -> io::Result<()> {
+        let dir = TestDir::new("serve_policy")?;
+        std::fs::write(dir.join(".env"), "SECRET=1")?;
+        let mut service = ServeDir::new(&dir, FileSystem::new());
+        assert_eq!(
+            get(&mut service, "/.env", None).await.status(),
+            StatusCode::OK
+        );
+
+        let mut service = service.path_policy(PathPolicy::new().dotfiles(false));
+        let response = get(&mut service, "/.env", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
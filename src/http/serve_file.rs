@@ -0,0 +1,183 @@
+use std::{
+    convert::Infallible,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use http::{
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    HeaderValue, Method,
+};
+use tower_service::Service;
+
+use super::{
+    chunk_size::ChunkSize,
+    disposition::{content_disposition, Disposition},
+    mime::MimeTypes,
+    serve::{method_not_allowed, serve_file, ResponseBody},
+};
+use crate::{Request, Response};
+
+/// Serves a single file of an inner `Service<Request>` over HTTP, whatever the request path
+///
+/// Useful for `/favicon.ico`, `/robots.txt` or download endpoints.  Files are served as by
+/// [`ServeDir`](super::ServeDir), honoring single `Range` requests and the conditional headers, and
+/// sent with the `Content-Type` given to [`ServeFile::content_type`], or otherwise the type
+/// [`MimeTypes::guess`](super::MimeTypes::guess) finds for it's extension, if any.  Download
+/// endpoints can also send a `Content-Disposition` with [`ServeFile::disposition`].
+#[derive(Debug, Clone)]
+pub struct ServeFile<S> {
+    path: Arc<Path>,
+    content_type: Option<HeaderValue>,
+    disposition: Option<HeaderValue>,
+    chunk_size: ChunkSize,
+    inner: S,
+}
+
+impl<S> ServeFile<S> {
+    pub fn new<P: Into<PathBuf>>(path: P, inner: S) -> Self {
+        let path: PathBuf = path.into();
+        Self {
+            content_type: MimeTypes::new().guess(&path),
+            disposition: None,
+            chunk_size: ChunkSize::default(),
+            path: path.into(),
+            inner,
+        }
+    }
+
+    /// Sends `content_type` as the `Content-Type` of the file
+    #[must_use]
+    pub fn content_type(mut self, content_type: HeaderValue) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+
+    /// Sends a `Content-Disposition` with the file's name, so browsers display or download it as
+    /// `disposition` says
+    #[must_use]
+    pub fn disposition(mut self, disposition: Disposition) -> Self {
+        self.disposition = Some(content_disposition(disposition, &self.path));
+        self
+    }
+
+    /// Reads the file as `chunk_size` chooses for it's length, as [`ServeDir::chunk_size`] does
+    ///
+    /// [`ServeDir::chunk_size`]: super::ServeDir::chunk_size
+    #[must_use]
+    pub fn chunk_size(mut self, chunk_size: ChunkSize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for ServeFile<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let (path, content_type) = (self.path.clone(), self.content_type.clone());
+        let (disposition, chunk_size) = (self.disposition.clone(), self.chunk_size);
+        let (parts, _) = req.into_parts();
+        async move {
+            if parts.method != Method::GET && parts.method != Method::HEAD {
+                return Ok(method_not_allowed("GET, HEAD"));
+            }
+            let mut response = serve_file(&mut inner, path, &parts, None, chunk_size).await;
+            if response.status().is_success() {
+                let headers = response.headers_mut();
+                if let Some(content_type) = content_type {
+                    headers.insert(CONTENT_TYPE, content_type);
+                }
+                if let Some(disposition) = disposition {
+                    headers.insert(CONTENT_DISPOSITION, disposition);
+                }
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED},
+        StatusCode,
+    };
+    use http_body::Body;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem};
+
+    async fn get(
+        service: &mut ServeFile<FileSystem>,
+        header: Option<(http::HeaderName, &HeaderValue)>,
+    ) -> http::Response<ResponseBody> {
+        let mut request = http::Request::builder().uri("/any/path");
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        let Ok(request) = request.body(()) else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_file() -> io::Result<()> {
+        let dir = TestDir::new("serve_file")?;
+        let path = dir.join("robots.txt");
+        std::fs::write(&path, "User-agent: *")?;
+        let mut service = ServeFile::new(&path, FileSystem::new())
+            .content_type(HeaderValue::from_static("text/plain"));
+
+        let response = get(&mut service, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        let last_modified = response.headers()[LAST_MODIFIED].clone();
+        let etag = response.headers()[ETAG].clone();
+        let body = response.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "User-agent: *");
+
+        let response = get(&mut service, Some((IF_MODIFIED_SINCE, &last_modified))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.headers().get(CONTENT_TYPE).is_none());
+        let response = get(&mut service, Some((IF_NONE_MATCH, &etag))).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag);
+        let response = get(&mut service, Some((IF_UNMODIFIED_SINCE, &last_modified))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let stale = HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT");
+        let response = get(&mut service, Some((IF_UNMODIFIED_SINCE, &stale))).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let mut service = service.disposition(Disposition::Attachment);
+        let response = get(&mut service, None).await;
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"robots.txt\""
+        );
+
+        std::fs::remove_file(&path)?;
+        let response = get(&mut service, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
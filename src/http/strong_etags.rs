@@ -0,0 +1,298 @@
+//! Strong entity tags derived from the contents of files
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+use tokio::io::AsyncReadExt;
+use tower_service::Service;
+
+use super::{conditional::ETag, serve::call};
+use crate::{digest::Sha256, BufferPool, Request, Response};
+
+/// Generates strong [`ETag`]s from the SHA-256 digest of each file's contents, for deployments
+/// behind caches which only accept strong validators
+///
+/// Digests are kept in memory, keyed by the path, modification time and length of the file, so
+/// each version of a file is only read once.  Files longer than the `max_len` given to
+/// [`StrongETags::new`], and files which change while they're read, get the weak tag from
+/// [`ETag::from_metadata`] instead.  Clones share the same cache, and it's use is counted by
+/// [`StrongETags::stats`].
+#[derive(Debug, Clone)]
+pub struct StrongETags {
+    max_len: u64,
+    capacity: usize,
+    cache: Arc<Mutex<Cache>>,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<PathBuf, Entry>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    modified: SystemTime,
+    len: u64,
+    etag: ETag,
+}
+
+/// How the cache of a [`StrongETags`] has been used, for metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CacheStats {
+    /// Tags served from the cache
+    pub hits: u64,
+    /// Tags which had to be hashed, as the file wasn't cached or had changed since.  Files too
+    /// long to hash aren't counted
+    pub misses: u64,
+    /// Digests dropped to make room for others
+    pub evictions: u64,
+    /// The number of digests cached now
+    pub len: usize,
+    pub capacity: usize,
+}
+
+/// A digest held by the cache of a [`StrongETags`], as listed by [`StrongETags::entries`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CachedETag {
+    pub path: PathBuf,
+    /// The modification time of the file when it was hashed
+    pub modified: SystemTime,
+    /// The length of the file when it was hashed
+    pub len: u64,
+    pub etag: ETag,
+}
+
+impl StrongETags {
+    /// Hashes files up to `max_len` bytes long
+    #[must_use]
+    pub fn new(max_len: u64) -> Self {
+        Self {
+            max_len,
+            capacity: 1024,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Keeps the digests of at most `capacity` files, which defaults to 1024
+    #[must_use]
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// The hits, misses and evictions of the cache so far, and how full it is
+    #[must_use]
+    pub fn stats(&self) -> CacheStats {
+        let cache = self.lock();
+        CacheStats {
+            hits: cache.hits,
+            misses: cache.misses,
+            evictions: cache.evictions,
+            len: cache.entries.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// The digests cached now, sorted by path, for debugging
+    #[must_use]
+    pub fn entries(&self) -> Vec<CachedETag> {
+        let mut entries: Vec<_> = self
+            .lock()
+            .entries
+            .iter()
+            .map(|(path, entry)| CachedETag {
+                path: path.clone(),
+                modified: entry.modified,
+                len: entry.len,
+                etag: entry.etag.clone(),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The tag for the file at `path`, with the given length and modification time, reading it
+    /// through `inner` unless it's digest is cached
+    pub(super) async fn etag<S>(
+        &self,
+        inner: &mut S,
+        path: &Arc<Path>,
+        len: u64,
+        modified: SystemTime,
+    ) -> ETag
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let weak = ETag::from_metadata(len, modified);
+        if len > self.max_len {
+            return weak;
+        }
+        {
+            let mut cache = self.lock();
+            let cached = cache
+                .entries
+                .get(&**path)
+                .filter(|entry| entry.modified == modified && entry.len == len)
+                .map(|entry| entry.etag.clone());
+            if let Some(etag) = cached {
+                cache.hits += 1;
+                return etag;
+            }
+            cache.misses += 1;
+        }
+
+        let Ok(digest) = digest(inner, path.clone()).await else {
+            return weak;
+        };
+        // A file written to while it was read has a digest of neither version
+        let metadata = Request::GetMetadata {
+            path: path.clone(),
+            follow_symlinks: true,
+        };
+        match call(inner, metadata).await {
+            Ok(Response::Metadata(metadata))
+                if metadata.len() == len && metadata.modified().ok() == Some(modified) => {}
+            _ => return weak,
+        }
+        let Some(etag) = ETag::strong(crate::digest::base64(&digest)) else {
+            return weak;
+        };
+        let mut cache = self.lock();
+        if cache.entries.len() >= self.capacity && !cache.entries.contains_key(&**path) {
+            let evicted = cache.entries.keys().next().cloned();
+            if let Some(evicted) = evicted {
+                cache.entries.remove(&evicted);
+                cache.evictions += 1;
+            }
+        }
+        if self.capacity > 0 {
+            let entry = Entry {
+                modified,
+                len,
+                etag: etag.clone(),
+            };
+            cache.entries.insert(path.to_path_buf(), entry);
+        }
+        drop(cache);
+        etag
+    }
+}
+
+/// The SHA-256 digest of the file at `path`, streamed with [`Request::Open`] or read with
+/// [`Request::ReadBytes`]
+async fn digest<S>(inner: &mut S, path: Arc<Path>) -> io::Result<[u8; 32]>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut hasher = Sha256::default();
+    let open = Request::Open {
+        mode: crate::Mode::Read,
+        path: path.clone(),
+    };
+    match call(inner, open).await {
+        Ok(response) => {
+            let mut file = response.into_file()?;
+            let mut buffer = BufferPool::shared().get();
+            loop {
+                match file.read(&mut buffer).await? {
+                    0 => break,
+                    read => hasher.update(&buffer[..read]),
+                }
+            }
+        }
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            let bytes = call(inner, Request::ReadBytes(path)).await?.into_bytes()?;
+            hasher.update(&bytes);
+        }
+        Err(err) => return Err(err),
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    #[tokio::test]
+    async fn test_strong_etags() -> io::Result<()> {
+        let dir = TestDir::new("strong_etags")?;
+        let small = Arc::<Path>::from(dir.join("small.txt"));
+        let large = Arc::<Path>::from(dir.join("large.txt"));
+        std::fs::write(&small, "hello")?;
+        std::fs::write(&large, "hello world")?;
+        let etags = StrongETags::new(8);
+        let mut inner = FileSystem::new();
+        let modified = std::fs::metadata(&small)?.modified()?;
+
+        let etag = etags.etag(&mut inner, &small, 5, modified).await;
+        assert!(!etag.is_weak());
+        assert_eq!(etag.tag(), "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
+
+        // The cached digest is used while the metadata matches
+        std::fs::write(&small, "jello")?;
+        let cached = etags.clone().etag(&mut inner, &small, 5, modified).await;
+        assert_eq!(cached, etag);
+
+        let modified = std::fs::metadata(&large)?.modified()?;
+        let etag = etags.etag(&mut inner, &large, 11, modified).await;
+        assert_eq!(etag, ETag::from_metadata(11, modified));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats() -> io::Result<()> {
+        let dir = TestDir::new("etag_stats")?;
+        let first = Arc::<Path>::from(dir.join("first.txt"));
+        let second = Arc::<Path>::from(dir.join("second.txt"));
+        let large = Arc::<Path>::from(dir.join("large.txt"));
+        std::fs::write(&first, "one")?;
+        std::fs::write(&second, "two")?;
+        std::fs::write(&large, "too long to hash")?;
+        let etags = StrongETags::new(8).capacity(1);
+        let mut inner = FileSystem::new();
+        let modified = |path: &Path| std::fs::metadata(path)?.modified();
+
+        let etag = etags.etag(&mut inner, &first, 3, modified(&first)?).await;
+        etags.etag(&mut inner, &first, 3, modified(&first)?).await;
+        // Files too long to hash aren't counted
+        etags.etag(&mut inner, &large, 16, modified(&large)?).await;
+        let stats = CacheStats {
+            hits: 1,
+            misses: 1,
+            evictions: 0,
+            len: 1,
+            capacity: 1,
+        };
+        assert_eq!(etags.stats(), stats);
+        assert_eq!(etags.entries()[0].path, *first);
+        assert_eq!(etags.entries()[0].etag, etag);
+
+        // Full caches make room for new digests, and clones share their counts
+        etags
+            .clone()
+            .etag(&mut inner, &second, 3, modified(&second)?)
+            .await;
+        let stats = CacheStats {
+            misses: 2,
+            evictions: 1,
+            ..stats
+        };
+        assert_eq!(etags.stats(), stats);
+        assert_eq!(etags.entries()[0].path, *second);
+        Ok(())
+    }
+}
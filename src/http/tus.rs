@@ -0,0 +1,526 @@
+//! The [tus](https://tus.io/protocols/resumable-upload) resumable upload protocol, version 1.0.0
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use http::{
+    header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    request::Parts,
+    HeaderName, HeaderValue, Method, StatusCode,
+};
+use http_body::Body;
+use tower_service::Service;
+
+use super::{
+    error::{error_status, write_error_status},
+    serve::{
+        call, expectation_failed, method_not_allowed, random_token, status_response, ResponseBody,
+    },
+    write_body::{upload_error_status, write_body_to_file, WriteOptions},
+};
+use crate::{Mode, Request, Response};
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+
+const VERSION: &str = "1.0.0";
+const ALLOW: &str = "OPTIONS, POST, HEAD, PATCH, DELETE";
+/// The length of upload ids, which are generated by [`random_token`]
+const ID_LEN: usize = 32;
+
+/// Accepts resumable uploads with the tus protocol, storing them in a directory of an inner
+/// `Service<Request>`
+///
+/// `POST` creates an upload of the length given by `Upload-Length`, responding with it's URL in
+/// `Location`, which is the request path followed by a random id.  `HEAD` on that URL reports how
+/// much has been received in `Upload-Offset`, and `PATCH` appends to the upload from that offset,
+/// so clients which lose their connection can continue where they left off.  The `creation` and
+/// `termination` extensions are supported, the latter removing uploads with `DELETE`.
+///
+/// Each upload is written to `base` as a file named by it's id, with the expected length kept
+/// alongside it in a file with an `.info` extension until the upload is complete.  Data is
+/// appended to files opened with [`Mode::AppendExisting`], or for backends which can't open files,
+/// by rewriting the whole file with [`Request::WriteBytes`].
+///
+/// `PATCH` and `DELETE` requests for the same upload are handled one at a time, so concurrent
+/// requests from a client which retried can't both append from the same offset.  This only holds
+/// between clones of the same `TusUploads`.
+///
+/// Ids are unpredictable, but anyone who knows one can change the upload, so authentication
+/// belongs in front of this service.  Incomplete uploads are never expired.
+#[derive(Debug, Clone)]
+pub struct TusUploads<S> {
+    base: PathBuf,
+    inner: S,
+    max_size: Option<u64>,
+    locks: Arc<Locks>,
+}
+
+/// A lock for each upload being changed, keyed by it's id
+type Locks = Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+impl<S> TusUploads<S> {
+    pub fn new<P: Into<PathBuf>>(base: P, inner: S) -> Self {
+        Self {
+            base: base.into(),
+            inner,
+            max_size: None,
+            locks: Arc::default(),
+        }
+    }
+
+    /// Rejects uploads longer than `max_size` bytes, which is advertised to clients in
+    /// `Tus-Max-Size`
+    #[must_use]
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+}
+
+impl<B, S> Service<http::Request<B>> for TusUploads<S>
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Send,
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = http::Response<ResponseBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let (base, max_size) = (self.base.clone(), self.max_size);
+        let locks = self.locks.clone();
+        let (parts, body) = req.into_parts();
+        async move {
+            let mut response = respond(&mut inner, &base, max_size, &locks, &parts, body).await;
+            response
+                .headers_mut()
+                .insert(TUS_RESUMABLE, HeaderValue::from_static(VERSION));
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+async fn respond<S, B>(
+    inner: &mut S,
+    base: &Path,
+    max_size: Option<u64>,
+    locks: &Locks,
+    parts: &Parts,
+    body: B,
+) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    B: Body,
+{
+    if parts.method == Method::OPTIONS {
+        let mut response = status_response(StatusCode::NO_CONTENT);
+        let headers = response.headers_mut();
+        headers.insert(TUS_VERSION, HeaderValue::from_static(VERSION));
+        headers.insert(
+            TUS_EXTENSION,
+            HeaderValue::from_static("creation,termination"),
+        );
+        if let Some(max_size) = max_size {
+            headers.insert(TUS_MAX_SIZE, max_size.into());
+        }
+        return response;
+    }
+    if parts.headers.get(TUS_RESUMABLE) != Some(&HeaderValue::from_static(VERSION)) {
+        let mut response = status_response(StatusCode::PRECONDITION_FAILED);
+        response
+            .headers_mut()
+            .insert(TUS_VERSION, HeaderValue::from_static(VERSION));
+        return response;
+    }
+    if expectation_failed(&parts.headers) {
+        return status_response(StatusCode::EXPECTATION_FAILED);
+    }
+
+    if parts.method == Method::POST {
+        return create(inner, base, max_size, parts).await;
+    }
+    if ![Method::HEAD, Method::PATCH, Method::DELETE].contains(&parts.method) {
+        return method_not_allowed(ALLOW);
+    }
+    let Some(id) = parts
+        .uri
+        .path()
+        .rsplit('/')
+        .next()
+        .filter(|id| id.len() == ID_LEN && id.bytes().all(|byte| byte.is_ascii_hexdigit()))
+    else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+    let (path, info) = (base.join(id), base.join(format!("{id}.info")));
+    let lock = (parts.method != Method::HEAD).then(|| {
+        let mut locks = locks.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.entry(id.to_owned()).or_default().clone()
+    });
+    let guard = match &lock {
+        Some(lock) => Some(lock.lock().await),
+        None => None,
+    };
+    let result = match parts.method {
+        Method::HEAD => progress(inner, path, info).await.map(|(offset, len)| {
+            let mut response = status_response(StatusCode::OK);
+            let headers = response.headers_mut();
+            headers.insert(UPLOAD_OFFSET, offset.into());
+            headers.insert(UPLOAD_LENGTH, len.into());
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            response
+        }),
+        Method::PATCH => resume(inner, path, info, parts, body).await,
+        _ => terminate(inner, path, info).await,
+    };
+    drop(guard);
+    if let Some(lock) = lock {
+        let mut locks = locks.lock().unwrap_or_else(PoisonError::into_inner);
+        // Only the map and this request hold the lock, so no other request is waiting for it
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(id);
+        }
+    }
+    result.unwrap_or_else(status_response)
+}
+
+/// Creates an empty upload, and it's info file if it's expected to have any content
+async fn create<S>(
+    inner: &mut S,
+    base: &Path,
+    max_size: Option<u64>,
+    parts: &Parts,
+) -> http::Response<ResponseBody>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    // Uploads of unknown length (the `creation-defer-length` extension) aren't supported
+    let Some(len) = header_u64(parts, &UPLOAD_LENGTH) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    if max_size.is_some_and(|max_size| len > max_size) {
+        return status_response(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let id = random_token();
+    let writes = [
+        (len > 0).then(|| {
+            (
+                base.join(format!("{id}.info")),
+                len.to_string().into_bytes(),
+            )
+        }),
+        Some((base.join(&id), Vec::new())),
+    ];
+    for (path, bytes) in writes.into_iter().flatten() {
+        if let Err(err) = call(
+            inner,
+            Request::WriteBytes {
+                path: path.into(),
+                bytes,
+            },
+        )
+        .await
+        {
+            return status_response(write_error_status(&err));
+        }
+    }
+
+    let location = format!("{}/{id}", parts.uri.path().trim_end_matches('/'));
+    let mut response = status_response(StatusCode::CREATED);
+    if let Ok(location) = HeaderValue::try_from(location) {
+        response.headers_mut().insert(LOCATION, location);
+    }
+    response
+}
+
+/// Appends the body of a `PATCH` request to the upload at `path`
+async fn resume<S, B>(
+    inner: &mut S,
+    path: PathBuf,
+    info: PathBuf,
+    parts: &Parts,
+    body: B,
+) -> Result<http::Response<ResponseBody>, StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    B: Body,
+{
+    if parts.headers.get(CONTENT_TYPE)
+        != Some(&HeaderValue::from_static("application/offset+octet-stream"))
+    {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let offset = header_u64(parts, &UPLOAD_OFFSET).ok_or(StatusCode::BAD_REQUEST)?;
+    let (current, len) = progress(inner, path.clone(), info.clone()).await?;
+    if offset != current {
+        return Err(StatusCode::CONFLICT);
+    }
+    let remaining = len.checked_sub(offset).ok_or(StatusCode::CONFLICT)?;
+    // Rejected before the body is read, so clients expecting `100 Continue` needn't send it
+    if header_u64(parts, &CONTENT_LENGTH).is_some_and(|declared| declared > remaining) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let appended = append(inner, path.clone(), body, remaining).await;
+    // Whatever was received is kept, so the client can resume after a failure
+    let (offset, len) = progress(inner, path, info.clone()).await?;
+    appended?;
+    if offset == len {
+        match call(inner, Request::RemoveFile(info.into())).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(write_error_status(&err)),
+        }
+    }
+    let mut response = status_response(StatusCode::NO_CONTENT);
+    response.headers_mut().insert(UPLOAD_OFFSET, offset.into());
+    Ok(response)
+}
+
+/// Appends at most `max_len` bytes of `body` to the file at `path`
+async fn append<S, B>(inner: &mut S, path: PathBuf, body: B, max_len: u64) -> Result<(), StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    B: Body,
+{
+    let options = WriteOptions::new()
+        .mode(Mode::AppendExisting)
+        .max_len(max_len)
+        .cleanup(false);
+    match write_body_to_file(body, inner, path, options).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(upload_error_status(&err)),
+    }
+}
+
+/// Removes an upload, whether or not it was complete
+async fn terminate<S>(
+    inner: &mut S,
+    path: PathBuf,
+    info: PathBuf,
+) -> Result<http::Response<ResponseBody>, StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    call(inner, Request::RemoveFile(path.into()))
+        .await
+        .map_err(|err| error_status(&err))?;
+    match call(inner, Request::RemoveFile(info.into())).await {
+        Ok(_) => Ok(status_response(StatusCode::NO_CONTENT)),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Ok(status_response(StatusCode::NO_CONTENT))
+        }
+        Err(err) => Err(error_status(&err)),
+    }
+}
+
+/// The offset and expected length of the upload at `path`, which is complete if it has no info
+/// file
+async fn progress<S>(inner: &mut S, path: PathBuf, info: PathBuf) -> Result<(u64, u64), StatusCode>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let metadata = Request::GetMetadata {
+        path: path.as_path().into(),
+        follow_symlinks: true,
+    };
+    let offset = match call(inner, metadata).await {
+        Ok(Response::Metadata(metadata)) if metadata.is_file() => metadata.len(),
+        Ok(Response::Metadata(_)) => return Err(StatusCode::NOT_FOUND),
+        Ok(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            match call(inner, Request::ReadBytes(path.into())).await {
+                Ok(Response::Bytes(bytes)) => bytes.len() as u64,
+                Ok(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+                Err(err) => return Err(error_status(&err)),
+            }
+        }
+        Err(err) => return Err(error_status(&err)),
+    };
+    let len = match call(inner, Request::ReadBytes(info.into())).await {
+        Ok(Response::Bytes(bytes)) => std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|len| len.trim().parse().ok())
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?,
+        Ok(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(err) if err.kind() == ErrorKind::NotFound => offset,
+        Err(err) => return Err(error_status(&err)),
+    };
+    // A longer file can only have been written to by something else
+    if offset > len {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok((offset, len))
+}
+
+fn header_u64(parts: &Parts, name: &HeaderName) -> Option<u64> {
+    parts.headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body::Full;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn send(
+        service: &mut TusUploads<FileSystem>,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &'static str,
+    ) -> http::Response<ResponseBody> {
+        let mut request = http::Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let Ok(request) = request.body(Full::new(Bytes::from(body))) else {
+            unreachable!("the test requests are valid")
+        };
+        match service.call(request).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+
+    fn header<'a>(response: &'a http::Response<ResponseBody>, name: &str) -> Option<&'a str> {
+        response.headers().get(name)?.to_str().ok()
+    }
+
+    #[tokio::test]
+    async fn test_tus() -> io::Result<()> {
+        let dir = TestDir::new("tus")?;
+        let mut service = TusUploads::new(&dir, FileSystem::new()).max_size(16);
+        let resumable = ("Tus-Resumable", VERSION);
+        let patch = "application/offset+octet-stream";
+
+        let response = send(&mut service, Method::OPTIONS, "/files", &[], "").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&response, "tus-max-size"), Some("16"));
+        let response = send(&mut service, Method::POST, "/files", &[], "").await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let headers = [resumable, ("Upload-Length", "17")];
+        let response = send(&mut service, Method::POST, "/files", &headers, "").await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let headers = [resumable, ("Upload-Length", "10")];
+        let response = send(&mut service, Method::POST, "/files/", &headers, "").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let Some(location) = header(&response, "location").map(str::to_owned) else {
+            unreachable!("created uploads have a location")
+        };
+        assert!(location.starts_with("/files/"));
+        let id = &location["/files/".len()..];
+
+        let response = send(&mut service, Method::HEAD, &location, &[resumable], "").await;
+        assert_eq!(header(&response, "upload-offset"), Some("0"));
+        assert_eq!(header(&response, "upload-length"), Some("10"));
+        let headers = [resumable, ("Upload-Offset", "0"), ("Content-Type", patch)];
+        let response = send(&mut service, Method::PATCH, &location, &headers, "hello").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(header(&response, "upload-offset"), Some("5"));
+        let response = send(&mut service, Method::PATCH, &location, &headers, "hello").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let headers = [resumable, ("Upload-Offset", "5"), ("Content-Type", patch)];
+        let response = send(&mut service, Method::PATCH, &location, &headers, "world").await;
+        assert_eq!(header(&response, "upload-offset"), Some("10"));
+        assert_eq!(std::fs::read_to_string(dir.join(id))?, "helloworld");
+        assert!(!dir.join(format!("{id}.info")).exists());
+
+        let response = send(&mut service, Method::HEAD, &location, &[resumable], "").await;
+        assert_eq!(header(&response, "upload-length"), Some("10"));
+        let response = send(&mut service, Method::DELETE, &location, &[resumable], "").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send(&mut service, Method::HEAD, &location, &[resumable], "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(&mut service, Method::GET, &location, &[resumable], "").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        Ok(())
+    }
+
+    /// A body which is only ready after being polled a few times, so requests sending it are
+    /// interleaved with others
+    struct Slow(u8, Option<Bytes>);
+
+    impl Body for Slow {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> Poll<Option<Result<Bytes, Infallible>>> {
+            if self.0 > 0 {
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.1.take().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> Poll<Result<Option<http::HeaderMap>, Infallible>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_patches() -> io::Result<()> {
+        let dir = TestDir::new("tus_concurrent")?;
+        let mut service = TusUploads::new(&dir, FileSystem::new());
+        let resumable = ("Tus-Resumable", VERSION);
+        let headers = [resumable, ("Upload-Length", "5")];
+        let response = send(&mut service, Method::POST, "/files", &headers, "").await;
+        let Some(location) = header(&response, "location").map(str::to_owned) else {
+            unreachable!("created uploads have a location")
+        };
+        let id = &location["/files/".len()..];
+
+        let patch = || {
+            let Ok(request) = http::Request::patch(&location)
+                .header("Tus-Resumable", VERSION)
+                .header("Upload-Offset", "0")
+                .header("Content-Type", "application/offset+octet-stream")
+                .body(Slow(16, Some(Bytes::from("hello"))))
+            else {
+                unreachable!("the test requests are valid")
+            };
+            request
+        };
+        let (first, second) =
+            futures::join!(service.clone().call(patch()), service.clone().call(patch()));
+        let (Ok(first), Ok(second)) = (first, second);
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::NO_CONTENT, StatusCode::CONFLICT]);
+        assert_eq!(std::fs::read_to_string(dir.join(id))?, "hello");
+        assert!(service.locks.lock().is_ok_and(|locks| locks.is_empty()));
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! Streaming request bodies into files, the upload counterpart of
+//! [`AsyncReadBody`](super::AsyncReadBody)
+
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+    pin::pin,
+};
+
+use bytes::Buf;
+use http::StatusCode;
+use http_body::Body;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tower_service::Service;
+
+use super::{error::write_error_status, serve::call};
+use crate::{Mode, Request, Response};
+
+/// How [`write_body_to_file`] writes a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    mode: Mode,
+    max_len: Option<u64>,
+    cleanup: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            mode: Mode::CreateNew,
+            max_len: None,
+            cleanup: true,
+        }
+    }
+}
+
+impl WriteOptions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the file with `mode`, which defaults to [`Mode::CreateNew`]
+    #[must_use]
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Fails with [`ErrorKind::FileTooLarge`] once more than `max_len` bytes have been received
+    #[must_use]
+    pub fn max_len(mut self, max_len: u64) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Removes the file if writing fails, which is enabled by default
+    ///
+    /// The whole file is removed, including anything it held before when appending or
+    /// overwriting.  When disabled, whatever was received before the failure is kept.
+    #[must_use]
+    pub fn cleanup(mut self, cleanup: bool) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+}
+
+/// The error a body failed with, which is left out so bodies needn't have sendable errors
+#[derive(Debug, thiserror::Error)]
+#[error("failed to receive the body")]
+struct BodyError;
+
+/// Streams `body` into the file at `path`, opened through `inner` with [`Request::Open`],
+/// returning the number of bytes written
+///
+/// Each chunk is written before the next is polled, so a slow file system slows down the sender
+/// rather than the body being buffered.  Backends which can't open files get the whole body with
+/// [`Request::WriteBytes`] instead, after reading the existing file with [`Request::ReadBytes`]
+/// when appending.
+///
+/// # Errors
+///
+/// - [`ErrorKind::FileTooLarge`] if the body is longer than [`WriteOptions::max_len`]
+/// - [`ErrorKind::InvalidData`] if the body fails
+/// - Any error from `inner`
+pub async fn write_body_to_file<B, S>(
+    body: B,
+    inner: &mut S,
+    path: PathBuf,
+    options: WriteOptions,
+) -> io::Result<u64>
+where
+    B: Body,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let open = Request::Open {
+        mode: options.mode,
+        path: path.as_path().into(),
+    };
+    let written = match call(inner, open).await {
+        Ok(Response::File(mut file)) => {
+            let written = copy_body(&mut file, body, options.max_len).await;
+            let flushed = file.flush().await;
+            written.and_then(|written| flushed.map(|()| written))
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "unexpected response from inner service",
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            return write_bytes(body, inner, path, options).await;
+        }
+        Err(err) => return Err(err),
+    };
+    if written.is_err() && options.cleanup {
+        let _ = call(inner, Request::RemoveFile(path.into())).await;
+    }
+    written
+}
+
+/// Buffers `body` and writes it with [`Request::WriteBytes`], for backends which can't open files
+async fn write_bytes<B, S>(
+    body: B,
+    inner: &mut S,
+    path: PathBuf,
+    options: WriteOptions,
+) -> io::Result<u64>
+where
+    B: Body,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut bytes = match options.mode {
+        Mode::AppendExisting | Mode::CreateOrAppend => {
+            match call(inner, Request::ReadBytes(path.as_path().into())).await {
+                Ok(Response::Bytes(bytes)) => bytes,
+                Err(err)
+                    if options.mode == Mode::CreateOrAppend
+                        && err.kind() == ErrorKind::NotFound =>
+                {
+                    Vec::new()
+                }
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "unexpected response from inner service",
+                    ))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        // Without `Open` the check can race with other writers, but it's the best available
+        Mode::CreateNew => match call(inner, Request::Exists(path.as_path().into())).await {
+            Ok(Response::Exists(false)) => Vec::new(),
+            Ok(Response::Exists(true)) => {
+                return Err(io::Error::new(ErrorKind::AlreadyExists, "file exists"))
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected response from inner service",
+                ))
+            }
+            Err(err) => return Err(err),
+        },
+        Mode::CreateOrOverwrite | Mode::Read => Vec::new(),
+    };
+    let written = copy_body(&mut bytes, body, options.max_len).await;
+    if written.is_err() && options.cleanup {
+        return written;
+    }
+    call(
+        inner,
+        Request::WriteBytes {
+            path: path.into(),
+            bytes,
+        },
+    )
+    .await?;
+    written
+}
+
+/// Copies `body` into `writer`, failing once more than `max_len` bytes have been received
+async fn copy_body<W, B>(writer: &mut W, body: B, max_len: Option<u64>) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+    B: Body,
+{
+    let max_len = max_len.unwrap_or(u64::MAX);
+    let mut body = pin!(body);
+    let mut written = 0_u64;
+    while let Some(chunk) = body.data().await {
+        let Ok(mut chunk) = chunk else {
+            return Err(io::Error::new(ErrorKind::InvalidData, BodyError));
+        };
+        if written.saturating_add(chunk.remaining() as u64) > max_len {
+            return Err(io::Error::new(ErrorKind::FileTooLarge, "body too long"));
+        }
+        written += chunk.remaining() as u64;
+        writer.write_all_buf(&mut chunk).await?;
+    }
+    Ok(written)
+}
+
+/// The status to respond to an upload which failed with `err`
+pub(super) fn upload_error_status(err: &io::Error) -> StatusCode {
+    if err
+        .get_ref()
+        .is_some_and(<dyn std::error::Error + Send + Sync>::is::<BodyError>)
+    {
+        StatusCode::BAD_REQUEST
+    } else {
+        write_error_status(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http_body::Full;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    #[tokio::test]
+    async fn test_write_body_to_file() -> io::Result<()> {
+        let dir = TestDir::new("write_body")?;
+        let path = dir.join("upload.txt");
+        let mut inner = FileSystem::new();
+        let body = |body: &'static str| Full::new(Bytes::from(body));
+
+        let options = WriteOptions::new().max_len(8);
+        let written = write_body_to_file(body("hello"), &mut inner, path.clone(), options).await;
+        assert_eq!(written?, 5);
+        let written = write_body_to_file(body("again"), &mut inner, path.clone(), options).await;
+        assert!(written.is_err_and(|err| err.kind() == ErrorKind::AlreadyExists));
+
+        let append = options.mode(Mode::AppendExisting).cleanup(false);
+        let written =
+            write_body_to_file(body(" and goodbye"), &mut inner, path.clone(), append).await;
+        assert!(
+            written.is_err_and(|err| upload_error_status(&err) == StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        assert_eq!(std::fs::read_to_string(&path)?, "hello");
+
+        let overwrite = options.mode(Mode::CreateOrOverwrite);
+        let written = write_body_to_file(body("too long!"), &mut inner, path.clone(), overwrite);
+        assert!(written.await.is_err());
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -1,86 +1,84 @@
-use std::{fs::Permissions, path::PathBuf, task::Poll};
-
-use futures::future::{BoxFuture, FutureExt, TryFutureExt};
-use tokio::fs;
-use tower_service::Service;
+use std::{
+    fmt,
+    fs::Permissions,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod backend;
+mod boxed;
+mod copy;
+// Not every helper in these modules is needed by every combination of features
+#[cfg(any(
+    feature = "archive",
+    feature = "azure",
+    feature = "ftp-server",
+    feature = "http",
+    feature = "s3",
+    feature = "sftp-server"
+))]
+#[allow(dead_code)]
+mod date;
+#[cfg(feature = "dedup")]
+pub mod dedup;
+#[allow(dead_code)]
+mod digest;
+mod ext;
+mod file;
+#[cfg(feature = "ftp-server")]
+pub mod ftp_server;
+#[cfg(all(unix, feature = "fuse"))]
+pub mod fuse;
+#[cfg(any(feature = "archive", feature = "http"))]
+mod glob;
 #[cfg(feature = "http")]
 pub mod http;
+#[cfg(target_os = "linux")]
+mod linux;
+mod local;
+mod make;
+mod metadata;
 #[cfg(feature = "middleware")]
 pub mod middleware;
+#[cfg(feature = "nfs-server")]
+pub mod nfs_server;
+#[cfg(feature = "ninep-server")]
+pub mod ninep_server;
+mod pool;
+#[cfg(feature = "posix-acl")]
+pub mod posix_acl;
+pub mod prelude;
+mod progress;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "sftp-server")]
+pub mod sftp_server;
+mod shared;
+#[cfg(feature = "sync")]
+pub mod sync;
+#[cfg(any(test, feature = "test-kit"))]
+pub mod test_kit;
+pub mod typed;
+mod walk;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(windows)]
+pub mod windows;
 
-#[derive(Debug, Clone, Copy)]
-pub struct FileSystem;
-
-impl Service<Request> for FileSystem {
-    type Response = Response;
-    type Error = std::io::Error;
-    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
-
-    /// The [`FileSystem`] performs no setup of it's own, so it's ready immediately
-    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
-    }
-
-    fn call(&mut self, req: Request) -> Self::Future {
-        match req {
-            Request::Copy { from, to } => fs::copy(from, to).map_ok(Response::Copied).boxed(),
-            Request::CreateDir {
-                path,
-                recursive: true,
-            } => fs::create_dir_all(path).map_ok(Response::done).boxed(),
-            Request::CreateDir {
-                path,
-                recursive: false,
-            } => fs::create_dir(path).map_ok(Response::done).boxed(),
-            Request::Exists(path) => fs::try_exists(path).map_ok(Response::Exists).boxed(),
-            Request::FollowLink(path) => fs::read_link(path).map_ok(Response::PointsTo).boxed(),
-            Request::GetMetadata {
-                path,
-                follow_symlinks: true,
-            } => fs::metadata(path).map_ok(Response::Metadata).boxed(),
-            Request::GetMetadata {
-                path,
-                follow_symlinks: false,
-            } => fs::symlink_metadata(path)
-                .map_ok(Response::Metadata)
-                .boxed(),
-            Request::HardLink { src, dst } => {
-                fs::hard_link(src, dst).map_ok(Response::done).boxed()
-            }
-            Request::Open { mode, path } => async move {
-                mode.into_open_options()
-                    .open(path)
-                    .await
-                    .map(Response::File)
-            }
-            .boxed(),
-            Request::RemoveDir {
-                path,
-                recursive: true,
-            } => fs::remove_dir_all(path).map_ok(Response::done).boxed(),
-            Request::RemoveDir {
-                path,
-                recursive: false,
-            } => fs::remove_dir(path).map_ok(Response::done).boxed(),
-            Request::RemoveFile(path) => fs::remove_file(path).map_ok(Response::done).boxed(),
-            Request::Rename { from, to } => fs::rename(from, to).map_ok(Response::done).boxed(),
-            Request::SetPermissions { path, perm } => fs::set_permissions(path, perm)
-                .map_ok(Response::done)
-                .boxed(),
-            #[cfg(unix)]
-            Request::SymLink { src, dst } => fs::symlink(src, dst).map_ok(Response::done).boxed(),
-            #[cfg(windows)]
-            Request::SymlinkDir { src, dst } => {
-                fs::symlink_dir(src, dst).map_ok(Response::done).boxed()
-            }
-
-            Request::SymlinkFile { src, dst } => {
-                fs::symlink_file(src, dst).map_ok(Response::done).boxed()
-            }
-        }
-    }
-}
+pub use boxed::{BoxCloneService, BoxFsService};
+pub use copy::CopyOptions;
+pub use ext::FileSystemExt;
+pub use file::FileHandle;
+pub use local::{FileSystem, FileSystemBuilder, FileSystemFuture, MetadataFields};
+pub use make::MakeFileSystem;
+pub use metadata::{Attributes, FileType, Metadata};
+pub use pool::{BufferPool, PooledBuffer};
+pub use progress::{Progress, ProgressReporter};
+pub use shared::SharedService;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Mode {
@@ -92,12 +90,12 @@ pub enum Mode {
 }
 
 impl Mode {
-    fn into_open_options(self) -> fs::OpenOptions {
-        let mut options = fs::OpenOptions::new();
+    fn into_open_options(self) -> std::fs::OpenOptions {
+        let mut options = std::fs::OpenOptions::new();
         match self {
             Self::Read => options.read(true),
             Self::AppendExisting => options.append(true),
-            Self::CreateOrOverwrite => options.write(true).truncate(true),
+            Self::CreateOrOverwrite => options.write(true).create(true).truncate(true),
             Self::CreateOrAppend => options.append(true).create(true),
             Self::CreateNew => options.write(true).create_new(true),
         };
@@ -105,72 +103,636 @@ impl Mode {
     }
 }
 
+/// An operation for a backend to perform
+///
+/// Paths are held in an [`Arc`], so cloning a request, as buffering and retrying layers do, doesn't
+/// copy them.  They can be built from a [`PathBuf`] or a [`Path`] with `into`.
 #[derive(Debug, Clone)]
 pub enum Request {
+    /// Packs the directory `src_dir` into an archive at `dst`, leaving out the paths `filter`
+    /// rejects.  Symlinks are stored as links rather than followed
+    #[cfg(feature = "archive")]
+    Archive {
+        src_dir: Arc<Path>,
+        dst: Arc<Path>,
+        format: archive::ArchiveFormat,
+        compression: archive::Compression,
+        filter: archive::ArchiveFilter,
+    },
+    /// Unpacks the tar or zip archive at `archive` into the directory `dst_dir`, creating it if it
+    /// doesn't exist.  Entries which would be unpacked outside `dst_dir`, or through a symlink,
+    /// and symlinks which lead out of it fail with [`io::ErrorKind::InvalidData`], as do archives
+    /// over the limits in `options`.  Entries unpacked before the failure are left in place
+    #[cfg(feature = "archive")]
+    Extract {
+        archive: Arc<Path>,
+        dst_dir: Arc<Path>,
+        options: archive::ExtractOptions,
+    },
+    /// Finds the regular files below the directory `dir` with the same contents, answered with
+    /// [`Response::Duplicates`], and hard links each set of them together if `options` asks to
+    #[cfg(feature = "dedup")]
+    FindDuplicates {
+        dir: Arc<Path>,
+        options: dedup::DedupOptions,
+    },
+    /// Reads the POSIX access control lists of a file or directory, answered with
+    /// [`Response::PosixAcl`].  Only Linux is supported, with `getfacl` installed, and anything
+    /// else fails with [`io::ErrorKind::Unsupported`]
+    #[cfg(feature = "posix-acl")]
+    GetPosixAcl(Arc<Path>),
+    /// Replaces the access control lists of a file or directory with those of `acl`, removing its
+    /// default list if `acl` has none.  Lists without the entries every list needs fail with
+    /// [`io::ErrorKind::InvalidInput`]
+    #[cfg(feature = "posix-acl")]
+    SetPosixAcl {
+        path: Arc<Path>,
+        acl: posix_acl::PosixAcl,
+    },
+    /// Asks the backend to reclaim space held by overwritten or removed entries.  Backends which
+    /// never hold on to such space (like [`FileSystem`]) treat this as a no-op
+    Compact,
+    /// Asks the service to write out anything it's buffering, such as the writes held back by the
+    /// `coalesce` middleware.  Backends which don't buffer writes (like [`FileSystem`]) treat this
+    /// as a no-op
+    Flush,
+    /// Copies the file `from` to `to`, answered with [`Response::Copied`] and the bytes copied,
+    /// keeping what `options` ask for of `from`
     Copy {
-        from: PathBuf,
-        to: PathBuf,
+        from: Arc<Path>,
+        to: Arc<Path>,
+        options: CopyOptions,
+    },
+    /// Copies the directory `from` and everything in it to `to`, answered with
+    /// [`Response::Copied`] and the bytes copied.  `to` and its parents are created if they don't
+    /// exist, files already in it are replaced, and copying a directory into itself fails with
+    /// [`io::ErrorKind::InvalidInput`].  `options` apply to `to` and every entry below it
+    CopyDir {
+        from: Arc<Path>,
+        to: Arc<Path>,
+        options: CopyOptions,
     },
     CreateDir {
-        path: PathBuf,
+        path: Arc<Path>,
         recursive: bool,
     },
-    FollowLink(PathBuf),
+    FollowLink(Arc<Path>),
     GetMetadata {
-        path: PathBuf,
+        path: Arc<Path>,
+        follow_symlinks: bool,
+    },
+    /// Gets the metadata of each of `paths` at once, answered with [`Response::MetadataBatch`]
+    /// and a result for each path in the same order, so listings don't need a request per entry.
+    /// Paths which fail don't fail the others
+    MetadataBatch {
+        paths: Vec<Arc<Path>>,
         follow_symlinks: bool,
     },
     HardLink {
-        src: PathBuf,
-        dst: PathBuf,
+        src: Arc<Path>,
+        dst: Arc<Path>,
     },
     Open {
         mode: Mode,
-        path: PathBuf,
+        path: Arc<Path>,
+    },
+    /// Lists the entries of a directory, answered with [`Response::Directory`] and the name and
+    /// metadata of each, sorted by name.  Symlinks among them aren't followed
+    ReadDir(Arc<Path>),
+    /// Reads the entire contents of a file into memory
+    ReadBytes(Arc<Path>),
+    /// Reads the bytes of a file within `range`, stopping early at the end of the file
+    ReadRange {
+        path: Arc<Path>,
+        range: Range<u64>,
     },
     RemoveDir {
-        path: PathBuf,
+        path: Arc<Path>,
         recursive: bool,
     },
-    RemoveFile(PathBuf),
+    RemoveFile(Arc<Path>),
     Rename {
-        from: PathBuf,
-        to: PathBuf,
+        from: Arc<Path>,
+        to: Arc<Path>,
     },
     SetPermissions {
-        path: PathBuf,
+        path: Arc<Path>,
         perm: Permissions,
     },
+    /// Reads the flags the file system keeps for a file or directory, as `lsattr` shows them,
+    /// answered with [`Response::Attributes`].  Only Linux has them, and file systems without any
+    /// fail with [`io::ErrorKind::Unsupported`]
+    #[cfg(unix)]
+    GetAttributes(Arc<Path>),
+    /// Sets the attributes of `set` and clears those of `clear`, as `chattr` does, leaving the rest
+    /// as they are.  Only [`Attributes::IMMUTABLE`], [`Attributes::APPEND`],
+    /// [`Attributes::COMPRESSED`], [`Attributes::NODUMP`] and [`Attributes::DAX`] can be changed,
+    /// and changing the first two needs `CAP_LINUX_IMMUTABLE`
     #[cfg(unix)]
-    SymLink {
-        src: PathBuf,
-        dst: PathBuf,
+    SetAttributes {
+        path: Arc<Path>,
+        set: Attributes,
+        clear: Attributes,
+    },
+    #[cfg(unix)]
+    Symlink {
+        src: Arc<Path>,
+        dst: Arc<Path>,
     },
     #[cfg(windows)]
     SymlinkDir {
-        src: PathBuf,
-        dst: PathBuf,
+        src: Arc<Path>,
+        dst: Arc<Path>,
     },
+    #[cfg(windows)]
     SymlinkFile {
-        src: PathBuf,
-        dst: PathBuf,
+        src: Arc<Path>,
+        dst: Arc<Path>,
+    },
+    /// Reads the target of the junction at the path, answered with [`Response::PointsTo`].
+    /// Directory symlinks are read the same way, while other paths fail with
+    /// [`io::ErrorKind::InvalidInput`]
+    #[cfg(windows)]
+    ReadJunction(Arc<Path>),
+    /// Opens the NTFS alternate data stream `stream` of the file at `path` with `mode`, answered
+    /// with [`Response::File`].  The stream is named separately rather than with the `path:stream`
+    /// syntax, and names holding `:`, separators or NULs fail with
+    /// [`io::ErrorKind::InvalidInput`]
+    #[cfg(windows)]
+    OpenStream {
+        mode: Mode,
+        path: Arc<Path>,
+        stream: String,
+    },
+    /// Reads the attributes of a file or directory, such as whether it's hidden, answered with
+    /// [`Response::FileAttributes`]
+    #[cfg(windows)]
+    GetFileAttributes(Arc<Path>),
+    /// Sets the attributes of `set` and clears those of `clear`, leaving the rest as they are.
+    /// Attributes Windows doesn't let files change, such as
+    /// [`FileAttributes::DIRECTORY`](windows::FileAttributes::DIRECTORY), fail with
+    /// [`io::ErrorKind::InvalidInput`].  Only
+    /// [`FileAttributes::READONLY`](windows::FileAttributes::READONLY) can be changed, and the
+    /// others fail with [`io::ErrorKind::Unsupported`]
+    #[cfg(windows)]
+    SetFileAttributes {
+        path: Arc<Path>,
+        set: windows::FileAttributes,
+        clear: windows::FileAttributes,
     },
-    Exists(PathBuf),
+    /// Writes `bytes` as the entire contents of a file, creating it if it doesn't exist and
+    /// replacing its contents if it does
+    WriteBytes {
+        path: Arc<Path>,
+        bytes: Vec<u8>,
+    },
+    /// Writes `bytes` to the end of a file, creating it if it doesn't exist.  Backends which can
+    /// only replace whole files, like object stores, fail with [`io::ErrorKind::Unsupported`]
+    AppendBytes {
+        path: Arc<Path>,
+        bytes: Vec<u8>,
+    },
+    Exists(Arc<Path>),
+}
+
+impl Request {
+    /// The name of the variant, such as `"ReadBytes"`, for logs and metrics
+    // One arm per request
+    #[allow(clippy::too_many_lines)]
+    #[must_use]
+    pub fn variant(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "archive")]
+            Self::Archive { .. } => "Archive",
+            #[cfg(feature = "archive")]
+            Self::Extract { .. } => "Extract",
+            #[cfg(feature = "dedup")]
+            Self::FindDuplicates { .. } => "FindDuplicates",
+            #[cfg(feature = "posix-acl")]
+            Self::GetPosixAcl(_) => "GetPosixAcl",
+            #[cfg(feature = "posix-acl")]
+            Self::SetPosixAcl { .. } => "SetPosixAcl",
+            Self::Compact => "Compact",
+            Self::Flush => "Flush",
+            Self::Copy { .. } => "Copy",
+            Self::CopyDir { .. } => "CopyDir",
+            Self::CreateDir { .. } => "CreateDir",
+            Self::FollowLink(_) => "FollowLink",
+            Self::GetMetadata { .. } => "GetMetadata",
+            Self::MetadataBatch { .. } => "MetadataBatch",
+            Self::HardLink { .. } => "HardLink",
+            Self::Open { .. } => "Open",
+            Self::ReadDir(_) => "ReadDir",
+            Self::ReadBytes(_) => "ReadBytes",
+            Self::ReadRange { .. } => "ReadRange",
+            Self::RemoveDir { .. } => "RemoveDir",
+            Self::RemoveFile(_) => "RemoveFile",
+            Self::Rename { .. } => "Rename",
+            Self::SetPermissions { .. } => "SetPermissions",
+            #[cfg(unix)]
+            Self::GetAttributes(_) => "GetAttributes",
+            #[cfg(unix)]
+            Self::SetAttributes { .. } => "SetAttributes",
+            #[cfg(unix)]
+            Self::Symlink { .. } => "Symlink",
+            #[cfg(windows)]
+            Self::SymlinkDir { .. } => "SymlinkDir",
+            #[cfg(windows)]
+            Self::SymlinkFile { .. } => "SymlinkFile",
+            #[cfg(windows)]
+            Self::ReadJunction(_) => "ReadJunction",
+            #[cfg(windows)]
+            Self::OpenStream { .. } => "OpenStream",
+            #[cfg(windows)]
+            Self::GetFileAttributes(_) => "GetFileAttributes",
+            #[cfg(windows)]
+            Self::SetFileAttributes { .. } => "SetFileAttributes",
+            Self::WriteBytes { .. } => "WriteBytes",
+            Self::AppendBytes { .. } => "AppendBytes",
+            Self::Exists(_) => "Exists",
+        }
+    }
+
+    /// The paths the request acts on, with the source before the destination for those with two
+    #[must_use]
+    pub fn paths(&self) -> Vec<&Path> {
+        self.shared_paths()
+            .into_iter()
+            .map(|path| &**path)
+            .collect()
+    }
+
+    /// The paths of [`Request::paths`], as they're held, so they can be kept without copying them
+    pub(crate) fn shared_paths(&self) -> Vec<&Arc<Path>> {
+        match self {
+            #[cfg(feature = "archive")]
+            Self::Archive { src_dir, dst, .. } => vec![src_dir, dst],
+            #[cfg(feature = "archive")]
+            Self::Extract {
+                archive, dst_dir, ..
+            } => vec![archive, dst_dir],
+            #[cfg(feature = "dedup")]
+            Self::FindDuplicates { dir: path, .. } => vec![path],
+            #[cfg(feature = "posix-acl")]
+            Self::GetPosixAcl(path) | Self::SetPosixAcl { path, .. } => vec![path],
+            Self::Compact | Self::Flush => Vec::new(),
+            Self::Copy { from, to, .. }
+            | Self::CopyDir { from, to, .. }
+            | Self::Rename { from, to } => {
+                vec![from, to]
+            }
+            Self::HardLink { src, dst } => vec![src, dst],
+            Self::MetadataBatch { paths, .. } => paths.iter().collect(),
+            #[cfg(unix)]
+            Self::Symlink { src, dst } => vec![src, dst],
+            #[cfg(windows)]
+            Self::SymlinkDir { src, dst } | Self::SymlinkFile { src, dst } => vec![src, dst],
+            #[cfg(unix)]
+            Self::GetAttributes(path) | Self::SetAttributes { path, .. } => vec![path],
+            #[cfg(windows)]
+            Self::ReadJunction(path)
+            | Self::OpenStream { path, .. }
+            | Self::GetFileAttributes(path)
+            | Self::SetFileAttributes { path, .. } => vec![path],
+            Self::CreateDir { path, .. }
+            | Self::FollowLink(path)
+            | Self::GetMetadata { path, .. }
+            | Self::Open { path, .. }
+            | Self::ReadDir(path)
+            | Self::ReadBytes(path)
+            | Self::ReadRange { path, .. }
+            | Self::RemoveDir { path, .. }
+            | Self::RemoveFile(path)
+            | Self::SetPermissions { path, .. }
+            | Self::WriteBytes { path, .. }
+            | Self::AppendBytes { path, .. }
+            | Self::Exists(path) => vec![path],
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Response {
     Done,
     Copied(u64),
-    File(fs::File),
-    Directory(Vec<(PathBuf, std::fs::Metadata)>),
-    Metadata(std::fs::Metadata),
+    Bytes(Vec<u8>),
+    File(FileHandle),
+    /// The entries listed by a [`Request::ReadDir`]
+    Directory(Vec<(PathBuf, Metadata)>),
+    Metadata(Metadata),
+    /// The metadata of each path of a [`Request::MetadataBatch`], in the same order
+    MetadataBatch(Vec<io::Result<Metadata>>),
     Exists(bool),
     PointsTo(PathBuf),
+    /// The sets of files with the same contents found by a [`Request::FindDuplicates`]
+    #[cfg(feature = "dedup")]
+    Duplicates(Vec<dedup::DuplicateSet>),
+    /// The access control lists read by a [`Request::GetPosixAcl`]
+    #[cfg(feature = "posix-acl")]
+    PosixAcl(posix_acl::PosixAcl),
+    /// The attributes read by a [`Request::GetAttributes`]
+    #[cfg(unix)]
+    Attributes(Attributes),
+    /// The attributes read by a [`Request::GetFileAttributes`]
+    #[cfg(windows)]
+    FileAttributes(windows::FileAttributes),
 }
 
 impl Response {
-    fn done(_: ()) -> Self {
+    fn done((): ()) -> Self {
         Self::Done
     }
+
+    /// The name of the variant, as used in [`WrongVariant`]'s message
+    fn variant(&self) -> &'static str {
+        match self {
+            Self::Done => "Done",
+            Self::Copied(_) => "Copied",
+            Self::Bytes(_) => "Bytes",
+            Self::File(_) => "File",
+            Self::Directory(_) => "Directory",
+            Self::Metadata(_) => "Metadata",
+            Self::MetadataBatch(_) => "MetadataBatch",
+            Self::Exists(_) => "Exists",
+            Self::PointsTo(_) => "PointsTo",
+            #[cfg(feature = "dedup")]
+            Self::Duplicates(_) => "Duplicates",
+            #[cfg(feature = "posix-acl")]
+            Self::PosixAcl(_) => "PosixAcl",
+            #[cfg(unix)]
+            Self::Attributes(_) => "Attributes",
+            #[cfg(windows)]
+            Self::FileAttributes(_) => "FileAttributes",
+        }
+    }
+
+    /// Checks the response is [`Response::Done`]
+    ///
+    /// # Errors
+    ///
+    /// If the response is any other variant
+    pub fn into_done(self) -> Result<(), WrongVariant> {
+        match self {
+            Self::Done => Ok(()),
+            response => Err(WrongVariant::new("Done", response)),
+        }
+    }
+
+    /// The number of bytes copied by a [`Request::Copy`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Copied`]
+    pub fn into_copied(self) -> Result<u64, WrongVariant> {
+        match self {
+            Self::Copied(copied) => Ok(copied),
+            response => Err(WrongVariant::new("Copied", response)),
+        }
+    }
+
+    /// The bytes read by a [`Request::ReadBytes`] or [`Request::ReadRange`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Bytes`]
+    pub fn into_bytes(self) -> Result<Vec<u8>, WrongVariant> {
+        match self {
+            Self::Bytes(bytes) => Ok(bytes),
+            response => Err(WrongVariant::new("Bytes", response)),
+        }
+    }
+
+    /// The file opened by a [`Request::Open`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::File`]
+    pub fn into_file(self) -> Result<FileHandle, WrongVariant> {
+        match self {
+            Self::File(file) => Ok(file),
+            response => Err(WrongVariant::new("File", response)),
+        }
+    }
+
+    /// The names of the entries listed by a [`Request::ReadDir`], with their metadata
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Directory`]
+    pub fn into_directory(self) -> Result<Vec<(PathBuf, Metadata)>, WrongVariant> {
+        match self {
+            Self::Directory(entries) => Ok(entries),
+            response => Err(WrongVariant::new("Directory", response)),
+        }
+    }
+
+    /// The metadata returned for a [`Request::GetMetadata`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Metadata`]
+    pub fn into_metadata(self) -> Result<Metadata, WrongVariant> {
+        match self {
+            Self::Metadata(metadata) => Ok(metadata),
+            response => Err(WrongVariant::new("Metadata", response)),
+        }
+    }
+
+    /// The results returned for a [`Request::MetadataBatch`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::MetadataBatch`]
+    pub fn into_metadata_batch(self) -> Result<Vec<io::Result<Metadata>>, WrongVariant> {
+        match self {
+            Self::MetadataBatch(results) => Ok(results),
+            response => Err(WrongVariant::new("MetadataBatch", response)),
+        }
+    }
+
+    /// Whether the path of a [`Request::Exists`] exists
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Exists`]
+    pub fn into_exists(self) -> Result<bool, WrongVariant> {
+        match self {
+            Self::Exists(exists) => Ok(exists),
+            response => Err(WrongVariant::new("Exists", response)),
+        }
+    }
+
+    /// The target of the link read by a [`Request::FollowLink`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::PointsTo`]
+    pub fn into_points_to(self) -> Result<PathBuf, WrongVariant> {
+        match self {
+            Self::PointsTo(target) => Ok(target),
+            response => Err(WrongVariant::new("PointsTo", response)),
+        }
+    }
+
+    /// The duplicates found by a [`Request::FindDuplicates`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Duplicates`]
+    #[cfg(feature = "dedup")]
+    pub fn into_duplicates(self) -> Result<Vec<dedup::DuplicateSet>, WrongVariant> {
+        match self {
+            Self::Duplicates(sets) => Ok(sets),
+            response => Err(WrongVariant::new("Duplicates", response)),
+        }
+    }
+
+    /// The access control lists read by a [`Request::GetPosixAcl`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::PosixAcl`]
+    #[cfg(feature = "posix-acl")]
+    pub fn into_posix_acl(self) -> Result<posix_acl::PosixAcl, WrongVariant> {
+        match self {
+            Self::PosixAcl(acl) => Ok(acl),
+            response => Err(WrongVariant::new("PosixAcl", response)),
+        }
+    }
+
+    /// The attributes read by a [`Request::GetAttributes`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::Attributes`]
+    #[cfg(unix)]
+    pub fn into_attributes(self) -> Result<Attributes, WrongVariant> {
+        match self {
+            Self::Attributes(attributes) => Ok(attributes),
+            response => Err(WrongVariant::new("Attributes", response)),
+        }
+    }
+
+    /// The attributes read by a [`Request::GetFileAttributes`]
+    ///
+    /// # Errors
+    ///
+    /// If the response isn't [`Response::FileAttributes`]
+    #[cfg(windows)]
+    pub fn into_file_attributes(self) -> Result<windows::FileAttributes, WrongVariant> {
+        match self {
+            Self::FileAttributes(attributes) => Ok(attributes),
+            response => Err(WrongVariant::new("FileAttributes", response)),
+        }
+    }
+}
+
+impl TryFrom<Response> for FileHandle {
+    type Error = WrongVariant;
+
+    fn try_from(response: Response) -> Result<Self, Self::Error> {
+        response.into_file()
+    }
+}
+
+impl TryFrom<Response> for Metadata {
+    type Error = WrongVariant;
+
+    fn try_from(response: Response) -> Result<Self, Self::Error> {
+        response.into_metadata()
+    }
+}
+
+/// The error returned when a [`Response`] isn't the variant a request should have been answered
+/// with, which usually means a middleware or backend is misbehaving
+///
+/// It converts into an [`io::Error`] of kind [`io::ErrorKind::InvalidData`], so it can be
+/// propagated with `?` alongside the errors of the service.
+#[derive(Debug)]
+pub struct WrongVariant {
+    expected: &'static str,
+    response: Box<Response>,
+}
+
+impl WrongVariant {
+    fn new(expected: &'static str, response: Response) -> Self {
+        Self {
+            expected,
+            response: Box::new(response),
+        }
+    }
+
+    /// The name of the variant which was expected
+    #[must_use]
+    pub fn expected(&self) -> &'static str {
+        self.expected
+    }
+
+    /// The response which was received instead
+    #[must_use]
+    pub fn into_response(self) -> Response {
+        *self.response
+    }
+}
+
+impl fmt::Display for WrongVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unexpected response from inner service: expected {}, got {}",
+            self.expected,
+            self.response.variant()
+        )
+    }
+}
+
+impl std::error::Error for WrongVariant {}
+
+impl From<WrongVariant> for io::Error {
+    fn from(err: WrongVariant) -> Self {
+        Self::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_variant() {
+        assert!(matches!(Response::Exists(true).into_exists(), Ok(true)));
+        assert!(matches!(Response::Copied(4).into_copied(), Ok(4)));
+        let Err(err) = Response::Exists(false).into_bytes() else {
+            unreachable!("an Exists response isn't Bytes")
+        };
+        assert_eq!(err.expected(), "Bytes");
+        assert_eq!(
+            err.to_string(),
+            "unexpected response from inner service: expected Bytes, got Exists"
+        );
+        assert!(matches!(err.into_response(), Response::Exists(false)));
+
+        let err = Response::Done.into_file().map_err(io::Error::from);
+        assert!(err.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn test_request_paths() {
+        let rename = Request::Rename {
+            from: Path::new("a").into(),
+            to: Path::new("b").into(),
+        };
+        assert_eq!(rename.variant(), "Rename");
+        assert_eq!(rename.paths(), [Path::new("a"), Path::new("b")]);
+        assert_eq!(
+            Request::ReadBytes(Path::new("c").into()).paths(),
+            [Path::new("c")]
+        );
+        assert!(Request::Flush.paths().is_empty());
+        let batch = Request::MetadataBatch {
+            paths: vec![Path::new("d").into(), Path::new("e").into()],
+            follow_symlinks: true,
+        };
+        assert_eq!(batch.paths(), [Path::new("d"), Path::new("e")]);
+    }
 }
@@ -0,0 +1,320 @@
+//! The parts of the local backend only Linux has: mount ids, inode attributes and copying extended
+//! attributes
+//!
+//! `statx` returns both, and [`std::fs::metadata`] calls it, but keeps them to itself, so they're
+//! only read when [`MetadataFields`] asks for them: mount ids from `/proc`, and attributes with
+//! `lsattr`, as the ioctls it and `chattr` use have no safe binding.  Extended attributes are copied
+//! with `getfattr` and `setfattr` for the same reason.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, ErrorKind},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    process::Command,
+};
+
+use crate::{Attributes, Metadata, MetadataFields};
+
+/// `O_PATH`, which opens a file without reading it, so needs no permission to
+#[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+const O_PATH: i32 = 0o10_000_000;
+#[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+const O_PATH: i32 = 0x0100_0000;
+
+/// The letters `lsattr` shows for each attribute, the first five of which `chattr` can change
+const LETTERS: [(char, Attributes); 7] = [
+    ('c', Attributes::COMPRESSED),
+    ('i', Attributes::IMMUTABLE),
+    ('a', Attributes::APPEND),
+    ('d', Attributes::NODUMP),
+    ('x', Attributes::DAX),
+    ('E', Attributes::ENCRYPTED),
+    ('V', Attributes::VERITY),
+];
+
+/// The number of attributes `chattr` can change
+const SETTABLE: usize = 5;
+
+/// The directories the tools this module runs are found in, rather than whatever `PATH` holds
+const TOOL_DIRS: [&str; 4] = ["/usr/bin", "/bin", "/usr/sbin", "/sbin"];
+
+/// Adds the `fields` [`std::fs::metadata`] leaves out to the `metadata` of `path`
+///
+/// Fields which can't be read, such as the mount id without `/proc` or attributes on file systems
+/// without them, are left unknown rather than failing the request.
+pub(crate) fn extend(
+    mut metadata: Metadata,
+    path: &Path,
+    follow_symlinks: bool,
+    fields: MetadataFields,
+) -> Metadata {
+    let link = !follow_symlinks && metadata.is_symlink();
+    if fields.mount_id {
+        // Links can't be opened, but they're always on the mount of the directory holding them
+        let path = match path.parent() {
+            Some(parent) if link => Path::new(".").join(parent),
+            _ => path.to_owned(),
+        };
+        if let Ok(mount_id) = mount_id(&path) {
+            metadata = metadata.with_mount_id(mount_id);
+        }
+    }
+    // Symlinks have no attributes of their own, and lsattr would read those of their target
+    if fields.attributes && !link {
+        if let Ok(attributes) = get_attributes(path) {
+            metadata = metadata.with_attributes(attributes);
+        }
+    }
+    metadata
+}
+
+fn mount_id(path: &Path) -> io::Result<u64> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(O_PATH)
+        .open(path)?;
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", file.as_raw_fd()))?;
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("mnt_id:"))
+        .and_then(|mount_id| mount_id.trim().parse().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "no mount id in fdinfo"))
+}
+
+/// The attributes of the file or directory at `path`, following symlinks
+pub(crate) fn get_attributes(path: &Path) -> io::Result<Attributes> {
+    // lsattr doesn't follow links itself
+    let path = std::fs::canonicalize(path)?;
+    let output = run(tool("lsattr").args(["-d", "--"]).arg(path))?;
+    let letters = output.split_whitespace().next().unwrap_or_default();
+    Ok(LETTERS
+        .iter()
+        .filter(|(letter, _)| letters.contains(*letter))
+        .fold(Attributes::default(), |attributes, (_, attribute)| {
+            attributes | *attribute
+        }))
+}
+
+/// Sets the attributes of `set` and clears those of `clear` on the file or directory at `path`
+pub(crate) fn set_attributes(path: &Path, set: Attributes, clear: Attributes) -> io::Result<()> {
+    let settable = LETTERS[..SETTABLE]
+        .iter()
+        .fold(Attributes::default(), |settable, (_, attribute)| {
+            settable | *attribute
+        });
+    if !settable.contains(set | clear) || set.0 & clear.0 != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "only the immutable, append, compressed, nodump and dax attributes can be changed",
+        ));
+    }
+    // Absolute, so the path is never taken for a mode, and chattr doesn't follow links itself
+    let path = std::fs::canonicalize(path)?;
+    if set.0 | clear.0 == 0 {
+        return Ok(());
+    }
+    let mut command = tool("chattr");
+    for (sign, attributes) in [('+', set), ('-', clear)] {
+        let letters = LETTERS[..SETTABLE]
+            .iter()
+            .filter(|(_, attribute)| attributes.contains(*attribute))
+            .map(|(letter, _)| *letter)
+            .collect::<String>();
+        if !letters.is_empty() {
+            command.arg(format!("{sign}{letters}"));
+        }
+    }
+    run(command.arg(path)).map(drop)
+}
+
+/// Copies the extended attributes of `from` to `to`, without following symlinks, leaving out those
+/// outside the `user.` namespace which can't be set
+pub(crate) fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    // Every namespace, in hex so values holding newlines survive
+    let dump = run(tool("getfattr")
+        .args(["--dump", "--match=-", "--encoding=hex", "--no-dereference"])
+        .args(["--absolute-names", "--"])
+        .arg(from))?;
+    let attributes = dump
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for attribute in attributes {
+        let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+        let mut command = tool("setfattr");
+        command.args(["--no-dereference", "--name", name]);
+        if !value.is_empty() {
+            command.args(["--value", value]);
+        }
+        match run(command.arg("--").arg(to)) {
+            Err(err)
+                if !name.starts_with("user.")
+                    && matches!(
+                        err.kind(),
+                        ErrorKind::PermissionDenied | ErrorKind::Unsupported
+                    ) => {}
+            result => result.map(drop)?,
+        }
+    }
+    Ok(())
+}
+
+/// A command running the tool `name` from the first of [`TOOL_DIRS`] holding it, with its
+/// messages in the C locale so [`run`] can read them
+pub(crate) fn tool(name: &str) -> Command {
+    let program = TOOL_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(name))
+        .find(|program| program.is_file())
+        .unwrap_or_else(|| Path::new(TOOL_DIRS[0]).join(name));
+    let mut command = Command::new(program);
+    command.env("LC_ALL", "C");
+    command
+}
+
+/// Runs one of e2fsprogs', acl's or attr's tools, made with [`tool`], returning what it printed,
+/// or failing with the error it printed
+pub(crate) fn run(command: &mut Command) -> io::Result<String> {
+    let output = command.output().map_err(|err| match err.kind() {
+        ErrorKind::NotFound => io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "{} isn't installed",
+                command.get_program().to_string_lossy()
+            ),
+        ),
+        _ => err,
+    })?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+    let message = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    // They only print the error's description, in English as the C locale has it, so it's matched
+    // to find it's kind
+    let kind = if message.contains("Operation not supported")
+        || message.contains("Inappropriate ioctl")
+    {
+        ErrorKind::Unsupported
+    } else if message.contains("Operation not permitted") || message.contains("Permission denied") {
+        ErrorKind::PermissionDenied
+    } else if message.contains("Invalid argument") || message.contains("Malformed") {
+        ErrorKind::InvalidInput
+    } else {
+        ErrorKind::Other
+    };
+    Err(io::Error::new(kind, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, Request};
+
+    #[test]
+    fn test_tool() {
+        let command = tool("lsattr");
+        assert!(Path::new(command.get_program()).is_absolute());
+        assert!(command
+            .get_envs()
+            .any(|(key, value)| key == "LC_ALL" && value == Some("C".as_ref())));
+    }
+
+    #[tokio::test]
+    async fn test_extended_metadata() -> io::Result<()> {
+        let dir = TestDir::new("statx")?;
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "statx")?;
+        std::os::unix::fs::symlink("file.txt", dir.join("link"))?;
+        let get = |path: &Path, follow_symlinks| Request::GetMetadata {
+            path: path.into(),
+            follow_symlinks,
+        };
+
+        let metadata = FileSystem::new()
+            .call(get(&path, true))
+            .await?
+            .into_metadata()?;
+        assert_eq!(metadata.mount_id(), None);
+        assert_eq!(metadata.attributes(), None);
+
+        let mut fs = FileSystem::builder()
+            .metadata_fields(MetadataFields {
+                mount_id: true,
+                attributes: true,
+            })
+            .build();
+        let metadata = fs.call(get(&path, true)).await?.into_metadata()?;
+        assert!(metadata.mount_id().is_some());
+        // Not every file system has attributes
+        let attributes = fs
+            .call(Request::GetAttributes(path.as_path().into()))
+            .await
+            .and_then(|response| Ok(response.into_attributes()?));
+        assert_eq!(metadata.attributes(), attributes.ok());
+        let link = fs
+            .call(get(&dir.join("link"), false))
+            .await?
+            .into_metadata()?;
+        assert!(link.is_symlink());
+        assert_eq!(link.mount_id(), metadata.mount_id());
+        assert_eq!(link.attributes(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_attributes() -> io::Result<()> {
+        let dir = TestDir::new("chattr")?;
+        let path = dir.join("published.txt");
+        std::fs::write(&path, "published")?;
+        let mut fs = FileSystem::new();
+        let mut set = |set, clear| {
+            fs.call(Request::SetAttributes {
+                path: path.as_path().into(),
+                set,
+                clear,
+            })
+        };
+        let invalid = set(Attributes::ENCRYPTED, Attributes::default()).await;
+        assert!(invalid.is_err_and(|err| err.kind() == ErrorKind::InvalidInput));
+        let both = set(Attributes::NODUMP, Attributes::NODUMP).await;
+        assert!(both.is_err_and(|err| err.kind() == ErrorKind::InvalidInput));
+
+        // Not every file system has attributes, nor every test runner the capability to make
+        // files immutable
+        match set(Attributes::NODUMP, Attributes::default()).await {
+            Err(err) if err.kind() == ErrorKind::Unsupported => {}
+            result => {
+                result?.into_done()?;
+                let get = Request::GetAttributes(path.as_path().into());
+                let attributes = fs.call(get.clone()).await?.into_attributes()?;
+                assert!(attributes.contains(Attributes::NODUMP));
+                let mut set = |set, clear| {
+                    fs.call(Request::SetAttributes {
+                        path: path.as_path().into(),
+                        set,
+                        clear,
+                    })
+                };
+                set(Attributes::default(), Attributes::NODUMP)
+                    .await?
+                    .into_done()?;
+                match set(Attributes::IMMUTABLE, Attributes::default()).await {
+                    Err(err) if err.kind() == ErrorKind::PermissionDenied => {}
+                    result => {
+                        result?.into_done()?;
+                        assert!(std::fs::write(&path, "changed").is_err());
+                        set(Attributes::default(), Attributes::IMMUTABLE)
+                            .await?
+                            .into_done()?;
+                    }
+                }
+                let attributes = fs.call(get).await?.into_attributes()?;
+                assert!(!attributes.contains(Attributes::NODUMP));
+                assert!(!attributes.contains(Attributes::IMMUTABLE));
+            }
+        }
+        Ok(())
+    }
+}
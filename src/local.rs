@@ -0,0 +1,835 @@
+//! The backend for the local file system, and it's configuration
+
+use std::{
+    fmt,
+    future::Future,
+    io::{self, Read, Seek, SeekFrom, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::future::{ready, BoxFuture, Ready};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    runtime::Handle,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{spawn_blocking, JoinHandle},
+};
+use tower_service::Service;
+
+use crate::{CopyOptions, FileHandle, Mode, Request, Response, SharedService};
+
+/// A backend for the local file system
+///
+/// [`FileSystem::new`] performs each request with [`std::fs`] on tokio's blocking thread pool, and
+/// [`FileSystem::builder`] configures how requests are performed.  Clones share their
+/// configuration, including the limit on open files.
+///
+/// Tokio can't start blocking threads on WASI, so services running in a WASI runtime should use
+/// the `Preopened` backend of the `wasi` feature instead.
+#[derive(Debug, Clone, Default)]
+pub struct FileSystem {
+    options: Option<Arc<Options>>,
+}
+
+#[derive(Debug)]
+struct Options {
+    read_buffer_capacity: Option<usize>,
+    /// A permit for each file which may be open at once
+    limit: Option<Arc<Semaphore>>,
+    tokio_fs: bool,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    runtime: Option<Handle>,
+    metadata_fields: MetadataFields,
+    parallelism: usize,
+}
+
+static DEFAULT_OPTIONS: Options = Options {
+    read_buffer_capacity: None,
+    limit: None,
+    tokio_fs: false,
+    file_mode: None,
+    dir_mode: None,
+    runtime: None,
+    metadata_fields: MetadataFields {
+        mount_id: false,
+        attributes: false,
+    },
+    parallelism: 1,
+};
+
+/// The metadata [`Request::GetMetadata`] reads beyond what `stat` returns, which each cost more
+/// to read, so are left out by default
+///
+/// Only read on Linux, where [`std::fs::metadata`] already reads the birth time with `statx`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MetadataFields {
+    /// Reads [`Metadata::mount_id`](crate::Metadata::mount_id), opening the file and reading
+    /// `/proc` to find it
+    pub mount_id: bool,
+    /// Reads [`Metadata::attributes`](crate::Metadata::attributes), running `lsattr` to find
+    /// them.  Symlinks which aren't followed have none
+    pub attributes: bool,
+}
+
+impl FileSystem {
+    /// A backend with the default configuration
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { options: None }
+    }
+
+    fn options(&self) -> &Options {
+        self.options.as_deref().unwrap_or(&DEFAULT_OPTIONS)
+    }
+
+    /// Configures a backend
+    pub fn builder() -> FileSystemBuilder {
+        FileSystemBuilder::default()
+    }
+}
+
+/// Configures a [`FileSystem`], applying each setting to every request it affects
+#[derive(Debug, Default)]
+#[must_use]
+pub struct FileSystemBuilder {
+    read_buffer_capacity: Option<usize>,
+    max_open_files: Option<usize>,
+    tokio_fs: bool,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    runtime: Option<Handle>,
+    metadata_fields: MetadataFields,
+    parallelism: Option<usize>,
+}
+
+impl FileSystemBuilder {
+    /// The most each file from [`Request::Open`] reads or writes at once, which defaults to
+    /// tokio's own default
+    pub fn read_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.read_buffer_capacity = Some(capacity);
+        self
+    }
+
+    /// Limits the number of files open at once, counting both those requests open while they're
+    /// performed and the files returned for [`Request::Open`] until they're dropped
+    ///
+    /// Requests wait for a file to be closed once the limit is reached, so keep it above the
+    /// number of files an application holds open.  A limit of zero is treated as one.
+    pub fn max_open_files(mut self, max: usize) -> Self {
+        self.max_open_files = Some(max.max(1));
+        self
+    }
+
+    /// Performs requests with the async functions of [`tokio::fs`], which move each step of a
+    /// request (such as opening, seeking and reading a file) to the blocking thread pool
+    /// separately, rather than performing the whole request there at once with [`std::fs`]
+    ///
+    /// Requests are boxed when this is enabled.
+    pub fn use_tokio_fs(mut self, enabled: bool) -> Self {
+        self.tokio_fs = enabled;
+        self
+    }
+
+    /// The permission bits of files created by [`Request::Open`] and [`Request::WriteBytes`],
+    /// before the process' umask is applied.  Only used on unix, and copies keep the permissions
+    /// of their source
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = Some(mode);
+        self
+    }
+
+    /// The permission bits of directories created by [`Request::CreateDir`], before the process'
+    /// umask is applied.  Only used on unix
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.dir_mode = Some(mode);
+        self
+    }
+
+    /// Performs requests on `runtime`, such as one dedicated to file system work, rather than the
+    /// runtime each request is called from, so disk stalls don't hold up latency sensitive tasks
+    ///
+    /// Requests are performed on it's blocking thread pool, or as tasks on it with
+    /// [`use_tokio_fs`](Self::use_tokio_fs), which it must be running to complete.  Files
+    /// returned for [`Request::Open`] are tokio files, which use the blocking pool of whichever
+    /// runtime they're read and written from.
+    pub fn runtime(mut self, runtime: Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Reads the extra `fields` of metadata for each [`Request::GetMetadata`]
+    pub fn metadata_fields(mut self, fields: MetadataFields) -> Self {
+        self.metadata_fields = fields;
+        self
+    }
+
+    /// Walks the trees of [`Request::CopyDir`] with up to `threads` threads, which defaults to one
+    ///
+    /// Each thread reads a directory at a time, which speeds up wide trees on fast disks.  Failures
+    /// don't stop the walk, and the first path to fail (in sorted order) is reported.  Recursive
+    /// [`Request::RemoveDir`]s always use [`std::fs::remove_dir_all`], which guards against
+    /// directories being swapped for symlinks while they're removed.
+    pub fn parallelism(mut self, threads: usize) -> Self {
+        self.parallelism = Some(threads.max(1));
+        self
+    }
+
+    /// Builds the configured backend
+    #[must_use]
+    pub fn build(self) -> FileSystem {
+        let options = Options {
+            read_buffer_capacity: self.read_buffer_capacity,
+            limit: self.max_open_files.map(|max| Arc::new(Semaphore::new(max))),
+            tokio_fs: self.tokio_fs,
+            file_mode: self.file_mode,
+            dir_mode: self.dir_mode,
+            runtime: self.runtime,
+            metadata_fields: self.metadata_fields,
+            parallelism: self.parallelism.unwrap_or(1),
+        };
+        FileSystem {
+            options: Some(Arc::new(options)),
+        }
+    }
+}
+
+impl Service<Request> for FileSystem {
+    type Response = Response;
+    type Error = std::io::Error;
+    type Future = FileSystemFuture;
+
+    /// The [`FileSystem`] performs no setup of it's own, so it's ready immediately
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        self.call_shared(req)
+    }
+}
+
+impl Service<Request> for &FileSystem {
+    type Response = Response;
+    type Error = std::io::Error;
+    type Future = FileSystemFuture;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        self.call_shared(req)
+    }
+}
+
+impl SharedService for FileSystem {
+    type Future = FileSystemFuture;
+
+    fn call_shared(&self, req: Request) -> Self::Future {
+        // Long paths are prefixed here once, rather than by each request which needs them to be
+        #[cfg(windows)]
+        let req = match crate::backend::map_paths(req, crate::windows::extended) {
+            Ok(req) => req,
+            Err(err) => return FileSystemFuture(Inner::Ready(ready(Err(err)))),
+        };
+        match (req, &self.options) {
+            (Request::Compact | Request::Flush, _) => {
+                FileSystemFuture(Inner::Ready(ready(Ok(Response::Done))))
+            }
+            (req, Some(options)) if options.tokio_fs => match &options.runtime {
+                Some(runtime) => {
+                    let task = runtime.spawn(call_async(req, options.clone()));
+                    FileSystemFuture(Inner::Async(Box::pin(async move {
+                        task.await.unwrap_or_else(|_| Err(background_task_failed()))
+                    })))
+                }
+                None => FileSystemFuture(Inner::Async(Box::pin(call_async(req, options.clone())))),
+            },
+            // The permit is acquired before the request takes a blocking thread, so requests
+            // waiting for one don't hold threads the requests holding them need
+            (req, Some(options)) if options.limit.is_some() => {
+                let options = options.clone();
+                FileSystemFuture(Inner::Async(Box::pin(async move {
+                    let permit = acquire(&options).await?;
+                    let runtime = options.runtime.clone();
+                    let call = move || call_blocking(req, &options, permit);
+                    let task = match runtime {
+                        Some(runtime) => runtime.spawn_blocking(call),
+                        None => spawn_blocking(call),
+                    };
+                    task.await.unwrap_or_else(|_| Err(background_task_failed()))
+                })))
+            }
+            (req, options) => {
+                let options = options.clone();
+                let call = move || {
+                    call_blocking(req, options.as_deref().unwrap_or(&DEFAULT_OPTIONS), None)
+                };
+                FileSystemFuture(Inner::Blocking(match self.options().runtime.as_ref() {
+                    Some(runtime) => runtime.spawn_blocking(call),
+                    None => spawn_blocking(call),
+                }))
+            }
+        }
+    }
+}
+
+/// Performs `req` with the blocking APIs of [`std::fs`] and the default configuration, on the
+/// calling thread
+#[cfg(feature = "wasi")]
+pub(crate) fn call_std(req: Request) -> io::Result<Response> {
+    call_blocking(req, &DEFAULT_OPTIONS, None)
+}
+
+/// Performs `req` with the blocking APIs of [`std::fs`], as `tokio::fs` does on it's blocking
+/// thread pool
+// One arm per request
+#[allow(clippy::too_many_lines)]
+fn call_blocking(
+    req: Request,
+    options: &Options,
+    permit: Option<OwnedSemaphorePermit>,
+) -> io::Result<Response> {
+    match req {
+        #[cfg(feature = "archive")]
+        req @ (Request::Archive { .. } | Request::Extract { .. }) => {
+            call_without_tokio(req, options)
+        }
+        #[cfg(feature = "dedup")]
+        req @ Request::FindDuplicates { .. } => call_without_tokio(req, options),
+        req @ (Request::CopyDir { .. } | Request::Copy { .. }) => call_without_tokio(req, options),
+        #[cfg(feature = "posix-acl")]
+        req @ (Request::GetPosixAcl(_) | Request::SetPosixAcl { .. }) => {
+            call_without_tokio(req, options)
+        }
+        #[cfg(windows)]
+        req @ (Request::ReadJunction(_)
+        | Request::GetFileAttributes(_)
+        | Request::SetFileAttributes { .. }) => call_without_tokio(req, options),
+        #[cfg(unix)]
+        req @ (Request::GetAttributes(_) | Request::SetAttributes { .. }) => {
+            call_without_tokio(req, options)
+        }
+        Request::Compact | Request::Flush => Ok(Response::Done),
+        Request::CreateDir { path, recursive } => {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(recursive);
+            #[cfg(unix)]
+            if let Some(mode) = options.dir_mode {
+                std::os::unix::fs::DirBuilderExt::mode(&mut builder, mode);
+            }
+            builder.create(path).map(Response::done)
+        }
+        Request::Exists(path) => path.try_exists().map(Response::Exists),
+        Request::FollowLink(path) => std::fs::read_link(path).map(points_to),
+        Request::GetMetadata {
+            path,
+            follow_symlinks,
+        } => get_metadata(&path, follow_symlinks, options).map(Response::Metadata),
+        Request::MetadataBatch {
+            paths,
+            follow_symlinks,
+        } => Ok(metadata_batch(&paths, follow_symlinks, options)),
+        Request::HardLink { src, dst } => std::fs::hard_link(src, dst).map(Response::done),
+        Request::Open { mode, path } => {
+            let file = fs::File::from_std(open_options(mode, options).open(path)?);
+            Ok(Response::File(file_handle(file, options, permit)))
+        }
+        #[cfg(windows)]
+        Request::OpenStream { mode, path, stream } => {
+            let path = crate::windows::stream_path(&path, &stream)?;
+            let file = fs::File::from_std(open_options(mode, options).open(path)?);
+            Ok(Response::File(file_handle(file, options, permit)))
+        }
+        Request::ReadDir(path) => read_dir(&path, options),
+        Request::ReadBytes(path) => std::fs::read(path).map(Response::Bytes),
+        Request::ReadRange { path, range } => {
+            let mut file = std::fs::File::open(path)?;
+            file.seek(SeekFrom::Start(range.start))?;
+            let mut bytes = Vec::new();
+            file.take(range.end.saturating_sub(range.start))
+                .read_to_end(&mut bytes)?;
+            Ok(Response::Bytes(bytes))
+        }
+        Request::RemoveDir {
+            path,
+            recursive: true,
+        } => std::fs::remove_dir_all(path).map(Response::done),
+        Request::RemoveDir {
+            path,
+            recursive: false,
+        } => std::fs::remove_dir(path).map(Response::done),
+        Request::RemoveFile(path) => std::fs::remove_file(path).map(Response::done),
+        Request::Rename { from, to } => std::fs::rename(from, to).map(Response::done),
+        Request::SetPermissions { path, perm } => {
+            std::fs::set_permissions(path, perm).map(Response::done)
+        }
+        #[cfg(unix)]
+        Request::Symlink { src, dst } => std::os::unix::fs::symlink(src, dst).map(Response::done),
+        #[cfg(windows)]
+        Request::SymlinkDir { src, dst } => {
+            std::os::windows::fs::symlink_dir(src, dst).map(Response::done)
+        }
+        #[cfg(windows)]
+        Request::SymlinkFile { src, dst } => {
+            std::os::windows::fs::symlink_file(src, dst).map(Response::done)
+        }
+        Request::WriteBytes { path, bytes } => open_options(Mode::CreateOrOverwrite, options)
+            .open(path)?
+            .write_all(&bytes)
+            .map(Response::done),
+        Request::AppendBytes { path, bytes } => open_options(Mode::CreateOrAppend, options)
+            .open(path)?
+            .write_all(&bytes)
+            .map(Response::done),
+    }
+}
+
+/// Performs `req` with the async APIs of [`tokio::fs`]
+// One arm per request
+#[allow(clippy::too_many_lines)]
+async fn call_async(req: Request, options: Arc<Options>) -> io::Result<Response> {
+    let permit = acquire(&options).await?;
+    match req {
+        #[cfg(feature = "archive")]
+        req @ (Request::Archive { .. } | Request::Extract { .. }) => {
+            spawn_without_tokio(req, options).await
+        }
+        #[cfg(feature = "dedup")]
+        req @ Request::FindDuplicates { .. } => spawn_without_tokio(req, options).await,
+        Request::Copy {
+            from,
+            to,
+            options: copy,
+        } if copy == CopyOptions::default() => fs::copy(from, to).await.map(Response::Copied),
+        req @ (Request::CopyDir { .. } | Request::Copy { .. }) => {
+            spawn_without_tokio(req, options).await
+        }
+        #[cfg(feature = "posix-acl")]
+        req @ (Request::GetPosixAcl(_) | Request::SetPosixAcl { .. }) => {
+            spawn_without_tokio(req, options).await
+        }
+        #[cfg(windows)]
+        req @ (Request::ReadJunction(_)
+        | Request::GetFileAttributes(_)
+        | Request::SetFileAttributes { .. }) => spawn_without_tokio(req, options).await,
+        #[cfg(unix)]
+        req @ (Request::GetAttributes(_) | Request::SetAttributes { .. }) => {
+            spawn_without_tokio(req, options).await
+        }
+        Request::Compact | Request::Flush => Ok(Response::Done),
+        Request::CreateDir { path, recursive } => {
+            let mut builder = fs::DirBuilder::new();
+            builder.recursive(recursive);
+            #[cfg(unix)]
+            if let Some(mode) = options.dir_mode {
+                builder.mode(mode);
+            }
+            builder.create(path).await.map(Response::done)
+        }
+        Request::Exists(path) => fs::try_exists(path).await.map(Response::Exists),
+        Request::FollowLink(path) => fs::read_link(path).await.map(points_to),
+        // Reading the extra fields blocks as much as the metadata itself does
+        Request::GetMetadata {
+            path,
+            follow_symlinks,
+        } => spawn_blocking(move || get_metadata(&path, follow_symlinks, &options))
+            .await
+            .map_err(|_| background_task_failed())?
+            .map(Response::Metadata),
+        // One blocking task stats the whole batch, rather than one per path
+        Request::MetadataBatch {
+            paths,
+            follow_symlinks,
+        } => spawn_blocking(move || metadata_batch(&paths, follow_symlinks, &options))
+            .await
+            .map_err(|_| background_task_failed()),
+        Request::HardLink { src, dst } => fs::hard_link(src, dst).await.map(Response::done),
+        Request::Open { mode, path } => {
+            let file = fs::OpenOptions::from(open_options(mode, &options))
+                .open(path)
+                .await?;
+            Ok(Response::File(file_handle(file, &options, permit)))
+        }
+        #[cfg(windows)]
+        Request::OpenStream { mode, path, stream } => {
+            let path = crate::windows::stream_path(&path, &stream)?;
+            let file = fs::OpenOptions::from(open_options(mode, &options))
+                .open(path)
+                .await?;
+            Ok(Response::File(file_handle(file, &options, permit)))
+        }
+        // One blocking task lists and stats the whole directory
+        Request::ReadDir(path) => spawn_blocking(move || read_dir(&path, &options))
+            .await
+            .map_err(|_| background_task_failed())?,
+        Request::ReadBytes(path) => fs::read(path).await.map(Response::Bytes),
+        Request::ReadRange { path, range } => {
+            let mut file = fs::File::open(path).await?;
+            file.seek(SeekFrom::Start(range.start)).await?;
+            let mut bytes = Vec::new();
+            file.take(range.end.saturating_sub(range.start))
+                .read_to_end(&mut bytes)
+                .await?;
+            Ok(Response::Bytes(bytes))
+        }
+        Request::RemoveDir {
+            path,
+            recursive: true,
+        } => fs::remove_dir_all(path).await.map(Response::done),
+        Request::RemoveDir {
+            path,
+            recursive: false,
+        } => fs::remove_dir(path).await.map(Response::done),
+        Request::RemoveFile(path) => fs::remove_file(path).await.map(Response::done),
+        Request::Rename { from, to } => fs::rename(from, to).await.map(Response::done),
+        Request::SetPermissions { path, perm } => {
+            fs::set_permissions(path, perm).await.map(Response::done)
+        }
+        #[cfg(unix)]
+        Request::Symlink { src, dst } => fs::symlink(src, dst).await.map(Response::done),
+        #[cfg(windows)]
+        Request::SymlinkDir { src, dst } => fs::symlink_dir(src, dst).await.map(Response::done),
+        #[cfg(windows)]
+        Request::SymlinkFile { src, dst } => fs::symlink_file(src, dst).await.map(Response::done),
+        Request::WriteBytes { path, bytes } => {
+            let mut file = fs::OpenOptions::from(open_options(Mode::CreateOrOverwrite, &options))
+                .open(path)
+                .await?;
+            file.write_all(&bytes).await?;
+            file.flush().await.map(Response::done)
+        }
+        Request::AppendBytes { path, bytes } => {
+            let mut file = fs::OpenOptions::from(open_options(Mode::CreateOrAppend, &options))
+                .open(path)
+                .await?;
+            file.write_all(&bytes).await?;
+            file.flush().await.map(Response::done)
+        }
+    }
+}
+
+/// Performs a request which [`tokio::fs`] has no API for, such as those working through a whole
+/// tree, on the blocking thread pool
+async fn spawn_without_tokio(req: Request, options: Arc<Options>) -> io::Result<Response> {
+    spawn_blocking(move || call_without_tokio(req, &options))
+        .await
+        .map_err(|_| background_task_failed())?
+}
+
+/// Performs a request which [`tokio::fs`] has no API for, removing what was written of a new
+/// archive on failure
+fn call_without_tokio(req: Request, options: &Options) -> io::Result<Response> {
+    match req {
+        #[cfg(feature = "archive")]
+        Request::Archive {
+            src_dir,
+            dst,
+            format,
+            compression,
+            filter,
+        } => {
+            let file = open_options(Mode::CreateOrOverwrite, options).open(&dst)?;
+            crate::archive::pack(&src_dir, &dst, file, format, compression, &filter)
+                .map(Response::done)
+                .inspect_err(|_| {
+                    let _ = std::fs::remove_file(&dst);
+                })
+        }
+        #[cfg(feature = "archive")]
+        Request::Extract {
+            archive,
+            dst_dir,
+            options,
+        } => crate::archive::extract(&archive, &dst_dir, &options).map(Response::done),
+        #[cfg(feature = "dedup")]
+        Request::FindDuplicates { dir, options } => {
+            crate::dedup::find_duplicates(&dir, &options).map(Response::Duplicates)
+        }
+        #[cfg(all(feature = "posix-acl", target_os = "linux"))]
+        Request::GetPosixAcl(path) => crate::posix_acl::get_acl(&path).map(Response::PosixAcl),
+        #[cfg(all(feature = "posix-acl", target_os = "linux"))]
+        Request::SetPosixAcl { path, acl } => {
+            crate::posix_acl::set_acl(&path, &acl).map(Response::done)
+        }
+        // Only Linux's getfacl and setfacl are supported
+        #[cfg(all(feature = "posix-acl", not(target_os = "linux")))]
+        Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+        #[cfg(windows)]
+        Request::ReadJunction(path) => crate::windows::read_junction(&path).map(Response::PointsTo),
+        #[cfg(windows)]
+        Request::GetFileAttributes(path) => {
+            crate::windows::get_attributes(&path).map(Response::FileAttributes)
+        }
+        #[cfg(windows)]
+        Request::SetFileAttributes { path, set, clear } => {
+            crate::windows::set_attributes(&path, set, clear).map(Response::done)
+        }
+        #[cfg(target_os = "linux")]
+        Request::GetAttributes(path) => {
+            crate::linux::get_attributes(&path).map(Response::Attributes)
+        }
+        #[cfg(target_os = "linux")]
+        Request::SetAttributes { path, set, clear } => {
+            crate::linux::set_attributes(&path, set, clear).map(Response::done)
+        }
+        // Only Linux has chattr's attributes
+        #[cfg(all(unix, not(target_os = "linux")))]
+        Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+        Request::Copy {
+            from,
+            to,
+            options: copy,
+        } => crate::copy::copy_file(&from, &to, copy).map(Response::Copied),
+        Request::CopyDir {
+            from,
+            to,
+            options: copy,
+        } => crate::walk::copy_dir(&from, &to, copy, options.parallelism).map(Response::Copied),
+        _ => unreachable!("only requests without a tokio API are handled here"),
+    }
+}
+
+fn metadata_batch(
+    paths: &[Arc<std::path::Path>],
+    follow_symlinks: bool,
+    options: &Options,
+) -> Response {
+    Response::MetadataBatch(
+        paths
+            .iter()
+            .map(|path| get_metadata(path, follow_symlinks, options))
+            .collect(),
+    )
+}
+
+fn read_dir(path: &std::path::Path, options: &Options) -> io::Result<Response> {
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| {
+            let entry = entry?;
+            let metadata = get_metadata(&entry.path(), false, options)?;
+            Ok((entry.file_name().into(), metadata))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by(|(a, _), (b, _): &(std::path::PathBuf, _)| a.cmp(b));
+    Ok(Response::Directory(entries))
+}
+
+fn get_metadata(
+    path: &std::path::Path,
+    follow_symlinks: bool,
+    options: &Options,
+) -> io::Result<crate::Metadata> {
+    let metadata = if follow_symlinks {
+        std::fs::metadata(path)?
+    } else {
+        std::fs::symlink_metadata(path)?
+    };
+    #[cfg(target_os = "linux")]
+    if options.metadata_fields != MetadataFields::default() {
+        let fields = options.metadata_fields;
+        return Ok(crate::linux::extend(
+            metadata.into(),
+            path,
+            follow_symlinks,
+            fields,
+        ));
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = options;
+    Ok(metadata.into())
+}
+
+/// Answers a [`Request::FollowLink`], leaving out the extended-length prefix windows gives the
+/// targets of some links
+fn points_to(target: std::path::PathBuf) -> Response {
+    #[cfg(windows)]
+    let target = crate::windows::strip_extended(target);
+    Response::PointsTo(target)
+}
+
+/// The options to open a file with `mode`, creating it with the configured permissions
+fn open_options(mode: Mode, options: &Options) -> std::fs::OpenOptions {
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut open_options = mode.into_open_options();
+    #[cfg(unix)]
+    if let Some(mode) = options.file_mode {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, mode);
+    }
+    #[cfg(not(unix))]
+    let _ = options;
+    open_options
+}
+
+fn file_handle(
+    mut file: fs::File,
+    options: &Options,
+    permit: Option<OwnedSemaphorePermit>,
+) -> FileHandle {
+    if let Some(capacity) = options.read_buffer_capacity {
+        file.set_max_buf_size(capacity);
+    }
+    let handle = FileHandle::from(file);
+    match permit {
+        Some(permit) => handle.hold(permit),
+        None => handle,
+    }
+}
+
+fn background_task_failed() -> io::Error {
+    io::Error::other("background task failed")
+}
+
+/// Waits for one of the files of [`FileSystemBuilder::max_open_files`] to be free, if it's set,
+/// returning the permit which holds it until it's dropped
+async fn acquire(options: &Options) -> io::Result<Option<OwnedSemaphorePermit>> {
+    match options.limit.clone() {
+        Some(limit) => match limit.acquire_owned().await {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(background_task_failed()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// The future returned by [`FileSystem`], which waits for the request to be performed on tokio's
+/// blocking thread pool without boxing anything (unless [`FileSystemBuilder::use_tokio_fs`] or
+/// [`FileSystemBuilder::max_open_files`] is set)
+pub struct FileSystemFuture(Inner);
+
+enum Inner {
+    /// A request which needed no work
+    Ready(Ready<io::Result<Response>>),
+    /// A request performed on the blocking thread pool
+    Blocking(JoinHandle<io::Result<Response>>),
+    /// A request performed with [`tokio::fs`], or waiting for a file to be free before it's
+    /// performed on the blocking thread pool
+    Async(BoxFuture<'static, io::Result<Response>>),
+}
+
+impl Future for FileSystemFuture {
+    type Output = io::Result<Response>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.get_mut().0 {
+            Inner::Ready(ready) => Pin::new(ready).poll(cx),
+            Inner::Blocking(handle) => Pin::new(handle)
+                .poll(cx)
+                .map(|joined| joined.unwrap_or_else(|_| Err(background_task_failed()))),
+            Inner::Async(future) => future.as_mut().poll(cx),
+        }
+    }
+}
+
+impl fmt::Debug for FileSystemFuture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Inner::Ready(ready) => f.debug_tuple("FileSystemFuture").field(ready).finish(),
+            Inner::Blocking(handle) => f.debug_tuple("FileSystemFuture").field(handle).finish(),
+            Inner::Async(_) => f.debug_tuple("FileSystemFuture").finish_non_exhaustive(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystemExt;
+
+    #[tokio::test]
+    async fn test_builder() -> io::Result<()> {
+        let dir = TestDir::new("local")?;
+        let (tree, copy) = (dir.join("tree"), dir.join("copy"));
+        for tokio_fs in [false, true] {
+            let mut fs = FileSystem::builder()
+                .read_buffer_capacity(16)
+                .max_open_files(1)
+                .use_tokio_fs(tokio_fs)
+                .file_mode(0o600)
+                .dir_mode(0o700)
+                .parallelism(4)
+                .build();
+            fs.create_dir_all(&tree).await?;
+            fs.write(tree.join("a.txt"), b"hello".to_vec()).await?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(tree.join("a.txt"))?.permissions().mode();
+                assert_eq!(mode & 0o077, 0);
+            }
+
+            // The open file holds the only permit, so other requests wait for it to close
+            let file = fs.open(tree.join("a.txt"), Mode::Read).await?;
+            let mut read = fs.clone();
+            let path = tree.join("a.txt");
+            let waiting = tokio::spawn(async move { read.read(path).await });
+            spawn_blocking(|| std::thread::sleep(Duration::from_millis(50)))
+                .await
+                .map_err(io::Error::other)?;
+            assert!(!waiting.is_finished());
+            drop(file);
+            assert_eq!(waiting.await.map_err(io::Error::other)??, b"hello");
+
+            // Recursive requests walk their trees with several threads
+            assert_eq!(fs.copy_dir(&tree, &copy).await?, 5);
+            for path in [tree.clone(), copy.clone()] {
+                let req = Request::RemoveDir {
+                    path: path.into(),
+                    recursive: true,
+                };
+                crate::ext::ready_call(&mut fs, req).await?;
+            }
+            assert!(!tree.exists());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_runtime() -> io::Result<()> {
+        let dir = TestDir::new("runtime")?;
+        let path = dir.join("pinned.txt");
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let handle = runtime.handle().clone();
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let driver = std::thread::spawn(move || runtime.block_on(stopped));
+        for tokio_fs in [false, true] {
+            let mut fs = FileSystem::builder()
+                .runtime(handle.clone())
+                .use_tokio_fs(tokio_fs)
+                .build();
+            fs.write(&path, b"pinned".to_vec()).await?;
+            assert_eq!(fs.read(&path).await?, b"pinned");
+        }
+
+        // Once the runtime is gone, requests have nowhere to run
+        let _ = stop.send(());
+        let _ = driver.join();
+        let mut fs = FileSystem::builder().runtime(handle).build();
+        assert!(fs.exists(&path).await.is_err());
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_read_junction() -> io::Result<()> {
+        let dir = TestDir::new("junction")?;
+        std::fs::create_dir_all(dir.join("release"))?;
+        let mut fs = FileSystem::new();
+        let not_junction = fs.call(Request::ReadJunction(dir.join("release").into()));
+        assert!(not_junction
+            .await
+            .is_err_and(|err| err.kind() == io::ErrorKind::InvalidInput));
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Building a service stack for each connection or session
+
+use std::{
+    future::Future,
+    io,
+    task::{Context, Poll},
+};
+
+use tower_service::Service;
+
+/// A make service, which builds a configured service stack from the `Target` describing a
+/// connection or session
+///
+/// Each call runs the closure given to [`MakeFileSystem::new`], so every connection can have it's
+/// own root, quotas or credentials.  Since it's a `Service<Target>` for any target, it fits
+/// hyper's make service pattern (where the target is the accepted connection) and
+/// [`ftp_server::serve_sessions`](crate::ftp_server::serve_sessions) (where it's the login).  For
+/// servers which take a single service, such as the SFTP server, call it with what the application
+/// knows about the connection before serving it.
+///
+/// ```
+/// # async fn example() -> std::io::Result<()> {
+/// use std::{future::ready, path::Path};
+///
+/// use tower_fs::{FileSystem, FileSystemExt, MakeFileSystem};
+/// use tower_service::Service;
+///
+/// // Gives each user a directory of their own
+/// let mut make = MakeFileSystem::new(|user: &str| {
+///     let root = std::env::temp_dir().join(user);
+///     ready(Ok((FileSystem::new(), root)))
+/// });
+/// let (mut fs, root) = make.call("alice").await?;
+/// fs.create_dir_all(&root).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MakeFileSystem<F> {
+    make: F,
+}
+
+impl<F> MakeFileSystem<F> {
+    /// Builds each service with `make`, which is given the target and returns a future resolving
+    /// to the service
+    #[must_use]
+    pub fn new(make: F) -> Self {
+        Self { make }
+    }
+}
+
+impl<F, T, Fut, S> Service<T> for MakeFileSystem<F>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    type Response = S;
+    type Error = io::Error;
+    type Future = Fut;
+
+    /// Services are built when called, so a [`MakeFileSystem`] is always ready
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        (self.make)(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::ready;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt};
+
+    #[tokio::test]
+    async fn test_make_file_system() -> io::Result<()> {
+        let dir = TestDir::new("make")?;
+        let mut make = MakeFileSystem::new(|user: &str| {
+            let root = dir.join(user);
+            ready(std::fs::create_dir_all(&root).map(|()| (FileSystem::new(), root)))
+        });
+        for user in ["alice", "bob"] {
+            let (mut fs, root) = make.call(user).await?;
+            fs.write(root.join("notes.txt"), user.into()).await?;
+        }
+        assert_eq!(std::fs::read(dir.join("alice/notes.txt"))?, b"alice");
+        assert_eq!(std::fs::read(dir.join("bob/notes.txt"))?, b"bob");
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+//! Metadata which any backend can fill in, not just the local file system
+
+use std::{
+    io::{self, ErrorKind},
+    ops::BitOr,
+    time::SystemTime,
+};
+
+const S_IFREG: u32 = 0o100_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// The kind of entry a path refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, sockets, pipes and anything else which isn't a regular file, directory or link
+    Other,
+}
+
+impl From<std::fs::FileType> for FileType {
+    fn from(file_type: std::fs::FileType) -> Self {
+        if file_type.is_file() {
+            Self::File
+        } else if file_type.is_dir() {
+            Self::Dir
+        } else if file_type.is_symlink() {
+            Self::Symlink
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// Flags a file system keeps for an inode, with the bits of the `attributes` of Linux's `statx`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Attributes(pub u64);
+
+impl Attributes {
+    pub const COMPRESSED: Self = Self(0x4);
+    /// The file can't be modified, removed, renamed or linked to, even by root
+    pub const IMMUTABLE: Self = Self(0x10);
+    /// The file can only be opened to append to it
+    pub const APPEND: Self = Self(0x20);
+    /// The file is skipped by `dump`
+    pub const NODUMP: Self = Self(0x40);
+    pub const ENCRYPTED: Self = Self(0x800);
+    /// The file is protected by fs-verity
+    pub const VERITY: Self = Self(0x10_0000);
+    /// The file is accessed directly, bypassing the page cache
+    pub const DAX: Self = Self(0x20_0000);
+
+    /// Whether every flag of `other` is set
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The metadata of a file, directory or link, returned for a [`Request::GetMetadata`]
+///
+/// Only the type and length are required.  Every other field is filled in where the backend knows
+/// it, so archives, object stores and remote servers can answer with what they have, and
+/// frontends fall back to sensible defaults for the rest.  The accessors mirror those of
+/// [`std::fs::Metadata`], which converts into this with `into`.
+///
+/// [`Request::GetMetadata`]: crate::Request::GetMetadata
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Metadata {
+    file_type: FileType,
+    len: u64,
+    readonly: bool,
+    permissions: Option<u32>,
+    modified: Option<SystemTime>,
+    accessed: Option<SystemTime>,
+    changed: Option<SystemTime>,
+    created: Option<SystemTime>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    ino: Option<u64>,
+    nlink: Option<u64>,
+    mount_id: Option<u64>,
+    attributes: Option<Attributes>,
+}
+
+impl Metadata {
+    /// The metadata of a writable entry of `len` bytes, with nothing else known about it
+    #[must_use]
+    pub fn new(file_type: FileType, len: u64) -> Self {
+        Self {
+            file_type,
+            len,
+            readonly: false,
+            permissions: None,
+            modified: None,
+            accessed: None,
+            changed: None,
+            created: None,
+            uid: None,
+            gid: None,
+            ino: None,
+            nlink: None,
+            mount_id: None,
+            attributes: None,
+        }
+    }
+
+    /// Sets whether the entry is read only, without any unix permission bits
+    #[must_use]
+    pub fn with_readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    /// Sets the unix permission bits (the low 12 bits of `mode`), which also decide whether the
+    /// entry is read only
+    #[must_use]
+    pub fn with_permissions(mut self, mode: u32) -> Self {
+        self.permissions = Some(mode & 0o7777);
+        self.readonly = mode & 0o222 == 0;
+        self
+    }
+
+    /// Sets the time the contents were last modified
+    #[must_use]
+    pub fn with_modified(mut self, time: SystemTime) -> Self {
+        self.modified = Some(time);
+        self
+    }
+
+    /// Sets the time the contents were last read
+    #[must_use]
+    pub fn with_accessed(mut self, time: SystemTime) -> Self {
+        self.accessed = Some(time);
+        self
+    }
+
+    /// Sets the time the contents or metadata were last changed (`ctime`)
+    #[must_use]
+    pub fn with_changed(mut self, time: SystemTime) -> Self {
+        self.changed = Some(time);
+        self
+    }
+
+    /// Sets the time the entry was created (it's birth time)
+    #[must_use]
+    pub fn with_created(mut self, time: SystemTime) -> Self {
+        self.created = Some(time);
+        self
+    }
+
+    /// Sets the ids of the user and group which own the entry
+    #[must_use]
+    pub fn with_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Sets the inode number, and the number of hard links to it
+    #[must_use]
+    pub fn with_inode(mut self, ino: u64, nlink: u64) -> Self {
+        self.ino = Some(ino);
+        self.nlink = Some(nlink);
+        self
+    }
+
+    /// Sets the id of the mount holding the entry
+    #[must_use]
+    pub fn with_mount_id(mut self, mount_id: u64) -> Self {
+        self.mount_id = Some(mount_id);
+        self
+    }
+
+    /// Sets the flags the file system keeps for the entry
+    #[must_use]
+    pub fn with_attributes(mut self, attributes: Attributes) -> Self {
+        self.attributes = Some(attributes);
+        self
+    }
+
+    #[must_use]
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    #[must_use]
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::File
+    }
+
+    #[must_use]
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Dir
+    }
+
+    #[must_use]
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+
+    /// The length of the file in bytes
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the file is empty
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[must_use]
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// The unix permission bits, if the backend has them
+    #[must_use]
+    pub fn permissions(&self) -> Option<u32> {
+        self.permissions
+    }
+
+    /// The file type and permission bits, as in unix's `st_mode`
+    ///
+    /// Without permission bits from the backend, entries are `0o755`, or `0o555` if read only.
+    /// [`FileType::Other`] has no file type bits.
+    #[must_use]
+    pub fn mode(&self) -> u32 {
+        let file_type = match self.file_type {
+            FileType::File => S_IFREG,
+            FileType::Dir => S_IFDIR,
+            FileType::Symlink => S_IFLNK,
+            FileType::Other => 0,
+        };
+        let permissions = self
+            .permissions
+            .unwrap_or(if self.readonly { 0o555 } else { 0o755 });
+        file_type | permissions
+    }
+
+    /// The time the contents were last modified
+    ///
+    /// # Errors
+    ///
+    /// [`ErrorKind::Unsupported`] if the backend doesn't know it
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        known(self.modified)
+    }
+
+    /// The time the contents were last read
+    ///
+    /// # Errors
+    ///
+    /// [`ErrorKind::Unsupported`] if the backend doesn't know it
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        known(self.accessed)
+    }
+
+    /// The time the contents or metadata were last changed (`ctime`)
+    ///
+    /// # Errors
+    ///
+    /// [`ErrorKind::Unsupported`] if the backend doesn't know it
+    pub fn changed(&self) -> io::Result<SystemTime> {
+        known(self.changed)
+    }
+
+    /// The time the entry was created
+    ///
+    /// # Errors
+    ///
+    /// [`ErrorKind::Unsupported`] if the backend doesn't know it
+    pub fn created(&self) -> io::Result<SystemTime> {
+        known(self.created)
+    }
+
+    /// The id of the user which owns the entry
+    #[must_use]
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// The id of the group which owns the entry
+    #[must_use]
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    /// The inode number, which identifies the entry on it's device
+    #[must_use]
+    pub fn ino(&self) -> Option<u64> {
+        self.ino
+    }
+
+    /// The number of hard links to the entry
+    #[must_use]
+    pub fn nlink(&self) -> Option<u64> {
+        self.nlink
+    }
+
+    /// The id of the mount holding the entry, as in `/proc/self/mountinfo`, which unlike the
+    /// device tells bind mounts apart
+    #[must_use]
+    pub fn mount_id(&self) -> Option<u64> {
+        self.mount_id
+    }
+
+    /// The flags the file system keeps for the entry, such as whether it's immutable
+    #[must_use]
+    pub fn attributes(&self) -> Option<Attributes> {
+        self.attributes
+    }
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        let mut converted = Self::new(metadata.file_type().into(), metadata.len())
+            .with_readonly(metadata.permissions().readonly());
+        converted.modified = metadata.modified().ok();
+        converted.accessed = metadata.accessed().ok();
+        converted.created = metadata.created().ok();
+        #[cfg(unix)]
+        {
+            use std::{os::unix::fs::MetadataExt, time::Duration};
+
+            converted = converted
+                .with_permissions(metadata.mode())
+                .with_owner(metadata.uid(), metadata.gid())
+                .with_inode(metadata.ino(), metadata.nlink());
+            converted.changed = u64::try_from(metadata.ctime())
+                .ok()
+                .zip(u32::try_from(metadata.ctime_nsec()).ok())
+                .and_then(|(secs, nanos)| {
+                    SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+                });
+        }
+        converted
+    }
+}
+
+fn known(time: Option<SystemTime>) -> io::Result<SystemTime> {
+    time.ok_or_else(|| ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() -> io::Result<()> {
+        let metadata = Metadata::new(FileType::File, 5).with_permissions(0o100_444);
+        assert!(metadata.is_file());
+        assert!(metadata.readonly());
+        assert_eq!(metadata.permissions(), Some(0o444));
+        assert_eq!(metadata.mode(), 0o100_444);
+        assert!(metadata
+            .modified()
+            .is_err_and(|err| err.kind() == ErrorKind::Unsupported));
+
+        let dir = Metadata::new(FileType::Dir, 0).with_readonly(true);
+        assert_eq!(dir.mode(), 0o040_555);
+        assert_eq!(dir.uid(), None);
+
+        let local = Metadata::from(std::fs::metadata(env!("CARGO_MANIFEST_DIR"))?);
+        assert!(local.is_dir());
+        assert!(local.modified().is_ok());
+        #[cfg(unix)]
+        assert!(local.uid().is_some() && local.changed().is_ok());
+        Ok(())
+    }
+}
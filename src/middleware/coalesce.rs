@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{ext::ready_call, Request, Response};
+
+/// Layers [`Coalesce`] over services, which each buffer their own writes
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy)]
+pub struct CoalesceLayer {
+    max_bytes: usize,
+    max_delay: Duration,
+}
+
+impl Default for CoalesceLayer {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl CoalesceLayer {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flushes once `max_bytes` are buffered, which defaults to 64 KiB.  Writes of at least this
+    /// many bytes aren't buffered at all
+    #[must_use]
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Flushes `max_delay` after a write is buffered, which defaults to a second
+    #[must_use]
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+impl<S: Service<Request>> Layer<S> for CoalesceLayer {
+    type Service = Coalesce<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Coalesce {
+            inner,
+            layer: *self,
+            shared: Arc::default(),
+        }
+    }
+}
+
+/// Buffers small [`Request::AppendBytes`] and [`Request::WriteBytes`] to the same files, and sends
+/// them to an inner service in batches, for services writing many small records to a few logs
+///
+/// Appends to a file are joined into one, and a write replaces whatever was buffered for it, with
+/// later appends joined to the write.  Everything buffered is flushed once
+/// [`CoalesceLayer::max_bytes`] are, [`CoalesceLayer::max_delay`] after the first write was
+/// buffered, on a [`Request::Flush`] (which is then passed on), and before any other request is
+/// passed on, so reads, renames and the like always see the buffered writes.
+///
+/// # Crash safety
+///
+/// Buffered writes are answered with [`Response::Done`] before they reach the inner service, so
+/// they're lost if the process exits or crashes before they're flushed, and a failed flush can't
+/// fail them.  Flushes fail the request which caused them instead, or for the timer, the next
+/// [`Request::Flush`], and the writes which failed are dropped.  The timer holds what's buffered
+/// after the service is dropped, but needs the tokio runtime to keep running to flush it.  Files
+/// are flushed one at a time, so a crash during a flush may leave some of them written and others
+/// not, and a file rewritten by a joined write is only as atomic as the inner service's
+/// [`Request::WriteBytes`].  Send a [`Request::Flush`] before relying on anything being written.
+#[derive(Debug, Clone)]
+pub struct Coalesce<S> {
+    inner: S,
+    layer: CoalesceLayer,
+    shared: Arc<Shared>,
+}
+
+impl<S> Service<Request> for Coalesce<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Keep the service which was driven to readiness for this request
+        let inner = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, inner);
+        let layer = self.layer;
+        let shared = self.shared.clone();
+        async move {
+            let (path, buffer) = match req {
+                Request::AppendBytes { path, bytes } if bytes.len() < layer.max_bytes => {
+                    (path, Buffer::Append(bytes))
+                }
+                Request::WriteBytes { path, bytes } if bytes.len() < layer.max_bytes => {
+                    (path, Buffer::Write(bytes))
+                }
+                Request::Flush => {
+                    let flushed = shared.flush(&mut inner).await;
+                    let failed = shared.lock().failed.take();
+                    let response = ready_call(&mut inner, Request::Flush).await;
+                    return flushed.and(failed.map_or(Ok(()), Err)).and(response);
+                }
+                req => {
+                    shared.flush(&mut inner).await?;
+                    return ready_call(&mut inner, req).await;
+                }
+            };
+            let (full, schedule) = shared.buffer(path, buffer, layer.max_bytes);
+            if schedule {
+                let shared = shared.clone();
+                let mut inner = inner.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(layer.max_delay).await;
+                    if let Err(err) = shared.flush(&mut inner).await {
+                        shared.lock().failed.get_or_insert(err);
+                    }
+                });
+            }
+            if full {
+                shared.flush(&mut inner).await?;
+            }
+            Ok(Response::Done)
+        }
+        .boxed()
+    }
+}
+
+/// What's buffered for a file
+#[derive(Debug)]
+enum Buffer {
+    /// Bytes to append to the file
+    Append(Vec<u8>),
+    /// The whole new contents of the file
+    Write(Vec<u8>),
+}
+
+impl Buffer {
+    fn len(&self) -> usize {
+        match self {
+            Self::Append(bytes) | Self::Write(bytes) => bytes.len(),
+        }
+    }
+
+    fn into_request(self, path: Arc<Path>) -> Request {
+        match self {
+            Self::Append(bytes) => Request::AppendBytes { path, bytes },
+            Self::Write(bytes) => Request::WriteBytes { path, bytes },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    buffered: Mutex<Buffered>,
+    /// Held while flushing, so one flush's writes reach the inner service before the next's
+    flushing: tokio::sync::Mutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct Buffered {
+    files: HashMap<Arc<Path>, Buffer>,
+    len: usize,
+    /// Whether a timer will flush what's buffered
+    scheduled: bool,
+    /// The first error of a flush by the timer, for the next [`Request::Flush`]
+    failed: Option<io::Error>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Buffered> {
+        self.buffered.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Buffers `buffer` for `path`, returning whether `max_bytes` are now buffered and whether a
+    /// timer needs to be started
+    fn buffer(&self, path: Arc<Path>, buffer: Buffer, max_bytes: usize) -> (bool, bool) {
+        let mut buffered = self.lock();
+        buffered.len += buffer.len();
+        match (buffered.files.get_mut(&path), buffer) {
+            (Some(Buffer::Append(existing) | Buffer::Write(existing)), Buffer::Append(bytes)) => {
+                existing.extend_from_slice(&bytes);
+            }
+            (_, buffer) => {
+                if let Some(replaced) = buffered.files.insert(path, buffer) {
+                    buffered.len -= replaced.len();
+                }
+            }
+        }
+        let schedule = !std::mem::replace(&mut buffered.scheduled, true);
+        (buffered.len >= max_bytes, schedule)
+    }
+
+    /// Sends everything buffered to `inner`, returning the first error
+    async fn flush<S>(&self, inner: &mut S) -> io::Result<()>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let _flushing = self.flushing.lock().await;
+        let files = {
+            let mut buffered = self.lock();
+            buffered.len = 0;
+            buffered.scheduled = false;
+            std::mem::take(&mut buffered.files)
+        };
+        let mut result = Ok(());
+        for (path, buffer) in files {
+            let written = ready_call(inner, buffer.into_request(path)).await;
+            result = result.and(written.map(drop));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt};
+
+    #[tokio::test]
+    async fn test_coalesce() -> io::Result<()> {
+        let dir = TestDir::new("coalesce")?;
+        let log = dir.join("events.log");
+        let layer = CoalesceLayer::new()
+            .max_bytes(32)
+            .max_delay(Duration::from_hours(1));
+        let mut fs = layer.layer(FileSystem::new());
+
+        fs.append(&log, b"one\n".to_vec()).await?;
+        fs.append(&log, b"two\n".to_vec()).await?;
+        assert!(!log.exists());
+        // Other requests see what's buffered
+        assert_eq!(fs.read(&log).await?, b"one\ntwo\n");
+
+        // Writes replace what's buffered, and are joined with later appends
+        fs.append(&log, b"lost\n".to_vec()).await?;
+        fs.write(&log, b"three\n".to_vec()).await?;
+        fs.append(&log, b"four\n".to_vec()).await?;
+        assert_eq!(std::fs::read(&log)?, b"one\ntwo\n");
+        ready_call(&mut fs, Request::Flush).await?;
+        assert_eq!(std::fs::read(&log)?, b"three\nfour\n");
+
+        // Filling the buffer flushes it, and large writes aren't buffered
+        fs.append(&log, [b'a'; 20].to_vec()).await?;
+        fs.append(&log, [b'b'; 20].to_vec()).await?;
+        assert_eq!(std::fs::metadata(&log)?.len(), 51);
+        fs.append(&log, [b'c'; 40].to_vec()).await?;
+        assert_eq!(std::fs::metadata(&log)?.len(), 91);
+
+        // As does the timer, after the service is dropped
+        let mut fs = CoalesceLayer::new()
+            .max_delay(Duration::from_millis(20))
+            .layer(FileSystem::new());
+        fs.append(dir.join("timed.log"), b"timed".to_vec()).await?;
+        drop(fs);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read(dir.join("timed.log"))?, b"timed");
+        Ok(())
+    }
+}
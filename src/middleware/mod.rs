@@ -1 +1,7 @@
+pub mod coalesce;
+pub mod open_files;
+pub mod readahead;
 pub mod root;
+pub mod shutdown;
+pub mod slow_log;
+pub mod snapshot;
@@ -0,0 +1,314 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{ext::ready_call, Request, Response};
+
+/// How often held back opens check the process' descriptors again, as files closed outside the
+/// layer don't wake them
+const RECHECK: Duration = Duration::from_millis(50);
+
+/// Layers [`TrackOpenFiles`] over services, which all count their files in the same [`OpenFiles`]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Default)]
+pub struct OpenFilesLayer {
+    files: OpenFiles,
+    /// A permit for each file which may be open at once, as [`FileSystemBuilder::max_open_files`]
+    /// uses
+    ///
+    /// [`FileSystemBuilder::max_open_files`]: crate::local::FileSystemBuilder::max_open_files
+    max_open: Option<Arc<Semaphore>>,
+    reserve: Option<u64>,
+    shed: bool,
+}
+
+impl OpenFilesLayer {
+    /// A layer which counts open files, without holding back any opens
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Holds back opens while `max` of the files opened through the layer are still open
+    #[must_use]
+    pub fn max_open(mut self, max: usize) -> Self {
+        self.max_open = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Holds back opens while fewer than `reserve` descriptors are left below the process' soft
+    /// `RLIMIT_NOFILE`, counting every descriptor the process has open, not just the layer's files
+    ///
+    /// Only Linux is supported, where both are read from `/proc`, and the reserve is ignored
+    /// elsewhere.
+    #[must_use]
+    pub fn rlimit_reserve(mut self, reserve: u64) -> Self {
+        self.reserve = Some(reserve);
+        self
+    }
+
+    /// Fails opens which would be held back with [`ErrorKind::ResourceBusy`] rather than waiting
+    /// for files to close, shedding load instead of queueing it
+    #[must_use]
+    pub fn shed(mut self, shed: bool) -> Self {
+        self.shed = shed;
+        self
+    }
+
+    /// The count of the layer's open files
+    #[must_use]
+    pub fn open_files(&self) -> OpenFiles {
+        self.files.clone()
+    }
+
+    /// Waits for one of the files of [`OpenFilesLayer::max_open`] to be free, or fails if the
+    /// layer sheds load
+    async fn acquire(&self) -> io::Result<Option<OwnedSemaphorePermit>> {
+        let Some(max_open) = self.max_open.clone() else {
+            return Ok(None);
+        };
+        let permit = if self.shed {
+            max_open.try_acquire_owned().map_err(|_| busy())?
+        } else {
+            max_open
+                .acquire_owned()
+                .await
+                .map_err(|_| io::Error::other("open file limit closed"))?
+        };
+        Ok(Some(permit))
+    }
+
+    /// Whether the process has enough descriptors left for another open
+    fn descriptors_left(&self) -> bool {
+        match (self.reserve, Descriptors::read()) {
+            (Some(reserve), Some(descriptors)) => {
+                descriptors.limit.saturating_sub(descriptors.open) >= reserve
+            }
+            _ => true,
+        }
+    }
+}
+
+fn busy() -> io::Error {
+    io::Error::new(ErrorKind::ResourceBusy, "too many files are open")
+}
+
+impl<S: Service<Request>> Layer<S> for OpenFilesLayer {
+    type Service = TrackOpenFiles<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TrackOpenFiles {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Counts the files an inner service opens until they're dropped, holding back further opens when
+/// too many are open, so a busy server waits for files to close rather than failing with `EMFILE`
+///
+/// Opens are held back by the future of the request rather than by [`Service::poll_ready`], which
+/// can't tell whether the next request opens a file, and only [`Request::Open`] (and
+/// `Request::OpenStream` on Windows) are held back.  Requests which open files only while they're
+/// performed, such as [`Request::ReadBytes`], aren't counted.
+#[derive(Debug, Clone)]
+pub struct TrackOpenFiles<S> {
+    inner: S,
+    layer: OpenFilesLayer,
+}
+
+impl<S> Service<Request> for TrackOpenFiles<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        #[cfg(windows)]
+        let opens = matches!(req, Request::Open { .. } | Request::OpenStream { .. });
+        #[cfg(not(windows))]
+        let opens = matches!(req, Request::Open { .. });
+        if !opens {
+            return self.inner.call(req).boxed();
+        }
+        // Keep the service which was driven to readiness for this request
+        let inner = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, inner);
+        let layer = self.layer.clone();
+        async move {
+            let registry = layer.files.0.clone();
+            let permit = layer.acquire().await?;
+            while !layer.descriptors_left() {
+                if layer.shed {
+                    return Err(busy());
+                }
+                let _ = tokio::time::timeout(RECHECK, registry.closed.notified()).await;
+            }
+            let open = registry.open.fetch_add(1, Ordering::AcqRel) + 1;
+            registry.peak.fetch_max(open, Ordering::Relaxed);
+            let reserved = Reserved {
+                registry,
+                _permit: permit,
+            };
+            match ready_call(&mut inner, req).await? {
+                Response::File(file) => Ok(Response::File(file.hold(reserved))),
+                response => Ok(response),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// The files opened through the services of an [`OpenFilesLayer`] which are still open, as a gauge
+#[derive(Debug, Clone, Default)]
+pub struct OpenFiles(Arc<Registry>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    open: AtomicUsize,
+    peak: AtomicUsize,
+    closed: Notify,
+}
+
+impl OpenFiles {
+    /// The number of files open now
+    #[must_use]
+    pub fn open(&self) -> usize {
+        self.0.open.load(Ordering::Acquire)
+    }
+
+    /// The most files which have been open at once
+    #[must_use]
+    pub fn peak(&self) -> usize {
+        self.0.peak.load(Ordering::Relaxed)
+    }
+}
+
+/// Holds a file's place in the count (and it's permit, if the number of files is limited) until
+/// it's dropped, waking the opens held back for it
+struct Reserved {
+    registry: Arc<Registry>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        self.registry.open.fetch_sub(1, Ordering::AcqRel);
+        self.registry.closed.notify_waiters();
+    }
+}
+
+/// The descriptors the whole process has open, and how many it may have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Descriptors {
+    pub open: u64,
+    /// The soft `RLIMIT_NOFILE`, which is `u64::MAX` when unlimited
+    pub limit: u64,
+}
+
+impl Descriptors {
+    /// Reads the process' descriptors from `/proc`, or `None` if it can't be read or on platforms
+    /// other than Linux
+    #[must_use]
+    pub fn read() -> Option<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+            let limit = limits.lines().find_map(|line| {
+                let soft = line
+                    .strip_prefix("Max open files")?
+                    .split_whitespace()
+                    .next()?;
+                match soft {
+                    "unlimited" => Some(u64::MAX),
+                    soft => soft.parse().ok(),
+                }
+            })?;
+            // Less the descriptor reading the directory
+            let open = std::fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+            Some(Self {
+                open: open.saturating_sub(1),
+                limit,
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt, Mode};
+
+    #[tokio::test]
+    async fn test_open_files() -> io::Result<()> {
+        let dir = TestDir::new("open_files")?;
+        let path = dir.join("file.txt");
+        std::fs::write(&path, "contents")?;
+        let layer = OpenFilesLayer::new().max_open(1);
+        let files = layer.open_files();
+        let mut fs = layer.layer(FileSystem::new());
+
+        let first = fs.open(&path, Mode::Read).await?;
+        assert_eq!(files.open(), 1);
+        // Other requests carry on, while opens wait for the file to close
+        assert_eq!(fs.read(&path).await?, b"contents");
+        let mut waiting = fs.clone();
+        let second = {
+            let path = path.clone();
+            tokio::spawn(async move { waiting.open(path, Mode::Read).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+        drop(first);
+        let second = second.await.map_err(io::Error::other)??;
+        assert_eq!((files.open(), files.peak()), (1, 1));
+
+        let mut shedding = layer.clone().shed(true).layer(FileSystem::new());
+        assert!(shedding
+            .open(&path, Mode::Read)
+            .await
+            .is_err_and(|err| err.kind() == ErrorKind::ResourceBusy));
+        drop(second);
+        assert_eq!(files.open(), 0);
+        drop(shedding.open(&path, Mode::Read).await?);
+
+        // Failed opens give their place back
+        assert!(fs.open(dir.join("missing"), Mode::Read).await.is_err());
+        assert_eq!(files.open(), 0);
+
+        #[cfg(target_os = "linux")]
+        {
+            let descriptors = Descriptors::read().ok_or(ErrorKind::NotFound)?;
+            assert!(descriptors.open > 0 && descriptors.limit >= descriptors.open);
+            let mut starved = OpenFilesLayer::new()
+                .rlimit_reserve(u64::MAX)
+                .shed(true)
+                .layer(FileSystem::new());
+            assert!(starved
+                .open(&path, Mode::Read)
+                .await
+                .is_err_and(|err| err.kind() == ErrorKind::ResourceBusy));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{Mode, Request, Response};
+
+/// Layers [`Readahead`] over services, counting what all of them prefetch
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone)]
+pub struct ReadaheadLayer {
+    window: u64,
+    prefetched: Arc<AtomicU64>,
+}
+
+impl Default for ReadaheadLayer {
+    fn default() -> Self {
+        Self::new(8 * 1024 * 1024)
+    }
+}
+
+impl ReadaheadLayer {
+    /// Prefetches up to the first `window` bytes of each file, which defaults to 8 MiB
+    #[must_use]
+    pub fn new(window: u64) -> Self {
+        Self {
+            window,
+            prefetched: Arc::default(),
+        }
+    }
+
+    /// The number of bytes prefetched by the services of this layer so far
+    #[must_use]
+    pub fn prefetched(&self) -> u64 {
+        self.prefetched.load(Ordering::Relaxed)
+    }
+}
+
+impl<S: Service<Request>> Layer<S> for ReadaheadLayer {
+    type Service = Readahead<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Readahead {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Warms the page cache for local files opened with [`Mode::Read`], so streaming them from a cold
+/// cache doesn't wait on the disk for each chunk
+///
+/// The start of each file is read in the background, on the blocking thread pool, at offsets of
+/// it's own which leave the returned handle's position alone.  Prefetching stops when the handle
+/// is dropped.  Pages aren't dropped from the cache afterwards, as `posix_fadvise` has no safe
+/// binding, and files from other backends, or on platforms other than unix, are returned as they
+/// are.
+#[derive(Debug, Clone)]
+pub struct Readahead<S> {
+    inner: S,
+    layer: ReadaheadLayer,
+}
+
+impl<S> Service<Request> for Readahead<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let prefetch = matches!(
+            req,
+            Request::Open {
+                mode: Mode::Read,
+                ..
+            }
+        );
+        let response = self.inner.call(req);
+        let layer = self.layer.clone();
+        async move {
+            match response.await? {
+                Response::File(file) if prefetch && layer.window > 0 => {
+                    let stop = Arc::new(AtomicBool::new(false));
+                    #[cfg(unix)]
+                    if let Some(local) = file.try_clone_local().await? {
+                        let stop = stop.clone();
+                        tokio::task::spawn_blocking(move || prefetch_local(&local, &layer, &stop));
+                    }
+                    Ok(Response::File(file.hold(Stop(stop))))
+                }
+                response => Ok(response),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Stops the prefetching of a file once it's handle is dropped
+struct Stop(Arc<AtomicBool>);
+
+impl Drop for Stop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Reads up to the first `window` bytes of `file`, a buffer at a time, until it ends or `stop` is
+/// set.  Failures only end the prefetching, as the reader will meet them too
+#[cfg(unix)]
+fn prefetch_local(file: &std::fs::File, layer: &ReadaheadLayer, stop: &AtomicBool) {
+    use std::os::unix::fs::FileExt;
+
+    let mut buffer = crate::BufferPool::shared().get();
+    let mut offset = 0;
+    while offset < layer.window && !stop.load(Ordering::Relaxed) {
+        let len = buffer
+            .len()
+            .min(usize::try_from(layer.window - offset).unwrap_or(usize::MAX));
+        match file.read_at(&mut buffer[..len], offset) {
+            Ok(0) => return,
+            Ok(read) => {
+                offset += read as u64;
+                layer.prefetched.fetch_add(read as u64, Ordering::Relaxed);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt};
+
+    #[tokio::test]
+    async fn test_readahead() -> io::Result<()> {
+        let dir = TestDir::new("readahead")?;
+        let path = dir.join("large.bin");
+        let contents = (0..=u8::MAX).cycle().take(200_000).collect::<Vec<_>>();
+        std::fs::write(&path, &contents)?;
+        let layer = ReadaheadLayer::new(150_000);
+        let mut fs = layer.layer(FileSystem::new());
+
+        let mut file = fs.open(&path, Mode::Read).await?;
+        let mut start = [0; 10];
+        file.read_exact(&mut start).await?;
+        // Prefetching doesn't move the handle, and stops at the window
+        #[cfg(unix)]
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while layer.prefetched() < 150_000 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "nothing prefetched"))?;
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).await?;
+        assert_eq!([&start[..], &rest].concat(), contents);
+        assert_eq!(layer.prefetched(), if cfg!(unix) { 150_000 } else { 0 });
+
+        // Writes aren't prefetched
+        drop(fs.open(&path, Mode::CreateOrAppend).await?);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(layer.prefetched(), if cfg!(unix) { 150_000 } else { 0 });
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     io::ErrorKind,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use futures::{
@@ -50,6 +51,13 @@ where
     }
 
     fn call(&mut self, req: Request) -> Self::Future {
+        if let Request::MetadataBatch {
+            paths,
+            follow_symlinks,
+        } = req
+        {
+            return self.call_metadata_batch(&paths, follow_symlinks);
+        }
         match req.adjust_paths(&self.root) {
             Some(req) => self.inner.call(req).boxed(),
             None => ready(Err(ErrorKind::NotFound.into())).boxed(),
@@ -57,6 +65,46 @@ where
     }
 }
 
+impl<S> Root<S>
+where
+    S: Service<Request, Error = std::io::Error, Response = Response>,
+    S::Future: 'static + Send,
+{
+    /// Gets the metadata of the paths inside the root, failing each of the others with
+    /// [`ErrorKind::NotFound`] rather than the whole batch
+    fn call_metadata_batch(
+        &mut self,
+        paths: &[Arc<Path>],
+        follow_symlinks: bool,
+    ) -> BoxFuture<'static, std::io::Result<Response>> {
+        let relative: Vec<Option<Arc<Path>>> = paths
+            .iter()
+            .map(|path| make_relative(&self.root, path).map(Into::into))
+            .collect();
+        let response = self.inner.call(Request::MetadataBatch {
+            paths: relative.iter().flatten().cloned().collect(),
+            follow_symlinks,
+        });
+        async move {
+            let mut results = response.await?.into_metadata_batch()?.into_iter();
+            let results = relative
+                .iter()
+                .map(|path| match path {
+                    Some(_) => results.next().unwrap_or_else(|| {
+                        Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "too few results from inner service",
+                        ))
+                    }),
+                    None => Err(ErrorKind::NotFound.into()),
+                })
+                .collect();
+            Ok(Response::MetadataBatch(results))
+        }
+        .boxed()
+    }
+}
+
 impl<S: Service<Request>> Layer<S> for RootLayer {
     type Service = Root<S>;
 
@@ -68,68 +116,188 @@ impl<S: Service<Request>> Layer<S> for RootLayer {
     }
 }
 
+/// `subpath` resolved inside `root`, or `None` if it leads out of it
+///
+/// Paths which don't exist yet, such as those being created, are resolved through their nearest
+/// existing ancestor.
 fn make_relative(root: &Path, subpath: &Path) -> Option<PathBuf> {
-    root.join(subpath.strip_prefix("/").unwrap_or(subpath))
-        .canonicalize()
-        .ok()
-        .filter(|path| path.starts_with(root))
+    let path = root.join(subpath.strip_prefix("/").unwrap_or(subpath));
+    let mut missing = Vec::new();
+    let mut existing = path.as_path();
+    let resolved = loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            break resolved;
+        }
+        missing.push(existing.file_name()?);
+        existing = existing.parent()?;
+    };
+    let path = missing
+        .iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+    path.starts_with(root).then_some(path)
+}
+
+/// `subpath` resolved inside `root` like [`make_relative`], but without following a link at it's
+/// last component, for the requests which act on links themselves
+fn make_relative_link(root: &Path, subpath: &Path) -> Option<PathBuf> {
+    match (subpath.parent(), subpath.file_name()) {
+        (Some(parent), Some(name)) => Some(make_relative(root, parent)?.join(name)),
+        _ => make_relative(root, subpath),
+    }
 }
 
 impl crate::Request {
+    // One arm per request
+    #[allow(clippy::too_many_lines)]
     fn adjust_paths(self, root: &Path) -> Option<Self> {
         Some(match self {
-            Self::Copy { from, to } => Self::Copy {
-                from: make_relative(root, &from)?,
-                to: make_relative(root, &to)?,
+            #[cfg(feature = "archive")]
+            Self::Archive {
+                src_dir,
+                dst,
+                format,
+                compression,
+                filter,
+            } => Self::Archive {
+                src_dir: make_relative(root, &src_dir)?.into(),
+                dst: make_relative(root, &dst)?.into(),
+                format,
+                compression,
+                filter,
+            },
+            #[cfg(feature = "archive")]
+            Self::Extract {
+                archive,
+                dst_dir,
+                options,
+            } => Self::Extract {
+                archive: make_relative(root, &archive)?.into(),
+                dst_dir: make_relative(root, &dst_dir)?.into(),
+                options,
+            },
+            #[cfg(feature = "dedup")]
+            Self::FindDuplicates { dir, options } => Self::FindDuplicates {
+                dir: make_relative(root, &dir)?.into(),
+                options,
+            },
+            #[cfg(feature = "posix-acl")]
+            Self::GetPosixAcl(path) => Self::GetPosixAcl(make_relative(root, &path)?.into()),
+            #[cfg(feature = "posix-acl")]
+            Self::SetPosixAcl { path, acl } => Self::SetPosixAcl {
+                path: make_relative(root, &path)?.into(),
+                acl,
+            },
+            Self::Compact => Self::Compact,
+            Self::Flush => Self::Flush,
+            Self::Copy { from, to, options } => Self::Copy {
+                from: make_relative(root, &from)?.into(),
+                to: make_relative(root, &to)?.into(),
+                options,
+            },
+            Self::CopyDir { from, to, options } => Self::CopyDir {
+                from: make_relative(root, &from)?.into(),
+                to: make_relative(root, &to)?.into(),
+                options,
             },
             Self::CreateDir { path, recursive } => Self::CreateDir {
-                path: make_relative(root, &path)?,
+                path: make_relative(root, &path)?.into(),
                 recursive,
             },
-            Self::Exists(path) => Self::Exists(make_relative(root, &path)?),
-            Self::FollowLink(path) => Self::FollowLink(make_relative(root, &path)?),
+            Self::Exists(path) => Self::Exists(make_relative(root, &path)?.into()),
+            Self::FollowLink(path) => Self::FollowLink(make_relative_link(root, &path)?.into()),
             Self::GetMetadata {
                 path,
                 follow_symlinks,
             } => Self::GetMetadata {
-                path: make_relative(root, &path)?,
+                path: if follow_symlinks {
+                    make_relative(root, &path)?
+                } else {
+                    make_relative_link(root, &path)?
+                }
+                .into(),
                 follow_symlinks,
             },
+            // Adjusted path by path by `Root::call_metadata_batch`, so one path outside the root
+            // doesn't fail the others
+            Self::MetadataBatch { .. } => return None,
             Self::HardLink { src, dst } => Self::HardLink {
-                src: make_relative(root, &src)?,
-                dst: make_relative(root, &dst)?,
+                src: make_relative(root, &src)?.into(),
+                dst: make_relative_link(root, &dst)?.into(),
             },
             Self::Open { mode, path } => Self::Open {
                 mode,
-                path: make_relative(root, &path)?,
+                path: make_relative(root, &path)?.into(),
+            },
+            Self::ReadDir(path) => Self::ReadDir(make_relative(root, &path)?.into()),
+            Self::ReadBytes(path) => Self::ReadBytes(make_relative(root, &path)?.into()),
+            Self::ReadRange { path, range } => Self::ReadRange {
+                path: make_relative(root, &path)?.into(),
+                range,
             },
             Self::RemoveDir { path, recursive } => Self::RemoveDir {
-                path: make_relative(root, &path)?,
+                path: make_relative(root, &path)?.into(),
                 recursive,
             },
-            Self::RemoveFile(path) => Self::RemoveFile(make_relative(root, &path)?),
+            Self::RemoveFile(path) => Self::RemoveFile(make_relative_link(root, &path)?.into()),
             Self::Rename { from, to } => Self::Rename {
-                from: make_relative(root, &from)?,
-                to: make_relative(root, &to)?,
+                from: make_relative_link(root, &from)?.into(),
+                to: make_relative_link(root, &to)?.into(),
             },
             Self::SetPermissions { path, perm } => Self::SetPermissions {
-                path: make_relative(root, &path)?,
+                path: make_relative(root, &path)?.into(),
                 perm,
             },
+            #[cfg(unix)]
+            Self::GetAttributes(path) => Self::GetAttributes(make_relative(root, &path)?.into()),
+            #[cfg(unix)]
+            Self::SetAttributes { path, set, clear } => Self::SetAttributes {
+                path: make_relative(root, &path)?.into(),
+                set,
+                clear,
+            },
+            Self::WriteBytes { path, bytes } => Self::WriteBytes {
+                path: make_relative(root, &path)?.into(),
+                bytes,
+            },
+            Self::AppendBytes { path, bytes } => Self::AppendBytes {
+                path: make_relative(root, &path)?.into(),
+                bytes,
+            },
             #[cfg(windows)]
             Self::SymlinkDir { src, dst } => Self::SymlinkDir {
-                src: make_relative(root, &src)?,
-                dst: make_relative(root, &dst)?,
+                src,
+                dst: make_relative_link(root, &dst)?.into(),
             },
             #[cfg(windows)]
             Self::SymlinkFile { src, dst } => Self::SymlinkFile {
-                src: make_relative(root, &src)?,
-                dst: make_relative(root, &dst)?,
+                src,
+                dst: make_relative_link(root, &dst)?.into(),
+            },
+            #[cfg(windows)]
+            Self::ReadJunction(path) => Self::ReadJunction(make_relative_link(root, &path)?.into()),
+            #[cfg(windows)]
+            Self::OpenStream { mode, path, stream } => Self::OpenStream {
+                mode,
+                path: make_relative(root, &path)?.into(),
+                stream,
+            },
+            #[cfg(windows)]
+            Self::GetFileAttributes(path) => {
+                Self::GetFileAttributes(make_relative(root, &path)?.into())
+            }
+            #[cfg(windows)]
+            Self::SetFileAttributes { path, set, clear } => Self::SetFileAttributes {
+                path: make_relative(root, &path)?.into(),
+                set,
+                clear,
             },
             #[cfg(unix)]
+            // Targets are left as they are, so they're resolved relative to the link like on the
+            // real file system, and reads through links leading out of the root are refused
             Self::Symlink { src, dst } => Self::Symlink {
-                src: make_relative(root, &src)?,
-                dst: make_relative(root, &dst)?,
+                src,
+                dst: make_relative_link(root, &dst)?.into(),
             },
         })
     }
@@ -138,12 +306,44 @@ impl crate::Request {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_kit::TestDir;
 
     #[test]
-    fn test_relative() {
+    fn test_relative() -> std::io::Result<()> {
         assert_eq!(
             make_relative("".as_ref(), "/src".as_ref()),
             std::fs::canonicalize("src").ok()
         );
+        // Missing paths are resolved through the ancestors which exist
+        let root = std::fs::canonicalize("src")?;
+        assert_eq!(
+            make_relative(&root, "/new/file.rs".as_ref()),
+            Some(root.join("new/file.rs"))
+        );
+        assert_eq!(make_relative(&root, "/new/../../escape".as_ref()), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata_batch() -> std::io::Result<()> {
+        use crate::ext::FileSystemExt;
+
+        let dir = TestDir::new("root_batch")?;
+        std::fs::write(dir.join("a.txt"), "a")?;
+        std::fs::write(dir.join("b.txt"), "bb")?;
+        let mut service = RootLayer::new(&dir)?.layer(crate::FileSystem::new());
+
+        let paths = ["/a.txt", "/missing.txt", "/b.txt", "/../outside.txt"];
+        let results = service.metadata_batch(paths.map(Path::new)).await?;
+        let lens: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().map(crate::Metadata::len).ok())
+            .collect();
+        assert_eq!(lens, [Some(1), None, Some(2), None]);
+        assert_eq!(
+            results[1].as_ref().err().map(std::io::Error::kind),
+            Some(ErrorKind::NotFound)
+        );
+        Ok(())
     }
 }
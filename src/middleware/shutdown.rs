@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use futures::{
+    future::{ready, AbortHandle, AbortRegistration, Abortable, BoxFuture},
+    FutureExt,
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{Request, Response};
+
+/// Layers [`Shutdown`] over services, which all stop when any [`ShutdownHandle`] of the layer
+/// shuts them down
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownLayer {
+    state: Arc<State>,
+}
+
+impl ShutdownLayer {
+    /// A layer whose services haven't been shut down
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle to shut down the services of this layer
+    #[must_use]
+    pub fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S: Service<Request>> Layer<S> for ShutdownLayer {
+    type Service = Shutdown<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Shutdown {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// Tracks the requests in flight through an inner service, and the files it opened, so they can be
+/// drained before a restart
+///
+/// Once shut down, [`Service::poll_ready`] and [`Service::call`] fail with
+/// [`ErrorKind::ConnectionAborted`].
+#[derive(Debug, Clone)]
+pub struct Shutdown<S> {
+    inner: S,
+    state: Arc<State>,
+}
+
+impl<S> Service<Request> for Shutdown<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.state.lock().shut_down {
+            return Poll::Ready(Err(shut_down()));
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let Some((guard, registration)) = self.state.start_request() else {
+            return ready(Err(shut_down())).boxed();
+        };
+        let response = Abortable::new(self.inner.call(req), registration);
+        async move {
+            let response = response.await.map_err(|_| {
+                io::Error::new(ErrorKind::Interrupted, "request aborted by shutdown")
+            })??;
+            // The file is counted before the request finishes, so the service is never drained
+            // in between
+            Ok(match response {
+                Response::File(file) => Response::File(file.hold(guard.state.open_file())),
+                response => response,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Shuts down the services of a [`ShutdownLayer`]
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    state: Arc<State>,
+}
+
+impl ShutdownHandle {
+    /// Stops the services accepting requests, then waits up to `timeout` for the requests in
+    /// flight to finish and the files they opened to be dropped
+    ///
+    /// Requests still in flight after the timeout are aborted, failing with
+    /// [`ErrorKind::Interrupted`].  Open files are left to their owners, which should be shut down
+    /// too.  The timeout uses tokio's timer, so the runtime must have time enabled.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        self.state.lock().shut_down = true;
+        let drained = poll_fn(|cx| {
+            let mut tracked = self.state.lock();
+            if tracked.requests.is_empty() && tracked.files == 0 {
+                return Poll::Ready(());
+            }
+            tracked.waiting.retain(|waker| !waker.will_wake(cx.waker()));
+            tracked.waiting.push(cx.waker().clone());
+            Poll::Pending
+        });
+        if tokio::time::timeout(timeout, drained).await.is_ok() {
+            return ShutdownReport::default();
+        }
+        let tracked = self.state.lock();
+        for abort in tracked.requests.values() {
+            abort.abort();
+        }
+        ShutdownReport {
+            aborted_requests: tracked.requests.len(),
+            open_files: tracked.files,
+        }
+    }
+
+    /// Whether the services have been shut down, though they may still be draining
+    #[must_use]
+    pub fn is_shut_down(&self) -> bool {
+        self.state.lock().shut_down
+    }
+}
+
+/// What was left over when [`ShutdownHandle::shutdown`] timed out
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ShutdownReport {
+    /// The requests which were aborted
+    pub aborted_requests: usize,
+    /// The files which were still open
+    pub open_files: usize,
+}
+
+impl ShutdownReport {
+    /// Whether everything finished before the timeout
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Default)]
+struct State(Mutex<Tracked>);
+
+#[derive(Debug, Default)]
+struct Tracked {
+    shut_down: bool,
+    next_id: u64,
+    requests: HashMap<u64, AbortHandle>,
+    files: usize,
+    /// The shutdowns waiting for the service to drain
+    waiting: Vec<Waker>,
+}
+
+impl State {
+    fn lock(&self) -> MutexGuard<'_, Tracked> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Tracks a new request, unless the service has been shut down
+    fn start_request(self: &Arc<Self>) -> Option<(RequestGuard, AbortRegistration)> {
+        let mut tracked = self.lock();
+        if tracked.shut_down {
+            return None;
+        }
+        let (abort, registration) = AbortHandle::new_pair();
+        let id = tracked.next_id;
+        tracked.next_id += 1;
+        tracked.requests.insert(id, abort);
+        let guard = RequestGuard {
+            state: self.clone(),
+            id,
+        };
+        Some((guard, registration))
+    }
+
+    fn open_file(self: &Arc<Self>) -> FileGuard {
+        self.lock().files += 1;
+        FileGuard(self.clone())
+    }
+}
+
+impl Tracked {
+    fn wake_if_drained(&mut self) {
+        if self.requests.is_empty() && self.files == 0 {
+            self.waiting.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// Tracks a request until it finishes
+struct RequestGuard {
+    state: Arc<State>,
+    id: u64,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let mut tracked = self.state.lock();
+        tracked.requests.remove(&self.id);
+        tracked.wake_if_drained();
+    }
+}
+
+/// Tracks a file until it's dropped
+struct FileGuard(Arc<State>);
+
+impl Drop for FileGuard {
+    fn drop(&mut self) {
+        let mut tracked = self.0.lock();
+        tracked.files -= 1;
+        tracked.wake_if_drained();
+    }
+}
+
+fn shut_down() -> io::Error {
+    io::Error::new(
+        ErrorKind::ConnectionAborted,
+        "the service has been shut down",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::pending, path::Path};
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt, Mode};
+
+    /// Never answers reads, and performs other requests on the local file system
+    #[derive(Debug, Clone)]
+    struct Stalled;
+
+    impl Service<Request> for Stalled {
+        type Response = Response;
+        type Error = io::Error;
+        type Future = BoxFuture<'static, io::Result<Response>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request) -> Self::Future {
+            match req {
+                Request::ReadBytes(_) => pending().boxed(),
+                req => FileSystem::new().call(req).boxed(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> io::Result<()> {
+        let dir = TestDir::new("shutdown")?;
+        let path = dir.join("draining.txt");
+        let layer = ShutdownLayer::new();
+        let handle = layer.handle();
+        let mut service = layer.layer(Stalled);
+
+        service.write(&path, b"draining".to_vec()).await?;
+        let Response::File(file) = service
+            .call(Request::Open {
+                mode: Mode::Read,
+                path: Path::new(&path).into(),
+            })
+            .await?
+        else {
+            unreachable!("Open always responds with a file")
+        };
+        let stalled = tokio::spawn(service.call(Request::ReadBytes(Path::new(&path).into())));
+
+        let report = handle.shutdown(Duration::from_millis(10)).await;
+        assert_eq!(
+            report,
+            ShutdownReport {
+                aborted_requests: 1,
+                open_files: 1
+            }
+        );
+        assert_eq!(
+            stalled
+                .await
+                .map_err(io::Error::other)?
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::Interrupted)
+        );
+        assert_eq!(
+            service.exists(&path).await.map_err(|err| err.kind()),
+            Err(ErrorKind::ConnectionAborted)
+        );
+
+        drop(file);
+        assert!(handle.shutdown(Duration::from_millis(10)).await.is_clean());
+        assert!(handle.is_shut_down());
+        Ok(())
+    }
+}
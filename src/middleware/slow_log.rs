@@ -0,0 +1,246 @@
+use std::{
+    fmt, io,
+    path::Path,
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{channel::mpsc, future::BoxFuture, FutureExt};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{Request, Response};
+
+/// Layers [`SlowLog`] over services, reporting requests slower than a threshold
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone)]
+pub struct SlowLogLayer {
+    threshold: Duration,
+    report: Arc<dyn Fn(SlowRequest) + Send + Sync>,
+}
+
+impl SlowLogLayer {
+    /// Passes requests taking `threshold` or longer to `report`, such as to log them with the
+    /// service's own logger.  `report` is called on the task awaiting the request, so it shouldn't
+    /// block
+    #[must_use]
+    pub fn new<F>(threshold: Duration, report: F) -> Self
+    where
+        F: Fn(SlowRequest) + Send + Sync + 'static,
+    {
+        Self {
+            threshold,
+            report: Arc::new(report),
+        }
+    }
+
+    /// Sends requests taking `threshold` or longer to the returned receiver, for services which
+    /// handle them on a task of their own.  Reports are dropped once the receiver is
+    #[must_use]
+    pub fn events(threshold: Duration) -> (Self, mpsc::UnboundedReceiver<SlowRequest>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let layer = Self::new(threshold, move |request| {
+            let _ = sender.unbounded_send(request);
+        });
+        (layer, receiver)
+    }
+}
+
+impl fmt::Debug for SlowLogLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlowLogLayer")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Service<Request>> Layer<S> for SlowLogLayer {
+    type Service = SlowLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SlowLog {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Reports requests to an inner service which take longer than a threshold, to find the
+/// directories and disks holding a service up
+///
+/// A request is reported once it's been running for the threshold, so requests which never finish
+/// are still seen, and again when it finishes, with how long it took and whether it failed.  Only
+/// the request itself is timed, not the reads and writes of the files it opens.
+#[derive(Debug, Clone)]
+pub struct SlowLog<S> {
+    inner: S,
+    layer: SlowLogLayer,
+}
+
+impl<S> Service<Request> for SlowLog<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let started = Instant::now();
+        let variant = req.variant();
+        let paths: Vec<Arc<Path>> = req.shared_paths().into_iter().cloned().collect();
+        let response = self.inner.call(req);
+        let layer = self.layer.clone();
+        async move {
+            let report = |outcome| SlowRequest {
+                variant,
+                paths: paths.clone(),
+                elapsed: started.elapsed(),
+                outcome,
+            };
+            let mut response = pin!(response);
+            let result =
+                if let Ok(result) = tokio::time::timeout(layer.threshold, &mut response).await {
+                    result
+                } else {
+                    (layer.report)(report(Outcome::Running));
+                    response.await
+                };
+            if started.elapsed() >= layer.threshold {
+                (layer.report)(report(match &result {
+                    Ok(_) => Outcome::Succeeded,
+                    Err(err) => Outcome::Failed(err.kind()),
+                }));
+            }
+            result
+        }
+        .boxed()
+    }
+}
+
+/// A request which took longer than the threshold of a [`SlowLogLayer`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SlowRequest {
+    /// The [variant](Request::variant) of the request
+    pub variant: &'static str,
+    /// The [paths](Request::paths) the request acts on
+    pub paths: Vec<Arc<Path>>,
+    /// How long the request had taken when it was reported
+    pub elapsed: Duration,
+    pub outcome: Outcome,
+}
+
+/// How a [`SlowRequest`] had got on when it was reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// The request reached the threshold, and hasn't finished yet
+    Running,
+    Succeeded,
+    Failed(io::ErrorKind),
+}
+
+impl fmt::Display for SlowRequest {
+    /// Formats the request for logs, such as `slow ReadBytes of "a.txt" took 2.1s and failed:
+    /// not found`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slow {}", self.variant)?;
+        for (i, path) in self.paths.iter().enumerate() {
+            let preposition = if i == 0 { "of" } else { "to" };
+            write!(f, " {preposition} \"{}\"", path.display())?;
+        }
+        match self.outcome {
+            Outcome::Running => write!(f, " still running after {:?}", self.elapsed),
+            Outcome::Succeeded => write!(f, " took {:?}", self.elapsed),
+            Outcome::Failed(kind) => write!(f, " took {:?} and failed: {kind}", self.elapsed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, path::Path, pin::Pin};
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{FileSystem, FileSystemExt};
+
+    /// Takes as long as the path's file name says, in milliseconds
+    #[derive(Clone)]
+    struct Sleepy;
+
+    impl Service<Request> for Sleepy {
+        type Response = Response;
+        type Error = io::Error;
+        type Future = Pin<Box<dyn Future<Output = io::Result<Response>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request) -> Self::Future {
+            let millis = req.paths()[0]
+                .file_name()
+                .and_then(|name| name.to_str()?.parse().ok())
+                .unwrap_or(0);
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+                Err(io::ErrorKind::NotFound.into())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_log() -> io::Result<()> {
+        let (layer, events) = SlowLogLayer::events(Duration::from_millis(50));
+        let mut fs = layer.layer(Sleepy);
+        assert!(fs.read("fast/0").await.is_err());
+        assert!(fs.read("slow/100").await.is_err());
+        drop(fs);
+        drop(layer);
+
+        let events = events.collect::<Vec<_>>().await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].outcome, Outcome::Running);
+        assert_eq!(events[1].outcome, Outcome::Failed(io::ErrorKind::NotFound));
+        assert_eq!(events[1].paths, [Path::new("slow/100").into()]);
+        assert!(events[1].elapsed >= Duration::from_millis(100));
+        assert!(events[1]
+            .to_string()
+            .starts_with(r#"slow ReadBytes of "slow/100" took "#));
+
+        // Fast requests aren't reported
+        let (layer, events) = SlowLogLayer::events(Duration::from_mins(1));
+        let mut fs = layer.layer(FileSystem::new());
+        assert!(fs.exists(std::env::temp_dir()).await?);
+        drop((fs, layer));
+        assert!(events.collect::<Vec<_>>().await.is_empty());
+
+        // Or passed to the caller's own function
+        let logged = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let layer = SlowLogLayer::new(Duration::from_millis(10), {
+            let logged = logged.clone();
+            move |request: SlowRequest| {
+                if let Ok(mut logged) = logged.lock() {
+                    logged.push(request.to_string());
+                }
+            }
+        });
+        assert!(layer.layer(Sleepy).read("logged/20").await.is_err());
+        let logged = logged
+            .lock()
+            .map(|logged| logged.clone())
+            .unwrap_or_default();
+        assert!(logged
+            .last()
+            .is_some_and(|line| line.starts_with(r#"slow ReadBytes of "logged/20" took "#)));
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    io::{self, Cursor, ErrorKind},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tower_service::Service;
+
+use crate::{FileHandle, FileType, Metadata, Mode, Request, Response};
+
+/// A read-only view of the files in an inner service, as they were when the view was captured
+///
+/// [`Snapshot::capture`] records each of the given roots, walking directories with
+/// [`Request::ReadDir`] to record everything below them, and keeps the metadata and contents of
+/// every file in memory, so the view keeps serving them whatever happens to the inner service.
+/// [`Snapshot::capture_checked`] only records metadata, and reads are served by the inner service
+/// after checking the file's length and modification time are unchanged, failing if it has
+/// changed since.  Files on backends without [`Request::GetMetadata`] support are always pinned.
+///
+/// Paths are matched exactly as they were recorded, joined onto the roots given, without
+/// normalization.  Symlinks are recorded as links, along with what they point to, but directories
+/// are only walked through their own paths, so listing a directory through a link fails with
+/// [`ErrorKind::Unsupported`].  Requests which would change the view fail with
+/// [`ErrorKind::Unsupported`].
+#[derive(Debug, Clone)]
+pub struct Snapshot<S> {
+    inner: S,
+    entries: Arc<HashMap<PathBuf, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// The metadata of the path itself, without following a link at it
+    metadata: Metadata,
+    /// The target of a link, and the metadata of what it points to unless it's dangling
+    link: Option<(PathBuf, Option<Metadata>)>,
+    contents: Contents,
+}
+
+#[derive(Debug)]
+enum Contents {
+    /// The names and metadata of the entries of a directory
+    Directory(Vec<(PathBuf, Metadata)>),
+    Pinned(Arc<[u8]>),
+    /// A file read from the inner service, once it's checked to be unchanged
+    Checked,
+    /// Anything else, such as a device, a dangling link or a link to a directory
+    None,
+}
+
+impl<S> Snapshot<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    /// Records `roots` in `inner`, along with everything below them, keeping the contents of their
+    /// files in memory
+    ///
+    /// # Errors
+    ///
+    /// If any of `roots`, or anything below them, can't be read from `inner`
+    pub async fn capture<I, P>(inner: S, roots: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self::capture_with(inner, roots, true).await
+    }
+
+    /// Records `roots` in `inner`, along with everything below them, keeping only the metadata of
+    /// their files, which are read from `inner` as long as they're unchanged
+    ///
+    /// # Errors
+    ///
+    /// If any of `roots`, or anything below them, can't be read from `inner`
+    pub async fn capture_checked<I, P>(inner: S, roots: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self::capture_with(inner, roots, false).await
+    }
+
+    async fn capture_with<I, P>(mut inner: S, roots: I, pin_contents: bool) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut entries = HashMap::new();
+        for root in roots {
+            let mut pending = vec![(root.as_ref().to_owned(), None)];
+            while let Some((path, metadata)) = pending.pop() {
+                let entry = capture(&mut inner, &path, metadata, pin_contents).await?;
+                if let Contents::Directory(listing) = &entry.contents {
+                    pending.extend(
+                        listing
+                            .iter()
+                            .map(|(name, metadata)| (path.join(name), Some(metadata.clone()))),
+                    );
+                }
+                entries.insert(path, entry);
+            }
+        }
+        Ok(Self {
+            inner,
+            entries: Arc::new(entries),
+        })
+    }
+}
+
+impl<S> Service<Request> for Snapshot<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Keep the service which was driven to readiness for this request
+        let inner = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, inner);
+        let entries = self.entries.clone();
+        async move {
+            match req {
+                Request::Compact | Request::Flush => Ok(Response::Done),
+                Request::Exists(path) => Ok(Response::Exists(entries.contains_key(&*path))),
+                Request::GetMetadata {
+                    path,
+                    follow_symlinks,
+                } => metadata(&entries, &path, follow_symlinks).map(Response::Metadata),
+                Request::MetadataBatch {
+                    paths,
+                    follow_symlinks,
+                } => Ok(Response::MetadataBatch(
+                    paths
+                        .iter()
+                        .map(|path| metadata(&entries, path, follow_symlinks))
+                        .collect(),
+                )),
+                Request::FollowLink(path) => match &get(&entries, &path)?.link {
+                    Some((target, _)) => Ok(Response::PointsTo(target.clone())),
+                    None => Err(ErrorKind::InvalidInput.into()),
+                },
+                Request::ReadDir(path) => match &get(&entries, &path)?.contents {
+                    Contents::Directory(listing) => Ok(Response::Directory(listing.clone())),
+                    _ if metadata(&entries, &path, true)?.is_dir() => {
+                        Err(ErrorKind::Unsupported.into())
+                    }
+                    _ => Err(ErrorKind::NotADirectory.into()),
+                },
+                Request::ReadBytes(path) => read(&mut inner, &entries, path, None).await,
+                Request::ReadRange { path, range } => {
+                    read(&mut inner, &entries, path, Some(range)).await
+                }
+                Request::Open {
+                    mode: Mode::Read,
+                    path,
+                } => {
+                    let entry = file(&entries, &path)?;
+                    if let Contents::Pinned(bytes) = &entry.contents {
+                        let file = FileHandle::read_only(Cursor::new(bytes.clone()));
+                        return Ok(Response::File(file));
+                    }
+                    verify(&mut inner, &path, entry).await?;
+                    let mode = Mode::Read;
+                    call(&mut inner, Request::Open { mode, path }).await
+                }
+                Request::Copy { .. }
+                | Request::CreateDir { .. }
+                | Request::HardLink { .. }
+                | Request::Open { .. }
+                | Request::RemoveDir { .. }
+                | Request::RemoveFile(_)
+                | Request::Rename { .. }
+                | Request::SetPermissions { .. }
+                | Request::WriteBytes { .. }
+                | Request::AppendBytes { .. }
+                | Request::CopyDir { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(unix)]
+                Request::Symlink { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(windows)]
+                Request::SymlinkDir { .. }
+                | Request::SymlinkFile { .. }
+                | Request::ReadJunction(_)
+                | Request::OpenStream { .. }
+                | Request::GetFileAttributes(_)
+                | Request::SetFileAttributes { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(feature = "archive")]
+                Request::Archive { .. } | Request::Extract { .. } => {
+                    Err(ErrorKind::Unsupported.into())
+                }
+                #[cfg(feature = "dedup")]
+                Request::FindDuplicates { .. } => Err(ErrorKind::Unsupported.into()),
+                #[cfg(feature = "posix-acl")]
+                Request::GetPosixAcl(_) | Request::SetPosixAcl { .. } => {
+                    Err(ErrorKind::Unsupported.into())
+                }
+                #[cfg(unix)]
+                Request::GetAttributes(_) | Request::SetAttributes { .. } => {
+                    Err(ErrorKind::Unsupported.into())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+async fn call<S>(inner: &mut S, req: Request) -> io::Result<Response>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    poll_fn(|cx| inner.poll_ready(cx)).await?;
+    inner.call(req).await
+}
+
+/// Records the entry at `path`, whose metadata is already known if it was listed in it's parent
+async fn capture<S>(
+    inner: &mut S,
+    path: &Path,
+    metadata: Option<Metadata>,
+    pin_contents: bool,
+) -> io::Result<Entry>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => match get_metadata(inner, path, false).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                let bytes = call(inner, Request::ReadBytes(path.into()))
+                    .await?
+                    .into_bytes()?;
+                return Ok(Entry {
+                    metadata: Metadata::new(FileType::File, bytes.len() as u64),
+                    link: None,
+                    contents: Contents::Pinned(bytes.into()),
+                });
+            }
+            Err(err) => return Err(err),
+        },
+    };
+    let link = if metadata.is_symlink() {
+        let target = call(inner, Request::FollowLink(path.into()))
+            .await?
+            .into_points_to()?;
+        let followed = match get_metadata(inner, path, true).await {
+            Ok(followed) => Some(followed),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        Some((target, followed))
+    } else {
+        None
+    };
+    let followed = match &link {
+        Some((_, followed)) => followed.as_ref(),
+        None => Some(&metadata),
+    };
+    let contents = match followed.map(Metadata::file_type) {
+        Some(FileType::Dir) if link.is_none() => {
+            let mut listing = call(inner, Request::ReadDir(path.into()))
+                .await?
+                .into_directory()?;
+            listing.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Contents::Directory(listing)
+        }
+        Some(FileType::File) if pin_contents => {
+            let bytes = call(inner, Request::ReadBytes(path.into()))
+                .await?
+                .into_bytes()?;
+            Contents::Pinned(bytes.into())
+        }
+        Some(FileType::File) => Contents::Checked,
+        _ => Contents::None,
+    };
+    Ok(Entry {
+        metadata,
+        link,
+        contents,
+    })
+}
+
+async fn get_metadata<S>(inner: &mut S, path: &Path, follow_symlinks: bool) -> io::Result<Metadata>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let req = Request::GetMetadata {
+        path: path.into(),
+        follow_symlinks,
+    };
+    Ok(call(inner, req).await?.into_metadata()?)
+}
+
+fn get<'a>(entries: &'a HashMap<PathBuf, Entry>, path: &Path) -> io::Result<&'a Entry> {
+    entries.get(path).ok_or_else(|| ErrorKind::NotFound.into())
+}
+
+/// The recorded metadata of `path`, or of what it points to if it's a link and `follow_symlinks`
+/// is set
+fn metadata(
+    entries: &HashMap<PathBuf, Entry>,
+    path: &Path,
+    follow_symlinks: bool,
+) -> io::Result<Metadata> {
+    let entry = get(entries, path)?;
+    match &entry.link {
+        Some((_, Some(followed))) if follow_symlinks => Ok(followed.clone()),
+        Some((_, None)) if follow_symlinks => Err(ErrorKind::NotFound.into()),
+        _ => Ok(entry.metadata.clone()),
+    }
+}
+
+/// The entry of the file at `path`, following a link at it
+fn file<'a>(entries: &'a HashMap<PathBuf, Entry>, path: &Path) -> io::Result<&'a Entry> {
+    let entry = get(entries, path)?;
+    match &entry.contents {
+        Contents::Pinned(_) | Contents::Checked => Ok(entry),
+        _ if metadata(entries, path, true)?.is_dir() => Err(ErrorKind::IsADirectory.into()),
+        _ => Err(ErrorKind::Unsupported.into()),
+    }
+}
+
+/// Fails unless the file at `path` still matches the snapshot
+async fn verify<S>(inner: &mut S, path: &Path, entry: &Entry) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let recorded = match &entry.link {
+        Some((_, Some(followed))) => followed,
+        _ => &entry.metadata,
+    };
+    let metadata = get_metadata(inner, path, true).await?;
+    if metadata.len() == recorded.len() && metadata.modified().ok() == recorded.modified().ok() {
+        Ok(())
+    } else {
+        Err(io::Error::other("file changed since the snapshot"))
+    }
+}
+
+async fn read<S>(
+    inner: &mut S,
+    entries: &HashMap<PathBuf, Entry>,
+    path: Arc<Path>,
+    range: Option<Range<u64>>,
+) -> io::Result<Response>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let entry = file(entries, &path)?;
+    if let Contents::Pinned(bytes) = &entry.contents {
+        let range = range.unwrap_or(0..u64::MAX);
+        let len = bytes.len() as u64;
+        let start = usize::try_from(range.start.min(len)).map_err(io::Error::other)?;
+        let end = usize::try_from(range.end.clamp(range.start.min(len), len))
+            .map_err(io::Error::other)?;
+        return Ok(Response::Bytes(bytes[start..end].to_vec()));
+    }
+    verify(inner, &path, entry).await?;
+    let response = match range {
+        Some(range) => {
+            call(
+                inner,
+                Request::ReadRange {
+                    path: path.clone(),
+                    range,
+                },
+            )
+            .await?
+        }
+        None => call(inner, Request::ReadBytes(path.clone())).await?,
+    };
+    // The file could have changed while it was being read
+    verify(inner, &path, entry).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    #[tokio::test]
+    async fn test_snapshot() -> io::Result<()> {
+        let dir = TestDir::new("snapshot")?;
+        std::fs::create_dir_all(dir.join("site/assets"))?;
+        let (index, app) = (dir.join("site/index.html"), dir.join("site/assets/app.js"));
+        std::fs::write(&index, "v1")?;
+        std::fs::write(&app, "app")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("index.html", dir.join("site/home.html"))?;
+
+        let mut view = Snapshot::capture(FileSystem::new(), [dir.join("site")]).await?;
+        let mut checked = Snapshot::capture_checked(FileSystem::new(), [dir.join("site")]).await?;
+        std::fs::write(&index, "v2, longer")?;
+        std::fs::remove_file(&app)?;
+
+        let read = |path: &Path| Request::ReadBytes(path.into());
+        assert_eq!(view.call(read(&index)).await?.into_bytes()?, b"v1");
+        assert_eq!(view.call(read(&app)).await?.into_bytes()?, b"app");
+        let stat = Request::GetMetadata {
+            path: index.as_path().into(),
+            follow_symlinks: true,
+        };
+        assert_eq!(view.call(stat).await?.into_metadata()?.len(), 2);
+        let open = Request::Open {
+            mode: Mode::Read,
+            path: index.as_path().into(),
+        };
+        let mut contents = Vec::new();
+        view.call(open)
+            .await?
+            .into_file()?
+            .read_to_end(&mut contents)
+            .await?;
+        assert_eq!(contents, b"v1");
+        let listing = view.call(Request::ReadDir(dir.join("site").into())).await?;
+        let names = listing.into_directory()?.into_iter().map(|(name, _)| name);
+        #[cfg(unix)]
+        assert!(names.eq(["assets", "home.html", "index.html"].map(PathBuf::from)));
+        #[cfg(not(unix))]
+        assert!(names.eq(["assets", "index.html"].map(PathBuf::from)));
+        #[cfg(unix)]
+        {
+            let home = dir.join("site/home.html");
+            let link = view
+                .call(Request::FollowLink(home.as_path().into()))
+                .await?;
+            assert_eq!(link.into_points_to()?, Path::new("index.html"));
+            assert_eq!(view.call(read(&home)).await?.into_bytes()?, b"v1");
+        }
+        let exists = view
+            .call(Request::Exists(dir.join("missing").into()))
+            .await?;
+        assert!(!exists.into_exists()?);
+        let write = Request::WriteBytes {
+            path: index.as_path().into(),
+            bytes: b"v3".to_vec(),
+        };
+        assert_eq!(
+            view.call(write).await.map(drop).map_err(|err| err.kind()),
+            Err(ErrorKind::Unsupported)
+        );
+
+        // Files which changed or were removed since fail, rather than serving the new contents
+        assert!(checked.call(read(&index)).await.is_err());
+        assert_eq!(
+            checked
+                .call(read(&app))
+                .await
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::NotFound)
+        );
+        Ok(())
+    }
+}
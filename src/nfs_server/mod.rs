@@ -0,0 +1,963 @@
+//! Serving a service stack over NFS version 3
+//!
+//! [`serve`] answers NFS and mount protocol calls read from a byte stream by translating them
+//! into requests against a service, so unmodified NFS clients can mount any backend and middleware
+//! stack, most usefully read only archives and object stores.  Accepting connections is left to
+//! the application, and since no port mapper is provided clients are told which port to use, for
+//! example with `mount -t nfs -o vers=3,proto=tcp,port=2049,mountport=2049,nolock host:/ /mnt`.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::poll_fn,
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tower_service::Service;
+
+use crate::{Metadata, Mode, Request, Response};
+use xdr::{
+    receive, status, Reader, Writer, AUTH_NONE, AUTH_UNIX, CALL, FILE_SYNC, FSF_HOMOGENEOUS,
+    FSF_LINK, FSF_SYMLINK, GARBAGE_ARGS, MOUNTPROC_DUMP, MOUNTPROC_EXPORT, MOUNTPROC_MNT,
+    MOUNTPROC_NULL, MOUNTPROC_UMNT, MOUNTPROC_UMNTALL, MOUNT_PROGRAM, MSG_ACCEPTED, MSG_DENIED,
+    NF3DIR, NF3LNK, NF3REG, NFSPROC_ACCESS, NFSPROC_COMMIT, NFSPROC_CREATE, NFSPROC_FSINFO,
+    NFSPROC_FSSTAT, NFSPROC_GETATTR, NFSPROC_LINK, NFSPROC_LOOKUP, NFSPROC_MKDIR, NFSPROC_MKNOD,
+    NFSPROC_NULL, NFSPROC_PATHCONF, NFSPROC_READ, NFSPROC_READDIR, NFSPROC_READDIRPLUS,
+    NFSPROC_READLINK, NFSPROC_REMOVE, NFSPROC_RENAME, NFSPROC_RMDIR, NFSPROC_SETATTR,
+    NFSPROC_SYMLINK, NFSPROC_WRITE, NFS_PROGRAM, PROC_UNAVAIL, PROG_MISMATCH, PROG_UNAVAIL, REPLY,
+    RPC_MISMATCH, RPC_VERSION, SET_TO_CLIENT_TIME, SUCCESS, UNCHECKED, VERSION,
+};
+
+mod xdr;
+
+/// The most bytes read or written by a single call
+const MAX_IO: u32 = 1024 * 1024;
+
+/// The length of a reply to `READDIR` or `READDIRPLUS` without any entries, leaving some room for
+/// the RPC header
+const REPLY_LEN: usize = 128 + 8 + 4 + 84 + 8 + 8;
+/// The length of the attributes and handle following each entry of a reply to `READDIRPLUS`
+const ENTRY_PLUS_LEN: usize = 4 + 84 + 4 + 4 + 8;
+
+/// Answers the NFS and mount protocol calls read from `stream` with `service` until the client
+/// closes the stream
+///
+/// Any path can be mounted, and paths are passed to `service` as absolute paths, so export a
+/// directory by wrapping the service in [`Root`](crate::middleware::root::Root).  Credentials are
+/// accepted without being checked, leaving access control to the service stack.  File handles
+/// identify paths, and are only remembered for as long as the stream is open, so clients which
+/// reconnect will find the handles they held are stale.
+///
+/// Files are read with [`Request::ReadRange`] and described with [`Request::GetMetadata`], falling
+/// back to reading the whole file to find it's size.  Writes are only supported at the end of a
+/// file, as happens when copying files onto the mount, and append with [`Request::Open`] where the
+/// service supports it and otherwise rewrite the whole file.  `SETATTR` only changes permissions
+/// or truncates files to nothing, ignoring other attributes.
+///
+/// Directories are read with [`Request::ReadDir`], listing the whole directory again for each
+/// `READDIR` or `READDIRPLUS` and continuing from the position the client's cookie gives, so
+/// entries may be skipped or repeated if the directory changes while it's being read.
+///
+/// # Errors
+///
+/// If reading from or writing to `stream` fails, or a record is too large.  Errors from `service`
+/// are sent to the client instead.
+pub async fn serve<T, S>(mut stream: T, service: S) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut server = Server {
+        service,
+        handles: HashMap::new(),
+    };
+    while let Some(record) = receive(&mut stream).await? {
+        if let Some(reply) = server.dispatch(&mut Reader(&record)).await {
+            reply.send(&mut stream).await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Server<S> {
+    service: S,
+    /// The paths clients have looked up, by their handle
+    handles: HashMap<u64, PathBuf>,
+}
+
+/// The arguments of an NFS procedure
+#[derive(Debug)]
+enum Call {
+    Null,
+    GetAttr(Vec<u8>),
+    SetAttr(Vec<u8>, SetAttrs),
+    Lookup(Vec<u8>, String),
+    Access(Vec<u8>, u32),
+    ReadLink(Vec<u8>),
+    Read(Vec<u8>, u64, u32),
+    Write(Vec<u8>, u64, Vec<u8>),
+    Create(Vec<u8>, String, Option<SetAttrs>),
+    MkDir(Vec<u8>, String),
+    Symlink(Vec<u8>, String, String),
+    Remove(Vec<u8>, String),
+    RmDir(Vec<u8>, String),
+    Rename(Vec<u8>, String, Vec<u8>, String),
+    Link(Vec<u8>, Vec<u8>, String),
+    FsStat(Vec<u8>),
+    FsInfo(Vec<u8>),
+    PathConf(Vec<u8>),
+    Commit(Vec<u8>),
+    /// The directory, cookie and how many bytes of entries the reply may hold
+    ReadDir(Vec<u8>, u64, u32),
+    /// As [`Call::ReadDir`], followed by the most bytes the whole reply may hold
+    ReadDirPlus(Vec<u8>, u64, u32, u32),
+    /// Including creating special files
+    Unsupported,
+}
+
+/// The attributes of a `sattr3` which are applied, the rest being ignored
+#[derive(Debug, Default)]
+struct SetAttrs {
+    mode: Option<u32>,
+    size: Option<u64>,
+}
+
+/// The attributes of a file, as sent in `fattr3`
+#[derive(Debug)]
+struct Attrs {
+    ty: u32,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    fileid: u64,
+    /// Access, modification and change times
+    times: [(u32, u32); 3],
+}
+
+impl<S> Server<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    /// Answers an RPC call, or returns `None` if the record isn't a call
+    async fn dispatch(&mut self, reader: &mut Reader<'_>) -> Option<Writer> {
+        let (xid, ty) = (reader.u32().ok()?, reader.u32().ok()?);
+        if ty != CALL {
+            return None;
+        }
+        let header = (|| {
+            let (rpc_version, program, version, procedure) =
+                (reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?);
+            // The credentials and verifier, which aren't checked
+            for _ in 0..2 {
+                reader.u32()?;
+                reader.opaque()?;
+            }
+            io::Result::Ok((rpc_version, program, version, procedure))
+        })();
+        let reply = Writer::default().u32(xid).u32(REPLY);
+        let Ok((rpc_version, program, version, procedure)) = header else {
+            return Some(accepted(reply, GARBAGE_ARGS));
+        };
+        if rpc_version != RPC_VERSION {
+            let reply = reply.u32(MSG_DENIED).u32(RPC_MISMATCH);
+            return Some(reply.u32(RPC_VERSION).u32(RPC_VERSION));
+        }
+        Some(match (program, version) {
+            (MOUNT_PROGRAM, VERSION) => self.mount(procedure, reader, reply).await,
+            (NFS_PROGRAM, VERSION) => match decode(procedure, reader) {
+                Ok(Call::Null) => accepted(reply, SUCCESS),
+                Ok(call) => match self.execute(call, accepted(reply, SUCCESS).u32(0)).await {
+                    Ok(reply) => reply,
+                    Err(err) => accepted(Writer::default().u32(xid).u32(REPLY), SUCCESS)
+                        .u32(status(&err))
+                        .zeros(failure_len(procedure)),
+                },
+                Err(err) if err.kind() == ErrorKind::Unsupported => accepted(reply, PROC_UNAVAIL),
+                Err(_) => accepted(reply, GARBAGE_ARGS),
+            },
+            (MOUNT_PROGRAM | NFS_PROGRAM, _) => {
+                accepted(reply, PROG_MISMATCH).u32(VERSION).u32(VERSION)
+            }
+            _ => accepted(reply, PROG_UNAVAIL),
+        })
+    }
+
+    async fn mount(&mut self, procedure: u32, reader: &mut Reader<'_>, reply: Writer) -> Writer {
+        match procedure {
+            MOUNTPROC_NULL | MOUNTPROC_UMNTALL => accepted(reply, SUCCESS),
+            MOUNTPROC_MNT => {
+                let Ok(path) = reader.string() else {
+                    return accepted(reply, GARBAGE_ARGS);
+                };
+                let path = Path::new("/").join(path);
+                let reply = accepted(reply, SUCCESS);
+                match self.attrs(&path).await {
+                    Ok(attrs) if attrs.ty == NF3DIR => {
+                        let handle = self.insert(path);
+                        let reply = reply.u32(SUCCESS).opaque(&handle);
+                        reply.u32(2).u32(AUTH_NONE).u32(AUTH_UNIX)
+                    }
+                    Ok(_) => reply.u32(status(&ErrorKind::NotADirectory.into())),
+                    Err(err) => reply.u32(status(&err)),
+                }
+            }
+            MOUNTPROC_UMNT => match reader.string() {
+                Ok(_) => accepted(reply, SUCCESS),
+                Err(_) => accepted(reply, GARBAGE_ARGS),
+            },
+            // No list of mounts is kept
+            MOUNTPROC_DUMP => accepted(reply, SUCCESS).bool(false),
+            // Everything is exported, to every client
+            MOUNTPROC_EXPORT => accepted(reply, SUCCESS)
+                .bool(true)
+                .string("/")
+                .bool(false)
+                .bool(false),
+            _ => accepted(reply, PROC_UNAVAIL),
+        }
+    }
+
+    /// Executes an NFS procedure besides `NULL`, returning the reply following it's status
+    async fn execute(&mut self, call: Call, reply: Writer) -> io::Result<Writer> {
+        match call {
+            Call::GetAttr(handle) => {
+                let path = self.path(&handle)?;
+                Ok(fattr(reply, &self.attrs(&path).await?))
+            }
+            Call::Lookup(dir, name) => {
+                let dir = self.path(&dir)?;
+                let path = match name.as_str() {
+                    "." => dir,
+                    ".." => dir.parent().unwrap_or(&dir).to_owned(),
+                    name => child(&dir, name)?,
+                };
+                let attrs = self.attrs(&path).await?;
+                let handle = self.insert(path);
+                Ok(fattr(reply.opaque(&handle).bool(true), &attrs).bool(false))
+            }
+            Call::Access(handle, access) => {
+                // Whether access is allowed is left to the service when the file is used
+                let path = self.path(&handle)?;
+                let attrs = self.attrs(&path).await?;
+                Ok(fattr(reply.bool(true), &attrs).u32(access))
+            }
+            Call::ReadLink(handle) => {
+                let path = self.path(&handle)?;
+                match self.call(Request::FollowLink(path.into())).await? {
+                    Response::PointsTo(target) => {
+                        Ok(reply.bool(false).string(&target.to_string_lossy()))
+                    }
+                    _ => Err(unexpected_response()),
+                }
+            }
+            Call::Read(handle, offset, count) => {
+                let path = self.path(&handle)?;
+                let count = count.min(MAX_IO);
+                let range = offset..offset.saturating_add(u64::from(count));
+                let Response::Bytes(bytes) = self
+                    .call(Request::ReadRange {
+                        path: path.into(),
+                        range,
+                    })
+                    .await?
+                else {
+                    return Err(unexpected_response());
+                };
+                let read = u32::try_from(bytes.len()).unwrap_or(u32::MAX);
+                Ok(reply
+                    .bool(false)
+                    .u32(read)
+                    .bool(read < count)
+                    .opaque(&bytes))
+            }
+            Call::FsStat(handle) => {
+                self.path(&handle)?;
+                // The space and number of files available aren't known
+                Ok(reply.bool(false).zeros(12).u32(0))
+            }
+            Call::FsInfo(handle) => {
+                self.path(&handle)?;
+                let reply = reply.bool(false).u32(MAX_IO).u32(MAX_IO).u32(4096);
+                let reply = reply.u32(MAX_IO).u32(MAX_IO).u32(4096).u32(4096);
+                // Maximum file size, then time resolution
+                let reply = reply.u64(u64::MAX).u32(0).u32(1);
+                Ok(reply.u32(FSF_LINK | FSF_SYMLINK | FSF_HOMOGENEOUS))
+            }
+            Call::PathConf(handle) => {
+                self.path(&handle)?;
+                let reply = reply.bool(false).u32(u32::MAX).u32(255);
+                // Long names are rejected, ownership can't be given away, and names are case
+                // sensitive and preserved
+                Ok(reply.bool(true).bool(true).bool(false).bool(true))
+            }
+            Call::Commit(handle) => {
+                self.path(&handle)?;
+                Ok(reply.zeros(2).u64(0))
+            }
+            Call::ReadDir(handle, cookie, count) => {
+                self.read_dir(&handle, cookie, count, None, reply).await
+            }
+            Call::ReadDirPlus(handle, cookie, dir_count, max_count) => {
+                self.read_dir(&handle, cookie, dir_count, Some(max_count), reply)
+                    .await
+            }
+            Call::Unsupported => Err(ErrorKind::Unsupported.into()),
+            call => self.modify(call, reply).await,
+        }
+    }
+
+    /// Executes the NFS procedures which change the file system
+    async fn modify(&mut self, call: Call, reply: Writer) -> io::Result<Writer> {
+        match call {
+            Call::SetAttr(handle, attrs) => {
+                let path = self.path(&handle)?;
+                self.set_attrs(path, attrs).await?;
+                Ok(reply.zeros(2))
+            }
+            Call::Write(handle, offset, data) => {
+                let path = self.path(&handle)?;
+                self.append(path, offset, &data).await?;
+                let written = u32::try_from(data.len()).unwrap_or(u32::MAX);
+                // Writes are never cached, so there's no verifier to change after a restart
+                Ok(reply.zeros(2).u32(written).u32(FILE_SYNC).u64(0))
+            }
+            Call::Create(dir, name, attrs) => {
+                let path = child(&self.path(&dir)?, &name)?;
+                self.create(path.clone(), attrs).await?;
+                self.created(path, reply).await
+            }
+            Call::MkDir(dir, name) => {
+                let path = child(&self.path(&dir)?, &name)?;
+                let req = Request::CreateDir {
+                    path: path.as_path().into(),
+                    recursive: false,
+                };
+                self.call(req).await?;
+                self.created(path, reply).await
+            }
+            Call::Symlink(dir, name, target) => {
+                let dst = child(&self.path(&dir)?, &name)?;
+                let src = PathBuf::from(target);
+                #[cfg(unix)]
+                let req = Request::Symlink {
+                    src: src.into(),
+                    dst: dst.as_path().into(),
+                };
+                #[cfg(windows)]
+                let req = Request::SymlinkFile {
+                    src: src.into(),
+                    dst: dst.as_path().into(),
+                };
+                self.call(req).await?;
+                self.created(dst, reply).await
+            }
+            Call::Remove(dir, name) => {
+                let path = child(&self.path(&dir)?, &name)?;
+                self.call(Request::RemoveFile(path.into())).await?;
+                Ok(reply.zeros(2))
+            }
+            Call::RmDir(dir, name) => {
+                let path = child(&self.path(&dir)?, &name)?;
+                let req = Request::RemoveDir {
+                    path: path.into(),
+                    recursive: false,
+                };
+                self.call(req).await?;
+                Ok(reply.zeros(2))
+            }
+            Call::Rename(from_dir, from_name, to_dir, to_name) => {
+                let from = child(&self.path(&from_dir)?, &from_name)?;
+                let to = child(&self.path(&to_dir)?, &to_name)?;
+                let req = Request::Rename {
+                    from: from.as_path().into(),
+                    to: to.as_path().into(),
+                };
+                self.call(req).await?;
+                // Handles the client holds for the old path now refer to the new one
+                self.handles.insert(path_hash(&from), to.clone());
+                self.insert(to);
+                Ok(reply.zeros(4))
+            }
+            Call::Link(handle, dir, name) => {
+                let src = self.path(&handle)?;
+                let dst = child(&self.path(&dir)?, &name)?;
+                self.call(Request::HardLink {
+                    src: src.into(),
+                    dst: dst.into(),
+                })
+                .await?;
+                Ok(reply.zeros(3))
+            }
+            _ => Err(ErrorKind::Unsupported.into()),
+        }
+    }
+
+    async fn call(&mut self, req: Request) -> io::Result<Response> {
+        poll_fn(|cx| self.service.poll_ready(cx)).await?;
+        self.service.call(req).await
+    }
+
+    /// Remembers `path`, returning the handle identifying it
+    fn insert(&mut self, path: PathBuf) -> [u8; 8] {
+        let hash = path_hash(&path);
+        self.handles.insert(hash, path);
+        hash.to_be_bytes()
+    }
+
+    /// The path `handle` identifies
+    fn path(&self, handle: &[u8]) -> io::Result<PathBuf> {
+        let root = Path::new("/");
+        <[u8; 8]>::try_from(handle)
+            .ok()
+            .map(u64::from_be_bytes)
+            .and_then(|hash| match self.handles.get(&hash) {
+                Some(path) => Some(path.clone()),
+                // The root is always known, so clients can carry on after reconnecting to it
+                None => (hash == path_hash(root)).then(|| root.to_owned()),
+            })
+            .ok_or_else(|| ErrorKind::StaleNetworkFileHandle.into())
+    }
+
+    /// Replies to a procedure which created `path`, with it's handle and attributes
+    async fn created(&mut self, path: PathBuf, reply: Writer) -> io::Result<Writer> {
+        let attrs = self.attrs(&path).await?;
+        let handle = self.insert(path);
+        Ok(fattr(reply.bool(true).opaque(&handle).bool(true), &attrs).zeros(2))
+    }
+
+    /// Lists the directory `handle` after the entry `cookie` (counting from one), with as many
+    /// entries as fit in `count` bytes, and for `READDIRPLUS`, `max_count` bytes including their
+    /// attributes and handles
+    async fn read_dir(
+        &mut self,
+        handle: &[u8],
+        cookie: u64,
+        count: u32,
+        max_count: Option<u32>,
+        reply: Writer,
+    ) -> io::Result<Writer> {
+        let dir = self.path(handle)?;
+        let attrs = self.attrs(&dir).await?;
+        let Response::Directory(entries) =
+            self.call(Request::ReadDir(dir.as_path().into())).await?
+        else {
+            return Err(unexpected_response());
+        };
+        // Cookies are positions rather than something which could be verified
+        let mut reply = fattr(reply.bool(true), &attrs).u64(0);
+        let skip = usize::try_from(cookie).unwrap_or(usize::MAX);
+        // The reply besides the entries takes about this many bytes
+        let (mut len, mut max_len) = (0, REPLY_LEN);
+        let mut eof = true;
+        for (index, (name, metadata)) in entries.iter().enumerate().skip(skip) {
+            let name = name.to_string_lossy();
+            // The entry's presence, file id, name and cookie
+            let entry_len = 4 + 8 + 4 + name.len().next_multiple_of(4) + 8;
+            len += entry_len;
+            max_len += entry_len + max_count.map_or(0, |_| ENTRY_PLUS_LEN);
+            // `READDIR`'s count covers the whole reply, and `READDIRPLUS`'s just the entries
+            let full = match max_count {
+                None => max_len > count as usize,
+                Some(max_count) => len > count as usize || max_len > max_count as usize,
+            };
+            // At least one entry is sent, so the client always gets further
+            if full && index > skip {
+                eof = false;
+                break;
+            }
+            let path = dir.join(&*name);
+            let attrs = metadata_attrs(&path, metadata);
+            reply = reply
+                .bool(true)
+                .u64(attrs.fileid)
+                .string(&name)
+                .u64(index as u64 + 1);
+            if max_count.is_some() {
+                let handle = self.insert(path);
+                reply = fattr(reply.bool(true), &attrs).bool(true).opaque(&handle);
+            }
+        }
+        Ok(reply.bool(false).bool(eof))
+    }
+
+    /// Describes the file at `path`, without following symbolic links
+    async fn attrs(&mut self, path: &Path) -> io::Result<Attrs> {
+        let req = Request::GetMetadata {
+            path: path.into(),
+            follow_symlinks: false,
+        };
+        match self.call(req).await {
+            Ok(Response::Metadata(metadata)) => Ok(metadata_attrs(path, &metadata)),
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => self.probe(path).await,
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Describes the file at `path` on services without metadata, by reading it
+    async fn probe(&mut self, path: &Path) -> io::Result<Attrs> {
+        let (ty, size) = match self.call(Request::ReadBytes(path.into())).await {
+            Ok(Response::Bytes(bytes)) => (NF3REG, bytes.len() as u64),
+            Ok(_) => return Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::IsADirectory => (NF3DIR, 0),
+            // Directories are usually implicit on services without metadata
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                match self.call(Request::Exists(path.into())).await? {
+                    Response::Exists(exists) if exists || path.parent().is_none() => (NF3DIR, 0),
+                    Response::Exists(_) => return Err(err),
+                    _ => return Err(unexpected_response()),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Attrs {
+            ty,
+            mode: if ty == NF3DIR { 0o755 } else { 0o644 },
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            size,
+            fileid: path_hash(path),
+            times: [(0, 0); 3],
+        })
+    }
+
+    async fn set_attrs(&mut self, path: PathBuf, attrs: SetAttrs) -> io::Result<()> {
+        if let Some(mode) = attrs.mode {
+            let perm = permissions(mode)?;
+            self.call(Request::SetPermissions {
+                path: path.as_path().into(),
+                perm,
+            })
+            .await?;
+        }
+        match attrs.size {
+            None => Ok(()),
+            Some(0) => {
+                let req = Request::WriteBytes {
+                    path: path.into(),
+                    bytes: Vec::new(),
+                };
+                self.call(req).await.map(drop)
+            }
+            Some(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "files can only be truncated to nothing",
+            )),
+        }
+    }
+
+    /// Creates an empty file at `path`, unless `attrs` are given and it exists already
+    async fn create(&mut self, path: PathBuf, attrs: Option<SetAttrs>) -> io::Result<()> {
+        let Some(attrs) = attrs else {
+            let open = Request::Open {
+                mode: Mode::CreateNew,
+                path: path.as_path().into(),
+            };
+            return match self.call(open).await {
+                Ok(Response::File(_)) => Ok(()),
+                Ok(_) => Err(unexpected_response()),
+                Err(err) if err.kind() == ErrorKind::Unsupported => {
+                    match self.call(Request::Exists(path.as_path().into())).await? {
+                        Response::Exists(true) => Err(ErrorKind::AlreadyExists.into()),
+                        Response::Exists(false) => self.set_attrs(path, truncate()).await,
+                        _ => Err(unexpected_response()),
+                    }
+                }
+                Err(err) => Err(err),
+            };
+        };
+        match self.call(Request::Exists(path.as_path().into())).await? {
+            Response::Exists(true) => self.set_attrs(path, attrs).await,
+            Response::Exists(false) => self.set_attrs(path, truncate()).await,
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Writes `data` at `offset`, which must be the end of the file
+    async fn append(&mut self, path: PathBuf, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.attrs(&path).await?.size != offset {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "files can only be written at their end",
+            ));
+        }
+        let open = Request::Open {
+            mode: Mode::AppendExisting,
+            path: path.as_path().into(),
+        };
+        match self.call(open).await {
+            Ok(Response::File(mut file)) => {
+                file.write_all(data).await?;
+                file.flush().await
+            }
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                let Response::Bytes(mut bytes) =
+                    self.call(Request::ReadBytes(path.as_path().into())).await?
+                else {
+                    return Err(unexpected_response());
+                };
+                bytes.extend_from_slice(data);
+                self.call(Request::WriteBytes {
+                    path: path.into(),
+                    bytes,
+                })
+                .await
+                .map(drop)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Parses the arguments of an NFS procedure, returning an [`ErrorKind::Unsupported`] error for
+/// unknown procedures
+fn decode(procedure: u32, reader: &mut Reader<'_>) -> io::Result<Call> {
+    Ok(match procedure {
+        NFSPROC_NULL => Call::Null,
+        NFSPROC_GETATTR => Call::GetAttr(handle(reader)?),
+        NFSPROC_READLINK => Call::ReadLink(handle(reader)?),
+        NFSPROC_FSSTAT => Call::FsStat(handle(reader)?),
+        NFSPROC_FSINFO => Call::FsInfo(handle(reader)?),
+        NFSPROC_PATHCONF => Call::PathConf(handle(reader)?),
+        NFSPROC_COMMIT => Call::Commit(handle(reader)?),
+        NFSPROC_SETATTR => Call::SetAttr(handle(reader)?, set_attrs(reader)?),
+        NFSPROC_LOOKUP => Call::Lookup(handle(reader)?, reader.string()?),
+        NFSPROC_ACCESS => Call::Access(handle(reader)?, reader.u32()?),
+        NFSPROC_READ => Call::Read(handle(reader)?, reader.u64()?, reader.u32()?),
+        NFSPROC_WRITE => {
+            let (handle, offset) = (handle(reader)?, reader.u64()?);
+            // The count and stability asked for, which the data and unbuffered writes make moot
+            reader.u32()?;
+            reader.u32()?;
+            Call::Write(handle, offset, reader.opaque()?.to_vec())
+        }
+        NFSPROC_CREATE => {
+            let (dir, name) = (handle(reader)?, reader.string()?);
+            // Guarded and exclusive creation both fail if the file exists, so the verifier
+            // exclusive creation sends isn't needed
+            let attrs = match reader.u32()? {
+                UNCHECKED => Some(set_attrs(reader)?),
+                _ => None,
+            };
+            Call::Create(dir, name, attrs)
+        }
+        NFSPROC_MKDIR => Call::MkDir(handle(reader)?, reader.string()?),
+        NFSPROC_SYMLINK => {
+            let (dir, name) = (handle(reader)?, reader.string()?);
+            set_attrs(reader)?;
+            Call::Symlink(dir, name, reader.string()?)
+        }
+        NFSPROC_REMOVE => Call::Remove(handle(reader)?, reader.string()?),
+        NFSPROC_RMDIR => Call::RmDir(handle(reader)?, reader.string()?),
+        NFSPROC_RENAME => {
+            let (from_dir, from_name) = (handle(reader)?, reader.string()?);
+            let to_dir = handle(reader)?;
+            Call::Rename(from_dir, from_name, to_dir, reader.string()?)
+        }
+        NFSPROC_LINK => Call::Link(handle(reader)?, handle(reader)?, reader.string()?),
+        NFSPROC_READDIR | NFSPROC_READDIRPLUS => {
+            let (dir, cookie) = (handle(reader)?, reader.u64()?);
+            // The cookie verifier, since cookies are never invalidated
+            reader.u64()?;
+            let count = reader.u32()?;
+            match procedure {
+                NFSPROC_READDIR => Call::ReadDir(dir, cookie, count),
+                _ => Call::ReadDirPlus(dir, cookie, count, reader.u32()?),
+            }
+        }
+        NFSPROC_MKNOD => Call::Unsupported,
+        _ => return Err(ErrorKind::Unsupported.into()),
+    })
+}
+
+fn handle(reader: &mut Reader<'_>) -> io::Result<Vec<u8>> {
+    reader.opaque().map(<[u8]>::to_vec)
+}
+
+fn set_attrs(reader: &mut Reader<'_>) -> io::Result<SetAttrs> {
+    let mode = reader.bool()?.then(|| reader.u32()).transpose()?;
+    // The owner and group
+    for _ in 0..2 {
+        if reader.bool()? {
+            reader.u32()?;
+        }
+    }
+    let size = reader.bool()?.then(|| reader.u64()).transpose()?;
+    // The access and modification times
+    for _ in 0..2 {
+        if reader.u32()? == SET_TO_CLIENT_TIME {
+            reader.u64()?;
+        }
+    }
+    Ok(SetAttrs { mode, size })
+}
+
+fn truncate() -> SetAttrs {
+    SetAttrs {
+        mode: None,
+        size: Some(0),
+    }
+}
+
+/// Starts the reply to a call which was accepted, with it's status
+fn accepted(reply: Writer, status: u32) -> Writer {
+    // The verifier, which is always empty
+    reply.u32(MSG_ACCEPTED).u32(AUTH_NONE).u32(0).u32(status)
+}
+
+/// The number of words following the status of a failed procedure, which are all absent optional
+/// attributes
+fn failure_len(procedure: u32) -> usize {
+    match procedure {
+        NFSPROC_LOOKUP | NFSPROC_ACCESS | NFSPROC_READLINK | NFSPROC_READ | NFSPROC_READDIR
+        | NFSPROC_READDIRPLUS | NFSPROC_FSSTAT | NFSPROC_FSINFO | NFSPROC_PATHCONF => 1,
+        NFSPROC_LINK => 3,
+        NFSPROC_RENAME => 4,
+        NFSPROC_NULL | NFSPROC_GETATTR => 0,
+        _ => 2,
+    }
+}
+
+fn fattr(reply: Writer, attrs: &Attrs) -> Writer {
+    let reply = reply.u32(attrs.ty).u32(attrs.mode).u32(attrs.nlink);
+    let reply = reply
+        .u32(attrs.uid)
+        .u32(attrs.gid)
+        .u64(attrs.size)
+        .u64(attrs.size);
+    // The device and file system ids
+    let mut reply = reply.zeros(2).u64(0).u64(attrs.fileid);
+    for (secs, nanos) in attrs.times {
+        reply = reply.u32(secs).u32(nanos);
+    }
+    reply
+}
+
+fn child(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "invalid file name"));
+    }
+    Ok(dir.join(name))
+}
+
+fn path_hash(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn metadata_attrs(path: &Path, metadata: &Metadata) -> Attrs {
+    let ty = if metadata.is_dir() {
+        NF3DIR
+    } else if metadata.is_symlink() {
+        NF3LNK
+    } else {
+        NF3REG
+    };
+    let modified = timestamp(metadata.modified());
+    let changed = metadata
+        .changed()
+        .map_or(modified, |changed| timestamp(Ok(changed)));
+    Attrs {
+        ty,
+        mode: metadata.mode() & 0o7777,
+        nlink: metadata.nlink().map_or(1, |nlink| nlink as u32),
+        uid: metadata.uid().unwrap_or(0),
+        gid: metadata.gid().unwrap_or(0),
+        size: metadata.len(),
+        fileid: metadata.ino().unwrap_or_else(|| path_hash(path)),
+        times: [timestamp(metadata.accessed()), modified, changed],
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn timestamp(time: io::Result<SystemTime>) -> (u32, u32) {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |elapsed| {
+            (elapsed.as_secs() as u32, elapsed.subsec_nanos())
+        })
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn permissions(mode: u32) -> io::Result<std::fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn permissions(_: u32) -> io::Result<std::fs::Permissions> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "permissions can only be set on unix",
+    ))
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "unexpected response from inner service",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    /// Calls `procedure` of `program`, returning the reply following the accept status
+    async fn call(
+        stream: &mut DuplexStream,
+        program: u32,
+        procedure: u32,
+        args: impl FnOnce(Writer) -> Writer,
+    ) -> io::Result<Vec<u8>> {
+        let record = Writer::default().u32(9).u32(CALL).u32(RPC_VERSION);
+        let record = record.u32(program).u32(VERSION).u32(procedure);
+        // Unix credentials, then an empty verifier
+        let record = record.u32(AUTH_UNIX).opaque(&[0; 20]).u32(AUTH_NONE).u32(0);
+        args(record).send(stream).await?;
+        let reply = receive(stream).await?.ok_or_else(unexpected_response)?;
+        let mut reader = Reader(&reply);
+        assert_eq!((reader.u32()?, reader.u32()?, reader.u32()?), (9, REPLY, 0));
+        let _verifier = (reader.u32()?, reader.opaque()?);
+        assert_eq!(reader.u32()?, SUCCESS);
+        Ok(reader.0.to_vec())
+    }
+
+    /// The name, cookie and handle of a directory entry
+    type Entry = (String, u64, Vec<u8>);
+
+    /// Reads the names, cookies and (for `READDIRPLUS`) handles of the entries in a successful
+    /// reply to `READDIR` or `READDIRPLUS`, and whether the directory was read to the end
+    fn entries(reply: &[u8], plus: bool) -> io::Result<(Vec<Entry>, bool)> {
+        let mut reader = Reader(reply);
+        assert_eq!((reader.u32()?, reader.bool()?), (0, true));
+        // The directory's attributes, then the cookie verifier
+        for _ in 0..21 + 2 {
+            reader.u32()?;
+        }
+        let mut entries = Vec::new();
+        while reader.bool()? {
+            let _fileid = reader.u64()?;
+            let (name, cookie) = (reader.string()?, reader.u64()?);
+            let mut handle = Vec::new();
+            if plus {
+                assert!(reader.bool()?);
+                for _ in 0..21 {
+                    reader.u32()?;
+                }
+                assert!(reader.bool()?);
+                handle = reader.opaque()?.to_vec();
+            }
+            entries.push((name, cookie, handle));
+        }
+        Ok((entries, reader.bool()?))
+    }
+
+    #[tokio::test]
+    async fn test_session() -> io::Result<()> {
+        let dir = TestDir::new("nfs")?;
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(server, FileSystem::new()));
+
+        let mount = |args: Writer| args.string(&dir.to_string_lossy());
+        let reply = call(&mut client, MOUNT_PROGRAM, MOUNTPROC_MNT, mount).await?;
+        let mut reader = Reader(&reply);
+        assert_eq!(reader.u32()?, 0);
+        let root = reader.opaque()?.to_vec();
+        let name = |args: Writer| args.opaque(&root).string("file.txt");
+
+        // Create a file and write to it
+        let create = |args: Writer| name(args).u32(1).zeros(6);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_CREATE, create).await?;
+        let mut reader = Reader(&reply);
+        assert_eq!((reader.u32()?, reader.bool()?), (0, true));
+        let file = reader.opaque()?.to_vec();
+        for (offset, data) in [(0, &b"hello "[..]), (6, b"nfs")] {
+            let write = |args: Writer| args.opaque(&file).u64(offset).u32(3).u32(0).opaque(data);
+            let reply = call(&mut client, NFS_PROGRAM, NFSPROC_WRITE, write).await?;
+            assert_eq!(Reader(&reply).u32()?, 0);
+        }
+        // Writing anywhere but the end isn't supported
+        let write = |args: Writer| args.opaque(&file).u64(0).u32(1).u32(0).opaque(b"j");
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_WRITE, write).await?;
+        assert_eq!(Reader(&reply).u32()?, 10004);
+
+        // Then look it up again and read it
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_LOOKUP, name).await?;
+        let mut reader = Reader(&reply);
+        assert_eq!(reader.u32()?, 0);
+        assert_eq!(reader.opaque()?, file);
+        assert!(reader.bool()?);
+        // The type, then the mode, links and owner before the size
+        assert_eq!(reader.u32()?, NF3REG);
+        for _ in 0..4 {
+            reader.u32()?;
+        }
+        assert_eq!(reader.u64()?, 9);
+        let read = |args: Writer| args.opaque(&file).u64(6).u32(100);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_READ, read).await?;
+        let mut reader = Reader(&reply);
+        assert_eq!((reader.u32()?, reader.bool()?), (0, false));
+        assert_eq!((reader.u32()?, reader.bool()?), (3, true));
+        assert_eq!(reader.opaque()?, b"nfs");
+
+        // List the directory, a page at a time
+        std::fs::write(dir.join("b.txt"), "b")?;
+        let root = root.as_slice();
+        let readdir = |cookie| move |args: Writer| args.opaque(root).u64(cookie).u64(0).u32(4096);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_READDIR, readdir(0)).await?;
+        let (listed, eof) = entries(&reply, false)?;
+        let names: Vec<_> = listed
+            .iter()
+            .map(|(name, cookie, _)| (name.as_str(), *cookie))
+            .collect();
+        assert_eq!(
+            (names.as_slice(), eof),
+            (&[("b.txt", 1), ("file.txt", 2)][..], true)
+        );
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_READDIR, readdir(1)).await?;
+        let (listed, eof) = entries(&reply, false)?;
+        assert_eq!(
+            (listed.len(), listed[0].0.as_str(), eof),
+            (1, "file.txt", true)
+        );
+        // Too little room for more than the first entry
+        let plus = |args: Writer| args.opaque(root).u64(0).u64(0).u32(8).u32(4096);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_READDIRPLUS, plus).await?;
+        let (listed, eof) = entries(&reply, true)?;
+        assert_eq!(
+            (listed.len(), listed[0].0.as_str(), eof),
+            (1, "b.txt", false)
+        );
+        let getattr = |args: Writer| args.opaque(&listed[0].2);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_GETATTR, getattr).await?;
+        let mut reader = Reader(&reply);
+        assert_eq!((reader.u32()?, reader.u32()?), (0, NF3REG));
+
+        // Remove it, after which looking it up fails
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_REMOVE, name).await?;
+        assert_eq!(Reader(&reply).u32()?, 0);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_LOOKUP, name).await?;
+        assert_eq!(reply.len(), 8);
+        assert_eq!(Reader(&reply).u32()?, 2);
+        let getattr = |args: Writer| args.opaque(&[0; 8]);
+        let reply = call(&mut client, NFS_PROGRAM, NFSPROC_GETATTR, getattr).await?;
+        assert_eq!(Reader(&reply).u32()?, 70);
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! Message encoding for ONC RPC, which is big endian XDR padded to four bytes and sent over streams
+//! as records split into fragments
+
+use std::io::{self, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The largest record accepted from clients, leaving room for a write of
+/// [`MAX_IO`](super::MAX_IO) bytes and it's arguments
+const MAX_RECORD: u32 = super::MAX_IO + 4096;
+const LAST_FRAGMENT: u32 = 1 << 31;
+
+pub(super) const RPC_VERSION: u32 = 2;
+pub(super) const CALL: u32 = 0;
+pub(super) const REPLY: u32 = 1;
+pub(super) const MSG_ACCEPTED: u32 = 0;
+pub(super) const MSG_DENIED: u32 = 1;
+pub(super) const RPC_MISMATCH: u32 = 0;
+pub(super) const AUTH_NONE: u32 = 0;
+pub(super) const AUTH_UNIX: u32 = 1;
+
+pub(super) const SUCCESS: u32 = 0;
+pub(super) const PROG_UNAVAIL: u32 = 1;
+pub(super) const PROG_MISMATCH: u32 = 2;
+pub(super) const PROC_UNAVAIL: u32 = 3;
+pub(super) const GARBAGE_ARGS: u32 = 4;
+
+pub(super) const NFS_PROGRAM: u32 = 100_003;
+pub(super) const MOUNT_PROGRAM: u32 = 100_005;
+/// The version of both NFS and it's mount protocol which is served
+pub(super) const VERSION: u32 = 3;
+
+pub(super) const MOUNTPROC_NULL: u32 = 0;
+pub(super) const MOUNTPROC_MNT: u32 = 1;
+pub(super) const MOUNTPROC_DUMP: u32 = 2;
+pub(super) const MOUNTPROC_UMNT: u32 = 3;
+pub(super) const MOUNTPROC_UMNTALL: u32 = 4;
+pub(super) const MOUNTPROC_EXPORT: u32 = 5;
+
+pub(super) const NFSPROC_NULL: u32 = 0;
+pub(super) const NFSPROC_GETATTR: u32 = 1;
+pub(super) const NFSPROC_SETATTR: u32 = 2;
+pub(super) const NFSPROC_LOOKUP: u32 = 3;
+pub(super) const NFSPROC_ACCESS: u32 = 4;
+pub(super) const NFSPROC_READLINK: u32 = 5;
+pub(super) const NFSPROC_READ: u32 = 6;
+pub(super) const NFSPROC_WRITE: u32 = 7;
+pub(super) const NFSPROC_CREATE: u32 = 8;
+pub(super) const NFSPROC_MKDIR: u32 = 9;
+pub(super) const NFSPROC_SYMLINK: u32 = 10;
+pub(super) const NFSPROC_MKNOD: u32 = 11;
+pub(super) const NFSPROC_REMOVE: u32 = 12;
+pub(super) const NFSPROC_RMDIR: u32 = 13;
+pub(super) const NFSPROC_RENAME: u32 = 14;
+pub(super) const NFSPROC_LINK: u32 = 15;
+pub(super) const NFSPROC_READDIR: u32 = 16;
+pub(super) const NFSPROC_READDIRPLUS: u32 = 17;
+pub(super) const NFSPROC_FSSTAT: u32 = 18;
+pub(super) const NFSPROC_FSINFO: u32 = 19;
+pub(super) const NFSPROC_PATHCONF: u32 = 20;
+pub(super) const NFSPROC_COMMIT: u32 = 21;
+
+/// File types of `fattr3`
+pub(super) const NF3REG: u32 = 1;
+pub(super) const NF3DIR: u32 = 2;
+pub(super) const NF3LNK: u32 = 5;
+
+/// How `CREATE` treats existing files
+pub(super) const UNCHECKED: u32 = 0;
+/// `sattr3`'s choices for setting times
+pub(super) const SET_TO_CLIENT_TIME: u32 = 2;
+/// The stability `WRITE` reports, since writes aren't cached
+pub(super) const FILE_SYNC: u32 = 2;
+
+pub(super) const FSF_LINK: u32 = 0x01;
+pub(super) const FSF_SYMLINK: u32 = 0x02;
+pub(super) const FSF_HOMOGENEOUS: u32 = 0x08;
+
+/// Builds the body of a record
+#[derive(Debug, Default)]
+pub(super) struct Writer(Vec<u8>);
+
+impl Writer {
+    pub(super) fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(super) fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub(super) fn bool(self, value: bool) -> Self {
+        self.u32(value.into())
+    }
+
+    /// Writes variable length opaque data, which is prefixed by it's length and padded
+    pub(super) fn opaque(self, value: &[u8]) -> Self {
+        let mut writer = self.u32(u32::try_from(value.len()).unwrap_or(u32::MAX));
+        writer.0.extend_from_slice(value);
+        writer.0.resize(writer.0.len().next_multiple_of(4), 0);
+        writer
+    }
+
+    pub(super) fn string(self, value: &str) -> Self {
+        self.opaque(value.as_bytes())
+    }
+
+    /// Writes `count` zeros, which is how absent optional results are encoded
+    pub(super) fn zeros(mut self, count: usize) -> Self {
+        for _ in 0..count {
+            self = self.u32(0);
+        }
+        self
+    }
+
+    /// Writes the body to `stream` as a single record
+    pub(super) async fn send<S: AsyncWrite + Unpin>(self, stream: &mut S) -> io::Result<()> {
+        let len = u32::try_from(self.0.len())
+            .ok()
+            .filter(|len| *len < LAST_FRAGMENT)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "record too large"))?;
+        let mut record = Vec::with_capacity(self.0.len() + 4);
+        record.extend_from_slice(&(len | LAST_FRAGMENT).to_be_bytes());
+        record.extend_from_slice(&self.0);
+        stream.write_all(&record).await?;
+        stream.flush().await
+    }
+}
+
+/// Reads a record from `stream`, joining it's fragments, or returns `None` if the stream ended
+/// between records
+pub(super) async fn receive<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Vec<u8>>> {
+    let mut record = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof && record.is_empty() => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        }
+        let header = u32::from_be_bytes(header);
+        let len = (header & !LAST_FRAGMENT) as usize;
+        if record.len() + len > MAX_RECORD as usize {
+            return Err(io::Error::new(ErrorKind::InvalidData, "record too large"));
+        }
+        let start = record.len();
+        record.resize(start + len, 0);
+        stream.read_exact(&mut record[start..]).await?;
+        if header & LAST_FRAGMENT != 0 {
+            return Ok(Some(record));
+        }
+    }
+}
+
+/// Parses the body of a record
+#[derive(Debug)]
+pub(super) struct Reader<'a>(pub(super) &'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated record"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(super) fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(super) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from(self.u32()?) << 32 | u64::from(self.u32()?))
+    }
+
+    pub(super) fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u32()? != 0)
+    }
+
+    pub(super) fn opaque(&mut self) -> io::Result<&[u8]> {
+        let len = self.u32()? as usize;
+        let padded = len
+            .checked_next_multiple_of(4)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "truncated record"))?;
+        Ok(&self.take(padded)?[..len])
+    }
+
+    pub(super) fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.opaque()?.to_vec())
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+/// Converts an [`io::Error`] into the `nfsstat3` sent in replies, which the mount protocol's
+/// `mountstat3` shares
+pub(super) fn status(err: &io::Error) -> u32 {
+    match err.kind() {
+        ErrorKind::PermissionDenied => 13,
+        ErrorKind::NotFound => 2,
+        ErrorKind::AlreadyExists => 17,
+        ErrorKind::CrossesDevices => 18,
+        ErrorKind::NotADirectory => 20,
+        ErrorKind::IsADirectory => 21,
+        ErrorKind::InvalidInput | ErrorKind::InvalidFilename => 22,
+        ErrorKind::FileTooLarge => 27,
+        ErrorKind::StorageFull => 28,
+        ErrorKind::ReadOnlyFilesystem => 30,
+        ErrorKind::TooManyLinks => 31,
+        ErrorKind::DirectoryNotEmpty => 66,
+        ErrorKind::QuotaExceeded => 69,
+        ErrorKind::StaleNetworkFileHandle => 70,
+        ErrorKind::Unsupported => 10004,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() -> io::Result<()> {
+        let mut buffer = Vec::new();
+        Writer::default()
+            .u32(7)
+            .u64(1 << 40)
+            .bool(true)
+            .string("/tmp/a")
+            .opaque(b"data")
+            .send(&mut buffer)
+            .await?;
+        // The string is padded to eight bytes
+        assert_eq!(buffer.len(), 4 + 4 + 8 + 4 + 4 + 8 + 4 + 4);
+
+        let Some(record) = receive(&mut buffer.as_slice()).await? else {
+            unreachable!("a record was sent")
+        };
+        let mut reader = Reader(&record);
+        assert_eq!((reader.u32()?, reader.u64()?), (7, 1 << 40));
+        assert!(reader.bool()?);
+        assert_eq!(reader.string()?, "/tmp/a");
+        assert_eq!(reader.opaque()?, b"data");
+        assert!(reader.u32().is_err());
+        assert!(receive(&mut [].as_slice()).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fragments() -> io::Result<()> {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&2_u32.to_be_bytes());
+        stream.extend_from_slice(b"ab");
+        stream.extend_from_slice(&(1 | LAST_FRAGMENT).to_be_bytes());
+        stream.extend_from_slice(b"c");
+        assert_eq!(
+            receive(&mut stream.as_slice()).await?,
+            Some(b"abc".to_vec())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,877 @@
+//! Serving a service stack over the 9P2000.L protocol
+//!
+//! [`serve`] answers 9P2000.L messages read from a byte stream by translating them into requests
+//! against a service, so Linux clients (including QEMU guests using virtio-9p) can mount any
+//! backend and middleware stack as a network file system.  Accepting connections is left to the
+//! application, for example mount a unix socket served by [`serve`] with
+//! `mount -t 9p -o trans=unix,version=9p2000.L,cache=none /run/app.sock /mnt`.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::poll_fn,
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tower_service::Service;
+
+use crate::{FileHandle, Metadata, Mode, Request, Response};
+use wire::{
+    errno, receive, Qid, Reader, Writer, AT_REMOVEDIR, GETATTR_BASIC, HEADER_LEN, O_ACCMODE,
+    O_APPEND, O_EXCL, O_RDONLY, O_TRUNC, O_WRONLY, QT_DIR, QT_FILE, QT_SYMLINK, RLERROR,
+    SETATTR_MODE, SETATTR_SIZE, S_IFDIR, S_IFREG, TATTACH, TCLUNK, TFLUSH, TFSYNC, TGETATTR,
+    TLCREATE, TLINK, TLOPEN, TMKDIR, TREAD, TREADDIR, TREADLINK, TREMOVE, TRENAME, TRENAMEAT,
+    TSETATTR, TSTATFS, TSYMLINK, TUNLINKAT, TVERSION, TWALK, TWRITE, V9FS_MAGIC, VERSION,
+};
+
+mod wire;
+
+/// The largest message size negotiated with clients
+const MAX_MSIZE: u32 = 512 * 1024;
+
+/// Answers the 9P2000.L messages read from `stream` with `service` until the client closes the
+/// stream
+///
+/// The file system is attached at `/`, whatever name the client asks for, and paths are passed to
+/// `service` as absolute paths, so serve a directory by wrapping the service in
+/// [`Root`](crate::middleware::root::Root).  Messages are answered in order, so `Tflush` has
+/// nothing to cancel.
+///
+/// Files are read and written through [`Request::Open`] where the service supports it, and
+/// otherwise with [`Request::ReadRange`], or buffered in memory and written with
+/// [`Request::WriteBytes`] once the client clunks them.  Like [`Mode`], files can only be opened
+/// for reading, or for writing with `O_TRUNC` or `O_APPEND`.  `Tgetattr` is answered with
+/// [`Request::GetMetadata`], falling back to reading the whole file to find it's size.
+///
+/// Directories are listed with [`Request::ReadDir`] when they're opened, and `Treaddir` reads
+/// that listing.  `Tsetattr` only changes permissions or truncates files to nothing, ignoring other
+/// attributes, and new files and directories get the service's default permissions.  Authentication, extended attributes and
+/// locks aren't supported.
+///
+/// # Errors
+///
+/// If reading from or writing to `stream` fails, or a message is malformed or too large.  Errors
+/// from `service` are sent to the client instead.
+pub async fn serve<T, S>(mut stream: T, service: S) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut server = Server {
+        service,
+        msize: MAX_MSIZE,
+        fids: HashMap::new(),
+    };
+    while let Some((ty, tag, body)) = receive(&mut stream, server.msize).await? {
+        let (ty, reply) = match server.handle(ty, &mut Reader(&body)).await {
+            Ok(reply) => (ty + 1, reply),
+            Err(err) => (RLERROR, Writer::default().u32(errno(&err))),
+        };
+        reply.send(&mut stream, ty, tag).await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Server<S> {
+    service: S,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+/// A file the client has walked to
+#[derive(Debug)]
+struct Fid {
+    path: PathBuf,
+    qid: Qid,
+    open: Option<Handle>,
+}
+
+#[derive(Debug)]
+enum Handle {
+    /// A file opened by the service
+    File(FileHandle),
+    /// A file read with [`Request::ReadRange`], for services which can't open files
+    Read,
+    /// A file written with [`Request::WriteBytes`] once it's clunked, for services which can't open
+    /// files
+    Write(Vec<u8>),
+    /// A directory's entries as they were when it was opened, or `None` for services which can't
+    /// list directories
+    Directory(Option<Vec<(PathBuf, Metadata)>>),
+}
+
+impl<S> Server<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    /// Handles a message, returning the body of the reply
+    async fn handle(&mut self, ty: u8, reader: &mut Reader<'_>) -> io::Result<Writer> {
+        let reply = Writer::default();
+        match ty {
+            TVERSION => {
+                let (msize, version) = (reader.u32()?, reader.string()?);
+                self.fids.clear();
+                self.msize = msize.clamp(HEADER_LEN + 64, MAX_MSIZE);
+                let version = if version.starts_with(VERSION) {
+                    VERSION
+                } else {
+                    "unknown"
+                };
+                Ok(reply.u32(self.msize).string(version))
+            }
+            TATTACH => {
+                let fid = reader.u32()?;
+                let path = PathBuf::from("/");
+                let qid = self.qid(&path).await?;
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        path,
+                        qid,
+                        open: None,
+                    },
+                );
+                Ok(reply.qid(&qid))
+            }
+            TWALK => self.walk(reader, reply).await,
+            TLOPEN => {
+                let (fid, flags) = (reader.u32()?, reader.u32()?);
+                let qid = self.open(fid, flags).await?;
+                Ok(reply.qid(&qid).u32(0))
+            }
+            TLCREATE => {
+                let (fid, name, flags) = (reader.u32()?, reader.string()?, reader.u32()?);
+                let qid = self.create(fid, &name, flags).await?;
+                Ok(reply.qid(&qid).u32(0))
+            }
+            TREAD => {
+                let (fid, offset, count) = (reader.u32()?, reader.u64()?, reader.u32()?);
+                let count = count.min(self.msize - HEADER_LEN - 4);
+                let data = self.read(fid, offset, count).await?;
+                Ok(reply.data(&data))
+            }
+            TREADDIR => {
+                let (fid, offset, count) = (reader.u32()?, reader.u64()?, reader.u32()?);
+                let count = count.min(self.msize - HEADER_LEN - 4);
+                self.read_dir(fid, offset, count, reply)
+            }
+            TWRITE => {
+                let (fid, offset, data) = (reader.u32()?, reader.u64()?, reader.data()?);
+                self.write(fid, offset, data).await?;
+                Ok(reply.u32(u32::try_from(data.len()).unwrap_or(u32::MAX)))
+            }
+            TCLUNK => {
+                let fid = self.fids.remove(&reader.u32()?).ok_or_else(unknown_fid)?;
+                self.close(fid).await?;
+                Ok(reply)
+            }
+            TGETATTR => {
+                let fid = self.fid(reader.u32()?)?;
+                let (path, qid) = (fid.path.clone(), fid.qid);
+                self.getattr(path, qid, reply).await
+            }
+            TSTATFS => {
+                self.fid(reader.u32()?)?;
+                // Type, block size, then counts of blocks and files which aren't known
+                let mut reply = reply.u32(V9FS_MAGIC).u32(4096);
+                for _ in 0..6 {
+                    reply = reply.u64(0);
+                }
+                Ok(reply.u32(255))
+            }
+            TFSYNC => {
+                if let Some(Handle::File(file)) = &mut self.fid_mut(reader.u32()?)?.open {
+                    file.sync_all().await?;
+                }
+                Ok(reply)
+            }
+            TREADLINK => {
+                let path = self.fid(reader.u32()?)?.path.clone();
+                match self.call(Request::FollowLink(path.into())).await? {
+                    Response::PointsTo(target) => Ok(reply.string(&target.to_string_lossy())),
+                    _ => Err(unexpected_response()),
+                }
+            }
+            TFLUSH => Ok(reply),
+            _ => self.modify(ty, reader, reply).await,
+        }
+    }
+
+    /// Handles the messages which change the file system, besides writes
+    async fn modify(
+        &mut self,
+        ty: u8,
+        reader: &mut Reader<'_>,
+        reply: Writer,
+    ) -> io::Result<Writer> {
+        match ty {
+            TMKDIR => {
+                let path = self.child(reader.u32()?, &reader.string()?)?;
+                let req = Request::CreateDir {
+                    path: path.as_path().into(),
+                    recursive: false,
+                };
+                self.call(req).await?;
+                Ok(reply.qid(&self.qid(&path).await?))
+            }
+            TSYMLINK => {
+                let dst = self.child(reader.u32()?, &reader.string()?)?;
+                let src = PathBuf::from(reader.string()?);
+                #[cfg(unix)]
+                let req = Request::Symlink {
+                    src: src.into(),
+                    dst: dst.as_path().into(),
+                };
+                #[cfg(windows)]
+                let req = Request::SymlinkFile {
+                    src: src.into(),
+                    dst: dst.as_path().into(),
+                };
+                self.call(req).await?;
+                Ok(reply.qid(&self.qid(&dst).await?))
+            }
+            TLINK => {
+                let (dir, fid, name) = (reader.u32()?, reader.u32()?, reader.string()?);
+                let dst = self.child(dir, &name)?;
+                let src = self.fid(fid)?.path.clone();
+                self.call(Request::HardLink {
+                    src: src.into(),
+                    dst: dst.into(),
+                })
+                .await?;
+                Ok(reply)
+            }
+            TRENAME => {
+                let (fid, dir, name) = (reader.u32()?, reader.u32()?, reader.string()?);
+                let to = self.child(dir, &name)?;
+                let from = self.fid(fid)?.path.clone();
+                let req = Request::Rename {
+                    from: from.into(),
+                    to: to.as_path().into(),
+                };
+                self.call(req).await?;
+                self.fid_mut(fid)?.path = to;
+                Ok(reply)
+            }
+            TRENAMEAT => {
+                let from = self.child(reader.u32()?, &reader.string()?)?;
+                let to = self.child(reader.u32()?, &reader.string()?)?;
+                self.call(Request::Rename {
+                    from: from.into(),
+                    to: to.into(),
+                })
+                .await?;
+                Ok(reply)
+            }
+            TUNLINKAT => {
+                let path = self.child(reader.u32()?, &reader.string()?)?;
+                let req = if reader.u32()? & AT_REMOVEDIR == 0 {
+                    Request::RemoveFile(path.into())
+                } else {
+                    Request::RemoveDir {
+                        path: path.into(),
+                        recursive: false,
+                    }
+                };
+                self.call(req).await?;
+                Ok(reply)
+            }
+            TREMOVE => {
+                // The fid is clunked even if the removal fails
+                let fid = self.fids.remove(&reader.u32()?).ok_or_else(unknown_fid)?;
+                let (path, is_dir) = (fid.path.clone(), fid.qid.ty == QT_DIR);
+                self.close(fid).await?;
+                let req = if is_dir {
+                    Request::RemoveDir {
+                        path: path.into(),
+                        recursive: false,
+                    }
+                } else {
+                    Request::RemoveFile(path.into())
+                };
+                self.call(req).await?;
+                Ok(reply)
+            }
+            TSETATTR => {
+                let (fid, valid, mode) = (reader.u32()?, reader.u32()?, reader.u32()?);
+                let (_uid, _gid, size) = (reader.u32()?, reader.u32()?, reader.u64()?);
+                self.setattr(fid, valid, mode, size).await?;
+                Ok(reply)
+            }
+            // Including authentication, extended attributes and locks
+            _ => Err(ErrorKind::Unsupported.into()),
+        }
+    }
+
+    async fn call(&mut self, req: Request) -> io::Result<Response> {
+        poll_fn(|cx| self.service.poll_ready(cx)).await?;
+        self.service.call(req).await
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(unknown_fid)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(unknown_fid)
+    }
+
+    /// The path of `name` in the directory `dir` refers to
+    fn child(&self, dir: u32, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid file name"));
+        }
+        Ok(self.fid(dir)?.path.join(name))
+    }
+
+    /// Identifies the file at `path`, without following symbolic links
+    async fn qid(&mut self, path: &Path) -> io::Result<Qid> {
+        let req = Request::GetMetadata {
+            path: path.into(),
+            follow_symlinks: false,
+        };
+        match self.call(req).await {
+            Ok(Response::Metadata(metadata)) => Ok(metadata_qid(path, &metadata)),
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => self.probe(path).await,
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Identifies the file at `path` on services without metadata, by reading nothing from it
+    async fn probe(&mut self, path: &Path) -> io::Result<Qid> {
+        let req = Request::ReadRange {
+            path: path.into(),
+            range: 0..0,
+        };
+        let ty = match self.call(req).await {
+            Ok(_) => QT_FILE,
+            Err(err) if err.kind() == ErrorKind::IsADirectory => QT_DIR,
+            // Directories are usually implicit on services without metadata
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                match self.call(Request::Exists(path.into())).await? {
+                    Response::Exists(exists) if exists || path.parent().is_none() => QT_DIR,
+                    Response::Exists(_) => return Err(err),
+                    _ => return Err(unexpected_response()),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Qid {
+            ty,
+            version: 0,
+            path: path_hash(path),
+        })
+    }
+
+    async fn walk(&mut self, reader: &mut Reader<'_>, reply: Writer) -> io::Result<Writer> {
+        let (fid, new_fid, count) = (reader.u32()?, reader.u32()?, reader.u16()?);
+        let names = (0..count)
+            .map(|_| reader.string())
+            .collect::<io::Result<Vec<_>>>()?;
+        if fid != new_fid && self.fids.contains_key(&new_fid) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "fid already in use",
+            ));
+        }
+        let start = self.fid(fid)?;
+        let (mut path, mut qid) = (start.path.clone(), start.qid);
+        let mut qids = Vec::new();
+        for name in &names {
+            let next = if name == ".." {
+                path.parent().unwrap_or(&path).to_owned()
+            } else {
+                if name.is_empty() || name == "." || name.contains('/') {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "invalid file name"));
+                }
+                path.join(name)
+            };
+            match self.qid(&next).await {
+                Ok(next_qid) => {
+                    (path, qid) = (next, next_qid);
+                    qids.push(qid);
+                }
+                Err(err) if qids.is_empty() => return Err(err),
+                // Walking part of the way succeeds, without creating the new fid
+                Err(_) => break,
+            }
+        }
+        if qids.len() == names.len() {
+            self.fids.insert(
+                new_fid,
+                Fid {
+                    path,
+                    qid,
+                    open: None,
+                },
+            );
+        }
+        let mut reply = reply.u16(u16::try_from(qids.len()).unwrap_or(u16::MAX));
+        for qid in &qids {
+            reply = reply.qid(qid);
+        }
+        Ok(reply)
+    }
+
+    async fn open(&mut self, fid: u32, flags: u32) -> io::Result<Qid> {
+        let entry = self.fid(fid)?;
+        if entry.open.is_some() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "fid already open"));
+        }
+        let (path, qid) = (entry.path.clone(), entry.qid);
+        let handle = if qid.ty == QT_DIR {
+            match self.call(Request::ReadDir(path.into())).await {
+                Ok(Response::Directory(entries)) => Handle::Directory(Some(entries)),
+                Ok(_) => return Err(unexpected_response()),
+                // The files in the directory can still be walked to
+                Err(err) if err.kind() == ErrorKind::Unsupported => Handle::Directory(None),
+                Err(err) => return Err(err),
+            }
+        } else {
+            let mode = match (flags & O_ACCMODE, flags & (O_TRUNC | O_APPEND)) {
+                (O_RDONLY, _) => Mode::Read,
+                (O_WRONLY, O_TRUNC) => Mode::CreateOrOverwrite,
+                (O_WRONLY, O_APPEND) => Mode::AppendExisting,
+                _ => {
+                    return Err(io::Error::new(
+                        ErrorKind::Unsupported,
+                        "files can only be opened for reading, truncating or appending",
+                    ))
+                }
+            };
+            self.open_file(path, mode).await?
+        };
+        self.fid_mut(fid)?.open = Some(handle);
+        Ok(qid)
+    }
+
+    async fn create(&mut self, fid: u32, name: &str, flags: u32) -> io::Result<Qid> {
+        if self.fid(fid)?.open.is_some() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "fid already open"));
+        }
+        let path = self.child(fid, name)?;
+        let mode = if flags & O_EXCL == 0 {
+            Mode::CreateOrOverwrite
+        } else {
+            Mode::CreateNew
+        };
+        let handle = self.open_file(path.clone(), mode).await?;
+        // Buffered files don't exist until they're clunked
+        let qid = match self.qid(&path).await {
+            Ok(qid) => qid,
+            Err(_) => Qid {
+                ty: QT_FILE,
+                version: 0,
+                path: path_hash(&path),
+            },
+        };
+        *self.fid_mut(fid)? = Fid {
+            path,
+            qid,
+            open: Some(handle),
+        };
+        Ok(qid)
+    }
+
+    async fn open_file(&mut self, path: PathBuf, mode: Mode) -> io::Result<Handle> {
+        let open = Request::Open {
+            mode,
+            path: path.as_path().into(),
+        };
+        match self.call(open).await {
+            Ok(Response::File(file)) => Ok(Handle::File(file)),
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => match mode {
+                Mode::Read => Ok(Handle::Read),
+                Mode::CreateNew => match self.call(Request::Exists(path.into())).await? {
+                    Response::Exists(true) => Err(ErrorKind::AlreadyExists.into()),
+                    Response::Exists(false) => Ok(Handle::Write(Vec::new())),
+                    _ => Err(unexpected_response()),
+                },
+                Mode::CreateOrOverwrite => Ok(Handle::Write(Vec::new())),
+                Mode::AppendExisting | Mode::CreateOrAppend => Err(err),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn close(&mut self, fid: Fid) -> io::Result<()> {
+        match fid.open {
+            Some(Handle::File(mut file)) => file.flush().await,
+            Some(Handle::Write(bytes)) => self
+                .call(Request::WriteBytes {
+                    path: fid.path.into(),
+                    bytes,
+                })
+                .await
+                .map(drop),
+            Some(Handle::Read | Handle::Directory(_)) | None => Ok(()),
+        }
+    }
+
+    async fn read(&mut self, fid: u32, offset: u64, count: u32) -> io::Result<Vec<u8>> {
+        let entry = self.fid_mut(fid)?;
+        match &mut entry.open {
+            Some(Handle::File(file)) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = Vec::new();
+                file.take(u64::from(count)).read_to_end(&mut data).await?;
+                Ok(data)
+            }
+            Some(Handle::Read) => {
+                let path = entry.path.clone();
+                let range = offset..offset.saturating_add(u64::from(count));
+                match self
+                    .call(Request::ReadRange {
+                        path: path.into(),
+                        range,
+                    })
+                    .await?
+                {
+                    Response::Bytes(bytes) => Ok(bytes),
+                    _ => Err(unexpected_response()),
+                }
+            }
+            Some(Handle::Directory(_)) => Err(ErrorKind::IsADirectory.into()),
+            Some(Handle::Write(_)) | None => Err(not_open()),
+        }
+    }
+
+    /// Appends the entries of an open directory from `offset` to `reply`, as many as fit in
+    /// `count` bytes, each with the offset of the entry after it
+    fn read_dir(&self, fid: u32, offset: u64, count: u32, reply: Writer) -> io::Result<Writer> {
+        let entry = self.fid(fid)?;
+        let entries = match &entry.open {
+            Some(Handle::Directory(Some(entries))) => entries,
+            Some(Handle::Directory(None)) => return Err(ErrorKind::Unsupported.into()),
+            _ => return Err(not_open()),
+        };
+        let skip = usize::try_from(offset).unwrap_or(usize::MAX);
+        let mut len = 0;
+        let mut dirents = Vec::new();
+        for (index, (name, metadata)) in entries.iter().enumerate().skip(skip) {
+            let name = name.to_string_lossy();
+            // The qid, offset, type and name
+            let dirent_len = 13 + 8 + 1 + 2 + name.len();
+            if len + dirent_len > count as usize {
+                break;
+            }
+            len += dirent_len;
+            let qid = metadata_qid(&entry.path.join(&*name), metadata);
+            // The file type bits of the mode, as in `d_type`
+            let ty = u8::try_from(metadata.mode() >> 12 & 0xf).unwrap_or_default();
+            dirents.push((qid, index as u64 + 1, ty, name));
+        }
+        let mut reply = reply.u32(u32::try_from(len).unwrap_or(u32::MAX));
+        for (qid, next, ty, name) in dirents {
+            reply = reply.qid(&qid).u64(next).u8(ty).string(&name);
+        }
+        Ok(reply)
+    }
+
+    async fn write(&mut self, fid: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        match &mut self.fid_mut(fid)?.open {
+            // Files opened for appending ignore the offset, as on Linux
+            Some(Handle::File(file)) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(data).await
+            }
+            Some(Handle::Write(bytes)) => {
+                let start = usize::try_from(offset).map_err(io::Error::other)?;
+                let end = start
+                    .checked_add(data.len())
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "offset too large"))?;
+                if bytes.len() < end {
+                    bytes.resize(end, 0);
+                }
+                bytes[start..end].copy_from_slice(data);
+                Ok(())
+            }
+            Some(Handle::Read | Handle::Directory(_)) | None => Err(not_open()),
+        }
+    }
+
+    async fn getattr(&mut self, path: PathBuf, qid: Qid, reply: Writer) -> io::Result<Writer> {
+        let req = Request::GetMetadata {
+            path: path.as_path().into(),
+            follow_symlinks: false,
+        };
+        let (mode, size, owner, times) = match self.call(req).await {
+            Ok(Response::Metadata(metadata)) => (
+                metadata.mode(),
+                metadata.len(),
+                (
+                    metadata.uid().unwrap_or(0),
+                    metadata.gid().unwrap_or(0),
+                    metadata.nlink().unwrap_or(1),
+                ),
+                [
+                    timestamp(metadata.accessed()),
+                    timestamp(metadata.modified()),
+                    timestamp(metadata.changed().or(metadata.modified())),
+                    timestamp(metadata.created()),
+                ],
+            ),
+            Ok(_) => return Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported && qid.ty == QT_DIR => {
+                (S_IFDIR | 0o755, 0, (0, 0, 1), [(0, 0); 4])
+            }
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                match self.call(Request::ReadBytes(path.into())).await? {
+                    Response::Bytes(bytes) => {
+                        (S_IFREG | 0o644, bytes.len() as u64, (0, 0, 1), [(0, 0); 4])
+                    }
+                    _ => return Err(unexpected_response()),
+                }
+            }
+            Err(err) => return Err(err),
+        };
+        let (uid, gid, nlink) = owner;
+        let mut reply = reply
+            .u64(GETATTR_BASIC)
+            .qid(&qid)
+            .u32(mode)
+            .u32(uid)
+            .u32(gid)
+            .u64(nlink)
+            // rdev, size, block size and blocks
+            .u64(0)
+            .u64(size)
+            .u64(4096)
+            .u64(size.div_ceil(512));
+        for (secs, nanos) in times {
+            reply = reply.u64(secs).u64(nanos);
+        }
+        // The generation and data version aren't tracked
+        Ok(reply.u64(0).u64(0))
+    }
+
+    async fn setattr(&mut self, fid: u32, valid: u32, mode: u32, size: u64) -> io::Result<()> {
+        let path = self.fid(fid)?.path.clone();
+        if valid & SETATTR_MODE != 0 {
+            let perm = permissions(mode)?;
+            self.call(Request::SetPermissions {
+                path: path.as_path().into(),
+                perm,
+            })
+            .await?;
+        }
+        if valid & SETATTR_SIZE != 0 {
+            if size != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "files can only be truncated to nothing",
+                ));
+            }
+            if let Some(Handle::Write(bytes)) = &mut self.fid_mut(fid)?.open {
+                bytes.clear();
+            } else {
+                let req = Request::WriteBytes {
+                    path: path.into(),
+                    bytes: Vec::new(),
+                };
+                self.call(req).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn path_hash(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn metadata_qid(path: &Path, metadata: &Metadata) -> Qid {
+    let ty = if metadata.is_dir() {
+        QT_DIR
+    } else if metadata.is_symlink() {
+        QT_SYMLINK
+    } else {
+        QT_FILE
+    };
+    Qid {
+        ty,
+        // Changes when the file is modified, so clients can tell their cache is stale
+        #[allow(clippy::cast_possible_truncation)]
+        version: timestamp(metadata.modified()).0 as u32,
+        path: metadata.ino().unwrap_or_else(|| path_hash(path)),
+    }
+}
+
+fn timestamp(time: io::Result<SystemTime>) -> (u64, u64) {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |elapsed| {
+            (elapsed.as_secs(), u64::from(elapsed.subsec_nanos()))
+        })
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn permissions(mode: u32) -> io::Result<std::fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn permissions(_: u32) -> io::Result<std::fs::Permissions> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "permissions can only be set on unix",
+    ))
+}
+
+fn unknown_fid() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "unknown fid")
+}
+
+fn not_open() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "fid not open for this")
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "unexpected response from inner service",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    async fn request(stream: &mut DuplexStream, ty: u8, body: Writer) -> io::Result<(u8, Vec<u8>)> {
+        body.send(stream, ty, 1).await?;
+        match receive(stream, MAX_MSIZE).await? {
+            Some((ty, 1, body)) => Ok((ty, body)),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Walks `fid` to `new_fid` through the components of `path`
+    fn walk(fid: u32, new_fid: u32, path: &Path) -> Writer {
+        let names: Vec<_> = path
+            .iter()
+            .filter_map(|name| name.to_str())
+            .filter(|name| *name != "/")
+            .collect();
+        let mut body = Writer::default()
+            .u32(fid)
+            .u32(new_fid)
+            .u16(u16::try_from(names.len()).unwrap_or(u16::MAX));
+        for name in names {
+            body = body.string(name);
+        }
+        body
+    }
+
+    /// The offset, type and name of each entry in the body of `Rreaddir`
+    fn dirents(body: &[u8]) -> io::Result<Vec<(u64, u8, String)>> {
+        let mut reader = Reader(body);
+        let len = reader.u32()? as usize;
+        assert_eq!(reader.0.len(), len);
+        let mut dirents = Vec::new();
+        while !reader.0.is_empty() {
+            // The qid
+            reader.0 = &reader.0[13..];
+            let offset = reader.u64()?;
+            let ty = reader.0[0];
+            reader.0 = &reader.0[1..];
+            dirents.push((offset, ty, reader.string()?));
+        }
+        Ok(dirents)
+    }
+
+    #[tokio::test]
+    async fn test_session() -> io::Result<()> {
+        let dir = TestDir::new("ninep")?;
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(server, FileSystem::new()));
+
+        let version = Writer::default().u32(8192).string(VERSION);
+        let (ty, body) = request(&mut client, TVERSION, version).await?;
+        assert_eq!(ty, TVERSION + 1);
+        assert_eq!(Reader(&body).u32()?, 8192);
+        let attach = Writer::default().u32(0).u32(u32::MAX).string("").string("");
+        let attach = attach.u32(0);
+        assert_eq!(request(&mut client, TATTACH, attach).await?.0, TATTACH + 1);
+
+        // Create a file in the directory and write to it
+        assert_eq!(
+            request(&mut client, TWALK, walk(0, 1, &dir)).await?.0,
+            TWALK + 1
+        );
+        let create = Writer::default().u32(1).string("file.txt").u32(O_WRONLY);
+        let create = create.u32(0o644).u32(0);
+        assert_eq!(
+            request(&mut client, TLCREATE, create).await?.0,
+            TLCREATE + 1
+        );
+        let write = Writer::default().u32(1).u64(0).data(b"hello 9p");
+        let (_, body) = request(&mut client, TWRITE, write).await?;
+        assert_eq!(Reader(&body).u32()?, 8);
+        request(&mut client, TCLUNK, Writer::default().u32(1)).await?;
+
+        // Then read it back
+        let file = dir.join("file.txt");
+        assert_eq!(
+            request(&mut client, TWALK, walk(0, 2, &file)).await?.0,
+            TWALK + 1
+        );
+        let getattr = Writer::default().u32(2).u64(GETATTR_BASIC);
+        let (_, body) = request(&mut client, TGETATTR, getattr).await?;
+        // The size follows the valid mask, qid, mode, owner, links and device
+        assert_eq!(Reader(&body[49..]).u64()?, 8);
+        let open = Writer::default().u32(2).u32(O_RDONLY);
+        assert_eq!(request(&mut client, TLOPEN, open).await?.0, TLOPEN + 1);
+        let read = Writer::default().u32(2).u64(6).u32(100);
+        let (_, body) = request(&mut client, TREAD, read).await?;
+        assert_eq!(Reader(&body).data()?, b"9p");
+
+        // List the directory, a page at a time
+        std::fs::create_dir(dir.join("nested"))?;
+        assert_eq!(
+            request(&mut client, TWALK, walk(0, 4, &dir)).await?.0,
+            TWALK + 1
+        );
+        let open = Writer::default().u32(4).u32(O_RDONLY);
+        assert_eq!(request(&mut client, TLOPEN, open).await?.0, TLOPEN + 1);
+        let readdir = |offset, count| Writer::default().u32(4).u64(offset).u32(count);
+        let (ty, body) = request(&mut client, TREADDIR, readdir(0, 4096)).await?;
+        assert_eq!(ty, TREADDIR + 1);
+        assert_eq!(
+            dirents(&body)?,
+            [
+                (1, 8, String::from("file.txt")),
+                (2, 4, String::from("nested"))
+            ]
+        );
+        // Only room for the first
+        let (_, body) = request(&mut client, TREADDIR, readdir(0, 40)).await?;
+        assert_eq!(dirents(&body)?, [(1, 8, String::from("file.txt"))]);
+        let (_, body) = request(&mut client, TREADDIR, readdir(2, 4096)).await?;
+        assert_eq!(dirents(&body)?, []);
+        request(&mut client, TCLUNK, Writer::default().u32(4)).await?;
+
+        // Remove it, after which walking stops at the directory
+        let remove = Writer::default().u32(2);
+        assert_eq!(request(&mut client, TREMOVE, remove).await?.0, TREMOVE + 1);
+        let (ty, body) = request(&mut client, TWALK, walk(0, 3, &file)).await?;
+        let walked = usize::from(Reader(&body).u16()?);
+        assert_eq!((ty, walked), (TWALK + 1, dir.iter().count() - 1));
+        let getattr = Writer::default().u32(3).u64(GETATTR_BASIC);
+        assert_eq!(request(&mut client, TGETATTR, getattr).await?.0, RLERROR);
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Message encoding for 9P2000.L, which is little endian with `u16` length prefixed strings
+
+use std::io::{self, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub(super) const VERSION: &str = "9P2000.L";
+/// The size of a message's header (size, type and tag)
+pub(super) const HEADER_LEN: u32 = 7;
+
+pub(super) const RLERROR: u8 = 7;
+pub(super) const TSTATFS: u8 = 8;
+pub(super) const TLOPEN: u8 = 12;
+pub(super) const TLCREATE: u8 = 14;
+pub(super) const TSYMLINK: u8 = 16;
+pub(super) const TRENAME: u8 = 20;
+pub(super) const TREADLINK: u8 = 22;
+pub(super) const TGETATTR: u8 = 24;
+pub(super) const TSETATTR: u8 = 26;
+pub(super) const TREADDIR: u8 = 40;
+pub(super) const TFSYNC: u8 = 50;
+pub(super) const TLINK: u8 = 70;
+pub(super) const TMKDIR: u8 = 72;
+pub(super) const TRENAMEAT: u8 = 74;
+pub(super) const TUNLINKAT: u8 = 76;
+pub(super) const TVERSION: u8 = 100;
+pub(super) const TATTACH: u8 = 104;
+pub(super) const TFLUSH: u8 = 108;
+pub(super) const TWALK: u8 = 110;
+pub(super) const TREAD: u8 = 116;
+pub(super) const TWRITE: u8 = 118;
+pub(super) const TCLUNK: u8 = 120;
+pub(super) const TREMOVE: u8 = 122;
+
+/// Linux open flags, as sent by `Tlopen` and `Tlcreate`
+pub(super) const O_ACCMODE: u32 = 0o3;
+pub(super) const O_RDONLY: u32 = 0o0;
+pub(super) const O_WRONLY: u32 = 0o1;
+pub(super) const O_EXCL: u32 = 0o200;
+pub(super) const O_TRUNC: u32 = 0o1000;
+pub(super) const O_APPEND: u32 = 0o2000;
+
+pub(super) const AT_REMOVEDIR: u32 = 0x200;
+
+pub(super) const SETATTR_MODE: u32 = 0x01;
+pub(super) const SETATTR_SIZE: u32 = 0x08;
+/// The fields of `Rgetattr` which are always filled in
+pub(super) const GETATTR_BASIC: u64 = 0x7ff;
+
+/// File type bits of `Rgetattr`'s mode, as in `st_mode`
+pub(super) const S_IFDIR: u32 = 0o040_000;
+pub(super) const S_IFREG: u32 = 0o100_000;
+
+/// The file system type `Rstatfs` reports
+pub(super) const V9FS_MAGIC: u32 = 0x0102_1997;
+
+pub(super) const QT_DIR: u8 = 0x80;
+pub(super) const QT_SYMLINK: u8 = 0x02;
+pub(super) const QT_FILE: u8 = 0x00;
+
+/// A server's unique identifier for a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Qid {
+    pub(super) ty: u8,
+    pub(super) version: u32,
+    pub(super) path: u64,
+}
+
+/// Builds the body of a message
+#[derive(Debug, Default)]
+pub(super) struct Writer(Vec<u8>);
+
+impl Writer {
+    pub(super) fn u8(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    pub(super) fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(super) fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(super) fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub(super) fn string(self, value: &str) -> Self {
+        let mut writer = self.u16(u16::try_from(value.len()).unwrap_or(u16::MAX));
+        writer.0.extend_from_slice(value.as_bytes());
+        writer
+    }
+
+    /// Writes `count[4] data[count]`, as `Rread` and `Twrite` do
+    pub(super) fn data(self, value: &[u8]) -> Self {
+        let mut writer = self.u32(u32::try_from(value.len()).unwrap_or(u32::MAX));
+        writer.0.extend_from_slice(value);
+        writer
+    }
+
+    pub(super) fn qid(self, qid: &Qid) -> Self {
+        self.u8(qid.ty).u32(qid.version).u64(qid.path)
+    }
+
+    /// Writes the body to `stream` as a message of type `ty`
+    pub(super) async fn send<S: AsyncWrite + Unpin>(
+        self,
+        stream: &mut S,
+        ty: u8,
+        tag: u16,
+    ) -> io::Result<()> {
+        let len = u32::try_from(self.0.len())
+            .ok()
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "message too large"))?;
+        let mut message = Vec::with_capacity(self.0.len() + HEADER_LEN as usize);
+        message.extend_from_slice(&len.to_le_bytes());
+        message.push(ty);
+        message.extend_from_slice(&tag.to_le_bytes());
+        message.extend_from_slice(&self.0);
+        stream.write_all(&message).await?;
+        stream.flush().await
+    }
+}
+
+/// Reads a message of at most `max_len` bytes from `stream`, returning it's type, tag and body, or
+/// `None` if the stream ended between messages
+pub(super) async fn receive<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_len: u32,
+) -> io::Result<Option<(u8, u16, Vec<u8>)>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u32::from_le_bytes(len);
+    if !(HEADER_LEN..=max_len).contains(&len) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid message length",
+        ));
+    }
+    let ty = stream.read_u8().await?;
+    let tag = stream.read_u16_le().await?;
+    let mut body = vec![0; (len - HEADER_LEN) as usize];
+    stream.read_exact(&mut body).await?;
+    Ok(Some((ty, tag, body)))
+}
+
+/// Parses the body of a message
+#[derive(Debug)]
+pub(super) struct Reader<'a>(pub(super) &'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated message"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(super) fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(super) fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(super) fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from(self.u32()?) | u64::from(self.u32()?) << 32)
+    }
+
+    pub(super) fn string(&mut self) -> io::Result<String> {
+        let len = usize::from(self.u16()?);
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    pub(super) fn data(&mut self) -> io::Result<&[u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Converts an [`io::Error`] into the Linux `errno` sent in `Rlerror`
+pub(super) fn errno(err: &io::Error) -> u32 {
+    match err.kind() {
+        ErrorKind::NotFound => 2,
+        ErrorKind::PermissionDenied => 13,
+        ErrorKind::AlreadyExists => 17,
+        ErrorKind::NotADirectory => 20,
+        ErrorKind::IsADirectory => 21,
+        ErrorKind::InvalidInput | ErrorKind::InvalidFilename => 22,
+        ErrorKind::FileTooLarge => 27,
+        ErrorKind::StorageFull => 28,
+        ErrorKind::ReadOnlyFilesystem => 30,
+        ErrorKind::DirectoryNotEmpty => 39,
+        ErrorKind::Unsupported => 95,
+        ErrorKind::QuotaExceeded => 122,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() -> io::Result<()> {
+        let qid = Qid {
+            ty: QT_DIR,
+            version: 3,
+            path: 1 << 40,
+        };
+        let mut buffer = Vec::new();
+        Writer::default()
+            .u32(7)
+            .string("/tmp")
+            .qid(&qid)
+            .data(b"data")
+            .send(&mut buffer, TWALK, 12)
+            .await?;
+
+        let received = receive(&mut buffer.as_slice(), 1024).await?;
+        let Some((ty, tag, body)) = received else {
+            unreachable!("a message was sent")
+        };
+        assert_eq!((ty, tag), (TWALK, 12));
+        let mut reader = Reader(&body);
+        assert_eq!(reader.u32()?, 7);
+        assert_eq!(reader.string()?, "/tmp");
+        assert_eq!(reader.take(1)?, [QT_DIR]);
+        assert_eq!((reader.u32()?, reader.u64()?), (3, 1 << 40));
+        assert_eq!(reader.data()?, b"data");
+        assert!(receive(&mut [].as_slice(), 1024).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+//! A pool of read buffers, shared by the bodies, copies and digests which stream files
+//!
+//! Servers handling many requests at once would otherwise allocate a fresh buffer for each.
+//! Buffers are handed out by [`BufferPool::get`] and return to their pool when dropped, which
+//! keeps up to it's maximum number of them idle and frees the rest.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+};
+
+/// The pool used where none is given, until [`BufferPool::set_shared`] replaces it
+static SHARED: OnceLock<BufferPool> = OnceLock::new();
+
+/// A pool of equally sized buffers.  Clones share the same buffers
+#[derive(Debug, Clone)]
+pub struct BufferPool(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    buffer_size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+    pub const DEFAULT_MAX_IDLE: usize = 64;
+
+    /// Creates a pool of buffers of `buffer_size` bytes, keeping up to `max_idle` of them for
+    /// reuse.  Buffers are never empty, so a size of zero is taken as one
+    #[must_use]
+    pub fn new(buffer_size: usize, max_idle: usize) -> Self {
+        Self(Arc::new(Inner {
+            buffer_size: buffer_size.max(1),
+            max_idle,
+            idle: Mutex::default(),
+        }))
+    }
+
+    /// The pool used for the bodies of files served by the `http` module,
+    /// [`FileSystemExt::copy_with_progress`](crate::FileSystemExt::copy_with_progress) and the
+    /// digests of files, which has the default size and maximum unless replaced
+    pub fn shared() -> &'static Self {
+        SHARED.get_or_init(Self::default)
+    }
+
+    /// Replaces the [shared](Self::shared) pool with `pool`
+    ///
+    /// # Errors
+    ///
+    /// Returns `pool` if the shared pool has already been used or replaced, as buffers may be in
+    /// use by then
+    pub fn set_shared(pool: Self) -> Result<(), Self> {
+        SHARED.set(pool)
+    }
+
+    #[must_use]
+    pub fn buffer_size(&self) -> usize {
+        self.0.buffer_size
+    }
+
+    #[must_use]
+    pub fn max_idle(&self) -> usize {
+        self.0.max_idle
+    }
+
+    /// The number of buffers waiting to be reused
+    #[must_use]
+    pub fn idle(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    /// Takes an idle buffer, or allocates a new one if there are none.  Reused buffers hold
+    /// whatever was last read into them
+    #[must_use]
+    pub fn get(&self) -> PooledBuffer {
+        let buffer = self
+            .0
+            .lock()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.0.buffer_size]);
+        PooledBuffer {
+            buffer,
+            pool: self.clone(),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BUFFER_SIZE, Self::DEFAULT_MAX_IDLE)
+    }
+}
+
+impl Inner {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A buffer taken from a [`BufferPool`], which it's returned to when dropped
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut idle = self.pool.0.lock();
+        if idle.len() < self.pool.0.max_idle {
+            idle.push(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(16, 1);
+        assert_eq!(pool.idle(), 0);
+        let mut first = pool.get();
+        let second = pool.get();
+        assert_eq!((first.len(), second.len()), (16, 16));
+        first[..5].copy_from_slice(b"first");
+        drop(first);
+        drop(second);
+        // Only one is kept, and it's handed out again as it was
+        assert_eq!(pool.idle(), 1);
+        let reused = pool.clone().get();
+        assert_eq!(&reused[..5], b"first");
+        assert_eq!(pool.idle(), 0);
+
+        assert_eq!(BufferPool::new(0, 1).get().len(), 1);
+        assert_eq!(
+            BufferPool::shared().buffer_size(),
+            BufferPool::DEFAULT_BUFFER_SIZE
+        );
+        assert!(BufferPool::set_shared(BufferPool::default()).is_err());
+    }
+}
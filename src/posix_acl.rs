@@ -0,0 +1,301 @@
+//! POSIX access control lists, for [`Request::GetPosixAcl`](crate::Request::GetPosixAcl) and
+//! [`Request::SetPosixAcl`](crate::Request::SetPosixAcl)
+//!
+//! ACLs are read and written with `getfacl` and `setfacl` from the acl package, as the extended
+//! attributes holding them have no safe binding, in the short text form those tools use.  Only
+//! Linux's tools are supported, as other systems' take other options.
+
+use std::fmt::{self, Write};
+#[cfg(target_os = "linux")]
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+/// The access control lists of a file or directory, granting users and groups besides its owner
+/// their own permissions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PosixAcl {
+    /// The entries deciding who may access the file itself.  Every list has one
+    /// [`AclTag::UserObj`], [`AclTag::GroupObj`] and [`AclTag::Other`] entry, mirroring the
+    /// permission bits, and those with named entries also have an [`AclTag::Mask`]
+    pub access: Vec<AclEntry>,
+    /// The entries inherited by what's created inside a directory, which is empty for files and
+    /// directories without a default list
+    pub default: Vec<AclEntry>,
+}
+
+/// An entry of a [`PosixAcl`], granting permissions to whoever its tag names
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub perms: AclPerms,
+}
+
+impl AclEntry {
+    #[must_use]
+    pub const fn new(tag: AclTag, perms: AclPerms) -> Self {
+        Self { tag, perms }
+    }
+}
+
+/// Who an [`AclEntry`] applies to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AclTag {
+    /// The owner of the file
+    UserObj,
+    /// The user with the given name or id.  Lists are always read back with numeric ids
+    User(String),
+    /// The group of the file
+    GroupObj,
+    /// The group with the given name or id
+    Group(String),
+    /// The most any named entry or the group of the file is granted
+    Mask,
+    /// Everyone else
+    Other,
+}
+
+/// The permissions an [`AclEntry`] grants
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct AclPerms {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl AclPerms {
+    pub const NONE: Self = Self::new(false, false, false);
+    pub const ALL: Self = Self::new(true, true, true);
+
+    #[must_use]
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+}
+
+impl fmt::Display for AclPerms {
+    /// Formats the permissions as `ls` does, such as `r-x`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (granted, letter) in [(self.read, 'r'), (self.write, 'w'), (self.execute, 'x')] {
+            f.write_char(if granted { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AclTag {
+    /// Formats the tag and its qualifier as `getfacl` does, such as `user:1000` or `mask:`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserObj => f.write_str("user:"),
+            Self::User(user) => write!(f, "user:{user}"),
+            Self::GroupObj => f.write_str("group:"),
+            Self::Group(group) => write!(f, "group:{group}"),
+            Self::Mask => f.write_str("mask:"),
+            Self::Other => f.write_str("other:"),
+        }
+    }
+}
+
+impl fmt::Display for AclEntry {
+    /// Formats the entry as `getfacl` does, such as `user:1000:rw-`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.tag, self.perms)
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Parses `getfacl`'s output, without its header or effective permissions
+fn parse(text: &str) -> io::Result<PosixAcl> {
+    let mut acl = PosixAcl::default();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (entries, line) = match line.strip_prefix("default:") {
+            Some(line) => (&mut acl.default, line),
+            None => (&mut acl.access, line),
+        };
+        entries.push(parse_entry(line)?);
+    }
+    Ok(acl)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_entry(entry: &str) -> io::Result<AclEntry> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, format!("invalid ACL entry {entry}"));
+    let mut fields = entry.split(':');
+    let (Some(tag), Some(qualifier), Some(perms), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(invalid());
+    };
+    let tag = match (tag, qualifier) {
+        ("user", "") => AclTag::UserObj,
+        ("user", user) => AclTag::User(user.to_owned()),
+        ("group", "") => AclTag::GroupObj,
+        ("group", group) => AclTag::Group(group.to_owned()),
+        ("mask", "") => AclTag::Mask,
+        ("other", "") => AclTag::Other,
+        _ => return Err(invalid()),
+    };
+    let perms = match perms.as_bytes() {
+        [read @ (b'r' | b'-'), write @ (b'w' | b'-'), execute @ (b'x' | b'-')] => {
+            AclPerms::new(*read == b'r', *write == b'w', *execute == b'x')
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(AclEntry::new(tag, perms))
+}
+
+#[cfg(target_os = "linux")]
+/// Builds the argument `setfacl --set` takes for `acl`, checking each list is one `setfacl` would
+/// accept so mistakes fail the same way whether or not the tools are installed
+fn to_text(acl: &PosixAcl) -> io::Result<String> {
+    validate(&acl.access, "access")?;
+    if !acl.default.is_empty() {
+        validate(&acl.default, "default")?;
+    }
+    let access = acl.access.iter().map(ToString::to_string);
+    let default = acl.default.iter().map(|entry| format!("default:{entry}"));
+    Ok(access.chain(default).collect::<Vec<_>>().join(","))
+}
+
+#[cfg(target_os = "linux")]
+fn validate(entries: &[AclEntry], list: &str) -> io::Result<()> {
+    let invalid = |message: &str| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("the {list} list {message}"),
+        )
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        if let AclTag::User(name) | AclTag::Group(name) = &entry.tag {
+            if name.is_empty()
+                || name
+                    .chars()
+                    .any(|c| matches!(c, ':' | ',' | '#') || c.is_whitespace() || c.is_control())
+            {
+                return Err(invalid(&format!("names an invalid user or group {name:?}")));
+            }
+        }
+        if entries[..i].iter().any(|other| other.tag == entry.tag) {
+            return Err(invalid(&format!("repeats the entry {}", entry.tag)));
+        }
+    }
+    for required in [AclTag::UserObj, AclTag::GroupObj, AclTag::Other] {
+        if !entries.iter().any(|entry| entry.tag == required) {
+            return Err(invalid(&format!("has no entry {required}")));
+        }
+    }
+    Ok(())
+}
+
+/// The access control lists of the file or directory at `path`, following symlinks
+#[cfg(target_os = "linux")]
+pub(crate) fn get_acl(path: &Path) -> io::Result<PosixAcl> {
+    // Absolute, so the path is never taken for an option
+    let path = std::fs::canonicalize(path)?;
+    let output = crate::linux::run(
+        crate::linux::tool("getfacl")
+            .args([
+                "--omit-header",
+                "--no-effective",
+                "--numeric",
+                "--absolute-names",
+            ])
+            .arg(path),
+    )?;
+    parse(&output)
+}
+
+/// Replaces both access control lists of the file or directory at `path` with those of `acl`,
+/// removing the default list if `acl` has none
+#[cfg(target_os = "linux")]
+pub(crate) fn set_acl(path: &Path, acl: &PosixAcl) -> io::Result<()> {
+    let text = to_text(acl)?;
+    let path = std::fs::canonicalize(path)?;
+    let mut command = crate::linux::tool("setfacl");
+    if acl.default.is_empty() {
+        command.arg("--remove-default");
+    }
+    crate::linux::run(command.arg(format!("--set={text}")).arg(path)).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text() -> io::Result<()> {
+        let text = "user::rw-\nuser:1000:r--\ngroup::r--\nmask::r-x\nother::---\n\
+                    default:user::rwx\ndefault:group:50:rwx\ndefault:group::r-x\n\
+                    default:other::r-x\n\n";
+        let acl = parse(text)?;
+        assert_eq!(
+            acl.access,
+            [
+                AclEntry::new(AclTag::UserObj, AclPerms::new(true, true, false)),
+                AclEntry::new(
+                    AclTag::User("1000".into()),
+                    AclPerms::new(true, false, false)
+                ),
+                AclEntry::new(AclTag::GroupObj, AclPerms::new(true, false, false)),
+                AclEntry::new(AclTag::Mask, AclPerms::new(true, false, true)),
+                AclEntry::new(AclTag::Other, AclPerms::NONE),
+            ]
+        );
+        assert_eq!(acl.default.len(), 4);
+        assert_eq!(acl.default[1].tag, AclTag::Group("50".into()));
+        assert_eq!(acl.default[1].perms, AclPerms::ALL);
+        assert_eq!(
+            to_text(&acl)?,
+            "user::rw-,user:1000:r--,group::r--,mask::r-x,other::---,default:user::rwx,\
+             default:group:50:rwx,default:group::r-x,default:other::r-x"
+        );
+
+        for invalid in [
+            "user:rw-",
+            "owner::rw-",
+            "user::rw",
+            "user::wr-",
+            "mask:1:rwx",
+        ] {
+            assert!(parse(invalid).is_err_and(|err| err.kind() == ErrorKind::InvalidData));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        let base = || {
+            vec![
+                AclEntry::new(AclTag::UserObj, AclPerms::ALL),
+                AclEntry::new(AclTag::GroupObj, AclPerms::NONE),
+                AclEntry::new(AclTag::Other, AclPerms::NONE),
+            ]
+        };
+        let text = |access: Vec<AclEntry>| {
+            to_text(&PosixAcl {
+                access,
+                default: Vec::new(),
+            })
+        };
+        assert!(text(base()).is_ok());
+        let mut missing = base();
+        missing.remove(1);
+        let mut repeated = base();
+        repeated.push(AclEntry::new(AclTag::Other, AclPerms::ALL));
+        let named = |name: &str| {
+            let mut entries = base();
+            entries.push(AclEntry::new(AclTag::User(name.into()), AclPerms::ALL));
+            entries
+        };
+        for invalid in [missing, repeated, named(""), named("a,b"), named("a:rwx")] {
+            assert!(text(invalid).is_err_and(|err| err.kind() == ErrorKind::InvalidInput));
+        }
+        assert!(text(named("www-data")).is_ok());
+    }
+}
@@ -0,0 +1,12 @@
+//! The traits and types most uses of the crate need, for glob importing
+//!
+//! ```
+//! use tower_fs::prelude::*;
+//! ```
+
+pub use tower_service::Service;
+
+pub use crate::{
+    BoxCloneService, BoxFsService, FileHandle, FileSystem, FileSystemExt, FileType, MakeFileSystem,
+    Metadata, Mode, Request, Response, SharedService, WrongVariant,
+};
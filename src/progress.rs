@@ -0,0 +1,81 @@
+//! Reporting the progress of long running operations, for rendering progress bars
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// How far an operation has got
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Progress {
+    /// The bytes processed so far
+    pub bytes: u64,
+    /// The bytes the operation will process in total, if it's known
+    pub total_bytes: Option<u64>,
+    /// The files or directories finished so far
+    pub entries: u64,
+}
+
+/// Updates the [`Progress`] seen by the receiver returned from [`ProgressReporter::new`]
+///
+/// Clones update the same progress, so one reporter can be shared by the steps of an operation.
+/// Updates are dropped once the receiver is, so operations needn't check whether anyone is
+/// watching.
+#[derive(Debug, Clone)]
+pub struct ProgressReporter(Arc<watch::Sender<Progress>>);
+
+impl ProgressReporter {
+    /// A reporter starting from no progress, and the receiver to watch it with
+    #[must_use]
+    pub fn new() -> (Self, watch::Receiver<Progress>) {
+        let (sender, receiver) = watch::channel(Progress::default());
+        (Self(Arc::new(sender)), receiver)
+    }
+
+    /// Records that `bytes` more have been processed
+    pub fn add_bytes(&self, bytes: u64) {
+        self.0.send_modify(|progress| progress.bytes += bytes);
+    }
+
+    /// Records that `bytes` more will be processed, as an operation discovers more work
+    pub fn add_total_bytes(&self, bytes: u64) {
+        self.0.send_modify(|progress| {
+            progress.total_bytes = Some(progress.total_bytes.unwrap_or(0) + bytes);
+        });
+    }
+
+    /// Records that another file or directory is finished
+    pub fn add_entry(&self) {
+        self.0.send_modify(|progress| progress.entries += 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt};
+
+    #[tokio::test]
+    async fn test_copy_with_progress() -> io::Result<()> {
+        let dir = TestDir::new("progress")?;
+        let (from, to) = (dir.join("from.bin"), dir.join("to.bin"));
+        let contents: Vec<u8> = (0..200_000u32).map(|i| i.to_le_bytes()[0]).collect();
+        let mut fs = FileSystem::new();
+        fs.write(&from, contents.clone()).await?;
+
+        let (reporter, mut progress) = ProgressReporter::new();
+        assert_eq!(fs.copy_with_progress(&from, &to, &reporter).await?, 200_000);
+        assert!(progress.has_changed().map_err(io::Error::other)?);
+        assert_eq!(
+            *progress.borrow_and_update(),
+            Progress {
+                bytes: 200_000,
+                total_bytes: Some(200_000),
+                entries: 1,
+            }
+        );
+        assert_eq!(fs.read(&to).await?, contents);
+        Ok(())
+    }
+}
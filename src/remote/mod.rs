@@ -0,0 +1,376 @@
+//! Sharing a service stack between processes
+//!
+//! [`serve`] answers requests sent over a byte stream (typically a TCP or unix socket connection)
+//! by calling a local service, and [`Remote`] is a service which sends it's requests to such a
+//! server, so frontends can use a storage node through the same API as a local backend.
+//!
+//! Each request and response is sent as a single length delimited frame, so large files should be
+//! read with [`Request::ReadRange`] rather than [`Request::ReadBytes`].  [`Response::File`] refers to a
+//! file open on the server, so it can't be sent and [`Request::Open`] fails with
+//! [`ErrorKind::Unsupported`].
+
+use std::{
+    future::poll_fn,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex, PoisonError},
+    task::Poll,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tower_service::Service;
+
+use crate::{Request, Response};
+pub use wire::MAX_FRAME_LEN;
+use wire::{
+    decode_request, decode_response, encode_request, encode_response, read_frame, write_frame,
+};
+
+mod wire;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Answers requests read from `stream` with `service` until the client closes the stream
+///
+/// Requests on one stream are answered in order; serve concurrent clients on separate streams.
+///
+/// # Errors
+///
+/// If reading from or writing to `stream` fails, or a request's frame is too large.  Errors from
+/// `service` are sent to the client instead, as are responses too large for a frame, which fail
+/// with [`ErrorKind::InvalidData`].
+pub async fn serve<T, S>(mut stream: T, mut service: S) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    while let Some(frame) = read_frame(&mut stream).await? {
+        let result = match decode_request(&frame) {
+            Ok(req) => match poll_fn(|cx| service.poll_ready(cx)).await {
+                Ok(()) => service.call(req).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        let mut reply = encode_response(&result);
+        // Sending it would fail, ending the connection rather than just this request
+        if reply.len() > MAX_FRAME_LEN as usize {
+            reply = encode_response(&Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "response too large for a frame",
+            )));
+        }
+        write_frame(&mut stream, &reply).await?;
+    }
+    Ok(())
+}
+
+/// A service which sends requests to a [`serve`]r
+///
+/// `M` is a connector which, when called, returns a stream connected to the server.  Idle
+/// connections are kept in a pool for reuse.  If a connection fails while a request is in flight,
+/// the request is retried on a new connection up to [`Config::retries`] times; since the server
+/// may have already handled the request, only enable retries when that's acceptable.
+#[derive(Debug)]
+pub struct Remote<M, S> {
+    connector: M,
+    idle: Arc<Mutex<Vec<S>>>,
+    config: Config,
+}
+
+impl<M: Clone, S> Clone for Remote<M, S> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            idle: self.idle.clone(),
+            config: self.config,
+        }
+    }
+}
+
+impl<M, S> Remote<M, S>
+where
+    M: Service<(), Response = S>,
+{
+    pub fn new(connector: M, config: Config) -> Self {
+        Self {
+            connector,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            config,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// How many idle connections to keep open for reuse
+    pub max_idle: usize,
+    /// How many times to retry a request on a new connection after it's connection failed
+    pub retries: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_idle: 4,
+            retries: 1,
+        }
+    }
+}
+
+impl<M, S> Service<Request> for Remote<M, S>
+where
+    M: Service<(), Response = S> + Clone + Send + 'static,
+    M::Error: Into<BoxError>,
+    M::Future: Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Response = Response;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Response, Self::Error>>;
+
+    /// Connections are only made once a request needs one, so the client is always ready
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let mut connector = self.connector.clone();
+        let idle = self.idle.clone();
+        let config = self.config;
+        async move {
+            let frame = encode_request(&req)?;
+            let mut attempt = 0;
+            loop {
+                let pooled = idle.lock().unwrap_or_else(PoisonError::into_inner).pop();
+                let mut stream = match pooled {
+                    Some(stream) => stream,
+                    None => connect(&mut connector).await?,
+                };
+                match exchange(&mut stream, &frame).await {
+                    Ok(result) => {
+                        let mut idle = idle.lock().unwrap_or_else(PoisonError::into_inner);
+                        if idle.len() < config.max_idle {
+                            idle.push(stream);
+                        }
+                        return result;
+                    }
+                    Err(err) if attempt >= config.retries => return Err(err),
+                    Err(_) => attempt += 1,
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+async fn connect<M, S>(connector: &mut M) -> io::Result<S>
+where
+    M: Service<(), Response = S>,
+    M::Error: Into<BoxError>,
+{
+    poll_fn(|cx| connector.poll_ready(cx))
+        .await
+        .map_err(|err| io::Error::other(err.into()))?;
+    connector
+        .call(())
+        .await
+        .map_err(|err| io::Error::other(err.into()))
+}
+
+/// Sends a request frame and reads the response.  The outer result is the connection's, the inner
+/// one the request's
+async fn exchange<S>(stream: &mut S, frame: &[u8]) -> io::Result<io::Result<Response>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_frame(stream, frame).await?;
+    let reply = read_frame(stream).await?.ok_or_else(|| {
+        io::Error::new(ErrorKind::ConnectionAborted, "server closed the connection")
+    })?;
+    decode_response(&reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use futures::future::{ready, Ready};
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    /// Connects to a new server task answering requests with [`FileSystem`]
+    #[derive(Clone)]
+    struct Connector;
+
+    impl Service<()> for Connector {
+        type Response = DuplexStream;
+        type Error = Infallible;
+        type Future = Ready<Result<DuplexStream, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(serve(server, FileSystem::new()));
+            ready(Ok(client))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() -> io::Result<()> {
+        let dir = TestDir::new("remote")?;
+        let path = dir.join("file.txt");
+        let mut remote = Remote::new(Connector, Config::default());
+
+        remote
+            .call(Request::WriteBytes {
+                path: path.as_path().into(),
+                bytes: b"remote".to_vec(),
+            })
+            .await?;
+        remote
+            .call(Request::AppendBytes {
+                path: path.as_path().into(),
+                bytes: b" contents".to_vec(),
+            })
+            .await?;
+        assert!(matches!(
+            remote
+                .call(Request::ReadRange {
+                    path: path.as_path().into(),
+                    range: 7..100,
+                })
+                .await?,
+            Response::Bytes(bytes) if bytes == b"contents"
+        ));
+        let stat = Request::GetMetadata {
+            path: path.as_path().into(),
+            follow_symlinks: true,
+        };
+        assert_eq!(
+            remote.call(stat).await?.into_metadata()?,
+            std::fs::metadata(&path)?.into()
+        );
+        let batch = Request::MetadataBatch {
+            paths: vec![path.as_path().into(), path.with_extension("missing").into()],
+            follow_symlinks: true,
+        };
+        let batch = remote.call(batch).await?.into_metadata_batch()?;
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch[0].as_ref().ok(),
+            Some(&std::fs::metadata(&path)?.into())
+        );
+        assert!(batch[1]
+            .as_ref()
+            .is_err_and(|err| err.kind() == ErrorKind::NotFound));
+        let listed = dir.join("listed");
+        std::fs::create_dir(&listed)?;
+        std::fs::write(listed.join("entry"), "listed")?;
+        let listing = remote
+            .call(Request::ReadDir(listed.as_path().into()))
+            .await?;
+        assert_eq!(
+            listing.into_directory()?,
+            [(
+                "entry".into(),
+                std::fs::symlink_metadata(listed.join("entry"))?.into()
+            )]
+        );
+        remote
+            .call(Request::RemoveFile(path.as_path().into()))
+            .await?;
+        assert_eq!(
+            remote
+                .call(Request::RemoveFile(path.into()))
+                .await
+                .map(drop)
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::NotFound)
+        );
+        #[cfg(feature = "dedup")]
+        {
+            let duplicates = dir.join("duplicates");
+            std::fs::create_dir(&duplicates)?;
+            std::fs::write(duplicates.join("a"), "same")?;
+            std::fs::write(duplicates.join("b"), "same")?;
+            let find = Request::FindDuplicates {
+                dir: duplicates.as_path().into(),
+                options: crate::dedup::DedupOptions::default(),
+            };
+            let sets = remote.call(find).await?.into_duplicates()?;
+            assert_eq!(sets.len(), 1);
+            assert_eq!(sets[0].paths, ["a", "b"].map(std::path::PathBuf::from));
+        }
+        assert_eq!(remote.idle.lock().map(|idle| idle.len()).ok(), Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response() -> io::Result<()> {
+        let dir = TestDir::new("remote_oversized")?;
+        let path = dir.join("large.bin");
+        // Sparse, so the file takes no space until it's read
+        std::fs::File::create(&path)?.set_len(MAX_FRAME_LEN.into())?;
+        let config = Config {
+            max_idle: 1,
+            retries: 0,
+        };
+        let mut remote = Remote::new(Connector, config);
+
+        let read = remote.call(Request::ReadBytes(path.as_path().into())).await;
+        assert!(read.is_err_and(|err| err.kind() == ErrorKind::InvalidData));
+        // The connection outlives the failed request, and is reused for the next
+        assert_eq!(remote.idle.lock().map(|idle| idle.len()).ok(), Some(1));
+        let exists = remote.call(Request::Exists(path.into())).await?;
+        assert!(exists.into_exists()?);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "posix-acl", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_posix_acl_round_trip() -> io::Result<()> {
+        use crate::posix_acl::{AclEntry, AclPerms, AclTag, PosixAcl};
+
+        let mut remote = Remote::new(Connector, Config::default());
+        let dir = TestDir::new("remote_posix_acl")?;
+        let path = dir.join("acl.txt");
+        std::fs::write(&path, "acl")?;
+        let mut acl = PosixAcl {
+            access: vec![
+                AclEntry::new(AclTag::UserObj, AclPerms::new(true, true, false)),
+                AclEntry::new(
+                    AclTag::User("65534".into()),
+                    AclPerms::new(true, false, false),
+                ),
+                AclEntry::new(AclTag::GroupObj, AclPerms::NONE),
+                AclEntry::new(AclTag::Mask, AclPerms::new(true, false, false)),
+            ],
+            default: Vec::new(),
+        };
+        let set = |acl: &PosixAcl| Request::SetPosixAcl {
+            path: path.as_path().into(),
+            acl: acl.clone(),
+        };
+        // Rejected before the tools are needed, so this runs whether or not they're installed
+        let missing = remote.call(set(&acl)).await.map_err(|err| err.kind());
+        assert_eq!(missing.map(drop), Err(ErrorKind::InvalidInput));
+        acl.access
+            .push(AclEntry::new(AclTag::Other, AclPerms::NONE));
+        match remote.call(set(&acl)).await {
+            Err(err) if err.kind() == ErrorKind::Unsupported => {}
+            result => {
+                result?.into_done()?;
+                let get = Request::GetPosixAcl(path.as_path().into());
+                assert_eq!(remote.call(get).await?.into_posix_acl()?, acl);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,884 @@
+//! Serving a service stack over the SSH file transfer protocol
+//!
+//! [`serve`] speaks SFTP (version 3) over any byte stream, translating each packet into requests
+//! against a service, so any backend and middleware stack can be exposed to SFTP clients.  Like
+//! the [`Sftp`](crate::backend::sftp::Sftp) backend, it leaves SSH itself to the application: run
+//! it on the `sftp` subsystem channel of an authenticated session.
+
+use std::{
+    collections::HashMap,
+    future::poll_fn,
+    io::{self, ErrorKind, SeekFrom},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tower_service::Service;
+
+use crate::{
+    backend::sftp::protocol::{
+        error_status, receive, Attrs, Reader, Writer, FXF_APPEND, FXF_CREAT, FXF_EXCL, FXF_READ,
+        FXF_TRUNC, FXF_WRITE, FXP_ATTRS, FXP_CLOSE, FXP_DATA, FXP_EXTENDED, FXP_FSTAT, FXP_HANDLE,
+        FXP_INIT, FXP_LSTAT, FXP_MKDIR, FXP_NAME, FXP_OPEN, FXP_OPENDIR, FXP_READ, FXP_READDIR,
+        FXP_READLINK, FXP_REALPATH, FXP_REMOVE, FXP_RENAME, FXP_RMDIR, FXP_SETSTAT, FXP_STAT,
+        FXP_STATUS, FXP_SYMLINK, FXP_VERSION, FXP_WRITE, FX_OK, HARDLINK, MAX_PACKET_LEN,
+        POSIX_RENAME, VERSION,
+    },
+    date::DateTime,
+    FileHandle, Metadata, Mode, Request, Response,
+};
+
+/// Reads are shortened so their replies fit in a packet
+const MAX_READ_LEN: u32 = MAX_PACKET_LEN - 1024;
+
+/// How many directory entries to send in each `SSH_FXP_NAME` reply to `SSH_FXP_READDIR`, few
+/// enough that they fit in a packet
+const NAMES_PER_READDIR: usize = 100;
+
+/// The [`Mode`] to open a file with for each combination of flags (besides `SSH_FXF_WRITE`) a
+/// write-only `SSH_FXP_OPEN` can have
+const WRITE_MODES: [(u32, Mode); 5] = [
+    (FXF_CREAT | FXF_TRUNC, Mode::CreateOrOverwrite),
+    (FXF_CREAT | FXF_EXCL, Mode::CreateNew),
+    (FXF_CREAT | FXF_TRUNC | FXF_EXCL, Mode::CreateNew),
+    (FXF_CREAT | FXF_APPEND, Mode::CreateOrAppend),
+    (FXF_APPEND, Mode::AppendExisting),
+];
+
+/// Answers SFTP requests read from `stream` with `service` until the client closes the stream
+///
+/// Paths are passed to `service` as the client sent them, so serve a directory by wrapping the
+/// service in [`Root`](crate::middleware::root::Root); `SSH_FXP_REALPATH` resolves paths against
+/// `/`.  Files are read and written through [`Request::Open`] where the service supports it, and
+/// otherwise with [`Request::ReadRange`], or buffered in memory and written with
+/// [`Request::WriteBytes`] once the client closes them.  Files can't be opened for both reading
+/// and writing, nor for writing without truncating or appending.
+///
+/// Directories are listed with [`Request::ReadDir`] when they're opened.  `SSH_FXP_SETSTAT` only
+/// changes permissions, ignoring other attributes.  The `posix-rename@openssh.com` and
+/// `hardlink@openssh.com` extensions are supported.
+///
+/// # Errors
+///
+/// If reading from or writing to `stream` fails, or the client doesn't follow the protocol.
+/// Errors from `service` are sent to the client instead.
+pub async fn serve<T, S>(mut stream: T, service: S) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let (ty, _client_version) = receive(&mut stream).await?;
+    if ty != FXP_INIT {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "expected SSH_FXP_INIT",
+        ));
+    }
+    // Later versions of the protocol are backwards compatible, so version 3 is always used
+    Writer::default()
+        .u32(VERSION)
+        .string(POSIX_RENAME)
+        .string("1")
+        .string(HARDLINK)
+        .string("1")
+        .send(&mut stream, FXP_VERSION)
+        .await?;
+
+    let mut server = Server {
+        service,
+        handles: HashMap::new(),
+        next_handle: 0,
+    };
+    loop {
+        let (ty, body) = match receive(&mut stream).await {
+            Ok(packet) => packet,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut reader = Reader(&body);
+        let id = reader.u32()?;
+        let reply = Writer::default().u32(id);
+        let (ty, reply) = match server.handle(ty, &mut reader, reply).await {
+            Ok(reply) => reply,
+            Err(err) => (
+                FXP_STATUS,
+                Writer::default()
+                    .u32(id)
+                    .u32(error_status(&err))
+                    .string(err.to_string())
+                    .string(""),
+            ),
+        };
+        reply.send(&mut stream, ty).await?;
+    }
+}
+
+#[derive(Debug)]
+struct Server<S> {
+    service: S,
+    handles: HashMap<Vec<u8>, Handle>,
+    next_handle: u32,
+}
+
+#[derive(Debug)]
+enum Handle {
+    /// A file opened by the service
+    File(FileHandle),
+    /// A file read with [`Request::ReadRange`], for services which can't open files
+    Read(PathBuf),
+    /// A file written with [`Request::WriteBytes`] once it's closed, for services which can't open
+    /// files
+    Write { path: PathBuf, bytes: Vec<u8> },
+    /// The entries of a directory which are yet to be sent, in reverse order
+    Directory(Vec<(PathBuf, Metadata)>),
+}
+
+impl<S> Server<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    /// Handles a packet, returning the type and body of the reply, which starts with `reply`
+    async fn handle(
+        &mut self,
+        ty: u8,
+        reader: &mut Reader<'_>,
+        reply: Writer,
+    ) -> io::Result<(u8, Writer)> {
+        let ok = |reply: Writer| (FXP_STATUS, reply.u32(FX_OK).string("").string(""));
+        match ty {
+            FXP_OPEN => {
+                let (path, flags) = (PathBuf::from(reader.string()?), reader.u32()?);
+                let handle = self.open(path, flags).await?;
+                Ok((FXP_HANDLE, reply.string(handle)))
+            }
+            FXP_OPENDIR => {
+                let path = PathBuf::from(reader.string()?);
+                let handle = self.open_dir(path).await?;
+                Ok((FXP_HANDLE, reply.string(handle)))
+            }
+            FXP_READDIR => {
+                let names = self.read_dir(&reader.bytes()?, reply)?;
+                Ok((FXP_NAME, names))
+            }
+            FXP_CLOSE => {
+                self.close(&reader.bytes()?).await?;
+                Ok(ok(reply))
+            }
+            FXP_READ => {
+                let (handle, offset, len) = (reader.bytes()?, reader.u64()?, reader.u32()?);
+                let data = self.read(&handle, offset, len.min(MAX_READ_LEN)).await?;
+                if data.is_empty() {
+                    // Reported as SSH_FX_EOF
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                Ok((FXP_DATA, reply.string(data)))
+            }
+            FXP_WRITE => {
+                let (handle, offset, data) = (reader.bytes()?, reader.u64()?, reader.bytes()?);
+                self.write(&handle, offset, &data).await?;
+                Ok(ok(reply))
+            }
+            FXP_STAT | FXP_LSTAT => {
+                let path = PathBuf::from(reader.string()?);
+                let attrs = self.stat(path, ty == FXP_STAT).await?;
+                Ok((FXP_ATTRS, reply.attrs(&attrs)))
+            }
+            FXP_FSTAT => {
+                let attrs = match self.handles.get(reader.bytes()?.as_slice()) {
+                    Some(Handle::File(file)) => file_attrs(&file.metadata().await?),
+                    Some(_) => Attrs::default(),
+                    None => return Err(invalid_handle()),
+                };
+                Ok((FXP_ATTRS, reply.attrs(&attrs)))
+            }
+            FXP_SETSTAT => {
+                let (path, attrs) = (PathBuf::from(reader.string()?), reader.attrs()?);
+                if let Some(mode) = attrs.permissions {
+                    let perm = permissions(mode)?;
+                    self.call(Request::SetPermissions {
+                        path: path.into(),
+                        perm,
+                    })
+                    .await?;
+                }
+                Ok(ok(reply))
+            }
+            FXP_REALPATH => {
+                let path = real_path(&reader.string()?);
+                Ok((FXP_NAME, name(reply, &path)))
+            }
+            FXP_RENAME => {
+                let (from, to) = (
+                    PathBuf::from(reader.string()?),
+                    PathBuf::from(reader.string()?),
+                );
+                // Unlike `Request::Rename`, SSH_FXP_RENAME doesn't replace existing files
+                if self.exists(to.clone()).await? {
+                    return Err(io::Error::new(
+                        ErrorKind::AlreadyExists,
+                        "the destination already exists",
+                    ));
+                }
+                self.call(Request::Rename {
+                    from: from.into(),
+                    to: to.into(),
+                })
+                .await?;
+                Ok(ok(reply))
+            }
+            FXP_READLINK => {
+                let path = PathBuf::from(reader.string()?);
+                match self.call(Request::FollowLink(path.into())).await? {
+                    Response::PointsTo(target) => {
+                        Ok((FXP_NAME, name(reply, &target.to_string_lossy())))
+                    }
+                    _ => Err(unexpected_response()),
+                }
+            }
+            _ => {
+                let req = decode_request(ty, reader)?;
+                self.call(req).await?;
+                Ok(ok(reply))
+            }
+        }
+    }
+
+    async fn call(&mut self, req: Request) -> io::Result<Response> {
+        poll_fn(|cx| self.service.poll_ready(cx)).await?;
+        self.service.call(req).await
+    }
+
+    async fn exists(&mut self, path: PathBuf) -> io::Result<bool> {
+        match self.call(Request::Exists(path.into())).await? {
+            Response::Exists(exists) => Ok(exists),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    async fn stat(&mut self, path: PathBuf, follow_symlinks: bool) -> io::Result<Attrs> {
+        let req = Request::GetMetadata {
+            path: path.as_path().into(),
+            follow_symlinks,
+        };
+        match self.call(req).await {
+            Ok(Response::Metadata(metadata)) => Ok(file_attrs(&metadata)),
+            Ok(_) => Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => {
+                if self.exists(path).await? {
+                    Ok(Attrs::default())
+                } else {
+                    Err(ErrorKind::NotFound.into())
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn open(&mut self, path: PathBuf, flags: u32) -> io::Result<Vec<u8>> {
+        let mode = if flags == FXF_READ {
+            Mode::Read
+        } else if flags & (FXF_READ | FXF_WRITE) == FXF_WRITE {
+            WRITE_MODES
+                .iter()
+                .find(|(write_flags, _)| *write_flags == flags & !FXF_WRITE)
+                .map(|(_, mode)| *mode)
+                .ok_or_else(|| unsupported_flags(flags))?
+        } else {
+            return Err(unsupported_flags(flags));
+        };
+        let open = Request::Open {
+            mode,
+            path: path.as_path().into(),
+        };
+        let handle = match self.call(open).await {
+            Ok(Response::File(file)) => Handle::File(file),
+            Ok(_) => return Err(unexpected_response()),
+            Err(err) if err.kind() == ErrorKind::Unsupported => match mode {
+                Mode::Read if self.exists(path.clone()).await? => Handle::Read(path),
+                Mode::Read => return Err(ErrorKind::NotFound.into()),
+                Mode::CreateNew if self.exists(path.clone()).await? => {
+                    return Err(ErrorKind::AlreadyExists.into())
+                }
+                Mode::CreateNew | Mode::CreateOrOverwrite => Handle::Write {
+                    path,
+                    bytes: Vec::new(),
+                },
+                Mode::AppendExisting | Mode::CreateOrAppend => return Err(err),
+            },
+            Err(err) => return Err(err),
+        };
+        Ok(self.insert(handle))
+    }
+
+    async fn open_dir(&mut self, path: PathBuf) -> io::Result<Vec<u8>> {
+        let Response::Directory(mut entries) = self.call(Request::ReadDir(path.into())).await?
+        else {
+            return Err(unexpected_response());
+        };
+        entries.reverse();
+        Ok(self.insert(Handle::Directory(entries)))
+    }
+
+    /// Appends the next batch of entries of a directory to `reply`, as a `SSH_FXP_NAME` body
+    fn read_dir(&mut self, handle: &[u8], reply: Writer) -> io::Result<Writer> {
+        let Some(Handle::Directory(entries)) = self.handles.get_mut(handle) else {
+            return Err(invalid_handle());
+        };
+        if entries.is_empty() {
+            // Reported as SSH_FX_EOF
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let count = entries.len().min(NAMES_PER_READDIR);
+        let batch = entries.split_off(entries.len() - count);
+        let reply = reply.u32(u32::try_from(count).unwrap_or(u32::MAX));
+        Ok(batch.iter().rev().fold(reply, |reply, (name, metadata)| {
+            let name = name.to_string_lossy();
+            reply
+                .string(name.as_bytes())
+                .string(long_name(&name, metadata))
+                .attrs(&file_attrs(metadata))
+        }))
+    }
+
+    fn insert(&mut self, handle: Handle) -> Vec<u8> {
+        let id = self.next_handle.to_be_bytes().to_vec();
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.handles.insert(id.clone(), handle);
+        id
+    }
+
+    async fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        match self.handles.remove(handle) {
+            Some(Handle::File(mut file)) => file.flush().await,
+            Some(Handle::Read(_) | Handle::Directory(_)) => Ok(()),
+            Some(Handle::Write { path, bytes }) => self
+                .call(Request::WriteBytes {
+                    path: path.into(),
+                    bytes,
+                })
+                .await
+                .map(drop),
+            None => Err(invalid_handle()),
+        }
+    }
+
+    async fn read(&mut self, handle: &[u8], offset: u64, len: u32) -> io::Result<Vec<u8>> {
+        let path = match self.handles.get_mut(handle) {
+            Some(Handle::File(file)) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut data = Vec::new();
+                file.take(u64::from(len)).read_to_end(&mut data).await?;
+                return Ok(data);
+            }
+            Some(Handle::Read(path)) => path.clone(),
+            Some(Handle::Write { .. } | Handle::Directory(_)) => return Err(wrong_mode()),
+            None => return Err(invalid_handle()),
+        };
+        let range = offset..offset.saturating_add(u64::from(len));
+        match self
+            .call(Request::ReadRange {
+                path: path.into(),
+                range,
+            })
+            .await?
+        {
+            Response::Bytes(bytes) => Ok(bytes),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    async fn write(&mut self, handle: &[u8], offset: u64, data: &[u8]) -> io::Result<()> {
+        match self.handles.get_mut(handle) {
+            // Files opened for appending ignore the offset, as the protocol allows
+            Some(Handle::File(file)) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                file.write_all(data).await
+            }
+            Some(Handle::Write { bytes, .. }) => {
+                let start = usize::try_from(offset).map_err(io::Error::other)?;
+                let end = start
+                    .checked_add(data.len())
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "offset too large"))?;
+                if bytes.len() < end {
+                    bytes.resize(end, 0);
+                }
+                bytes[start..end].copy_from_slice(data);
+                Ok(())
+            }
+            Some(Handle::Read(_) | Handle::Directory(_)) => Err(wrong_mode()),
+            None => Err(invalid_handle()),
+        }
+    }
+}
+
+/// Decodes the packets which translate to a single request, and are answered with a status
+fn decode_request(ty: u8, reader: &mut Reader<'_>) -> io::Result<Request> {
+    Ok(match ty {
+        FXP_REMOVE => Request::RemoveFile(PathBuf::from(reader.string()?).into()),
+        FXP_MKDIR => Request::CreateDir {
+            path: PathBuf::from(reader.string()?).into(),
+            recursive: false,
+        },
+        FXP_RMDIR => Request::RemoveDir {
+            path: PathBuf::from(reader.string()?).into(),
+            recursive: false,
+        },
+        FXP_SYMLINK => {
+            // OpenSSH sends the target first, contrary to the draft, and clients follow it
+            let (src, dst) = (
+                PathBuf::from(reader.string()?),
+                PathBuf::from(reader.string()?),
+            );
+            #[cfg(unix)]
+            let req = Request::Symlink {
+                src: src.into(),
+                dst: dst.into(),
+            };
+            #[cfg(windows)]
+            let req = Request::SymlinkFile {
+                src: src.into(),
+                dst: dst.into(),
+            };
+            req
+        }
+        FXP_EXTENDED => {
+            let extension = reader.string()?;
+            let (from, to) = (
+                PathBuf::from(reader.string()?),
+                PathBuf::from(reader.string()?),
+            );
+            match extension.as_str() {
+                POSIX_RENAME => Request::Rename {
+                    from: from.into(),
+                    to: to.into(),
+                },
+                HARDLINK => Request::HardLink {
+                    src: from.into(),
+                    dst: to.into(),
+                },
+                _ => return Err(ErrorKind::Unsupported.into()),
+            }
+        }
+        // Including SSH_FXP_FSETSTAT
+        _ => return Err(ErrorKind::Unsupported.into()),
+    })
+}
+
+/// Appends a `SSH_FXP_NAME` body with the single entry `path` to `reply`
+fn name(reply: Writer, path: &str) -> Writer {
+    reply
+        .u32(1)
+        .string(path)
+        .string(path)
+        .attrs(&Attrs::default())
+}
+
+/// Resolves `path` against `/`, without following symbolic links
+fn real_path(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// Formats a directory entry as `ls -l` does, for clients to show as they are
+fn long_name(name: &str, metadata: &Metadata) -> String {
+    let mode = metadata.mode();
+    let mut permissions = String::from(if metadata.is_dir() {
+        "d"
+    } else if metadata.is_symlink() {
+        "l"
+    } else {
+        "-"
+    });
+    for shift in [6, 3, 0] {
+        for (bit, flag) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            permissions.push(if mode >> shift & bit == 0 { '-' } else { flag });
+        }
+    }
+    let modified = metadata
+        .modified()
+        .map(|modified| DateTime::from(modified).ls())
+        .unwrap_or_default();
+    format!(
+        "{permissions} 1 {:<8} {:<8} {:>8} {modified} {name}",
+        metadata.uid().unwrap_or_default(),
+        metadata.gid().unwrap_or_default(),
+        metadata.len()
+    )
+}
+
+fn file_attrs(metadata: &Metadata) -> Attrs {
+    let secs = |time: io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |elapsed| {
+                u32::try_from(elapsed.as_secs()).unwrap_or(u32::MAX)
+            })
+    };
+    Attrs {
+        size: Some(metadata.len()),
+        uid_gid: metadata.uid().zip(metadata.gid()),
+        permissions: Some(metadata.mode()),
+        atime_mtime: Some((secs(metadata.accessed()), secs(metadata.modified()))),
+    }
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn permissions(mode: u32) -> io::Result<std::fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn permissions(_: u32) -> io::Result<std::fs::Permissions> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "permissions can only be set on unix",
+    ))
+}
+
+fn unsupported_flags(flags: u32) -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        format!("unsupported open flags {flags:#x}"),
+    )
+}
+
+fn invalid_handle() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "invalid handle")
+}
+
+fn wrong_mode() -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        "the file wasn't opened for this",
+    )
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "unexpected response from inner service",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        path::Path,
+        sync::{Arc, Mutex, PoisonError},
+        task::Poll,
+    };
+
+    use futures::future::{ready, Ready};
+    use tokio::{io::DuplexStream, task::AbortHandle};
+
+    use super::*;
+    use crate::{
+        backend::sftp::{protocol::FX_EOF, Config, Sftp},
+        test_kit::TestDir,
+        FileSystem,
+    };
+
+    /// Connects to a new server task answering requests with [`FileSystem`]
+    #[derive(Clone, Default)]
+    struct Connector {
+        servers: Arc<Mutex<Vec<AbortHandle>>>,
+    }
+
+    impl Connector {
+        fn connections(&self) -> usize {
+            self.servers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .len()
+        }
+
+        /// Stops every server, breaking the connections to them
+        async fn disconnect(&self) {
+            for server in self
+                .servers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+            {
+                server.abort();
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    impl Service<()> for Connector {
+        type Response = DuplexStream;
+        type Error = Infallible;
+        type Future = Ready<Result<DuplexStream, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, (): ()) -> Self::Future {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let server = tokio::spawn(serve(server, FileSystem::new()));
+            self.servers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(server.abort_handle());
+            ready(Ok(client))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_round_trip() -> io::Result<()> {
+        let dir = TestDir::new("sftp_server")?;
+        let (file, renamed) = (dir.join("file.txt"), dir.join("renamed.txt"));
+        let config = Config {
+            chunk_size: 4,
+            ..Config::default()
+        };
+        let mut sftp = Sftp::new(Connector::default(), config);
+
+        sftp.call(Request::CreateDir {
+            path: dir.path().into(),
+            recursive: true,
+        })
+        .await?;
+        sftp.call(Request::WriteBytes {
+            path: file.as_path().into(),
+            bytes: b"sftp contents".to_vec(),
+        })
+        .await?;
+        assert!(matches!(
+            sftp.call(Request::ReadRange {
+                path: file.as_path().into(),
+                range: 5..100,
+            })
+            .await?,
+            Response::Bytes(bytes) if bytes == b"contents"
+        ));
+        sftp.call(Request::Rename {
+            from: file.as_path().into(),
+            to: renamed.as_path().into(),
+        })
+        .await?;
+        assert!(matches!(
+            sftp.call(Request::Exists(file.into())).await?,
+            Response::Exists(false)
+        ));
+        assert_eq!(std::fs::read(&renamed)?, b"sftp contents");
+        sftp.call(Request::RemoveFile(renamed.into())).await?;
+        sftp.call(Request::RemoveDir {
+            path: dir.path().into(),
+            recursive: false,
+        })
+        .await?;
+        assert!(!dir.exists());
+        assert_eq!(real_path("a/./b/../../c/"), "/c");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_metadata() -> io::Result<()> {
+        let dir = TestDir::new("sftp_client_metadata")?;
+        std::fs::create_dir(dir.join("nested"))?;
+        std::fs::write(dir.join("file.txt"), "contents")?;
+        let mut sftp = Sftp::new(Connector::default(), Config::default());
+
+        let Response::Metadata(metadata) = sftp
+            .call(Request::GetMetadata {
+                path: dir.join("file.txt").into(),
+                follow_symlinks: true,
+            })
+            .await?
+        else {
+            unreachable!()
+        };
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 8);
+        // SFTP only has whole seconds
+        let secs = |time: io::Result<SystemTime>| {
+            time.ok()
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|since| since.as_secs())
+        };
+        assert_eq!(
+            secs(metadata.modified()),
+            secs(std::fs::metadata(dir.join("file.txt"))?.modified())
+        );
+        let Response::MetadataBatch(batch) = sftp
+            .call(Request::MetadataBatch {
+                paths: vec![dir.join("nested").into(), dir.join("missing").into()],
+                follow_symlinks: false,
+            })
+            .await?
+        else {
+            unreachable!()
+        };
+        assert!(batch[0].as_ref().is_ok_and(Metadata::is_dir));
+        assert!(batch[1]
+            .as_ref()
+            .is_err_and(|err| err.kind() == ErrorKind::NotFound));
+
+        let Response::Directory(entries) = sftp.call(Request::ReadDir(dir.path().into())).await?
+        else {
+            unreachable!()
+        };
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_path()).collect();
+        assert_eq!(names, [Path::new("file.txt"), Path::new("nested")]);
+        assert!(entries[0].1.is_file() && entries[1].1.is_dir());
+        assert_eq!(entries[0].1.len(), 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_open() -> io::Result<()> {
+        let dir = TestDir::new("sftp_client_open")?;
+        let path: Arc<Path> = dir.join("file.txt").into();
+        let config = Config {
+            chunk_size: 4,
+            ..Config::default()
+        };
+        let connector = Connector::default();
+        let mut sftp = Sftp::new(connector.clone(), config);
+        let open = |mode| Request::Open {
+            mode,
+            path: path.clone(),
+        };
+
+        let mut file = sftp.call(open(Mode::CreateNew)).await?.into_file()?;
+        file.write_all(b"remote file").await?;
+        file.shutdown().await?;
+        assert_eq!(std::fs::read(&path)?, b"remote file");
+        // SFTP version 3 has no status for existing files, so the kind of error is lost
+        assert!(sftp.call(open(Mode::CreateNew)).await.is_err());
+
+        let mut file = sftp.call(open(Mode::Read)).await?.into_file()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "remote file");
+        assert_eq!(file.seek(SeekFrom::End(-4)).await?, 7);
+        contents.clear();
+        file.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "file");
+        file.seek(SeekFrom::Start(2)).await?;
+        let mut bytes = [0; 3];
+        file.read_exact(&mut bytes).await?;
+        assert_eq!(&bytes, b"mot");
+        file.shutdown().await?;
+
+        let mut file = sftp.call(open(Mode::AppendExisting)).await?.into_file()?;
+        file.write_all(b"!").await?;
+        file.shutdown().await?;
+        assert_eq!(std::fs::read(&path)?, b"remote file!");
+        // Every file's session went back to the pool once it was shut down
+        assert_eq!(connector.connections(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnect() -> io::Result<()> {
+        let dir = TestDir::new("sftp_client_reconnect")?;
+        std::fs::write(dir.join("file.txt"), "contents")?;
+        let exists = || Request::Exists(dir.join("file.txt").into());
+        let connector = Connector::default();
+        let mut sftp = Sftp::new(connector.clone(), Config::default());
+
+        sftp.call(exists()).await?;
+        // The pooled session's connection fails, so the request is retried on a new one
+        connector.disconnect().await;
+        assert!(matches!(sftp.call(exists()).await?, Response::Exists(true)));
+        assert_eq!(connector.connections(), 2);
+        connector.disconnect().await;
+        assert!(matches!(
+            sftp.call(Request::Open {
+                mode: Mode::Read,
+                path: dir.join("file.txt").into(),
+            })
+            .await?,
+            Response::File(_)
+        ));
+        assert_eq!(connector.connections(), 3);
+
+        // Without retries the failure is returned, and the broken session is dropped
+        let mut sftp = Sftp::new(
+            connector.clone(),
+            Config {
+                retries: 0,
+                ..Config::default()
+            },
+        );
+        sftp.call(exists()).await?;
+        connector.disconnect().await;
+        assert!(sftp.call(exists()).await.is_err());
+        assert!(matches!(sftp.call(exists()).await?, Response::Exists(true)));
+        assert_eq!(connector.connections(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_dir() -> io::Result<()> {
+        let dir = TestDir::new("sftp_server_dir")?;
+        std::fs::create_dir(dir.join("nested"))?;
+        std::fs::write(dir.join("file.txt"), "contents")?;
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(server, FileSystem::new()));
+        Writer::default()
+            .u32(VERSION)
+            .send(&mut client, FXP_INIT)
+            .await?;
+        assert_eq!(receive(&mut client).await?.0, FXP_VERSION);
+
+        let path = dir.to_str().unwrap_or_default();
+        Writer::default()
+            .u32(1)
+            .string(path)
+            .send(&mut client, FXP_OPENDIR)
+            .await?;
+        let (ty, body) = receive(&mut client).await?;
+        let mut reader = Reader(&body);
+        assert_eq!((ty, reader.u32()?), (FXP_HANDLE, 1));
+        let handle = reader.bytes()?;
+        let readdir = || Writer::default().u32(2).string(&handle);
+        readdir().send(&mut client, FXP_READDIR).await?;
+        let (ty, body) = receive(&mut client).await?;
+        let mut reader = Reader(&body);
+        assert_eq!((ty, reader.u32()?, reader.u32()?), (FXP_NAME, 2, 2));
+        let mut entries = Vec::new();
+        for _ in 0..2 {
+            let (name, long_name) = (reader.string()?, reader.string()?);
+            entries.push((name, long_name, reader.attrs()?));
+        }
+        assert_eq!(
+            (entries[0].0.as_str(), entries[1].0.as_str()),
+            ("file.txt", "nested")
+        );
+        assert!(entries[0].1.starts_with("-rw") && entries[0].1.ends_with(" file.txt"));
+        assert!(entries[1].1.starts_with("drwx") && entries[1].2.is_dir());
+        assert_eq!(entries[0].2.size, Some(8));
+        // Then the end of the directory is reported
+        readdir().send(&mut client, FXP_READDIR).await?;
+        let (ty, body) = receive(&mut client).await?;
+        let mut reader = Reader(&body);
+        assert_eq!((ty, reader.u32()?, reader.u32()?), (FXP_STATUS, 2, FX_EOF));
+        Writer::default()
+            .u32(3)
+            .string(&handle)
+            .send(&mut client, FXP_CLOSE)
+            .await?;
+        let (ty, body) = receive(&mut client).await?;
+        let mut reader = Reader(&body);
+        assert_eq!((ty, reader.u32()?, reader.u32()?), (FXP_STATUS, 3, FX_OK));
+        Ok(())
+    }
+}
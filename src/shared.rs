@@ -0,0 +1,68 @@
+//! Calling stateless backends through shared references
+
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tower_service::Service;
+
+use crate::{Request, Response};
+
+/// A backend which answers requests through a shared reference, and is always ready
+///
+/// Such backends are `Service<Request>` through an [`Arc`], and the backends of this crate also
+/// through `&`, so they can live in shared application state (such as axum's `State`, or a
+/// `static`) and be called from many tasks at once, without cloning them or taking `&mut` access.
+pub trait SharedService {
+    type Future: Future<Output = io::Result<Response>>;
+
+    /// Answers `req`, as [`Service::call`] would
+    fn call_shared(&self, req: Request) -> Self::Future;
+}
+
+impl<S: SharedService + ?Sized> Service<Request> for Arc<S> {
+    type Response = Response;
+    type Error = io::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        self.call_shared(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, FileSystemExt};
+
+    #[tokio::test]
+    async fn test_shared_service() -> io::Result<()> {
+        static FS: FileSystem = FileSystem::new();
+
+        let dir = TestDir::new("shared")?;
+        let path = dir.join("shared");
+        let shared = Arc::new(FileSystem::new());
+        let tasks: Vec<_> = (0..4u8)
+            .map(|i| {
+                let (mut shared, path) = (shared.clone(), path.with_extension(i.to_string()));
+                tokio::spawn(async move { shared.write(path, vec![i]).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.map_err(io::Error::other)??;
+        }
+        for i in 0..4u8 {
+            let path = path.with_extension(i.to_string());
+            assert_eq!((&FS).read(&path).await?, [i]);
+            (&FS).remove_file(path).await?;
+        }
+        Ok(())
+    }
+}
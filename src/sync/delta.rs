@@ -0,0 +1,477 @@
+//! Rolling checksum block matching, as rsync and rdiff do, to find what changed in a large file
+//!
+//! The holder of the old version of a file sends it's [`Signature`], the holder of the new version
+//! works out the [`Delta`] from it, and the holder of the old version [`Delta::patch`]es it with
+//! the delta.  Only the signature (a few dozen bytes per block) and the delta (the blocks which
+//! changed) need to be sent between them.
+//!
+//! [`transfer`] does all three through services, which can't do any of the work themselves, so
+//! both versions are read in full.  It saves writing the unchanged part of a file which only had
+//! bytes appended to it, but the types here are exposed for protocols which can compute
+//! signatures and apply deltas at the far end.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+use tokio::io::AsyncWriteExt;
+use tower_service::Service;
+
+use super::Tree;
+use crate::{digest::sha256, ext::ready_call, FileSystemExt, Mode, Request, Response};
+
+/// The checksums of each block of a file, for finding the blocks in another version of it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signature {
+    block_size: usize,
+    base_len: u64,
+    blocks: Vec<BlockSignature>,
+}
+
+/// The checksums of one block of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockSignature {
+    /// A checksum which can be rolled along the new version a byte at a time
+    pub weak: u32,
+    /// The SHA-256 digest of the block, which confirms a match of the weak checksum
+    pub strong: [u8; 32],
+}
+
+impl Signature {
+    /// The signature of `base`, split into blocks of `block_size` bytes
+    ///
+    /// Smaller blocks find more of the file unchanged, but make a larger signature.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is zero
+    #[must_use]
+    pub fn new(base: &[u8], block_size: usize) -> Self {
+        assert!(block_size > 0, "blocks must hold at least one byte");
+        let blocks = base
+            .chunks(block_size)
+            .map(|block| BlockSignature {
+                weak: Rolling::new(block).checksum(),
+                strong: sha256(block),
+            })
+            .collect();
+        Self {
+            block_size,
+            base_len: base.len() as u64,
+            blocks,
+        }
+    }
+
+    /// Rebuilds a signature sent from elsewhere
+    ///
+    /// Every block is `block_size` bytes long, except the last, which holds what's left of
+    /// `base_len`.
+    ///
+    /// # Errors
+    ///
+    /// With [`ErrorKind::InvalidData`] if the number of blocks doesn't match the lengths
+    pub fn from_parts(
+        block_size: usize,
+        base_len: u64,
+        blocks: Vec<BlockSignature>,
+    ) -> io::Result<Self> {
+        if block_size == 0 || base_len.div_ceil(block_size as u64) != blocks.len() as u64 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "the signature's blocks don't cover the file",
+            ));
+        }
+        Ok(Self {
+            block_size,
+            base_len,
+            blocks,
+        })
+    }
+
+    #[must_use]
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The length of the file the signature was made from
+    #[must_use]
+    pub fn base_len(&self) -> u64 {
+        self.base_len
+    }
+
+    #[must_use]
+    pub fn blocks(&self) -> &[BlockSignature] {
+        &self.blocks
+    }
+
+    /// The delta which turns the file this is the signature of into `data`
+    #[must_use]
+    pub fn delta(&self, data: &[u8]) -> Delta {
+        let size = self.block_size;
+        let mut full_blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, block) in self.blocks.iter().enumerate() {
+            if self.block_len(index) == size {
+                full_blocks.entry(block.weak).or_default().push(index);
+            }
+        }
+        let find = |window: &[u8], weak: u32| {
+            let candidates = full_blocks.get(&weak)?;
+            let strong = sha256(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|&index| self.blocks[index].strong == strong)
+        };
+
+        let mut delta = Delta::default();
+        let (mut start, mut literal_start) = (0, 0);
+        let mut rolling = data.get(..size).map(Rolling::new);
+        while let Some(weak) = rolling.as_ref().map(Rolling::checksum) {
+            if let Some(index) = find(&data[start..start + size], weak) {
+                delta.push_data(&data[literal_start..start]);
+                delta.push_copy(self.offset(index), size as u64);
+                start += size;
+                literal_start = start;
+                rolling = data.get(start..start + size).map(Rolling::new);
+            } else if let (Some(rolling), Some(&next)) = (&mut rolling, data.get(start + size)) {
+                rolling.roll(data[start], next);
+                start += 1;
+            } else {
+                break;
+            }
+        }
+
+        // A last block shorter than the others is only looked for straight after the blocks found,
+        // as when bytes have been appended, or at the end of the data
+        let last = self.blocks.len().checked_sub(1);
+        if let Some(index) = last.filter(|&index| self.block_len(index) < size) {
+            let len = self.block_len(index);
+            let found = [literal_start, data.len().saturating_sub(len)]
+                .into_iter()
+                .filter(|&at| at >= literal_start)
+                .find(|&at| {
+                    data.get(at..at + len)
+                        .is_some_and(|block| sha256(block) == self.blocks[index].strong)
+                });
+            if let Some(at) = found {
+                delta.push_data(&data[literal_start..at]);
+                delta.push_copy(self.offset(index), len as u64);
+                literal_start = at + len;
+            }
+        }
+        delta.push_data(&data[literal_start..]);
+        delta
+    }
+
+    fn offset(&self, index: usize) -> u64 {
+        index as u64 * self.block_size as u64
+    }
+
+    fn block_len(&self, index: usize) -> usize {
+        let remaining = self.base_len - self.offset(index);
+        usize::try_from(remaining)
+            .map_or(self.block_size, |remaining| remaining.min(self.block_size))
+    }
+}
+
+/// The instructions to rebuild a new version of a file from the old version
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Delta(Vec<DeltaOp>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DeltaOp {
+    /// Copies `len` bytes from `offset` in the old version
+    Copy { offset: u64, len: u64 },
+    /// Bytes which aren't in the old version
+    Data(Vec<u8>),
+}
+
+impl Delta {
+    #[must_use]
+    pub fn ops(&self) -> &[DeltaOp] {
+        &self.0
+    }
+
+    /// The bytes of the new version taken from the old version
+    #[must_use]
+    pub fn copied_len(&self) -> u64 {
+        self.0
+            .iter()
+            .map(|op| match op {
+                DeltaOp::Copy { len, .. } => *len,
+                DeltaOp::Data(_) => 0,
+            })
+            .sum()
+    }
+
+    /// The bytes of the new version which had to be sent
+    #[must_use]
+    pub fn literal_len(&self) -> u64 {
+        self.0
+            .iter()
+            .map(|op| match op {
+                DeltaOp::Copy { .. } => 0,
+                DeltaOp::Data(data) => data.len() as u64,
+            })
+            .sum()
+    }
+
+    /// Rebuilds the new version from `base`, the old version
+    ///
+    /// # Errors
+    ///
+    /// With [`ErrorKind::InvalidData`] if the delta copies bytes from beyond the end of `base`
+    pub fn patch(&self, base: &[u8]) -> io::Result<Vec<u8>> {
+        let mut patched = Vec::new();
+        for op in &self.0 {
+            match op {
+                DeltaOp::Copy { offset, len } => {
+                    let copied = usize::try_from(*offset)
+                        .ok()
+                        .zip(usize::try_from(offset + len).ok())
+                        .and_then(|(start, end)| base.get(start..end))
+                        .ok_or_else(|| {
+                            io::Error::new(ErrorKind::InvalidData, "the delta is for another file")
+                        })?;
+                    patched.extend_from_slice(copied);
+                }
+                DeltaOp::Data(data) => patched.extend_from_slice(data),
+            }
+        }
+        Ok(patched)
+    }
+
+    /// The bytes to append to the old version, `base_len` bytes long, to make the new version, if
+    /// it starts with all of the old version
+    fn appended(&self, base_len: u64) -> Option<Vec<&[u8]>> {
+        let rest = match self.0.split_first() {
+            Some((DeltaOp::Copy { offset: 0, len }, rest)) if *len == base_len => rest,
+            _ if base_len == 0 => &self.0[..],
+            _ => return None,
+        };
+        rest.iter()
+            .map(|op| match op {
+                DeltaOp::Data(data) => Some(data.as_slice()),
+                DeltaOp::Copy { .. } => None,
+            })
+            .collect()
+    }
+
+    fn push_copy(&mut self, offset: u64, len: u64) {
+        if let Some(DeltaOp::Copy {
+            offset: previous,
+            len: previous_len,
+        }) = self.0.last_mut()
+        {
+            if *previous + *previous_len == offset {
+                *previous_len += len;
+                return;
+            }
+        }
+        self.0.push(DeltaOp::Copy { offset, len });
+    }
+
+    fn push_data(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        match self.0.last_mut() {
+            Some(DeltaOp::Data(previous)) => previous.extend_from_slice(data),
+            _ => self.0.push(DeltaOp::Data(data.to_vec())),
+        }
+    }
+}
+
+impl From<Vec<DeltaOp>> for Delta {
+    fn from(ops: Vec<DeltaOp>) -> Self {
+        Self(ops)
+    }
+}
+
+/// Updates the file at `path` in `destination` to match `source`, with a delta from it's
+/// signature, and returns the delta
+///
+/// If only bytes were appended in the source, only they're appended in the destination.
+/// Otherwise the patched file is written in full.  Both versions are held in memory.
+///
+/// # Errors
+///
+/// If a request to either tree fails
+///
+/// # Panics
+///
+/// If `block_size` is zero
+pub async fn transfer<A, B>(
+    source: &mut Tree<A>,
+    destination: &mut Tree<B>,
+    path: &Path,
+    block_size: usize,
+) -> io::Result<Delta>
+where
+    A: Service<Request, Response = Response, Error = io::Error>,
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    let to: Arc<Path> = destination.resolve(path).into();
+    let base = destination.service.read(&to).await?;
+    let signature = Signature::new(&base, block_size);
+    let data = source.service.read(source.resolve(path)).await?;
+    let delta = signature.delta(&data);
+    if let Some(appended) = delta.appended(signature.base_len()) {
+        let mut file = destination.service.open(&to, Mode::CreateOrAppend).await?;
+        for data in appended {
+            file.write_all(data).await?;
+        }
+        file.flush().await?;
+    } else {
+        let req = Request::WriteBytes {
+            path: to,
+            bytes: delta.patch(&base)?,
+        };
+        ready_call(&mut destination.service, req)
+            .await?
+            .into_done()?;
+    }
+    Ok(delta)
+}
+
+/// The weak checksum of a window of bytes, which can be moved along a byte at a time
+#[derive(Debug)]
+struct Rolling {
+    len: u32,
+    a: u32,
+    b: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = u32::try_from(window.len()).unwrap_or(u32::MAX);
+        let (mut a, mut b) = (0u32, 0u32);
+        for (byte, weight) in window.iter().zip((1..=len).rev()) {
+            a = a.wrapping_add(u32::from(*byte));
+            b = b.wrapping_add(weight.wrapping_mul(u32::from(*byte)));
+        }
+        Self { len, a, b }
+    }
+
+    fn checksum(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Moves the window on by one byte, dropping `old` from the start and adding `new` to the end
+    fn roll(&mut self, old: u8, new: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(old))
+            .wrapping_add(u32::from(new));
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(u32::from(old)))
+            .wrapping_add(self.a);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    fn contents(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                state.to_be_bytes()[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rolling() {
+        let data = contents(100, 1);
+        let mut rolling = Rolling::new(&data[..16]);
+        for start in 1..=84 {
+            rolling.roll(data[start - 1], data[start + 15]);
+            assert_eq!(
+                rolling.checksum(),
+                Rolling::new(&data[start..start + 16]).checksum()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta() -> io::Result<()> {
+        let base = contents(10_000, 2);
+        let signature = Signature::new(&base, 64);
+        let unchanged = signature.delta(&base);
+        assert_eq!(
+            unchanged.ops(),
+            [DeltaOp::Copy {
+                offset: 0,
+                len: 10_000
+            }]
+        );
+
+        let mut edited = base.clone();
+        edited.splice(5000..5010, *b"an edit in the middle");
+        edited.drain(..100);
+        edited.extend_from_slice(b"appended");
+        let delta = signature.delta(&edited);
+        assert_eq!(delta.patch(&base)?, edited);
+        assert!(delta.literal_len() < 300, "{}", delta.literal_len());
+        assert_eq!(
+            delta.copied_len() + delta.literal_len(),
+            edited.len() as u64
+        );
+
+        let different = contents(5000, 3);
+        let delta = signature.delta(&different);
+        assert_eq!(delta.ops(), [DeltaOp::Data(different.clone())]);
+        assert_eq!(Signature::new(&[], 64).delta(&different), delta);
+        assert_eq!(signature.delta(&[]), Delta::default());
+
+        let copy_past_end = Delta::from(vec![DeltaOp::Copy {
+            offset: 9_990,
+            len: 20,
+        }]);
+        assert_eq!(
+            copy_past_end.patch(&base).map_err(|err| err.kind()),
+            Err(ErrorKind::InvalidData)
+        );
+        let rebuilt = Signature::from_parts(64, 10_000, signature.blocks().to_vec())?;
+        assert_eq!(rebuilt, signature);
+        assert!(Signature::from_parts(64, 20_000, signature.blocks().to_vec()).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer() -> io::Result<()> {
+        let dir = TestDir::new("delta")?;
+        let (source, destination) = (dir.join("source"), dir.join("destination"));
+        std::fs::create_dir_all(&source)?;
+        std::fs::create_dir_all(&destination)?;
+        let base = contents(50_000, 4);
+        std::fs::write(destination.join("log"), &base)?;
+        let mut appended = base.clone();
+        appended.extend_from_slice(b"a new line\n");
+        std::fs::write(source.join("log"), &appended)?;
+        std::fs::write(destination.join("data"), &base)?;
+        let mut edited = base.clone();
+        edited[25_000] ^= 0xff;
+        std::fs::write(source.join("data"), &edited)?;
+
+        let mut source = Tree::new(FileSystem::new(), source);
+        let mut destination = Tree::new(FileSystem::new(), destination);
+        let delta = transfer(&mut source, &mut destination, Path::new("log"), 1024).await?;
+        assert_eq!(delta.literal_len(), 11);
+        assert_eq!(std::fs::read(dir.join("destination/log"))?, appended);
+        let delta = transfer(&mut source, &mut destination, Path::new("data"), 1024).await?;
+        assert_eq!(delta.literal_len(), 1024);
+        assert_eq!(std::fs::read(dir.join("destination/data"))?, edited);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+//! Working out the changes which turn one tree into another
+
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use tokio::io::AsyncReadExt;
+use tower_service::Service;
+
+use super::{entries, relative, Tree};
+use crate::{
+    digest::Sha256, ext::ready_call, BufferPool, FileSystemExt, FileType, Metadata, Mode, Request,
+    Response,
+};
+
+/// A difference between two trees, with paths relative to their roots
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Change {
+    /// The path only exists in the later tree
+    Added(PathBuf),
+    /// The path only exists in the earlier tree
+    Removed(PathBuf),
+    /// The path exists in both trees, but as different types of entry, files with different
+    /// contents, or symlinks to different targets
+    Modified(PathBuf),
+    /// A file was removed from `from` and added, unchanged, at `to`
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+impl Change {
+    /// The paths the change affects
+    #[must_use]
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::Added(path) | Self::Removed(path) | Self::Modified(path) => vec![path],
+            Self::Renamed { from, to } => vec![from, to],
+        }
+    }
+
+    /// The path the change is ordered by, which is where a renamed file ended up
+    fn key(&self) -> &Path {
+        match self {
+            Self::Added(path) | Self::Removed(path) | Self::Modified(path) => path,
+            Self::Renamed { to, .. } => to,
+        }
+    }
+}
+
+/// The changes between two trees, from [`diff`], in path order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet(Vec<Change>);
+
+impl ChangeSet {
+    /// Whether the trees matched
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Change> {
+        self.0.iter()
+    }
+
+    /// The paths of the entries only in the later tree
+    pub fn added(&self) -> impl Iterator<Item = &Path> {
+        self.iter().filter_map(|change| match change {
+            Change::Added(path) => Some(path.as_path()),
+            _ => None,
+        })
+    }
+
+    /// The paths of the entries only in the earlier tree
+    pub fn removed(&self) -> impl Iterator<Item = &Path> {
+        self.iter().filter_map(|change| match change {
+            Change::Removed(path) => Some(path.as_path()),
+            _ => None,
+        })
+    }
+
+    /// The paths of the entries which differ between the trees
+    pub fn modified(&self) -> impl Iterator<Item = &Path> {
+        self.iter().filter_map(|change| match change {
+            Change::Modified(path) => Some(path.as_path()),
+            _ => None,
+        })
+    }
+
+    /// Where each renamed file was, and where it is now
+    pub fn renamed(&self) -> impl Iterator<Item = (&Path, &Path)> {
+        self.iter().filter_map(|change| match change {
+            Change::Renamed { from, to } => Some((from.as_path(), to.as_path())),
+            _ => None,
+        })
+    }
+}
+
+impl IntoIterator for ChangeSet {
+    type Item = Change;
+    type IntoIter = std::vec::IntoIter<Change>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChangeSet {
+    type Item = &'a Change;
+    type IntoIter = std::slice::Iter<'a, Change>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Compare files of the same length by the SHA-256 digests of their contents, rather than by
+    /// their modification times
+    ///
+    /// This reads every file on both sides, but finds changes which kept the length and
+    /// modification time, and doesn't report files copied between backends (which rarely keep
+    /// modification times) as modified.
+    pub compare_contents: bool,
+    /// Only compare these paths and what's below them, rather than everything below the roots
+    ///
+    /// Paths are relative to the roots, or absolute paths below them, and those missing from both
+    /// trees are ignored.
+    pub paths: Option<Vec<PathBuf>>,
+}
+
+/// The changes which turn the `before` tree into the `after` tree
+///
+/// Both trees are walked with [`Request::ReadDir`], without following symlinks, and a root which
+/// doesn't exist is treated as empty.  A file removed from one path and added at another
+/// is reported as renamed, if it's contents (or without [`DiffOptions::compare_contents`], it's
+/// length and modification time) are unchanged.  Directories are only compared by type, as the
+/// entries within them are compared too.  Files without a modification time are
+/// always reported as modified, unless their contents are compared.
+///
+/// # Errors
+///
+/// If a request to either tree fails, other than because a path doesn't exist
+pub async fn diff<A, B>(
+    mut before: Tree<A>,
+    mut after: Tree<B>,
+    options: DiffOptions,
+) -> io::Result<ChangeSet>
+where
+    A: Service<Request, Response = Response, Error = io::Error>,
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    let paths = options.paths.as_ref().map(relative);
+    let old_entries = entries(&mut before, paths.as_ref()).await?;
+    let new_entries = entries(&mut after, paths.as_ref()).await?;
+    let paths: BTreeSet<_> = old_entries.keys().chain(new_entries.keys()).collect();
+    let mut changes = Vec::new();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    for path in paths {
+        let old = match old_entries.get(path) {
+            Some(metadata) => Some(entry(&mut before, path, metadata, &options).await?),
+            None => None,
+        };
+        let new = match new_entries.get(path) {
+            Some(metadata) => Some(entry(&mut after, path, metadata, &options).await?),
+            None => None,
+        };
+        let path = path.clone();
+        match (old, new) {
+            (None, None) => {}
+            (Some(old), None) => removed.push((path, old)),
+            (None, Some(new)) => added.push((path, new)),
+            (Some(old), Some(new)) => {
+                if old.differs(&new) {
+                    changes.push(Change::Modified(path));
+                }
+            }
+        }
+    }
+    for (path, new) in added {
+        match removed.iter().position(|(_, old)| old.same_file(&new)) {
+            Some(index) => changes.push(Change::Renamed {
+                from: removed.remove(index).0,
+                to: path,
+            }),
+            None => changes.push(Change::Added(path)),
+        }
+    }
+    changes.extend(removed.into_iter().map(|(path, _)| Change::Removed(path)));
+    changes.sort_by(|change, other| change.key().cmp(other.key()));
+    Ok(ChangeSet(changes))
+}
+
+/// What's compared about an entry
+#[derive(Debug)]
+struct Entry {
+    file_type: FileType,
+    len: u64,
+    modified: Option<SystemTime>,
+    /// The SHA-256 digest of a file's contents, if they're being compared
+    digest: Option<[u8; 32]>,
+    /// Where a symlink points
+    target: Option<PathBuf>,
+}
+
+impl Entry {
+    /// Whether `other`, at the same path in the other tree, differs from this entry
+    fn differs(&self, other: &Self) -> bool {
+        if self.file_type != other.file_type {
+            return true;
+        }
+        match self.file_type {
+            FileType::File => {
+                self.len != other.len
+                    || match (self.digest, other.digest) {
+                        (Some(digest), Some(other_digest)) => digest != other_digest,
+                        _ => self.modified.is_none() || self.modified != other.modified,
+                    }
+            }
+            FileType::Symlink => self.target != other.target,
+            FileType::Dir | FileType::Other => false,
+        }
+    }
+
+    /// Whether `other` is likely this file under another name
+    fn same_file(&self, other: &Self) -> bool {
+        self.file_type == FileType::File && !self.differs(other)
+    }
+}
+
+/// What's compared about the entry at `path`, which was listed with `metadata`
+async fn entry<S>(
+    tree: &mut Tree<S>,
+    path: &Path,
+    metadata: &Metadata,
+    options: &DiffOptions,
+) -> io::Result<Entry>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let path = tree.resolve(path);
+    let target = if metadata.is_symlink() {
+        let req = Request::FollowLink(path.as_path().into());
+        Some(ready_call(&mut tree.service, req).await?.into_points_to()?)
+    } else {
+        None
+    };
+    let digest = if options.compare_contents && metadata.is_file() {
+        Some(digest(&mut tree.service, &path).await?)
+    } else {
+        None
+    };
+    Ok(Entry {
+        file_type: metadata.file_type(),
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        digest,
+        target,
+    })
+}
+
+/// The SHA-256 digest of the contents of the file at `path`
+pub(super) async fn digest<S>(service: &mut S, path: &Path) -> io::Result<[u8; 32]>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut file = service.open(path, Mode::Read).await?;
+    let mut hasher = Sha256::default();
+    let mut buf = BufferPool::shared().get();
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buf[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, time::Duration};
+
+    use super::*;
+    use crate::test_kit::TestDir;
+    use crate::FileSystem;
+
+    /// Writes `contents` to `path`, modified at `modified` seconds after the epoch
+    fn write(path: &Path, contents: &str, modified: u64) -> io::Result<()> {
+        std::fs::write(path, contents)?;
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified))
+    }
+
+    #[tokio::test]
+    async fn test_diff() -> io::Result<()> {
+        let dir = TestDir::new("diff")?;
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        for root in [&old, &new] {
+            std::fs::create_dir_all(root.join("sub"))?;
+            write(&root.join("same.txt"), "same", 1000)?;
+        }
+        write(&old.join("edited.txt"), "old", 1000)?;
+        write(&new.join("edited.txt"), "new", 2000)?;
+        write(&old.join("removed.txt"), "gone", 1000)?;
+        write(&new.join("added.txt"), "fresh", 1000)?;
+        write(&old.join("old_name.txt"), "moving", 1000)?;
+        write(&new.join("sub/new_name.txt"), "moving", 1000)?;
+
+        let paths = [
+            "same.txt",
+            "edited.txt",
+            "removed.txt",
+            "added.txt",
+            "old_name.txt",
+            "/sub",
+            "missing.txt",
+        ]
+        .map(PathBuf::from);
+        let mut fs = FileSystem::new();
+        for (compare_contents, paths) in [(false, None), (true, None), (false, Some(paths))] {
+            let options = DiffOptions {
+                compare_contents,
+                paths: paths.map(Vec::from),
+            };
+            let changes = diff(
+                Tree::new(&mut fs, &old),
+                Tree::new(FileSystem::new(), &new),
+                options,
+            )
+            .await?;
+            assert_eq!(
+                changes.iter().cloned().collect::<Vec<_>>(),
+                vec![
+                    Change::Added("added.txt".into()),
+                    Change::Modified("edited.txt".into()),
+                    Change::Removed("removed.txt".into()),
+                    Change::Renamed {
+                        from: "old_name.txt".into(),
+                        to: "sub/new_name.txt".into(),
+                    },
+                ]
+            );
+            assert_eq!(
+                changes.modified().collect::<Vec<_>>(),
+                [Path::new("edited.txt")]
+            );
+        }
+
+        // Same length and modification time, but different contents
+        write(&new.join("same.txt"), "diff", 1000)?;
+        let mut options = DiffOptions {
+            compare_contents: false,
+            paths: Some(vec!["same.txt".into()]),
+        };
+        let changes = diff(
+            Tree::new(&mut fs, &old),
+            Tree::new(FileSystem::new(), &new),
+            options.clone(),
+        )
+        .await?;
+        assert!(changes.is_empty());
+        options.compare_contents = true;
+        let changes = diff(
+            Tree::new(&mut fs, &old),
+            Tree::new(FileSystem::new(), &new),
+            options,
+        )
+        .await?;
+        assert_eq!(
+            changes.modified().collect::<Vec<_>>(),
+            [Path::new("same.txt")]
+        );
+
+        // A root which doesn't exist is empty
+        let changes = diff(
+            Tree::new(&mut fs, dir.join("missing")),
+            Tree::new(FileSystem::new(), &new),
+            DiffOptions::default(),
+        )
+        .await?;
+        assert_eq!(changes.added().count(), 5);
+
+        Ok(())
+    }
+}
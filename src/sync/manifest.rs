@@ -0,0 +1,317 @@
+//! Recording the files in a tree, and checking the tree still matches the record later
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use tower_service::Service;
+
+use super::{diff::digest, entries, Tree};
+use crate::{digest::hex, Metadata, Request, Response};
+
+/// The size, modification time and SHA-256 digest of each file in a tree
+///
+/// Manifests are written and read as text, with [`fmt::Display`] and [`FromStr`], one file per
+/// line:
+///
+/// ```text
+/// <hex digest> <size> <seconds.nanoseconds since the epoch, or -> <path>
+/// ```
+///
+/// The fields are separated by tabs, and backslashes, tabs and newlines in paths are escaped with
+/// backslashes.  Paths which aren't UTF-8 are written lossily.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+/// What a [`Manifest`] records about a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ManifestEntry {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub digest: [u8; 32],
+}
+
+/// How a tree differs from a [`Manifest`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Files which aren't in the manifest
+    pub added: Vec<PathBuf>,
+    /// Files in the manifest which are missing, or are no longer files
+    pub removed: Vec<PathBuf>,
+    /// Files whose contents changed along with their size or modification time
+    pub modified: Vec<PathBuf>,
+    /// Files whose contents changed, though their size and modification time didn't, as they do
+    /// when storage is corrupted
+    pub corrupted: Vec<PathBuf>,
+}
+
+impl Verification {
+    /// Whether the tree matched the manifest
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Manifest {
+    /// Records the files in `tree`, walking it with [`Request::ReadDir`] and reading every file
+    ///
+    /// Directories and symlinks are left out, and a root which doesn't exist is empty.
+    ///
+    /// # Errors
+    ///
+    /// If a request to the tree fails
+    pub async fn generate<S>(mut tree: Tree<S>) -> io::Result<Self>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let mut entries = BTreeMap::new();
+        for (path, metadata) in files(&mut tree).await? {
+            let entry = entry(&mut tree, &path, &metadata).await?;
+            entries.insert(path, entry);
+        }
+        Ok(Self { entries })
+    }
+
+    /// Walks `tree` again, and checks every file in it and in the manifest against the manifest
+    ///
+    /// # Errors
+    ///
+    /// If a request to the tree fails
+    pub async fn verify<S>(&self, mut tree: Tree<S>) -> io::Result<Verification>
+    where
+        S: Service<Request, Response = Response, Error = io::Error>,
+    {
+        let files = files(&mut tree).await?;
+        let paths: BTreeSet<_> = files.keys().chain(self.entries.keys()).collect();
+        let mut verification = Verification::default();
+        for path in paths {
+            let found = match files.get(path) {
+                Some(metadata) => Some(entry(&mut tree, path, metadata).await?),
+                None => None,
+            };
+            let path = path.clone();
+            match (self.entries.get(&path), found) {
+                (None, Some(_)) => verification.added.push(path),
+                (Some(_), None) => verification.removed.push(path),
+                (Some(recorded), Some(found)) if recorded.digest != found.digest => {
+                    if (recorded.len, recorded.modified) == (found.len, found.modified) {
+                        verification.corrupted.push(path);
+                    } else {
+                        verification.modified.push(path);
+                    }
+                }
+                (None, None) | (Some(_), Some(_)) => {}
+            }
+        }
+        Ok(verification)
+    }
+
+    #[must_use]
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&ManifestEntry> {
+        self.entries.get(path.as_ref())
+    }
+
+    /// The files recorded, in path order
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &ManifestEntry)> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (path.as_path(), entry))
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records `entry` for the file at `path`, replacing anything recorded for it before
+    pub fn insert(&mut self, path: impl Into<PathBuf>, entry: ManifestEntry) {
+        self.entries.insert(path.into(), entry);
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, entry) in &self.entries {
+            let modified = entry
+                .modified
+                .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or_else(
+                    || "-".to_owned(),
+                    |since| format!("{}.{:09}", since.as_secs(), since.subsec_nanos()),
+                );
+            let path = path
+                .to_string_lossy()
+                .replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n");
+            writeln!(
+                f,
+                "{}\t{}\t{modified}\t{path}",
+                hex(&entry.digest),
+                entry.len
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = BTreeMap::new();
+        for (number, line) in s.lines().enumerate() {
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {} of the manifest is invalid", number + 1),
+                )
+            };
+            let mut fields = line.splitn(4, '\t');
+            let (Some(digest), Some(len), Some(modified), Some(path)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let entry = ManifestEntry {
+                len: len.parse().map_err(|_| invalid())?,
+                modified: match modified {
+                    "-" => None,
+                    modified => Some(parse_modified(modified).ok_or_else(invalid)?),
+                },
+                digest: parse_digest(digest).ok_or_else(invalid)?,
+            };
+            entries.insert(unescape(path).ok_or_else(invalid)?, entry);
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// The files in `tree`, with the metadata they were listed with
+async fn files<S>(tree: &mut Tree<S>) -> io::Result<BTreeMap<PathBuf, Metadata>>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut files = entries(tree, None).await?;
+    files.retain(|_, metadata| metadata.is_file());
+    Ok(files)
+}
+
+/// What's recorded about the file at `path`, which was listed with `metadata`
+async fn entry<S>(tree: &mut Tree<S>, path: &Path, metadata: &Metadata) -> io::Result<ManifestEntry>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let path = tree.resolve(path);
+    Ok(ManifestEntry {
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        digest: digest(&mut tree.service, &path).await?,
+    })
+}
+
+fn parse_modified(modified: &str) -> Option<SystemTime> {
+    let (secs, nanos) = modified.split_once('.')?;
+    let since = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    SystemTime::UNIX_EPOCH.checked_add(since)
+}
+
+fn parse_digest(digest: &str) -> Option<[u8; 32]> {
+    if digest.len() != 64 || !digest.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(digest.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn unescape(path: &str) -> Option<PathBuf> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem};
+
+    #[tokio::test]
+    async fn test_manifest() -> io::Result<()> {
+        let dir = TestDir::new("manifest")?;
+        std::fs::create_dir_all(dir.join("sub"))?;
+        std::fs::write(dir.join("kept.txt"), "kept")?;
+        std::fs::write(dir.join("sub/edited.txt"), "before")?;
+        std::fs::write(dir.join("sub/rotted.txt"), "intact")?;
+        std::fs::write(dir.join("removed\ttab.txt"), "removed")?;
+
+        let manifest = Manifest::generate(Tree::new(FileSystem::new(), &dir)).await?;
+        assert_eq!(manifest.len(), 4);
+        assert_eq!(
+            manifest.get("sub/edited.txt").map(|entry| entry.len),
+            Some(6)
+        );
+        let parsed: Manifest = manifest.to_string().parse()?;
+        assert_eq!(parsed, manifest);
+        let verification = parsed.verify(Tree::new(FileSystem::new(), &dir)).await?;
+        assert!(verification.is_ok(), "{verification:?}");
+
+        std::fs::write(dir.join("added.txt"), "added")?;
+        std::fs::remove_file(dir.join("removed\ttab.txt"))?;
+        std::fs::write(dir.join("sub/edited.txt"), "after, and longer")?;
+        let rotted = dir.join("sub/rotted.txt");
+        let modified = std::fs::metadata(&rotted)?.modified()?;
+        std::fs::write(&rotted, "in tact")?;
+        std::fs::write(&rotted, "intacT")?;
+        File::options()
+            .write(true)
+            .open(&rotted)?
+            .set_modified(modified)?;
+        let verification = parsed.verify(Tree::new(FileSystem::new(), &dir)).await?;
+        assert_eq!(
+            verification,
+            Verification {
+                added: vec!["added.txt".into()],
+                removed: vec!["removed\ttab.txt".into()],
+                modified: vec!["sub/edited.txt".into()],
+                corrupted: vec!["sub/rotted.txt".into()],
+            }
+        );
+
+        assert_eq!(
+            "not a manifest"
+                .parse::<Manifest>()
+                .map_err(|err| err.kind()),
+            Err(ErrorKind::InvalidData)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+//! Applying the [`ChangeSet`] between two trees to make one a copy of the other
+
+use std::{
+    future::ready,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use tokio::io::AsyncWriteExt;
+use tower_service::Service;
+
+use super::{delta::transfer, diff, Change, ChangeSet, DiffOptions, Tree};
+use crate::{
+    ext::{copy_vectored, ready_call},
+    FileSystemExt, FileType, Mode, ProgressReporter, Request, Response,
+};
+
+/// A step taken to bring the destination tree up to date, with paths relative to the trees' roots
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Moves an entry already in the destination
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    CreateDir(PathBuf),
+    Symlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+    /// Copies a file of `len` bytes from the source
+    Copy {
+        path: PathBuf,
+        len: u64,
+    },
+    /// Removes a file, symlink, or directory and everything in it
+    Remove(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct MirrorOptions {
+    /// How many files to copy at once
+    pub concurrency: usize,
+    /// Work out the actions to take, without taking them
+    pub dry_run: bool,
+    /// Reports the bytes copied, and an entry for each action taken
+    pub progress: Option<ProgressReporter>,
+    /// Update files which are already in the destination with a [`delta`](super::delta) of this
+    /// block size, rather than copying them in full
+    pub delta_block_size: Option<usize>,
+    /// How the trees are compared, and which paths within them
+    pub diff: DiffOptions,
+}
+
+impl Default for MirrorOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            dry_run: false,
+            progress: None,
+            delta_block_size: None,
+            diff: DiffOptions::default(),
+        }
+    }
+}
+
+/// Makes `destination` match `source`, by walking both and applying the changes from
+/// [`diff`](super::diff())ing the destination (as the earlier tree) against the source, compared
+/// as [`MirrorOptions::diff`] says
+///
+/// Renames are applied first, then directories and symlinks are created, then files are copied up
+/// to [`MirrorOptions::concurrency`] at a time, and finally removed entries are removed.  Parent
+/// directories are created as they're needed, and entries in the destination are replaced when
+/// the source has a different type of entry at the same path.  The services are cloned for each
+/// file copied at once.  Files already in the destination can be updated with deltas instead, with
+/// [`MirrorOptions::delta_block_size`].
+///
+/// Returns the actions taken, or with [`MirrorOptions::dry_run`] the actions which would have
+/// been taken.
+///
+/// # Errors
+///
+/// If a request to either tree fails, which stops any further actions being taken.  An entry
+/// which was already removed isn't an error.  Symlinks can only be created on unix.
+pub async fn mirror<A, B>(
+    mut source: Tree<A>,
+    mut destination: Tree<B>,
+    options: MirrorOptions,
+) -> io::Result<Vec<Action>>
+where
+    A: Service<Request, Response = Response, Error = io::Error> + Clone,
+    B: Service<Request, Response = Response, Error = io::Error> + Clone,
+{
+    let changes = diff(destination.clone(), source.clone(), options.diff.clone()).await?;
+    let actions = plan(&mut source, &changes).await?;
+    if options.dry_run {
+        return Ok(actions);
+    }
+    let progress = options.progress.as_ref();
+    let delta_block_size = options.delta_block_size;
+    if let Some(progress) = progress {
+        for action in &actions {
+            if let Action::Copy { len, .. } = action {
+                progress.add_total_bytes(*len);
+            }
+        }
+    }
+    let mut copies = Vec::new();
+    for action in &actions {
+        match action {
+            Action::Rename { from, to } => {
+                create_parent(&mut destination, to).await?;
+                let req = Request::Rename {
+                    from: destination.resolve(from).into(),
+                    to: destination.resolve(to).into(),
+                };
+                ready_call(&mut destination.service, req)
+                    .await?
+                    .into_done()?;
+            }
+            Action::CreateDir(path) => {
+                clear(&mut destination, path, FileType::Dir).await?;
+                let req = Request::CreateDir {
+                    path: destination.resolve(path).into(),
+                    recursive: true,
+                };
+                ready_call(&mut destination.service, req)
+                    .await?
+                    .into_done()?;
+            }
+            Action::Symlink { path, target } => {
+                create_parent(&mut destination, path).await?;
+                clear(&mut destination, path, FileType::Symlink).await?;
+                symlink(&mut destination, path, target).await?;
+            }
+            Action::Copy { path, .. } => {
+                copies.push(path);
+                continue;
+            }
+            Action::Remove(_) => continue,
+        }
+        if let Some(progress) = progress {
+            progress.add_entry();
+        }
+    }
+    stream::iter(copies)
+        .map(|path| {
+            let (mut source, mut destination) = (source.clone(), destination.clone());
+            async move {
+                copy(
+                    &mut source,
+                    &mut destination,
+                    path,
+                    delta_block_size,
+                    progress,
+                )
+                .await
+            }
+        })
+        .buffer_unordered(options.concurrency.max(1))
+        .try_for_each(|()| ready(Ok(())))
+        .await?;
+    // In reverse, so files are removed before the directories they're in
+    for action in actions.iter().rev() {
+        if let Action::Remove(path) = action {
+            remove(&mut destination, path).await?;
+            if let Some(progress) = progress {
+                progress.add_entry();
+            }
+        }
+    }
+    Ok(actions)
+}
+
+/// The actions which apply `changes`, in the order they're taken
+async fn plan<A>(source: &mut Tree<A>, changes: &ChangeSet) -> io::Result<Vec<Action>>
+where
+    A: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut renames = Vec::new();
+    let mut creates = Vec::new();
+    let mut copies = Vec::new();
+    let mut removes = Vec::new();
+    for change in changes {
+        match change {
+            Change::Added(path) | Change::Modified(path) => {
+                let resolved: Arc<Path> = source.resolve(path).into();
+                let req = Request::GetMetadata {
+                    path: resolved.clone(),
+                    follow_symlinks: false,
+                };
+                let metadata = ready_call(&mut source.service, req)
+                    .await?
+                    .into_metadata()?;
+                let path = path.clone();
+                match metadata.file_type() {
+                    FileType::File => copies.push(Action::Copy {
+                        path,
+                        len: metadata.len(),
+                    }),
+                    FileType::Dir => creates.push(Action::CreateDir(path)),
+                    FileType::Symlink => {
+                        let req = Request::FollowLink(resolved);
+                        let target = ready_call(&mut source.service, req).await?;
+                        let target = target.into_points_to()?;
+                        creates.push(Action::Symlink { path, target });
+                    }
+                    FileType::Other => {
+                        return Err(io::Error::new(
+                            ErrorKind::Unsupported,
+                            format!("{} is not a file, directory or symlink", path.display()),
+                        ))
+                    }
+                }
+            }
+            Change::Removed(path) => removes.push(Action::Remove(path.clone())),
+            Change::Renamed { from, to } => renames.push(Action::Rename {
+                from: from.clone(),
+                to: to.clone(),
+            }),
+        }
+    }
+    Ok([renames, creates, copies, removes].concat())
+}
+
+async fn copy<A, B>(
+    source: &mut Tree<A>,
+    destination: &mut Tree<B>,
+    path: &Path,
+    delta_block_size: Option<usize>,
+    progress: Option<&ProgressReporter>,
+) -> io::Result<()>
+where
+    A: Service<Request, Response = Response, Error = io::Error>,
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    create_parent(destination, path).await?;
+    let existing = file_type(destination, path).await?;
+    if let (Some(block_size), Some(FileType::File)) = (delta_block_size, existing) {
+        let delta = transfer(source, destination, path, block_size).await?;
+        if let Some(progress) = progress {
+            progress.add_bytes(delta.copied_len() + delta.literal_len());
+            progress.add_entry();
+        }
+        return Ok(());
+    }
+    clear(destination, path, FileType::File).await?;
+    let from = source.resolve(path);
+    let to = destination.resolve(path);
+    let mut reader = source.service.open(from, Mode::Read).await?;
+    let mut writer = destination
+        .service
+        .open(to, Mode::CreateOrOverwrite)
+        .await?;
+    copy_vectored(&mut reader, &mut writer, |len| {
+        if let Some(progress) = progress {
+            progress.add_bytes(len);
+        }
+    })
+    .await?;
+    writer.flush().await?;
+    if let Some(progress) = progress {
+        progress.add_entry();
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn symlink<B>(destination: &mut Tree<B>, path: &Path, target: &Path) -> io::Result<()>
+where
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    let req = Request::Symlink {
+        src: target.into(),
+        dst: destination.resolve(path).into(),
+    };
+    Ok(ready_call(&mut destination.service, req)
+        .await?
+        .into_done()?)
+}
+
+#[cfg(not(unix))]
+async fn symlink<B>(_: &mut Tree<B>, path: &Path, _: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "can't tell whether {} links to a file or a directory",
+            path.display()
+        ),
+    ))
+}
+
+async fn create_parent<B>(destination: &mut Tree<B>, path: &Path) -> io::Result<()>
+where
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    let Some(parent) = destination.resolve(path).parent().map(Arc::from) else {
+        return Ok(());
+    };
+    let req = Request::CreateDir {
+        path: parent,
+        recursive: true,
+    };
+    Ok(ready_call(&mut destination.service, req)
+        .await?
+        .into_done()?)
+}
+
+/// Removes whatever is at `path` in the destination, unless it's a `keep`, which is never the case
+/// for symlinks as they can't be replaced in place
+async fn clear<B>(destination: &mut Tree<B>, path: &Path, keep: FileType) -> io::Result<()>
+where
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    match file_type(destination, path).await? {
+        Some(file_type) if file_type != keep || keep == FileType::Symlink => {
+            remove(destination, path).await
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn remove<B>(destination: &mut Tree<B>, path: &Path) -> io::Result<()>
+where
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    let resolved: Arc<Path> = destination.resolve(path).into();
+    let req = match file_type(destination, path).await? {
+        Some(FileType::Dir) => Request::RemoveDir {
+            path: resolved,
+            recursive: true,
+        },
+        Some(_) => Request::RemoveFile(resolved),
+        None => return Ok(()),
+    };
+    match ready_call(&mut destination.service, req).await {
+        Ok(response) => Ok(response.into_done()?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// The type of the entry at `path` in the destination, without following symlinks, if there is
+/// one
+async fn file_type<B>(destination: &mut Tree<B>, path: &Path) -> io::Result<Option<FileType>>
+where
+    B: Service<Request, Response = Response, Error = io::Error>,
+{
+    let req = Request::GetMetadata {
+        path: destination.resolve(path).into(),
+        follow_symlinks: false,
+    };
+    match ready_call(&mut destination.service, req).await {
+        Ok(response) => Ok(Some(response.into_metadata()?.file_type())),
+        Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{diff, DiffOptions},
+        test_kit::TestDir,
+        FileSystem,
+    };
+
+    #[tokio::test]
+    async fn test_mirror() -> io::Result<()> {
+        let dir = TestDir::new("mirror")?;
+        let (source, destination) = (dir.join("source"), dir.join("destination"));
+        std::fs::create_dir_all(source.join("sub/empty"))?;
+        std::fs::create_dir_all(destination.join("stale_dir/deeper"))?;
+        std::fs::write(source.join("a.txt"), "new contents")?;
+        std::fs::write(destination.join("a.txt"), "old")?;
+        std::fs::write(source.join("sub/b.txt"), "b")?;
+        std::fs::write(
+            destination.join("sub"),
+            "a file where the source has a directory",
+        )?;
+        std::fs::write(source.join("moved.txt"), "moved")?;
+        std::fs::write(destination.join("unmoved.txt"), "moved")?;
+        std::fs::write(destination.join("stale_dir/deeper/stale.txt"), "stale")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a.txt", source.join("link"))?;
+
+        let diff_options = DiffOptions {
+            compare_contents: true,
+            ..DiffOptions::default()
+        };
+        let options = MirrorOptions {
+            dry_run: true,
+            diff: diff_options.clone(),
+            ..MirrorOptions::default()
+        };
+        let planned = mirror(
+            Tree::new(FileSystem::new(), &source),
+            Tree::new(FileSystem::new(), &destination),
+            options,
+        )
+        .await?;
+        assert_eq!(std::fs::read(destination.join("a.txt"))?, b"old");
+        let mut expected = vec![
+            Action::Rename {
+                from: "unmoved.txt".into(),
+                to: "moved.txt".into(),
+            },
+            Action::CreateDir("sub".into()),
+            Action::CreateDir("sub/empty".into()),
+            Action::Copy {
+                path: "a.txt".into(),
+                len: 12,
+            },
+            Action::Copy {
+                path: "sub/b.txt".into(),
+                len: 1,
+            },
+            Action::Remove("stale_dir".into()),
+            Action::Remove("stale_dir/deeper".into()),
+            Action::Remove("stale_dir/deeper/stale.txt".into()),
+        ];
+        #[cfg(unix)]
+        expected.insert(
+            1,
+            Action::Symlink {
+                path: "link".into(),
+                target: "a.txt".into(),
+            },
+        );
+        assert_eq!(planned, expected);
+
+        let (reporter, progress) = ProgressReporter::new();
+        let options = MirrorOptions {
+            concurrency: 2,
+            progress: Some(reporter),
+            delta_block_size: Some(4),
+            diff: diff_options.clone(),
+            ..MirrorOptions::default()
+        };
+        let taken = mirror(
+            Tree::new(FileSystem::new(), &source),
+            Tree::new(FileSystem::new(), &destination),
+            options,
+        )
+        .await?;
+        assert_eq!(taken, planned);
+        let progress = *progress.borrow();
+        assert_eq!(progress.bytes, 13);
+        assert_eq!(progress.total_bytes, Some(13));
+        assert_eq!(progress.entries, planned.len() as u64);
+        let changes = diff(
+            Tree::new(FileSystem::new(), &destination),
+            Tree::new(FileSystem::new(), &source),
+            diff_options,
+        )
+        .await?;
+        assert!(changes.is_empty(), "{changes:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mirror_to_missing_destination() -> io::Result<()> {
+        let dir = TestDir::new("mirror_missing")?;
+        let (source, destination) = (dir.join("source"), dir.join("destination"));
+        std::fs::create_dir_all(source.join("sub"))?;
+        std::fs::write(source.join("sub/file.txt"), "contents")?;
+
+        mirror(
+            Tree::new(FileSystem::new(), &source),
+            Tree::new(FileSystem::new(), &destination),
+            MirrorOptions::default(),
+        )
+        .await?;
+        assert_eq!(
+            std::fs::read(destination.join("sub/file.txt"))?,
+            b"contents"
+        );
+        Ok(())
+    }
+}
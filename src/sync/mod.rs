@@ -0,0 +1,272 @@
+//! Comparing and mirroring trees of files held by any `Service<Request>`, as sync and deployment
+//! tools do
+//!
+//! Each side is a [`Tree`], a service and the directory within it the paths are relative to,
+//! which is walked with [`Request::ReadDir`], so trees can be compared whatever backend holds
+//! them.  The walk can be limited to the paths which matter with [`DiffOptions::paths`].
+//! [`diff()`] reports the [`ChangeSet`] between two trees, and [`mirror()`] applies it, to make
+//! one tree a copy of the other.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use tower_fs::{
+//!     sync::{diff, DiffOptions, Tree},
+//!     FileSystem,
+//! };
+//!
+//! let deployed = Tree::new(FileSystem::new(), "/srv/site");
+//! let built = Tree::new(FileSystem::new(), "build");
+//! let changes = diff(deployed, built, DiffOptions::default()).await?;
+//! for change in &changes {
+//!     println!("{change:?}");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use tower_service::Service;
+
+use crate::{ext::ready_call, Metadata, Request, Response};
+
+pub mod delta;
+pub mod diff;
+pub mod manifest;
+pub mod mirror;
+
+pub use diff::{diff, Change, ChangeSet, DiffOptions};
+pub use manifest::{Manifest, ManifestEntry, Verification};
+pub use mirror::{mirror, Action, MirrorOptions};
+
+/// A service, and the directory within it which paths are relative to
+#[derive(Debug, Clone)]
+pub struct Tree<S> {
+    service: S,
+    root: PathBuf,
+}
+
+impl<S> Tree<S> {
+    /// The tree at `root` in `service`
+    ///
+    /// `&mut S` is a service too, so a tree can borrow a service rather than own it.
+    pub fn new(service: S, root: impl Into<PathBuf>) -> Self {
+        Self {
+            service,
+            root: root.into(),
+        }
+    }
+
+    /// The directory the tree's paths are relative to
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path within the service of `path`, which may be given relative to the root or as an
+    /// absolute path below it
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+/// `paths` without duplicates, in order, and relative to the root if they were given as absolute
+/// paths below it
+fn relative(paths: impl IntoIterator<Item = impl AsRef<Path>>) -> BTreeSet<PathBuf> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let path = path.as_ref();
+            path.strip_prefix("/").unwrap_or(path).to_path_buf()
+        })
+        .collect()
+}
+
+/// The entries below `tree`'s root, or with `paths` only those paths and what's below them,
+/// keyed by their paths relative to the root
+///
+/// Directories are listed with [`Request::ReadDir`], so symlinks aren't followed.  Paths which
+/// don't exist, including the root, are left out.
+async fn entries<S>(
+    tree: &mut Tree<S>,
+    paths: Option<&BTreeSet<PathBuf>>,
+) -> io::Result<BTreeMap<PathBuf, Metadata>>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let mut entries = BTreeMap::new();
+    let mut dirs = Vec::new();
+    match paths {
+        None => dirs.push(PathBuf::new()),
+        Some(paths) => {
+            for path in paths {
+                let req = Request::GetMetadata {
+                    path: tree.resolve(path).into(),
+                    follow_symlinks: false,
+                };
+                let metadata = match ready_call(&mut tree.service, req).await {
+                    Ok(response) => response.into_metadata()?,
+                    Err(err) if missing(&err) => continue,
+                    Err(err) => return Err(err),
+                };
+                if metadata.is_dir() {
+                    dirs.push(path.clone());
+                }
+                entries.insert(path.clone(), metadata);
+            }
+        }
+    }
+    while let Some(dir) = dirs.pop() {
+        let req = Request::ReadDir(tree.resolve(&dir).into());
+        let listing = match ready_call(&mut tree.service, req).await {
+            Ok(response) => response.into_directory()?,
+            Err(err) if dir.as_os_str().is_empty() && missing(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        for (name, metadata) in listing {
+            let path = dir.join(name);
+            if metadata.is_dir() {
+                dirs.push(path.clone());
+            }
+            entries.insert(path, metadata);
+        }
+    }
+    Ok(entries)
+}
+
+/// Whether `err` is because a path doesn't exist, which a file where the other tree has a
+/// directory is, as there's nothing below it
+fn missing(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory)
+}
+
+/// The paths of everything below `tree`'s root, relative to it and in order, listed with
+/// [`Request::ReadDir`] so symlinks aren't followed
+///
+/// A root which doesn't exist has nothing below it.
+///
+/// # Errors
+///
+/// If a directory can't be listed
+pub async fn walk<S>(mut tree: Tree<S>) -> io::Result<Vec<PathBuf>>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    Ok(entries(&mut tree, None).await?.into_keys().collect())
+}
+
+/// The paths of everything below the local directory `root`, relative to it and in order,
+/// without following symlinks, reading up to `parallelism` directories at once
+///
+/// The directories are read with [`std::fs`] on threads of their own, which is quicker than a
+/// [`walk`] of a local tree.
+///
+/// # Errors
+///
+/// If a directory can't be read.  The rest of the tree is still read, and the first directory to
+/// fail (in sorted order) is reported, along with how many others failed
+pub fn walk_with(root: impl AsRef<Path>, parallelism: usize) -> io::Result<Vec<PathBuf>> {
+    let paths = Mutex::new(Vec::new());
+    crate::walk::walk(root.as_ref(), parallelism, |path, _, _| {
+        lock(&paths).push(path.to_path_buf());
+        Ok(())
+    })?;
+    let mut paths = paths.into_inner().unwrap_or_else(PoisonError::into_inner);
+    paths.sort();
+    Ok(paths)
+}
+
+/// How much is stored below a local directory, from [`disk_usage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct DiskUsage {
+    /// The files, symlinks and other entries which aren't directories
+    pub files: u64,
+    pub dirs: u64,
+    /// The lengths of the files and symlinks, rather than the blocks they take up.  Hard linked
+    /// files are counted once for each link
+    pub bytes: u64,
+}
+
+/// Adds up what's stored below the local directory `root`, without following symlinks, reading up
+/// to `parallelism` directories at once
+///
+/// # Errors
+///
+/// If a directory or the metadata of an entry can't be read, reported as [`walk_with`] does
+pub fn disk_usage(root: impl AsRef<Path>, parallelism: usize) -> io::Result<DiskUsage> {
+    let usage = Mutex::new(DiskUsage::default());
+    crate::walk::walk(root.as_ref(), parallelism, |_, entry, file_type| {
+        let len = if file_type.is_dir() {
+            0
+        } else {
+            entry.metadata()?.len()
+        };
+        let mut usage = lock(&usage);
+        if file_type.is_dir() {
+            usage.dirs += 1;
+        } else {
+            usage.files += 1;
+        }
+        usage.bytes += len;
+        Ok(())
+    })?;
+    Ok(usage.into_inner().unwrap_or_else(PoisonError::into_inner))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem};
+
+    #[tokio::test]
+    async fn test_walk() -> io::Result<()> {
+        let dir = TestDir::new("sync_walk")?;
+        std::fs::create_dir_all(dir.join("a/b"))?;
+        std::fs::write(dir.join("a/b/one"), "one")?;
+        std::fs::write(dir.join("two"), "two")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("a"), dir.join("link"))?;
+
+        let paths = walk(Tree::new(FileSystem::new(), &dir)).await?;
+        assert_eq!(paths, walk_with(&dir, 2)?);
+        assert!(paths.starts_with(&["a", "a/b", "a/b/one"].map(PathBuf::from)));
+        assert!(walk(Tree::new(FileSystem::new(), dir.join("missing")))
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_usage() -> io::Result<()> {
+        let dir = TestDir::new("disk_usage")?;
+        std::fs::create_dir_all(dir.join("a/b"))?;
+        std::fs::write(dir.join("a/one"), [0; 100])?;
+        std::fs::write(dir.join("a/b/two"), [0; 20])?;
+        for parallelism in [1, 3] {
+            assert_eq!(
+                walk_with(&dir, parallelism)?,
+                ["a", "a/b", "a/b/two", "a/one"].map(PathBuf::from)
+            );
+            assert_eq!(
+                disk_usage(&dir, parallelism)?,
+                DiskUsage {
+                    files: 2,
+                    dirs: 2,
+                    bytes: 120
+                }
+            );
+        }
+        assert!(disk_usage(dir.join("missing"), 2)
+            .is_err_and(|err| err.kind() == io::ErrorKind::NotFound));
+        Ok(())
+    }
+}
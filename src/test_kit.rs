@@ -0,0 +1,642 @@
+//! A conformance suite for custom backends and middleware, checking they answer requests as the
+//! local [`FileSystem`](crate::FileSystem) does
+//!
+//! Each check works in a directory of it's own under the given root, which must exist, and removes
+//! it once it passes.  Checks panic when a response differs from the local file system's, and
+//! return the error of any request which fails unexpectedly.  Requests for optional features
+//! (links, permissions, metadata, listing directories and opening files to write them) may fail
+//! with [`ErrorKind::Unsupported`] instead, which skips what depends on them.
+//!
+//! Tests which need a directory of their own on the local file system can take one from
+//! [`TestDir`], which removes it once the test is done with it.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! // In the tests of a backend, with `tower_fs = { features = ["test-kit"] }` as a dev-dependency
+//! let root = tower_fs::test_kit::TestDir::new("my_backend_conformance")?;
+//! tower_fs::test_kit::run(&mut tower_fs::FileSystem::new(), &root).await
+//! # }
+//! ```
+
+use std::{
+    future::poll_fn,
+    io::{self, ErrorKind, SeekFrom},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tower_service::Service;
+
+use crate::{CopyOptions, FileSystemExt, Metadata, Mode, Request, Response};
+
+/// Runs every check of the suite against `service`, under `root`
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn run<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    check_read_write(service, root).await?;
+    check_open(service, root).await?;
+    check_dirs(service, root).await?;
+    check_rename_copy(service, root).await?;
+    check_missing(service, root).await?;
+    check_symlink(service, root).await?;
+    check_permissions(service, root).await
+}
+
+/// Checks writing whole files, reading them back whole or in part, and their metadata
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_read_write<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "read_write").await?;
+    let path = dir.join("file.txt");
+
+    assert!(
+        !service.exists(&path).await?,
+        "files exist before being written"
+    );
+    service.write(&path, b"hello, world".to_vec()).await?;
+    assert!(service.exists(&path).await?, "written files exist");
+    assert_eq!(service.read(&path).await?, b"hello, world");
+    service.write(&path, b"bye".to_vec()).await?;
+    assert_eq!(
+        service.read(&path).await?,
+        b"bye",
+        "writes replace contents"
+    );
+
+    let range = |range| Request::ReadRange {
+        path: path.as_path().into(),
+        range,
+    };
+    let bytes = call(service, range(1..2)).await?.into_bytes()?;
+    assert_eq!(bytes, b"y", "ranges are read exactly");
+    let bytes = call(service, range(1..100)).await?.into_bytes()?;
+    assert_eq!(bytes, b"ye", "ranges stop at the end of the file");
+
+    if let Some(metadata) = metadata(service, &path, true).await? {
+        assert!(metadata.is_file(), "written files are files");
+        assert_eq!(metadata.len(), 3, "the length of a file is it's size");
+    }
+    remove_scratch(service, &dir).await
+}
+
+/// Checks opening files in each [`Mode`], and reading, writing and seeking them.  Backends which
+/// can only open files to read them may fail [`Mode::CreateNew`] with [`ErrorKind::Unsupported`],
+/// which skips the modes which write
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_open<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "open").await?;
+    let path = dir.join("file.txt");
+
+    let created = supported(service.open(&path, Mode::CreateNew).await)?;
+    let writable = created.is_some();
+    if let Some(mut file) = created {
+        file.write_all(b"one").await?;
+        file.shutdown().await?;
+        drop(file);
+        assert_eq!(
+            kind(service.open(&path, Mode::CreateNew).await),
+            Err(ErrorKind::AlreadyExists),
+            "creating a new file fails if it exists"
+        );
+        assert_eq!(
+            kind(
+                service
+                    .open(dir.join("missing.txt"), Mode::AppendExisting)
+                    .await
+            ),
+            Err(ErrorKind::NotFound),
+            "appending to a missing file fails"
+        );
+
+        let mut file = service.open(&path, Mode::CreateOrAppend).await?;
+        file.write_all(b", two").await?;
+        file.shutdown().await?;
+        drop(file);
+        assert_eq!(
+            service.read(&path).await?,
+            b"one, two",
+            "appends keep contents"
+        );
+    } else {
+        service.write(&path, b"one, two".to_vec()).await?;
+    }
+
+    let mut file = service.open(&path, Mode::Read).await?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).await?;
+    assert_eq!(contents, "one, two");
+    assert_eq!(file.seek(SeekFrom::Start(5)).await?, 5);
+    contents.clear();
+    file.read_to_string(&mut contents).await?;
+    assert_eq!(
+        contents, "two",
+        "reads continue from where files are seeked to"
+    );
+    let written = async {
+        file.write_all(b"three").await?;
+        file.flush().await
+    };
+    assert!(
+        written.await.is_err(),
+        "files opened to read can't be written"
+    );
+    drop(file);
+
+    if writable {
+        let mut file = service.open(&path, Mode::CreateOrOverwrite).await?;
+        file.write_all(b"three").await?;
+        file.shutdown().await?;
+        drop(file);
+        assert_eq!(
+            service.read(&path).await?,
+            b"three",
+            "overwrites replace contents"
+        );
+    }
+    remove_scratch(service, &dir).await
+}
+
+/// Checks creating, listing and removing directories, with and without their parents and
+/// contents
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_dirs<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "dirs").await?;
+    let nested = dir.join("a/b");
+    let create_dir = |path: &Path, recursive| Request::CreateDir {
+        path: path.into(),
+        recursive,
+    };
+    let remove_dir = |path: &Path, recursive| Request::RemoveDir {
+        path: path.into(),
+        recursive,
+    };
+
+    assert_eq!(
+        kind(call(service, create_dir(&nested, false)).await),
+        Err(ErrorKind::NotFound),
+        "directories aren't created without their parents"
+    );
+    call(service, create_dir(&nested, true)).await?;
+    assert!(service.exists(&nested).await?, "created directories exist");
+    if let Some(metadata) = metadata(service, &nested, true).await? {
+        assert!(metadata.is_dir(), "created directories are directories");
+    }
+    assert_eq!(
+        kind(call(service, create_dir(&nested, false)).await),
+        Err(ErrorKind::AlreadyExists),
+        "directories can't be created twice"
+    );
+    call(service, create_dir(&nested, true)).await?;
+
+    service
+        .write(nested.join("file.txt"), b"nested".to_vec())
+        .await?;
+    if let Some(entries) = supported(service.read_dir(&nested).await)? {
+        let names = entries.iter().map(|(name, _)| name.as_path());
+        assert!(
+            names.eq([Path::new("file.txt")]),
+            "directories list their entries by name"
+        );
+        assert!(
+            entries.iter().all(|(_, metadata)| metadata.is_file()),
+            "listed entries have their own metadata"
+        );
+    }
+    assert!(
+        call(service, remove_dir(&nested, false)).await.is_err(),
+        "directories with contents aren't removed without them"
+    );
+    call(service, remove_dir(&dir.join("a"), true)).await?;
+    assert!(
+        !service.exists(&nested).await?,
+        "removed directories don't exist"
+    );
+    remove_scratch(service, &dir).await
+}
+
+/// Checks moving and copying files, including over existing files
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_rename_copy<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "rename_copy").await?;
+    let (a, b, c) = (dir.join("a.txt"), dir.join("b.txt"), dir.join("c.txt"));
+
+    service.write(&a, b"first".to_vec()).await?;
+    service.rename(&a, &b).await?;
+    assert!(
+        !service.exists(&a).await?,
+        "renamed files leave their old path"
+    );
+    assert_eq!(
+        service.read(&b).await?,
+        b"first",
+        "renamed files keep contents"
+    );
+
+    service.write(&a, b"second".to_vec()).await?;
+    service.rename(&a, &b).await?;
+    assert_eq!(service.read(&b).await?, b"second", "renames replace files");
+
+    assert_eq!(
+        service.copy(&b, &c).await?,
+        6,
+        "copies count the bytes copied"
+    );
+    assert_eq!(
+        service.read(&b).await?,
+        b"second",
+        "copies keep their source"
+    );
+    assert_eq!(service.read(&c).await?, b"second");
+    service.write(&b, b"third".to_vec()).await?;
+    assert_eq!(service.read(&c).await?, b"second", "copies are independent");
+    remove_scratch(service, &dir).await
+}
+
+/// Checks requests for missing paths fail with [`ErrorKind::NotFound`]
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_missing<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "missing").await?;
+    let path = dir.join("missing.txt");
+
+    let path_ref = || Arc::from(path.as_path());
+    for req in [
+        Request::ReadBytes(path_ref()),
+        Request::RemoveFile(path_ref()),
+        Request::Open {
+            mode: Mode::Read,
+            path: path_ref(),
+        },
+        Request::Rename {
+            from: path_ref(),
+            to: dir.join("to.txt").into(),
+        },
+        Request::Copy {
+            from: path_ref(),
+            to: dir.join("to.txt").into(),
+            options: CopyOptions::default(),
+        },
+    ] {
+        let name = format!("{req:?}");
+        assert_eq!(
+            kind(call(service, req).await),
+            Err(ErrorKind::NotFound),
+            "{name} fails for a missing path"
+        );
+    }
+    assert_eq!(
+        kind(metadata(service, &path, true).await),
+        Err(ErrorKind::NotFound),
+        "missing paths have no metadata"
+    );
+    remove_scratch(service, &dir).await
+}
+
+/// Checks creating and following symbolic links, which backends may not support
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_symlink<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "symlink").await?;
+    let (target, link) = (dir.join("target.txt"), dir.join("link.txt"));
+    service.write(&target, b"linked".to_vec()).await?;
+
+    // Relative to the link, so the target is the same inside a backend rooted anywhere
+    let src = Path::new("target.txt");
+    #[cfg(unix)]
+    let req = Request::Symlink {
+        src: src.into(),
+        dst: link.as_path().into(),
+    };
+    #[cfg(windows)]
+    let req = Request::SymlinkFile {
+        src: src.into(),
+        dst: link.as_path().into(),
+    };
+    #[cfg(any(unix, windows))]
+    if supported(call(service, req).await)?.is_some() {
+        let points_to = call(service, Request::FollowLink(link.as_path().into()))
+            .await?
+            .into_points_to()?;
+        assert_eq!(points_to, src, "links point to their target");
+        assert_eq!(service.read(&link).await?, b"linked", "reads follow links");
+        if let Some(metadata) = metadata(service, &link, false).await? {
+            assert!(
+                metadata.is_symlink(),
+                "links aren't followed without asking"
+            );
+        }
+        if let Some(metadata) = metadata(service, &link, true).await? {
+            assert!(metadata.is_file(), "links are followed when asked");
+        }
+        service.remove_file(&link).await?;
+        assert!(
+            service.exists(&target).await?,
+            "removing links keeps targets"
+        );
+    }
+    #[cfg(not(any(unix, windows)))]
+    let _ = (src, link);
+    remove_scratch(service, &dir).await
+}
+
+/// Checks setting permissions, which backends may not support
+///
+/// Permissions can only be built for a request on unix, so this passes without checking anything
+/// elsewhere.
+///
+/// # Errors
+///
+/// If a request fails unexpectedly
+///
+/// # Panics
+///
+/// If a response differs from the local file system's
+pub async fn check_permissions<S>(service: &mut S, root: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = scratch(service, root, "permissions").await?;
+    let path = dir.join("file.txt");
+    service.write(&path, b"locked".to_vec()).await?;
+
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        let set_permissions = |mode| Request::SetPermissions {
+            path: path.as_path().into(),
+            perm: Permissions::from_mode(mode),
+        };
+        if supported(call(service, set_permissions(0o444)).await)?.is_some() {
+            if let Some(metadata) = metadata(service, &path, true).await? {
+                assert!(
+                    metadata.readonly(),
+                    "files without write bits are read only"
+                );
+            }
+            call(service, set_permissions(0o644)).await?;
+            if let Some(metadata) = metadata(service, &path, true).await? {
+                assert!(!metadata.readonly(), "files with write bits are writable");
+            }
+        }
+    }
+    remove_scratch(service, &dir).await
+}
+
+/// An empty directory under [`std::env::temp_dir`] for a test, removed along with it's contents
+/// once dropped
+///
+/// The directory is named `tower_fs_<name>_<process id>`, so tests running at the same time, in
+/// this process or another, need names of their own.  Anything a killed run left at the path is
+/// removed when the directory is created.
+#[derive(Debug)]
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// Creates the directory for the test called `name`
+    ///
+    /// # Errors
+    ///
+    /// If the directory, or what was left at it's path, can't be removed or created
+    pub fn new(name: &str) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("tower_fs_{name}_{}", std::process::id()));
+        match std::fs::remove_dir_all(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => std::fs::create_dir(&path)?,
+        }
+        Ok(Self(path))
+    }
+
+    /// The path of the directory
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<&TestDir> for PathBuf {
+    fn from(dir: &TestDir) -> Self {
+        dir.0.clone()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Creates an empty directory for a check, removing anything a failed run left behind
+async fn scratch<S>(service: &mut S, root: &Path, name: &str) -> io::Result<PathBuf>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let dir = root.join(name);
+    if service.exists(&dir).await? {
+        remove_scratch(service, &dir).await?;
+    }
+    service.create_dir_all(&dir).await?;
+    Ok(dir)
+}
+
+async fn remove_scratch<S>(service: &mut S, dir: &Path) -> io::Result<()>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    call(
+        service,
+        Request::RemoveDir {
+            path: dir.into(),
+            recursive: true,
+        },
+    )
+    .await
+    .map(drop)
+}
+
+async fn call<S>(service: &mut S, req: Request) -> io::Result<Response>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    service.call(req).await
+}
+
+/// The metadata of `path`, or `None` if the service doesn't support it
+async fn metadata<S>(
+    service: &mut S,
+    path: &Path,
+    follow_symlinks: bool,
+) -> io::Result<Option<Metadata>>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let req = Request::GetMetadata {
+        path: path.into(),
+        follow_symlinks,
+    };
+    match supported(call(service, req).await)? {
+        Some(response) => Ok(Some(response.into_metadata()?)),
+        None => Ok(None),
+    }
+}
+
+fn supported<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == ErrorKind::Unsupported => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn kind<T>(result: io::Result<T>) -> Result<(), ErrorKind> {
+    result.map(drop).map_err(|err| err.kind())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileSystem;
+
+    #[tokio::test]
+    async fn test_file_system_conforms() -> io::Result<()> {
+        let root = TestDir::new("test_kit")?;
+        run(&mut FileSystem::new(), &root).await?;
+        run(&mut FileSystem::builder().use_tokio_fs(true).build(), &root).await?;
+        // Every check removes it's directory once it passes
+        assert!(root.read_dir()?.next().is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "middleware")]
+    #[tokio::test]
+    async fn test_root_conforms() -> io::Result<()> {
+        use tower_layer::Layer;
+
+        let root = TestDir::new("test_kit_root")?;
+        let mut service = crate::middleware::root::RootLayer::new(&root)?.layer(FileSystem::new());
+        run(&mut service, Path::new("/")).await
+    }
+
+    #[cfg(feature = "tempdir")]
+    #[tokio::test]
+    async fn test_temp_dir_conforms() -> io::Result<()> {
+        use crate::backend::tempdir::{Config, TempDir};
+
+        let mut service = TempDir::new(Config::default()).await?;
+        run(&mut service, Path::new("")).await
+    }
+
+    #[cfg(feature = "wasi")]
+    #[tokio::test]
+    async fn test_preopened_conforms() -> io::Result<()> {
+        let root = TestDir::new("test_kit_wasi")?;
+        run(
+            &mut crate::backend::wasi::Preopened::new(&root),
+            Path::new(""),
+        )
+        .await
+    }
+
+    // Embedded files can't be written, which every check needs to set up, so it has no run here
+    #[cfg(feature = "tar")]
+    #[tokio::test]
+    async fn test_tar_conforms() -> io::Result<()> {
+        let dir = TestDir::new("test_kit_tar")?;
+        let mut tar = crate::backend::tar::Tar::open(dir.join("conformance.tar")).await?;
+        run(&mut tar, Path::new("")).await
+    }
+
+    #[cfg(feature = "cas")]
+    #[tokio::test]
+    async fn test_cas_conforms() -> io::Result<()> {
+        let root = TestDir::new("test_kit_cas")?;
+        run(
+            &mut crate::backend::cas::Cas::open(&root).await?,
+            Path::new(""),
+        )
+        .await
+    }
+}
@@ -0,0 +1,222 @@
+//! Services for single operations, which answer with the exact type of their response
+//!
+//! Each wraps any `Service<Request>`, such as a stack of middleware over a backend, and converts
+//! it's [`Response`] with the matching `into_*` method, so callers needn't match on the enum.
+//! A response of the wrong variant becomes the service's error, through it's
+//! `From<`[`WrongVariant`]`>` implementation.
+
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use pin_project_lite::pin_project;
+use tower_service::Service;
+
+use crate::{FileHandle, Metadata, Mode, Request, Response, WrongVariant};
+
+pin_project! {
+    /// The future returned by the services of this module, converting the inner service's
+    /// [`Response`] into `T`
+    #[derive(Debug)]
+    pub struct ResponseFuture<F, T> {
+        #[pin]
+        inner: F,
+        extract: fn(Response) -> Result<T, WrongVariant>,
+    }
+}
+
+impl<F, T, E> Future for ResponseFuture<F, T>
+where
+    F: Future<Output = Result<Response, E>>,
+    E: From<WrongVariant>,
+{
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        this.inner
+            .poll(cx)
+            .map(|response| Ok((this.extract)(response?)?))
+    }
+}
+
+/// Opens a file with a [`Request::Open`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OpenRequest {
+    pub mode: Mode,
+    pub path: Arc<Path>,
+}
+
+/// Gets the metadata of a path with a [`Request::GetMetadata`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StatRequest {
+    pub path: Arc<Path>,
+    pub follow_symlinks: bool,
+}
+
+/// Replaces the contents of a file with a [`Request::WriteBytes`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WriteRequest {
+    pub path: Arc<Path>,
+    pub bytes: Vec<u8>,
+}
+
+macro_rules! typed_service {
+    (
+        $(#[$meta:meta])*
+        $name:ident($request:ty) -> $response:ty,
+        |$req:ident| $into_request:expr,
+        $extract:path
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+        pub struct $name<S>(pub S);
+
+        impl<S> $name<S> {
+            pub fn new(inner: S) -> Self {
+                Self(inner)
+            }
+
+            pub fn into_inner(self) -> S {
+                self.0
+            }
+        }
+
+        impl<S> Service<$request> for $name<S>
+        where
+            S: Service<Request, Response = Response>,
+            S::Error: From<WrongVariant>,
+        {
+            type Response = $response;
+            type Error = S::Error;
+            type Future = ResponseFuture<S::Future, $response>;
+
+            fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                self.0.poll_ready(cx)
+            }
+
+            fn call(&mut self, $req: $request) -> Self::Future {
+                ResponseFuture {
+                    inner: self.0.call($into_request),
+                    extract: $extract,
+                }
+            }
+        }
+    };
+}
+
+typed_service! {
+    /// Opens files, answering with the [`FileHandle`]
+    Open(OpenRequest) -> FileHandle,
+    |req| Request::Open { mode: req.mode, path: req.path },
+    Response::into_file
+}
+
+typed_service! {
+    /// Gets the [`Metadata`] of paths
+    Stat(StatRequest) -> Metadata,
+    |req| Request::GetMetadata { path: req.path, follow_symlinks: req.follow_symlinks },
+    Response::into_metadata
+}
+
+typed_service! {
+    /// Reads the entire contents of files
+    Read(Arc<Path>) -> Vec<u8>,
+    |path| Request::ReadBytes(path),
+    Response::into_bytes
+}
+
+typed_service! {
+    /// Replaces the entire contents of files
+    Write(WriteRequest) -> (),
+    |req| Request::WriteBytes { path: req.path, bytes: req.bytes },
+    Response::into_done
+}
+
+typed_service! {
+    /// Removes files, but not directories
+    Remove(Arc<Path>) -> (),
+    |path| Request::RemoveFile(path),
+    Response::into_done
+}
+
+typed_service! {
+    /// Checks whether paths exist
+    Exists(Arc<Path>) -> bool,
+    |path| Request::Exists(path),
+    Response::into_exists
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind};
+
+    use futures::future::{ready, Ready};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem};
+
+    #[tokio::test]
+    async fn test_typed_services() -> io::Result<()> {
+        let dir = TestDir::new("typed")?;
+        let path = Arc::<Path>::from(dir.join("hello.txt"));
+        let bytes = b"hello".to_vec();
+        let write = WriteRequest {
+            path: path.clone(),
+            bytes,
+        };
+        Write(FileSystem::new()).call(write).await?;
+        assert!(Exists(FileSystem::new()).call(path.clone()).await?);
+        let stat = StatRequest {
+            path: path.clone(),
+            follow_symlinks: true,
+        };
+        assert_eq!(Stat(FileSystem::new()).call(stat).await?.len(), 5);
+        assert_eq!(Read(FileSystem::new()).call(path.clone()).await?, b"hello");
+
+        let open = OpenRequest {
+            mode: Mode::Read,
+            path: path.clone(),
+        };
+        let mut contents = String::new();
+        Open(FileSystem::new())
+            .call(open)
+            .await?
+            .read_to_string(&mut contents)
+            .await?;
+        assert_eq!(contents, "hello");
+
+        Remove(FileSystem::new()).call(path.clone()).await?;
+        assert!(!Exists(FileSystem::new()).call(path).await?);
+        Ok(())
+    }
+
+    /// Answers every request with [`Response::Done`]
+    #[derive(Debug, Clone, Copy)]
+    struct AlwaysDone;
+
+    impl Service<Request> for AlwaysDone {
+        type Response = Response;
+        type Error = io::Error;
+        type Future = Ready<io::Result<Response>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request) -> Self::Future {
+            ready(Ok(Response::Done))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_variant() {
+        let exists = Exists(AlwaysDone).call(Arc::from(Path::new("a"))).await;
+        assert!(exists.is_err_and(|err| err.kind() == ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,355 @@
+//! Walking local trees with a bounded number of threads, for the [`Request::CopyDir`](crate::Request::CopyDir)s of
+//! [`FileSystem`](crate::FileSystem) and the walks of the `sync` module
+//!
+//! Directories are queued as they're found, and each thread reads whichever is next, so wide trees
+//! on fast disks aren't held up by reading one directory at a time.  Entries are visited in no
+//! particular order, but a directory is always visited before what's in it.
+
+use std::{
+    fs::{DirEntry, FileType},
+    io,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+};
+
+use crate::copy::{self, CopyOptions};
+
+/// Visits everything below the directory `root`, without following symlinks, with up to
+/// `parallelism` threads, passing `visit` each entry's path relative to `root`
+///
+/// Directories are only walked into once they're visited successfully.  The walk carries on past
+/// failures, so the same entries fail whatever order they're visited in, and the failure of the
+/// first path (in sorted order) is returned, noting how many others failed.
+pub(crate) fn walk<F>(root: &Path, parallelism: usize, visit: F) -> io::Result<()>
+where
+    F: Fn(&Path, &DirEntry, FileType) -> io::Result<()> + Sync,
+{
+    let queue = Queue {
+        state: Mutex::new(QueueState {
+            dirs: vec![PathBuf::new()],
+            busy: 0,
+        }),
+        changed: Condvar::new(),
+    };
+    let failures = Mutex::new(Vec::new());
+    let worker = || {
+        while let Some(dir) = queue.next() {
+            read_dir(root, &dir, &queue, &failures, &visit);
+            queue.done();
+        }
+    };
+    if parallelism > 1 {
+        thread::scope(|scope| {
+            for _ in 1..parallelism {
+                // Threads which can't be started leave the rest of the walk to those which were
+                if thread::Builder::new().spawn_scoped(scope, worker).is_err() {
+                    break;
+                }
+            }
+            worker();
+        });
+    } else {
+        worker();
+    }
+    let mut failures = failures
+        .into_inner()
+        .unwrap_or_else(PoisonError::into_inner);
+    failures.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let others = failures.len().saturating_sub(1);
+    match failures.into_iter().next() {
+        None => Ok(()),
+        Some((path, err)) if path.as_os_str().is_empty() && others == 0 => Err(err),
+        Some((path, err)) => {
+            let more = match others {
+                0 => String::new(),
+                others => format!(" (and {others} more failures)"),
+            };
+            let message = format!("{}: {err}{more}", root.join(path).display());
+            Err(io::Error::new(err.kind(), message))
+        }
+    }
+}
+
+/// Visits the entries of `dir`, queueing the directories among them
+fn read_dir<F>(
+    root: &Path,
+    dir: &Path,
+    queue: &Queue,
+    failures: &Mutex<Vec<(PathBuf, io::Error)>>,
+    visit: &F,
+) where
+    F: Fn(&Path, &DirEntry, FileType) -> io::Result<()>,
+{
+    let fail = |path: PathBuf, err| {
+        failures
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((path, err));
+    };
+    let entries = match std::fs::read_dir(root.join(dir)) {
+        Ok(entries) => entries,
+        Err(err) => return fail(dir.to_path_buf(), err),
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                fail(dir.to_path_buf(), err);
+                continue;
+            }
+        };
+        let path = dir.join(entry.file_name());
+        match entry
+            .file_type()
+            .and_then(|file_type| visit(&path, &entry, file_type).map(|()| file_type))
+        {
+            Ok(file_type) if file_type.is_dir() => queue.push(path),
+            Ok(_) => {}
+            Err(err) => fail(path, err),
+        }
+    }
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+struct QueueState {
+    dirs: Vec<PathBuf>,
+    /// The threads reading a directory, which may queue more
+    busy: usize,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The next directory to read, waiting while other threads may still find more, or `None`
+    /// once the walk is finished
+    fn next(&self) -> Option<PathBuf> {
+        let mut state = self.lock();
+        loop {
+            if let Some(dir) = state.dirs.pop() {
+                state.busy += 1;
+                return Some(dir);
+            }
+            if state.busy == 0 {
+                return None;
+            }
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn push(&self, dir: PathBuf) {
+        self.lock().dirs.push(dir);
+        self.changed.notify_one();
+    }
+
+    /// Marks a directory as read, waking the other threads if that finished the walk
+    fn done(&self) {
+        let mut state = self.lock();
+        state.busy -= 1;
+        if state.busy == 0 && state.dirs.is_empty() {
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Copies the directory `from` and everything in it to `to` with `options`, creating `to` and its
+/// parents if they don't exist, and returning the number of bytes copied
+///
+/// Files are copied with [`std::fs::copy`], replacing those already at `to`, and symlinks are
+/// recreated on unix, while anything else fails with [`io::ErrorKind::Unsupported`].  What
+/// `options` keep of directories is given to their copies once the walk is finished, as copying
+/// their entries would change their modification times, and their permissions may not allow it.
+pub(crate) fn copy_dir(
+    from: &Path,
+    to: &Path,
+    options: CopyOptions,
+    parallelism: usize,
+) -> io::Result<u64> {
+    let metadata = std::fs::metadata(from)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", from.display()),
+        ));
+    }
+    if inside(to, from)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't copy a directory into itself",
+        ));
+    }
+    std::fs::create_dir_all(to)?;
+    let copied = std::sync::atomic::AtomicU64::new(0);
+    let dirs = Mutex::new(Vec::new());
+    walk(from, parallelism, |path, entry, file_type| {
+        let dst = to.join(path);
+        if file_type.is_dir() {
+            match std::fs::create_dir(&dst) {
+                Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+                _ => {}
+            }
+            let metadata = entry.metadata()?;
+            dirs.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push((path.to_path_buf(), metadata));
+            Ok(())
+        } else if file_type.is_file() {
+            let len = copy::copy_file(&entry.path(), &dst, options)?;
+            copied.fetch_add(len, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        } else {
+            #[cfg(unix)]
+            if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                if options.skip_identical
+                    && std::fs::read_link(&dst).is_ok_and(|existing| existing == target)
+                {
+                    return Ok(());
+                }
+                std::os::unix::fs::symlink(target, &dst)?;
+                return copy::preserve(&entry.path(), &entry.metadata()?, &dst, options);
+            }
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a file, directory or symlink",
+            ))
+        }
+    })?;
+    for (path, metadata) in dirs.into_inner().unwrap_or_else(PoisonError::into_inner) {
+        copy::preserve(&from.join(&path), &metadata, &to.join(path), options)?;
+    }
+    copy::preserve(from, &metadata, to, options)?;
+    Ok(copied.into_inner())
+}
+
+/// Whether `path` is `dir` or below it, once both are resolved
+fn inside(path: &Path, dir: &Path) -> io::Result<bool> {
+    let dir = std::fs::canonicalize(dir)?;
+    // The nearest ancestor which exists is resolved, as `path` may not exist yet
+    let mut missing = Vec::new();
+    let mut existing = path;
+    let resolved = loop {
+        match std::fs::canonicalize(if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        }) {
+            Ok(resolved) => break resolved,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                    return Err(err);
+                };
+                missing.push(name);
+                existing = parent;
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    Ok(missing
+        .into_iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name))
+        .starts_with(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_kit::TestDir;
+
+    #[test]
+    fn test_walk() -> io::Result<()> {
+        let dir = TestDir::new("walk")?;
+        for i in 0..20 {
+            std::fs::create_dir_all(dir.join(format!("src/{i}/nested")))?;
+            std::fs::write(dir.join(format!("src/{i}/nested/file")), [0; 10])?;
+            std::fs::write(dir.join(format!("src/{i}/file")), [0; 5])?;
+        }
+        for parallelism in [1, 4] {
+            let paths = Mutex::new(Vec::new());
+            walk(&dir.join("src"), parallelism, |path, _, _| {
+                paths
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(path.to_path_buf());
+                Ok(())
+            })?;
+            assert_eq!(
+                paths
+                    .into_inner()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .len(),
+                80
+            );
+
+            let copy = dir.join(format!("copy_{parallelism}/nested"));
+            assert_eq!(
+                copy_dir(&dir.join("src"), &copy, CopyOptions::default(), parallelism)?,
+                300
+            );
+            assert_eq!(std::fs::read(copy.join("19/nested/file"))?, [0; 10]);
+            std::fs::remove_dir_all(&copy)?;
+            assert!(!copy.exists());
+        }
+        assert!(copy_dir(
+            &dir.join("src"),
+            &dir.join("src/3/inside"),
+            CopyOptions::default(),
+            4
+        )
+        .is_err_and(|err| err.kind() == io::ErrorKind::InvalidInput));
+        assert!(!dir.join("src/3/inside").exists());
+
+        // Directories keep their times once their entries are copied, and copies which are
+        // already identical are skipped
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        let times = std::fs::FileTimes::new().set_modified(modified);
+        std::fs::File::open(dir.join("src/7/nested/file"))?.set_times(times)?;
+        std::fs::File::open(dir.join("src/7"))?.set_times(times)?;
+        let options = CopyOptions {
+            timestamps: true,
+            permissions: true,
+            skip_identical: true,
+            ..CopyOptions::default()
+        };
+        let copy = dir.join("preserved");
+        assert_eq!(copy_dir(&dir.join("src"), &copy, options, 4)?, 300);
+        assert_eq!(std::fs::metadata(copy.join("7"))?.modified()?, modified);
+        assert_eq!(
+            std::fs::metadata(copy.join("7/nested/file"))?.modified()?,
+            modified
+        );
+        assert_eq!(copy_dir(&dir.join("src"), &copy, options, 4)?, 0);
+
+        // Failures are reported for the first path, however many threads find them
+        let failing = |parallelism| {
+            walk(&dir.join("src"), parallelism, |_, _, file_type| {
+                if file_type.is_file() {
+                    Err(io::Error::other("failed"))
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|err| err.to_string())
+        };
+        let expected = format!(
+            "{}: failed (and 39 more failures)",
+            dir.join("src/0/file").display()
+        );
+        for parallelism in [1, 4] {
+            assert_eq!(failing(parallelism), Err(expected.clone()));
+        }
+        assert!(walk(&dir.join("missing"), 4, |_, _, _| Ok(()))
+            .is_err_and(|err| err.kind() == io::ErrorKind::NotFound));
+        Ok(())
+    }
+}
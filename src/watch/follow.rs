@@ -0,0 +1,290 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io::{self, ErrorKind},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use tokio::time::{interval, Interval, MissedTickBehavior};
+use tower_service::Service;
+
+use crate::{Metadata, Request, Response};
+
+/// What happened to a followed file
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FollowEvent {
+    /// Bytes appended to the file
+    Appended(Vec<u8>),
+    /// The file became shorter than what had been read, and is followed from it's start again
+    Truncated,
+    /// The file was replaced, such as by log rotation, and the new file is followed from it's
+    /// start
+    Rotated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowConfig {
+    /// How long to wait before checking the file again, once everything appended has been read
+    pub poll_interval: Duration,
+    /// Stream the file's current contents first, rather than only what's appended later
+    pub from_start: bool,
+}
+
+impl Default for FollowConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(250),
+            from_start: false,
+        }
+    }
+}
+
+/// Follows the file at `path` through `service`, streaming what's appended to it as it grows
+///
+/// The file is checked with [`Request::GetMetadata`] and read with [`Request::ReadRange`], so the
+/// backend must support both.  Rotation is recognised by the file's inode number changing, where
+/// the backend reports one, or by the file going missing and reappearing.  The stream yields an
+/// error, and carries on, when a request fails for any reason but the file being missing.
+///
+/// # Errors
+///
+/// If the file's metadata can't be read
+pub async fn follow<S>(
+    mut service: S,
+    path: impl AsRef<Path>,
+    config: FollowConfig,
+) -> io::Result<Follow>
+where
+    S: Service<Request, Response = Response, Error = io::Error> + Send + 'static,
+    S::Future: Send,
+{
+    let path: Arc<Path> = path.as_ref().into();
+    let metadata = metadata(&mut service, &path).await?;
+    let mut interval = interval(config.poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let state = State {
+        service,
+        path,
+        position: if config.from_start { 0 } else { metadata.len() },
+        ino: metadata.ino(),
+        missing: false,
+        interval,
+        events: VecDeque::new(),
+    };
+    let events = stream::unfold(state, |mut state| async move {
+        let event = state.next().await;
+        Some((event, state))
+    });
+    Ok(Follow(events.boxed()))
+}
+
+/// The stream of changes to a followed file, from [`follow`]
+pub struct Follow(BoxStream<'static, io::Result<FollowEvent>>);
+
+impl Follow {
+    /// The lines appended to the file, without their line endings, decoded as UTF-8 with invalid
+    /// sequences replaced
+    ///
+    /// A line is only yielded once it's ending has been appended.  A partial line is dropped if
+    /// the file is truncated or rotated before it's finished.
+    #[must_use]
+    pub fn lines(self) -> Lines {
+        let lines = stream::unfold(
+            (self, Vec::new(), VecDeque::new()),
+            |(mut follow, mut partial, mut lines)| async move {
+                loop {
+                    if let Some(line) = lines.pop_front() {
+                        return Some((Ok(line), (follow, partial, lines)));
+                    }
+                    match follow.next().await? {
+                        Ok(FollowEvent::Appended(bytes)) => {
+                            partial.extend(bytes);
+                            while let Some(end) = partial.iter().position(|&byte| byte == b'\n') {
+                                let mut line: Vec<_> = partial.drain(..=end).collect();
+                                line.pop();
+                                if line.last() == Some(&b'\r') {
+                                    line.pop();
+                                }
+                                lines.push_back(String::from_utf8_lossy(&line).into_owned());
+                            }
+                        }
+                        Ok(FollowEvent::Truncated | FollowEvent::Rotated) => partial.clear(),
+                        Err(err) => return Some((Err(err), (follow, partial, lines))),
+                    }
+                }
+            },
+        );
+        Lines(lines.boxed())
+    }
+}
+
+impl Stream for Follow {
+    type Item = io::Result<FollowEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.as_mut().poll_next(cx)
+    }
+}
+
+impl std::fmt::Debug for Follow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Follow").finish_non_exhaustive()
+    }
+}
+
+/// The lines appended to a followed file, from [`Follow::lines`]
+pub struct Lines(BoxStream<'static, io::Result<String>>);
+
+impl Stream for Lines {
+    type Item = io::Result<String>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.as_mut().poll_next(cx)
+    }
+}
+
+impl std::fmt::Debug for Lines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Lines").finish_non_exhaustive()
+    }
+}
+
+struct State<S> {
+    service: S,
+    path: Arc<Path>,
+    /// How much of the file has been read
+    position: u64,
+    ino: Option<u64>,
+    /// Whether the file was missing when last checked
+    missing: bool,
+    interval: Interval,
+    events: VecDeque<FollowEvent>,
+}
+
+impl<S> State<S>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    async fn next(&mut self) -> io::Result<FollowEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            match metadata(&mut self.service, &self.path).await {
+                Ok(metadata) => self.check(&metadata).await?,
+                Err(err) if err.kind() == ErrorKind::NotFound => self.missing = true,
+                Err(err) => return Err(err),
+            }
+            if self.events.is_empty() {
+                self.interval.tick().await;
+            }
+        }
+    }
+
+    /// Queues the events which bring the followed state up to date with `metadata`
+    async fn check(&mut self, metadata: &Metadata) -> io::Result<()> {
+        let replaced = matches!((self.ino, metadata.ino()), (Some(old), Some(new)) if old != new);
+        if replaced || self.missing {
+            self.events.push_back(FollowEvent::Rotated);
+            (self.position, self.ino, self.missing) = (0, metadata.ino(), false);
+        } else if metadata.len() < self.position {
+            self.events.push_back(FollowEvent::Truncated);
+            self.position = 0;
+        }
+        if metadata.len() > self.position {
+            let req = Request::ReadRange {
+                path: self.path.clone(),
+                range: self.position..metadata.len(),
+            };
+            let bytes = call(&mut self.service, req).await?.into_bytes()?;
+            if !bytes.is_empty() {
+                self.position += bytes.len() as u64;
+                self.events.push_back(FollowEvent::Appended(bytes));
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn metadata<S>(service: &mut S, path: &Arc<Path>) -> io::Result<Metadata>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    let req = Request::GetMetadata {
+        path: path.clone(),
+        follow_symlinks: true,
+    };
+    Ok(call(service, req).await?.into_metadata()?)
+}
+
+async fn call<S>(service: &mut S, req: Request) -> io::Result<Response>
+where
+    S: Service<Request, Response = Response, Error = io::Error>,
+{
+    poll_fn(|cx| service.poll_ready(cx)).await?;
+    service.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem};
+
+    async fn next<T>(stream: &mut (impl Stream<Item = io::Result<T>> + Unpin)) -> io::Result<T> {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::other("the stream ended"))?
+    }
+
+    #[tokio::test]
+    async fn test_follow() -> io::Result<()> {
+        let dir = TestDir::new("follow")?;
+        let path = dir.join("app.log");
+        std::fs::write(&path, "old\n")?;
+        let config = FollowConfig {
+            poll_interval: Duration::from_millis(5),
+            ..FollowConfig::default()
+        };
+        let mut follow = follow(FileSystem::new(), &path, config).await?;
+
+        let append = |bytes: &[u8]| {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)?
+                .write_all(bytes)
+        };
+        append(b"new\n")?;
+        assert_eq!(
+            next(&mut follow).await?,
+            FollowEvent::Appended(b"new\n".to_vec())
+        );
+        std::fs::write(&path, "")?;
+        assert_eq!(next(&mut follow).await?, FollowEvent::Truncated);
+        append(b"after\n")?;
+        assert_eq!(
+            next(&mut follow).await?,
+            FollowEvent::Appended(b"after\n".to_vec())
+        );
+
+        let mut lines = follow.lines();
+        let rotated = path.with_extension("1");
+        std::fs::rename(&path, &rotated)?;
+        std::fs::write(&path, "one\ntw")?;
+        assert_eq!(next(&mut lines).await?, "one");
+        append(b"o\r\n")?;
+        assert_eq!(next(&mut lines).await?, "two");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+//! Watching the local file system for changes, for live reloading and cache invalidation
+//!
+//! [`watch`] scans a path (and by default everything below it) at an interval, and streams the
+//! differences between scans as [`WatchEvent`]s.  Scanning works on every platform and file system,
+//! including network mounts which don't deliver change notifications, at the cost of noticing
+//! changes up to one interval late.  [`follow`] streams what's appended to a single file, through
+//! any `Service<Request>`, like `tail -f`.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use futures::StreamExt;
+//! use tower_fs::watch::{watch, Config};
+//!
+//! let mut events = watch("site", Config::default()).await?;
+//! while let Some(event) = events.next().await {
+//!     println!("{:?}", event?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::{channel::mpsc, Stream};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::{FileType, Metadata};
+
+mod follow;
+
+pub use follow::{follow, Follow, FollowConfig, FollowEvent, Lines};
+
+/// A change to the watched path
+///
+/// Paths are translated as the root middleware translates them, so they're absolute paths
+/// relative to the watched path, which itself is `/`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WatchEvent {
+    Created(PathBuf),
+    /// The length or modification time of a file changed.  Changes to directories are reported
+    /// as changes to their contents instead
+    Modified(PathBuf),
+    Removed(PathBuf),
+    /// An entry was moved within the watched path, which is recognised by it's inode number on
+    /// unix, and elsewhere by a file reappearing with the same length and modification time
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+impl WatchEvent {
+    /// The paths the event affects
+    #[must_use]
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            Self::Created(path) | Self::Modified(path) | Self::Removed(path) => vec![path],
+            Self::Renamed { from, to } => vec![from, to],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// How long to wait between scans
+    pub poll_interval: Duration,
+    /// Watch everything below the path, rather than only the path and it's direct children
+    pub recursive: bool,
+    /// Wait until nothing has changed for this long before reporting changes, so bursts of
+    /// changes (such as an editor saving a file in several steps) are reported once
+    ///
+    /// The changes are coalesced into the events between the state before the burst and after
+    /// it: repeated writes become one [`WatchEvent::Modified`], a file created and removed again
+    /// isn't reported at all, and the halves of a rename are paired into a
+    /// [`WatchEvent::Renamed`].
+    pub debounce: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(500),
+            recursive: true,
+            debounce: None,
+        }
+    }
+}
+
+/// Starts watching `root`, returning a stream of the changes made to it after this returns
+///
+/// Without [`Config::debounce`], each scan's changes are reported as soon as it's finished.
+/// The stream yields an error, and carries on, when a scan fails for any reason but the watched
+/// path being missing, which is treated as it being empty.  Scanning stops when the stream is
+/// dropped.
+///
+/// # Errors
+///
+/// If the first scan fails
+pub async fn watch(root: impl Into<PathBuf>, config: Config) -> io::Result<Watcher> {
+    let root: Arc<Path> = root.into().into();
+    // The state last reported, and the latest state with when it was first seen
+    let mut reported = {
+        let root = root.clone();
+        spawn_blocking(move || scan(&root, config.recursive))
+            .await
+            .map_err(io::Error::other)??
+    };
+    let (mut latest, mut changed) = (reported.clone(), Instant::now());
+    let (sender, events) = mpsc::unbounded();
+    let task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.poll_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let root = root.clone();
+            let scanned = spawn_blocking(move || scan(&root, config.recursive))
+                .await
+                .unwrap_or_else(|err| Err(io::Error::other(err)));
+            match scanned {
+                Ok(after) if after != latest => (latest, changed) = (after, Instant::now()),
+                Ok(_) => {}
+                Err(err) => {
+                    if sender.unbounded_send(Err(err)).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+            if config
+                .debounce
+                .is_some_and(|debounce| changed.elapsed() < debounce)
+            {
+                continue;
+            }
+            let sent = diff(&reported, &latest)
+                .into_iter()
+                .try_for_each(|event| sender.unbounded_send(Ok(event)));
+            if sent.is_err() {
+                // The stream was dropped
+                return;
+            }
+            reported.clone_from(&latest);
+        }
+    });
+    Ok(Watcher { events, task })
+}
+
+/// The changes made to a watched path, from [`watch`]
+#[derive(Debug)]
+pub struct Watcher {
+    events: mpsc::UnboundedReceiver<io::Result<WatchEvent>>,
+    task: JoinHandle<()>,
+}
+
+impl Stream for Watcher {
+    type Item = io::Result<WatchEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().events).poll_next(cx)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What a scan recorded about an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    file_type: FileType,
+    len: u64,
+    modified: Option<SystemTime>,
+    ino: Option<u64>,
+}
+
+impl From<Metadata> for Stamp {
+    fn from(metadata: Metadata) -> Self {
+        Self {
+            file_type: metadata.file_type(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            ino: metadata.ino(),
+        }
+    }
+}
+
+impl Stamp {
+    /// Whether `other` is likely this entry under another name, going by the inode number where
+    /// there is one, or else by the entry being unchanged
+    fn same_entry(&self, other: &Self) -> bool {
+        self.file_type == other.file_type
+            && match (self.ino, other.ino) {
+                (Some(ino), Some(other_ino)) => ino == other_ino,
+                _ => {
+                    self.file_type != FileType::Dir
+                        && self.modified.is_some()
+                        && (self.len, self.modified) == (other.len, other.modified)
+                }
+            }
+    }
+}
+
+/// Records every entry at or below `root`, keyed by it's translated path
+fn scan(root: &Path, recursive: bool) -> io::Result<BTreeMap<PathBuf, Stamp>> {
+    let mut entries = BTreeMap::new();
+    let metadata = match std::fs::symlink_metadata(root) {
+        Ok(metadata) => Metadata::from(metadata),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(entries),
+        Err(err) => return Err(err),
+    };
+    let is_dir = metadata.is_dir();
+    entries.insert(PathBuf::from("/"), metadata.into());
+    if is_dir {
+        scan_dir(root, Path::new("/"), recursive, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+fn scan_dir(
+    dir: &Path,
+    translated: &Path,
+    recursive: bool,
+    entries: &mut BTreeMap<PathBuf, Stamp>,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => Metadata::from(metadata),
+            // Removed since the directory was read
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let path = translated.join(entry.file_name());
+        let is_dir = metadata.is_dir();
+        entries.insert(path.clone(), metadata.into());
+        if recursive && is_dir {
+            match scan_dir(&entry.path(), &path, recursive, entries) {
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                scanned => scanned?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The events which turn `before` into `after`, in path order
+fn diff(before: &BTreeMap<PathBuf, Stamp>, after: &BTreeMap<PathBuf, Stamp>) -> Vec<WatchEvent> {
+    let mut removed: Vec<_> = before
+        .iter()
+        .filter(|(path, _)| !after.contains_key(*path))
+        .collect();
+    let mut events = Vec::new();
+    for (path, stamp) in after {
+        match before.get(path) {
+            None => {
+                let renamed = removed.iter().position(|(_, old)| old.same_entry(stamp));
+                match renamed {
+                    Some(index) => events.push(WatchEvent::Renamed {
+                        from: removed.remove(index).0.clone(),
+                        to: path.clone(),
+                    }),
+                    None => events.push(WatchEvent::Created(path.clone())),
+                }
+            }
+            Some(old)
+                if stamp.file_type != FileType::Dir
+                    && (old.len, old.modified) != (stamp.len, stamp.modified) =>
+            {
+                events.push(WatchEvent::Modified(path.clone()));
+            }
+            Some(_) => {}
+        }
+    }
+    events.extend(
+        removed
+            .into_iter()
+            .map(|(path, _)| WatchEvent::Removed(path.clone())),
+    );
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::test_kit::TestDir;
+
+    /// The next event, skipping repeats of `previous` seen while a write was in progress
+    async fn next(watcher: &mut Watcher, previous: Option<&WatchEvent>) -> io::Result<WatchEvent> {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), watcher.next())
+                .await
+                .map_err(io::Error::other)?
+                .ok_or_else(|| io::Error::other("the watcher stopped"))??;
+            if Some(&event) != previous {
+                return Ok(event);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch() -> io::Result<()> {
+        let dir = TestDir::new("watch")?;
+        std::fs::create_dir_all(dir.join("sub"))?;
+        let config = Config {
+            poll_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let mut watcher = watch(&dir, config).await?;
+
+        std::fs::write(dir.join("sub/a.txt"), "a")?;
+        let created = next(&mut watcher, None).await?;
+        assert_eq!(created, WatchEvent::Created("/sub/a.txt".into()));
+        std::fs::write(dir.join("sub/a.txt"), "longer")?;
+        let modified = next(&mut watcher, Some(&created)).await?;
+        assert_eq!(modified, WatchEvent::Modified("/sub/a.txt".into()));
+        std::fs::rename(dir.join("sub/a.txt"), dir.join("b.txt"))?;
+        let renamed = next(&mut watcher, Some(&modified)).await?;
+        assert_eq!(
+            renamed,
+            WatchEvent::Renamed {
+                from: "/sub/a.txt".into(),
+                to: "/b.txt".into()
+            }
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        let mut removed = Vec::new();
+        while removed.len() < 3 {
+            match next(&mut watcher, None).await? {
+                WatchEvent::Removed(path) => removed.push(path),
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        removed.sort();
+        assert_eq!(removed, ["/", "/b.txt", "/sub"].map(PathBuf::from));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_debounce() -> io::Result<()> {
+        let dir = TestDir::new("debounce")?;
+        std::fs::write(dir.join("a.txt"), "")?;
+        let config = Config {
+            poll_interval: Duration::from_millis(5),
+            debounce: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let mut watcher = watch(&dir, config).await?;
+
+        // A save storm is reported once
+        for contents in ["1", "12", "123"] {
+            std::fs::write(dir.join("a.txt"), contents)?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let modified = next(&mut watcher, None).await?;
+        assert_eq!(modified, WatchEvent::Modified("/a.txt".into()));
+
+        // A temporary file comes and goes unnoticed, and a rename is reported whole
+        std::fs::write(dir.join("a.txt.tmp"), "scratch")?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        std::fs::remove_file(dir.join("a.txt.tmp"))?;
+        std::fs::rename(dir.join("a.txt"), dir.join("b.txt"))?;
+        assert_eq!(
+            next(&mut watcher, None).await?,
+            WatchEvent::Renamed {
+                from: "/a.txt".into(),
+                to: "/b.txt".into()
+            }
+        );
+        let quiet = tokio::time::timeout(Duration::from_millis(300), watcher.next()).await;
+        assert!(quiet.is_err(), "unexpected {quiet:?}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_pairs_renames_without_inodes() {
+        let stamp = |len| Stamp {
+            file_type: FileType::File,
+            len,
+            modified: Some(SystemTime::UNIX_EPOCH),
+            ino: None,
+        };
+        let before = BTreeMap::from([("/a".into(), stamp(1)), ("/b".into(), stamp(2))]);
+        let after = BTreeMap::from([("/c".into(), stamp(1)), ("/d".into(), stamp(3))]);
+        assert_eq!(
+            diff(&before, &after),
+            [
+                WatchEvent::Renamed {
+                    from: "/a".into(),
+                    to: "/c".into()
+                },
+                WatchEvent::Created("/d".into()),
+                WatchEvent::Removed("/b".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,354 @@
+//! The parts of the local backend only Windows has: junctions, NTFS alternate data streams, file
+//! attributes and extended-length paths
+//!
+//! Everything here goes through [`std`] and [`std::os::windows`].  What those don't wrap, like
+//! creating junctions, listing streams, access control lists and most attributes, is left out.
+
+use std::{
+    ffi::OsString,
+    io::{self, ErrorKind},
+    ops::BitOr,
+    os::windows::fs::{FileTypeExt, MetadataExt},
+    path::{Component, Path, PathBuf, Prefix},
+};
+
+/// The longest path most Windows APIs accept without an extended-length (`\\?\`) prefix, less
+/// the room `CreateDirectoryW` keeps for an 8.3 file name
+const MAX_PATH: usize = 248;
+
+/// `path` as an absolute extended-length (`\\?\`) path if it's too long for the Windows APIs
+/// which limit paths to `MAX_PATH`, or as it is otherwise
+///
+/// Extended-length paths skip Windows' own normalization, so the path is made absolute first,
+/// resolving `.` and `..`.
+pub(crate) fn extended(path: &Path) -> io::Result<PathBuf> {
+    let absolute = std::path::absolute(path)?;
+    if absolute.as_os_str().len() < MAX_PATH {
+        return Ok(path.to_owned());
+    }
+    let mut components = absolute.components();
+    let mut extended = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => {
+                let mut extended = OsString::from(r"\\?\");
+                extended.push(prefix.as_os_str());
+                extended
+            }
+            Prefix::UNC(server, share) => {
+                let mut extended = OsString::from(r"\\?\UNC\");
+                extended.push(server);
+                extended.push(r"\");
+                extended.push(share);
+                extended
+            }
+            // Already verbatim, or a device
+            _ => return Ok(absolute),
+        },
+        _ => return Ok(absolute),
+    };
+    extended.push(components.as_path());
+    Ok(extended.into())
+}
+
+/// `path` without an extended-length (`\\?\`) prefix, if it means the same thing without it
+pub(crate) fn strip_extended(path: PathBuf) -> PathBuf {
+    strip_verbatim(&path).unwrap_or(path)
+}
+
+/// The attributes Windows keeps for a file or directory, as the bits of `GetFileAttributesW`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct FileAttributes(pub u32);
+
+impl FileAttributes {
+    pub const READONLY: Self = Self(0x1);
+    pub const HIDDEN: Self = Self(0x2);
+    pub const SYSTEM: Self = Self(0x4);
+    pub const DIRECTORY: Self = Self(0x10);
+    /// Set when a file changes, for backup tools to clear once they've copied it
+    pub const ARCHIVE: Self = Self(0x20);
+    pub const TEMPORARY: Self = Self(0x100);
+    pub const REPARSE_POINT: Self = Self(0x400);
+    pub const NOT_CONTENT_INDEXED: Self = Self(0x2000);
+
+    /// The attributes `SetFileAttributesW` can change
+    const SETTABLE: Self = Self(0x2127);
+
+    /// Whether every attribute of `other` is set
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for FileAttributes {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The attributes of the file or directory at `path`, following symlinks
+pub(crate) fn get_attributes(path: &Path) -> io::Result<FileAttributes> {
+    std::fs::metadata(path).map(|metadata| FileAttributes(metadata.file_attributes()))
+}
+
+/// Sets the attributes of `set` and clears those of `clear` on the file or directory at `path`
+///
+/// Only [`FileAttributes::READONLY`] can be changed, through [`std::fs::set_permissions`], as
+/// [`std`] doesn't wrap `SetFileAttributesW`.  Other attributes files can have fail with
+/// [`ErrorKind::Unsupported`].
+pub(crate) fn set_attributes(
+    path: &Path,
+    set: FileAttributes,
+    clear: FileAttributes,
+) -> io::Result<()> {
+    if !FileAttributes::SETTABLE.contains(set | clear) || set.0 & clear.0 != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "only the readonly, hidden, system, archive, temporary and not content indexed \
+             attributes can be changed",
+        ));
+    }
+    if !FileAttributes::READONLY.contains(set | clear) {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "only the readonly attribute can be changed",
+        ));
+    }
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if set | clear == FileAttributes::default() {
+        return Ok(());
+    }
+    permissions.set_readonly(set.contains(FileAttributes::READONLY));
+    std::fs::set_permissions(path, permissions)
+}
+
+/// The path `path:stream`, which opens the alternate data stream `stream` of the file at `path`
+///
+/// Stream names can't be empty or hold separators, colons or NULs, which would let them name a
+/// stream type or a different file, and `path` can't already name a stream.
+pub(crate) fn stream_path(path: &Path, stream: &str) -> io::Result<PathBuf> {
+    if stream.is_empty() || stream.contains([':', '\\', '/', '\0']) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "invalid alternate data stream name",
+        ));
+    }
+    let file_name = path.file_name().ok_or(ErrorKind::InvalidInput)?;
+    if file_name.to_string_lossy().contains(':') {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "the path already names a stream",
+        ));
+    }
+    let mut stream_path = OsString::from(path);
+    stream_path.push(":");
+    stream_path.push(stream);
+    Ok(stream_path.into())
+}
+
+/// The target of the junction at `path`, without the `\\?\` prefix [`std::fs::read_link`] gives it
+pub(crate) fn read_junction(path: &Path) -> io::Result<PathBuf> {
+    if !std::fs::symlink_metadata(path)?
+        .file_type()
+        .is_symlink_dir()
+    {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "not a junction"));
+    }
+    std::fs::read_link(path).map(strip_extended)
+}
+
+/// `path` without a verbatim (`\\?\`) prefix, if it has one which can be left out
+///
+/// It can't be left out of paths Windows would normalize into something else, such as those with
+/// names ending in a dot or naming devices like `NUL`.
+fn strip_verbatim(path: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return None;
+    };
+    let mut stripped = match prefix.kind() {
+        Prefix::VerbatimDisk(disk) => OsString::from(format!("{}:", char::from(disk))),
+        Prefix::VerbatimUNC(server, share) => {
+            let mut stripped = OsString::from(r"\\");
+            stripped.push(server);
+            stripped.push(r"\");
+            stripped.push(share);
+            stripped
+        }
+        _ => return None,
+    };
+    stripped.push(components.as_path());
+    let stripped = PathBuf::from(stripped);
+    (std::path::absolute(&stripped).ok()? == stripped).then_some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{test_kit::TestDir, FileSystem, Mode, Request};
+
+    #[test]
+    fn test_stream_path() {
+        let path = Path::new(r"C:\downloads\setup.exe");
+        assert_eq!(
+            stream_path(path, "Zone.Identifier").ok(),
+            Some(PathBuf::from(r"C:\downloads\setup.exe:Zone.Identifier"))
+        );
+        for stream in ["", "a:$DATA", r"..\other", "a/b", "a\0"] {
+            assert!(stream_path(path, stream).is_err(), "{stream:?}");
+        }
+        assert!(stream_path(Path::new(r"C:\setup.exe:a"), "b").is_err());
+    }
+
+    #[test]
+    fn test_extended() -> io::Result<()> {
+        let short = Path::new(r"C:\short\path.txt");
+        assert_eq!(extended(short)?, short);
+        let (a, b) = ("a".repeat(300), "b".repeat(300));
+        let long = PathBuf::from(format!(r"C:\deep\{a}\..\{b}\file.txt"));
+        let expected = PathBuf::from(format!(r"\\?\C:\deep\{b}\file.txt"));
+        assert_eq!(extended(&long)?, expected);
+        assert_eq!(
+            strip_extended(expected),
+            PathBuf::from(format!(r"C:\deep\{b}\file.txt"))
+        );
+        let long = PathBuf::from(format!(r"\\server\share\{b}"));
+        assert_eq!(
+            extended(&long)?,
+            PathBuf::from(format!(r"\\?\UNC\server\share\{b}"))
+        );
+        // Windows would strip the trailing dot without the prefix
+        let dotted = PathBuf::from(r"\\?\C:\dir\name.");
+        assert_eq!(strip_extended(dotted.clone()), dotted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deep_tree() -> io::Result<()> {
+        let dir = TestDir::new("deep")?;
+        let deep = (0..30).fold(dir.to_path_buf(), |deep, depth| {
+            deep.join(format!("directory_{depth:02}"))
+        });
+        assert!(deep.as_os_str().len() > 400);
+        let mut fs = FileSystem::new();
+        fs.call(Request::CreateDir {
+            path: deep.as_path().into(),
+            recursive: true,
+        })
+        .await?
+        .into_done()?;
+        let file = deep.join("file.txt");
+        fs.call(Request::WriteBytes {
+            path: file.as_path().into(),
+            bytes: b"deep".to_vec(),
+        })
+        .await?
+        .into_done()?;
+        let read = fs.call(Request::ReadBytes(file.as_path().into())).await?;
+        assert_eq!(read.into_bytes()?, b"deep");
+
+        // Stream names are appended to the prefixed path
+        let mut stream = fs
+            .call(Request::OpenStream {
+                mode: Mode::CreateOrOverwrite,
+                path: file.as_path().into(),
+                stream: "meta".into(),
+            })
+            .await?
+            .into_file()?;
+        stream.write_all(b"meta").await?;
+        stream.flush().await?;
+        drop(stream);
+        assert_eq!(std::fs::read(format!("{}:meta", file.display()))?, b"meta");
+        fs.call(Request::RemoveDir {
+            path: dir.path().into(),
+            recursive: true,
+        })
+        .await?
+        .into_done()
+    }
+
+    #[tokio::test]
+    async fn test_file_attributes() -> io::Result<()> {
+        let dir = TestDir::new("attrib")?;
+        let path = dir.join("desktop.ini");
+        std::fs::write(&path, "[.ShellClassInfo]")?;
+        let mut fs = FileSystem::new();
+        let set = Request::SetFileAttributes {
+            path: path.as_path().into(),
+            set: FileAttributes::READONLY,
+            clear: FileAttributes::default(),
+        };
+        fs.call(set).await?.into_done()?;
+        let get = Request::GetFileAttributes(path.as_path().into());
+        let attributes = fs.call(get.clone()).await?.into_file_attributes()?;
+        assert!(attributes.contains(FileAttributes::READONLY));
+
+        let invalid = fs.call(Request::SetFileAttributes {
+            path: path.as_path().into(),
+            set: FileAttributes::DIRECTORY,
+            clear: FileAttributes::default(),
+        });
+        assert!(invalid
+            .await
+            .is_err_and(|err| err.kind() == ErrorKind::InvalidInput));
+        let hidden = fs.call(Request::SetFileAttributes {
+            path: path.as_path().into(),
+            set: FileAttributes::HIDDEN,
+            clear: FileAttributes::default(),
+        });
+        assert!(hidden
+            .await
+            .is_err_and(|err| err.kind() == ErrorKind::Unsupported));
+        fs.call(Request::SetFileAttributes {
+            path: path.as_path().into(),
+            set: FileAttributes::default(),
+            clear: FileAttributes::READONLY,
+        })
+        .await?
+        .into_done()?;
+        let attributes = fs.call(get).await?.into_file_attributes()?;
+        assert!(!attributes.contains(FileAttributes::READONLY));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streams() -> io::Result<()> {
+        let dir = TestDir::new("streams")?;
+        let path = dir.join("setup.exe");
+        std::fs::write(&path, "contents")?;
+        let mut fs = FileSystem::new();
+        let mut file = fs
+            .call(Request::OpenStream {
+                mode: Mode::CreateOrOverwrite,
+                path: path.as_path().into(),
+                stream: "Zone.Identifier".into(),
+            })
+            .await?
+            .into_file()?;
+        file.write_all(b"[ZoneTransfer]\r\nZoneId=3\r\n").await?;
+        file.flush().await?;
+        drop(file);
+
+        let stream = stream_path(&path, "Zone.Identifier")?;
+        assert_eq!(std::fs::metadata(stream)?.len(), 26);
+        let mut file = fs
+            .call(Request::OpenStream {
+                mode: Mode::Read,
+                path: path.as_path().into(),
+                stream: "Zone.Identifier".into(),
+            })
+            .await?
+            .into_file()?;
+        let mut zone = String::new();
+        file.read_to_string(&mut zone).await?;
+        assert!(zone.contains("ZoneId=3"));
+        assert_eq!(std::fs::read(&path)?, b"contents");
+        Ok(())
+    }
+}